@@ -143,8 +143,11 @@ lapses=?, left=?, odue=?, odid=?, did=? where id = ?""",
         )
         self.col.log(self)
 
-    def q(self, reload: bool = False, browser: bool = False) -> str:
-        return self.css() + self._getQA(reload, browser)["q"]
+    def q(
+        self, reload: bool = False, browser: bool = False, preview: bool = False
+    ) -> str:
+        "If preview is true, empty fields are shown as placeholders."
+        return self.css() + self._getQA(reload, browser, preview)["q"]
 
     def a(self) -> str:
         return self.css() + self._getQA()["a"]
@@ -152,7 +155,9 @@ lapses=?, left=?, odue=?, odid=?, did=? where id = ?""",
     def css(self) -> str:
         return "<style>%s</style>" % self.model()["css"]
 
-    def _getQA(self, reload: bool = False, browser: bool = False) -> Any:
+    def _getQA(
+        self, reload: bool = False, browser: bool = False, preview: bool = False
+    ) -> Any:
         if not self._qa or reload:
             f = self.note(reload)
             m = self.model()
@@ -173,6 +178,7 @@ lapses=?, left=?, odue=?, odid=?, did=? where id = ?""",
                     self.flags,
                 ),
                 *args,
+                preview=preview,
             )  # type: ignore
         return self._qa
 
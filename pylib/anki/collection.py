@@ -8,11 +8,11 @@ import os
 import pprint
 import random
 import re
-import stat
 import time
 import traceback
-from typing import Any, Dict, Iterable, List, Optional, Tuple, Union
+from typing import Any, Callable, Dict, Iterable, List, Optional, Tuple, Union
 
+import anki.backend_pb2 as pb
 import anki.find
 import anki.latex  # sets up hook
 import anki.template
@@ -27,7 +27,16 @@ from anki.lang import _, ngettext
 from anki.media import MediaManager
 from anki.models import ModelManager, NoteType, Template
 from anki.notes import Note
-from anki.rsbackend import RustBackend
+from anki.rsbackend import (
+    BrowserRow,
+    CheckDatabaseOut,
+    CollectionSnapshot,
+    FullSyncPreview,
+    RustBackend,
+    SyncCollectionOut,
+    SyncNetworkSettings,
+    UndoStatus,
+)
 from anki.sched import Scheduler as V1Scheduler
 from anki.schedv2 import Scheduler as V2Scheduler
 from anki.tags import TagManager
@@ -39,8 +48,9 @@ from anki.utils import (
     intTime,
     joinFields,
     maxID,
+    platDesc,
     splitFields,
-    stripHTMLMedia,
+    versionWithBuild,
 )
 
 defaultConf = {
@@ -60,6 +70,8 @@ defaultConf = {
     "addToCur": True,  # add new to currently selected deck?
     "dayLearnFirst": False,
     "schedVer": 1,
+    # convert note fields to NFC form when saving
+    "normalize_note_text": True,
 }
 
 
@@ -85,6 +97,8 @@ class _Collection:
         log: bool = False,
     ) -> None:
         self.backend = backend
+        # set if the collection wasn't closed cleanly last time
+        self.recovered = False
         self._debugLog = log
         self.db = db
         self.path = db._path
@@ -186,6 +200,7 @@ select crt, mod, scm, dty, usn, ls,
 conf, models, decks, dconf, tags from col"""
         )
         self.conf = json.loads(conf)  # type: ignore
+        self.backend.set_undo_steps(self.conf.get("undoSteps", 30))
         self.models.load(models)
         self.decks.load(decks, dconf)
         self.tags.load(tags)
@@ -247,18 +262,16 @@ crt=?, mod=?, scm=?, dty=?, usn=?, ls=?, conf=?""",
                 self.save()
             else:
                 self.db.rollback()
-            if not self.server:
-                self.db.setAutocommit(True)
-                self.db.execute("pragma journal_mode = delete")
-                self.db.setAutocommit(False)
             self.db.close()
             self.db = None
+            self.backend.close_collection(downgrade=not self.server)
             self.media.close()
             self._closeLog()
 
     def reopen(self) -> None:
         "Reconnect to DB (after changing threads, etc)."
         if not self.db:
+            self.recovered = self.backend.open_collection()
             self.db = DB(self.path)
             self.media.connect()
             self._openLog()
@@ -283,24 +296,93 @@ crt=?, mod=?, scm=?, dty=?, usn=?, ls=?, conf=?""",
     def usn(self) -> Any:
         return self._usn if self.server else -1
 
-    def beforeUpload(self) -> None:
-        "Called before a full upload."
-        tbls = "notes", "cards", "revlog"
-        for t in tbls:
-            self.db.execute("update %s set usn=0 where usn=-1" % t)
-        # we can save space by removing the log of deletions
-        self.db.execute("delete from graves")
-        self._usn += 1
-        self.models.beforeUpload()
-        self.tags.beforeUpload()
-        self.decks.beforeUpload()
-        self.modSchema(check=False)
-        self.ls = self.scm
-        # ensure db is compacted before upload
-        self.db.setAutocommit(True)
-        self.db.execute("vacuum")
-        self.db.execute("analyze")
+    def sync(
+        self,
+        hkey: str,
+        endpoint: str,
+        network: Optional[SyncNetworkSettings] = None,
+    ) -> SyncCollectionOut:
+        """Sync with the collection sync server at ENDPOINT. If the sync is
+        aborted through the backend, its changes are rolled back. NETWORK
+        configures the proxy and certificates used to reach the server."""
+        # the backend merges the changes into the collection file
+        self.save()
+        self.db.commit()
+        try:
+            return self.backend.sync_collection(
+                hkey, endpoint, self._syncClientVersion(), network
+            )
+        finally:
+            self.load()
+            self.lock()
+
+    def syncLogin(
+        self,
+        username: str,
+        password: str,
+        endpoint: str,
+        network: Optional[SyncNetworkSettings] = None,
+    ) -> Optional[str]:
+        "Returns a host key, or None if the username or password were incorrect."
+        return self.backend.sync_login(
+            username, password, endpoint, self._syncClientVersion(), network
+        )
+
+    def fullSyncPreview(
+        self,
+        hkey: str,
+        endpoint: str,
+        network: Optional[SyncNetworkSettings] = None,
+    ) -> FullSyncPreview:
+        """The changes made here and on the server since the last sync, so the
+        user can see what an upload or download would lose."""
+        self.save()
+        self.db.commit()
+        try:
+            return self.backend.full_sync_preview(
+                hkey, endpoint, self._syncClientVersion(), network
+            )
+        finally:
+            self.lock()
+
+    def fullUpload(
+        self,
+        hkey: str,
+        endpoint: str,
+        network: Optional[SyncNetworkSettings] = None,
+    ) -> int:
+        """Replace the collection on the server with this one, returning a
+        FullSyncOutcome. The collection is closed while it's uploaded."""
         self.close()
+        try:
+            return self.backend.full_upload(
+                hkey, endpoint, self._syncClientVersion(), network=network
+            )
+        finally:
+            self.reopen()
+            self.load()
+
+    def fullDownload(
+        self,
+        hkey: str,
+        endpoint: str,
+        backupFolder: str = "",
+        network: Optional[SyncNetworkSettings] = None,
+    ) -> int:
+        """Replace this collection with the one on the server, returning a
+        FullSyncOutcome. If backupFolder is provided, the current collection is
+        backed up there first."""
+        self.close()
+        try:
+            return self.backend.full_download(
+                hkey, endpoint, self._syncClientVersion(), backupFolder, network
+            )
+        finally:
+            self.reopen()
+            self.load()
+
+    def _syncClientVersion(self) -> str:
+        return "ankidesktop,%s,%s" % (versionWithBuild(), platDesc())
 
     # Object creation helpers
     ##########################################################################
@@ -345,20 +427,67 @@ crt=?, mod=?, scm=?, dty=?, usn=?, ls=?, conf=?""",
         return Note(self, self.models.current(forDeck))
 
     def addNote(self, note: Note) -> int:
-        """Add a note to the collection. Return number of new cards."""
+        """Add a note and the cards its fields call for. Return the number of
+        new cards, or 0 if there would be none, in which case the note is not
+        added. The change can be undone."""
         # check we have card models available, then save
-        cms = self.findTemplates(note)
-        if not cms:
+        if not self.findTemplates(note):
             return 0
-        note.flush()
-        # deck conf governs which of these are used
-        due = self.nextID("pos")
-        # add cards
-        ncards = 0
-        for template in cms:
-            self._newCard(note, template, due)
-            ncards += 1
-        return ncards
+        did = note.model()["did"]
+        out = self._saveNote(
+            note,
+            lambda pbnote: self.backend.add_note(pbnote, did, self.usn(), intTime()),
+        )
+        return len(out.added_card_ids)
+
+    def updateNote(self, note: Note) -> List[int]:
+        """Save changes to the fields and tags of an existing note, adding the
+        cards the fields now call for, and removing new cards they no longer
+        do. Return the ids of the removed cards. The change can be undone."""
+        out = self._saveNote(
+            note, lambda pbnote: self.backend.update_note(pbnote, self.usn(), intTime())
+        )
+        return list(out.removed_card_ids)
+
+    def _saveNote(
+        self, note: Note, op: Callable[[pb.Note], pb.NoteSaveOut]
+    ) -> pb.NoteSaveOut:
+        # an operation the caller checkpointed is undone as the backend op
+        checkpoint = self._undo[1] if self._undo and self._undo[0] == 2 else None
+        tagsBefore = set(self.tags.all())
+        self.save()
+        self.db.commit()
+        try:
+            out = op(
+                pb.Note(
+                    id=note.id,
+                    guid=note.guid,
+                    notetype_id=note.mid,
+                    tags=note.tags,
+                    fields=note.fields,
+                    flags=note.flags,
+                    data=note.data,
+                )
+            )
+        finally:
+            self.lock()
+        note.id = out.note.id
+        note.guid = out.note.guid
+        note.mod = out.note.mtime_secs
+        note.usn = out.note.usn
+        note.tags = list(out.note.tags)
+        note.fields = list(out.note.fields)
+        # the backend takes the next new card position and registers new tags
+        conf = json.loads(self.db.scalar("select conf from col"))
+        self.conf["nextPos"] = conf["nextPos"]
+        self.tags.load(self.db.scalar("select tags from col"))
+        for tag in set(self.tags.all()) - tagsBefore:
+            hooks.tag_added(tag)
+        self.mod = self.db.scalar("select mod from col")
+        self.markBackendOp()
+        if checkpoint and self._undo:
+            self._undo[1] = checkpoint
+        return out
 
     def remNotes(self, ids: Iterable[int]) -> None:
         """Deletes notes with the given IDs."""
@@ -605,7 +734,7 @@ where c.nid = n.id and c.id in %s group by nid"""
                 continue
             r.append(
                 (
-                    stripHTMLMedia(fields[self.models.sortIdx(model)]),
+                    self.backend.sort_field_text(fields[self.models.sortIdx(model)]),
                     fieldChecksum(fields[0]),
                     nid,
                 )
@@ -632,37 +761,29 @@ where c.nid = n.id and c.id in %s group by nid"""
 
     # data is [cid, nid, mid, did, ord, tags, flds, cardFlags]
     def _renderQA(
-        self, data: QAData, qfmt: Optional[str] = None, afmt: Optional[str] = None
+        self,
+        data: QAData,
+        qfmt: Optional[str] = None,
+        afmt: Optional[str] = None,
+        preview: bool = False,
     ) -> Dict[str, Union[str, int]]:
         # extract info from data
         split_fields = splitFields(data[6])
-        card_ord = data[4]
         model = self.models.get(data[2])
         if model["type"] == MODEL_STD:
             template = model["tmpls"][data[4]]
         else:
             template = model["tmpls"][0]
-        flag = data[7]
-        deck_id = data[3]
         card_id = data[0]
-        tags = data[5]
         qfmt = qfmt or template["qfmt"]
         afmt = afmt or template["afmt"]
 
-        # create map of field names -> field content
+        # create map of field names -> field content. special fields like
+        # {{Tags}} are filled in by the backend.
         fields: Dict[str, str] = {}
         for (name, (idx, conf)) in list(self.models.fieldMap(model).items()):
             fields[name] = split_fields[idx]
 
-        # add special fields
-        fields["Tags"] = tags.strip()
-        fields["Type"] = model["name"]
-        fields["Deck"] = self.decks.name(deck_id)
-        fields["Subdeck"] = fields["Deck"].split("::")[-1]
-        fields["Card"] = template["name"]
-        fields["CardFlag"] = self._flagNameFromCardFlags(flag)
-        fields["c%d" % (card_ord + 1)] = "1"
-
         # legacy hook
         fields = runFilter("mungeFields", fields, model, data, self)
 
@@ -670,12 +791,7 @@ where c.nid = n.id and c.id in %s group by nid"""
 
         # render fields. if any custom filters are encountered,
         # the field_filter hook will be called.
-        try:
-            qtext, atext = render_card(self, qfmt, afmt, ctx)
-        except anki.rsbackend.BackendException as e:
-            errmsg = _("Card template has a problem:") + f"<br>{e}"
-            qtext = errmsg
-            atext = errmsg
+        qtext, atext = render_card(self, qfmt, afmt, ctx, preview=preview)
 
         # avoid showing the user a confusing blank card if they've
         # forgotten to add a cloze deletion
@@ -709,12 +825,6 @@ where c.nid == f.id
             % where
         )
 
-    def _flagNameFromCardFlags(self, flags: int) -> str:
-        flag = flags & 0b111
-        if not flag:
-            return ""
-        return "flag%d" % flag
-
     # Finding cards
     ##########################################################################
 
@@ -724,6 +834,16 @@ where c.nid == f.id
     def findNotes(self, query: str) -> Any:
         return anki.find.Finder(self).findNotes(query)
 
+    def findCardsPage(
+        self, query: str, offset: int, limit: int
+    ) -> Tuple[List[int], int]:
+        return anki.find.Finder(self).findCardsPage(query, offset, limit)
+
+    def findNotesPage(
+        self, query: str, offset: int, limit: int
+    ) -> Tuple[List[int], int]:
+        return anki.find.Finder(self).findNotesPage(query, offset, limit)
+
     def findReplace(
         self,
         nids: List[int],
@@ -732,12 +852,36 @@ where c.nid == f.id
         regex: Optional[bool] = None,
         field: Optional[str] = None,
         fold: bool = True,
+        tags: bool = False,
     ) -> int:
-        return anki.find.findReplace(self, nids, src, dst, regex, field, fold)
+        return anki.find.findReplace(self, nids, src, dst, regex, field, fold, tags)
 
     def findDupes(self, fieldName: str, search: str = "") -> List[Tuple[Any, list]]:
         return anki.find.findDupes(self, fieldName, search)
 
+    def tagDuplicates(self, fieldName: str, search: str, tag: str) -> int:
+        "Add TAG to the notes findDupes() returns. Returns the number changed."
+        changed = anki.find.Finder(self).tagDupes(fieldName, search, tag)
+        # the backend has registered the tag
+        self.tags.load(self.db.scalar("select tags from col"))
+        if changed:
+            self.setMod()
+        return changed
+
+    # Browser rows
+    ##########################################################################
+
+    def browserRows(self, cids: List[int], columns: List[str]) -> List[BrowserRow]:
+        """The browser's text for COLUMNS, a list of column keys, for each card
+        in CIDS. Cards that don't exist are skipped."""
+        # the backend reads from the collection file
+        self.save()
+        self.db.commit()
+        try:
+            return self.backend.browser_rows(cids, columns, self.sched.today)
+        finally:
+            self.lock()
+
     # Stats
     ##########################################################################
 
@@ -773,8 +917,10 @@ where c.nid == f.id
 
     def clearUndo(self) -> None:
         # [type, undoName, data]
-        # type 1 = review; type 2 = checkpoint
+        # type 1 = review; type 2 = checkpoint; type 3 = backend op
         self._undo = None
+        # ops the backend recorded can't be undone past a review or checkpoint
+        self.backend.clear_undo()
 
     def undoName(self) -> Any:
         "Undo menu item name, or None if undo unavailable."
@@ -785,15 +931,16 @@ where c.nid == f.id
     def undo(self) -> Any:
         if self._undo[0] == 1:
             return self._undoReview()
+        elif self._undo[0] == 3:
+            self._undoBackendOp()
         else:
             self._undoOp()
 
     def markReview(self, card: Card) -> None:
         old: List[Any] = []
-        if self._undo:
-            if self._undo[0] == 1:
-                old = self._undo[2]
-            self.clearUndo()
+        if self._undo and self._undo[0] == 1:
+            old = self._undo[2]
+        self.clearUndo()
         wasLeech = card.note().hasTag("leech") or False
         self._undo = [1, _("Review"), old + [copy.copy(card)], wasLeech]
 
@@ -831,6 +978,7 @@ where c.nid == f.id
     def _markOp(self, name: Optional[str]) -> None:
         "Call via .save()"
         if name:
+            self.clearUndo()
             self._undo = [2, name]
         else:
             # saving disables old checkpoint, but not review undo
@@ -841,251 +989,218 @@ where c.nid == f.id
         self.rollback()
         self.clearUndo()
 
+    def markBackendOp(self) -> None:
+        """Mark an operation the backend has committed and recorded, so it
+        can be undone. The backend keeps several steps, and they can be
+        redone after being undone."""
+        self._markBackendUndo(self.backend.undo_status())
+
+    def _markBackendUndo(self, status: UndoStatus) -> None:
+        if status.undo:
+            self._undo = [3, _(status.undo)]
+        else:
+            # keep any steps that can be redone
+            self._undo = None
+
+    def _undoBackendOp(self) -> None:
+        self.db.commit()
+        self._markBackendUndo(self.backend.undo())
+
+    def redoName(self) -> Optional[str]:
+        "Redo menu item name, or None if redo unavailable."
+        name = self.backend.undo_status().redo
+        return _(name) if name else None
+
+    def redo(self) -> None:
+        self.db.commit()
+        self._markBackendUndo(self.backend.redo())
+
     # DB maintenance
     ##########################################################################
 
-    def basicCheck(self) -> bool:
-        "Basic integrity check for syncing. True if ok."
-        # cards without notes
-        if self.db.scalar(
-            """
-select 1 from cards where nid not in (select id from notes) limit 1"""
-        ):
-            return False
-        # notes without cards or models
-        if self.db.scalar(
-            """
-select 1 from notes where id not in (select distinct nid from cards)
-or mid not in %s limit 1"""
-            % ids2str(self.models.ids())
-        ):
-            return False
-        # invalid ords
-        for m in self.models.all():
-            # ignore clozes
-            if m["type"] != MODEL_STD:
-                continue
-            if self.db.scalar(
-                """
-select 1 from cards where ord not in %s and nid in (
-select id from notes where mid = ?) limit 1"""
-                % ids2str([t["ord"] for t in m["tmpls"]]),
-                m["id"],
-            ):
-                return False
-        return True
-
     def fixIntegrity(self) -> Tuple[str, bool]:
         """Fix possible problems and rebuild caches.
 
         Returns tuple of (error: str, ok: bool). 'ok' will be true if no
-        problems were found.
+        problems were found. The check and optimize report their progress
+        through the backend, and can be aborted.
         """
-        problems = []
-        curs = self.db.cursor()
         self.save()
-        oldSize = os.stat(self.path)[stat.ST_SIZE]
-        if self.db.scalar("pragma integrity_check") != "ok":
+        self.db.commit()
+        out = self.backend.check_database(self.sched.today, self.usn(), intTime())
+        if out.corrupt:
             return (_("Collection is corrupt. Please see the manual."), False)
-        # note types with a missing model
-        ids = self.db.list(
-            """
-select id from notes where mid not in """
-            + ids2str(self.models.ids())
-        )
-        if ids:
+        # the backend has changed the notetypes, tags and config
+        self.load()
+        problems = self._checkDatabaseProblems(out)
+        # model with missing req specification
+        for m in self.models.all():
+            if m["type"] == MODEL_STD and "req" not in m:
+                self.models._updateRequired(m)
+                problems.append(_("Fixed note type: %s") % m["name"])
+        if self.models.ensureNotEmpty():
+            problems.append("Added missing note type.")
+        # and finally, optimize
+        self.optimize()
+        txt = _("Database rebuilt and optimized.")
+        ok = not problems
+        problems.append(txt)
+        # if any problems were found, force a full sync
+        if not ok:
+            self.modSchema(check=False)
+        self.save()
+        return ("\n".join(problems), ok)
+
+    def _checkDatabaseProblems(self, out: CheckDatabaseOut) -> List[str]:
+        "Describe the problems the backend found and fixed."
+        problems = []
+        if out.notes_with_missing_notetype:
+            n = out.notes_with_missing_notetype
             problems.append(
                 ngettext(
                     "Deleted %d note with missing note type.",
                     "Deleted %d notes with missing note type.",
-                    len(ids),
+                    n,
                 )
-                % len(ids)
+                % n
             )
-            self.remNotes(ids)
-        # for each model
-        for m in self.models.all():
-            for t in m["tmpls"]:
-                if t["did"] == "None":
-                    t["did"] = None
-                    problems.append(_("Fixed AnkiDroid deck override bug."))
-                    self.models.save(m, updateReqs=False)
-            if m["type"] == MODEL_STD:
-                # model with missing req specification
-                if "req" not in m:
-                    self.models._updateRequired(m)
-                    problems.append(_("Fixed note type: %s") % m["name"])
-                # cards with invalid ordinal
-                ids = self.db.list(
-                    """
-select id from cards where ord not in %s and nid in (
-select id from notes where mid = ?)"""
-                    % ids2str([t["ord"] for t in m["tmpls"]]),
-                    m["id"],
+        if out.templates_with_invalid_deck:
+            problems.append(_("Fixed AnkiDroid deck override bug."))
+        if out.cards_with_missing_template:
+            n = out.cards_with_missing_template
+            problems.append(
+                ngettext(
+                    "Deleted %d card with missing template.",
+                    "Deleted %d cards with missing template.",
+                    n,
                 )
-                if ids:
-                    problems.append(
-                        ngettext(
-                            "Deleted %d card with missing template.",
-                            "Deleted %d cards with missing template.",
-                            len(ids),
-                        )
-                        % len(ids)
-                    )
-                    self.remCards(ids)
-            # notes with invalid field count
-            ids = []
-            for id, flds in self.db.execute(
-                "select id, flds from notes where mid = ?", m["id"]
-            ):
-                if (flds.count("\x1f") + 1) != len(m["flds"]):
-                    ids.append(id)
-            if ids:
-                problems.append(
-                    ngettext(
-                        "Deleted %d note with wrong field count.",
-                        "Deleted %d notes with wrong field count.",
-                        len(ids),
-                    )
-                    % len(ids)
+                % n
+            )
+        if out.notes_with_wrong_field_count:
+            n = out.notes_with_wrong_field_count
+            problems.append(
+                ngettext(
+                    "Deleted %d note with wrong field count.",
+                    "Deleted %d notes with wrong field count.",
+                    n,
                 )
-                self.remNotes(ids)
-        # delete any notes with missing cards
-        ids = self.db.list(
-            """
-select id from notes where id not in (select distinct nid from cards)"""
-        )
-        if ids:
-            cnt = len(ids)
+                % n
+            )
+        if out.notes_without_cards:
+            n = out.notes_without_cards
             problems.append(
                 ngettext(
                     "Deleted %d note with no cards.",
                     "Deleted %d notes with no cards.",
-                    cnt,
+                    n,
                 )
-                % cnt
+                % n
             )
-            self._remNotes(ids)
-        # cards with missing notes
-        ids = self.db.list(
-            """
-select id from cards where nid not in (select id from notes)"""
-        )
-        if ids:
-            cnt = len(ids)
+        if out.cards_with_missing_note:
+            n = out.cards_with_missing_note
             problems.append(
                 ngettext(
                     "Deleted %d card with missing note.",
                     "Deleted %d cards with missing note.",
-                    cnt,
+                    n,
                 )
-                % cnt
+                % n
             )
-            self.remCards(ids)
-        # cards with odue set when it shouldn't be
-        ids = self.db.list(
-            """
-select id from cards where odue > 0 and (type=1 or queue=2) and not odid"""
-        )
-        if ids:
-            cnt = len(ids)
+        if out.cards_with_invalid_properties:
+            n = out.cards_with_invalid_properties
             problems.append(
                 ngettext(
                     "Fixed %d card with invalid properties.",
                     "Fixed %d cards with invalid properties.",
-                    cnt,
+                    n,
                 )
-                % cnt
+                % n
             )
-            self.db.execute("update cards set odue=0 where id in " + ids2str(ids))
-        # cards with odid set when not in a dyn deck
-        dids = [id for id in self.decks.allIds() if not self.decks.isDyn(id)]
-        ids = self.db.list(
-            """
-select id from cards where odid > 0 and did in %s"""
-            % ids2str(dids)
-        )
-        if ids:
-            cnt = len(ids)
+        if out.cards_with_missing_deck:
+            n = out.cards_with_missing_deck
             problems.append(
                 ngettext(
-                    "Fixed %d card with invalid properties.",
-                    "Fixed %d cards with invalid properties.",
-                    cnt,
+                    "Moved %d card with missing deck to the Default deck.",
+                    "Moved %d cards with missing deck to the Default deck.",
+                    n,
                 )
-                % cnt
-            )
-            self.db.execute(
-                "update cards set odid=0, odue=0 where id in " + ids2str(ids)
+                % n
             )
-        # tags
-        self.tags.registerNotes()
-        # field cache
-        for m in self.models.all():
-            self.updateFieldCache(self.models.nids(m))
-        # new cards can't have a due position > 32 bits, so wrap items over
-        # 2 million back to 1 million
-        curs.execute(
-            """
-update cards set due=1000000+due%1000000,mod=?,usn=? where due>=1000000
-and type=0""",
-            [intTime(), self.usn()],
-        )
-        if curs.rowcount:
+        if out.new_cards_with_large_due:
             problems.append(
                 "Found %d new cards with a due number >= 1,000,000 - consider repositioning them in the Browse screen."
-                % curs.rowcount
+                % out.new_cards_with_large_due
             )
-        # new card position
-        self.conf["nextPos"] = (
-            self.db.scalar("select max(due)+1 from cards where type = 0") or 0
-        )
-        # reviews should have a reasonable due #
-        ids = self.db.list("select id from cards where queue = 2 and due > 100000")
-        if ids:
+        if out.reviews_with_invalid_due:
             problems.append("Reviews had incorrect due date.")
-            self.db.execute(
-                "update cards set due = ?, ivl = 1, mod = ?, usn = ? where id in %s"
-                % ids2str(ids),
-                self.sched.today,
-                intTime(),
-                self.usn(),
+        if out.cards_with_fractional_values:
+            problems.append(
+                "Fixed %d cards with v2 scheduler bug."
+                % out.cards_with_fractional_values
             )
-        # v2 sched had a bug that could create decimal intervals
-        curs.execute(
-            "update cards set ivl=round(ivl),due=round(due) where ivl!=round(ivl) or due!=round(due)"
-        )
-        if curs.rowcount:
-            problems.append("Fixed %d cards with v2 scheduler bug." % curs.rowcount)
-
-        curs.execute(
-            "update revlog set ivl=round(ivl),lastIvl=round(lastIvl) where ivl!=round(ivl) or lastIvl!=round(lastIvl)"
-        )
-        if curs.rowcount:
+        if out.revlog_with_fractional_values:
             problems.append(
-                "Fixed %d review history entries with v2 scheduler bug." % curs.rowcount
+                "Fixed %d review history entries with v2 scheduler bug."
+                % out.revlog_with_fractional_values
             )
-        # models
-        if self.models.ensureNotEmpty():
-            problems.append("Added missing note type.")
-        # and finally, optimize
-        self.optimize()
-        newSize = os.stat(self.path)[stat.ST_SIZE]
-        txt = _("Database rebuilt and optimized.")
-        ok = not problems
-        problems.append(txt)
-        # if any problems were found, force a full sync
-        if not ok:
-            self.modSchema(check=False)
-        self.save()
-        return ("\n".join(problems), ok)
+        return problems
+
+    def snapshot(self) -> CollectionSnapshot:
+        """A read-only view of the collection as it is now, which can be
+        queried from a background thread. Pending changes are committed
+        first."""
+        self.db.commit()
+        return self.backend.open_snapshot()
+
+    def optimize(self) -> int:
+        """Vacuum and analyze the collection, returning the number of bytes
+        freed."""
+        self.db.commit()
+        try:
+            return self.backend.optimize()
+        finally:
+            self.lock()
 
-    def optimize(self) -> None:
-        self.db.setAutocommit(True)
-        self.db.execute("vacuum")
-        self.db.execute("analyze")
-        self.db.setAutocommit(False)
-        self.lock()
+    def fulltextIndexEnabled(self) -> bool:
+        return self.backend.fulltext_index_enabled()
+
+    def setFulltextIndex(self, enabled: bool) -> None:
+        """Build or remove the index used to speed up searches for plain
+        words. Pending changes are committed first."""
+        self.db.commit()
+        try:
+            self.backend.set_fulltext_index(enabled)
+        finally:
+            self.lock()
+
+    # Saved searches
+    ##########################################################################
+
+    def savedSearches(self) -> Dict[str, str]:
+        "Saved searches, sorted by name."
+        return self.backend.saved_searches()
+
+    def saveSearch(self, name: str, search: str) -> None:
+        "Save SEARCH as NAME, replacing any existing search with that name."
+        self._updateSavedSearches(lambda: self.backend.save_search(name, search))
+
+    def renameSavedSearch(self, oldName: str, newName: str) -> None:
+        self._updateSavedSearches(
+            lambda: self.backend.rename_saved_search(oldName, newName)
+        )
+
+    def removeSavedSearch(self, name: str) -> None:
+        self._updateSavedSearches(lambda: self.backend.remove_saved_search(name))
+
+    def _updateSavedSearches(self, func: Callable[[], Dict[str, str]]) -> None:
+        # the backend changes the config, which we also hold a copy of
+        self.save()
+        self.db.commit()
+        try:
+            self.conf["savedFilters"] = func()
+        finally:
+            self.lock()
+        self.mod = self.db.scalar("select mod from col")
 
     # Logging
     ##########################################################################
@@ -1131,12 +1246,18 @@ and type=0""",
     ##########################################################################
 
     def setUserFlag(self, flag: int, cids: List[int]) -> None:
+        "Set the flag of CIDS, or clear it if FLAG is 0. The change can be undone."
         assert 0 <= flag <= 7
-        self.db.execute(
-            "update cards set flags = (flags & ~?) | ?, usn=?, mod=? where id in %s"
-            % ids2str(cids),
-            0b111,
-            flag,
-            self.usn(),
-            intTime(),
-        )
+        self.db.commit()
+        self.backend.set_flag(cids, flag, self.usn(), intTime())
+        self.markBackendOp()
+
+    # Changing decks
+    ##########################################################################
+
+    def setDeck(self, cids: List[int], did: int) -> None:
+        """Move CIDS into the normal deck DID, returning any in a filtered deck
+        to their home deck first. The change can be undone."""
+        self.db.commit()
+        self.backend.set_deck(cids, did, self.usn(), intTime())
+        self.markBackendOp()
@@ -12,7 +12,10 @@ DBError = sqlite.Error
 
 class DB:
     def __init__(self, path: str, timeout: int = 0) -> None:
-        self._db = sqlite.connect(path, timeout=timeout)
+        # long operations such as Check Database run on a background thread
+        # while the GUI shows their progress, so the connection may be used
+        # from more than one thread, though never at the same time
+        self._db = sqlite.connect(path, timeout=timeout, check_same_thread=False)
         self._db.text_factory = self._textFactory
         self._path = path
         self.echo = os.environ.get("DBECHO")
@@ -7,13 +7,14 @@ import copy
 import json
 import operator
 import unicodedata
-from typing import Any, Dict, List, Optional, Set, Tuple, Union
+from typing import Any, Callable, Dict, List, Optional, Set, Tuple, Union
 
 import anki  # pylint: disable=unused-import
 from anki import hooks
 from anki.consts import *
-from anki.errors import DeckRenameError
+from anki.errors import DeckConfError, DeckRenameError
 from anki.lang import _
+from anki.rsbackend import BackendException
 from anki.utils import ids2str, intTime
 
 # fixmes:
@@ -136,6 +137,34 @@ class DeckManager:
             )
             self.changed = False
 
+    def _runBackendOp(self, op: Callable[[], Any]) -> Any:
+        """Run a backend operation that changes decks or options groups, then
+        update the registry to match."""
+        # the backend reads the decks from the database
+        self.col.save()
+        self.col.db.commit()
+        try:
+            ret = op()
+        finally:
+            self.col.lock()
+        self._reload()
+        return ret
+
+    def _reload(self) -> None:
+        # update the existing objects in place, as callers may hold on to them
+        decks, dconf = self.col.db.first("select decks, dconf from col")
+        for registry, saved in ((self.decks, decks), (self.dconf, dconf)):
+            saved = json.loads(saved)
+            for id in set(registry) - set(saved):
+                del registry[id]
+            for id, obj in saved.items():
+                if id in registry:
+                    registry[id].clear()
+                    registry[id].update(obj)
+                else:
+                    registry[id] = obj
+        self.col.mod = self.col.db.scalar("select mod from col")
+
     # Deck save/load
     #############################################################
 
@@ -277,28 +306,25 @@ class DeckManager:
         self.save()
 
     def rename(self, g: Dict[str, Any], newName: str) -> None:
-        "Rename deck prefix to NAME if not exists. Updates children."
-        # make sure target node doesn't already exist
-        if self.byName(newName):
-            raise DeckRenameError(_("That deck already exists."))
-        # make sure we're not nesting under a filtered deck
-        for p in self.parentsByName(newName):
-            if p["dyn"]:
-                raise DeckRenameError(_("A filtered deck cannot have subdecks."))
-        # ensure we have parents
-        newName = self._ensureParents(newName)
-        # rename children
-        for grp in self.all():
-            if grp["name"].startswith(g["name"] + "::"):
-                grp["name"] = grp["name"].replace(g["name"] + "::", newName + "::", 1)
-                self.save(grp)
-        # adjust name
-        g["name"] = newName
-        # ensure we have parents again, as we may have renamed parent->child
-        newName = self._ensureParents(newName)
-        self.save(g)
+        """Rename deck to NEWNAME, creating any missing parents. Children are
+        renamed too, and a child whose new name is already taken is merged
+        into the existing deck."""
+        try:
+            self._runBackendOp(
+                lambda: self.col.backend.rename_deck(
+                    int(g["id"]), newName, self.col.usn(), intTime()
+                )
+            )
+        except BackendException as e:
+            if e.args[0].WhichOneof("value") == "deck_rename_error":
+                raise DeckRenameError(str(e))
+            raise
+        if str(self.selected()) not in self.decks:
+            self.select(int(g["id"]))
         # renaming may have altered active did order
         self.maybeAddToActive()
+        # changes recorded before can no longer be undone
+        self.col.clearUndo()
 
     def renameForDragAndDrop(self, draggedDeckDid: int, ontoDeckDid: Any) -> None:
         draggedDeck = self.get(draggedDeckDid)
@@ -371,7 +397,8 @@ class DeckManager:
         deck = self.get(did, default=False)
         assert deck
         if "conf" in deck:
-            conf = self.getConf(deck["conf"])
+            # fall back on the default if the configuration is missing
+            conf = self.dconf.get(str(deck["conf"])) or self.getConf(1)
             conf["dyn"] = False
             return conf
         # dynamic decks have embedded conf
@@ -389,33 +416,54 @@ class DeckManager:
         if cloneFrom is None:
             cloneFrom = defaultConf
         c = copy.deepcopy(cloneFrom)
-        while 1:
-            id = intTime(1000)
-            if str(id) not in self.dconf:
-                break
-        c["id"] = id
+        c["id"] = 0
         c["name"] = name
-        self.dconf[str(id)] = c
-        self.save(c)
-        return id
+        saved = self._runBackendOp(
+            lambda: self.col.backend.add_or_update_deck_conf(
+                c, self.col.usn(), intTime()
+            )
+        )
+        return int(saved["id"])
+
+    def saveConf(self, conf: Dict[str, Any]) -> None:
+        """Save changes to a configuration, after checking them. Raises
+        DeckConfError if they're invalid, leaving the saved version as it
+        was."""
+        try:
+            self._runBackendOp(
+                lambda: self.col.backend.add_or_update_deck_conf(
+                    conf, self.col.usn(), intTime()
+                )
+            )
+        except BackendException as e:
+            err = e.args[0]
+            if err.WhichOneof("value") == "deck_conf_error":
+                raise DeckConfError(str(e), err.deck_conf_error.kind)
+            raise
 
     def remConf(self, id) -> None:
         "Remove a configuration and update all decks using it."
         assert int(id) != 1
         self.col.modSchema(check=True)
-        del self.dconf[str(id)]
-        for g in self.all():
-            # ignore cram decks
-            if "conf" not in g:
-                continue
-            if str(g["conf"]) == str(id):
-                g["conf"] = 1
-                self.save(g)
+        self._runBackendOp(
+            lambda: self.col.backend.remove_deck_conf(
+                int(id), self.col.usn(), intTime()
+            )
+        )
 
     def setConf(self, grp: Dict[str, Any], id: int) -> None:
         grp["conf"] = id
         self.save(grp)
 
+    def setConfForChildren(self, did: int, confId: int) -> int:
+        """Set the configuration of a deck and all decks below it, apart from
+        filtered decks. Returns the number of decks changed."""
+        return self._runBackendOp(
+            lambda: self.col.backend.set_deck_conf(
+                did, confId, True, self.col.usn(), intTime()
+            )
+        )
+
     def didsForConf(self, conf) -> List:
         dids = []
         for deck in list(self.decks.values()):
@@ -610,16 +658,6 @@ class DeckManager:
     def nameMap(self) -> dict:
         return dict((d["name"], d) for d in self.decks.values())
 
-    # Sync handling
-    ##########################################################################
-
-    def beforeUpload(self) -> None:
-        for d in self.all():
-            d["usn"] = 0
-        for c in self.allConf():
-            c["usn"] = 0
-        self.save()
-
     # Dynamic decks
     ##########################################################################
 
@@ -24,3 +24,15 @@ class DeckRenameError(Exception):
 
     def __str__(self):
         return "Couldn't rename deck: " + self.description
+
+
+class DeckConfError(Exception):
+    "Invalid settings in an options group. KIND is a pb.DeckConfError.Kind."
+
+    def __init__(self, description, kind) -> None:
+        super().__init__()
+        self.description = description
+        self.kind = kind
+
+    def __str__(self):
+        return self.description
@@ -4,19 +4,18 @@
 import json
 import os
 import re
-import shutil
 import typing
 import unicodedata
 import zipfile
-from io import BufferedWriter
 from typing import Any, Dict, List, Tuple
 from zipfile import ZipFile
 
 from anki import hooks
 from anki.collection import _Collection
 from anki.lang import _
+from anki.rsbackend import ExportSharingOptions
 from anki.storage import Collection
-from anki.utils import ids2str, namedtmp, splitFields, stripHTML
+from anki.utils import ids2str, namedtmp
 
 
 class Exporter:
@@ -38,32 +37,26 @@ class Exporter:
     def processText(self, text: str) -> str:
         if self.includeHTML is False:
             text = self.stripHTML(text)
+        else:
+            # newlines and tabs are not significant in HTML
+            text = text.replace("\n", " ")
+            text = text.replace("\t", " " * 8)
 
         text = self.escapeText(text)
 
         return text
 
     def escapeText(self, text: str) -> str:
-        "Escape newlines, tabs, CSS and quotechar."
-        # fixme: we should probably quote fields with newlines
-        # instead of converting them to spaces
-        text = text.replace("\n", " ")
-        text = text.replace("\t", " " * 8)
+        "Escape CSS and quotechar, quoting fields with newlines or tabs."
         text = re.sub("(?i)<style>.*?</style>", "", text)
         text = re.sub(r"\[\[type:[^]]+\]\]", "", text)
-        if '"' in text:
+        if '"' in text or "\n" in text or "\t" in text:
             text = '"' + text.replace('"', '""') + '"'
         return text
 
     def stripHTML(self, text: str) -> str:
-        # very basic conversion to text
-        s = text
-        s = re.sub(r"(?i)<(br ?/?|div|p)>", " ", s)
-        s = re.sub(r"\[sound:[^]]+\]", "", s)
-        s = stripHTML(s)
-        s = re.sub(r"[ \n\t]+", " ", s)
-        s = s.strip()
-        return s
+        # tables keep their rows and cells, everything else is on one line
+        return self.col.backend.html_to_text(text, tables=True)
 
     def cardIds(self) -> Any:
         if not self.did:
@@ -118,31 +111,26 @@ class TextNoteExporter(Exporter):
     def __init__(self, col: _Collection) -> None:
         Exporter.__init__(self, col)
         self.includeID = False
+        self.includeDeck = False
+        self.includeNotetype = False
 
-    def doExport(self, file: BufferedWriter) -> None:
-        cardIds = self.cardIds()
-        data = []
-        for id, flds, tags in self.col.db.execute(
-            """
-select guid, flds, tags from notes
-where id in
-(select nid from cards
-where cards.id in %s)"""
-            % ids2str(cardIds)
-        ):
-            row = []
-            # note id
-            if self.includeID:
-                row.append(str(id))
-            # fields
-            row.extend([self.processText(f) for f in splitFields(flds)])
-            # tags
-            if self.includeTags:
-                row.append(tags.strip())
-            data.append("\t".join(row))
-        self.count = len(data)
-        out = "\n".join(data)
-        file.write(out.encode("utf-8"))
+    def exportInto(self, path: str) -> None:
+        """Write the notes in the backend. It reads the collection file, so
+        pending changes are committed first."""
+        self.col.save()
+        self.col.db.commit()
+        try:
+            self.count = self.col.backend.export_notes_as_text(
+                path,
+                self.did or 0,
+                include_html=bool(self.includeHTML),
+                include_tags=self.includeTags,
+                include_deck=self.includeDeck,
+                include_notetype=self.includeNotetype,
+                include_guid=self.includeID,
+            )
+        finally:
+            self.col.lock()
 
 
 # Anki decks
@@ -301,38 +289,35 @@ class AnkiPackageExporter(AnkiExporter):
 
     def __init__(self, col: _Collection) -> None:
         AnkiExporter.__init__(self, col)
+        # preparation for publishing a shared deck
+        self.renameMedia = False
+        self.stripSystemTags = False
+        # a regular expression; fields with a matching name are emptied
+        self.clearFieldsMatching = ""
 
     def exportInto(self, path: str) -> None:
-        # open a zip file
-        z = zipfile.ZipFile(path, "w", zipfile.ZIP_DEFLATED, allowZip64=True)
-        media = self.doExport(z, path)
-        # media map
-        z.writestr("media", json.dumps(media))
-        z.close()
-
-    def doExport(self, z: ZipFile, path: str) -> Dict[str, str]:  # type: ignore
-        # export into the anki2 file
-        colfile = path.replace(".apkg", ".anki2")
-        AnkiExporter.exportInto(self, colfile)
-        if not self._v2sched:
-            z.write(colfile, "collection.anki2")
-        else:
-            # prevent older clients from accessing
-            # pylint: disable=unreachable
-            self._addDummyCollection(z)
-            z.write(colfile, "collection.anki21")
-
-        # and media
-        self.prepareMedia()
-        media = self._exportMedia(z, self.mediaFiles, self.mediaDir)
-        # tidy up intermediate files
-        os.unlink(colfile)
-        p = path.replace(".apkg", ".media.db2")
-        if os.path.exists(p):
-            os.unlink(p)
-        os.chdir(self.mediaDir)
-        shutil.rmtree(path.replace(".apkg", ".media"))
-        return media
+        """Write the package in the backend. It reads the collection file, so
+        pending changes are committed first."""
+        sharing = None
+        if self.renameMedia or self.stripSystemTags or self.clearFieldsMatching:
+            sharing = ExportSharingOptions(
+                rename_media=self.renameMedia,
+                strip_system_tags=self.stripSystemTags,
+                clear_fields_matching=self.clearFieldsMatching,
+            )
+        self.col.save()
+        self.col.db.commit()
+        try:
+            out = self.col.backend.export_package(
+                path,
+                self.did or 0,
+                include_scheduling=bool(self.includeSched),
+                include_media=self.includeMedia,
+                sharing=sharing,
+            )
+        finally:
+            self.col.lock()
+        self.count = out.cards
 
     def _exportMedia(self, z: ZipFile, files: List[str], fdir: str) -> Dict[str, str]:
         media = {}
@@ -351,11 +336,6 @@ class AnkiPackageExporter(AnkiExporter):
 
         return media
 
-    def prepareMedia(self) -> None:
-        # chance to move each file in self.mediaFiles into place before media
-        # is zipped up
-        pass
-
     # create a dummy collection to ensure older clients don't try to read
     # data they don't understand
     def _addDummyCollection(self, zip) -> None:
@@ -385,6 +365,14 @@ class AnkiCollectionPackageExporter(AnkiPackageExporter):
     def __init__(self, col):
         AnkiPackageExporter.__init__(self, col)
 
+    def exportInto(self, path: str) -> None:
+        # open a zip file
+        z = zipfile.ZipFile(path, "w", zipfile.ZIP_DEFLATED, allowZip64=True)
+        media = self.doExport(z, path)
+        # media map
+        z.writestr("media", json.dumps(media))
+        z.close()
+
     def doExport(self, z, path):
         # close our deck & write it into the zip file, and reopen
         self.count = self.col.cardCount()
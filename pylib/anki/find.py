@@ -4,11 +4,14 @@
 import re
 import sre_constants
 import unicodedata
-from typing import Any, List, Optional, Set, Tuple
+from contextlib import contextmanager
+from typing import Any, Iterator, List, Optional, Set, Tuple
 
 from anki import hooks
 from anki.consts import *
+from anki.errors import AnkiError
 from anki.hooks import *
+from anki.rsbackend import FindReplaceNote, SearchContext
 from anki.utils import (
     fieldChecksum,
     ids2str,
@@ -40,16 +43,157 @@ class Finder:
             flag=self._findFlag,
         )
         self.search["is"] = self._findCardState
+        builtin = dict(self.search)
         hooks.search_terms_prepared(self.search)
+        self._customTerms = {
+            key for key, fn in self.search.items() if builtin.get(key) != fn
+        }
 
     def findCards(self, query, order=False) -> Any:
-        "Return a list of card ids for QUERY."
+        """Return a list of card ids for QUERY. ORDER can be False for no
+        order, True for the browser's sort column, or an SQL order clause."""
+        if self._usesCustomTerms(query):
+            return self._legacyFindCards(query, order)
+        # the sort column may have been changed without being saved
+        order, rev = self._order(order)
+        with self._backendSearch():
+            res = self.col.backend.search_cards(query, self._searchContext(), order)
+        if rev:
+            res.reverse()
+        return res
+
+    def findNotes(self, query) -> Any:
+        if self._usesCustomTerms(query):
+            return self._legacyFindNotes(query)
+        with self._backendSearch():
+            return self.col.backend.search_notes(query, self._searchContext())
+
+    def findCardsPage(
+        self, query: str, offset: int, limit: int
+    ) -> Tuple[List[int], int]:
+        """A page of the ids findCards(query, order=True) returns, and the
+        number of matching cards, so large results needn't be loaded at
+        once. A limit of 0 returns all the cards after offset."""
+        if self._usesCustomTerms(query):
+            ids = self._legacyFindCards(query, order=True)
+            return self._page(ids, offset, limit), len(ids)
+        column, reverse = self._sortColumn()
+        with self._backendSearch():
+            return self.col.backend.search_cards_page(
+                query, self._searchContext(), column, reverse, offset, limit
+            )
+
+    def findNotesPage(
+        self, query: str, offset: int, limit: int
+    ) -> Tuple[List[int], int]:
+        """Like findCardsPage(), but for notes. Notes are sorted on the
+        browser's column if it's a note column, or by creation otherwise."""
+        if self._usesCustomTerms(query):
+            ids = self._legacyFindNotes(query)
+            return self._page(ids, offset, limit), len(ids)
+        column, reverse = self._sortColumn()
+        with self._backendSearch():
+            return self.col.backend.search_notes_page(
+                query, self._searchContext(), column, reverse, offset, limit
+            )
+
+    def _sortColumn(self) -> Tuple[str, bool]:
+        return (
+            self.col.conf.get("sortType", "noteCrt"),
+            self.col.conf.get("sortBackwards", False),
+        )
+
+    def _page(self, ids: List[int], offset: int, limit: int) -> List[int]:
+        if limit:
+            return ids[offset : offset + limit]
+        return ids[offset:]
+
+    @contextmanager
+    def _backendSearch(self) -> Iterator[None]:
+        # the backend reads from the collection file, and may need to write
+        # to it to update the full-text index
+        self.col.save()
+        self.col.db.commit()
+        try:
+            yield
+        finally:
+            self.col.lock()
+
+    def _searchContext(self) -> SearchContext:
+        sched = self.col.sched
+        return SearchContext(
+            today=sched.today,
+            day_cutoff=sched.dayCutoff,
+            current_deck_id=self.col.decks.current()["id"],
+        )
+
+    def _usesCustomTerms(self, query) -> bool:
+        "True if QUERY uses a term added or replaced by an add-on."
+        if not self._customTerms:
+            return False
+        for token in self._tokenize(query):
+            key = token.lstrip("-").split(":", 1)[0].lower()
+            if ":" in token and key in self._customTerms:
+                return True
+        return False
+
+    # Duplicates
+    ######################################################################
+
+    def findDupes(self, fieldName: str, search="") -> List[Tuple[str, List[int]]]:
+        """Groups of notes sharing the same text in FIELDNAME, or the first
+        field if it's empty, as (text, nids). An invalid search finds nothing."""
+        search = self._dupesSearch(search)
+        if search is None:
+            return []
+        try:
+            with self._backendSearch():
+                return self.col.backend.find_duplicates(
+                    fieldName, search, self._searchContext()
+                )
+        except AnkiError:
+            return []
+
+    def tagDupes(self, fieldName: str, search: str, tag: str) -> int:
+        "Add TAG to the notes findDupes() returns. Returns the number changed."
+        search = self._dupesSearch(search)
+        if search is None:
+            return 0
+        try:
+            with self._backendSearch():
+                return self.col.backend.tag_duplicates(
+                    fieldName,
+                    search,
+                    self._searchContext(),
+                    tag,
+                    self.col.usn(),
+                    intTime(),
+                )
+        except AnkiError:
+            return 0
+
+    def _dupesSearch(self, search: str) -> Optional[str]:
+        # add-on terms are resolved to note ids first, as the backend
+        # doesn't know about them
+        if not self._usesCustomTerms(search):
+            return search
+        nids = self._legacyFindNotes(search)
+        if not nids:
+            return None
+        return "nid:" + ",".join(str(nid) for nid in nids)
+
+    # Legacy searching
+    ######################################################################
+
+    # Searches using terms added by add-ons are run in Python.
+
+    def _legacyFindCards(self, query, order=False) -> Any:
         tokens = self._tokenize(query)
         preds, args = self._where(tokens)
         if preds is None:
-            raise Exception("invalidSearch")
+            raise AnkiError("invalidSearch")
         order, rev = self._order(order)
-        sql = self._query(preds, order)
+        sql = self._query(preds, order and " order by " + order)
         try:
             res = self.col.db.list(sql, *args)
         except:
@@ -59,7 +203,7 @@ class Finder:
             res.reverse()
         return res
 
-    def findNotes(self, query) -> Any:
+    def _legacyFindNotes(self, query) -> Any:
         tokens = self._tokenize(query)
         preds, args = self._where(tokens)
         if preds is None:
@@ -221,7 +365,7 @@ select distinct(n.id) from cards c, notes n where c.nid=n.id and """
             return "", False
         elif order is not True:
             # custom order string provided
-            return " order by " + order, False
+            return order, False
         # use deck default
         type = self.col.conf["sortType"]
         sort = None
@@ -248,7 +392,7 @@ select distinct(n.id) from cards c, notes n where c.nid=n.id and """
         if not sort:
             # deck has invalid sort order; revert to noteCrt
             sort = "n.id, c.ord"
-        return " order by " + sort, self.col.conf["sortBackwards"]
+        return sort, self.col.conf["sortBackwards"]
 
     # Commands
     ######################################################################
@@ -500,8 +644,12 @@ where mid in %s and flds like ? escape '\\'"""
 ##########################################################################
 
 
-def findReplace(col, nids, src, dst, regex=False, field=None, fold=True) -> int:
-    "Find and replace fields in a note."
+def findReplace(
+    col, nids, src, dst, regex=False, field=None, fold=True, tags=False
+) -> int:
+    """Find and replace fields in a note. If tags is true and no field is
+    provided, tags are also changed. In regex mode, the replacement can
+    refer to groups with $1 or ${name}. Returns the number of notes changed."""
     mmap = {}
     if field:
         for m in col.models.all():
@@ -510,44 +658,40 @@ def findReplace(col, nids, src, dst, regex=False, field=None, fold=True) -> int:
                     mmap[str(m["id"])] = f["ord"]
         if not mmap:
             return 0
-    # find and gather replacements
-    if not regex:
-        src = re.escape(src)
-        dst = dst.replace("\\", "\\\\")
-    if fold:
-        src = "(?i)" + src
-    regex = re.compile(src)
-
-    def repl(str):
-        return re.sub(regex, dst, str)
-
-    d = []
-    snids = ids2str(nids)
-    nids = []
-    for nid, mid, flds in col.db.execute(
-        "select id, mid, flds from notes where id in " + snids
+    # gather notes
+    originals = []
+    notes = []
+    for nid, mid, flds, ntags in col.db.execute(
+        "select id, mid, flds, tags from notes where id in " + ids2str(nids)
     ):
-        origFlds = flds
-        # does it match?
-        sflds = splitFields(flds)
         if field:
-            try:
-                ord = mmap[str(mid)]
-                sflds[ord] = repl(sflds[ord])
-            except KeyError:
+            if str(mid) not in mmap:
                 # note doesn't have that field
                 continue
+            ord = mmap[str(mid)]
         else:
-            for c in range(len(sflds)):
-                sflds[c] = repl(sflds[c])
-        flds = joinFields(sflds)
-        if flds != origFlds:
+            ord = None
+        originals.append((nid, flds, ntags))
+        notes.append(
+            FindReplaceNote(fields=splitFields(flds), tags=ntags, field_ord=ord)
+        )
+    # find and gather replacements
+    updated, _count = col.backend.find_and_replace(
+        notes, src, dst, bool(regex), not fold, tags and not field
+    )
+    d = []
+    nids = []
+    for (nid, origFlds, origTags), note in zip(originals, updated):
+        flds = joinFields(note.fields)
+        if flds != origFlds or note.tags != origTags:
             nids.append(nid)
-            d.append(dict(nid=nid, flds=flds, u=col.usn(), m=intTime()))
+            d.append(dict(nid=nid, flds=flds, t=note.tags, u=col.usn(), m=intTime()))
     if not d:
         return 0
     # replace
-    col.db.executemany("update notes set flds=:flds,mod=:m,usn=:u where id=:nid", d)
+    col.db.executemany(
+        "update notes set flds=:flds,tags=:t,mod=:m,usn=:u where id=:nid", d
+    )
     col.updateFieldCache(nids)
     col.genCards(nids)
     return len(d)
@@ -577,38 +721,5 @@ def fieldNamesForNotes(col, nids) -> List:
 # Find duplicates
 ##########################################################################
 # returns array of ("dupestr", [nids])
-def findDupes(col, fieldName, search="") -> List[Tuple[Any, List]]:
-    # limit search to notes with applicable field name
-    if search:
-        search = "(" + search + ") "
-    search += "'%s:*'" % fieldName
-    # go through notes
-    vals: Dict[str, List[int]] = {}
-    dupes = []
-    fields: Dict[int, int] = {}
-
-    def ordForMid(mid):
-        if mid not in fields:
-            model = col.models.get(mid)
-            for c, f in enumerate(model["flds"]):
-                if f["name"].lower() == fieldName.lower():
-                    fields[mid] = c
-                    break
-        return fields[mid]
-
-    for nid, mid, flds in col.db.all(
-        "select id, mid, flds from notes where id in " + ids2str(col.findNotes(search))
-    ):
-        flds = splitFields(flds)
-        ord = ordForMid(mid)
-        if ord is None:
-            continue
-        val = flds[ord]
-        val = stripHTMLMedia(val)
-        # empty does not count as duplicate
-        if not val:
-            continue
-        vals.setdefault(val, []).append(nid)
-        if len(vals[val]) == 2:
-            dupes.append((val, vals[val]))
-    return dupes
+def findDupes(col, fieldName, search="") -> List[Tuple[str, List[int]]]:
+    return Finder(col).findDupes(fieldName, search)
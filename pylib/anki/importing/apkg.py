@@ -1,59 +1,71 @@
 # Copyright: Ankitects Pty Ltd and contributors
 # License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
 
-import json
-import os
-import unicodedata
-import zipfile
-from typing import Any, Dict, Optional
+from typing import List, Optional
 
-from anki.importing.anki2 import Anki2Importer
-from anki.utils import tmpfile
+from anki.importing.base import Importer
+from anki.lang import _
+from anki.rsbackend import DuplicateMode, ImportedNote, ImportPackageOut
 
 
-class AnkiPackageImporter(Anki2Importer):
-    nameToNum: Dict[str, str]
-    zip: Optional[zipfile.ZipFile]
+class AnkiPackageImporter(Importer):
+    "Add the contents of an .apkg file to the collection, in the backend."
+
+    # if not set, existing notes are updated when the file has a newer
+    # version if allowUpdate is true, and skipped otherwise
+    duplicateMode: Optional[int] = None
+    allowUpdate = True
 
     def run(self) -> None:  # type: ignore
-        # extract the deck from the zip file
-        self.zip = z = zipfile.ZipFile(self.file)
-        # v2 scheduler?
+        # the backend reads the collection file, so pending changes are
+        # committed first
+        self.col.save()
+        self.col.db.commit()
         try:
-            z.getinfo("collection.anki21")
-            suffix = ".anki21"
-        except KeyError:
-            suffix = ".anki2"
-
-        col = z.read("collection" + suffix)
-        colpath = tmpfile(suffix=suffix)
-        with open(colpath, "wb") as f:
-            f.write(col)
-        self.file = colpath
-        # we need the media dict in advance, and we'll need a map of fname ->
-        # number to use during the import
-        self.nameToNum = {}
-        dir = self.col.media.dir()
-        for k, v in list(json.loads(z.read("media").decode("utf8")).items()):
-            path = os.path.abspath(os.path.join(dir, v))
-            if os.path.commonprefix([path, dir]) != dir:
-                raise Exception("Invalid file")
-
-            self.nameToNum[unicodedata.normalize("NFC", v)] = k
-        # run anki2 importer
-        Anki2Importer.run(self)
-        # import static media
-        for file, c in list(self.nameToNum.items()):
-            if not file.startswith("_") and not file.startswith("latex-"):
-                continue
-            path = os.path.join(self.col.media.dir(), file)
-            if not os.path.exists(path):
-                with open(path, "wb") as f:
-                    f.write(z.read(c))
-
-    def _srcMediaData(self, fname: str) -> Any:
-        if fname in self.nameToNum:
-            return self.zip.read(
-                self.nameToNum[fname]
-            )  # pytype: disable=attribute-error
-        return None
+            out = self.col.backend.import_package(self.file, self._duplicateMode())
+        finally:
+            self.col.lock()
+        # the backend may have added notetypes, decks and options
+        self.col.load()
+        self._logImport(out)
+        # export info for calling code
+        self.dupes = len(out.duplicate)
+        self.added = len(out.added)
+        self.updated = len(out.updated)
+
+    def _duplicateMode(self) -> int:
+        if self.duplicateMode is not None:
+            return self.duplicateMode
+        elif self.allowUpdate:
+            return DuplicateMode.UPDATE_IF_NEWER
+        else:
+            return DuplicateMode.SKIP
+
+    def _logImport(self, out: ImportPackageOut) -> None:
+        self.log.append(_("Notes found in file: %d") % out.found_notes)
+        if out.conflicting:
+            self.log.append(
+                _("Notes that could not be imported as note type has changed: %d")
+                % len(out.conflicting)
+            )
+        if out.updated:
+            self.log.append(
+                _("Notes updated, as file had newer version: %d") % len(out.updated)
+            )
+        if out.added:
+            self.log.append(_("Notes added from file: %d") % len(out.added))
+        if out.duplicate:
+            self.log.append(
+                _("Notes skipped, as they're already in your collection: %d")
+                % len(out.duplicate)
+            )
+        self.log.append("")
+
+        self._logNotes(_("Skipped"), out.conflicting)
+        self._logNotes(_("Updated"), out.updated)
+        self._logNotes(_("Added"), out.added)
+        self._logNotes(_("Identical"), out.duplicate)
+
+    def _logNotes(self, action: str, notes: List[ImportedNote]) -> None:
+        for note in notes:
+            self.log.append("[%s] %s" % (action, ", ".join(note.fields)))
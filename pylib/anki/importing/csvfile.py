@@ -1,132 +1,56 @@
 # Copyright: Ankitects Pty Ltd and contributors
 # License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
 
-import csv
-import re
-from typing import Any, List, Optional, TextIO, Union
+from typing import List, Optional
 
 from anki.collection import _Collection
-from anki.importing.noteimp import ForeignNote, NoteImporter
-from anki.lang import _
+from anki.importing.noteimp import NoteImporter
+from anki.lang import _, ngettext
+from anki.rsbackend import (
+    ImportTextOut,
+    TextColumnMapping,
+    TextImportPreview,
+    TextImportProblem,
+)
+
+# Besides field names and _tags, columns can be mapped to _deck, which names
+# the deck new cards go in, and _guid, which is used instead of the first
+# field to find existing notes.
+SPECIAL_COLUMNS = {
+    "_tags": TextColumnMapping.TAGS,
+    "_deck": TextColumnMapping.DECK,
+    "_guid": TextColumnMapping.GUID,
+}
 
 
 class TextImporter(NoteImporter):
+    "Import a delimited text file in the backend."
 
     needDelimiter = True
-    patterns = "\t|,;:"
 
     def __init__(self, col: _Collection, file: str) -> None:
         NoteImporter.__init__(self, col, file)
-        self.lines = None
-        self.fileobj: Optional[TextIO] = None
+        # set when the user chooses a delimiter
         self.delimiter: Optional[str] = None
+        self.detectedDelimiter: Optional[str] = None
+        self.preview: Optional[TextImportPreview] = None
         self.tagsToAdd: List[str] = []
         self.numFields = 0
-        self.dialect: Optional[Any]
-        self.data: Optional[Union[str, List[str]]]
-
-    def foreignNotes(self) -> List[ForeignNote]:
-        self.open()
-        # process all lines
-        log = []
-        notes = []
-        lineNum = 0
-        ignored = 0
-        if self.delimiter:
-            reader = csv.reader(self.data, delimiter=self.delimiter, doublequote=True)
-        else:
-            reader = csv.reader(self.data, self.dialect, doublequote=True)
-        try:
-            for row in reader:
-                if len(row) != self.numFields:
-                    if row:
-                        log.append(
-                            _("'%(row)s' had %(num1)d fields, " "expected %(num2)d")
-                            % {
-                                "row": " ".join(row),
-                                "num1": len(row),
-                                "num2": self.numFields,
-                            }
-                        )
-                        ignored += 1
-                    continue
-                note = self.noteFromFields(row)
-                notes.append(note)
-        except (csv.Error) as e:
-            log.append(_("Aborted: %s") % str(e))
-        self.log = log
-        self.ignored = ignored
-        self.fileobj.close()
-        return notes
+        self.updateCount = 0
 
     def open(self) -> None:
-        "Parse the top line and determine the pattern and number of fields."
-        # load & look for the right pattern
-        self.cacheFile()
-
-    def cacheFile(self) -> None:
-        "Read file into self.lines if not already there."
-        if not self.fileobj:
-            self.openFile()
-
-    def openFile(self) -> None:
-        self.dialect = None
-        self.fileobj = open(self.file, "r", encoding="utf-8-sig")
-        self.data = self.fileobj.read()
-
-        def sub(s):
-            return re.sub(r"^\#.*$", "__comment", s)
-
-        self.data = [
-            sub(x) + "\n" for x in self.data.split("\n") if sub(x) != "__comment"
-        ]
-        if self.data:
-            if self.data[0].startswith("tags:"):
-                tags = str(self.data[0][5:]).strip()
-                self.tagsToAdd = tags.split(" ")
-                del self.data[0]
+        "Read the top of the file, to find the delimiter and number of fields."
+        if self.preview is None:
             self.updateDelimiter()
-        if not self.dialect and not self.delimiter:
-            raise Exception("unknownFormat")
 
     def updateDelimiter(self) -> None:
-        def err():
+        preview = self.col.backend.preview_text_import(self.file, self.delimiter or "")
+        if not preview.column_count:
             raise Exception("unknownFormat")
-
-        self.dialect = None
-        sniffer = csv.Sniffer()
-        if not self.delimiter:
-            try:
-                self.dialect = sniffer.sniff("\n".join(self.data[:10]), self.patterns)
-            except:
-                try:
-                    self.dialect = sniffer.sniff(self.data[0], self.patterns)
-                except:
-                    pass
-        if self.dialect:
-            try:
-                reader = csv.reader(self.data, self.dialect, doublequote=True)
-            except:
-                err()
-        else:
-            if not self.delimiter:
-                if "\t" in self.data[0]:
-                    self.delimiter = "\t"
-                elif ";" in self.data[0]:
-                    self.delimiter = ";"
-                elif "," in self.data[0]:
-                    self.delimiter = ","
-                else:
-                    self.delimiter = " "
-            reader = csv.reader(self.data, delimiter=self.delimiter, doublequote=True)
-        try:
-            while True:
-                row = next(reader)
-                if row:
-                    self.numFields = len(row)
-                    break
-        except:
-            err()
+        self.preview = preview
+        self.detectedDelimiter = preview.delimiter
+        self.tagsToAdd = list(preview.tags)
+        self.numFields = preview.column_count
         self.initMapping()
 
     def fields(self) -> int:
@@ -134,8 +58,97 @@ class TextImporter(NoteImporter):
         self.open()
         return self.numFields
 
-    def noteFromFields(self, fields: List[str]) -> ForeignNote:
-        note = ForeignNote()
-        note.fields.extend([x for x in fields])
-        note.tags.extend(self.tagsToAdd)
-        return note
+    def run(self) -> None:
+        assert self.mapping
+        assert self.mappingOk()
+        self.open()
+        if self.importMode == 0 and self.tagModified:
+            tagsForUpdated = self.tagModified.split()
+        else:
+            tagsForUpdated = []
+        # the backend reads the collection file, so pending changes are
+        # committed first
+        self.col.save()
+        self.col.db.commit()
+        try:
+            out = self.col.backend.import_text(
+                self.file,
+                self.model["id"],
+                self.model.get("did") or 1,
+                columns=self._columns(),
+                allow_html=self.allowHTML,
+                duplicate_mode=self.importMode,
+                delimiter=self.delimiter or self.detectedDelimiter or "",
+                tags_for_updated=tagsForUpdated,
+            )
+        finally:
+            self.col.lock()
+        # the backend may have added decks and tags
+        self.col.load()
+        self._logImport(out)
+        self.total = len(out.note_ids)
+        self.updateCount = out.updated
+
+    def _columns(self) -> List[TextColumnMapping]:
+        ords = {f["name"]: f["ord"] for f in self.model["flds"]}
+        columns = []
+        for name in self.mapping:
+            if name in SPECIAL_COLUMNS:
+                columns.append(TextColumnMapping(kind=SPECIAL_COLUMNS[name]))
+            elif name in ords:
+                column = TextColumnMapping(
+                    kind=TextColumnMapping.FIELD, field_ord=ords[name]
+                )
+                columns.append(column)
+            else:
+                columns.append(TextColumnMapping(kind=TextColumnMapping.IGNORED))
+        return columns
+
+    def _logImport(self, out: ImportTextOut) -> None:
+        log = []
+        for problem in out.problems:
+            if problem.kind == TextImportProblem.WRONG_FIELD_COUNT:
+                log.append(
+                    _("'%(row)s' had %(num1)d fields, " "expected %(num2)d")
+                    % {
+                        "row": " ".join(problem.fields),
+                        "num1": len(problem.fields),
+                        "num2": out.column_count,
+                    }
+                )
+            elif problem.kind == TextImportProblem.EMPTY_FIRST_FIELD:
+                log.append(_("Empty first field: %s") % " ".join(problem.fields))
+            elif problem.kind == TextImportProblem.REPEATED:
+                log.append(_("Appeared twice in file: %s") % problem.fields[0])
+            else:
+                log.append(
+                    _("GUID belongs to a note of another note type: %s")
+                    % problem.fields[0]
+                )
+        if out.parse_error:
+            log.append(_("Aborted: %s") % out.parse_error)
+
+        part1 = ngettext("%d note added", "%d notes added", out.added) % out.added
+        part2 = (
+            ngettext("%d note updated", "%d notes updated", out.updated) % out.updated
+        )
+        part3 = (
+            ngettext("%d note unchanged", "%d notes unchanged", out.unchanged)
+            % out.unchanged
+        )
+        log.append("%s, %s, %s." % (part1, part2, part3))
+        if self.importMode == 2:
+            matchedTxt = _("Added duplicate with first field: %s")
+        else:
+            matchedTxt = _("First field matched: %s")
+        log.extend(matchedTxt % field for field in out.matched)
+        if out.without_cards:
+            log.append(
+                _(
+                    """\
+One or more notes were not imported, because they didn't generate any cards. \
+This can happen when you have empty fields or when you have not mapped the \
+content in the text file to the correct fields."""
+                )
+            )
+        self.log = log
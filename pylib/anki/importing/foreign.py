@@ -0,0 +1,43 @@
+# Copyright: Ankitects Pty Ltd and contributors
+# License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+from anki.importing.base import Importer
+from anki.lang import _, ngettext
+
+
+class ForeignImporter(Importer):
+    """Import another program's file in the backend. The notes are added with
+    new note types, and cards that have been studied keep their scheduling."""
+
+    # a ForeignImportKind
+    kind = 0
+
+    def run(self) -> None:
+        # the backend reads the collection file, so pending changes are
+        # committed first
+        self.col.save()
+        self.col.db.commit()
+        try:
+            out = self.col.backend.import_foreign(
+                self.kind, self.file, self.col.decks.selected()
+            )
+        finally:
+            self.col.lock()
+        # the backend has added note types and tags
+        self.col.load()
+
+        if out.unknown_version:
+            self.log.append(_("File version unknown, trying import anyway."))
+        self.total = out.notes_added
+        self.log.append(
+            ngettext("%d note imported.", "%d notes imported.", self.total) % self.total
+        )
+        if out.without_cards:
+            self.log.append(
+                ngettext(
+                    "%d note was not imported, as it would have no cards.",
+                    "%d notes were not imported, as they would have no cards.",
+                    out.without_cards,
+                )
+                % out.without_cards
+            )
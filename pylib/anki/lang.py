@@ -4,6 +4,7 @@
 # Please leave the coding line in this file to prevent xgettext complaining.
 
 import gettext
+import os
 import re
 import threading
 from typing import Any
@@ -111,6 +112,8 @@ threadLocal = threading.local()
 # global defaults
 currentLang: Any = None
 currentTranslation: Any = None
+# the translations used by the backend, in a folder per language
+ftlFolder = ""
 
 
 def localTranslation() -> Any:
@@ -130,6 +133,9 @@ def ngettext(single: str, plural: str, n: int) -> str:
 
 
 def setLang(lang: str, locale_dir: str, local: bool = True) -> None:
+    global ftlFolder
+    if locale_dir:
+        ftlFolder = os.path.join(locale_dir, "ftl")
     lang = mungeCode(lang)
     trans = gettext.translation("anki", locale_dir, languages=[lang], fallback=True)
     if local:
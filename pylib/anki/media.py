@@ -1,7 +1,5 @@
 # Copyright: Ankitects Pty Ltd and contributors
 # License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
-import io
-import json
 import os
 import pathlib
 import re
@@ -11,15 +9,15 @@ import unicodedata
 import urllib.error
 import urllib.parse
 import urllib.request
-import zipfile
 from typing import Any, Callable, List, Optional, Tuple, Union
 
+import anki
 from anki.consts import *
-from anki.db import DB, DBError
-from anki.lang import _
+from anki.db import DB
 from anki.latex import render_latex
+from anki.rsbackend import ImageTranscodeConfig, SyncNetworkSettings, TrashedMediaFile
 from anki.template import expand_clozes
-from anki.utils import checksum, isMac, isWin
+from anki.utils import checksum, isMac, isWin, platDesc
 
 
 class MediaManager:
@@ -154,11 +152,22 @@ create table meta (dirMod int, lastUsn int); insert into meta values (0, 0);
     ##########################################################################
     # opath must be in unicode
 
-    def addFile(self, opath: str) -> Any:
+    def addFile(
+        self, opath: str, transcode: Optional[ImageTranscodeConfig] = None
+    ) -> Any:
         with open(opath, "rb") as f:
-            return self.writeData(opath, f.read())
-
-    def writeData(self, opath: str, data: bytes, typeHint: Optional[str] = None) -> Any:
+            return self.writeData(opath, f.read(), transcode=transcode)
+
+    def writeData(
+        self,
+        opath: str,
+        data: bytes,
+        typeHint: Optional[str] = None,
+        transcode: Optional[ImageTranscodeConfig] = None,
+    ) -> Any:
+        """Add data to the media folder, returning the name it was stored
+        under. If transcode is provided, large PNG and BMP images are
+        converted to WebP."""
         # if fname is a full path, use only the basename
         fname = os.path.basename(opath)
 
@@ -173,37 +182,10 @@ create table meta (dirMod int, lastUsn int); insert into meta values (0, 0);
             if typeHint in typeMap:
                 fname += typeMap[typeHint]
 
-        # make sure we write it in NFC form (pre-APFS Macs will autoconvert to NFD),
-        # and return an NFC-encoded reference
-        fname = unicodedata.normalize("NFC", fname)
-        # ensure it's a valid filename
-        base = self.cleanFilename(fname)
-        (root, ext) = os.path.splitext(base)
-
-        def repl(match):
-            n = int(match.group(1))
-            return " (%d)" % (n + 1)
-
-        # find the first available name
-        csum = checksum(data)
-        while True:
-            fname = root + ext
-            path = os.path.join(self.dir(), fname)
-            # if it doesn't exist, copy it directly
-            if not os.path.exists(path):
-                with open(path, "wb") as f:
-                    f.write(data)
-                return fname
-            # if it's identical, reuse
-            with open(path, "rb") as f:
-                if checksum(f.read()) == csum:
-                    return fname
-            # otherwise, increment the index in the filename
-            reg = r" \((\d+)\)$"
-            if not re.search(reg, root):
-                root = root + " (1)"
-            else:
-                root = re.sub(reg, repl, root)
+        # the backend normalizes the name, and reuses an existing file if it
+        # has the same contents
+        self.db.commit()
+        return self.col.backend.add_media_file(fname, data, transcode)
 
     # String manipulation
     ##########################################################################
@@ -263,91 +245,41 @@ create table meta (dirMod int, lastUsn int); insert into meta values (0, 0);
     # Rebuilding DB
     ##########################################################################
 
-    def check(
-        self, local: Optional[List[str]] = None
-    ) -> Tuple[List[str], List[str], List[str]]:
-        "Return (missingFiles, unusedFiles)."
-        mdir = self.dir()
-        # gather all media references in NFC form
-        allRefs = set()
-        for nid, mid, flds in self.col.db.execute("select id, mid, flds from notes"):
-            noteRefs = self.filesInStr(mid, flds)
-            # check the refs are in NFC
-            for f in noteRefs:
-                # if they're not, we'll need to fix them first
-                if f != unicodedata.normalize("NFC", f):
-                    self._normalizeNoteRefs(nid)
-                    noteRefs = self.filesInStr(mid, flds)
-                    break
-            allRefs.update(noteRefs)
-        # loop through media folder
-        unused = []
-        if local is None:
-            files = os.listdir(mdir)
-        else:
-            files = local
-        renamedFiles = False
-        dirFound = False
-        warnings = []
-        for file in files:
-            if not local:
-                if not os.path.isfile(file):
-                    # ignore directories
-                    dirFound = True
-                    continue
-            if file.startswith("_"):
-                # leading _ says to ignore file
-                continue
-
-            if self.hasIllegal(file):
-                name = file.encode(sys.getfilesystemencoding(), errors="replace")
-                name = str(name, sys.getfilesystemencoding())
-                warnings.append(_("Invalid file name, please rename: %s") % name)
-                continue
-
-            nfcFile = unicodedata.normalize("NFC", file)
-            # we enforce NFC fs encoding on non-macs
-            if not isMac and not local:
-                if file != nfcFile:
-                    # delete if we already have the NFC form, otherwise rename
-                    if os.path.exists(nfcFile):
-                        os.unlink(file)
-                        renamedFiles = True
-                    else:
-                        os.rename(file, nfcFile)
-                        renamedFiles = True
-                    file = nfcFile
-            # compare
-            if nfcFile not in allRefs:
-                unused.append(file)
-            else:
-                allRefs.discard(nfcFile)
-        # if we renamed any files to nfc format, we must rerun the check
-        # to make sure the renamed files are not marked as unused
-        if renamedFiles:
-            return self.check(local=local)
-        nohave = [x for x in allRefs if not x.startswith("_")]
-        # make sure the media DB is valid
-        try:
-            self.findChanges()
-        except DBError:
-            self._deleteDB()
-
-        if dirFound:
-            warnings.append(
-                _(
-                    "Anki does not support files in subfolders of the collection.media folder."
-                )
-            )
-        return (nohave, unused, warnings)
+    def check(self, dry_run: bool = False) -> Tuple[List[str], List[str], List[str]]:
+        """Return (missingFiles, unusedFiles, warnings).
+
+        Filenames and references in notes that are not in NFC form are
+        normalized so they match. If dry_run is true, the warnings report
+        what would be changed, and nothing is modified."""
+        # the backend reads the collection and media DB from disk
+        self.col.db.commit()
+        self.db.commit()
+        output = self.col.backend.check_media(dry_run)
+        # latex images are generated when rendered
+        if not dry_run:
+            for nid in output.notes_missing_latex:
+                note = self.col.getNote(nid)
+                self.filesInStr(note.mid, note.joinedFields())
+        return (list(output.missing), list(output.unused), list(output.warnings))
+
+    def trash_files(self, fnames: List[str]) -> None:
+        "Move the provided files into the media trash folder."
+        self.db.commit()
+        self.col.backend.trash_media_files(fnames)
+
+    def trash_contents(self) -> List[TrashedMediaFile]:
+        "Files in the media trash folder, sorted by name."
+        return self.col.backend.media_trash_contents()
+
+    def restore_from_trash(self, fnames: List[str]) -> List[str]:
+        """Move the provided files from the trash back into the media folder,
+        returning the names they were restored under."""
+        self.db.commit()
+        return self.col.backend.restore_media_trash(fnames)
 
-    def _normalizeNoteRefs(self, nid) -> None:
-        note = self.col.getNote(nid)
-        for c, fld in enumerate(note.fields):
-            nfc = unicodedata.normalize("NFC", fld)
-            if nfc != fld:
-                note.fields[c] = nfc
-        note.flush()
+    def empty_trash(self) -> None:
+        "Permanently delete the files in the media trash folder."
+        self.col.backend.empty_media_trash()
 
     # Copying on import
     ##########################################################################
@@ -427,9 +359,6 @@ create table meta (dirMod int, lastUsn int); insert into meta values (0, 0);
         if self._changed():
             self._logChanges()
 
-    def haveDirty(self) -> Any:
-        return self.db.scalar("select 1 from media where dirty=1 limit 1")
-
     def _mtime(self, path: str) -> int:
         return int(os.stat(path).st_mtime)
 
@@ -518,34 +447,21 @@ create table meta (dirMod int, lastUsn int); insert into meta values (0, 0);
                 removed.append(k)
         return added, removed
 
-    # Syncing-related
+    # Syncing
     ##########################################################################
 
-    def lastUsn(self) -> Any:
-        return self.db.scalar("select lastUsn from meta")
-
-    def setLastUsn(self, usn) -> None:
-        self.db.execute("update meta set lastUsn = ?", usn)
+    def sync(
+        self,
+        hkey: str,
+        endpoint: str,
+        network: Optional[SyncNetworkSettings] = None,
+    ) -> int:
+        """Sync the media folder with the server, returning a
+        SyncMediaOut.Outcome."""
+        # the backend updates the media DB itself
         self.db.commit()
-
-    def syncInfo(self, fname) -> Any:
-        ret = self.db.first("select csum, dirty from media where fname=?", fname)
-        return ret or (None, 0)
-
-    def markClean(self, fnames) -> None:
-        for fname in fnames:
-            self.db.execute("update media set dirty=0 where fname=?", fname)
-
-    def syncDelete(self, fname) -> None:
-        if os.path.exists(fname):
-            os.unlink(fname)
-        self.db.execute("delete from media where fname=?", fname)
-
-    def mediaCount(self) -> Any:
-        return self.db.scalar("select count() from media where csum is not null")
-
-    def dirtyCount(self) -> Any:
-        return self.db.scalar("select count() from media where dirty=1")
+        client_version = "ankidesktop,%s,%s" % (anki.version, platDesc())
+        return self.col.backend.sync_media(hkey, endpoint, client_version, network)
 
     def forceResync(self) -> None:
         self.db.execute("delete from media")
@@ -555,71 +471,3 @@ create table meta (dirMod int, lastUsn int); insert into meta values (0, 0);
         self.db.execute("vacuum")
         self.db.execute("analyze")
         self.db.setAutocommit(False)
-
-    # Media syncing: zips
-    ##########################################################################
-
-    def mediaChangesZip(self) -> Tuple[bytes, list]:
-        f = io.BytesIO()
-        z = zipfile.ZipFile(f, "w", compression=zipfile.ZIP_DEFLATED)
-
-        fnames = []
-        # meta is list of (fname, zipname), where zipname of None
-        # is a deleted file
-        meta = []
-        sz = 0
-
-        for c, (fname, csum) in enumerate(
-            self.db.execute(
-                "select fname, csum from media where dirty=1"
-                " limit %d" % SYNC_ZIP_COUNT
-            )
-        ):
-
-            fnames.append(fname)
-            normname = unicodedata.normalize("NFC", fname)
-
-            if csum:
-                self.col.log("+media zip", fname)
-                z.write(fname, str(c))
-                meta.append((normname, str(c)))
-                sz += os.path.getsize(fname)
-            else:
-                self.col.log("-media zip", fname)
-                meta.append((normname, ""))
-
-            if sz >= SYNC_ZIP_SIZE:
-                break
-
-        z.writestr("_meta", json.dumps(meta))
-        z.close()
-        return f.getvalue(), fnames
-
-    def addFilesFromZip(self, zipData) -> int:
-        "Extract zip data; true if finished."
-        f = io.BytesIO(zipData)
-        z = zipfile.ZipFile(f, "r")
-        media = []
-        # get meta info first
-        meta = json.loads(z.read("_meta").decode("utf8"))
-        # then loop through all files
-        cnt = 0
-        for i in z.infolist():
-            if i.filename == "_meta":
-                # ignore previously-retrieved meta
-                continue
-            else:
-                data = z.read(i)
-                csum = checksum(data)
-                name = meta[i.filename]
-                # normalize name
-                name = unicodedata.normalize("NFC", name)
-                # save file
-                with open(name, "wb") as f:  # type: ignore
-                    f.write(data)
-                # update db
-                media.append((name, csum, self._mtime(name), 0))
-                cnt += 1
-        if media:
-            self.db.executemany("insert or replace into media values (?,?,?,?)", media)
-        return cnt
@@ -13,7 +13,7 @@ import anki  # pylint: disable=unused-import
 from anki import hooks
 from anki.consts import *
 from anki.lang import _
-from anki.utils import checksum, ids2str, intTime, joinFields, splitFields
+from anki.utils import checksum, ids2str, intTime, splitFields
 
 # types
 NoteType = Dict[str, Any]
@@ -66,6 +66,8 @@ defaultField: Field = {
     "rtl": False,
     "font": "Arial",
     "size": 20,
+    # convert the field from Markdown to HTML when showing cards
+    "markdown": False,
     # reserved for future use
     "media": [],
 }
@@ -280,110 +282,86 @@ and notes.mid = ? and cards.ord = ?""",
     def setSortIdx(self, m: NoteType, idx: int) -> None:
         assert 0 <= idx < len(m["flds"])
         self.col.modSchema(check=True)
-        m["sortf"] = idx
-        self.col.updateFieldCache(self.nids(m))
-        self.save(m, updateReqs=False)
+
+        def change():
+            m["sortf"] = idx
+
+        self._changeSchema(m, change)
 
     def addField(self, m: NoteType, field: Field) -> None:
         # only mod schema if model isn't new
         if m["id"]:
             self.col.modSchema(check=True)
-        m["flds"].append(field)
-        self._updateFieldOrds(m)
-        self.save(m)
+        fieldOrds: List[Optional[int]] = list(range(len(m["flds"])))
 
-        def add(fields):
-            fields.append("")
-            return fields
+        def add():
+            m["flds"].append(field)
 
-        self._transformFields(m, add)
+        self._changeSchema(m, add, fieldOrds=fieldOrds + [None])
 
     def remField(self, m: NoteType, field: Field) -> None:
         self.col.modSchema(check=True)
-        # save old sort field
-        sortFldName = m["flds"][m["sortf"]]["name"]
         idx = m["flds"].index(field)
-        m["flds"].remove(field)
-        # restore old sort field if possible, or revert to first field
-        m["sortf"] = 0
-        for c, f in enumerate(m["flds"]):
-            if f["name"] == sortFldName:
-                m["sortf"] = c
-                break
-        self._updateFieldOrds(m)
-
-        def delete(fields):
-            del fields[idx]
-            return fields
-
-        self._transformFields(m, delete)
-        if m["flds"][m["sortf"]]["name"] != sortFldName:
-            # need to rebuild sort field
-            self.col.updateFieldCache(self.nids(m))
-        # saves
-        self.renameField(m, field, None)
+        fieldOrds: List[Optional[int]] = list(range(len(m["flds"])))
+        del fieldOrds[idx]
+
+        def delete():
+            # save old sort field
+            sortFldName = m["flds"][m["sortf"]]["name"]
+            m["flds"].remove(field)
+            # restore old sort field if possible, or revert to first field
+            m["sortf"] = 0
+            for c, f in enumerate(m["flds"]):
+                if f["name"] == sortFldName:
+                    m["sortf"] = c
+                    break
+            self._renameFieldReferences(m, field["name"], None)
+
+        self._changeSchema(m, delete, fieldOrds=fieldOrds)
 
     def moveField(self, m: NoteType, field: Field, idx: int) -> None:
         self.col.modSchema(check=True)
         oldidx = m["flds"].index(field)
         if oldidx == idx:
             return
-        # remember old sort field
-        sortf = m["flds"][m["sortf"]]
-        # move
-        m["flds"].remove(field)
-        m["flds"].insert(idx, field)
-        # restore sort field
-        m["sortf"] = m["flds"].index(sortf)
-        self._updateFieldOrds(m)
-        self.save(m, updateReqs=False)
+        fieldOrds: List[Optional[int]] = list(range(len(m["flds"])))
+        fieldOrds.insert(idx, fieldOrds.pop(oldidx))
 
-        def move(fields, oldidx=oldidx):
-            val = fields[oldidx]
-            del fields[oldidx]
-            fields.insert(idx, val)
-            return fields
+        def move():
+            # remember old sort field
+            sortf = m["flds"][m["sortf"]]
+            m["flds"].remove(field)
+            m["flds"].insert(idx, field)
+            # restore sort field
+            m["sortf"] = m["flds"].index(sortf)
 
-        self._transformFields(m, move)
+        self._changeSchema(m, move, fieldOrds=fieldOrds)
 
-    def renameField(self, m: NoteType, field: Field, newName: Optional[str]) -> None:
+    def renameField(self, m: NoteType, field: Field, newName: str) -> None:
         self.col.modSchema(check=True)
-        if newName is not None:
-            newName = newName.replace(":", "")
-        pat = r"{{([^{}]*)([:#^/]|[^:#/^}][^:}]*?:|)%s}}"
+        newName = newName.replace(":", "")
 
-        def wrap(txt):
-            def repl(match):
-                return "{{" + match.group(1) + match.group(2) + txt + "}}"
+        def rename():
+            self._renameFieldReferences(m, field["name"], newName)
+            field["name"] = newName
 
-            return repl
+        self._changeSchema(m, rename)
 
+    def _renameFieldReferences(
+        self, m: NoteType, oldName: str, newName: Optional[str]
+    ) -> None:
+        "Update references to a field in M's templates, or remove them."
+        # references are updated by the backend, in qfmt/afmt pairs
+        fmts = [t[fmt] for t in m["tmpls"] for fmt in ("qfmt", "afmt")]
+        renamed = iter(self.col.backend.rename_field(fmts, oldName, newName))
         for t in m["tmpls"]:
-            for fmt in ("qfmt", "afmt"):
-                if newName:
-                    t[fmt] = re.sub(
-                        pat % re.escape(field["name"]), wrap(newName), t[fmt]
-                    )
-                else:
-                    t[fmt] = re.sub(pat % re.escape(field["name"]), "", t[fmt])
-        field["name"] = newName
-        self.save(m)
+            t["qfmt"] = next(renamed)
+            t["afmt"] = next(renamed)
 
     def _updateFieldOrds(self, m: NoteType) -> None:
         for c, f in enumerate(m["flds"]):
             f["ord"] = c
 
-    def _transformFields(self, m: NoteType, fn: Callable) -> None:
-        # model hasn't been added yet?
-        if not m["id"]:
-            return
-        r = []
-        for (id, flds) in self.col.db.execute(
-            "select id, flds from notes where mid = ?", m["id"]
-        ):
-            r.append((joinFields(fn(splitFields(flds))), intTime(), self.col.usn(), id))
-        self.col.db.executemany("update notes set flds=?,mod=?,usn=? where id = ?", r)
-
     # Templates
     ##################################################
 
@@ -396,9 +374,12 @@ and notes.mid = ? and cards.ord = ?""",
         "Note: should col.genCards() afterwards."
         if m["id"]:
             self.col.modSchema(check=True)
-        m["tmpls"].append(template)
-        self._updateTemplOrds(m)
-        self.save(m)
+        templateOrds: List[Optional[int]] = list(range(len(m["tmpls"])))
+
+        def add():
+            m["tmpls"].append(template)
+
+        self._changeSchema(m, add, templateOrds=templateOrds + [None])
 
     def remTemplate(self, m: NoteType, template: Template) -> bool:
         "False if removing template would leave orphan notes."
@@ -423,22 +404,15 @@ limit 1"""
             % ids2str(cids)
         ):
             return False
-        # ok to proceed; remove cards
+        # ok to proceed; the backend removes the cards and shifts ordinals
         self.col.modSchema(check=True)
-        self.col.remCards(cids)
-        # shift ordinals
-        self.col.db.execute(
-            """
-update cards set ord = ord - 1, usn = ?, mod = ?
- where nid in (select id from notes where mid = ?) and ord > ?""",
-            self.col.usn(),
-            intTime(),
-            m["id"],
-            ord,
-        )
-        m["tmpls"].remove(template)
-        self._updateTemplOrds(m)
-        self.save(m)
+        templateOrds: List[Optional[int]] = list(range(len(m["tmpls"])))
+        del templateOrds[ord]
+
+        def remove():
+            m["tmpls"].remove(template)
+
+        self._changeSchema(m, remove, templateOrds=templateOrds)
         return True
 
     def _updateTemplOrds(self, m: NoteType) -> None:
@@ -449,25 +423,61 @@ update cards set ord = ord - 1, usn = ?, mod = ?
         oldidx = m["tmpls"].index(template)
         if oldidx == idx:
             return
-        oldidxs = dict((id(t), t["ord"]) for t in m["tmpls"])
-        m["tmpls"].remove(template)
-        m["tmpls"].insert(idx, template)
+        templateOrds: List[Optional[int]] = list(range(len(m["tmpls"])))
+        templateOrds.insert(idx, templateOrds.pop(oldidx))
+
+        def move():
+            m["tmpls"].remove(template)
+            m["tmpls"].insert(idx, template)
+
+        self._changeSchema(m, move, templateOrds=templateOrds)
+
+    def _changeSchema(
+        self,
+        m: NoteType,
+        change: Callable[[], None],
+        fieldOrds: Optional[List[Optional[int]]] = None,
+        templateOrds: Optional[List[Optional[int]]] = None,
+    ) -> None:
+        """Apply CHANGE to M, and have the backend change M's notes and cards
+        to match, in a single transaction. FIELDORDS and TEMPLATEORDS give the
+        previous index of each field and template after the change, or None
+        for new ones, and default to no change."""
+        if fieldOrds is None:
+            fieldOrds = list(range(len(m["flds"])))
+        if templateOrds is None:
+            templateOrds = list(range(len(m["tmpls"])))
+        if not m["id"]:
+            # not added yet, so there are no notes to change
+            change()
+            self._updateFieldOrds(m)
+            self._updateTemplOrds(m)
+            self.save(m)
+            return
+        # the backend compares the change with the saved note type
+        self.col.save()
+        change()
+        self._updateFieldOrds(m)
         self._updateTemplOrds(m)
-        # generate change map
-        map = []
-        for t in m["tmpls"]:
-            map.append("when ord = %d then %d" % (oldidxs[id(t)], t["ord"]))
-        # apply
-        self.save(m, updateReqs=False)
-        self.col.db.execute(
-            """
-update cards set ord = (case %s end),usn=?,mod=? where nid in (
-select id from notes where mid = ?)"""
-            % " ".join(map),
-            self.col.usn(),
-            intTime(),
-            m["id"],
-        )
+        self.col.db.commit()
+        try:
+            self.col.backend.update_notetype_schema(
+                m, fieldOrds, templateOrds, self.col.usn(), intTime()
+            )
+        finally:
+            self.col.lock()
+        # the saved note type has a new mod time and requirements; update M in
+        # place, as callers may hold references to its fields and templates
+        saved = json.loads(self.col.db.scalar("select models from col"))[str(m["id"])]
+        for f, savedField in zip(m["flds"], saved.pop("flds")):
+            f.update(savedField)
+        for t, savedTemplate in zip(m["tmpls"], saved.pop("tmpls")):
+            t.update(savedTemplate)
+        m.update(saved)
+        self.col.scm, self.col.mod = self.col.db.first("select scm, mod from col")
+        # changes recorded before can no longer be undone
+        self.col.clearUndo()
+        hooks.note_type_added(m)
 
     def _syncTemplates(self, m: NoteType) -> None:
         rem = self.col.genCards(self.nids(m))
@@ -480,76 +490,23 @@ select id from notes where mid = ?)"""
     def change(
         self, m: NoteType, nids: List[int], newModel: NoteType, fmap: Any, cmap: Any
     ) -> None:
+        """Change the notes in NIDS to NEWMODEL. Fields and cards not in the
+        maps are dropped, and missing cards are generated. The change can be
+        undone."""
         self.col.modSchema(check=True)
         assert newModel["id"] == m["id"] or (fmap and cmap)
-        if fmap:
-            self._changeNotes(nids, newModel, fmap)
-        if cmap:
-            self._changeCards(nids, m, newModel, cmap)
-        self.col.genCards(nids)
-
-    def _changeNotes(
-        self, nids: List[int], newModel: NoteType, map: Dict[int, Union[None, int]]
-    ) -> None:
-        d = []
-        nfields = len(newModel["flds"])
-        for (nid, flds) in self.col.db.execute(
-            "select id, flds from notes where id in " + ids2str(nids)
-        ):
-            newflds = {}
-            flds = splitFields(flds)
-            for old, new in list(map.items()):
-                newflds[new] = flds[old]
-            flds = []
-            for c in range(nfields):
-                flds.append(newflds.get(c, ""))
-            flds = joinFields(flds)
-            d.append(
-                dict(
-                    nid=nid,
-                    flds=flds,
-                    mid=newModel["id"],
-                    m=intTime(),
-                    u=self.col.usn(),
-                )
+        # the backend wants the new index of each field and template
+        fieldOrds = [fmap.get(f["ord"]) if fmap else f["ord"] for f in m["flds"]]
+        templateOrds = [cmap.get(t["ord"]) if cmap else t["ord"] for t in m["tmpls"]]
+        self.col.save()
+        self.col.db.commit()
+        try:
+            self.col.backend.change_notetype(
+                nids, newModel["id"], fieldOrds, templateOrds, self.col.usn(), intTime()
             )
-        self.col.db.executemany(
-            "update notes set flds=:flds,mid=:mid,mod=:m,usn=:u where id = :nid", d
-        )
-        self.col.updateFieldCache(nids)
-
-    def _changeCards(
-        self,
-        nids: List[int],
-        oldModel: NoteType,
-        newModel: NoteType,
-        map: Dict[int, Union[None, int]],
-    ) -> None:
-        d = []
-        deleted = []
-        for (cid, ord) in self.col.db.execute(
-            "select id, ord from cards where nid in " + ids2str(nids)
-        ):
-            # if the src model is a cloze, we ignore the map, as the gui
-            # doesn't currently support mapping them
-            if oldModel["type"] == MODEL_CLOZE:
-                new = ord
-                if newModel["type"] != MODEL_CLOZE:
-                    # if we're mapping to a regular note, we need to check if
-                    # the destination ord is valid
-                    if len(newModel["tmpls"]) <= ord:
-                        new = None
-            else:
-                # mapping from a regular note, so the map should be valid
-                new = map[ord]
-            if new is not None:
-                d.append(dict(cid=cid, new=new, u=self.col.usn(), m=intTime()))
-            else:
-                deleted.append(cid)
-        self.col.db.executemany(
-            "update cards set ord=:new,usn=:u,mod=:m where id=:cid", d
-        )
-        self.col.remCards(deleted)
+        finally:
+            self.col.lock()
+        self.col.markBackendOp()
 
     # Schema hash
     ##########################################################################
@@ -627,11 +584,3 @@ select id from notes where mid = ?)"""
             # empty clozes use first ord
             return [0]
         return list(ords)
-
-    # Sync handling
-    ##########################################################################
-
-    def beforeUpload(self) -> None:
-        for m in self.all():
-            m["usn"] = 0
-        self.save()
@@ -82,7 +82,13 @@ from notes where id = ?""",
         "If fields or tags have changed, write changes to disk."
         assert self.scm == self.col.scm
         self._preFlush()
-        sfld = stripHTMLMedia(self.fields[self.col.models.sortIdx(self._model)])
+        if self.col.conf.get("normalize_note_text", True):
+            self.fields = splitFields(
+                self.col.backend.normalize_to_nfc(self.joinedFields())
+            )
+        sfld = self.col.backend.sort_field_text(
+            self.fields[self.col.models.sortIdx(self._model)]
+        )
         tags = self.stringTags()
         fields = self.joinedFields()
         if not mod and self.col.db.scalar(
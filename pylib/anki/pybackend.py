@@ -44,7 +44,7 @@ class PythonBackend:
             # forward any unknown commands onto the Rust backend
             return self.col.backend._run_command(input)
 
-    def deck_tree(self, _input: pb.Empty) -> pb.DeckTreeOut:
+    def deck_tree(self, _input: pb.DeckDueCountsIn) -> pb.DeckTreeOut:
         native = self.col.sched.deckDueTree()
         return native_deck_tree_to_proto(native)
 
@@ -52,16 +52,6 @@ class PythonBackend:
         cids = self.col.findCards(input.search)
         return pb.FindCardsOut(card_ids=cids)
 
-    def browser_rows(self, input: pb.BrowserRowsIn) -> pb.BrowserRowsOut:
-        sort_fields = []
-        for cid in input.card_ids:
-            sort_fields.append(
-                self.col.db.scalar(
-                    "select sfld from notes n,cards c where n.id=c.nid and c.id=?", cid
-                )
-            )
-        return pb.BrowserRowsOut(sort_fields=sort_fields)
-
 
 def native_deck_tree_to_proto(native):
     top = pb.DeckTreeNode(children=[native_deck_node_to_proto(c) for c in native])
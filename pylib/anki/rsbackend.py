@@ -1,32 +1,74 @@
 # Copyright: Ankitects Pty Ltd and contributors
 # License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
 # pylint: skip-file
-from dataclasses import dataclass
-from typing import Dict, List, Tuple, Union
+import json
+from dataclasses import dataclass, field
+from typing import Any, Dict, List, Optional, Sequence, Tuple, Union
 
 import ankirspy  # pytype: disable=import-error
 
 import anki.backend_pb2 as pb
 import anki.buildinfo
+from anki.errors import AnkiError
 from anki.models import AllTemplateReqs
-from anki.sound import AVTag, SoundOrVideoTag, TTSTag
+from anki.sound import AVTag, InvalidTTSTag, SoundOrVideoTag, TTSTag
 
 assert ankirspy.buildhash() == anki.buildinfo.buildhash
 
 SchedTimingToday = pb.SchedTimingTodayOut
+DBErrorKind = pb.DBError.Kind
+NetworkErrorKind = pb.NetworkError.Kind
 
 
 class BackendException(Exception):
     def __str__(self) -> str:
         err: pb.BackendError = self.args[0]  # pylint: disable=unsubscriptable-object
+        if err.localized:
+            return err.localized
         kind = err.WhichOneof("value")
         if kind == "invalid_input":
             return f"invalid input: {err.invalid_input.info}"
         elif kind == "template_parse":
             return err.template_parse.info
+        elif kind == "io_error":
+            return f"I/O error: {err.io_error.info}"
+        elif kind == "db_error":
+            return f"DB error: {err.db_error.info}"
+        elif kind == "network_error":
+            return f"Network error: {err.network_error.info}"
+        elif kind == "sync_error":
+            return f"Sync error: {err.sync_error.info}"
+        elif kind == "sync_auth_error":
+            return "The sync server rejected the login."
+        elif kind == "interrupted":
+            return "Operation cancelled."
+        elif kind == "collection_too_new":
+            return "This file requires a newer version of Anki."
+        elif kind == "search_error":
+            return f"invalid search: {err.search_error.info}"
+        elif kind == "deck_rename_error":
+            return "Couldn't rename deck."
+        elif kind == "deck_conf_error":
+            return "Invalid deck options."
+        elif kind == "collection_in_use":
+            return (
+                "The collection is open in another copy of Anki. If it isn't, "
+                f"remove {err.collection_in_use.lock_path}."
+            )
         else:
             return f"unhandled error: {err}"
 
+    def is_transient(self) -> bool:
+        """True if the operation may succeed if tried again later, as the
+        network or the collection was temporarily unavailable."""
+        err: pb.BackendError = self.args[0]  # pylint: disable=unsubscriptable-object
+        kind = err.WhichOneof("value")
+        if kind == "network_error":
+            return err.network_error.kind != NetworkErrorKind.OTHER
+        elif kind == "db_error":
+            return err.db_error.kind == DBErrorKind.LOCKED
+        return False
+
 
 def proto_template_reqs_to_legacy(
     reqs: List[pb.TemplateRequirement],
@@ -50,11 +92,16 @@ def av_tag_to_native(tag: pb.AVTag) -> AVTag:
     val = tag.WhichOneof("value")
     if val == "sound_or_video":
         return SoundOrVideoTag(filename=tag.sound_or_video)
+    elif val == "tts_error":
+        return InvalidTTSTag(message=tag.tts_error.message)
     else:
         return TTSTag(
             field_text=tag.tts.field_text,
             lang=tag.tts.lang,
             voices=list(tag.tts.voices),
+            speed=tag.tts.speed or None,
+            pitch=tag.tts.pitch or None,
+            style=tag.tts.style or None,
             other_args=list(tag.tts.other_args),
         )
 
@@ -69,6 +116,58 @@ class TemplateReplacement:
 TemplateReplacementList = List[Union[str, TemplateReplacement]]
 
 
+@dataclass
+class CardContext:
+    "Details of the card being rendered that aren't part of the note."
+    card_ord: int
+    tags: str = ""
+    notetype_name: str = ""
+    deck_name: str = ""
+    template_name: str = ""
+    card_flags: int = 0
+    # fields that should be converted from Markdown to HTML
+    markdown_fields: List[str] = field(default_factory=list)
+
+
+@dataclass
+class RenderCardOutput:
+    question_nodes: TemplateReplacementList
+    answer_nodes: TemplateReplacementList
+    # if set, the side could not be rendered, and its nodes are empty
+    question_error: Optional[str]
+    answer_error: Optional[str]
+
+
+@dataclass
+class ExtractedLatex:
+    filename: str
+    latex_body: str
+
+
+@dataclass
+class ExtractLatexOutput:
+    html: str
+    latex: List[ExtractedLatex]
+
+
+@dataclass
+class FindReplaceNote:
+    fields: List[str]
+    tags: str
+    # if set, only this field is changed
+    field_ord: Optional[int] = None
+
+
+@dataclass
+class TemplateDiagnostic:
+    kind: int  # pb.TemplateDiagnostic.Kind
+    # the field or conditional name, if applicable
+    name: str
+    line: int
+    column: int
+    message: str
+
+
 def proto_replacement_list_to_native(
     nodes: List[pb.RenderedTemplateNode],
 ) -> TemplateReplacementList:
@@ -87,9 +186,82 @@ def proto_replacement_list_to_native(
     return results
 
 
+MediaSyncProgress = pb.MediaSyncProgress
+MediaSyncOutcome = pb.SyncMediaOut
+TrashedMediaFile = pb.TrashedMediaFile
+ImageTranscodeConfig = pb.ImageTranscodeConfig
+CardSchedulingState = pb.CardSchedulingState
+SchedulingConfig = pb.SchedulingConfig
+RevlogAnswer = pb.RevlogAnswer
+LoadBalancerConfig = pb.LoadBalancerIn
+FilteredSearchTerm = pb.FilteredSearchTerm
+CardScheduleSnapshot = pb.CardScheduleSnapshot
+BuryOrSuspendMode = pb.BuryOrSuspendCardsIn.Mode
+UnburyMode = pb.UnburyCardsIn.Mode
+DeckDueInput = pb.DeckDueInput
+LeechEvent = pb.LeechEvent
+DeckDueCountsOut = pb.DeckDueCountsOut
+DeckTreeNode = pb.DeckTreeNode
+UndoStatus = pb.UndoStatusOut
+BackupLimits = pb.BackupLimits
+CheckDatabaseOut = pb.CheckDatabaseOut
+SearchContext = pb.SearchContext
+TagTreeNode = pb.TagTreeNode
+TagUsage = pb.TagUsage
+CardStats = pb.CardStatsOut
+Graphs = pb.GraphsOut
+BrowserRow = pb.BrowserRow
+BrowserCell = pb.BrowserCell
+OptimizeProgress = pb.OptimizeProgress
+NormalSyncProgress = pb.NormalSyncProgress
+SyncCollectionOut = pb.SyncCollectionOut
+SyncCollectionOutcome = pb.SyncCollectionOut
+FullSyncOutcome = pb.FullSyncOut
+FullSyncPreview = pb.FullSyncPreviewOut
+SyncChangeCounts = pb.SyncChangeCounts
+SyncNetworkSettings = pb.SyncNetworkSettings
+Progress = pb.Progress
+DatabaseCheckProgress = pb.DatabaseCheckProgress
+ExportPackageOut = pb.ExportPackageOut
+ExportSharingOptions = pb.ExportSharingOptions
+ImportPackageOut = pb.ImportPackageOut
+ImportedNote = pb.ImportedNote
+DuplicateMode = pb.ImportPackageIn.DuplicateMode
+TextImportPreview = pb.TextImportPreviewOut
+TextColumnMapping = pb.TextColumnMapping
+TextImportProblem = pb.TextImportProblem
+TextDuplicateMode = pb.ImportTextIn.DuplicateMode
+ImportTextOut = pb.ImportTextOut
+ForeignImportKind = pb.ImportForeignIn.Kind
+ImportForeignOut = pb.ImportForeignOut
+
+
+def sql_value_to_proto(value: Any) -> pb.SqlValue:
+    if value is None:
+        return pb.SqlValue(null=pb.Empty())
+    elif isinstance(value, int):
+        return pb.SqlValue(integer=value)
+    elif isinstance(value, float):
+        return pb.SqlValue(real=value)
+    elif isinstance(value, str):
+        return pb.SqlValue(text=value)
+    else:
+        return pb.SqlValue(blob=bytes(value))
+
+
+def sql_value_from_proto(value: pb.SqlValue) -> Any:
+    kind = value.WhichOneof("value")
+    if kind == "null":
+        return None
+    else:
+        return getattr(value, kind)
+
+
 class RustBackend:
-    def __init__(self, path: str):
-        self._backend = ankirspy.Backend(path)
+    def __init__(self, col_path: str, media_folder: str, media_db: str):
+        self._backend = ankirspy.Backend(col_path, media_folder, media_db)
+        self._progress = self._backend.progress_handle()
+        self._media_progress = self._backend.media_progress_handle()
 
     def _run_command(self, input: pb.BackendInput) -> pb.BackendOutput:
         input_bytes = input.SerializeToString()
@@ -102,6 +274,36 @@ class RustBackend:
         else:
             return output
 
+    def latest_progress(self) -> Optional[Progress]:
+        """The latest progress of the long-running operation in progress, or
+        None if it hasn't reported any. Operations block, so this is called
+        from another thread."""
+        data = self._progress.latest_progress()
+        if not data:
+            return None
+        progress = pb.Progress()
+        progress.ParseFromString(data)
+        return progress
+
+    def set_wants_abort(self) -> None:
+        """Ask the running operation to stop. It raises a BackendException
+        of kind 'interrupted' when it does. Can be called from any thread."""
+        self._progress.set_wants_abort()
+
+    def latest_media_progress(self) -> Optional[Progress]:
+        """Like latest_progress(), but for a media sync, which can run on
+        another thread at the same time as other operations."""
+        data = self._media_progress.latest_progress()
+        if not data:
+            return None
+        progress = pb.Progress()
+        progress.ParseFromString(data)
+        return progress
+
+    def set_wants_media_abort(self) -> None:
+        "Ask a running media sync to stop."
+        self._media_progress.set_wants_abort()
+
     def template_requirements(
         self, template_fronts: List[str], field_map: Dict[str, int]
     ) -> AllTemplateReqs:
@@ -134,24 +336,79 @@ class RustBackend:
             )
         ).sched_timing_today
 
-    def render_card(
-        self, qfmt: str, afmt: str, fields: Dict[str, str], card_ord: int
-    ) -> Tuple[TemplateReplacementList, TemplateReplacementList]:
-        out = self._run_command(
+    def local_sched_timing_today(
+        self, created_secs: int, now_secs: int, rollover: int
+    ) -> SchedTimingToday:
+        return self._run_command(
             pb.BackendInput(
-                render_card=pb.RenderCardIn(
-                    question_template=qfmt,
-                    answer_template=afmt,
-                    fields=fields,
-                    card_ordinal=card_ord,
+                local_sched_timing_today=pb.LocalSchedTimingTodayIn(
+                    created_secs=created_secs, now_secs=now_secs, rollover_hour=rollover
                 )
             )
-        ).render_card
+        ).local_sched_timing_today
+
+    def render_card(
+        self,
+        qfmt: str,
+        afmt: str,
+        fields: Dict[str, str],
+        card: CardContext,
+        preview: bool = False,
+    ) -> RenderCardOutput:
+        """Special fields like {{Tags}} are filled in from card. If preview is
+        true, empty fields are shown as placeholders like (Front)."""
+        render_input = pb.RenderCardIn(
+            question_template=qfmt,
+            answer_template=afmt,
+            fields=fields,
+            card_ordinal=card.card_ord,
+            tags=card.tags,
+            notetype_name=card.notetype_name,
+            deck_name=card.deck_name,
+            template_name=card.template_name,
+            card_flags=card.card_flags,
+            markdown_fields=card.markdown_fields,
+        )
+        if preview:
+            out = self._run_command(
+                pb.BackendInput(render_preview=render_input)
+            ).render_preview
+        else:
+            out = self._run_command(
+                pb.BackendInput(render_card=render_input)
+            ).render_card
 
         qnodes = proto_replacement_list_to_native(out.question_nodes)  # type: ignore
         anodes = proto_replacement_list_to_native(out.answer_nodes)  # type: ignore
 
-        return (qnodes, anodes)
+        return RenderCardOutput(
+            question_nodes=qnodes,
+            answer_nodes=anodes,
+            question_error=out.question_error or None,
+            answer_error=out.answer_error or None,
+        )
+
+    def apply_filters(
+        self,
+        text: str,
+        filters: List[str],
+        field_name: str,
+        card_ord: int,
+        question_side: bool,
+    ) -> Tuple[str, List[str]]:
+        "Apply built-in filters, returning the text and any unrecognized filters."
+        out = self._run_command(
+            pb.BackendInput(
+                apply_filters=pb.ApplyFiltersIn(
+                    text=text,
+                    filters=filters,
+                    field_name=field_name,
+                    card_ordinal=card_ord,
+                    question_side=question_side,
+                )
+            )
+        ).apply_filters
+        return out.text, list(out.remaining_filters)
 
     def local_minutes_west(self, stamp: int) -> int:
         return self._run_command(
@@ -171,5 +428,1222 @@ class RustBackend:
             )
         )
 
+    def get_av_tags_with_spans(self, text: str) -> List[Tuple[AVTag, int, int]]:
+        "Returns each tag with its start and end offset in text."
+        encoded = text.encode("utf8")
+
+        def char_offset(byte_offset: int) -> int:
+            return len(encoded[:byte_offset].decode("utf8"))
+
+        return [
+            (av_tag_to_native(tag), char_offset(tag.start), char_offset(tag.end))
+            for tag in self._run_command(
+                pb.BackendInput(get_av_tags=text)
+            ).get_av_tags.av_tags
+        ]
+
     def flag_av_tags(self, text: str) -> str:
         return self._run_command(pb.BackendInput(flag_av_tags=text)).flag_av_tags
+
+    def sanitize_html(self, html: str) -> str:
+        return self._run_command(pb.BackendInput(sanitize_html=html)).sanitize_html
+
+    def furigana_to_ruby(self, text: str) -> str:
+        return self._ruby(text, pb.RubyIn.FURIGANA)
+
+    def kana_only(self, text: str) -> str:
+        return self._ruby(text, pb.RubyIn.KANA)
+
+    def kanji_only(self, text: str) -> str:
+        return self._ruby(text, pb.RubyIn.KANJI)
+
+    def _ruby(self, text: str, mode: int) -> str:
+        return self._run_command(
+            pb.BackendInput(ruby=pb.RubyIn(text=text, mode=mode))  # type: ignore
+        ).ruby
+
+    def html_to_text(self, text: str, tables: bool = False) -> str:
+        """Convert HTML to plain text on a single line. If tables is true,
+        table rows are placed on separate lines, with tabs between cells."""
+        layout = pb.HtmlToTextIn.TABLES if tables else pb.HtmlToTextIn.SINGLE_LINE
+        return self._run_command(
+            pb.BackendInput(
+                html_to_text=pb.HtmlToTextIn(text=text, layout=layout)  # type: ignore
+            )
+        ).html_to_text
+
+    def normalize_to_nfc(self, text: str) -> str:
+        return self._run_command(
+            pb.BackendInput(normalize_to_nfc=text)
+        ).normalize_to_nfc
+
+    def extract_latex(self, text: str, svg: bool) -> ExtractLatexOutput:
+        out = self._run_command(
+            pb.BackendInput(extract_latex=pb.ExtractLatexIn(text=text, svg=svg))
+        ).extract_latex
+
+        return ExtractLatexOutput(
+            html=out.text,
+            latex=[
+                ExtractedLatex(filename=l.filename, latex_body=l.latex_body)
+                for l in out.latex
+            ],
+        )
+
+    def render_latex(
+        self,
+        text: str,
+        svg: bool,
+        header: str,
+        footer: str,
+        build: bool,
+        commands: List[List[str]],
+    ) -> pb.RenderLatexOut:
+        return self._run_command(
+            pb.BackendInput(
+                render_latex=pb.RenderLatexIn(
+                    text=text,
+                    svg=svg,
+                    header=header,
+                    footer=footer,
+                    build=build,
+                    commands=[pb.LatexCommand(args=args) for args in commands],
+                )
+            )
+        ).render_latex
+
+    def compare_answer(self, expected: str, provided: str) -> str:
+        return self._run_command(
+            pb.BackendInput(
+                compare_answer=pb.CompareAnswerIn(expected=expected, provided=provided)
+            )
+        ).compare_answer
+
+    def render_cloze(self, text: str, ordinal: int, question_side: bool) -> str:
+        return self._run_command(
+            pb.BackendInput(
+                render_cloze=pb.RenderClozeIn(
+                    text=text, ordinal=ordinal, question_side=question_side
+                )
+            )
+        ).render_cloze
+
+    def find_and_replace(
+        self,
+        notes: List[FindReplaceNote],
+        search: str,
+        replacement: str,
+        regex: bool,
+        match_case: bool,
+        include_tags: bool,
+    ) -> Tuple[List[FindReplaceNote], int]:
+        "Returns updated notes in the order provided, and the replacement count."
+        output = self._run_command(
+            pb.BackendInput(
+                find_and_replace=pb.FindAndReplaceIn(
+                    search=search,
+                    replacement=replacement,
+                    regex=regex,
+                    match_case=match_case,
+                    include_tags=include_tags,
+                    notes=[
+                        pb.FindAndReplaceNote(
+                            fields=note.fields,
+                            tags=note.tags,
+                            restrict_to_field=note.field_ord is not None,
+                            field_ord=note.field_ord or 0,
+                        )
+                        for note in notes
+                    ],
+                )
+            )
+        ).find_and_replace
+        updated = [
+            FindReplaceNote(
+                fields=list(note.fields),
+                tags=note.tags,
+                field_ord=note.field_ord if note.restrict_to_field else None,
+            )
+            for note in output.notes
+        ]
+        return updated, output.replacements
+
+    def check_template(
+        self, template: str, field_names: List[str], question_side: bool
+    ) -> List[TemplateDiagnostic]:
+        "Returns a list of problems with the template, which is empty if it's valid."
+        out = self._run_command(
+            pb.BackendInput(
+                check_template=pb.CheckTemplateIn(
+                    template=template,
+                    field_names=field_names,
+                    question_side=question_side,
+                )
+            )
+        ).check_template
+        return [
+            TemplateDiagnostic(
+                kind=d.kind,
+                name=d.name,
+                line=d.line,
+                column=d.column,
+                message=d.message,
+            )
+            for d in out.diagnostics
+        ]
+
+    def card_changes(
+        self,
+        template_fronts: List[str],
+        field_names: List[str],
+        is_cloze: bool,
+        fields: List[str],
+        existing_ords: List[int],
+    ) -> Tuple[List[int], List[int]]:
+        """Returns (ords to add, ords to remove) for a note with the given
+        fields and existing cards."""
+        out = self._run_command(
+            pb.BackendInput(
+                card_changes=pb.CardChangesIn(
+                    template_fronts=template_fronts,
+                    field_names=field_names,
+                    is_cloze=is_cloze,
+                    fields=fields,
+                    existing_ords=existing_ords,
+                )
+            )
+        ).card_changes
+        return list(out.to_add), list(out.to_remove)
+
+    def rename_field(
+        self, templates: List[str], old_name: str, new_name: Optional[str]
+    ) -> List[str]:
+        """Update field references in the provided templates. If new_name is
+        None, the references are removed."""
+        return list(
+            self._run_command(
+                pb.BackendInput(
+                    rename_field=pb.RenameFieldIn(
+                        templates=templates, old_name=old_name, new_name=new_name or ""
+                    )
+                )
+            ).rename_field.templates
+        )
+
+    def check_media(self, dry_run: bool = False) -> pb.CheckMediaOut:
+        return self._run_command(
+            pb.BackendInput(check_media=pb.CheckMediaIn(dry_run=dry_run))
+        ).check_media
+
+    def trash_media_files(self, fnames: List[str]) -> None:
+        self._run_command(
+            pb.BackendInput(trash_media_files=pb.TrashMediaFilesIn(fnames=fnames))
+        )
+
+    def media_trash_contents(self) -> List[TrashedMediaFile]:
+        return list(
+            self._run_command(
+                pb.BackendInput(media_trash_contents=pb.Empty())
+            ).media_trash_contents.files
+        )
+
+    def restore_media_trash(self, fnames: List[str]) -> List[str]:
+        return list(
+            self._run_command(
+                pb.BackendInput(
+                    restore_media_trash=pb.TrashMediaFilesIn(fnames=fnames)
+                )
+            ).restore_media_trash.fnames
+        )
+
+    def empty_media_trash(self) -> None:
+        self._run_command(pb.BackendInput(empty_media_trash=pb.Empty()))
+
+    def answer_card(
+        self,
+        card: CardSchedulingState,
+        ease: int,
+        config: SchedulingConfig,
+        now: int,
+        days_elapsed: int,
+        next_day_at: int,
+        load_balancer: Optional[LoadBalancerConfig] = None,
+        note_tags: str = "",
+    ) -> pb.AnswerCardOut:
+        return self._run_command(
+            pb.BackendInput(
+                answer_card=pb.AnswerCardIn(
+                    card=card,
+                    ease=ease,
+                    config=config,
+                    now_secs=now,
+                    days_elapsed=days_elapsed,
+                    next_day_at=next_day_at,
+                    load_balancer=load_balancer,
+                    note_tags=note_tags,
+                )
+            )
+        ).answer_card
+
+    def empty_filtered_deck(
+        self, deck_id: int, usn: int, card_ids: Optional[List[int]] = None
+    ) -> None:
+        """Return the deck's cards to their home decks. If card_ids is
+        provided, only those cards are returned."""
+        self._run_command(
+            pb.BackendInput(
+                empty_filtered_deck=pb.EmptyFilteredDeckIn(
+                    deck_id=deck_id, card_ids=card_ids or [], usn=usn
+                )
+            )
+        )
+
+    def fill_filtered_deck(
+        self,
+        deck_id: int,
+        terms: List[FilteredSearchTerm],
+        reschedule: bool,
+        today: int,
+        usn: int,
+    ) -> int:
+        """Move the cards matching each term into the deck, returning the
+        number of cards moved."""
+        return self._run_command(
+            pb.BackendInput(
+                fill_filtered_deck=pb.FillFilteredDeckIn(
+                    deck_id=deck_id,
+                    terms=terms,
+                    reschedule=reschedule,
+                    today=today,
+                    usn=usn,
+                )
+            )
+        ).fill_filtered_deck
+
+    def set_due_date(
+        self, card_ids: List[int], days: str, today: int, usn: int, mtime: int
+    ) -> List[CardScheduleSnapshot]:
+        """Reschedule cards as reviews due in DAYS days (eg "0", "1-7" or
+        "3!"), returning their previous state for undo."""
+        return list(
+            self._run_command(
+                pb.BackendInput(
+                    set_due_date=pb.SetDueDateIn(
+                        card_ids=card_ids,
+                        days=days,
+                        today=today,
+                        usn=usn,
+                        mtime_secs=mtime,
+                    )
+                )
+            ).set_due_date.previous
+        )
+
+    def restore_card_schedules(self, cards: List[CardScheduleSnapshot]) -> None:
+        self._run_command(
+            pb.BackendInput(
+                restore_card_schedules=pb.RestoreCardSchedulesIn(cards=cards)
+            )
+        )
+
+    def bury_or_suspend_cards(
+        self, card_ids: List[int], mode: int, usn: int, mtime: int
+    ) -> List[CardScheduleSnapshot]:
+        "Mode is one of the BuryOrSuspendCardsIn.Mode values."
+        return list(
+            self._run_command(
+                pb.BackendInput(
+                    bury_or_suspend_cards=pb.BuryOrSuspendCardsIn(
+                        card_ids=card_ids, mode=mode, usn=usn, mtime_secs=mtime
+                    )
+                )
+            ).bury_or_suspend_cards.previous
+        )
+
+    def unsuspend_cards(
+        self, card_ids: List[int], usn: int, mtime: int
+    ) -> List[CardScheduleSnapshot]:
+        return list(
+            self._run_command(
+                pb.BackendInput(
+                    unsuspend_cards=pb.UnsuspendCardsIn(
+                        card_ids=card_ids, usn=usn, mtime_secs=mtime
+                    )
+                )
+            ).unsuspend_cards.previous
+        )
+
+    def unbury_cards(
+        self,
+        deck_ids: List[int],
+        mode: int,
+        usn: int,
+        mtime: int,
+        skip_undo: bool = False,
+    ) -> List[CardScheduleSnapshot]:
+        """Unbury cards in DECK_IDS, or all decks if empty. Mode is one of
+        the UnburyCardsIn.Mode values."""
+        return list(
+            self._run_command(
+                pb.BackendInput(
+                    unbury_cards=pb.UnburyCardsIn(
+                        deck_ids=deck_ids,
+                        mode=mode,
+                        usn=usn,
+                        mtime_secs=mtime,
+                        skip_undo=skip_undo,
+                    )
+                )
+            ).unbury_cards.previous
+        )
+
+    def bury_siblings(
+        self,
+        card_id: int,
+        note_id: int,
+        today: int,
+        bury_new: bool,
+        bury_reviews: bool,
+        usn: int,
+        mtime: int,
+    ) -> pb.BurySiblingsOut:
+        return self._run_command(
+            pb.BackendInput(
+                bury_siblings=pb.BurySiblingsIn(
+                    card_id=card_id,
+                    note_id=note_id,
+                    today=today,
+                    bury_new=bury_new,
+                    bury_reviews=bury_reviews,
+                    usn=usn,
+                    mtime_secs=mtime,
+                )
+            )
+        ).bury_siblings
+
+    def deck_due_counts(
+        self, decks: List[DeckDueInput], report_limit: int
+    ) -> pb.DeckDueCountsOut:
+        return self._run_command(
+            pb.BackendInput(
+                deck_due_counts=pb.DeckDueCountsIn(
+                    decks=decks, report_limit=report_limit
+                )
+            )
+        ).deck_due_counts
+
+    def new_count_for_active_decks(
+        self, decks: List[DeckDueInput], active_deck_ids: List[int]
+    ) -> int:
+        return self._run_command(
+            pb.BackendInput(
+                new_count_for_active_decks=pb.NewCountForActiveDecksIn(
+                    decks=decks, active_deck_ids=active_deck_ids
+                )
+            )
+        ).new_count_for_active_decks
+
+    def undo_status(self) -> UndoStatus:
+        "The untranslated names of the ops that can be undone and redone."
+        return self._run_command(pb.BackendInput(undo_status=pb.Empty())).undo_status
+
+    def undo(self) -> UndoStatus:
+        return self._run_command(pb.BackendInput(undo=pb.Empty())).undo
+
+    def redo(self) -> UndoStatus:
+        return self._run_command(pb.BackendInput(redo=pb.Empty())).redo
+
+    def clear_undo(self) -> None:
+        self._run_command(pb.BackendInput(clear_undo=pb.Empty()))
+
+    def set_undo_steps(self, steps: int) -> None:
+        "Set how many ops can be undone."
+        self._run_command(pb.BackendInput(set_undo_steps=steps))
+
+    def backup_collection(self, backup_folder: str, limits: BackupLimits) -> None:
+        """Back up the collection in the background, pruning old backups.
+        The collection must be closed."""
+        self._run_command(
+            pb.BackendInput(
+                backup_collection=pb.BackupCollectionIn(
+                    backup_folder=backup_folder, limits=limits
+                )
+            )
+        )
+
+    def await_backup_completion(self) -> None:
+        "Wait for a backup in progress to finish, raising any error."
+        self._run_command(pb.BackendInput(await_backup_completion=pb.Empty()))
+
+    def list_backups(self, backup_folder: str) -> List[str]:
+        "Paths of the backups in BACKUP_FOLDER, newest first."
+        return list(
+            self._run_command(
+                pb.BackendInput(list_backups=backup_folder)
+            ).list_backups.paths
+        )
+
+    def restore_backup(self, backup_path: str) -> None:
+        "Replace the collection with a backup. The collection must be closed."
+        self._run_command(pb.BackendInput(restore_backup=backup_path))
+
+    def check_database(self, today: int, usn: int, mtime: int) -> CheckDatabaseOut:
+        """Fix problems in the collection and rebuild its caches, returning
+        counts of the problems found."""
+        return self._run_command(
+            pb.BackendInput(
+                check_database=pb.CheckDatabaseIn(
+                    today=today, usn=usn, mtime_secs=mtime
+                )
+            )
+        ).check_database
+
+    def optimize(self) -> int:
+        """Vacuum and analyze the collection, returning the number of bytes
+        freed. Reports its progress, and can be aborted before each stage."""
+        return self._run_command(
+            pb.BackendInput(optimize=pb.Empty())
+        ).optimize.freed_bytes
+
+    def open_collection(self) -> bool:
+        """Switch the collection to WAL mode and lock it. Returns True if it
+        wasn't closed cleanly last time."""
+        return self._run_command(
+            pb.BackendInput(open_collection=pb.Empty())
+        ).open_collection.recovered
+
+    def close_collection(self, downgrade: bool) -> None:
+        """Unlock the collection, after all other connections are closed.
+        If downgrade is true, it's returned to the legacy journal mode."""
+        self._run_command(
+            pb.BackendInput(close_collection=pb.CloseCollectionIn(downgrade=downgrade))
+        )
+
+    def open_snapshot(self) -> "CollectionSnapshot":
+        "Take a read-only snapshot of the collection, which must be in WAL mode."
+        handle = self._run_command(
+            pb.BackendInput(open_snapshot=pb.Empty())
+        ).open_snapshot
+        return CollectionSnapshot(self, handle)
+
+    def _snapshot_query(self, handle: int, sql: str, args: Tuple) -> List[Tuple]:
+        rows = self._run_command(
+            pb.BackendInput(
+                snapshot_query=pb.SnapshotQueryIn(
+                    snapshot=handle,
+                    sql=sql,
+                    args=[sql_value_to_proto(arg) for arg in args],
+                )
+            )
+        ).snapshot_query.rows
+        return [tuple(sql_value_from_proto(v) for v in row.values) for row in rows]
+
+    def _close_snapshot(self, handle: int) -> None:
+        self._run_command(pb.BackendInput(close_snapshot=handle))
+
+    def search_cards(
+        self, search: str, context: SearchContext, order: Union[bool, str]
+    ) -> List[int]:
+        """Return the ids of cards matching search. Order can be False for no
+        order, True for the browser's sort column, or an SQL order clause."""
+        input = pb.SearchCardsIn(search=search, context=context)
+        if order is True:
+            input.from_config.CopyFrom(pb.Empty())
+        elif order:
+            input.custom = order
+        else:
+            input.no_order.CopyFrom(pb.Empty())
+        return list(
+            self._run_search(pb.BackendInput(search_cards=input)).search_cards.card_ids
+        )
+
+    def search_notes(self, search: str, context: SearchContext) -> List[int]:
+        "Return the ids of notes with a card matching search."
+        input = pb.SearchNotesIn(search=search, context=context)
+        return list(
+            self._run_search(pb.BackendInput(search_notes=input)).search_notes.note_ids
+        )
+
+    def search_cards_page(
+        self,
+        search: str,
+        context: SearchContext,
+        sort_column: str,
+        reverse: bool,
+        offset: int,
+        limit: int,
+    ) -> Tuple[List[int], int]:
+        """Return a page of the ids of cards matching search, sorted on a
+        browser column like "cardDue", and the number of matching cards. A
+        limit of 0 returns all the cards after offset."""
+        input = pb.SearchPageIn(
+            search=search,
+            context=context,
+            sort_column=sort_column,
+            reverse=reverse,
+            offset=offset,
+            limit=limit,
+        )
+        output = self._run_search(pb.BackendInput(search_cards_page=input))
+        out = output.search_cards_page
+        return list(out.ids), out.total
+
+    def search_notes_page(
+        self,
+        search: str,
+        context: SearchContext,
+        sort_column: str,
+        reverse: bool,
+        offset: int,
+        limit: int,
+    ) -> Tuple[List[int], int]:
+        """Like search_cards_page(), but for notes with a card matching
+        search. Only note columns can be sorted on."""
+        input = pb.SearchPageIn(
+            search=search,
+            context=context,
+            sort_column=sort_column,
+            reverse=reverse,
+            offset=offset,
+            limit=limit,
+        )
+        output = self._run_search(pb.BackendInput(search_notes_page=input))
+        out = output.search_notes_page
+        return list(out.ids), out.total
+
+    def find_duplicates(
+        self, field_name: str, search: str, context: SearchContext
+    ) -> List[Tuple[str, List[int]]]:
+        """Return (text, note_ids) for each group of notes sharing the same
+        text in field_name, or the first field if it's empty."""
+        input = pb.FindDuplicatesIn(
+            field_name=field_name, search=search, context=context
+        )
+        groups = self._run_search(
+            pb.BackendInput(find_duplicates=input)
+        ).find_duplicates.groups
+        return [(g.text, list(g.note_ids)) for g in groups]
+
+    def tag_duplicates(
+        self,
+        field_name: str,
+        search: str,
+        context: SearchContext,
+        tag: str,
+        usn: int,
+        mtime: int,
+    ) -> int:
+        "Add tag to the notes find_duplicates() returns. Returns notes changed."
+        query = pb.FindDuplicatesIn(
+            field_name=field_name, search=search, context=context
+        )
+        input = pb.TagDuplicatesIn(query=query, tag=tag, usn=usn, mtime_secs=mtime)
+        return self._run_search(
+            pb.BackendInput(tag_duplicates=input)
+        ).tag_duplicates
+
+    def browser_rows(
+        self, card_ids: List[int], columns: List[str], today: int
+    ) -> List[BrowserRow]:
+        input = pb.BrowserRowsIn(card_ids=card_ids, columns=columns, today=today)
+        output = self._run_command(pb.BackendInput(browser_rows=input))
+        return list(output.browser_rows.rows)
+
+    def tag_tree(self) -> List[TagTreeNode]:
+        output = self._run_command(pb.BackendInput(tag_tree=pb.Empty()))
+        return list(output.tag_tree.nodes)
+
+    def rename_tag(self, old_name: str, new_name: str, usn: int, mtime: int) -> int:
+        """Rename old_name and its children. Returns the number of notes
+        changed. The change can be undone."""
+        input = pb.RenameTagIn(
+            old_name=old_name, new_name=new_name, usn=usn, mtime_secs=mtime
+        )
+        return self._run_command(pb.BackendInput(rename_tag=input)).rename_tag
+
+    def tag_usage(self) -> List[TagUsage]:
+        "The registered tags, sorted by name, with the number of notes using them."
+        output = self._run_command(pb.BackendInput(tag_usage=pb.Empty()))
+        return list(output.tag_usage.tags)
+
+    def clear_unused_tags(self, usn: int) -> List[str]:
+        """Remove tags no notes use from the registry, and register any that
+        are missing. Returns the removed tags."""
+        output = self._run_command(pb.BackendInput(clear_unused_tags=usn))
+        return list(output.clear_unused_tags.removed_tags)
+
+    # The bulk edits below return the number of notes or cards changed, and
+    # can be undone.
+
+    def add_note_tags(
+        self, note_ids: List[int], tags: str, usn: int, mtime: int
+    ) -> int:
+        input = pb.NoteTagsIn(note_ids=note_ids, tags=tags, usn=usn, mtime_secs=mtime)
+        return self._run_command(pb.BackendInput(add_note_tags=input)).add_note_tags
+
+    def remove_note_tags(
+        self, note_ids: List[int], tags: str, usn: int, mtime: int
+    ) -> int:
+        input = pb.NoteTagsIn(note_ids=note_ids, tags=tags, usn=usn, mtime_secs=mtime)
+        return self._run_command(
+            pb.BackendInput(remove_note_tags=input)
+        ).remove_note_tags
+
+    def set_deck(self, card_ids: List[int], deck_id: int, usn: int, mtime: int) -> int:
+        input = pb.SetDeckIn(
+            card_ids=card_ids, deck_id=deck_id, usn=usn, mtime_secs=mtime
+        )
+        return self._run_command(pb.BackendInput(set_deck=input)).set_deck
+
+    def set_flag(self, card_ids: List[int], flag: int, usn: int, mtime: int) -> int:
+        input = pb.SetFlagIn(card_ids=card_ids, flag=flag, usn=usn, mtime_secs=mtime)
+        return self._run_command(pb.BackendInput(set_flag=input)).set_flag
+
+    def add_note(
+        self, note: pb.Note, deck_id: int, usn: int, mtime: int
+    ) -> pb.NoteSaveOut:
+        input = pb.AddNoteIn(note=note, deck_id=deck_id, usn=usn, mtime_secs=mtime)
+        return self._run_command(pb.BackendInput(add_note=input)).add_note
+
+    def update_note(self, note: pb.Note, usn: int, mtime: int) -> pb.NoteSaveOut:
+        input = pb.UpdateNoteIn(note=note, usn=usn, mtime_secs=mtime)
+        return self._run_command(pb.BackendInput(update_note=input)).update_note
+
+    def update_notetype_schema(
+        self,
+        notetype: Dict[str, Any],
+        field_ords: List[Optional[int]],
+        template_ords: List[Optional[int]],
+        usn: int,
+        mtime: int,
+    ) -> bool:
+        """Save a changed note type, changing its notes and cards to match.
+        The ords give the previous index of each field and template, or None
+        for new ones. Returns true if a full sync is required."""
+        input = pb.UpdateNotetypeSchemaIn(
+            notetype_json=json.dumps(notetype),
+            field_ords=[-1 if ord is None else ord for ord in field_ords],
+            template_ords=[-1 if ord is None else ord for ord in template_ords],
+            usn=usn,
+            mtime_secs=mtime,
+        )
+        return self._run_command(
+            pb.BackendInput(update_notetype_schema=input)
+        ).update_notetype_schema
+
+    def change_notetype(
+        self,
+        note_ids: List[int],
+        new_notetype_id: int,
+        new_field_ords: List[Optional[int]],
+        new_template_ords: List[Optional[int]],
+        usn: int,
+        mtime: int,
+    ) -> None:
+        """Change notes that share a note type to another. The ords give the
+        new index of each current field and template, or None to drop them.
+        The change can be undone."""
+        input = pb.ChangeNotetypeIn(
+            note_ids=note_ids,
+            new_notetype_id=new_notetype_id,
+            new_field_ords=[-1 if ord is None else ord for ord in new_field_ords],
+            new_template_ords=[-1 if ord is None else ord for ord in new_template_ords],
+            usn=usn,
+            mtime_secs=mtime,
+        )
+        self._run_command(pb.BackendInput(change_notetype=input))
+
+    def card_stats(self, card_id: int, today: int, now: int) -> CardStats:
+        """The card's scheduling and review history. TODAY is the scheduler's
+        day number. The review history is newest first."""
+        input = pb.CardStatsIn(card_id=card_id, today=today, now_secs=now)
+        return self._run_command(pb.BackendInput(card_stats=input)).card_stats
+
+    def graphs(
+        self,
+        deck_ids: List[int],
+        today: int,
+        next_day_at: int,
+        rollover_hour: int,
+        bucket_days: int,
+        buckets: Optional[int],
+        v1_learning_buttons: bool,
+    ) -> Graphs:
+        """The data of the statistics graphs, for the cards in DECK_IDS, or
+        the whole collection if it's empty. Days are grouped into buckets of
+        BUCKET_DAYS, and BUCKETS limits the history and forecast included."""
+        input = pb.GraphsIn(
+            deck_ids=deck_ids,
+            today=today,
+            next_day_at=next_day_at,
+            rollover_hour=rollover_hour,
+            bucket_days=bucket_days,
+            buckets=buckets or 0,
+            v1_learning_buttons=v1_learning_buttons,
+        )
+        return self._run_command(pb.BackendInput(graphs=input)).graphs
+
+    def rename_deck(
+        self, deck_id: int, new_name: str, usn: int, mtime: int
+    ) -> List[int]:
+        """Rename a deck and its children, creating any missing parents.
+        Returns the ids of the children that were merged into existing decks
+        and removed."""
+        return list(
+            self._run_command(
+                pb.BackendInput(
+                    rename_deck=pb.RenameDeckIn(
+                        deck_id=deck_id, new_name=new_name, usn=usn, mtime_secs=mtime
+                    )
+                )
+            ).rename_deck.merged_deck_ids
+        )
+
+    def add_or_update_deck_conf(
+        self, conf: Dict[str, Any], usn: int, mtime: int
+    ) -> Dict[str, Any]:
+        """Save an options group after checking its settings, adding it if its
+        id is 0. Returns the saved group."""
+        input = pb.AddOrUpdateDeckConfIn(
+            conf_json=json.dumps(conf), usn=usn, mtime_secs=mtime
+        )
+        return json.loads(
+            self._run_command(
+                pb.BackendInput(add_or_update_deck_conf=input)
+            ).add_or_update_deck_conf
+        )
+
+    def remove_deck_conf(self, conf_id: int, usn: int, mtime: int) -> None:
+        self._run_command(
+            pb.BackendInput(
+                remove_deck_conf=pb.RemoveDeckConfIn(
+                    conf_id=conf_id, usn=usn, mtime_secs=mtime
+                )
+            )
+        )
+
+    def set_deck_conf(
+        self, deck_id: int, conf_id: int, include_children: bool, usn: int, mtime: int
+    ) -> int:
+        "Returns the number of decks changed. Filtered decks are skipped."
+        return self._run_command(
+            pb.BackendInput(
+                set_deck_conf=pb.SetDeckConfIn(
+                    deck_id=deck_id,
+                    conf_id=conf_id,
+                    include_children=include_children,
+                    usn=usn,
+                    mtime_secs=mtime,
+                )
+            )
+        ).set_deck_conf
+
+    def deck_conf_for_deck(self, deck_id: int) -> Optional[Dict[str, Any]]:
+        "The options group the deck uses, or None for a filtered deck."
+        conf = self._run_command(
+            pb.BackendInput(deck_conf_for_deck=deck_id)
+        ).deck_conf_for_deck
+        return json.loads(conf) if conf else None
+
+    def _run_search(self, input: pb.BackendInput) -> pb.BackendOutput:
+        try:
+            return self._run_command(input)
+        except BackendException as e:
+            err: pb.BackendError = e.args[0]
+            if err.WhichOneof("value") != "search_error":
+                raise
+            raise AnkiError(
+                "invalidSearch",
+                message=err.search_error.info,
+                start=err.search_error.start,
+                end=err.search_error.end,
+            ) from e
+
+    def saved_searches(self) -> Dict[str, str]:
+        "Saved searches, sorted by name."
+        return self._saved_searches(pb.BackendInput(get_saved_searches=pb.Empty()))
+
+    def save_search(self, name: str, search: str) -> Dict[str, str]:
+        "Save search as name, replacing any existing search with that name."
+        return self._saved_searches(
+            pb.BackendInput(save_search=pb.SavedSearch(name=name, search=search))
+        )
+
+    def rename_saved_search(self, old_name: str, new_name: str) -> Dict[str, str]:
+        return self._saved_searches(
+            pb.BackendInput(
+                rename_saved_search=pb.RenameSavedSearchIn(
+                    old_name=old_name, new_name=new_name
+                )
+            )
+        )
+
+    def remove_saved_search(self, name: str) -> Dict[str, str]:
+        return self._saved_searches(pb.BackendInput(remove_saved_search=name))
+
+    def _saved_searches(self, input: pb.BackendInput) -> Dict[str, str]:
+        output = self._run_command(input)
+        searches = getattr(output, output.WhichOneof("value")).searches
+        return {s.name: s.search for s in searches}
+
+    def set_fulltext_index(self, enabled: bool) -> None:
+        """Build or remove the full-text index. Building it may take a while
+        on large collections."""
+        self._run_command(pb.BackendInput(set_fulltext_index=enabled))
+
+    def fulltext_index_enabled(self) -> bool:
+        return self._run_command(
+            pb.BackendInput(fulltext_index_enabled=pb.Empty())
+        ).fulltext_index_enabled
+
+    def add_media_file(
+        self,
+        desired_name: str,
+        data: bytes,
+        transcode: Optional[ImageTranscodeConfig] = None,
+    ) -> str:
+        return self._run_command(
+            pb.BackendInput(
+                add_media_file=pb.AddMediaFileIn(
+                    desired_name=desired_name, data=data, transcode=transcode
+                )
+            )
+        ).add_media_file
+
+    def sync_media(
+        self,
+        hkey: str,
+        endpoint: str,
+        client_version: str,
+        network: Optional[SyncNetworkSettings] = None,
+    ) -> int:
+        return self._run_command(
+            pb.BackendInput(
+                sync_media=pb.SyncMediaIn(
+                    hkey=hkey,
+                    endpoint=endpoint,
+                    client_version=client_version,
+                    network=network,
+                )
+            )
+        ).sync_media.outcome
+
+    def sync_login(
+        self,
+        username: str,
+        password: str,
+        endpoint: str,
+        client_version: str,
+        network: Optional[SyncNetworkSettings] = None,
+    ) -> Optional[str]:
+        """Exchange a username and password for a host key, or None if they
+        were rejected."""
+        hkey = self._run_command(
+            pb.BackendInput(
+                sync_login=pb.SyncLoginIn(
+                    username=username,
+                    password=password,
+                    endpoint=endpoint,
+                    client_version=client_version,
+                    network=network,
+                )
+            )
+        ).sync_login.hkey
+        return hkey or None
+
+    def sync_collection(
+        self,
+        hkey: str,
+        endpoint: str,
+        client_version: str,
+        network: Optional[SyncNetworkSettings] = None,
+    ) -> SyncCollectionOut:
+        """Sync the collection with the server. Changes made through the
+        main connection must be committed first. If the sync is aborted, its
+        changes are rolled back."""
+        return self._run_command(
+            pb.BackendInput(
+                sync_collection=pb.SyncCollectionIn(
+                    hkey=hkey,
+                    endpoint=endpoint,
+                    client_version=client_version,
+                    network=network,
+                )
+            )
+        ).sync_collection
+
+    def full_upload(
+        self,
+        hkey: str,
+        endpoint: str,
+        client_version: str,
+        package: bool = False,
+        network: Optional[SyncNetworkSettings] = None,
+    ) -> int:
+        """Replace the server's collection with the local one, returning a
+        FullSyncOut.Outcome. The collection must be closed."""
+        return self._run_command(
+            pb.BackendInput(
+                full_upload=pb.FullUploadIn(
+                    hkey=hkey,
+                    endpoint=endpoint,
+                    client_version=client_version,
+                    package=package,
+                    network=network,
+                )
+            )
+        ).full_upload.outcome
+
+    def full_download(
+        self,
+        hkey: str,
+        endpoint: str,
+        client_version: str,
+        backup_folder: str = "",
+        network: Optional[SyncNetworkSettings] = None,
+    ) -> int:
+        """Replace the local collection with the server's, returning a
+        FullSyncOut.Outcome. The collection must be closed. If backup_folder is
+        provided, the old collection is backed up there first."""
+        return self._run_command(
+            pb.BackendInput(
+                full_download=pb.FullDownloadIn(
+                    hkey=hkey,
+                    endpoint=endpoint,
+                    client_version=client_version,
+                    backup_folder=backup_folder,
+                    network=network,
+                )
+            )
+        ).full_download.outcome
+
+    def full_sync_preview(
+        self,
+        hkey: str,
+        endpoint: str,
+        client_version: str,
+        network: Optional[SyncNetworkSettings] = None,
+    ) -> FullSyncPreview:
+        """The changes each side has made since the last sync, which a full
+        sync in the other direction would discard."""
+        return self._run_command(
+            pb.BackendInput(
+                full_sync_preview=pb.SyncCollectionIn(
+                    hkey=hkey,
+                    endpoint=endpoint,
+                    client_version=client_version,
+                    network=network,
+                )
+            )
+        ).full_sync_preview
+
+    def export_package(
+        self,
+        out_path: str,
+        deck_id: int,
+        include_scheduling: bool,
+        include_media: bool,
+        search: str = "",
+        context: Optional[SearchContext] = None,
+        sharing: Optional[ExportSharingOptions] = None,
+    ) -> ExportPackageOut:
+        """Write a deck and its children, or the whole collection if DECK_ID
+        is 0, to an .apkg file. If SEARCH is given, the matching cards are
+        exported instead. If SHARING is given, media is renamed and notes are
+        scrubbed before the deck is published. Reports its progress, and can
+        be aborted."""
+        return self._run_command(
+            pb.BackendInput(
+                export_package=pb.ExportPackageIn(
+                    out_path=out_path,
+                    deck_id=deck_id,
+                    search=search,
+                    context=context,
+                    include_scheduling=include_scheduling,
+                    include_media=include_media,
+                    sharing=sharing,
+                )
+            )
+        ).export_package
+
+    def set_languages(self, langs: List[str], ftl_folder: str) -> None:
+        """Translate the text the backend shows into the first of LANGS
+        it has a translation for, falling back to English. FTL_FOLDER
+        holds a folder of .ftl files for each language."""
+        self._run_command(
+            pb.BackendInput(
+                set_languages=pb.SetLanguagesIn(
+                    preferred_langs=langs, ftl_folder=ftl_folder
+                )
+            )
+        )
+
+    def translate(self, key: str, **kwargs: Union[str, int, float]) -> str:
+        """The message KEY in the user's language, with its arguments
+        filled in. Numbers select the plural form of the message."""
+        args = {}
+        for (name, value) in kwargs.items():
+            if isinstance(value, str):
+                args[name] = pb.TranslateArgValue(str=value)
+            else:
+                args[name] = pb.TranslateArgValue(number=value)
+        return self._run_command(
+            pb.BackendInput(translate_string=pb.TranslateStringIn(key=key, args=args))
+        ).translate_string
+
+    def set_logging(self, path: str, level: str = "") -> None:
+        """Log to the file at PATH, rotating it when it gets large, or stop
+        logging if PATH is empty. LEVEL is like "info" or "debug"; if empty,
+        the ANKI_LOG environment variable is used."""
+        self._run_command(
+            pb.BackendInput(set_logging=pb.SetLoggingIn(path=path, level=level))
+        )
+
+    def export_notes_as_text(
+        self,
+        out_path: str,
+        deck_id: int,
+        include_html: bool,
+        include_tags: bool,
+        include_deck: bool = False,
+        include_notetype: bool = False,
+        include_guid: bool = False,
+        search: str = "",
+        context: Optional[SearchContext] = None,
+    ) -> int:
+        """Write the notes of a deck and its children, or of the whole
+        collection if DECK_ID is 0, to a tab-separated text file, returning
+        the number of notes. If SEARCH is given, the notes of the matching
+        cards are exported instead."""
+        return self._run_command(
+            pb.BackendInput(
+                export_notes_as_text=pb.ExportNotesAsTextIn(
+                    out_path=out_path,
+                    deck_id=deck_id,
+                    search=search,
+                    context=context,
+                    include_html=include_html,
+                    include_tags=include_tags,
+                    include_deck=include_deck,
+                    include_notetype=include_notetype,
+                    include_guid=include_guid,
+                )
+            )
+        ).export_notes_as_text
+
+    def import_package(
+        self, package_path: str, duplicate_mode: int = DuplicateMode.UPDATE_IF_NEWER
+    ) -> ImportPackageOut:
+        """Add the notes, cards and media in an .apkg file to the collection,
+        returning what was done with each note. DUPLICATE_MODE decides what
+        happens to notes already in the collection. Reports its progress,
+        and can be aborted."""
+        return self._run_command(
+            pb.BackendInput(
+                import_package=pb.ImportPackageIn(
+                    package_path=package_path, duplicate_mode=duplicate_mode
+                )
+            )
+        ).import_package
+
+    def import_collection_package(self, package_path: str) -> None:
+        """Replace the collection with the one in a .colpkg file, and add its
+        media. The collection must be closed. Reports its progress, and can
+        be aborted."""
+        self._run_command(pb.BackendInput(import_collection_package=package_path))
+
+    def preview_text_import(
+        self, path: str, delimiter: str = "", max_rows: int = 10
+    ) -> TextImportPreview:
+        """Read the first MAX_ROWS rows of a text file. The delimiter is
+        detected if not provided."""
+        return self._run_command(
+            pb.BackendInput(
+                preview_text_import=pb.TextImportPreviewIn(
+                    path=path, delimiter=delimiter, max_rows=max_rows
+                )
+            )
+        ).preview_text_import
+
+    def import_text(
+        self,
+        path: str,
+        notetype_id: int,
+        deck_id: int,
+        columns: List[TextColumnMapping],
+        allow_html: bool,
+        duplicate_mode: int,
+        delimiter: str = "",
+        tags_for_updated: Sequence[str] = (),
+    ) -> ImportTextOut:
+        """Add or update a note for each row of a text file. COLUMNS maps
+        each column to a field, the tags, the deck or the guid."""
+        return self._run_command(
+            pb.BackendInput(
+                import_text=pb.ImportTextIn(
+                    path=path,
+                    notetype_id=notetype_id,
+                    deck_id=deck_id,
+                    delimiter=delimiter,
+                    columns=columns,
+                    allow_html=allow_html,
+                    duplicate_mode=duplicate_mode,
+                    tags_for_updated=tags_for_updated,
+                )
+            )
+        ).import_text
+
+    def import_foreign(self, kind: int, path: str, deck_id: int) -> ImportForeignOut:
+        """Add the notes of another program's file as new notes, with new
+        note types. KIND is a ForeignImportKind."""
+        return self._run_command(
+            pb.BackendInput(
+                import_foreign=pb.ImportForeignIn(kind=kind, path=path, deck_id=deck_id)
+            )
+        ).import_foreign
+
+    def field_checksum(self, text: str) -> int:
+        return self._run_command(pb.BackendInput(field_checksum=text)).field_checksum
+
+    def sort_field_text(self, html: str) -> str:
+        return self._run_command(pb.BackendInput(sort_field_text=html)).sort_field_text
+
+
+class CollectionSnapshot:
+    """The collection as it was when the snapshot was taken. Queries don't
+    block changes made through the main connection, so slow reads like the
+    statistics can be run on a background thread.
+
+    The query methods mirror those of anki.db.DB, but only accept positional
+    arguments. Close the snapshot when done, or use it as a context manager."""
+
+    def __init__(self, backend: RustBackend, handle: int) -> None:
+        self._backend = backend
+        self._handle = handle
+
+    def all(self, sql: str, *args) -> List[Tuple]:
+        return self._backend._snapshot_query(self._handle, sql, args)
+
+    def first(self, sql: str, *args) -> Optional[Tuple]:
+        rows = self.all(sql, *args)
+        if rows:
+            return rows[0]
+        return None
+
+    def scalar(self, sql: str, *args) -> Any:
+        row = self.first(sql, *args)
+        if row:
+            return row[0]
+        return None
+
+    def list(self, sql: str, *args) -> List:
+        return [row[0] for row in self.all(sql, *args)]
+
+    def close(self) -> None:
+        self._backend._close_snapshot(self._handle)
+
+    def __enter__(self) -> "CollectionSnapshot":
+        return self
+
+    def __exit__(self, *args) -> None:
+        self.close()
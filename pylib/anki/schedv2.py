@@ -3,12 +3,9 @@
 
 from __future__ import annotations
 
-import datetime
-import itertools
 import random
 import time
 from heapq import *
-from operator import itemgetter
 
 # from anki.collection import _Collection
 from typing import Any, Callable, Dict, List, Optional, Set, Tuple, Union
@@ -18,7 +15,20 @@ from anki import hooks
 from anki.cards import Card
 from anki.consts import *
 from anki.lang import _
-from anki.rsbackend import SchedTimingToday
+from anki.rsbackend import (
+    BuryOrSuspendMode,
+    CardSchedulingState,
+    DeckDueCountsOut,
+    DeckDueInput,
+    DeckTreeNode,
+    FilteredSearchTerm,
+    LeechEvent,
+    LoadBalancerConfig,
+    RevlogAnswer,
+    SchedTimingToday,
+    SchedulingConfig,
+    UnburyMode,
+)
 from anki.utils import fmtTimeSpan, ids2str, intTime
 
 # card types: 0=new, 1=lrn, 2=rev, 3=relrn
@@ -89,47 +99,124 @@ class Scheduler:
         card.flushSched()
 
     def _answerCard(self, card: Card, ease: int) -> None:
-        if self._previewingCard(card):
-            self._answerCardPreview(card, ease)
+        "Schedule the card in the backend, then update the queues and revlog."
+        startingQueue = card.queue
+        previewing = self._previewingCard(card)
+        out = self.col.backend.answer_card(
+            self._cardSchedulingState(card),
+            ease,
+            self._schedulingConfig(card),
+            int(time.time()),
+            self.today,
+            self.dayCutoff,
+            self._loadBalancerConfig(),
+            card.note().stringTags(),
+        )
+        state = out.card
+        card.type = state.ctype
+        card.queue = state.queue
+        card.due = state.due
+        card.ivl = state.interval
+        card.factor = state.ease_factor
+        card.reps = state.reps
+        card.lapses = state.lapses
+        card.left = state.left
+        card.did = state.deck_id
+        card.odue = state.original_due
+        card.odid = state.original_deck_id
+
+        if previewing:
+            if ease == 1:
+                # shown again after the preview delay
+                self.lrnCount += 1
             return
 
-        card.reps += 1
-
-        if card.queue == 0:
-            # came from the new queue, move to learning
-            card.queue = 1
-            card.type = 1
-            # init reps to graduation
-            card.left = self._startingLeft(card)
-            # update daily limit
+        # update daily limits
+        if startingQueue == 0:
             self._updateStats(card, "new")
-
-        if card.queue in (1, QUEUE_TYPE_DAY_LEARN_RELEARN):
-            self._answerLrnCard(card, ease)
-        elif card.queue == 2:
-            self._answerRevCard(card, ease)
-            # update daily limit
+        elif startingQueue == 2:
             self._updateStats(card, "rev")
-        else:
-            assert 0
 
-        # once a card has been answered once, the original due date
-        # no longer applies
-        if card.odue:
-            card.odue = 0
+        if card.queue == 1:
+            self._addToLrnQueue(card)
+        if out.HasField("leech"):
+            self._onLeech(card, out.leech)
+        self._logAnswer(card, out.revlog)
+
+    def _cardSchedulingState(self, card: Card) -> CardSchedulingState:
+        return CardSchedulingState(
+            id=card.id,
+            ctype=card.type,
+            queue=card.queue,
+            due=card.due,
+            interval=card.ivl,
+            ease_factor=card.factor,
+            reps=card.reps,
+            lapses=card.lapses,
+            left=card.left,
+            deck_id=card.did,
+            original_due=card.odue,
+            original_deck_id=card.odid,
+        )
 
-    def _answerCardPreview(self, card: Card, ease: int) -> None:
-        assert 1 <= ease <= 2
+    def _schedulingConfig(self, card: Card) -> SchedulingConfig:
+        new = self._newConf(card)
+        lapse = self._lapseConf(card)
+        rev = self._revConf(card)
+        previewing = bool(self._previewingCard(card))
+        return SchedulingConfig(
+            new_delays=new["delays"],
+            graduating_interval=new["ints"][0],
+            easy_interval=new["ints"][1],
+            initial_ease=new["initialFactor"],
+            lapse_delays=lapse["delays"],
+            lapse_multiplier=lapse["mult"],
+            minimum_interval=lapse["minInt"],
+            leech_threshold=lapse["leechFails"],
+            leech_suspend=lapse["leechAction"] == 0,
+            easy_multiplier=rev["ease4"],
+            hard_multiplier=rev.get("hardFactor", 1.2),
+            interval_multiplier=rev.get("ivlFct", 1),
+            maximum_interval=rev["maxIvl"],
+            previewing=previewing,
+            preview_delay_secs=self._previewDelay(card) if previewing else 0,
+            algorithm=self.col.decks.confForDid(card.odid or card.did).get(
+                "algorithm", 0
+            ),
+        )
 
-        if ease == 1:
-            # repeat after delay
-            card.queue = QUEUE_TYPE_PREVIEW
-            card.due = intTime() + self._previewDelay(card)
-            self.lrnCount += 1
-        else:
-            # restore original card state and remove from filtered deck
-            self._restorePreviewCard(card)
-            self._removeFromFiltered(card)
+    def _loadBalancerConfig(self) -> Optional[LoadBalancerConfig]:
+        "If enabled, reviews are spread over the days with the fewest cards due."
+        if not self.col.conf.get("loadBalance", False):
+            return None
+        # the day the current scheduling day started on; 0 is Monday
+        weekday = time.localtime(self.dayCutoff - 86400).tm_wday
+        return LoadBalancerConfig(
+            today_weekday=weekday,
+            free_weekdays=self.col.conf.get("freeWeekdays", []),
+        )
+
+    def _logAnswer(self, card: Card, revlog: RevlogAnswer) -> None:
+        def log():
+            self.col.db.execute(
+                "insert into revlog values (?,?,?,?,?,?,?,?,?)",
+                int(time.time() * 1000),
+                card.id,
+                self.col.usn(),
+                revlog.ease,
+                revlog.interval,
+                revlog.last_interval,
+                revlog.ease_factor,
+                card.timeTaken(),
+                revlog.review_kind,
+            )
+
+        try:
+            log()
+        except:
+            # duplicate pk; retry in 10ms
+            time.sleep(0.01)
+            log()
 
     def counts(self, card: None = None) -> tuple:
         counts = [self.newCount, self.lrnCount, self.revCount]
@@ -195,131 +282,84 @@ order by due"""
             g["revToday"][1] -= rev
             self.col.decks.save(g)
 
-    def _walkingCount(
-        self, limFn: Optional[Callable] = None, cntFn: Optional[Callable] = None
-    ) -> Any:
-        tot = 0
-        pcounts: Dict[int, int] = {}
-        # for each of the active decks
-        nameMap = self.col.decks.nameMap()
-        for did in self.col.decks.active():
-            # early alphas were setting the active ids as a str
-            did = int(did)
-            # get the individual deck's limit
-            lim = limFn(self.col.decks.get(did))
-            if not lim:
-                continue
-            # check the parents
-            parents = self.col.decks.parents(did, nameMap)
-            for p in parents:
-                # add if missing
-                if p["id"] not in pcounts:
-                    pcounts[p["id"]] = limFn(p)
-                # take minimum of child and parent
-                lim = min(pcounts[p["id"]], lim)
-            # see how many cards we actually have
-            cnt = cntFn(did, lim)
-            # if non-zero, decrement from parent counts
-            for p in parents:
-                pcounts[p["id"]] -= cnt
-            # we may also be a parent
-            pcounts[did] = lim - cnt
-            # and add to running total
-            tot += cnt
-        return tot
+    def _deckDueInputs(self, newOnly: bool = False) -> List[DeckDueInput]:
+        "Each deck's own limits and due cards, ignoring parents and children."
+
+        def counts(cond: str, *args) -> Dict[int, int]:
+            return dict(
+                self.col.db.all(
+                    f"select did, count() from cards where {cond} group by did",
+                    *args,
+                )
+            )
+
+        new = counts("queue = 0")
+        if newOnly:
+            lrn: Dict[int, int] = {}
+            dayLrn: Dict[int, int] = {}
+            rev: Dict[int, int] = {}
+        else:
+            lrn = counts(
+                "queue = 1 and due < ?", intTime() + self.col.conf["collapseTime"]
+            )
+            dayLrn = counts(
+                f"queue = {QUEUE_TYPE_DAY_LEARN_RELEARN} and due <= ?", self.today
+            )
+            rev = counts("queue = 2 and due <= ?", self.today)
+
+        decks = []
+        for deck in self.col.decks.all():
+            did = deck["id"]
+            decks.append(
+                DeckDueInput(
+                    deck_id=did,
+                    name=deck["name"],
+                    filtered=bool(deck["dyn"]),
+                    new_limit=self._deckNewLimitSingle(deck),
+                    review_limit=self._deckRevLimitOwn(deck),
+                    new_cards=new.get(did, 0),
+                    learn_cards=lrn.get(did, 0),
+                    day_learn_cards=dayLrn.get(did, 0),
+                    review_cards=rev.get(did, 0),
+                    collapsed=bool(deck["collapsed"]),
+                )
+            )
+        return decks
 
     # Deck list
     ##########################################################################
 
-    def deckDueList(self) -> List[list]:
-        "Returns [deckname, did, rev, lrn, new]"
+    def _deckDueCounts(self) -> DeckDueCountsOut:
         self._checkDay()
         self.col.decks.checkIntegrity()
-        decks = self.col.decks.all()
-        decks.sort(key=itemgetter("name"))
-        lims: Dict[str, List[int]] = {}
-        data = []
-
-        def parent(name):
-            parts = name.split("::")
-            if len(parts) < 2:
-                return None
-            parts = parts[:-1]
-            return "::".join(parts)
-
-        childMap = self.col.decks.childMap()
-        for deck in decks:
-            p = parent(deck["name"])
-            # new
-            nlim = self._deckNewLimitSingle(deck)
-            if p:
-                nlim = min(nlim, lims[p][0])
-            new = self._newForDeck(deck["id"], nlim)
-            # learning
-            lrn = self._lrnForDeck(deck["id"])
-            # reviews
-            if p:
-                plim = lims[p][1]
-            else:
-                plim = None
-            rlim = self._deckRevLimitSingle(deck, parentLimit=plim)
-            rev = self._revForDeck(deck["id"], rlim, childMap)
-            # save to list
-            data.append([deck["name"], deck["id"], rev, lrn, new])
-            # add deck as a parent
-            lims[deck["name"]] = [nlim, rlim]
-        return data
+        return self.col.backend.deck_due_counts(
+            self._deckDueInputs(), self.reportLimit
+        )
+
+    def deckDueList(self) -> List[list]:
+        "Returns [deckname, did, rev, lrn, new]"
+        return [
+            [d.name, d.deck_id, d.review_count, d.learn_count, d.new_count]
+            for d in self._deckDueCounts().decks
+        ]
 
     def deckDueTree(self) -> Any:
-        return self._groupChildren(self.deckDueList())
+        return self._deckTreeToTuples(self._deckDueCounts().top.children)
 
-    def _groupChildren(
-        self, grps: List[List]
+    def _deckTreeToTuples(
+        self, nodes: List[DeckTreeNode]
     ) -> Tuple[Tuple[Any, Any, Any, Any, Any, Any], ...]:
-        # first, split the group names into components
-        for g in grps:
-            g[0] = g[0].split("::")
-        # and sort based on those components
-        grps.sort(key=itemgetter(0))
-        # then run main function
-        return self._groupChildrenMain(grps)
-
-    def _groupChildrenMain(self, grps: Any) -> Any:
-        tree = []
-        # group and recurse
-        def key(grp):
-            return grp[0][0]
-
-        for (head, tail) in itertools.groupby(grps, key=key):
-            tail = list(tail)  # type: ignore
-            did = None
-            rev = 0
-            new = 0
-            lrn = 0
-            children: Any = []
-            for c in tail:
-                if len(c[0]) == 1:
-                    # current node
-                    did = c[1]
-                    rev += c[2]
-                    lrn += c[3]
-                    new += c[4]
-                else:
-                    # set new string to tail
-                    c[0] = c[0][1:]
-                    children.append(c)
-            children = self._groupChildrenMain(children)
-            # tally up children counts
-            for ch in children:
-                lrn += ch[3]
-                new += ch[4]
-            # limit the counts to the deck's limits
-            conf = self.col.decks.confForDid(did)
-            deck = self.col.decks.get(did)
-            if not conf["dyn"]:
-                new = max(0, min(new, self._deckNewLimitSingle(deck)))
-            tree.append((head, did, rev, lrn, new, children))
-        return tuple(tree)
+        return tuple(
+            (
+                node.names[-1],
+                node.deck_id,
+                node.review_count,
+                node.learn_count,
+                node.new_count,
+                self._deckTreeToTuples(node.children),
+            )
+            for node in nodes
+        )
 
     # Getting the next card
     ##########################################################################
@@ -367,14 +407,10 @@ order by due"""
     ##########################################################################
 
     def _resetNewCount(self) -> None:
-        cntFn = lambda did, lim: self.col.db.scalar(
-            """
-select count() from (select 1 from cards where
-did = ? and queue = 0 limit ?)""",
-            did,
-            lim,
+        self.newCount = self.col.backend.new_count_for_active_decks(
+            self._deckDueInputs(newOnly=True),
+            [int(did) for did in self.col.decks.active()],
         )
-        self.newCount = self._walkingCount(self._deckNewLimitSingle, cntFn)
 
     def _resetNew(self) -> None:
         self._resetNewCount()
@@ -456,19 +492,6 @@ did = ? and queue = 0 limit ?)""",
                 lim = min(rem, lim)
         return lim
 
-    def _newForDeck(self, did: int, lim: int) -> Any:
-        "New count for a single deck."
-        if not lim:
-            return 0
-        lim = min(lim, self.reportLimit)
-        return self.col.db.scalar(
-            """
-select count() from
-(select 1 from cards where did = ? and queue = 0 limit ?)""",
-            did,
-            lim,
-        )
-
     def _deckNewLimitSingle(self, g: Dict[str, Any]) -> Any:
         "Limit for deck without parent limits."
         if g["dyn"]:
@@ -567,6 +590,18 @@ limit %d"""
                 return card
         return None
 
+    def _addToLrnQueue(self, card: Card) -> None:
+        "Add a card that was just answered, if it's due within the collapse time."
+        if card.due < (intTime() + self.col.conf["collapseTime"]):
+            self.lrnCount += 1
+            # if the queue is not empty and there's nothing else to do, make
+            # sure we don't put it at the head of the queue and end up showing
+            # it twice in a row
+            if self._lrnQueue and not self.revCount and not self.newCount:
+                smallestDue = self._lrnQueue[0][0]
+                card.due = max(card.due, smallestDue + 1)
+            heappush(self._lrnQueue, (card.due, card.id))
+
     # daily learning
     def _fillLrnDay(self) -> Optional[bool]:
         if not self.lrnCount:
@@ -604,93 +639,6 @@ did = ? and queue = {QUEUE_TYPE_DAY_LEARN_RELEARN} and due <= ? limit ?""",
             return self.col.getCard(self._lrnDayQueue.pop())
         return None
 
-    def _answerLrnCard(self, card: Card, ease: int) -> None:
-        conf = self._lrnConf(card)
-        if card.type in (2, CARD_TYPE_RELEARNING):
-            type = 2
-        else:
-            type = 0
-        # lrnCount was decremented once when card was fetched
-        lastLeft = card.left
-
-        leaving = False
-
-        # immediate graduate?
-        if ease == 4:
-            self._rescheduleAsRev(card, conf, True)
-            leaving = True
-        # next step?
-        elif ease == 3:
-            # graduation time?
-            if (card.left % 1000) - 1 <= 0:
-                self._rescheduleAsRev(card, conf, False)
-                leaving = True
-            else:
-                self._moveToNextStep(card, conf)
-        elif ease == 2:
-            self._repeatStep(card, conf)
-        else:
-            # back to first step
-            self._moveToFirstStep(card, conf)
-
-        self._logLrn(card, ease, conf, leaving, type, lastLeft)
-
-    def _updateRevIvlOnFail(self, card: Card, conf: Dict[str, Any]) -> None:
-        card.lastIvl = card.ivl
-        card.ivl = self._lapseIvl(card, conf)
-
-    def _moveToFirstStep(self, card: Card, conf: Dict[str, Any]) -> Any:
-        card.left = self._startingLeft(card)
-
-        # relearning card?
-        if card.type == CARD_TYPE_RELEARNING:
-            self._updateRevIvlOnFail(card, conf)
-
-        return self._rescheduleLrnCard(card, conf)
-
-    def _moveToNextStep(self, card: Card, conf: Dict[str, Any]) -> None:
-        # decrement real left count and recalculate left today
-        left = (card.left % 1000) - 1
-        card.left = self._leftToday(conf["delays"], left) * 1000 + left
-
-        self._rescheduleLrnCard(card, conf)
-
-    def _repeatStep(self, card: Card, conf: Dict[str, Any]) -> None:
-        delay = self._delayForRepeatingGrade(conf, card.left)
-        self._rescheduleLrnCard(card, conf, delay=delay)
-
-    def _rescheduleLrnCard(
-        self, card: Card, conf: Dict[str, Any], delay: Optional[int] = None
-    ) -> Any:
-        # normal delay for the current step?
-        if delay is None:
-            delay = self._delayForGrade(conf, card.left)
-
-        card.due = int(time.time() + delay)
-        # due today?
-        if card.due < self.dayCutoff:
-            # add some randomness, up to 5 minutes or 25%
-            maxExtra = min(300, int(delay * 0.25))
-            fuzz = random.randrange(0, maxExtra)
-            card.due = min(self.dayCutoff - 1, card.due + fuzz)
-            card.queue = 1
-            if card.due < (intTime() + self.col.conf["collapseTime"]):
-                self.lrnCount += 1
-                # if the queue is not empty and there's nothing else to do, make
-                # sure we don't put it at the head of the queue and end up showing
-                # it twice in a row
-                if self._lrnQueue and not self.revCount and not self.newCount:
-                    smallestDue = self._lrnQueue[0][0]
-                    card.due = max(card.due, smallestDue + 1)
-                heappush(self._lrnQueue, (card.due, card.id))
-        else:
-            # the card is due in one or more days, so we need to use the
-            # day learn queue
-            ahead = ((card.due - self.dayCutoff) // 86400) + 1
-            card.due = self.today + ahead
-            card.queue = QUEUE_TYPE_DAY_LEARN_RELEARN
-        return delay
-
     def _delayForGrade(self, conf: Dict[str, Any], left: int) -> Any:
         left = left % 1000
         try:
@@ -719,25 +667,6 @@ did = ? and queue = {QUEUE_TYPE_DAY_LEARN_RELEARN} and due <= ? limit ?""",
         else:
             return self._newConf(card)
 
-    def _rescheduleAsRev(self, card: Card, conf: Dict[str, Any], early: bool) -> None:
-        lapse = card.type in (2, CARD_TYPE_RELEARNING)
-
-        if lapse:
-            self._rescheduleGraduatingLapse(card, early)
-        else:
-            self._rescheduleNew(card, conf, early)
-
-        # if we were dynamic, graduating means moving back to the old deck
-        if card.odid:
-            self._removeFromFiltered(card)
-
-    def _rescheduleGraduatingLapse(self, card: Card, early: bool = False) -> None:
-        if early:
-            card.ivl += 1
-        card.due = self.today + card.ivl
-        card.queue = 2
-        card.type = 2
-
     def _startingLeft(self, card: Card) -> int:
         if card.type == CARD_TYPE_RELEARNING:
             conf = self._lapseConf(card)
@@ -781,74 +710,6 @@ did = ? and queue = {QUEUE_TYPE_DAY_LEARN_RELEARN} and due <= ? limit ?""",
             ideal = self._fuzzedIvl(ideal)
         return ideal
 
-    def _rescheduleNew(self, card: Card, conf: Dict[str, Any], early: bool) -> None:
-        "Reschedule a new card that's graduated for the first time."
-        card.ivl = self._graduatingIvl(card, conf, early)
-        card.due = self.today + card.ivl
-        card.factor = conf["initialFactor"]
-        card.type = card.queue = 2
-
-    def _logLrn(
-        self,
-        card: Card,
-        ease: int,
-        conf: Dict[str, Any],
-        leaving: bool,
-        type: int,
-        lastLeft: int,
-    ) -> None:
-        lastIvl = -(self._delayForGrade(conf, lastLeft))
-        if leaving:
-            ivl = card.ivl
-        else:
-            if ease == 2:
-                ivl = -self._delayForRepeatingGrade(conf, card.left)
-            else:
-                ivl = -self._delayForGrade(conf, card.left)
-
-        def log():
-            self.col.db.execute(
-                "insert into revlog values (?,?,?,?,?,?,?,?,?)",
-                int(time.time() * 1000),
-                card.id,
-                self.col.usn(),
-                ease,
-                ivl,
-                lastIvl,
-                card.factor,
-                card.timeTaken(),
-                type,
-            )
-
-        try:
-            log()
-        except:
-            # duplicate pk; retry in 10ms
-            time.sleep(0.01)
-            log()
-
-    def _lrnForDeck(self, did: int) -> Any:
-        cnt = (
-            self.col.db.scalar(
-                """
-select count() from
-(select null from cards where did = ? and queue = 1 and due < ? limit ?)""",
-                did,
-                intTime() + self.col.conf["collapseTime"],
-                self.reportLimit,
-            )
-            or 0
-        )
-        return cnt + self.col.db.scalar(
-            f"""
-select count() from
-(select null from cards where did = ? and queue = {QUEUE_TYPE_DAY_LEARN_RELEARN}
-and due <= ? limit ?)""",
-            did,
-            self.today,
-            self.reportLimit,
-        )
-
     # Reviews
     ##########################################################################
 
@@ -856,6 +717,13 @@ and due <= ? limit ?)""",
         d = self.col.decks.get(self.col.decks.selected(), default=False)
         return self._deckRevLimitSingle(d)
 
+    def _deckRevLimitOwn(self, d: Dict[str, Any]) -> Any:
+        "Limit for deck without parent limits."
+        if d["dyn"]:
+            return self.dynReportLimit
+        c = self.col.decks.confForDid(d["id"])
+        return max(0, c["rev"]["perDay"] - d["revToday"][1])
+
     def _deckRevLimitSingle(
         self, d: Dict[str, Any], parentLimit: Optional[int] = None
     ) -> Any:
@@ -863,11 +731,9 @@ and due <= ? limit ?)""",
         if not d:
             return 0
 
+        lim = self._deckRevLimitOwn(d)
         if d["dyn"]:
-            return self.dynReportLimit
-
-        c = self.col.decks.confForDid(d["id"])
-        lim = max(0, c["rev"]["perDay"] - d["revToday"][1])
+            return lim
 
         if parentLimit is not None:
             return min(parentLimit, lim)
@@ -879,19 +745,6 @@ and due <= ? limit ?)""",
                 lim = min(lim, self._deckRevLimitSingle(parent, parentLimit=lim))
             return lim
 
-    def _revForDeck(self, did: int, lim: int, childMap: Dict[int, Any]) -> Any:
-        dids = [did] + self.col.decks.childDids(did, childMap)
-        lim = min(lim, self.reportLimit)
-        return self.col.db.scalar(
-            """
-select count() from
-(select 1 from cards where did in %s and queue = 2
-and due <= ? limit ?)"""
-            % ids2str(dids),
-            self.today,
-            lim,
-        )
-
     def _resetRevCount(self) -> None:
         lim = self._currentRevLimit()
         self.revCount = self.col.db.scalar(
@@ -954,87 +807,13 @@ select id from cards where did in %s and queue = 2 and due <= ? limit ?)"""
             self.reportLimit,
         )
 
-    # Answering a review card
+    # Interval management
     ##########################################################################
 
-    def _answerRevCard(self, card: Card, ease: int) -> None:
-        delay = 0
-        early = card.odid and (card.odue > self.today)
-        type = early and 3 or 1
-
-        if ease == 1:
-            delay = self._rescheduleLapse(card)
-        else:
-            self._rescheduleRev(card, ease, early)
-
-        self._logRev(card, ease, delay, type)
-
-    def _rescheduleLapse(self, card: Card) -> Any:
-        conf = self._lapseConf(card)
-
-        card.lapses += 1
-        card.factor = max(1300, card.factor - 200)
-
-        suspended = self._checkLeech(card, conf) and card.queue == -1
-
-        if conf["delays"] and not suspended:
-            card.type = CARD_TYPE_RELEARNING
-            delay = self._moveToFirstStep(card, conf)
-        else:
-            # no relearning steps
-            self._updateRevIvlOnFail(card, conf)
-            self._rescheduleAsRev(card, conf, early=False)
-            # need to reset the queue after rescheduling
-            if suspended:
-                card.queue = -1
-            delay = 0
-
-        return delay
-
     def _lapseIvl(self, card: Card, conf: Dict[str, Any]) -> Any:
         ivl = max(1, conf["minInt"], int(card.ivl * conf["mult"]))
         return ivl
 
-    def _rescheduleRev(self, card: Card, ease: int, early: Union[bool, int]) -> None:
-        # update interval
-        card.lastIvl = card.ivl
-        if early:
-            self._updateEarlyRevIvl(card, ease)
-        else:
-            self._updateRevIvl(card, ease)
-
-        # then the rest
-        card.factor = max(1300, card.factor + [-150, 0, 150][ease - 2])
-        card.due = self.today + card.ivl
-
-        # card leaves filtered deck
-        self._removeFromFiltered(card)
-
-    def _logRev(self, card: Card, ease: int, delay: int, type: int) -> None:
-        def log():
-            self.col.db.execute(
-                "insert into revlog values (?,?,?,?,?,?,?,?,?)",
-                int(time.time() * 1000),
-                card.id,
-                self.col.usn(),
-                ease,
-                -delay or card.ivl,
-                card.lastIvl,
-                card.factor,
-                card.timeTaken(),
-                type,
-            )
-
-        try:
-            log()
-        except:
-            # duplicate pk; retry in 10ms
-            time.sleep(0.01)
-            log()
-
-    # Interval management
-    ##########################################################################
-
     def _nextRevIvl(self, card: Card, ease: int, fuzz: bool) -> int:
         "Next review interval for CARD, given EASE."
         delay = self._daysLate(card)
@@ -1092,12 +871,6 @@ select id from cards where did in %s and queue = 2 and due <= ? limit ?)"""
         due = card.odue if card.odid else card.due
         return max(0, self.today - due)
 
-    def _updateRevIvl(self, card: Card, ease: int) -> None:
-        card.ivl = self._nextRevIvl(card, ease, fuzz=True)
-
-    def _updateEarlyRevIvl(self, card: Card, ease: int) -> None:
-        card.ivl = self._earlyReviewIvl(card, ease)
-
     # next interval for card when answered early+correctly
     def _earlyReviewIvl(self, card: Card, ease: int) -> int:
         assert card.odid and card.type == 2
@@ -1152,133 +925,45 @@ select id from cards where did in %s and queue = 2 and due <= ? limit ?)"""
         return cnt
 
     def _fillDyn(self, deck: Dict[str, Any]) -> int:
-        start = -100000
-        total = 0
+        terms = []
         for search, limit, order in deck["terms"]:
-            orderlimit = self._dynOrder(order, limit)
             if search.strip():
                 search = "(%s)" % search
             search = "%s -is:suspended -is:buried -deck:filtered" % search
             try:
-                ids = self.col.findCards(search, order=orderlimit)
+                ids = self.col.findCards(search)
             except:
-                return total
-            # move the cards over
-            self.col.log(deck["id"], ids)
-            self._moveToDyn(deck["id"], ids, start=start + total)
-            total += len(ids)
+                break
+            terms.append(FilteredSearchTerm(card_ids=ids, order=order, limit=limit))
+        # ordering, limits and moving the cards are handled by the backend
+        self.col.db.commit()
+        total = self.col.backend.fill_filtered_deck(
+            deck["id"], terms, bool(deck["resched"]), self.today, self.col.usn()
+        )
+        self.col.log(deck["id"], total)
         return total
 
-    def emptyDyn(self, did: Optional[int], lim: Optional[str] = None) -> None:
-        if not lim:
-            lim = "did = %s" % did
-        self.col.log(self.col.db.list("select id from cards where %s" % lim))
-
-        self.col.db.execute(
-            """
-update cards set did = odid, %s,
-due = (case when odue>0 then odue else due end), odue = 0, odid = 0, usn = ? where %s"""
-            % (self._restoreQueueSnippet, lim),
-            self.col.usn(),
-        )
+    def emptyDyn(self, did: int) -> None:
+        self.col.log(did)
+        self.col.db.commit()
+        self.col.backend.empty_filtered_deck(did, self.col.usn())
 
     def remFromDyn(self, cids: List[int]) -> None:
-        self.emptyDyn(None, "id in %s and odid" % ids2str(cids))
-
-    def _dynOrder(self, o: int, l: int) -> str:
-        if o == DYN_OLDEST:
-            t = "(select max(id) from revlog where cid=c.id)"
-        elif o == DYN_RANDOM:
-            t = "random()"
-        elif o == DYN_SMALLINT:
-            t = "ivl"
-        elif o == DYN_BIGINT:
-            t = "ivl desc"
-        elif o == DYN_LAPSES:
-            t = "lapses desc"
-        elif o == DYN_ADDED:
-            t = "n.id"
-        elif o == DYN_REVADDED:
-            t = "n.id desc"
-        elif o == DYN_DUEPRIORITY:
-            t = (
-                "(case when queue=2 and due <= %d then (ivl / cast(%d-due+0.001 as real)) else 100000+due end)"
-                % (self.today, self.today)
-            )
-        else:  # DYN_DUE or unknown
-            t = "c.due, c.ord"
-        return t + " limit %d" % l
-
-    def _moveToDyn(self, did: int, ids: List[int], start: int = -100000) -> None:
-        deck = self.col.decks.get(did)
-        data = []
-        u = self.col.usn()
-        due = start
-        for id in ids:
-            data.append((did, due, u, id))
-            due += 1
-
-        queue = ""
-        if not deck["resched"]:
-            queue = ",queue=2"
-
-        query = (
-            """
-update cards set
-odid = did, odue = due,
-did = ?,
-due = (case when due <= 0 then due else ? end),
-usn = ?
-%s
-where id = ?
-"""
-            % queue
-        )
-        self.col.db.executemany(query, data)
-
-    def _removeFromFiltered(self, card: Card) -> None:
-        if card.odid:
-            card.did = card.odid
-            card.odue = 0
-            card.odid = 0
-
-    def _restorePreviewCard(self, card: Card) -> None:
-        assert card.odid
-
-        card.due = card.odue
-
-        # learning and relearning cards may be seconds-based or day-based;
-        # other types map directly to queues
-        if card.type in (1, CARD_TYPE_RELEARNING):
-            if card.odue > 1000000000:
-                card.queue = 1
-            else:
-                card.queue = QUEUE_TYPE_DAY_LEARN_RELEARN
-        else:
-            card.queue = card.type
+        self.col.log(cids)
+        self.col.db.commit()
+        self.col.backend.empty_filtered_deck(0, self.col.usn(), card_ids=cids)
 
     # Leeches
     ##########################################################################
 
-    def _checkLeech(self, card: Card, conf: Dict[str, Any]) -> bool:
-        "Leech handler. True if card was a leech."
-        lf = conf["leechFails"]
-        if not lf:
-            return False
-        # if over threshold or every half threshold reps after that
-        if card.lapses >= lf and (card.lapses - lf) % (max(lf // 2, 1)) == 0:
-            # add a leech tag
-            f = card.note()
-            f.addTag("leech")
-            f.flush()
-            # handle
-            a = conf["leechAction"]
-            if a == 0:
-                card.queue = -1
-            # notify UI
-            hooks.card_did_leech(card)
-            return True
-        return False
+    def _onLeech(self, card: Card, leech: LeechEvent) -> None:
+        "Called when the backend reports that CARD became a leech."
+        # save the tags the backend added
+        f = card.note()
+        f.setTagsFromStr(leech.note_tags)
+        f.flush()
+        # notify UI
+        hooks.card_did_leech(card)
 
     # Tools
     ##########################################################################
@@ -1347,13 +1032,8 @@ where id = ?
     def _updateCutoff(self) -> None:
         oldToday = self.today
         timing = self._timing_today()
-
-        if self._new_timezone_enabled():
-            self.today = timing.days_elapsed
-            self.dayCutoff = timing.next_day_at
-        else:
-            self.today = self._daysSinceCreation()
-            self.dayCutoff = self._dayCutoff()
+        self.today = timing.days_elapsed
+        self.dayCutoff = timing.next_day_at
 
         if oldToday != self.today:
             self.col.log(self.today, self.dayCutoff)
@@ -1379,25 +1059,6 @@ where id = ?
         if time.time() > self.dayCutoff:
             self.reset()
 
-    def _dayCutoff(self) -> int:
-        rolloverTime = self.col.conf.get("rollover", 4)
-        if rolloverTime < 0:
-            rolloverTime = 24 + rolloverTime
-        date = datetime.datetime.today()
-        date = date.replace(hour=rolloverTime, minute=0, second=0, microsecond=0)
-        if date < datetime.datetime.today():
-            date = date + datetime.timedelta(days=1)
-
-        stamp = int(time.mktime(date.timetuple()))
-        return stamp
-
-    def _daysSinceCreation(self) -> int:
-        startDate = datetime.datetime.fromtimestamp(self.col.crt)
-        startDate = startDate.replace(
-            hour=self._rolloverHour(), minute=0, second=0, microsecond=0
-        )
-        return int((time.time() - time.mktime(startDate.timetuple())) // 86400)
-
     def _rolloverHour(self) -> int:
         return self.col.conf.get("rollover", 4)
 
@@ -1408,6 +1069,11 @@ where id = ?
         return self.col.conf.get("creationOffset") is not None
 
     def _timing_today(self) -> SchedTimingToday:
+        if not self._new_timezone_enabled():
+            # day boundaries in the local timezone
+            return self.col.backend.local_sched_timing_today(
+                self.col.crt, intTime(), self._rolloverHour()
+            )
         return self.col.backend.sched_timing_today(
             self.col.crt,
             self._creation_timezone_offset(),
@@ -1590,47 +1256,28 @@ To study outside of the normal schedule, click the Custom Study button below."""
     # Suspending & burying
     ##########################################################################
 
-    # learning and relearning cards may be seconds-based or day-based;
-    # other types map directly to queues
-    _restoreQueueSnippet = f"""
-queue = (case when type in (1,{CARD_TYPE_RELEARNING}) then
-  (case when (case when odue then odue else due end) > 1000000000 then 1 else
-  {QUEUE_TYPE_DAY_LEARN_RELEARN} end)
-else
-  type
-end)
-"""
-
     def suspendCards(self, ids: List[int]) -> None:
         "Suspend cards."
         self.col.log(ids)
-        self.col.db.execute(
-            "update cards set queue=-1,mod=?,usn=? where id in " + ids2str(ids),
-            intTime(),
-            self.col.usn(),
+        self.col.db.commit()
+        self.col.backend.bury_or_suspend_cards(
+            ids, BuryOrSuspendMode.SUSPEND, self.col.usn(), intTime()
         )
+        self.col.markBackendOp()
 
     def unsuspendCards(self, ids: List[int]) -> None:
         "Unsuspend cards."
         self.col.log(ids)
-        self.col.db.execute(
-            ("update cards set %s,mod=?,usn=? " "where queue = -1 and id in %s")
-            % (self._restoreQueueSnippet, ids2str(ids)),
-            intTime(),
-            self.col.usn(),
-        )
+        self.col.db.commit()
+        self.col.backend.unsuspend_cards(ids, self.col.usn(), intTime())
+        self.col.markBackendOp()
 
     def buryCards(self, cids: List[int], manual: bool = True) -> None:
-        queue = manual and QUEUE_TYPE_MANUALLY_BURIED or QUEUE_TYPE_SIBLING_BURIED
+        mode = manual and BuryOrSuspendMode.BURY_USER or BuryOrSuspendMode.BURY_SCHED
         self.col.log(cids)
-        self.col.db.execute(
-            """
-update cards set queue=?,mod=?,usn=? where id in """
-            + ids2str(cids),
-            queue,
-            intTime(),
-            self.col.usn(),
-        )
+        self.col.db.commit()
+        self.col.backend.bury_or_suspend_cards(cids, mode, self.col.usn(), intTime())
+        self.col.markBackendOp()
 
     def buryNote(self, nid) -> None:
         "Bury all cards for note until next session."
@@ -1641,78 +1288,51 @@ update cards set queue=?,mod=?,usn=? where id in """
 
     def unburyCards(self) -> None:
         "Unbury all buried cards in all decks."
-        self.col.log(
-            self.col.db.list(
-                f"select id from cards where queue in (-2, {QUEUE_TYPE_MANUALLY_BURIED})"
-            )
-        )
-        self.col.db.execute(
-            f"update cards set %s where queue in (-2, {QUEUE_TYPE_MANUALLY_BURIED})"
-            % self._restoreQueueSnippet
+        self.col.log()
+        self.col.db.commit()
+        self.col.backend.unbury_cards(
+            [], UnburyMode.ALL, self.col.usn(), intTime(), skip_undo=True
         )
 
     def unburyCardsForDeck(self, type: str = "all") -> None:
         if type == "all":
-            queue = (
-                f"queue in ({QUEUE_TYPE_SIBLING_BURIED}, {QUEUE_TYPE_MANUALLY_BURIED})"
-            )
+            mode = UnburyMode.ALL
         elif type == "manual":
-            queue = f"queue = {QUEUE_TYPE_MANUALLY_BURIED}"
+            mode = UnburyMode.USER
         elif type == "siblings":
-            queue = f"queue = {QUEUE_TYPE_SIBLING_BURIED}"
+            mode = UnburyMode.SCHED
         else:
             raise Exception("unknown type")
 
-        self.col.log(
-            self.col.db.list(
-                "select id from cards where %s and did in %s"
-                % (queue, self._deckLimit())
-            )
-        )
-        self.col.db.execute(
-            "update cards set mod=?,usn=?,%s where %s and did in %s"
-            % (self._restoreQueueSnippet, queue, self._deckLimit()),
-            intTime(),
-            self.col.usn(),
+        self.col.log()
+        self.col.db.commit()
+        self.col.backend.unbury_cards(
+            self.col.decks.active(), mode, self.col.usn(), intTime()
         )
+        self.col.markBackendOp()
 
     # Sibling spacing
     ##########################################################################
 
     def _burySiblings(self, card: Card) -> None:
-        toBury = []
-        nconf = self._newConf(card)
-        buryNew = nconf.get("bury", True)
-        rconf = self._revConf(card)
-        buryRev = rconf.get("bury", True)
-        # loop through and remove from queues
-        for cid, queue in self.col.db.execute(
-            """
-select id, queue from cards where nid=? and id!=?
-and (queue=0 or (queue=2 and due<=?))""",
-            card.nid,
+        self.col.db.commit()
+        out = self.col.backend.bury_siblings(
             card.id,
+            card.nid,
             self.today,
-        ):
-            if queue == 2:
-                if buryRev:
-                    toBury.append(cid)
-                # if bury disabled, we still discard to give same-day spacing
-                try:
-                    self._revQueue.remove(cid)
-                except ValueError:
-                    pass
-            else:
-                # if bury disabled, we still discard to give same-day spacing
-                if buryNew:
-                    toBury.append(cid)
+            self._newConf(card).get("bury", True),
+            self._revConf(card).get("bury", True),
+            self.col.usn(),
+            intTime(),
+        )
+        # siblings are removed from the queues even if burying is disabled,
+        # to give same-day spacing
+        for cid in out.siblings:
+            for queue in (self._revQueue, self._newQueue):
                 try:
-                    self._newQueue.remove(cid)
+                    queue.remove(cid)
                 except ValueError:
                     pass
-        # then bury
-        if toBury:
-            self.buryCards(toBury, manual=False)
 
     # Resetting
     ##########################################################################
@@ -1732,28 +1352,16 @@ and (queue=0 or (queue=2 and due<=?))""",
 
     def reschedCards(self, ids: List[int], imin: int, imax: int) -> None:
         "Put cards in review queue with a new interval in days (min, max)."
-        d = []
-        t = self.today
-        mod = intTime()
-        for id in ids:
-            r = random.randint(imin, imax)
-            d.append(
-                dict(
-                    id=id,
-                    due=r + t,
-                    ivl=max(1, r),
-                    mod=mod,
-                    usn=self.col.usn(),
-                    fact=STARTING_FACTOR,
-                )
-            )
-        self.remFromDyn(ids)
-        self.col.db.executemany(
-            """
-update cards set type=2,queue=2,ivl=:ivl,due=:due,odue=0,
-usn=:usn,mod=:mod,factor=:fact where id=:id""",
-            d,
-        )
+        self.setDueDate(ids, "%d-%d!" % (imin, imax))
+
+    def setDueDate(self, ids: List[int], days: str) -> None:
+        """Put cards in review queue, due in DAYS days.
+
+DAYS is a number like "0" (today), a range like "1-7", and may end in "!" to
+reset the interval to the number of days. Can be undone."""
+        self.col.db.commit()
+        self.col.backend.set_due_date(ids, days, self.today, self.col.usn(), intTime())
+        self.col.markBackendOp()
         self.col.log(ids)
 
     def resetCards(self, ids: List[int]) -> None:
@@ -11,7 +11,7 @@ and collection.backend.get_av_tags(string) to get a list of AVTags.
 from __future__ import annotations
 
 from dataclasses import dataclass
-from typing import List, Union
+from typing import List, Optional, Union
 
 
 @dataclass
@@ -24,6 +24,9 @@ class TTSTag:
     field_text: str
     lang: str
     voices: List[str]
+    speed: Optional[float]
+    pitch: Optional[float]
+    style: Optional[str]
     # each arg should be in the form 'foo=bar'
     other_args: List[str]
 
@@ -38,8 +41,16 @@ class SoundOrVideoTag:
     filename: str
 
 
+@dataclass
+class InvalidTTSTag:
+    """A TTS tag that could not be parsed, such as one missing a language
+    code or with a malformed argument."""
+
+    message: str
+
+
 # note this does not include image tags, which are handled with HTML.
-AVTag = Union[SoundOrVideoTag, TTSTag]
+AVTag = Union[SoundOrVideoTag, TTSTag, InvalidTTSTag]
 
 # Legacy utils
 ##########################################################################
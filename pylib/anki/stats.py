@@ -7,7 +7,8 @@ import time
 from typing import Any, Dict, List, Optional, Tuple
 
 from anki.lang import _, ngettext
-from anki.utils import fmtTimeSpan, ids2str
+from anki import rsbackend
+from anki.utils import fmtTimeSpan, ids2str, intTime
 
 # Card stats
 ##########################################################################
@@ -18,47 +19,37 @@ class CardStats:
         self.col = col
         self.card = card
         self.txt = ""
+        self.stats: Optional[rsbackend.CardStats] = None
 
     def report(self) -> str:
         c = self.card
         # pylint: disable=unnecessary-lambda
         fmt = lambda x, **kwargs: fmtTimeSpan(x, short=True, **kwargs)
+        s = self.col.backend.card_stats(c.id, self.col.sched.today, intTime())
+        self.stats = s
         self.txt = "<table width=100%>"
-        self.addLine(_("Added"), self.date(c.id / 1000))
-        first = self.col.db.scalar("select min(id) from revlog where cid = ?", c.id)
-        last = self.col.db.scalar("select max(id) from revlog where cid = ?", c.id)
-        if first:
-            self.addLine(_("First Review"), self.date(first / 1000))
-            self.addLine(_("Latest Review"), self.date(last / 1000))
+        self.addLine(_("Added"), self.date(s.added_secs))
+        if s.first_review_secs:
+            self.addLine(_("First Review"), self.date(s.first_review_secs))
+            self.addLine(_("Latest Review"), self.date(s.latest_review_secs))
         if c.type in (1, 2):
-            if c.odid or c.queue < 0:
-                next = None
-            else:
-                if c.queue in (2, 3):
-                    next = time.time() + ((c.due - self.col.sched.today) * 86400)
-                else:
-                    next = c.due
-                next = self.date(next)
-            if next:
-                self.addLine(_("Due"), next)
-            if c.queue == 2:
-                self.addLine(_("Interval"), fmt(c.ivl * 86400))
-            self.addLine(_("Ease"), "%d%%" % (c.factor / 10.0))
-            self.addLine(_("Reviews"), "%d" % c.reps)
-            self.addLine(_("Lapses"), "%d" % c.lapses)
-            (cnt, total) = self.col.db.first(
-                "select count(), sum(time)/1000 from revlog where cid = :id", id=c.id
-            )
-            if cnt:
-                self.addLine(_("Average Time"), self.time(total / float(cnt)))
-                self.addLine(_("Total Time"), self.time(total))
-        elif c.queue == 0:
-            self.addLine(_("Position"), c.due)
-        self.addLine(_("Card Type"), c.template()["name"])
-        self.addLine(_("Note Type"), c.model()["name"])
-        self.addLine(_("Deck"), self.col.decks.name(c.did))
-        self.addLine(_("Note ID"), c.nid)
-        self.addLine(_("Card ID"), c.id)
+            if s.due_secs:
+                self.addLine(_("Due"), self.date(s.due_secs))
+            if s.interval_days:
+                self.addLine(_("Interval"), fmt(s.interval_days * 86400))
+            self.addLine(_("Ease"), "%d%%" % (s.ease_factor / 10.0))
+            self.addLine(_("Reviews"), "%d" % s.reviews)
+            self.addLine(_("Lapses"), "%d" % s.lapses)
+            if s.revlog:
+                self.addLine(_("Average Time"), self.time(s.average_secs))
+                self.addLine(_("Total Time"), self.time(s.total_secs))
+        elif s.new_position >= 0:
+            self.addLine(_("Position"), s.new_position)
+        self.addLine(_("Card Type"), s.card_type_name)
+        self.addLine(_("Note Type"), s.notetype_name)
+        self.addLine(_("Deck"), s.deck_name)
+        self.addLine(_("Note ID"), s.note_id)
+        self.addLine(_("Card ID"), s.card_id)
         self.txt += "</table>"
         return self.txt
 
@@ -101,7 +92,7 @@ colSusp = "#ff0"
 class CollectionStats:
     def __init__(self, col) -> None:
         self.col = col
-        self._stats = None
+        self._graphData: Optional[Tuple[int, rsbackend.Graphs]] = None
         self.type = 0
         self.width = 600
         self.height = 200
@@ -231,7 +222,7 @@ from revlog where id > ? """
 
     def dueGraph(self) -> str:
         start, end, chunk = self.get_start_end_chunk()
-        d = self._due(start, end, chunk)
+        d = self._due()
         yng = []
         mtr = []
         tot = 0
@@ -278,43 +269,20 @@ from revlog where id > ? """
         i: List[str] = []
         self._line(i, _("Total"), ngettext("%d review", "%d reviews", tot) % tot)
         self._line(i, _("Average"), self._avgDay(tot, num, _("reviews")))
-        tomorrow = self.col.db.scalar(
-            """
-select count() from cards where did in %s and queue in (2,3)
-and due = ?"""
-            % self._limit(),
-            self.col.sched.today + 1,
-        )
+        tomorrow = self._graphs().due_tomorrow
         tomorrow = ngettext("%d card", "%d cards", tomorrow) % tomorrow
         self._line(i, _("Due tomorrow"), tomorrow)
         return self._lineTbl(i)
 
-    def _due(self, start=None, end=None, chunk=1) -> Any:
-        lim = ""
-        if start is not None:
-            lim += " and due-:today >= %d" % start
-        if end is not None:
-            lim += " and day < %d" % end
-        return self.col.db.all(
-            """
-select (due-:today)/:chunk as day,
-sum(case when ivl < 21 then 1 else 0 end), -- yng
-sum(case when ivl >= 21 then 1 else 0 end) -- mtr
-from cards
-where did in %s and queue in (2,3)
-%s
-group by day order by day"""
-            % (self._limit(), lim),
-            today=self.col.sched.today,
-            chunk=chunk,
-        )
+    def _due(self) -> List[Tuple[int, int, int]]:
+        return [(p.bucket, p.young, p.mature) for p in self._graphs().forecast]
 
     # Added, reps and time spent
     ######################################################################
 
     def introductionGraph(self) -> str:
         start, days, chunk = self.get_start_end_chunk()
-        data = self._added(days, chunk)
+        data = self._added()
         if not data:
             return ""
         conf: Dict[str, Any] = dict(
@@ -349,7 +317,7 @@ group by day order by day"""
 
     def repsGraphs(self) -> str:
         start, days, chunk = self.get_start_end_chunk()
-        data = self._done(days, chunk)
+        data = self._done()
         if not data:
             return ""
         conf: Dict[str, Any] = dict(
@@ -498,73 +466,23 @@ group by day order by day"""
                 )
         return (ret, alltot)
 
-    def _added(self, num=7, chunk=1) -> Any:
-        lims = []
-        if num is not None:
-            lims.append(
-                "id > %d" % ((self.col.sched.dayCutoff - (num * chunk * 86400)) * 1000)
-            )
-        lims.append("did in %s" % self._limit())
-        if lims:
-            lim = "where " + " and ".join(lims)
-        else:
-            lim = ""
-        if self.type == 0:
-            tf = 60.0  # minutes
-        else:
-            tf = 3600.0  # hours
-        return self.col.db.all(
-            """
-select
-(cast((id/1000.0 - :cut) / 86400.0 as int))/:chunk as day,
-count(id)
-from cards %s
-group by day order by day"""
-            % lim,
-            cut=self.col.sched.dayCutoff,
-            tf=tf,
-            chunk=chunk,
-        )
+    def _added(self) -> List[Tuple[int, int]]:
+        return [(p.bucket, p.count) for p in self._graphs().added]
 
-    def _done(self, num=7, chunk=1) -> Any:
-        lims = []
-        if num is not None:
-            lims.append(
-                "id > %d" % ((self.col.sched.dayCutoff - (num * chunk * 86400)) * 1000)
-            )
-        lim = self._revlogLimit()
-        if lim:
-            lims.append(lim)
-        if lims:
-            lim = "where " + " and ".join(lims)
-        else:
-            lim = ""
+    def _done(self) -> List[Tuple]:
         if self.type == 0:
             tf = 60.0  # minutes
         else:
             tf = 3600.0  # hours
-        return self.col.db.all(
-            """
-select
-(cast((id/1000.0 - :cut) / 86400.0 as int))/:chunk as day,
-sum(case when type = 0 then 1 else 0 end), -- lrn count
-sum(case when type = 1 and lastIvl < 21 then 1 else 0 end), -- yng count
-sum(case when type = 1 and lastIvl >= 21 then 1 else 0 end), -- mtr count
-sum(case when type = 2 then 1 else 0 end), -- lapse count
-sum(case when type = 3 then 1 else 0 end), -- cram count
-sum(case when type = 0 then time/1000.0 else 0 end)/:tf, -- lrn time
--- yng + mtr time
-sum(case when type = 1 and lastIvl < 21 then time/1000.0 else 0 end)/:tf,
-sum(case when type = 1 and lastIvl >= 21 then time/1000.0 else 0 end)/:tf,
-sum(case when type = 2 then time/1000.0 else 0 end)/:tf, -- lapse time
-sum(case when type = 3 then time/1000.0 else 0 end)/:tf -- cram time
-from revlog %s
-group by day order by day"""
-            % lim,
-            cut=self.col.sched.dayCutoff,
-            tf=tf,
-            chunk=chunk,
-        )
+        rows = []
+        for p in self._graphs().reviews:
+            c, t = p.counts, p.secs
+            rows.append(
+                (p.bucket, c.learn, c.young, c.mature, c.relearn, c.filtered)
+                + (t.learn / tf, t.young / tf, t.mature / tf)
+                + (t.relearn / tf, t.filtered / tf)
+            )
+        return rows
 
     def _daysStudied(self) -> Any:
         lims = []
@@ -636,27 +554,11 @@ group by day order by day)"""
 
     def _ivls(self) -> Tuple[list, int]:
         start, end, chunk = self.get_start_end_chunk()
-        lim = "and grp <= %d" % end if end else ""
-        data = [
-            self.col.db.all(
-                """
-select ivl / :chunk as grp, count() from cards
-where did in %s and queue = 2 %s
-group by grp
-order by grp"""
-                % (self._limit(), lim),
-                chunk=chunk,
-            )
-        ]
+        data = self._graphs()
+        summary = data.interval_summary
+        ivls = [(p.bucket, p.count) for p in data.intervals]
         return (
-            data
-            + list(
-                self.col.db.first(
-                    """
-select count(), avg(ivl), max(ivl) from cards where did in %s and queue = 2"""
-                    % self._limit()
-                )
-            ),
+            [ivls, summary.cards, summary.average_days, summary.longest_days],
             chunk,
         )
 
@@ -736,35 +638,8 @@ select count(), avg(ivl), max(ivl) from cards where did in %s and queue = 2"""
             + "</td></tr></table></center>"
         )
 
-    def _eases(self) -> Any:
-        lims = []
-        lim = self._revlogLimit()
-        if lim:
-            lims.append(lim)
-        days = self._periodDays()
-        if days is not None:
-            lims.append(
-                "id > %d" % ((self.col.sched.dayCutoff - (days * 86400)) * 1000)
-            )
-        if lims:
-            lim = "where " + " and ".join(lims)
-        else:
-            lim = ""
-        if self.col.schedVer() == 1:
-            ease4repl = "3"
-        else:
-            ease4repl = "ease"
-        return self.col.db.all(
-            """
-select (case
-when type in (0,2) then 0
-when lastIvl < 21 then 1
-else 2 end) as thetype,
-(case when type in (0,2) and ease = 4 then %s else ease end), count() from revlog %s
-group by thetype, ease
-order by thetype, ease"""
-            % (ease4repl, lim)
-        )
+    def _eases(self) -> List[Tuple[int, int, int]]:
+        return [(b.kind, b.button, b.count) for b in self._graphs().buttons]
 
     # Hourly retention
     ######################################################################
@@ -836,30 +711,13 @@ order by thetype, ease"""
         txt += _("Hours with less than 30 reviews are not shown.")
         return txt
 
-    def _hourRet(self) -> Any:
-        lim = self._revlogLimit()
-        if lim:
-            lim = " and " + lim
-        if self.col.schedVer() == 1:
-            sd = datetime.datetime.fromtimestamp(self.col.crt)
-            rolloverHour = sd.hour
-        else:
-            rolloverHour = self.col.conf.get("rollover", 4)
-        pd = self._periodDays()
-        if pd:
-            lim += " and id > %d" % ((self.col.sched.dayCutoff - (86400 * pd)) * 1000)
-        return self.col.db.all(
-            """
-select
-23 - ((cast((:cut - id/1000) / 3600.0 as int)) %% 24) as hour,
-sum(case when ease = 1 then 0 else 1 end) /
-cast(count() as float) * 100,
-count()
-from revlog where type in (0,1,2) %s
-group by hour having count() > 30 order by hour"""
-            % lim,
-            cut=self.col.sched.dayCutoff - (rolloverHour * 3600),
-        )
+    def _hourRet(self) -> List[Tuple[int, float, int]]:
+        # hours with few answers would skew the graph
+        return [
+            (h.hour, h.correct / float(h.answers) * 100, h.answers)
+            for h in self._graphs().hours
+            if h.answers > 30
+        ]
 
     # Cards
     ######################################################################
@@ -923,15 +781,16 @@ when you answer "good" on a review."""
     def _lineTbl(self, i) -> str:
         return "<table width=400>" + "".join(i) + "</table>"
 
-    def _factors(self) -> Any:
-        return self.col.db.first(
-            """
-select
-min(factor) / 10.0,
-avg(factor) / 10.0,
-max(factor) / 10.0
-from cards where did in %s and queue = 2"""
-            % self._limit()
+    def _factors(self) -> Tuple[Optional[float], ...]:
+        factors = self._graphs().ease_factors
+        if not factors:
+            return (None, None, None)
+        total = sum(f.count for f in factors)
+        avg = sum(f.ease_factor * f.count for f in factors) / float(total)
+        return (
+            factors[0].ease_factor / 10.0,
+            avg / 10.0,
+            factors[-1].ease_factor / 10.0,
         )
 
     def _cards(self) -> Any:
@@ -1073,6 +932,27 @@ $(function () {
             conf=json.dumps(conf),
         )
 
+    def _graphs(self) -> rsbackend.Graphs:
+        "The data of the graphs, gathered by the backend for the current period."
+        if self._graphData and self._graphData[0] == self.type:
+            return self._graphData[1]
+        start, end, chunk = self.get_start_end_chunk()
+        if self.col.schedVer() == 1:
+            rolloverHour = datetime.datetime.fromtimestamp(self.col.crt).hour
+        else:
+            rolloverHour = self.col.conf.get("rollover", 4)
+        data = self.col.backend.graphs(
+            deck_ids=[] if self.wholeCollection else self.col.decks.active(),
+            today=self.col.sched.today,
+            next_day_at=self.col.sched.dayCutoff,
+            rollover_hour=rolloverHour,
+            bucket_days=chunk,
+            buckets=end,
+            v1_learning_buttons=self.col.schedVer() == 1,
+        )
+        self._graphData = (self.type, data)
+        return data
+
     def _limit(self) -> Any:
         if self.wholeCollection:
             return ids2str([d["id"] for d in self.col.decks.all()])
@@ -7,6 +7,7 @@ import os
 import re
 from typing import Any, Dict, Optional, Tuple
 
+import anki.lang
 from anki.collection import _Collection
 from anki.consts import *
 from anki.db import DB
@@ -19,20 +20,29 @@ from anki.stdmodels import (
     addForwardOptionalReverse,
     addForwardReverse,
 )
-from anki.utils import intTime, isWin
+from anki.utils import intTime
 
 
 class ServerData:
     minutes_west: Optional[int] = None
 
 
+def backend_for_collection(path: str) -> RustBackend:
+    """A backend for the collection at PATH, which doesn't need to be open.
+    Used for operations on closed collections, like restoring a backup."""
+    media_dir = re.sub(r"(?i)\.(anki2)$", ".media", path)
+    backend = RustBackend(path, media_dir, media_dir + ".db2")
+    backend.set_languages([anki.lang.getLang()], anki.lang.ftlFolder)
+    return backend
+
+
 def Collection(
     path: str, lock: bool = True, server: Optional[ServerData] = None, log: bool = False
 ) -> _Collection:
     "Open a new or existing collection. Path must be unicode."
-    backend = RustBackend(path)
     assert path.endswith(".anki2")
     path = os.path.abspath(path)
+    backend = backend_for_collection(path)
     create = not os.path.exists(path)
     if create:
         base = os.path.basename(path)
@@ -47,8 +57,6 @@ def Collection(
         ver = _upgradeSchema(db)
     db.execute("pragma temp_store = memory")
     db.execute("pragma cache_size = 10000")
-    if not isWin:
-        db.execute("pragma journal_mode = wal")
     db.setAutocommit(False)
     # add db to col and do any remaining upgrades
     col = _Collection(db, backend=backend, server=server, log=log)
@@ -64,12 +72,16 @@ def Collection(
         addForwardReverse(col)
         addBasicModel(col)
         col.save()
-    if lock:
-        try:
+    # the backend switches to WAL mode and locks the file, which requires
+    # there to be no open transaction
+    col.db.commit()
+    try:
+        col.recovered = backend.open_collection()
+        if lock:
             col.lock()
-        except:
-            col.db.close()
-            raise
+    except:
+        col.db.close()
+        raise
     return col
 
 
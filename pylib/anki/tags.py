@@ -13,10 +13,11 @@ from __future__ import annotations
 
 import json
 import re
-from typing import Callable, Dict, List, Tuple
+from typing import Dict, List, Tuple
 
 import anki  # pylint: disable=unused-import
 from anki import hooks
+from anki.rsbackend import TagTreeNode
 from anki.utils import ids2str, intTime
 
 
@@ -91,51 +92,82 @@ class TagManager:
         res = self.col.db.list(query)
         return list(set(self.split(" ".join(res))))
 
+    # Hierarchy
+    #############################################################
+
+    # Tags containing :: are shown as a tree, like decks.
+
+    def tree(self) -> List[TagTreeNode]:
+        "Registered tags as a tree, with the number of notes using each."
+        self.col.save()
+        self.col.db.commit()
+        try:
+            return self.col.backend.tag_tree()
+        finally:
+            self.col.lock()
+
+    def rename(self, old: str, new: str) -> int:
+        """Rename OLD and its children to NEW, merging them with any
+        existing tags. Returns the number of notes changed."""
+        self.col.save()
+        self.col.db.commit()
+        try:
+            changed = self.col.backend.rename_tag(old, new, self.col.usn(), intTime())
+        finally:
+            self.col.lock()
+        # the backend has updated the registry
+        self.load(self.col.db.scalar("select tags from col"))
+        self.col.markBackendOp()
+        return changed
+
+    def usage(self) -> List[Tuple[str, int]]:
+        """Each registered tag with the number of notes using it, sorted by
+        name. Notes with a child tag are not counted."""
+        self.col.save()
+        self.col.db.commit()
+        try:
+            usage = self.col.backend.tag_usage()
+        finally:
+            self.col.lock()
+        return [(t.name, t.note_count) for t in usage]
+
+    def clearUnused(self) -> List[str]:
+        """Remove tags that no notes use, and register any that are missing.
+        Returns the removed tags."""
+        self.col.save()
+        self.col.db.commit()
+        try:
+            removed = self.col.backend.clear_unused_tags(self.col.usn())
+        finally:
+            self.col.lock()
+        self.load(self.col.db.scalar("select tags from col"))
+        self.col.setMod()
+        return removed
+
     # Bulk addition/removal from notes
     #############################################################
 
     def bulkAdd(self, ids, tags, add=True) -> None:
-        "Add tags in bulk. TAGS is space-separated."
-        newTags = self.split(tags)
-        if not newTags:
-            return
-        # cache tag names
-        if add:
-            self.register(newTags)
-        # find notes missing the tags
-        fn: Callable[[str, str], str]
-        if add:
-            l = "tags not "
-            fn = self.addToStr
-        else:
-            l = "tags "
-            fn = self.remFromStr
-        lim = " or ".join([l + "like :_%d" % c for c, t in enumerate(newTags)])
-        res = self.col.db.all(
-            "select id, tags from notes where id in %s and (%s)" % (ids2str(ids), lim),
-            **dict(
-                [
-                    ("_%d" % x, "%% %s %%" % y.replace("*", "%"))
-                    for x, y in enumerate(newTags)
-                ]
-            ),
-        )
-        # update tags
-        nids = []
-
-        def fix(row):
-            nids.append(row[0])
-            return {
-                "id": row[0],
-                "t": fn(tags, row[1]),
-                "n": intTime(),
-                "u": self.col.usn(),
-            }
-
-        self.col.db.executemany(
-            "update notes set tags=:t,mod=:n,usn=:u where id = :id",
-            [fix(row) for row in res],
-        )
+        "Add tags in bulk. TAGS is space-separated. The change can be undone."
+        before = set(self.tags)
+        self.col.save()
+        self.col.db.commit()
+        try:
+            if add:
+                self.col.backend.add_note_tags(
+                    list(ids), tags, self.col.usn(), intTime()
+                )
+            else:
+                self.col.backend.remove_note_tags(
+                    list(ids), tags, self.col.usn(), intTime()
+                )
+        finally:
+            self.col.lock()
+        # the backend registers any new tags
+        self.load(self.col.db.scalar("select tags from col"))
+        for tag in set(self.tags) - before:
+            hooks.tag_added(tag)
+        self.col.markBackendOp()
 
     def bulkRem(self, ids, tags) -> None:
         self.bulkAdd(ids, tags, False)
@@ -197,11 +229,3 @@ class TagManager:
     def inList(self, tag, tags) -> bool:
         "True if TAG is in TAGS. Ignore case."
         return tag.lower() in [t.lower() for t in tags]
-
-    # Sync handling
-    ##########################################################################
-
-    def beforeUpload(self) -> None:
-        for k in list(self.tags.keys()):
-            self.tags[k] = 0
-        self.save()
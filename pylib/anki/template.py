@@ -33,8 +33,10 @@ from typing import Any, Dict, List, Optional, Tuple
 
 import anki
 from anki import hooks
+from anki.lang import _
+from anki.consts import MODEL_STD
 from anki.models import NoteType
-from anki.rsbackend import TemplateReplacementList
+from anki.rsbackend import CardContext, TemplateReplacementList
 
 QAData = Tuple[
     # Card ID this QA comes from. Corresponds to 'cid' column.
@@ -82,6 +84,8 @@ class TemplateRenderContext:
         return self._col
 
     def fields(self) -> Dict[str, str]:
+        """The note's fields. Special fields like Tags and Deck are not
+        included, as they are filled in by the backend."""
         return self._fields
 
     def card_id(self) -> int:
@@ -121,21 +125,62 @@ class TemplateRenderContext:
 
         return self._note_type
 
+    def template(self) -> Dict[str, Any]:
+        "The card template being rendered."
+        note_type = self.note_type()
+        if note_type["type"] == MODEL_STD:
+            return note_type["tmpls"][self.card_ord()]
+        else:
+            return note_type["tmpls"][0]
+
+    def card_context(self) -> CardContext:
+        "Details used to fill in special fields like {{Tags}} and {{Deck}}."
+        return CardContext(
+            card_ord=self.card_ord(),
+            tags=self._qadata[5],
+            notetype_name=self.note_type()["name"],
+            deck_name=self.col().decks.name(self.deck_id()),
+            template_name=self.template()["name"],
+            card_flags=self._qadata[7],
+            markdown_fields=[
+                f["name"] for f in self.note_type()["flds"] if f.get("markdown")
+            ],
+        )
+
 
 def render_card(
-    col: anki.storage._Collection, qfmt: str, afmt: str, ctx: TemplateRenderContext
+    col: anki.storage._Collection,
+    qfmt: str,
+    afmt: str,
+    ctx: TemplateRenderContext,
+    preview: bool = False,
 ) -> Tuple[str, str]:
     """Renders the provided templates, returning rendered q & a text.
 
-    Will raise if the template is invalid."""
-    (qnodes, anodes) = col.backend.render_card(qfmt, afmt, ctx.fields(), ctx.card_ord())
+    If a side's template is invalid, an error message is returned for
+    that side instead. If preview is true, empty fields are shown as
+    placeholders."""
+    out = col.backend.render_card(
+        qfmt, afmt, ctx.fields(), ctx.card_context(), preview=preview
+    )
 
-    qtext = apply_custom_filters(qnodes, ctx, front_side=None)
-    atext = apply_custom_filters(anodes, ctx, front_side=qtext)
+    if out.question_error:
+        qtext = template_error(out.question_error)
+    else:
+        qtext = apply_custom_filters(out.question_nodes, ctx, front_side=None)
+
+    if out.answer_error:
+        atext = template_error(out.answer_error)
+    else:
+        atext = apply_custom_filters(out.answer_nodes, ctx, front_side=qtext)
 
     return qtext, atext
 
 
+def template_error(msg: str) -> str:
+    return _("Card template has a problem:") + f"<br>{msg}"
+
+
 def apply_custom_filters(
     rendered: TemplateReplacementList,
     ctx: TemplateRenderContext,
@@ -156,7 +201,9 @@ def apply_custom_filters(
                 node.current_text = ctx.col().backend.strip_av_tags(front_side)
 
             field_text = node.current_text
-            for filter_name in node.filters:
+            filters = node.filters
+            while filters:
+                filter_name = filters[0]
                 field_text = hooks.field_filter(
                     field_text, node.field_name, filter_name, ctx
                 )
@@ -169,6 +216,17 @@ def apply_custom_filters(
                     node.field_name,
                     "",
                 )
+                filters = filters[1:]
+                if filters:
+                    # built-in filters that follow are handled by the backend,
+                    # up to the next custom filter
+                    field_text, filters = ctx.col().backend.apply_filters(
+                        field_text,
+                        filters,
+                        node.field_name,
+                        ctx.card_ord(),
+                        question_side=front_side is None,
+                    )
 
             res += field_text
     return res
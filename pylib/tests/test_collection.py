@@ -85,6 +85,35 @@ def test_noteAddDelete():
     assert f2.dupeOrEmpty()
 
 
+def test_updateNote():
+    deck = getEmptyCol()
+    m = deck.models.current()
+    t = deck.models.newTemplate("Reverse")
+    t["qfmt"] = "{{Back}}"
+    t["afmt"] = "{{Front}}"
+    deck.models.addTemplate(m, t)
+    deck.models.save(m)
+    f = deck.newNote()
+    f["Front"] = "one"
+    f.tags = ["b", "A", "a"]
+    assert deck.addNote(f) == 1
+    # tags are tidied and registered
+    assert f.tags == ["A", "b"]
+    assert "A" in deck.tags.all()
+    assert deck.undoName() == "Add Note"
+    # filling in the back adds the reverse card
+    f["Back"] = "two"
+    assert deck.updateNote(f) == []
+    assert deck.cardCount() == 2
+    # and emptying it removes it again, as it's new
+    reverse = [c.id for c in f.cards() if c.ord == 1]
+    f["Back"] = ""
+    assert deck.updateNote(f) == reverse
+    assert deck.cardCount() == 1
+    deck.undo()
+    assert deck.cardCount() == 2
+
+
 def test_fieldChecksum():
     deck = getEmptyCol()
     f = deck.newNote()
@@ -1,6 +1,7 @@
 # coding: utf-8
+import copy
 
-from anki.errors import DeckRenameError
+from anki.errors import DeckConfError, DeckRenameError
 from tests.shared import assertException, getEmptyCol
 
 
@@ -166,6 +167,30 @@ def test_renameForDragAndDrop():
     assert deckNames() == ["Chinese", "HSK", "Languages"]
 
 
+def test_conf():
+    d = getEmptyCol()
+    parent = d.decks.get(d.decks.id("parent"))
+    child = d.decks.get(d.decks.id("parent::child"))
+    confId = d.decks.confId("new conf")
+    conf = copy.deepcopy(d.decks.getConf(confId))
+    # invalid steps are rejected, leaving the saved conf as it was
+    conf["new"]["delays"] = []
+    assertException(DeckConfError, lambda: d.decks.saveConf(conf))
+    conf["new"]["delays"] = [1, -1]
+    assertException(DeckConfError, lambda: d.decks.saveConf(conf))
+    assert d.decks.getConf(confId)["new"]["delays"] == [1, 10]
+    conf["new"]["delays"] = [1.5]
+    d.decks.saveConf(conf)
+    assert d.decks.getConf(confId)["new"]["delays"] == [1.5]
+    # the deck and its children can be set at once
+    assert d.decks.setConfForChildren(parent["id"], confId) == 2
+    assert child["conf"] == confId
+    # removing the conf returns its decks to the default
+    d.decks.remConf(confId)
+    assert parent["conf"] == 1
+    assert str(confId) not in d.decks.dconf
+
+
 def test_check():
     d = getEmptyCol()
 
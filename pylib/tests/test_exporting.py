@@ -153,6 +153,11 @@ def test_export_textnote():
     e.includeHTML = False
     e.exportInto(f)
     assert open(f).readline() == "foo\tbar\n"
+    # tables keep their layout, and are quoted
+    assert (
+        e.processText("<table><tr><td>a</td><td>b</td></tr><tr><td>c</td></tr></table>")
+        == '"a\tb\nc"'
+    )
 
 
 def test_exporters():
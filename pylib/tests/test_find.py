@@ -244,6 +244,23 @@ def test_findCards():
         deck.findCards("flag:12")
 
 
+def test_findPage():
+    deck = getEmptyCol()
+    for word in ("c", "a", "b"):
+        f = deck.newNote()
+        f["Front"] = word
+        deck.addNote(f)
+    deck.conf["sortType"] = "noteFld"
+    deck.conf["sortBackwards"] = False
+    cids = deck.findCards("", order=True)
+    assert deck.findCardsPage("", 1, 1) == (cids[1:2], 3)
+    assert deck.findCardsPage("", 1, 0) == (cids[1:], 3)
+    deck.conf["sortBackwards"] = True
+    assert deck.findCardsPage("", 0, 2) == (list(reversed(cids))[:2], 3)
+    nids = deck.findNotesPage("front:a or front:b", 0, 0)[0]
+    assert [deck.getNote(nid)["Front"] for nid in nids] == ["b", "a"]
+
+
 def test_findReplace():
     deck = getEmptyCol()
     f = deck.newNote()
@@ -276,6 +293,16 @@ def test_findReplace():
     assert deck.findReplace(nids, "B.r", "reg", regex=True) == 1
     f.load()
     assert f["Back"] == "reg"
+    # tags are only changed when requested
+    f.tags = ["reg"]
+    f.flush()
+    assert deck.findReplace([f.id], "reg", "(r)", regex=True) == 1
+    f.load()
+    assert f["Back"] == "(r)"
+    assert f.tags == ["reg"]
+    assert deck.findReplace([f.id], "(re)g", "${1}x", regex=True, tags=True) == 1
+    f.load()
+    assert f.tags == ["rex"]
 
 
 def test_findDupes():
@@ -78,6 +78,28 @@ def test_deckIntegration():
     assert ret[1] == ["foo.jpg"]
 
 
+def test_normalize_refs():
+    d = getEmptyCol()
+    # keep the decomposed form in the note
+    d.conf["normalize_note_text"] = False
+    with open(os.path.join(d.media.dir(), "e\u0301.jpg"), "w") as f:
+        f.write("test")
+    f = d.newNote()
+    f["Front"] = "<img src='e\u0301.jpg'>"
+    d.addNote(f)
+    # a dry run reports the changes without making them
+    (missing, unused, warnings) = d.media.check(dry_run=True)
+    assert not missing and not unused
+    assert len(warnings) == 2
+    assert os.listdir(d.media.dir()) == ["e\u0301.jpg"]
+    # the file is renamed, and the reference updated to match
+    (missing, unused, warnings) = d.media.check()
+    assert not missing and not unused
+    assert os.listdir(d.media.dir()) == ["\u00e9.jpg"]
+    f.load()
+    assert f["Front"] == "<img src='\u00e9.jpg'>"
+
+
 def test_changes():
     d = getEmptyCol()
 
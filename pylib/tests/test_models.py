@@ -95,8 +95,10 @@ def test_templates():
     # first card should have first ord
     assert c.ord == 0
     assert c2.ord == 1
-    # switch templates
+    # switch templates, which doesn't require a full sync
+    scm = d.scm
     d.models.moveTemplate(m, c.template(), 1)
+    assert d.scm == scm
     c.load()
     c2.load()
     assert c.ord == 1
@@ -13,3 +13,27 @@ def test_deferred_frontside():
     d.addNote(f)
 
     assert "xxtest" in f.cards()[0].a()
+
+
+def test_builtin_after_custom_filter():
+    from anki import hooks
+
+    def upper(txt, field_name, filter_name, ctx):
+        if filter_name == "upper":
+            return txt.upper()
+        return txt
+
+    hooks.field_filter.append(upper)
+    try:
+        d = getEmptyCol()
+        m = d.models.current()
+        m["tmpls"][0]["qfmt"] = "{{text:upper:Front}}"
+        d.models.save(m)
+
+        f = d.newNote()
+        f["Front"] = "<b>ab</b>"
+        d.addNote(f)
+
+        assert f.cards()[0].q().endswith("AB")
+    finally:
+        hooks.field_filter.remove(upper)
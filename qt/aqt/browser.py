@@ -5,7 +5,6 @@
 import html
 import json
 import re
-import sre_constants
 import time
 import unicodedata
 from operator import itemgetter
@@ -17,15 +16,16 @@ from anki import hooks
 from anki.cards import Card
 from anki.collection import _Collection
 from anki.consts import *
+from anki.errors import AnkiError
 from anki.lang import _, ngettext
 from anki.models import NoteType
 from anki.notes import Note
+from anki.rsbackend import BackendException, BrowserCell
 from anki.utils import (
     bodyClass,
     fmtTimeSpan,
     htmlToTextLine,
     ids2str,
-    intTime,
     isMac,
     isWin,
 )
@@ -60,6 +60,9 @@ from aqt.webview import AnkiWebView
 # Data model
 ##########################################################################
 
+# the number of rows fetched from the backend at once
+ROW_BATCH_SIZE = 100
+
 
 class DataModel(QAbstractTableModel):
     def __init__(self, browser):
@@ -72,6 +75,7 @@ class DataModel(QAbstractTableModel):
         )
         self.cards = []
         self.cardObjs = {}
+        self.rows = {}
 
     def getCard(self, index):
         id = self.cards[index.row()]
@@ -79,9 +83,26 @@ class DataModel(QAbstractTableModel):
             self.cardObjs[id] = self.col.getCard(id)
         return self.cardObjs[id]
 
+    def getRow(self, index):
+        "The backend's data for the row, or None if the card no longer exists."
+        id = self.cards[index.row()]
+        if id not in self.rows:
+            # fetch the rows around this one in a single call
+            start = max(0, index.row() - ROW_BATCH_SIZE // 2)
+            ids = [
+                cid
+                for cid in self.cards[start : start + ROW_BATCH_SIZE]
+                if cid not in self.rows
+            ]
+            for row in self.col.browserRows(ids, self.activeCols):
+                self.rows[row.card_id] = row
+        return self.rows.get(id)
+
     def refreshNote(self, note):
         refresh = False
         for c in note.cards():
+            if self.rows.pop(c.id, None) is not None:
+                refresh = True
             if c.id in self.cardObjs:
                 del self.cardObjs[c.id]
                 refresh = True
@@ -107,14 +128,12 @@ class DataModel(QAbstractTableModel):
         if role == Qt.FontRole:
             if self.activeCols[index.column()] not in ("question", "answer", "noteFld"):
                 return
-            row = index.row()
-            c = self.getCard(index)
-            t = c.template()
-            if not t.get("bfont"):
+            row = self.getRow(index)
+            if row is None or not row.font_family:
                 return
             f = QFont()
-            f.setFamily(t.get("bfont", "arial"))
-            f.setPixelSize(t.get("bsize", 12))
+            f.setFamily(row.font_family)
+            f.setPixelSize(row.font_size)
             return f
 
         elif role == Qt.TextAlignmentRole:
@@ -163,20 +182,19 @@ class DataModel(QAbstractTableModel):
         # the db progress handler may cause a refresh, so we need to zero out
         # old data first
         self.cards = []
-        invalid = False
+        error = None
         try:
             self.cards = self.col.findCards(txt, order=True)
-        except Exception as e:
-            if str(e) == "invalidSearch":
-                self.cards = []
-                invalid = True
-            else:
+        except AnkiError as e:
+            if e.type != "invalidSearch":
                 raise
+            self.cards = []
+            error = e
         # print "fetch cards in %dms" % ((time.time() - t)*1000)
         self.endReset()
 
-        if invalid:
-            showWarning(_("Invalid search - please check for typing mistakes."))
+        if error:
+            self.browser.onInvalidSearch(error)
 
     def reset(self):
         self.beginReset()
@@ -189,6 +207,7 @@ class DataModel(QAbstractTableModel):
         self.saveSelection()
         self.beginResetModel()
         self.cardObjs = {}
+        self.rows = {}
 
     def endReset(self):
         t = time.time()
@@ -264,64 +283,38 @@ class DataModel(QAbstractTableModel):
         return self.activeCols[column]
 
     def columnData(self, index):
-        row = index.row()
         col = index.column()
         type = self.columnType(col)
+        row = self.getRow(index)
+        if row is None:
+            return
+        cell = row.cells[col]
+        kind = cell.WhichOneof("value")
+        if kind == "text":
+            return cell.text
+        elif kind == "label":
+            t = self.cellLabel(cell.label)
+            if type == "cardDue" and row.queue < 0:
+                t = "(" + t + ")"
+            return t
+        elif kind == "interval_days":
+            return fmtTimeSpan(cell.interval_days * 86400)
+        # the remaining columns need the card to be rendered
         c = self.getCard(index)
         if type == "question":
             return self.question(c)
         elif type == "answer":
             return self.answer(c)
-        elif type == "noteFld":
-            f = c.note()
-            return htmlToTextLine(f.fields[self.col.models.sortIdx(f.model())])
-        elif type == "template":
-            t = c.template()["name"]
-            if c.model()["type"] == MODEL_CLOZE:
-                t += " %d" % (c.ord + 1)
-            return t
-        elif type == "cardDue":
-            # catch invalid dates
-            try:
-                t = self.nextDue(c, index)
-            except:
-                t = ""
-            if c.queue < 0:
-                t = "(" + t + ")"
-            return t
-        elif type == "noteCrt":
-            return time.strftime("%Y-%m-%d", time.localtime(c.note().id / 1000))
-        elif type == "noteMod":
-            return time.strftime("%Y-%m-%d", time.localtime(c.note().mod))
-        elif type == "cardMod":
-            return time.strftime("%Y-%m-%d", time.localtime(c.mod))
-        elif type == "cardReps":
-            return str(c.reps)
-        elif type == "cardLapses":
-            return str(c.lapses)
-        elif type == "noteTags":
-            return " ".join(c.note().tags)
-        elif type == "note":
-            return c.model()["name"]
-        elif type == "cardIvl":
-            if c.type == 0:
-                return _("(new)")
-            elif c.type == 1:
-                return _("(learning)")
-            return fmtTimeSpan(c.ivl * 86400)
-        elif type == "cardEase":
-            if c.type == 0:
-                return _("(new)")
-            return "%d%%" % (c.factor / 10)
-        elif type == "deck":
-            if c.odid:
-                # in a cram deck
-                return "%s (%s)" % (
-                    self.browser.mw.col.decks.name(c.did),
-                    self.browser.mw.col.decks.name(c.odid),
-                )
-            # normal deck
-            return self.browser.mw.col.decks.name(c.did)
+
+    def cellLabel(self, label):
+        if label == BrowserCell.NEW:
+            return _("(new)")
+        elif label == BrowserCell.LEARNING:
+            return _("(learning)")
+        elif label == BrowserCell.FILTERED:
+            return _("(filtered)")
+        else:
+            return _("[no deck]")
 
     def question(self, c):
         return htmlToTextLine(c.q(browser=True))
@@ -338,29 +331,14 @@ class DataModel(QAbstractTableModel):
             return a[len(q) :].strip()
         return a
 
-    def nextDue(self, c, index):
-        if c.odid:
-            return _("(filtered)")
-        elif c.queue == 1:
-            date = c.due
-        elif c.queue == 0 or c.type == 0:
-            return str(c.due)
-        elif c.queue in (2, 3) or (c.type == 2 and c.queue < 0):
-            date = time.time() + ((c.due - self.col.sched.today) * 86400)
-        else:
-            return ""
-        return time.strftime("%Y-%m-%d", time.localtime(date))
-
     def isRTL(self, index):
         col = index.column()
         type = self.columnType(col)
         if type != "noteFld":
             return False
 
-        row = index.row()
-        c = self.getCard(index)
-        nt = c.note().model()
-        return nt["flds"][self.col.models.sortIdx(nt)]["rtl"]
+        row = self.getRow(index)
+        return row is not None and row.rtl
 
 
 # Line painter
@@ -386,23 +364,26 @@ class StatusDelegate(QItemDelegate):
     def paint(self, painter, option, index):
         self.browser.mw.progress.blockUpdates = True
         try:
-            c = self.model.getCard(index)
+            row = self.model.getRow(index)
         except:
             # in the the middle of a reset; return nothing so this row is not
             # rendered until we have a chance to reset the model
             return
         finally:
             self.browser.mw.progress.blockUpdates = True
+        if row is None:
+            # the card has been deleted
+            return
 
         if self.model.isRTL(index):
             option.direction = Qt.RightToLeft
 
         col = None
-        if c.userFlag() > 0:
-            col = flagColours[c.userFlag()]
-        elif c.note().hasTag("Marked"):
+        if row.flag > 0:
+            col = flagColours[row.flag]
+        elif row.marked:
             col = COLOUR_MARKED
-        elif c.queue == -1:
+        elif row.queue == -1:
             col = COLOUR_SUSPENDED
         if col:
             brush = QBrush(QColor(col))
@@ -791,6 +772,18 @@ class Browser(QMainWindow):
             # no row change will fire
             self._onRowChanged(None, None)
 
+    def onInvalidSearch(self, err: AnkiError) -> None:
+        msg = _("Invalid search - please check for typing mistakes.")
+        if "message" in err.data:
+            msg += "\n\n" + err.data["message"]
+        showWarning(msg)
+        # highlight the problem, if the search is still in the search box
+        line = self.form.searchEdit.lineEdit()
+        start, end = err.data.get("start", 0), err.data.get("end", 0)
+        if end > start and line.text() == self._lastSearchTxt:
+            line.setSelection(start, end - start)
+            self.form.searchEdit.setFocus()
+
     def updateTitle(self):
         selected = len(self.form.tableView.selectionModel().selectedRows())
         cur = len(self.model.cards)
@@ -1097,8 +1090,7 @@ by clicking on one on the left."""
 
     def _favTree(self, root) -> None:
         assert self.col
-        saved = self.col.conf.get("savedFilters", {})
-        for name, filt in sorted(saved.items()):
+        for name, filt in self.col.savedSearches().items():
             item = SidebarItem(
                 name,
                 ":/icons/heart.svg",
@@ -1108,11 +1100,23 @@ by clicking on one on the left."""
 
     def _userTagTree(self, root) -> None:
         assert self.col
-        for t in sorted(self.col.tags.all(), key=lambda t: t.lower()):
-            item = SidebarItem(
-                t, ":/icons/tag.svg", lambda t=t: self.setFilter("tag", t)  # type: ignore
-            )
-            root.addChild(item)
+
+        def fillTags(root, nodes):
+            for node in nodes:
+                # parents also match their children
+                search = node.full_name + "::*" if node.children else node.full_name
+                item = SidebarItem(
+                    node.name,
+                    ":/icons/tag.svg",
+                    lambda s=search: self.setFilter("tag", s),  # type: ignore
+                )
+                item.tooltip = (
+                    ngettext("%d note", "%d notes", node.note_count) % node.note_count
+                )
+                root.addChild(item)
+                fillTags(item, node.children)
+
+        fillTags(root, self.col.tags.tree())
 
     def _decksTree(self, root) -> None:
         assert self.col
@@ -1322,23 +1326,20 @@ by clicking on one on the left."""
 
     def _savedSearches(self):
         ml = MenuList()
-        # make sure exists
-        if "savedFilters" not in self.col.conf:
-            self.col.conf["savedFilters"] = {}
-
         ml.addSeparator()
 
         if self._currentFilterIsSaved():
+            ml.addItem(_("Rename Current Filter..."), self._onRenameFilter)
             ml.addItem(_("Remove Current Filter..."), self._onRemoveFilter)
         else:
             ml.addItem(_("Save Current Filter..."), self._onSaveFilter)
 
-        saved = self.col.conf["savedFilters"]
+        saved = self.col.savedSearches()
         if not saved:
             return ml
 
         ml.addSeparator()
-        for name, filt in sorted(saved.items()):
+        for name, filt in saved.items():
             ml.addItem(name, self._filterFunc(filt))
 
         return ml
@@ -1348,22 +1349,32 @@ by clicking on one on the left."""
         if not name:
             return
         filt = self.form.searchEdit.lineEdit().text()
-        self.col.conf["savedFilters"][name] = filt
-        self.col.setMod()
+        self.col.saveSearch(name, filt)
+        self.maybeRefreshSidebar()
+
+    def _onRenameFilter(self):
+        name = self._currentFilterIsSaved()
+        newName = getOnlyText(_("New name:"), default=name)
+        if not newName or newName == name:
+            return
+        try:
+            self.col.renameSavedSearch(name, newName)
+        except BackendException as e:
+            showWarning(str(e))
+            return
         self.maybeRefreshSidebar()
 
     def _onRemoveFilter(self):
         name = self._currentFilterIsSaved()
         if not askUser(_("Remove %s from your saved searches?") % name):
             return
-        del self.col.conf["savedFilters"][name]
-        self.col.setMod()
+        self.col.removeSavedSearch(name)
         self.maybeRefreshSidebar()
 
     # returns name if found
     def _currentFilterIsSaved(self):
         filt = self.form.searchEdit.lineEdit().text()
-        for k, v in self.col.conf["savedFilters"].items():
+        for k, v in self.col.savedSearches().items():
             if filt == v:
                 return k
         return None
@@ -1414,11 +1425,7 @@ border: 1px solid #000; padding: 3px; '>%s</div>"""
         return rep, cs
 
     def _revlogData(self, cs):
-        entries = self.mw.col.db.all(
-            "select id/1000.0, ease, ivl, factor, time/1000.0, type "
-            "from revlog where cid = ?",
-            self.card.id,
-        )
+        entries = cs.stats.revlog
         if not entries:
             return ""
         s = "<table width=100%%><tr><th align=left>%s</th>" % _("Date")
@@ -1430,7 +1437,15 @@ border: 1px solid #000; padding: 3px; '>%s</div>"""
             _("Time"),
         )
         cnt = 0
-        for (date, ease, ivl, factor, taken, type) in reversed(entries):
+        for entry in entries:
+            (date, ease, ivl, factor, taken, type) = (
+                entry.time_secs,
+                entry.ease,
+                entry.interval,
+                entry.ease_factor,
+                entry.taken_secs,
+                entry.review_kind,
+            )
             cnt += 1
             s += "<tr><td>%s</td>" % time.strftime(
                 _("<b>%Y-%m-%d</b> @ %H:%M"), time.localtime(date)
@@ -1814,22 +1829,7 @@ where id in %s"""
             showWarning(_("Cards can't be manually moved into a filtered deck."))
             return
         self.model.beginReset()
-        self.mw.checkpoint(_("Change Deck"))
-        mod = intTime()
-        usn = self.col.usn()
-        # normal cards
-        scids = ids2str(cids)
-        # remove any cards from filtered deck first
-        self.col.sched.remFromDyn(cids)
-        # then move into new deck
-        self.col.db.execute(
-            """
-update cards set usn=?, mod=?, did=? where id in """
-            + scids,
-            usn,
-            mod,
-            did,
-        )
+        self.col.setDeck(cids, did)
         self.model.endReset()
         self.mw.requireReset()
 
@@ -1850,10 +1850,7 @@ update cards set usn=?, mod=?, did=? where id in """
             return
         if func is None:
             func = self.col.tags.bulkAdd
-        if label is None:
-            label = _("Add Tags")
-        if label:
-            self.mw.checkpoint(label)
+        # the bulk edits record their own undo step
         self.model.beginReset()
         func(self.selectedNotes(), tags)
         self.model.endReset()
@@ -1870,7 +1867,7 @@ update cards set usn=?, mod=?, did=? where id in """
         self.editor.saveNow(self._clearUnusedTags)
 
     def _clearUnusedTags(self):
-        self.col.tags.registerNotes()
+        self.col.tags.clearUnused()
 
     # Suspending
     ######################################################################
@@ -2095,7 +2092,7 @@ update cards set usn=?, mod=?, did=? where id in """
                 field,
                 frm.ignoreCase.isChecked(),
             )
-        except sre_constants.error:
+        except BackendException:
             showInfo(_("Invalid regular expression."), parent=self)
             return
         else:
@@ -2151,11 +2148,12 @@ update cards set usn=?, mod=?, did=? where id in """
     def duplicatesReport(self, web, fname, search, frm):
         self.mw.progress.start()
         res = self.mw.col.findDupes(fname, search)
+        self._dupesQuery = (fname, search)
         if not self._dupesButton:
             self._dupesButton = b = frm.buttonBox.addButton(
                 _("Tag Duplicates"), QDialogButtonBox.ActionRole
             )
-            b.clicked.connect(lambda: self._onTagDupes(res))
+            b.clicked.connect(self._onTagDupes)
         t = "<html><body>"
         groups = len(res)
         notes = sum(len(r[1]) for r in res)
@@ -2177,16 +2175,11 @@ update cards set usn=?, mod=?, did=? where id in """
         web.setHtml(t)
         self.mw.progress.finish()
 
-    def _onTagDupes(self, res):
-        if not res:
-            return
+    def _onTagDupes(self):
+        fname, search = self._dupesQuery
         self.model.beginReset()
         self.mw.checkpoint(_("Tag Duplicates"))
-        nids = set()
-        for s, nidlist in res:
-            nids.update(nidlist)
-        self.col.tags.bulkAdd(nids, _("duplicate"))
-        self.mw.progress.finish()
+        self.col.tagDuplicates(fname, search, _("duplicate"))
         self.model.endReset()
         self.mw.requireReset()
         tooltip(_("Notes tagged."))
@@ -2432,7 +2425,7 @@ Are you sure you want to continue?"""
                 )
             ):
                 return
-        self.browser.mw.checkpoint(_("Change Note Type"))
+        # the change records its own undo step
         b = self.browser
         b.mw.col.modSchema(check=True)
         b.mw.progress.start()
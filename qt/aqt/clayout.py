@@ -42,12 +42,6 @@ class CardLayout(QDialog):
         self.addMode = addMode
         if addMode:
             # save it to DB temporarily
-            self.emptyFields = []
-            for name, val in list(note.items()):
-                if val.strip():
-                    continue
-                self.emptyFields.append(name)
-                note[name] = "(%s)" % name
             note.flush()
         self.removeColons()
         self.setupTopArea()
@@ -338,7 +332,8 @@ Please create a new card type first."""
 
         bodyclass = bodyClass(self.mw.col, c)
 
-        q = ti(mungeQA(self.mw.col, c.q(reload=True)))
+        # when adding, empty fields are shown as placeholders
+        q = ti(mungeQA(self.mw.col, c.q(reload=True, preview=self.addMode)))
         q = gui_hooks.card_will_show(q, c, "clayoutQuestion")
 
         a = ti(mungeQA(self.mw.col, c.a()), type="a")
@@ -597,9 +592,6 @@ Enter deck to place new %s cards in, or leave blank:"""
         self.cancelPreviewTimer()
         av_player.stop_and_clear_queue()
         if self.addMode:
-            # remove the filler fields we added
-            for name in self.emptyFields:
-                self.note[name] = ""
             self.mw.col.db.execute("delete from notes where id = ?", self.note.id)
         self.mm.save(self.model, templates=True)
         self.mw.reset()
@@ -1,10 +1,12 @@
 # Copyright: Ankitects Pty Ltd and contributors
 # -*- coding: utf-8 -*-
 # License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+import copy
 from operator import itemgetter
 
 import aqt
 from anki.consts import NEW_CARDS_RANDOM
+from anki.errors import DeckConfError
 from anki.lang import _, ngettext
 from aqt.qt import *
 from aqt.utils import (
@@ -93,8 +95,12 @@ class DeckConf(QDialog):
     def onConfChange(self, idx):
         if self.ignoreConfChange:
             return
-        if self.conf:
-            self.saveConf()
+        if self.conf and not self.saveConf():
+            # stay on the current group until it's fixed
+            self.ignoreConfChange = True
+            self.form.dconf.setCurrentIndex(self.confList.index(self.conf))
+            self.ignoreConfChange = False
+            return
         conf = self.confList[idx]
         self.deck["conf"] = conf["id"]
         self.loadConf()
@@ -118,7 +124,8 @@ class DeckConf(QDialog):
         if not name:
             return
         # first, save currently entered data to current conf
-        self.saveConf()
+        if not self.saveConf():
+            return
         # then clone the conf
         id = self.mw.col.decks.confId(name, cloneFrom=self.conf)
         # set the deck to the new conf
@@ -147,16 +154,10 @@ class DeckConf(QDialog):
             _("Set all decks below %s to this option group?") % self.deck["name"]
         ):
             return
-        for did in self.childDids:
-            deck = self.mw.col.decks.get(did)
-            if deck["dyn"]:
-                continue
-            deck["conf"] = self.deck["conf"]
-            self.mw.col.decks.save(deck)
-        tooltip(
-            ngettext("%d deck updated.", "%d decks updated.", len(self.childDids))
-            % len(self.childDids)
-        )
+        if not self.saveConf():
+            return
+        cnt = self.mw.col.decks.setConfForChildren(self.deck["id"], self.deck["conf"])
+        tooltip(ngettext("%d deck updated.", "%d decks updated.", cnt) % cnt)
 
     # Loading
     ##################################################
@@ -240,30 +241,27 @@ class DeckConf(QDialog):
     # Saving
     ##################################################
 
-    def updateList(self, conf, key, w, minSize=1):
-        items = str(w.text()).split(" ")
-        ret = []
-        for i in items:
+    def updateList(self, conf, key, w):
+        items = []
+        for i in str(w.text()).split(" "):
             if not i:
                 continue
             try:
                 i = float(i)
-                assert i > 0
                 if i == int(i):
                     i = int(i)
-                ret.append(i)
-            except:
-                # invalid, don't update
-                showWarning(_("Steps must be numbers."))
-                return
-        if len(ret) < minSize:
-            showWarning(_("At least one step is required."))
-            return
-        conf[key] = ret
+            except (ValueError, OverflowError):
+                # rejected when the conf is saved
+                pass
+            items.append(i)
+        conf[key] = items
 
     def saveConf(self):
+        "Save the entered options. False if they're invalid."
+        # the changes are made to a copy, so an invalid conf is never kept
+        conf = copy.deepcopy(self.conf)
         # new
-        c = self.conf["new"]
+        c = conf["new"]
         f = self.form
         self.updateList(c, "delays", f.lrnSteps)
         c["ints"][0] = f.lrnGradInt.value()
@@ -272,14 +270,8 @@ class DeckConf(QDialog):
         c["order"] = f.newOrder.currentIndex()
         c["perDay"] = f.newPerDay.value()
         c["bury"] = f.bury.isChecked()
-        if self._origNewOrder != c["order"]:
-            # order of current deck has changed, so have to resort
-            if c["order"] == NEW_CARDS_RANDOM:
-                self.mw.col.sched.randomizeCards(self.deck["id"])
-            else:
-                self.mw.col.sched.orderCards(self.deck["id"])
         # rev
-        c = self.conf["rev"]
+        c = conf["rev"]
         c["perDay"] = f.revPerDay.value()
         c["ease4"] = f.easyBonus.value() / 100.0
         c["ivlFct"] = f.fi1.value() / 100.0
@@ -287,14 +279,14 @@ class DeckConf(QDialog):
         c["bury"] = f.buryRev.isChecked()
         c["hardFactor"] = f.hardFactor.value() / 100.0
         # lapse
-        c = self.conf["lapse"]
-        self.updateList(c, "delays", f.lapSteps, minSize=0)
+        c = conf["lapse"]
+        self.updateList(c, "delays", f.lapSteps)
         c["mult"] = f.lapMult.value() / 100.0
         c["minInt"] = f.lapMinInt.value()
         c["leechFails"] = f.leechThreshold.value()
         c["leechAction"] = f.leechAction.currentIndex()
         # general
-        c = self.conf
+        c = conf
         c["maxTaken"] = f.maxTaken.value()
         c["timer"] = f.showTimer.isChecked() and 1 or 0
         c["autoplay"] = f.autoplaySounds.isChecked()
@@ -302,12 +294,24 @@ class DeckConf(QDialog):
         # description
         self.deck["desc"] = f.desc.toPlainText()
         self.mw.col.decks.save(self.deck)
-        self.mw.col.decks.save(self.conf)
+        try:
+            self.mw.col.decks.saveConf(conf)
+        except DeckConfError as e:
+            showWarning(e.description)
+            return False
+        if self._origNewOrder != conf["new"]["order"]:
+            # order of current deck has changed, so have to resort
+            if conf["new"]["order"] == NEW_CARDS_RANDOM:
+                self.mw.col.sched.randomizeCards(self.deck["id"])
+            else:
+                self.mw.col.sched.orderCards(self.deck["id"])
+        return True
 
     def reject(self):
         self.accept()
 
     def accept(self):
-        self.saveConf()
+        if not self.saveConf():
+            return
         self.mw.reset()
         QDialog.accept(self)
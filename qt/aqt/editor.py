@@ -23,6 +23,7 @@ from anki.hooks import runFilter
 from anki.httpclient import HttpClient
 from anki.lang import _
 from anki.notes import Note
+from anki.rsbackend import ImageTranscodeConfig
 from anki.utils import checksum, isWin, namedtmp, stripHTMLMedia
 from aqt import AnkiQt, gui_hooks
 from aqt.qt import *
@@ -660,7 +661,9 @@ to a cloze type first, via Edit>Change Note Type."""
     def _addMedia(self, path, canDelete=False):
         "Add to media folder and return local img or sound tag."
         # copy to media folder
-        fname = self.mw.col.media.addFile(path)
+        fname = self.mw.col.media.addFile(
+            path, transcode=self._imageTranscodeConfig()
+        )
         # remove original?
         if canDelete and self.mw.pm.profile["deleteMedia"]:
             if os.path.abspath(fname) != os.path.abspath(path):
@@ -672,7 +675,20 @@ to a cloze type first, via Edit>Change Note Type."""
         return self.fnameToLink(fname)
 
     def _addMediaFromData(self, fname, data):
-        return self.mw.col.media.writeData(fname, data)
+        return self.mw.col.media.writeData(
+            fname, data, transcode=self._imageTranscodeConfig()
+        )
+
+    def _imageTranscodeConfig(self) -> Optional[ImageTranscodeConfig]:
+        "If enabled, large images are converted to WebP when added."
+        prof = self.mw.pm.profile
+        if not prof.get("convertToWebp", False):
+            return None
+        return ImageTranscodeConfig(
+            quality=prof.get("webpQuality", 80),
+            max_dimension=prof.get("webpMaxDimension", 1920),
+            min_size=100 * 1024,
+        )
 
     def onRecSound(self):
         try:
@@ -790,7 +806,9 @@ to a cloze type first, via Edit>Change Note Type."""
         # strip off any query string
         url = re.sub(r"\?.*?$", "", url)
         path = urllib.parse.unquote(url)
-        return self.mw.col.media.writeData(path, filecontents, typeHint=ct)
+        return self.mw.col.media.writeData(
+            path, filecontents, typeHint=ct, transcode=self._imageTranscodeConfig()
+        )
 
     # Paste/drag&drop
     ######################################################################
@@ -798,6 +816,9 @@ to a cloze type first, via Edit>Change Note Type."""
     removeTags = ["script", "iframe", "object", "style"]
 
     def _pastePreFilter(self, html, internal):
+        if not internal:
+            html = self.mw.col.backend.sanitize_html(html)
+
         with warnings.catch_warnings() as w:
             warnings.simplefilter("ignore", UserWarning)
             doc = BeautifulSoup(html, "html.parser")
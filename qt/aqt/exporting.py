@@ -9,6 +9,7 @@ import aqt
 from anki import hooks
 from anki.exporting import exporters
 from anki.lang import _, ngettext
+from anki.rsbackend import BackendException
 from aqt.qt import *
 from aqt.utils import checkInvalidFilename, getSaveFile, showInfo, showWarning, tooltip
 
@@ -126,15 +127,20 @@ class ExportDialog(QDialog):
                 showWarning(_("Couldn't save file: %s") % str(e))
             else:
                 os.unlink(file)
-                exportedMedia = lambda cnt: self.mw.progress.update(
-                    label=ngettext(
-                        "Exported %d media file", "Exported %d media files", cnt
+                if self.isApkg and not self.isVerbatim:
+                    if not self._exportPackage(file):
+                        QDialog.accept(self)
+                        return
+                else:
+                    exportedMedia = lambda cnt: self.mw.progress.update(
+                        label=ngettext(
+                            "Exported %d media file", "Exported %d media files", cnt
+                        )
+                        % cnt
                     )
-                    % cnt
-                )
-                hooks.media_files_did_export.append(exportedMedia)
-                self.exporter.exportInto(file)
-                hooks.media_files_did_export.remove(exportedMedia)
+                    hooks.media_files_did_export.append(exportedMedia)
+                    self.exporter.exportInto(file)
+                    hooks.media_files_did_export.remove(exportedMedia)
                 period = 3000
                 if self.isVerbatim:
                     msg = _("Collection exported.")
@@ -161,3 +167,16 @@ class ExportDialog(QDialog):
             finally:
                 self.mw.progress.finish()
         QDialog.accept(self)
+
+    def _exportPackage(self, file: str) -> bool:
+        "Export a deck package in the backend. False if it was stopped."
+        try:
+            self.mw.progress.run_backend_op(
+                lambda: self.exporter.exportInto(file), self.mw._onBackendProgress
+            )
+        except BackendException as e:
+            if e.args[0].WhichOneof("value") != "interrupted":
+                raise
+            tooltip(_("Export was stopped."))
+            return False
+        return True
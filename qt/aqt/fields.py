@@ -137,6 +137,7 @@ class FieldDialog(QDialog):
         f.sticky.setChecked(fld["sticky"])
         f.sortField.setChecked(self.model["sortf"] == fld["ord"])
         f.rtl.setChecked(fld["rtl"])
+        f.markdown.setChecked(fld.get("markdown", False))
 
     def saveField(self):
         # not initialized yet?
@@ -149,6 +150,7 @@ class FieldDialog(QDialog):
         fld["size"] = f.fontSize.value()
         fld["sticky"] = f.sticky.isChecked()
         fld["rtl"] = f.rtl.isChecked()
+        fld["markdown"] = f.markdown.isChecked()
 
     def reject(self):
         self.saveField()
@@ -2,19 +2,18 @@
 # Copyright: Ankitects Pty Ltd and contributors
 # License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
 
-import json
 import os
 import re
-import shutil
 import traceback
-import unicodedata
 import zipfile
 
 import anki.importing as importing
 import aqt.deckchooser
 import aqt.forms
 import aqt.modelchooser
-from anki.lang import _, ngettext
+from anki.lang import _
+from anki.rsbackend import BackendException
+from anki.storage import backend_for_collection
 from aqt import AnkiQt, gui_hooks
 from aqt.qt import *
 from aqt.utils import (
@@ -45,13 +44,19 @@ class ChangeMap(QDialog):
                 setCurrent = True
                 self.frm.fields.setCurrentRow(n)
             n += 1
-        self.frm.fields.addItem(QListWidgetItem(_("Map to Tags")))
-        self.frm.fields.addItem(QListWidgetItem(_("Ignore field")))
-        if not setCurrent:
-            if current == "_tags":
+        # non-field targets, with None ignoring the column
+        self.specials = [
+            ("_tags", _("Map to Tags")),
+            ("_deck", _("Map to Deck")),
+            ("_guid", _("Map to GUID")),
+            (None, _("Ignore field")),
+        ]
+        for special, label in self.specials:
+            self.frm.fields.addItem(QListWidgetItem(label))
+            if not setCurrent and (current == special or special is None):
+                setCurrent = True
                 self.frm.fields.setCurrentRow(n)
-            else:
-                self.frm.fields.setCurrentRow(n + 1)
+            n += 1
         self.field = None
 
     def getField(self):
@@ -62,10 +67,8 @@ class ChangeMap(QDialog):
         row = self.frm.fields.currentRow()
         if row < len(self.model["flds"]):
             self.field = self.model["flds"][row]["name"]
-        elif row == self.frm.fields.count() - 2:
-            self.field = "_tags"
         else:
-            self.field = None
+            self.field = self.specials[row - len(self.model["flds"])][0]
         QDialog.accept(self)
 
     def reject(self):
@@ -153,10 +156,7 @@ you can enter it here. Use \\t to represent tab."""
     def updateDelimiterButtonText(self):
         if not self.importer.needDelimiter:
             return
-        if self.importer.delimiter:
-            d = self.importer.delimiter
-        else:
-            d = self.importer.dialect.delimiter
+        d = self.importer.delimiter or self.importer.detectedDelimiter
         if d == "\t":
             d = _("Tab")
         elif d == ",":
@@ -250,6 +250,10 @@ you can enter it here. Use \\t to represent tab."""
             self.grid.addWidget(QLabel(text), num, 0)
             if self.mapping[num] == "_tags":
                 text = _("mapped to <b>Tags</b>")
+            elif self.mapping[num] == "_deck":
+                text = _("mapped to <b>Deck</b>")
+            elif self.mapping[num] == "_guid":
+                text = _("mapped to <b>GUID</b>")
             elif self.mapping[num]:
                 text = _("mapped to <b>%s</b>") % self.mapping[num]
             else:
@@ -379,12 +383,20 @@ def importFile(mw, file):
             # we need to ask whether to import/replace
             if not setupApkgImport(mw, importer):
                 return
-        mw.progress.start(immediate=True)
         try:
-            try:
-                importer.run()
-            finally:
-                mw.progress.finish()
+            if importer.__class__.__name__ == "AnkiPackageImporter":
+                mw.progress.run_backend_op(importer.run, mw._onBackendProgress)
+            else:
+                mw.progress.start(immediate=True)
+                try:
+                    importer.run()
+                finally:
+                    mw.progress.finish()
+        except BackendException as e:
+            if e.args[0].WhichOneof("value") != "interrupted":
+                showWarning(str(e))
+            else:
+                tooltip(_("Import was stopped."))
         except zipfile.BadZipfile:
             showWarning(invalidZipMsg())
         except Exception as e:
@@ -456,47 +468,24 @@ def replaceWithApkg(mw, file, backup):
 
 
 def _replaceWithApkg(mw, file, backup):
-    mw.progress.start(immediate=True)
-
-    z = zipfile.ZipFile(file)
-
-    # v2 scheduler?
-    colname = "collection.anki21"
-    try:
-        z.getinfo(colname)
-    except KeyError:
-        colname = "collection.anki2"
-
+    # the backend also copies the media, skipping files that are already
+    # present. because users don't have a backup of media, it's safer to
+    # import new data and rely on them running a media db check to get rid
+    # of any unwanted media
+    backend = backend_for_collection(mw.pm.collectionPath())
     try:
-        with z.open(colname) as source, open(mw.pm.collectionPath(), "wb") as target:
-            shutil.copyfileobj(source, target)
-    except:
-        mw.progress.finish()
-        showWarning(_("The provided file is not a valid .apkg file."))
-        return
-    # because users don't have a backup of media, it's safer to import new
-    # data and rely on them running a media db check to get rid of any
-    # unwanted media. in the future we might also want to deduplicate this
-    # step
-    d = os.path.join(mw.pm.profileFolder(), "collection.media")
-    for n, (cStr, file) in enumerate(
-        json.loads(z.read("media").decode("utf8")).items()
-    ):
-        mw.progress.update(
-            ngettext("Processed %d media file", "Processed %d media files", n) % n
+        mw.progress.run_backend_op(
+            lambda: backend.import_collection_package(file),
+            mw._onBackendProgress,
+            backend=backend,
         )
-        size = z.getinfo(cStr).file_size
-        dest = os.path.join(d, unicodedata.normalize("NFC", file))
-        # if we have a matching file size
-        if os.path.exists(dest) and size == os.stat(dest).st_size:
-            continue
-        data = z.read(cStr)
-        open(dest, "wb").write(data)
-    z.close()
+    except BackendException as e:
+        if e.args[0].WhichOneof("value") == "interrupted":
+            tooltip(_("Import was stopped."))
+        else:
+            showWarning(_("The provided file is not a valid .apkg file."))
     # reload
     if not mw.loadCollection():
-        mw.progress.finish()
         return
     if backup:
         mw.col.modSchema(check=False)
-    mw.progress.finish()
@@ -9,12 +9,9 @@ import platform
 import re
 import signal
 import time
-import zipfile
 from argparse import Namespace
-from threading import Thread
 from typing import Any, Callable, Dict, List, Optional, Sequence, Tuple
 
-from send2trash import send2trash
 
 import aqt
 import aqt.mediasrv
@@ -28,8 +25,16 @@ from anki import hooks
 from anki.collection import _Collection
 from anki.hooks import runHook
 from anki.lang import _, ngettext
+from anki.rsbackend import (
+    BackendException,
+    BackupLimits,
+    DatabaseCheckProgress,
+    OptimizeProgress,
+    Progress,
+    RustBackend,
+)
 from anki.sound import AVTag, SoundOrVideoTag
-from anki.storage import Collection
+from anki.storage import Collection, backend_for_collection
 from anki.utils import devMode, ids2str, intTime, isMac, isWin, splitFields
 from aqt import gui_hooks
 from aqt.addons import DownloadLogEntry, check_and_prompt_for_updates, show_log_to_user
@@ -168,6 +173,9 @@ class AnkiQt(QMainWindow):
 
         self.pendingImport: Optional[str] = None
         self.restoringBackup = False
+        # the backend of the last closed collection, which may still be
+        # writing a backup
+        self._backupBackend: Optional[RustBackend] = None
         # profile not provided on command line?
         if not self.pm.name:
             # if there's a single profile, load it automatically
@@ -308,7 +316,13 @@ Replace your collection with an earlier backup?"""
             )
             return
 
-        self.pendingImport = path
+        try:
+            self._awaitBackup()
+            backend_for_collection(self.pm.collectionPath()).restore_backup(path)
+        except Exception as e:
+            showWarning(str(e))
+            return
+
         self.restoringBackup = True
 
         showInfo(
@@ -386,6 +400,7 @@ close the profile or restart Anki."""
         self.unloadProfile(self.showProfileManager)
 
     def cleanupAndExit(self) -> None:
+        self._awaitBackup()
         self.errorHandler.unload()
         self.mediaServer.shutdown()
         self.app.exit(0)
@@ -441,11 +456,20 @@ Debug info:
         cpath = self.pm.collectionPath()
 
         self.col = Collection(cpath, log=True)
+        self.col.backend.set_logging(os.path.join(self.pm.profileFolder(), "anki.log"))
 
         self.setEnabled(True)
         self.progress.setupDB(self.col.db)
         self.maybeEnableUndo()
         self.moveToState("deckBrowser")
+        if self.col.recovered:
+            tooltip(
+                _(
+                    "Anki was not closed properly last time. If you notice any "
+                    "problems, please use Tools>Check Database."
+                ),
+                period=5000,
+            )
         return True
 
     def unloadCollection(self, onsuccess: Callable) -> None:
@@ -465,6 +489,7 @@ Debug info:
             label = _("Backing Up...")
         self.progress.start(label=label, immediate=True)
         corrupt = False
+        backend = self.col.backend
         try:
             self.maybeOptimize()
             if not devMode:
@@ -488,70 +513,99 @@ from the profile screen."
                 )
             )
         if not corrupt and not self.restoringBackup:
-            self.backup()
+            try:
+                self.backup(backend)
+            except Exception as e:
+                showWarning(_("Unable to create backup: %s") % e)
 
         self.progress.finish()
 
     # Backup and auto-optimize
     ##########################################################################
 
-    class BackupThread(Thread):
-        def __init__(self, path, data):
-            Thread.__init__(self)
-            self.path = path
-            self.data = data
-            # create the file in calling thread to ensure the same
-            # file is not created twice
-            open(self.path, "wb").close()
-
-        def run(self):
-            z = zipfile.ZipFile(self.path, "w", zipfile.ZIP_DEFLATED)
-            z.writestr("collection.anki2", self.data)
-            z.writestr("media", "{}")
-            z.close()
-
-    def backup(self) -> None:
+    def backup(self, backend: RustBackend) -> None:
+        "Back up the closed collection in the background."
         nbacks = self.pm.profile["numBackups"]
         if not nbacks or devMode:
             return
-        dir = self.pm.backupFolder()
-        path = self.pm.collectionPath()
-
-        # do backup
-        fname = time.strftime(
-            "backup-%Y-%m-%d-%H.%M.%S.colpkg", time.localtime(time.time())
+        limits = BackupLimits(
+            recent=nbacks,
+            daily=self.pm.profile.get("dailyBackups", 7),
+            weekly=self.pm.profile.get("weeklyBackups", 4),
         )
-        newpath = os.path.join(dir, fname)
-        with open(path, "rb") as f:
-            data = f.read()
-        b = self.BackupThread(newpath, data)
-        b.start()
-
-        # find existing backups
-        backups = []
-        for file in os.listdir(dir):
-            # only look for new-style format
-            m = re.match(r"backup-\d{4}-\d{2}-.+.colpkg", file)
-            if not m:
-                continue
-            backups.append(file)
-        backups.sort()
+        # backups of another profile's collection may still be pruning
+        self._awaitBackup()
+        backend.backup_collection(self.pm.backupFolder(), limits)
+        self._backupBackend = backend
 
-        # remove old ones
-        while len(backups) > nbacks:
-            fname = backups.pop(0)
-            path = os.path.join(dir, fname)
-            os.unlink(path)
+    def _awaitBackup(self) -> None:
+        if not self._backupBackend:
+            return
+        try:
+            self._backupBackend.await_backup_completion()
+        except Exception as e:
+            showWarning(_("Unable to create backup: %s") % e)
+        self._backupBackend = None
 
     def maybeOptimize(self) -> None:
         # have two weeks passed?
         if (intTime() - self.pm.profile["lastOptimize"]) < 86400 * 14:
             return
-        self.progress.start(label=_("Optimizing..."), immediate=True)
-        self.col.optimize()
+        try:
+            self.progress.run_backend_op(
+                self.col.optimize, self._onBackendProgress, label=_("Optimizing...")
+            )
+        except BackendException as e:
+            if e.args[0].WhichOneof("value") == "interrupted":
+                # try again next time
+                return
+            raise
         self.pm.profile["lastOptimize"] = intTime()
         self.pm.save()
-        self.progress.finish()
+
+    def _onBackendProgress(self, progress: Progress) -> None:
+        "Describe the progress of a long-running backend operation."
+        kind = progress.WhichOneof("value")
+        if kind == "optimize":
+            if progress.optimize.stage == OptimizeProgress.VACUUM:
+                label = _("Reclaiming unused space...")
+            else:
+                label = _("Updating statistics...")
+        elif kind == "database_check":
+            stage = progress.database_check.stage
+            if stage == DatabaseCheckProgress.INTEGRITY:
+                label = _("Checking integrity...")
+            elif stage in (DatabaseCheckProgress.NOTES, DatabaseCheckProgress.CARDS):
+                label = _("Checking notes and cards...")
+            else:
+                label = _("Rebuilding...")
+        elif kind == "media_check":
+            checked = progress.media_check.checked
+            label = (
+                ngettext("Checked %d file...", "Checked %d files...", checked)
+                % checked
+            )
+        elif kind == "export":
+            if progress.export.media:
+                files = progress.export.media_files
+                label = (
+                    ngettext("Exported %d media file", "Exported %d media files", files)
+                    % files
+                )
+            else:
+                label = _("Exporting...")
+        elif kind == "importing":
+            if progress.importing.media:
+                files = progress.importing.media_files
+                label = (
+                    ngettext("Imported %d media file", "Imported %d media files", files)
+                    % files
+                )
+            else:
+                label = _("Importing...")
+        else:
+            return
+        self.progress.update(label=label)
 
     # State machine
     ##########################################################################
@@ -1250,13 +1304,20 @@ will be lost. Continue?"""
 
     def onCheckDB(self):
         "True if no problems"
-        self.progress.start(immediate=True)
-        ret, ok = self.col.fixIntegrity()
-        self.progress.finish()
-        if not ok:
-            showText(ret)
+        ret = None
+        try:
+            ret, ok = self.progress.run_backend_op(
+                self.col.fixIntegrity, self._onBackendProgress
+            )
+        except BackendException as e:
+            if e.args[0].WhichOneof("value") != "interrupted":
+                raise
+            tooltip(_("Check Database was stopped."))
         else:
-            tooltip(ret)
+            if not ok:
+                showText(ret)
+            else:
+                tooltip(ret)
 
         # if an error has directed the user to check the database,
         # silently clean up any broken reset hooks which distract from
@@ -1271,9 +1332,14 @@ will be lost. Continue?"""
         return ret
 
     def onCheckMediaDB(self):
-        self.progress.start(immediate=True)
-        (nohave, unused, warnings) = self.col.media.check()
-        self.progress.finish()
+        try:
+            (nohave, unused, warnings) = self.progress.run_backend_op(
+                self.col.media.check, self._onBackendProgress
+            )
+        except BackendException as e:
+            if e.args[0].WhichOneof("value") == "interrupted":
+                return
+            raise
         # generate report
         report = ""
         if warnings:
@@ -1296,9 +1362,21 @@ will be lost. Continue?"""
                 report += "\n\n\n"
             report += _("Used on cards but missing from media folder:")
             report += "\n" + "\n".join(nohave)
-        if not report:
+        trash = self.col.media.trash_contents()
+        if not report and not trash:
             tooltip(_("No unused or missing files found."))
             return
+        if trash:
+            if report:
+                report += "\n\n\n"
+            report += (
+                ngettext(
+                    "%d file is in the media trash.",
+                    "%d files are in the media trash.",
+                    len(trash),
+                )
+                % len(trash)
+            )
         # show report and offer to delete
         diag = QDialog(self)
         diag.setWindowTitle("Anki")
@@ -1315,6 +1393,15 @@ will be lost. Continue?"""
             b.setAutoDefault(False)
             box.addButton(b, QDialogButtonBox.ActionRole)
             b.clicked.connect(lambda c, u=unused, d=diag: self.deleteUnused(u, d))
+        if trash:
+            b = QPushButton(_("Restore Deleted"))
+            b.setAutoDefault(False)
+            box.addButton(b, QDialogButtonBox.ActionRole)
+            b.clicked.connect(lambda c, t=trash, d=diag: self.restoreTrash(t, d))
+            b = QPushButton(_("Empty Trash"))
+            b.setAutoDefault(False)
+            box.addButton(b, QDialogButtonBox.ActionRole)
+            b.clicked.connect(lambda c, d=diag: self.emptyMediaTrash(d))
 
         box.rejected.connect(diag.reject)
         diag.setMinimumHeight(400)
@@ -1326,39 +1413,37 @@ will be lost. Continue?"""
     def deleteUnused(self, unused, diag):
         if not askUser(_("Delete unused media?")):
             return
-        mdir = self.col.media.dir()
         self.progress.start(immediate=True)
         try:
-            lastProgress = 0
-            for c, f in enumerate(unused):
-                path = os.path.join(mdir, f)
-                if os.path.exists(path):
-                    send2trash(path)
-
-                now = time.time()
-                if now - lastProgress >= 0.3:
-                    numberOfRemainingFilesToBeDeleted = len(unused) - c
-                    lastProgress = now
-                    label = (
-                        ngettext(
-                            "%d file remaining...",
-                            "%d files remaining...",
-                            numberOfRemainingFilesToBeDeleted,
-                        )
-                        % numberOfRemainingFilesToBeDeleted
-                    )
-                    self.progress.update(label)
+            self.col.media.trash_files(unused)
         finally:
             self.progress.finish()
-        # caller must not pass in empty list
-        # pylint: disable=undefined-loop-variable
-        numberOfFilesDeleted = c + 1
+        numberOfFilesDeleted = len(unused)
         tooltip(
             ngettext("Deleted %d file.", "Deleted %d files.", numberOfFilesDeleted)
             % numberOfFilesDeleted
         )
         diag.close()
 
+    def restoreTrash(self, trash, diag):
+        self.progress.start(immediate=True)
+        try:
+            restored = self.col.media.restore_from_trash([f.fname for f in trash])
+        finally:
+            self.progress.finish()
+        tooltip(
+            ngettext("Restored %d file.", "Restored %d files.", len(restored))
+            % len(restored)
+        )
+        diag.close()
+
+    def emptyMediaTrash(self, diag):
+        if not askUser(_("Permanently delete the files in the media trash?")):
+            return
+        self.col.media.empty_trash()
+        tooltip(_("Media trash emptied."))
+        diag.close()
+
     def onStudyDeck(self):
         from aqt.studydeck import StudyDeck
 
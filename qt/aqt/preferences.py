@@ -90,8 +90,11 @@ class Preferences(QDialog):
         f.newSpread.setCurrentIndex(qc["newSpread"])
         f.useCurrent.setCurrentIndex(int(not qc.get("addToCur", True)))
         f.dayLearnFirst.setChecked(qc.get("dayLearnFirst", False))
+        f.loadBalance.setChecked(qc.get("loadBalance", False))
+        f.fulltextIndex.setChecked(self.mw.col.fulltextIndexEnabled())
         if self.mw.col.schedVer() != 2:
             f.dayLearnFirst.setVisible(False)
+            f.loadBalance.setVisible(False)
         else:
             f.newSched.setChecked(True)
 
@@ -118,9 +121,22 @@ class Preferences(QDialog):
         qc["collapseTime"] = f.lrnCutoff.value() * 60
         qc["addToCur"] = not f.useCurrent.currentIndex()
         qc["dayLearnFirst"] = f.dayLearnFirst.isChecked()
+        qc["loadBalance"] = f.loadBalance.isChecked()
         self._updateDayCutoff()
         self._updateSchedVer(f.newSched.isChecked())
         d.setMod()
+        self._updateFulltextIndex(f.fulltextIndex.isChecked())
+
+    def _updateFulltextIndex(self, wanted: bool) -> None:
+        col = self.mw.col
+        if wanted == col.fulltextIndexEnabled():
+            return
+        # building the index reads every note
+        self.mw.progress.start(immediate=True)
+        try:
+            col.setFulltextIndex(wanted)
+        finally:
+            self.mw.progress.finish()
 
     # Scheduler version
     ######################################################################
@@ -181,6 +197,9 @@ class Preferences(QDialog):
     def setupNetwork(self):
         self.form.syncOnProgramOpen.setChecked(self.prof["autoSync"])
         self.form.syncMedia.setChecked(self.prof["syncMedia"])
+        self.form.customSyncBase.setText(self.prof.get("customSyncBase", ""))
+        self.form.syncProxy.setText(self.prof.get("syncProxy", ""))
+        self.form.syncCaCertificates.setText(self.prof.get("syncCaCertificates", ""))
         if not self.prof["syncKey"]:
             self._hideAuth()
         else:
@@ -206,6 +225,16 @@ Not currently enabled; click the sync button in the main window to enable."""
     def updateNetwork(self):
         self.prof["autoSync"] = self.form.syncOnProgramOpen.isChecked()
         self.prof["syncMedia"] = self.form.syncMedia.isChecked()
+        self.prof["syncProxy"] = self.form.syncProxy.text().strip()
+        self.prof["syncCaCertificates"] = self.form.syncCaCertificates.text().strip()
+        base = self.form.customSyncBase.text().strip()
+        if base != self.prof.get("customSyncBase", ""):
+            # the login, media state and last sync belong to the old server
+            self.prof["customSyncBase"] = base
+            self.prof["hostNum"] = None
+            self.onSyncDeauth()
+            self.mw.col.modSchema(check=False)
+            self.mw.col.setMod()
         if self.form.fullSync.isChecked():
             self.mw.col.modSchema(check=False)
             self.mw.col.setMod()
@@ -226,11 +255,13 @@ Not currently enabled; click the sync button in the main window to enable."""
         self.form.pastePNG.setChecked(self.prof.get("pastePNG", False))
         self.form.uiScale.setValue(self.mw.pm.uiScale() * 100)
         self.form.pasteInvert.setChecked(self.prof.get("pasteInvert", False))
+        self.form.convertToWebp.setChecked(self.prof.get("convertToWebp", False))
         self.form.showPlayButtons.setChecked(self.prof.get("showPlayButtons", True))
 
     def updateOptions(self):
         self.prof["pastePNG"] = self.form.pastePNG.isChecked()
         self.prof["pasteInvert"] = self.form.pasteInvert.isChecked()
+        self.prof["convertToWebp"] = self.form.convertToWebp.isChecked()
         newScale = self.form.uiScale.value() / 100
         if newScale != self.mw.pm.uiScale():
             self.mw.pm.setUiScale(newScale)
@@ -20,6 +20,7 @@ import aqt.forms
 import aqt.sound
 from anki.db import DB
 from anki.lang import _
+from anki.rsbackend import SyncNetworkSettings
 from anki.utils import intTime, isMac, isWin
 from aqt import appHelpSite
 from aqt.qt import *
@@ -41,6 +42,8 @@ profileConf: Dict[str, Any] = dict(
     mainWindowGeom=None,
     mainWindowState=None,
     numBackups=50,
+    dailyBackups=7,
+    weeklyBackups=4,
     lastOptimize=intTime(),
     # editing
     fullSearch=False,
@@ -48,6 +51,7 @@ profileConf: Dict[str, Any] = dict(
     lastColour="#00f",
     stripHTML=True,
     pastePNG=False,
+    convertToWebp=False,
     # not exposed in gui
     deleteMedia=False,
     preserveKeyboard=True,
@@ -481,6 +485,20 @@ please see:
         elif mode == "angle":
             self.setGlMode("software")
 
+    # Syncing
+    ######################################################################
+
+    def customSyncBase(self) -> str:
+        "The URL of a self-hosted sync server, or an empty string for AnkiWeb."
+        return self.profile.get("customSyncBase", "")
+
+    def syncNetworkSettings(self) -> SyncNetworkSettings:
+        "The proxy and extra certificate authorities used when syncing."
+        return SyncNetworkSettings(
+            proxy=self.profile.get("syncProxy", ""),
+            ca_certificates=self.profile.get("syncCaCertificates", ""),
+        )
+
     # Helpers
     ######################################################################
 
@@ -3,9 +3,12 @@
 # License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
 
 import time
+from concurrent.futures import wait
+from typing import Any, Callable, Optional
 
 import aqt.forms
 from anki.lang import _
+from anki.rsbackend import Progress, RustBackend
 from aqt.qt import *
 
 # fixme: if mw->subwindow opens a progress dialog with mw as the parent, mw
@@ -168,6 +171,41 @@ class ProgressManager:
             self._levels = 1
             self.finish()
 
+    # Long-running backend operations
+    ##########################################################################
+
+    def run_backend_op(
+        self,
+        task: Callable[[], Any],
+        on_progress: Callable[[Progress], None],
+        label: Optional[str] = None,
+        backend: Optional[RustBackend] = None,
+    ) -> Any:
+        """Run TASK on a background thread, returning its result or raising
+        its exception. While it runs, the backend's latest progress is passed
+        to ON_PROGRESS on the main thread, and closing the progress window
+        asks the backend to abort. Nothing else may use the collection until
+        the task completes; the modal progress window and deferred timers
+        ensure that. BACKEND defaults to the open collection's, and must be
+        given when no collection is open."""
+        backend = backend or self.mw.col.backend
+        self.start(label=label, immediate=True)
+        fut = self.mw.taskman.run_in_background(task)
+        try:
+            while not fut.done():
+                if self._win and self._win.wantCancel:
+                    backend.set_wants_abort()
+                    self.update(_("Stopping..."))
+                else:
+                    progress = backend.latest_progress()
+                    if progress is not None:
+                        on_progress(progress)
+                self.app.processEvents()
+                wait([fut], timeout=0.1)
+        finally:
+            self.finish()
+        return fut.result()
+
     def _maybeShow(self):
         if not self._levels:
             return
@@ -2,12 +2,10 @@
 # Copyright: Ankitects Pty Ltd and contributors
 # License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
 
-import difflib
 import html
 import html.parser
 import json
 import re
-import unicodedata as ucd
 from typing import List, Optional
 
 from anki import hooks
@@ -449,79 +447,9 @@ Please run Tools>Empty Cards"""
             txt = ", ".join(matches)
         return txt
 
-    def tokenizeComparison(self, given, correct):
-        # compare in NFC form so accents appear correct
-        given = ucd.normalize("NFC", given)
-        correct = ucd.normalize("NFC", correct)
-        s = difflib.SequenceMatcher(None, given, correct, autojunk=False)
-        givenElems = []
-        correctElems = []
-        givenPoint = 0
-        correctPoint = 0
-        offby = 0
-
-        def logBad(old, new, str, array):
-            if old != new:
-                array.append((False, str[old:new]))
-
-        def logGood(start, cnt, str, array):
-            if cnt:
-                array.append((True, str[start : start + cnt]))
-
-        for x, y, cnt in s.get_matching_blocks():
-            # if anything was missed in correct, pad given
-            if cnt and y - offby > x:
-                givenElems.append((False, "-" * (y - x - offby)))
-                offby = y - x
-            # log any proceeding bad elems
-            logBad(givenPoint, x, given, givenElems)
-            logBad(correctPoint, y, correct, correctElems)
-            givenPoint = x + cnt
-            correctPoint = y + cnt
-            # log the match
-            logGood(x, cnt, given, givenElems)
-            logGood(y, cnt, correct, correctElems)
-        return givenElems, correctElems
-
     def correct(self, given, correct, showBad=True):
         "Diff-corrects the typed-in answer."
-        givenElems, correctElems = self.tokenizeComparison(given, correct)
-
-        def good(s):
-            return "<span class=typeGood>" + html.escape(s) + "</span>"
-
-        def bad(s):
-            return "<span class=typeBad>" + html.escape(s) + "</span>"
-
-        def missed(s):
-            return "<span class=typeMissed>" + html.escape(s) + "</span>"
-
-        if given == correct:
-            res = good(given)
-        else:
-            res = ""
-            for ok, txt in givenElems:
-                txt = self._noLoneMarks(txt)
-                if ok:
-                    res += good(txt)
-                else:
-                    res += bad(txt)
-            res += "<br>&darr;<br>"
-            for ok, txt in correctElems:
-                txt = self._noLoneMarks(txt)
-                if ok:
-                    res += good(txt)
-                else:
-                    res += missed(txt)
-        res = "<div><code id=typeans>" + res + "</code></div>"
-        return res
-
-    def _noLoneMarks(self, s):
-        # ensure a combining character at the start does not join to
-        # previous text
-        if s and ucd.category(s[0]).startswith("M"):
-            return "\xa0" + s
-        return s
+        return self.mw.col.backend.compare_answer(correct, given)
 
     def _getTypedAnswer(self):
         self.web.evalWithCallback("typeans ? typeans.value : null", self._onTypedAnswer)
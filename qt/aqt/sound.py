@@ -19,13 +19,13 @@ import pyaudio
 import anki
 import aqt
 from anki.lang import _
-from anki.sound import AVTag, SoundOrVideoTag
+from anki.sound import AVTag, InvalidTTSTag, SoundOrVideoTag
 from anki.utils import isLin, isMac, isWin
 from aqt import gui_hooks
 from aqt.mpv import MPV, MPVBase
 from aqt.qt import *
 from aqt.taskman import TaskManager
-from aqt.utils import restoreGeom, saveGeom
+from aqt.utils import restoreGeom, saveGeom, tooltip
 
 # AV player protocol
 ##########################################################################
@@ -160,6 +160,11 @@ class AVPlayer:
             self._play(next)
 
     def _play(self, tag: AVTag) -> None:
+        if isinstance(tag, InvalidTTSTag):
+            tooltip(tag.message)
+            self._play_next_if_idle()
+            return
+
         best_player = self._best_player_for_tag(tag)
         if best_player:
             self.current_player = best_player
@@ -3,11 +3,25 @@
 
 import gc
 import time
+from typing import Optional
 
 from anki import hooks
-from anki.lang import _
+from anki.lang import _, ngettext
+from anki.rsbackend import (
+    BackendException,
+    FullSyncOutcome,
+    FullSyncPreview,
+    MediaSyncOutcome,
+    MediaSyncProgress,
+    NetworkErrorKind,
+    NormalSyncProgress,
+    Progress,
+    SyncChangeCounts,
+    SyncCollectionOutcome,
+    SyncNetworkSettings,
+)
 from anki.storage import Collection
-from anki.sync import FullSyncer, MediaSyncer, RemoteMediaServer, RemoteServer, Syncer
+from anki.sync import mediaSyncEndpoint, syncEndpoint
 from aqt.qt import *
 from aqt.utils import askUserDialog, showInfo, showText, showWarning, tooltip
 
@@ -44,12 +58,15 @@ class SyncManager(QObject):
             auth=auth,
             media=self.pm.profile["syncMedia"],
             hostNum=self.pm.profile.get("hostNum"),
+            backupFolder=self.pm.backupFolder(),
+            customBase=self.pm.customSyncBase(),
+            network=self.pm.syncNetworkSettings(),
         )
         t._event.connect(self.onEvent)
-        t.progress_event.connect(self.on_progress)
         self.label = _("Connecting...")
         prog = self.mw.progress.start(immediate=True, label=self.label)
         self.sentBytes = self.recvBytes = 0
+        self._stage = None
         self._updateLabel()
         self.thread.start()
         while not self.thread.isFinished():
@@ -59,6 +76,10 @@ class SyncManager(QObject):
                 self._didFullUp = False
                 # abort may take a while
                 self.mw.progress.update(_("Stopping..."))
+            else:
+                progress = self.thread.latestProgress()
+                if progress is not None:
+                    self._onBackendProgress(progress)
             self.mw.app.processEvents()
             self.thread.wait(100)
         self.mw.progress.finish()
@@ -94,11 +115,52 @@ automatically."""
             )
         )
 
-    def on_progress(self, upload: int, download: int) -> None:
-        # posted events not guaranteed to arrive in order; don't go backwards
-        self.sentBytes = max(self.sentBytes, upload)
-        self.recvBytes = max(self.recvBytes, download)
-        self._updateLabel()
+    def _onBackendProgress(self, progress: Progress) -> None:
+        "Show the progress the sync thread's backend last reported."
+        kind = progress.WhichOneof("value")
+        if kind == "normal_sync":
+            p = progress.normal_sync
+            if p.stage != self._stage:
+                self._stage = p.stage
+                self.onEvent("sync", NormalSyncProgress.Stage.Name(p.stage).lower())
+            self.sentBytes = p.sent_bytes
+            self.recvBytes = p.received_bytes
+            self._updateLabel()
+        elif kind == "full_sync":
+            if self._didFullUp:
+                self.sentBytes = progress.full_sync.transferred_bytes
+            else:
+                self.recvBytes = progress.full_sync.transferred_bytes
+            self._updateLabel()
+        elif kind == "media_sync":
+            self.onEvent("syncMsg", self._mediaProgressMsg(progress.media_sync))
+
+    def _mediaProgressMsg(self, p: MediaSyncProgress) -> str:
+        uploaded = p.uploaded_files + p.uploaded_deletions
+        downloaded = p.downloaded_files + p.downloaded_deletions
+        if uploaded:
+            return (
+                ngettext(
+                    "%d media change uploaded", "%d media changes uploaded", uploaded
+                )
+                % uploaded
+            )
+        elif downloaded:
+            return (
+                ngettext(
+                    "%d media change downloaded",
+                    "%d media changes downloaded",
+                    downloaded,
+                )
+                % downloaded
+            )
+        else:
+            return (
+                ngettext(
+                    "%d media change checked", "%d media changes checked", p.checked
+                )
+                % p.checked
+            )
 
     def onEvent(self, evt, *args):
         pu = self.mw.progress.update
@@ -117,6 +179,8 @@ automatically."""
             self.pm.save()
         elif evt == "offline":
             tooltip(_("Syncing failed; internet offline."))
+        elif evt == "timeout":
+            tooltip(_("Syncing failed; the connection to AnkiWeb timed out."))
         elif evt == "upbad":
             self._didFullUp = False
             self._checkFailed()
@@ -317,7 +381,8 @@ last sync to this device will be lost.
 
 After all devices are in sync, future reviews and added cards can be merged \
 automatically."""
-                ),
+                )
+                + self._fullSyncPreviewText(),
                 [_("Upload to AnkiWeb"), _("Download from AnkiWeb"), _("Cancel")],
             )
             diag.setDefault(2)
@@ -330,6 +395,42 @@ automatically."""
             self.thread.fullSyncChoice = "cancel"
         self.mw.progress.start(immediate=True)
 
+    def _describeChanges(self, counts: SyncChangeCounts) -> str:
+        parts = []
+        for count, text in (
+            (counts.notes, ngettext("%d note", "%d notes", counts.notes)),
+            (counts.cards, ngettext("%d card", "%d cards", counts.cards)),
+            (counts.reviews, ngettext("%d review", "%d reviews", counts.reviews)),
+            (counts.decks, ngettext("%d deck", "%d decks", counts.decks)),
+            (
+                counts.notetypes,
+                ngettext("%d note type", "%d note types", counts.notetypes),
+            ),
+            (
+                counts.removals,
+                ngettext("%d deletion", "%d deletions", counts.removals),
+            ),
+        ):
+            if count:
+                parts.append(text % count)
+        return ", ".join(parts) or _("nothing")
+
+    def _fullSyncPreviewText(self) -> str:
+        preview = self.thread.fullSyncPreview
+        if not preview:
+            return ""
+        changes = self._describeChanges(preview.local)
+        local = _("Changes that a download would lose: %s.") % changes
+        if preview.remote_replaced:
+            remote = _(
+                "The collection on AnkiWeb was replaced by another device since "
+                "your last sync, and an upload would overwrite all of it."
+            )
+        else:
+            changes = self._describeChanges(preview.remote)
+            remote = _("Changes that an upload would lose: %s.") % changes
+        return "\n\n" + local + "\n" + remote
+
     def _clockOff(self):
         showWarning(
             _(
@@ -356,19 +457,45 @@ Check Database, then sync again."""
 class SyncThread(QThread):
 
     _event = pyqtSignal(str, str)
-    progress_event = pyqtSignal(int, int)
 
-    def __init__(self, path, hkey, auth=None, media=True, hostNum=None):
+    def __init__(
+        self,
+        path,
+        hkey,
+        auth=None,
+        media=True,
+        hostNum=None,
+        backupFolder="",
+        customBase="",
+        network: Optional[SyncNetworkSettings] = None,
+    ):
         QThread.__init__(self)
         self.path = path
+        self.backupFolder = backupFolder
         self.hkey = hkey
         self.auth = auth
         self.media = media
         self.hostNum = hostNum
-        self._abort = 0  # 1=flagged, 2=aborting
+        self.customBase = customBase
+        self.network = network
+        self.col = None
+        self.fullSyncPreview: Optional[FullSyncPreview] = None
+        self._abort = False
+        self._syncingMedia = False
 
     def flagAbort(self):
-        self._abort = 1
+        self._abort = True
+        if self.col:
+            self.col.backend.set_wants_abort()
+            self.col.backend.set_wants_media_abort()
+
+    def latestProgress(self) -> Optional[Progress]:
+        "The progress of the backend op in progress; called from the main thread."
+        if not self.col:
+            return None
+        if self._syncingMedia:
+            return self.col.backend.latest_media_progress()
+        return self.col.backend.latest_progress()
 
     def run(self):
         # init this first so an early crash doesn't cause an error
@@ -380,10 +507,6 @@ class SyncThread(QThread):
         except:
             self.fireEvent("corrupt")
             return
-        self.server = RemoteServer(self.hkey, hostNum=self.hostNum)
-        self.client = Syncer(self.col, self.server)
-        self.sentTotal = 0
-        self.recvTotal = 0
 
         def syncEvent(type):
             self.fireEvent("sync", type)
@@ -391,17 +514,6 @@ class SyncThread(QThread):
         def syncMsg(msg):
             self.fireEvent("syncMsg", msg)
 
-        def http_progress(upload: int, download: int) -> None:
-            if not self._abort:
-                self.sentTotal += upload
-                self.recvTotal += download
-                self.progress_event.emit(self.sentTotal, self.recvTotal)  # type: ignore
-            elif self._abort == 1:
-                self._abort = 2
-                raise Exception("sync cancelled")
-
-        self.server.client.progress_hook = http_progress
-
         hooks.sync_stage_did_change.append(syncEvent)
         hooks.sync_progress_did_change.append(syncMsg)
         # run sync and catch any errors
@@ -416,65 +528,62 @@ class SyncThread(QThread):
             hooks.sync_stage_did_change.remove(syncEvent)
             hooks.sync_progress_did_change.remove(syncMsg)
 
-    def _abortingSync(self):
-        try:
-            return self.client.sync()
-        except Exception as e:
-            if "sync cancelled" in str(e):
-                self.server.abort()
-                raise
-            else:
-                raise
-
     def _sync(self):
         if self.auth:
             # need to authenticate and obtain host key
-            self.hkey = self.server.hostKey(*self.auth)
+            try:
+                self.hkey = self.col.syncLogin(
+                    self.auth[0], self.auth[1], self._endpoint(), self.network
+                )
+            except BackendException as e:
+                if e.args[0].WhichOneof("value") == "network_error":
+                    return self._fireNetworkError(e)
+                raise
             if not self.hkey:
                 # provided details were invalid
                 return self.fireEvent("badAuth")
             else:
                 # write new details and tell calling thread to save
                 self.fireEvent("newKey", self.hkey)
+        # the backend only sees aborts requested once an op has started
+        if self._abort:
+            return
         # run sync and check state
         try:
-            ret = self._abortingSync()
-        except Exception as e:
-            log = traceback.format_exc()
-            err = repr(str(e))
-            if (
-                "Unable to find the server" in err
-                or "Errno 2" in err
-                or "getaddrinfo" in err
-            ):
-                self.fireEvent("offline")
-            elif "sync cancelled" in err:
-                pass
-            else:
-                self.fireEvent("error", log)
-            return
-        if ret == "badAuth":
+            out = self.col.sync(self.hkey, self._endpoint(), self.network)
+        except BackendException as e:
+            kind = e.args[0].WhichOneof("value")
+            if kind == "interrupted":
+                return
+            elif kind == "network_error":
+                self._fireNetworkError(e)
+                return
+            elif kind == "sync_auth_error":
+                self.fireEvent("badAuth")
+                return
+            raise
+        if out.outcome == SyncCollectionOutcome.BAD_AUTH:
             return self.fireEvent("badAuth")
-        elif ret == "clockOff":
+        self.syncMsg = out.server_message
+        self.uname = out.username
+        self.hostNum = out.host_number or None
+        if out.outcome == SyncCollectionOutcome.CLOCK_OFF:
             return self.fireEvent("clockOff")
-        elif ret == "basicCheckFailed" or ret == "sanityCheckFailed":
+        elif out.outcome in (
+            SyncCollectionOutcome.BASIC_CHECK_FAILED,
+            SyncCollectionOutcome.SANITY_CHECK_FAILED,
+        ):
             return self.fireEvent("checkFailed")
+        elif out.outcome == SyncCollectionOutcome.SERVER_ABORT:
+            return
         # full sync?
-        if ret == "fullSync":
+        if out.outcome == SyncCollectionOutcome.FULL_SYNC_REQUIRED:
             return self._fullSync()
-        # save and note success state
-        if ret == "noChanges":
+        # note success state
+        if out.outcome == SyncCollectionOutcome.NO_CHANGES:
             self.fireEvent("noChanges")
-        elif ret == "success":
-            self.fireEvent("success")
-        elif ret == "serverAbort":
-            self.syncMsg = self.client.syncMsg
-            return
         else:
-            self.fireEvent("error", "Unknown sync return code.")
-        self.syncMsg = self.client.syncMsg
-        self.uname = self.client.uname
-        self.hostNum = self.client.hostNum
+            self.fireEvent("success")
         # then move on to media sync
         self._syncMedia()
 
@@ -483,51 +592,90 @@ class SyncThread(QThread):
         # wait for a reply
         self.fullSyncChoice = False
         self.localIsEmpty = self.col.isEmpty()
+        self.fullSyncPreview = self._fullSyncPreview()
         self.fireEvent("fullSync")
         while not self.fullSyncChoice:
             time.sleep(0.1)
         f = self.fullSyncChoice
-        if f == "cancel":
+        if f == "cancel" or self._abort:
             return
-        self.client = FullSyncer(
-            self.col, self.hkey, self.server.client, hostNum=self.hostNum
-        )
+        endpoint = self._endpoint()
         try:
             if f == "upload":
-                if not self.client.upload():
+                self.fireEvent("sync", "upload")
+                ret = self.col.fullUpload(self.hkey, endpoint, self.network)
+                if ret != FullSyncOutcome.SUCCESS:
                     self.fireEvent("upbad")
             else:
-                ret = self.client.download()
-                if ret == "downloadClobber":
-                    self.fireEvent(ret)
+                self.fireEvent("sync", "download")
+                ret = self.col.fullDownload(
+                    self.hkey, endpoint, self.backupFolder, self.network
+                )
+                if ret == FullSyncOutcome.UPGRADE_REQUIRED:
+                    self.fireEvent("sync", "upgradeRequired")
                     return
-        except Exception as e:
-            if "sync cancelled" in str(e):
+                elif ret == FullSyncOutcome.DOWNLOAD_CLOBBER:
+                    self.fireEvent("downloadClobber")
+                    return
+        except BackendException as e:
+            kind = e.args[0].WhichOneof("value")
+            if kind == "interrupted":
+                return
+            elif kind == "network_error":
+                self._fireNetworkError(e)
+                return
+            elif kind == "sync_auth_error":
+                self.fireEvent("badAuth")
                 return
             raise
-        # reopen db and move on to media sync
-        self.col.reopen()
+        # move on to media sync
         self._syncMedia()
 
+    def _fullSyncPreview(self) -> Optional[FullSyncPreview]:
+        try:
+            return self.col.fullSyncPreview(self.hkey, self._endpoint(), self.network)
+        except BackendException:
+            # the user can still choose without it
+            return None
+
     def _syncMedia(self):
-        if not self.media:
+        if not self.media or self._abort:
             return
-        self.server = RemoteMediaServer(
-            self.col, self.hkey, self.server.client, hostNum=self.hostNum
-        )
-        self.client = MediaSyncer(self.col, self.server)
+        self._syncingMedia = True
         try:
-            ret = self.client.sync()
-        except Exception as e:
-            if "sync cancelled" in str(e):
+            ret = self.col.media.sync(self.hkey, self._mediaEndpoint(), self.network)
+        except BackendException as e:
+            kind = e.args[0].WhichOneof("value")
+            if kind == "interrupted":
+                return
+            elif kind == "network_error":
+                self._fireNetworkError(e)
+                return
+            elif kind == "sync_auth_error":
+                self.fireEvent("badAuth")
+                return
+            elif kind == "db_error":
+                self.fireEvent("mediaSanity")
                 return
             raise
-        if ret == "noChanges":
+        if ret == MediaSyncOutcome.NO_CHANGES:
             self.fireEvent("noMediaChanges")
-        elif ret == "sanityCheckFailed" or ret == "corruptMediaDB":
+        elif ret == MediaSyncOutcome.SANITY_CHECK_FAILED:
             self.fireEvent("mediaSanity")
         else:
             self.fireEvent("mediaSuccess")
 
+    def _fireNetworkError(self, e: BackendException) -> None:
+        if e.args[0].network_error.kind == NetworkErrorKind.TIMEOUT:
+            self.fireEvent("timeout")
+        else:
+            self.fireEvent("offline")
+
+    def _endpoint(self) -> str:
+        return syncEndpoint(self.hostNum, self.customBase)
+
+    def _mediaEndpoint(self) -> str:
+        return mediaSyncEndpoint(self.hostNum, self.customBase)
+
     def fireEvent(self, cmd, arg=""):
         self._event.emit(cmd, arg)
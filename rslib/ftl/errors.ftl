@@ -0,0 +1,18 @@
+### Errors shown to the user. Errors that only developers would see are
+### not translated.
+
+error-network-offline = Couldn't connect to AnkiWeb. Please check your network connection and try again.
+error-network-timeout = The connection to AnkiWeb timed out. Please check your network connection and try again.
+error-network-other = A network error occurred: { $info }
+error-sync-auth = AnkiWeb ID or password was incorrect; please try again.
+error-sync-other = Syncing failed: { $info }
+error-interrupted = Operation cancelled.
+error-db-corrupt = The collection file is damaged. Please use Tools>Check Database, or restore from a backup.
+error-db-locked = The collection is busy. Please try again in a moment.
+error-collection-too-new = This file requires a newer version of Anki.
+error-collection-in-use = The collection is open in another copy of Anki. If it isn't, remove { $lock_path }.
+error-deck-exists = That deck already exists.
+error-deck-filtered-parent = A filtered deck cannot have subdecks.
+error-deck-conf-empty-name = The options group needs a name.
+error-deck-conf-invalid-steps = Steps must be numbers.
+error-deck-conf-no-steps = At least one step is required.
@@ -0,0 +1,17 @@
+### Problems found by Tools>Check Media. When the check is a dry run,
+### the "would" messages describe what the check would change.
+
+media-check-renamed = Renamed { $old } to { $new }
+media-check-would-rename = Would rename { $old } to { $new }
+media-check-updated-refs =
+    { $count ->
+        [one] Updated media references in { $count } note.
+       *[other] Updated media references in { $count } notes.
+    }
+media-check-would-update-refs =
+    { $count ->
+        [one] Media references would be updated in { $count } note.
+       *[other] Media references would be updated in { $count } notes.
+    }
+media-check-invalid-name = Invalid file name, please rename: { $name }
+media-check-subfolders = Anki does not support files in subfolders of the collection.media folder.
@@ -4,32 +4,263 @@
 use crate::backend_proto as pt;
 use crate::backend_proto::backend_input::Value;
 use crate::backend_proto::RenderedTemplateReplacement;
-use crate::err::{AnkiError, Result};
-use crate::sched::{local_minutes_west_for_stamp, sched_timing_today};
+use crate::backup::{backup_collection, list_backups, restore_backup, BackupLimits};
+use crate::browser_rows::{browser_rows, Cell, Column, Label};
+use crate::bulk::{
+    add_tags_to_notes, remove_tags_from_notes, rename_tag_in_notes, set_deck, set_flag,
+};
+use crate::card::{CardQueue, CardType};
+use crate::cardgen::CardGenContext;
+use crate::cloze::render_cloze;
+use crate::collection::{close_collection, open_collection, CollectionLock};
+use crate::dbcheck::{check_database, DatabaseCheckStage};
+use crate::decks::{
+    add_deck_conf, deck_conf_for_deck, remove_deck_conf, rename_deck, set_deck_conf,
+    update_deck_conf, DeckConf,
+};
+use crate::dupes::{find_duplicates, tag_duplicates};
+use crate::err::{
+    AnkiError, DBErrorKind, DeckConfErrorKind, DeckRenameErrorKind, LatexError, NetworkErrorKind,
+    Result, TTSError, TemplateError,
+};
+use crate::findreplace::{FindReplacer, NoteText};
+use crate::i18n::{FluentArgs, FluentValue, I18n};
+use crate::import_export::foreign::{import_mnemosyne, import_supermemo_xml};
+use crate::import_export::package::{
+    export_package, import_collection_package, import_package, DuplicateMode, ExportProgress,
+    ImportProgress, LoggedNote, PackageExportOptions, PackageImportOptions, SharingOptions,
+};
+use crate::import_export::text::{
+    export_notes_as_text, import_text_file, read_text_file, ColumnMapping, RowProblem,
+    TextDuplicateMode, TextExportOptions, TextImportOptions,
+};
+use crate::import_export::ExportLimit;
+use crate::latex::{extract_latex, render_latex, ExtractedLatex, LatexOptions};
+use crate::log::set_up_logging;
+use crate::media::sync::{MediaSyncOutcome, MediaSyncProgress};
+use crate::media::transcode::ImageTranscodeConfig;
+use crate::media::MediaManager;
+use crate::notes::{
+    add_note, change_notetype_of_notes, field_checksum, update_note, Note, NoteSaveOutcome,
+};
+use crate::notetypes::{update_notetype_schema, NoteType};
+use crate::ruby::{furigana_to_ruby, kana_only, kanji_only};
+use crate::sched::algorithm::AlgorithmKind;
+use crate::sched::answering::{
+    answer_card, CardSchedulingState, LapseConfig, NewCardConfig, ReviewConfig, SchedulingConfig,
+};
+use crate::sched::bury_suspend::{
+    bury_or_suspend_cards, bury_siblings, unbury_cards, unsuspend_cards, BuryOrSuspendMode,
+    SiblingBuryConfig, UnburyMode,
+};
+use crate::sched::counts::{
+    deck_due_counts, new_count_for_active_decks, DeckDueInput, DeckTreeNode,
+};
+use crate::sched::filtered::{
+    empty_filtered_deck, fill_filtered_deck, remove_from_filtered_decks, FilteredDeckFill,
+    FilteredSearchOrder, FilteredSearchTerm,
+};
+use crate::sched::leech::add_leech_tag;
+use crate::sched::load_balance::LoadBalancer;
+use crate::sched::reschedule::{set_due_date, DueDateSpec};
+use crate::sched::undo::{restore_card_schedules, CardScheduleSnapshot};
+use crate::sched::{
+    local_minutes_west_for_stamp, local_sched_timing_today, sched_timing_today, SchedTimingToday,
+};
+use crate::search::{
+    remove_saved_search, rename_saved_search, save_search, saved_searches, search_cards,
+    search_cards_page, search_notes, search_notes_page, PageSpec, SearchContext, SearchPage,
+    SortMode,
+};
+use crate::stats::card::card_stats;
+use crate::stats::graphs::{graphs, CountPoint, GraphsSpec};
+use crate::storage::{now_millis, CollectionSnapshot, OptimizeStage, SqliteStorage};
+use crate::sync::{
+    full_download, full_sync_preview, full_upload, sync_collection, sync_login, ChangeCounts,
+    CollectionFormat, FullSyncOutcome, FullSyncProgress, NetworkSettings, NormalSyncProgress,
+    SyncOutcome, SyncOutput, SyncStage,
+};
+use crate::tags::{clear_unused_tags, tag_tree, tag_usage, TagTreeNode};
 use crate::template::{
-    render_card, without_legacy_template_directives, FieldMap, FieldRequirements, ParsedTemplate,
-    RenderedNode,
+    check_template, rename_field, render_card_preview, render_card_sides,
+    without_legacy_template_directives, CardContext, FieldMap, FieldRequirements, ParsedTemplate,
+    RenderContext, RenderedNode,
+};
+use crate::template_filters::apply_filters;
+use crate::text::{
+    av_tags_with_spans, flag_av_tags, html_to_text, normalize_to_nfc, sanitize_html,
+    sort_field_text, strip_av_tags, AVTag, TextLayout,
 };
-use crate::text::{av_tags_in_string, flag_av_tags, strip_av_tags, AVTag};
+use crate::typeanswer::compare_answer;
+use crate::undo::{UndoManager, UndoableChange, UndoableOp};
 use prost::Message;
+use rusqlite::types::Value as SqlValue;
 use std::collections::{HashMap, HashSet};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use tokio::runtime::Builder;
 
+/// Commands take a shared reference, so the backend can be used from
+/// several threads at once: media syncing, which mostly waits on the
+/// network, can run while collection commands are handled on another
+/// thread. Collection commands open the collection file themselves, and
+/// SQLite's locking keeps them apart; state that lives across commands is
+/// kept behind mutexes.
 pub struct Backend {
-    #[allow(dead_code)]
-    path: PathBuf,
+    col_path: PathBuf,
+    media_folder: PathBuf,
+    media_db: PathBuf,
+    /// The progress of collection operations.
+    progress: ProgressHandle,
+    /// The progress of media syncing, which runs alongside them.
+    media_progress: ProgressHandle,
+    /// Held while the media folder or the media DB is changed: by checks,
+    /// by the local steps of a sync (but not while it waits on the
+    /// network), and when files are added to, trashed from, restored to or
+    /// imported into the folder, so none of these happen in the middle of
+    /// another's scan.
+    media_lock: Mutex<()>,
+    /// Held while the collection is open.
+    col_lock: Mutex<Option<CollectionLock>>,
+    undo: Mutex<UndoManager>,
+    backup_task: Mutex<Option<JoinHandle<Result<()>>>>,
+    snapshots: Mutex<HashMap<u32, CollectionSnapshot>>,
+    next_snapshot: AtomicU32,
+    /// Translates the text shown to the user, including errors.
+    i18n: Mutex<I18n>,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Progress {
+    MediaSync(MediaSyncProgress),
+    Optimize(OptimizeStage),
+    NormalSync(NormalSyncProgress),
+    FullSync(FullSyncProgress),
+    MediaCheck(usize),
+    DatabaseCheck(DatabaseCheckStage),
+    Export(ExportProgress),
+    Import(ImportProgress),
+}
+
+#[derive(Default)]
+struct ProgressState {
+    last_progress: Option<Progress>,
+    want_abort: bool,
+}
+
+/// Follows the operation the backend is running, and can ask it to stop.
+/// Commands block until they complete, so this is used from another
+/// thread; the frontend polls it to update its progress window.
+#[derive(Clone, Default)]
+pub struct ProgressHandle {
+    state: Arc<Mutex<ProgressState>>,
+}
+
+impl ProgressHandle {
+    /// The latest progress the running operation reported, as an encoded
+    /// Progress message. Empty if it hasn't reported any.
+    pub fn latest_progress_bytes(&self) -> Vec<u8> {
+        match self.state.lock().unwrap().last_progress {
+            Some(progress) => progress_to_proto_bytes(progress),
+            None => vec![],
+        }
+    }
+
+    /// Ask the running operation to stop at its next progress update,
+    /// which makes it return AnkiError::Interrupted.
+    pub fn set_wants_abort(&self) {
+        self.state.lock().unwrap().want_abort = true;
+    }
+
+    /// Forget the previous operation's progress and any abort request.
+    fn reset(&self) {
+        *self.state.lock().unwrap() = ProgressState::default();
+    }
+
+    /// Record the running operation's progress. Returns false if it should
+    /// stop.
+    fn update(&self, progress: Progress) -> bool {
+        let mut state = self.state.lock().unwrap();
+        state.last_progress = Some(progress);
+        !state.want_abort
+    }
 }
 
 /// Convert an Anki error to a protobuf error.
 impl std::convert::From<AnkiError> for pt::BackendError {
     fn from(err: AnkiError) -> Self {
         use pt::backend_error::Value as V;
+        // replaced with a translation when the backend's language is known
+        let localized = err.to_string();
         let value = match err {
             AnkiError::InvalidInput { info } => V::InvalidInput(pt::InvalidInputError { info }),
             AnkiError::TemplateError { info } => V::TemplateParse(pt::TemplateParseError { info }),
+            AnkiError::IOError { info } => V::IoError(pt::StringError { info }),
+            AnkiError::DBError { info, kind } => V::DbError(pt::DbError {
+                info,
+                kind: match kind {
+                    DBErrorKind::Corrupt => pt::db_error::Kind::Corrupt,
+                    DBErrorKind::Locked => pt::db_error::Kind::Locked,
+                    DBErrorKind::FileTooOld => pt::db_error::Kind::FileTooOld,
+                    DBErrorKind::Other => pt::db_error::Kind::Other,
+                } as i32,
+            }),
+            AnkiError::NetworkError { info, kind } => V::NetworkError(pt::NetworkError {
+                info,
+                kind: match kind {
+                    NetworkErrorKind::Offline => pt::network_error::Kind::Offline,
+                    NetworkErrorKind::Timeout => pt::network_error::Kind::Timeout,
+                    NetworkErrorKind::Other => pt::network_error::Kind::Other,
+                } as i32,
+            }),
+            AnkiError::SyncError { info } => V::SyncError(pt::StringError { info }),
+            AnkiError::SyncAuthError => V::SyncAuthError(pt::Empty {}),
+            AnkiError::Interrupted => V::Interrupted(pt::Empty {}),
+            AnkiError::CollectionTooNew { version } => {
+                V::CollectionTooNew(pt::CollectionTooNewError {
+                    version: u32::from(version),
+                })
+            }
+            AnkiError::CollectionInUse { lock_path } => {
+                V::CollectionInUse(pt::CollectionInUseError { lock_path })
+            }
+            AnkiError::SearchError { info, start, end } => V::SearchError(pt::SearchError {
+                info,
+                start: start as u32,
+                end: end as u32,
+            }),
+            AnkiError::DeckRenameError { kind } => V::DeckRenameError(pt::DeckRenameError {
+                kind: match kind {
+                    DeckRenameErrorKind::AlreadyExists => {
+                        pt::deck_rename_error::Kind::AlreadyExists
+                    }
+                    DeckRenameErrorKind::FilteredParent => {
+                        pt::deck_rename_error::Kind::FilteredParent
+                    }
+                } as i32,
+            }),
+            AnkiError::DeckConfError { kind } => V::DeckConfError(pt::DeckConfError {
+                kind: match kind {
+                    DeckConfErrorKind::EmptyName => pt::deck_conf_error::Kind::EmptyName,
+                    DeckConfErrorKind::InvalidLearningSteps => {
+                        pt::deck_conf_error::Kind::InvalidLearningSteps
+                    }
+                    DeckConfErrorKind::NoLearningSteps => {
+                        pt::deck_conf_error::Kind::NoLearningSteps
+                    }
+                    DeckConfErrorKind::InvalidRelearningSteps => {
+                        pt::deck_conf_error::Kind::InvalidRelearningSteps
+                    }
+                } as i32,
+            }),
         };
 
-        pt::BackendError { value: Some(value) }
+        pt::BackendError {
+            value: Some(value),
+            localized,
+        }
     }
 }
 
@@ -41,12 +272,38 @@ impl std::convert::From<AnkiError> for pt::backend_output::Value {
 }
 
 impl Backend {
-    pub fn new<P: Into<PathBuf>>(path: P) -> Backend {
-        Backend { path: path.into() }
+    pub fn new<P: Into<PathBuf>>(col_path: P, media_folder: P, media_db: P) -> Backend {
+        Backend {
+            col_path: col_path.into(),
+            media_folder: media_folder.into(),
+            media_db: media_db.into(),
+            progress: ProgressHandle::default(),
+            media_progress: ProgressHandle::default(),
+            media_lock: Mutex::new(()),
+            col_lock: Mutex::new(None),
+            undo: Mutex::new(UndoManager::default()),
+            backup_task: Mutex::new(None),
+            snapshots: Mutex::new(HashMap::new()),
+            next_snapshot: AtomicU32::new(1),
+            i18n: Mutex::new(I18n::default()),
+        }
+    }
+
+    /// A handle for following the progress of long-running operations,
+    /// such as syncing and checking the database, and aborting them.
+    pub fn progress_handle(&self) -> ProgressHandle {
+        self.progress.clone()
     }
 
-    /// Decode a request, process it, and return the encoded result.
-    pub fn run_command_bytes(&mut self, req: &[u8]) -> Vec<u8> {
+    /// Like progress_handle(), but for media syncing, so it can be followed
+    /// and aborted separately from operations on the collection.
+    pub fn media_progress_handle(&self) -> ProgressHandle {
+        self.media_progress.clone()
+    }
+
+    /// Decode a request, process it, and return the encoded result. Can be
+    /// called from several threads at once.
+    pub fn run_command_bytes(&self, req: &[u8]) -> Vec<u8> {
         let mut buf = vec![];
 
         let req = match pt::BackendInput::decode(req) {
@@ -71,7 +328,12 @@ impl Backend {
         let oval = if let Some(ival) = input.value {
             match self.run_command_inner(ival) {
                 Ok(output) => output,
-                Err(err) => err.into(),
+                Err(err) => {
+                    let localized = err.localized_description(&self.i18n.lock().unwrap());
+                    let mut err: pt::BackendError = err.into();
+                    err.localized = localized;
+                    pt::backend_output::Value::Error(err)
+                }
             }
         } else {
             AnkiError::invalid_input("unrecognized backend input value").into()
@@ -92,16 +354,278 @@ impl Backend {
             Value::SchedTimingToday(input) => {
                 OValue::SchedTimingToday(self.sched_timing_today(input))
             }
-            Value::DeckTree(_) => todo!(),
-            Value::FindCards(_) => todo!(),
-            Value::BrowserRows(_) => todo!(),
-            Value::RenderCard(input) => OValue::RenderCard(self.render_template(input)?),
+            Value::LocalSchedTimingToday(input) => {
+                OValue::LocalSchedTimingToday(self.local_sched_timing_today(input))
+            }
+            Value::DeckTree(input) => OValue::DeckTree(pt::DeckTreeOut {
+                top: self.deck_due_counts(input).top,
+            }),
+            Value::FindCards(input) => OValue::FindCards(self.find_cards(input)?),
+            Value::BrowserRows(input) => OValue::BrowserRows(self.browser_rows(input)?),
+            Value::RenderCard(input) => OValue::RenderCard(self.render_template(input, false)),
+            Value::RenderPreview(input) => OValue::RenderPreview(self.render_template(input, true)),
             Value::LocalMinutesWest(stamp) => {
                 OValue::LocalMinutesWest(local_minutes_west_for_stamp(stamp))
             }
             Value::StripAvTags(text) => OValue::StripAvTags(strip_av_tags(&text).into()),
             Value::GetAvTags(text) => OValue::GetAvTags(self.get_av_tags(&text)),
             Value::FlagAvTags(text) => OValue::FlagAvTags(flag_av_tags(&text).into()),
+            Value::SanitizeHtml(text) => OValue::SanitizeHtml(sanitize_html(&text).into()),
+            Value::Ruby(input) => OValue::Ruby(self.ruby(input)),
+            Value::NormalizeToNfc(text) => OValue::NormalizeToNfc(normalize_to_nfc(&text).into()),
+            Value::ExtractLatex(input) => OValue::ExtractLatex(self.extract_latex(input)),
+            Value::RenderLatex(input) => OValue::RenderLatex(self.render_latex(input)),
+            Value::CompareAnswer(input) => {
+                OValue::CompareAnswer(compare_answer(&input.expected, &input.provided))
+            }
+            Value::RenderCloze(input) => OValue::RenderCloze(
+                render_cloze(&input.text, input.ordinal as u16, input.question_side).into(),
+            ),
+            Value::SortFieldText(html) => OValue::SortFieldText(sort_field_text(&html).into()),
+            Value::FindAndReplace(input) => OValue::FindAndReplace(self.find_and_replace(input)?),
+            Value::FieldChecksum(text) => OValue::FieldChecksum(field_checksum(&text)),
+            Value::HtmlToText(input) => OValue::HtmlToText(self.html_to_text(input)),
+            Value::ApplyFilters(input) => OValue::ApplyFilters(self.apply_filters(input)),
+            Value::CheckTemplate(input) => OValue::CheckTemplate(self.check_template(input)),
+            Value::CardChanges(input) => OValue::CardChanges(self.card_changes(input)),
+            Value::RenameField(input) => OValue::RenameField(self.rename_field(input)),
+            Value::CheckMedia(input) => OValue::CheckMedia(self.check_media(input)?),
+            Value::TrashMediaFiles(input) => {
+                self.trash_media_files(input)?;
+                OValue::TrashMediaFiles(pt::Empty {})
+            }
+            Value::MediaTrashContents(_) => {
+                OValue::MediaTrashContents(self.media_trash_contents()?)
+            }
+            Value::RestoreMediaTrash(input) => {
+                OValue::RestoreMediaTrash(self.restore_media_trash(input)?)
+            }
+            Value::EmptyMediaTrash(_) => {
+                let _guard = self.media_lock.lock().unwrap();
+                self.media_manager()?.empty_trash()?;
+                OValue::EmptyMediaTrash(pt::Empty {})
+            }
+            Value::AnswerCard(input) => OValue::AnswerCard(self.answer_card(input)?),
+            Value::EmptyFilteredDeck(input) => {
+                self.empty_filtered_deck(input)?;
+                OValue::EmptyFilteredDeck(pt::Empty {})
+            }
+            Value::FillFilteredDeck(input) => {
+                OValue::FillFilteredDeck(self.fill_filtered_deck(input)?)
+            }
+            Value::SetDueDate(input) => OValue::SetDueDate(self.set_due_date(input)?),
+            Value::RestoreCardSchedules(input) => {
+                let cards: Vec<_> = input
+                    .cards
+                    .into_iter()
+                    .map(card_snapshot_from_proto)
+                    .collect();
+                restore_card_schedules(&self.col_path, &cards)?;
+                OValue::RestoreCardSchedules(pt::Empty {})
+            }
+            Value::BuryOrSuspendCards(input) => {
+                OValue::BuryOrSuspendCards(self.bury_or_suspend_cards(input)?)
+            }
+            Value::UnsuspendCards(input) => {
+                let previous =
+                    unsuspend_cards(&self.col_path, &input.card_ids, input.usn, input.mtime_secs)?;
+                self.add_undo_op("Unsuspend", &previous);
+                OValue::UnsuspendCards(card_schedules_to_proto(previous))
+            }
+            Value::UnburyCards(input) => OValue::UnburyCards(self.unbury_cards(input)?),
+            Value::BurySiblings(input) => OValue::BurySiblings(self.bury_siblings(input)?),
+            Value::DeckDueCounts(input) => OValue::DeckDueCounts(self.deck_due_counts(input)),
+            Value::NewCountForActiveDecks(input) => {
+                OValue::NewCountForActiveDecks(new_count_for_active_decks(
+                    &deck_due_inputs_from_proto(input.decks),
+                    &input.active_deck_ids,
+                ))
+            }
+            Value::UndoStatus(_) => OValue::UndoStatus(self.undo_status()),
+            Value::Undo(_) => OValue::Undo(self.undo_or_redo(false)?),
+            Value::Redo(_) => OValue::Redo(self.undo_or_redo(true)?),
+            Value::ClearUndo(_) => {
+                self.undo.lock().unwrap().clear();
+                OValue::ClearUndo(pt::Empty {})
+            }
+            Value::SetUndoSteps(steps) => {
+                self.undo.lock().unwrap().set_max_steps(steps as usize);
+                OValue::SetUndoSteps(pt::Empty {})
+            }
+            Value::BackupCollection(input) => {
+                self.backup_collection(input)?;
+                OValue::BackupCollection(pt::Empty {})
+            }
+            Value::AwaitBackupCompletion(_) => {
+                self.await_backup_completion()?;
+                OValue::AwaitBackupCompletion(pt::Empty {})
+            }
+            Value::ListBackups(folder) => OValue::ListBackups(pt::ListBackupsOut {
+                paths: list_backups(Path::new(&folder))?
+                    .into_iter()
+                    .map(|backup| backup.path.to_string_lossy().into())
+                    .collect(),
+            }),
+            Value::RestoreBackup(path) => {
+                self.await_backup_completion()?;
+                restore_backup(Path::new(&path), &self.col_path)?;
+                OValue::RestoreBackup(pt::Empty {})
+            }
+            Value::CheckDatabase(input) => OValue::CheckDatabase(self.check_database(input)?),
+            Value::Optimize(_) => OValue::Optimize(self.optimize()?),
+            Value::OpenSnapshot(_) => OValue::OpenSnapshot(self.open_snapshot()?),
+            Value::SnapshotQuery(input) => OValue::SnapshotQuery(self.snapshot_query(input)?),
+            Value::CloseSnapshot(handle) => {
+                self.snapshots.lock().unwrap().remove(&handle);
+                OValue::CloseSnapshot(pt::Empty {})
+            }
+            Value::OpenCollection(_) => OValue::OpenCollection(pt::OpenCollectionOut {
+                recovered: open_collection(&self.col_path, &mut self.col_lock.lock().unwrap())?,
+            }),
+            Value::CloseCollection(input) => {
+                close_collection(
+                    &self.col_path,
+                    &mut self.col_lock.lock().unwrap(),
+                    input.downgrade,
+                )?;
+                OValue::CloseCollection(pt::Empty {})
+            }
+            Value::SearchCards(input) => OValue::SearchCards(self.search_cards(input)?),
+            Value::SearchNotes(input) => OValue::SearchNotes(self.search_notes(input)?),
+            Value::SearchCardsPage(input) => {
+                OValue::SearchCardsPage(self.search_page(input, search_cards_page)?)
+            }
+            Value::SearchNotesPage(input) => {
+                OValue::SearchNotesPage(self.search_page(input, search_notes_page)?)
+            }
+            Value::SetFulltextIndex(enabled) => {
+                let storage = SqliteStorage::open(&self.col_path)?;
+                storage.set_fulltext_index_enabled(enabled)?;
+                OValue::SetFulltextIndex(pt::Empty {})
+            }
+            Value::FulltextIndexEnabled(_) => OValue::FulltextIndexEnabled(
+                SqliteStorage::open(&self.col_path)?.fulltext_index_enabled()?,
+            ),
+            Value::GetSavedSearches(_) => OValue::GetSavedSearches(self.saved_searches()?),
+            Value::SaveSearch(input) => OValue::SaveSearch(self.save_search(input)?),
+            Value::RenameSavedSearch(input) => {
+                OValue::RenameSavedSearch(self.rename_saved_search(input)?)
+            }
+            Value::RemoveSavedSearch(name) => {
+                OValue::RemoveSavedSearch(self.remove_saved_search(&name)?)
+            }
+            Value::FindDuplicates(input) => OValue::FindDuplicates(self.find_duplicates(input)?),
+            Value::TagDuplicates(input) => OValue::TagDuplicates(self.tag_duplicates(input)?),
+            Value::TagTree(_) => OValue::TagTree(self.tag_tree()?),
+            Value::TagUsage(_) => OValue::TagUsage(self.tag_usage()?),
+            Value::ClearUnusedTags(usn) => OValue::ClearUnusedTags(self.clear_unused_tags(usn)?),
+            Value::RenameTag(input) => {
+                OValue::RenameTag(self.bulk_edit("Rename Tag", |storage| {
+                    rename_tag_in_notes(
+                        storage,
+                        &input.old_name,
+                        &input.new_name,
+                        input.usn,
+                        input.mtime_secs,
+                    )
+                })?)
+            }
+            Value::AddNoteTags(input) => {
+                OValue::AddNoteTags(self.bulk_edit("Add Tags", |storage| {
+                    add_tags_to_notes(
+                        storage,
+                        &input.note_ids,
+                        &input.tags,
+                        input.usn,
+                        input.mtime_secs,
+                    )
+                })?)
+            }
+            Value::RemoveNoteTags(input) => {
+                OValue::RemoveNoteTags(self.bulk_edit("Delete Tags", |storage| {
+                    remove_tags_from_notes(
+                        storage,
+                        &input.note_ids,
+                        &input.tags,
+                        input.usn,
+                        input.mtime_secs,
+                    )
+                })?)
+            }
+            Value::SetDeck(input) => OValue::SetDeck(self.bulk_edit("Change Deck", |storage| {
+                set_deck(
+                    storage,
+                    &input.card_ids,
+                    input.deck_id,
+                    input.usn,
+                    input.mtime_secs,
+                )
+            })?),
+            Value::SetFlag(input) => OValue::SetFlag(self.bulk_edit("Flag", |storage| {
+                set_flag(
+                    storage,
+                    &input.card_ids,
+                    input.flag,
+                    input.usn,
+                    input.mtime_secs,
+                )
+            })?),
+            Value::AddMediaFile(input) => OValue::AddMediaFile(self.add_media_file(input)?),
+            Value::SyncMedia(input) => OValue::SyncMedia(self.sync_media(input)?),
+            Value::SyncLogin(input) => OValue::SyncLogin(self.sync_login(input)?),
+            Value::SyncCollection(input) => OValue::SyncCollection(self.sync_collection(input)?),
+            Value::FullUpload(input) => OValue::FullUpload(self.full_upload(input)?),
+            Value::FullDownload(input) => OValue::FullDownload(self.full_download(input)?),
+            Value::FullSyncPreview(input) => {
+                OValue::FullSyncPreview(self.full_sync_preview(input)?)
+            }
+            Value::ExportPackage(input) => OValue::ExportPackage(self.export_package(input)?),
+            Value::ImportPackage(input) => OValue::ImportPackage(self.import_package(input)?),
+            Value::ImportCollectionPackage(path) => {
+                self.import_collection_package(&path)?;
+                OValue::ImportCollectionPackage(pt::Empty {})
+            }
+            Value::PreviewTextImport(input) => {
+                OValue::PreviewTextImport(self.preview_text_import(input)?)
+            }
+            Value::ImportText(input) => OValue::ImportText(self.import_text(input)?),
+            Value::ImportForeign(input) => OValue::ImportForeign(self.import_foreign(input)?),
+            Value::ExportNotesAsText(input) => {
+                OValue::ExportNotesAsText(self.export_notes_as_text(input)?)
+            }
+            Value::SetLogging(input) => {
+                let path = Some(Path::new(&input.path)).filter(|_| !input.path.is_empty());
+                set_up_logging(path, &input.level)?;
+                OValue::SetLogging(pt::Empty {})
+            }
+            Value::SetLanguages(input) => {
+                self.set_languages(input);
+                OValue::SetLanguages(pt::Empty {})
+            }
+            Value::TranslateString(input) => OValue::TranslateString(self.translate_string(input)),
+            Value::AddNote(input) => OValue::AddNote(self.add_note(input)?),
+            Value::UpdateNote(input) => OValue::UpdateNote(self.update_note(input)?),
+            Value::UpdateNotetypeSchema(input) => {
+                OValue::UpdateNotetypeSchema(self.update_notetype_schema(input)?)
+            }
+            Value::RenameDeck(input) => OValue::RenameDeck(self.rename_deck(input)?),
+            Value::AddOrUpdateDeckConf(input) => {
+                OValue::AddOrUpdateDeckConf(self.add_or_update_deck_conf(input)?)
+            }
+            Value::RemoveDeckConf(input) => {
+                self.remove_deck_conf(input)?;
+                OValue::RemoveDeckConf(pt::Empty {})
+            }
+            Value::SetDeckConf(input) => OValue::SetDeckConf(self.set_deck_conf(input)?),
+            Value::DeckConfForDeck(deck_id) => {
+                OValue::DeckConfForDeck(self.deck_conf_for_deck(deck_id)?)
+            }
+            Value::ChangeNotetype(input) => {
+                self.change_notetype(input)?;
+                OValue::ChangeNotetype(pt::Empty {})
+            }
+            Value::CardStats(input) => OValue::CardStats(self.card_stats(input)?),
+            Value::Graphs(input) => OValue::Graphs(self.graphs(input)?),
         })
     }
 
@@ -160,7 +684,23 @@ impl Backend {
         }
     }
 
-    fn render_template(&self, input: pt::RenderCardIn) -> Result<pt::RenderCardOut> {
+    fn local_sched_timing_today(
+        &self,
+        input: pt::LocalSchedTimingTodayIn,
+    ) -> pt::SchedTimingTodayOut {
+        let today = local_sched_timing_today(
+            input.created_secs,
+            input.now_secs,
+            input.rollover_hour as i8,
+        );
+        pt::SchedTimingTodayOut {
+            days_elapsed: today.days_elapsed,
+            next_day_at: today.next_day_at,
+        }
+    }
+
+    /// If `preview` is true, empty fields are replaced with placeholders.
+    fn render_template(&self, input: pt::RenderCardIn, preview: bool) -> pt::RenderCardOut {
         // convert string map to &str
         let fields: HashMap<_, _> = input
             .fields
@@ -168,71 +708,1919 @@ impl Backend {
             .map(|(k, v)| (k.as_ref(), v.as_ref()))
             .collect();
 
+        let card = CardContext {
+            card_ord: input.card_ordinal as u16,
+            tags: &input.tags,
+            notetype_name: &input.notetype_name,
+            deck_name: &input.deck_name,
+            template_name: &input.template_name,
+            card_flags: input.card_flags,
+            markdown_fields: &input.markdown_fields,
+        };
+
         // render
-        let (qnodes, anodes) = render_card(
+        let render = if preview {
+            render_card_preview
+        } else {
+            render_card_sides
+        };
+        let rendered = render(
             &input.question_template,
             &input.answer_template,
             &fields,
-            input.card_ordinal as u16,
-        )?;
+            &card,
+        );
+        let (question_nodes, question_error) = rendered_side_to_proto(rendered.question);
+        let (answer_nodes, answer_error) = rendered_side_to_proto(rendered.answer);
 
         // return
-        Ok(pt::RenderCardOut {
-            question_nodes: rendered_nodes_to_proto(qnodes),
-            answer_nodes: rendered_nodes_to_proto(anodes),
+        pt::RenderCardOut {
+            question_nodes,
+            answer_nodes,
+            question_error,
+            answer_error,
+        }
+    }
+
+    /// Continue applying built in filters after the calling code has
+    /// handled a custom one.
+    fn apply_filters(&self, input: pt::ApplyFiltersIn) -> pt::ApplyFiltersOut {
+        let filters: Vec<&str> = input.filters.iter().map(AsRef::as_ref).collect();
+        let context = RenderContext {
+            fields: &Default::default(),
+            nonempty_fields: &Default::default(),
+            question_side: input.question_side,
+            card_ord: input.card_ordinal as u16,
+            front_text: None,
+        };
+        let (text, remaining_filters) =
+            apply_filters(&input.text, &filters, &input.field_name, &context);
+
+        pt::ApplyFiltersOut {
+            text: text.into(),
+            remaining_filters,
+        }
+    }
+
+    fn check_template(&self, input: pt::CheckTemplateIn) -> pt::CheckTemplateOut {
+        use pt::template_diagnostic::Kind;
+        let field_names: HashSet<&str> = input.field_names.iter().map(AsRef::as_ref).collect();
+        let diagnostics = check_template(&input.template, &field_names, input.question_side)
+            .into_iter()
+            .map(|diag| {
+                let (kind, name) = match &diag.error {
+                    TemplateError::NoClosingBrackets(_) => (Kind::NoClosingBrackets, ""),
+                    TemplateError::ConditionalNotClosed(name) => {
+                        (Kind::ConditionalNotClosed, name.as_str())
+                    }
+                    TemplateError::ConditionalNotOpen(name) => {
+                        (Kind::ConditionalNotOpen, name.as_str())
+                    }
+                    TemplateError::FieldNotFound { field, .. } => {
+                        (Kind::FieldNotFound, field.as_str())
+                    }
+                    TemplateError::EmptyFront => (Kind::EmptyFront, ""),
+                };
+                pt::TemplateDiagnostic {
+                    kind: kind as i32,
+                    name: name.to_string(),
+                    line: diag.line as u32,
+                    column: diag.column as u32,
+                    message: match AnkiError::from(diag.error) {
+                        AnkiError::TemplateError { info } => info,
+                        other => other.to_string(),
+                    },
+                }
+            })
+            .collect();
+
+        pt::CheckTemplateOut { diagnostics }
+    }
+
+    fn card_changes(&self, input: pt::CardChangesIn) -> pt::CardChangesOut {
+        let fronts: Vec<&str> = input.template_fronts.iter().map(AsRef::as_ref).collect();
+        let field_names: Vec<&str> = input.field_names.iter().map(AsRef::as_ref).collect();
+        let ctx = if input.is_cloze {
+            CardGenContext::new_cloze(fronts.first().copied().unwrap_or_default(), &field_names)
+        } else {
+            CardGenContext::new_standard(&fronts, &field_names)
+        };
+
+        let fields: Vec<&str> = input.fields.iter().map(AsRef::as_ref).collect();
+        let existing: HashSet<u16> = input.existing_ords.iter().map(|&ord| ord as u16).collect();
+        let changes = ctx.card_changes(&fields, &existing);
+
+        pt::CardChangesOut {
+            to_add: changes.to_add.into_iter().map(Into::into).collect(),
+            to_remove: changes.to_remove.into_iter().map(Into::into).collect(),
+        }
+    }
+
+    fn rename_field(&self, input: pt::RenameFieldIn) -> pt::RenameFieldOut {
+        let new_name = if input.new_name.is_empty() {
+            None
+        } else {
+            Some(input.new_name.as_str())
+        };
+        let templates = input
+            .templates
+            .iter()
+            .map(|tmpl| rename_field(tmpl, &input.old_name, new_name).into())
+            .collect();
+
+        pt::RenameFieldOut { templates }
+    }
+
+    fn media_manager(&self) -> Result<MediaManager> {
+        MediaManager::new(&self.media_folder, &self.media_db)
+    }
+
+    fn check_media(&self, input: pt::CheckMediaIn) -> Result<pt::CheckMediaOut> {
+        self.progress.reset();
+        let _guard = self.media_lock.lock().unwrap();
+        let output =
+            self.media_manager()?
+                .check_media(&self.col_path, input.dry_run, |checked| {
+                    self.progress.update(Progress::MediaCheck(checked))
+                })?;
+        let warnings = output.warnings(input.dry_run, &self.i18n.lock().unwrap());
+
+        Ok(pt::CheckMediaOut {
+            unused: output.unused,
+            missing: output.missing,
+            invalid_names: output.invalid_names,
+            subfolders: output.subfolders,
+            renamed: output
+                .renamed
+                .into_iter()
+                .map(|(old_name, new_name)| pt::RenamedMediaFile { old_name, new_name })
+                .collect(),
+            notes_missing_latex: output.notes_missing_latex,
+            notes_with_unnormalized_refs: output.notes_with_unnormalized_refs,
+            warnings,
         })
     }
 
-    fn get_av_tags(&self, text: &str) -> pt::GetAvTagsOut {
-        let tags = av_tags_in_string(text)
-            .map(|avtag| match avtag {
-                AVTag::SoundOrVideo(file) => pt::AvTag {
-                    value: Some(pt::av_tag::Value::SoundOrVideo(file.to_string())),
-                },
-                AVTag::TextToSpeech {
-                    field_text,
-                    lang,
-                    voices,
-                    other_args,
-                } => pt::AvTag {
-                    value: Some(pt::av_tag::Value::Tts(pt::TtsTag {
-                        field_text: field_text.to_string(),
-                        lang: lang.to_string(),
-                        voices: voices.into_iter().map(ToOwned::to_owned).collect(),
-                        other_args: other_args.into_iter().map(ToOwned::to_owned).collect(),
-                    })),
-                },
+    fn trash_media_files(&self, input: pt::TrashMediaFilesIn) -> Result<()> {
+        let fnames: Vec<_> = input.fnames.iter().map(String::as_str).collect();
+        let _guard = self.media_lock.lock().unwrap();
+        self.media_manager()?.trash_files(&fnames)
+    }
+
+    fn media_trash_contents(&self) -> Result<pt::MediaTrashContentsOut> {
+        let files = self
+            .media_manager()?
+            .trash_contents()?
+            .into_iter()
+            .map(|f| pt::TrashedMediaFile {
+                fname: f.fname,
+                size: f.size,
+                mtime: f.mtime,
             })
             .collect();
 
-        pt::GetAvTagsOut { av_tags: tags }
+        Ok(pt::MediaTrashContentsOut { files })
     }
-}
 
-fn ords_hash_to_set(ords: HashSet<u16>) -> Vec<u32> {
-    ords.iter().map(|ord| *ord as u32).collect()
-}
+    fn restore_media_trash(
+        &self,
+        input: pt::TrashMediaFilesIn,
+    ) -> Result<pt::RestoreMediaTrashOut> {
+        let fnames: Vec<_> = input.fnames.iter().map(String::as_str).collect();
+        let _guard = self.media_lock.lock().unwrap();
+        let fnames = self.media_manager()?.restore_from_trash(&fnames)?;
 
-fn rendered_nodes_to_proto(nodes: Vec<RenderedNode>) -> Vec<pt::RenderedTemplateNode> {
-    nodes
-        .into_iter()
-        .map(|n| pt::RenderedTemplateNode {
-            value: Some(rendered_node_to_proto(n)),
+        Ok(pt::RestoreMediaTrashOut { fnames })
+    }
+
+    fn add_media_file(&self, input: pt::AddMediaFileIn) -> Result<String> {
+        let _guard = self.media_lock.lock().unwrap();
+        let mut mgr = self.media_manager()?;
+        if let Some(transcode) = input.transcode {
+            let config = ImageTranscodeConfig {
+                quality: transcode.quality,
+                max_dimension: transcode.max_dimension,
+                min_size: transcode.min_size as usize,
+            };
+            mgr.add_file_transcoding(&input.desired_name, &input.data, &config)
+        } else {
+            mgr.add_file(&input.desired_name, &input.data)
+        }
+    }
+
+    fn sync_media(&self, input: pt::SyncMediaIn) -> Result<pt::SyncMediaOut> {
+        self.media_progress.reset();
+        let mut mgr = self.media_manager()?;
+        let callback = |progress: &MediaSyncProgress| {
+            self.media_progress.update(Progress::MediaSync(*progress))
+        };
+
+        let mut rt = Builder::new().basic_scheduler().enable_all().build()?;
+        let outcome = rt.block_on(mgr.sync_media(
+            callback,
+            &input.endpoint,
+            &network_settings_from_proto(input.network),
+            &input.hkey,
+            &input.client_version,
+            &self.media_lock,
+        ))?;
+
+        use pt::sync_media_out::Outcome;
+        let outcome = match outcome {
+            MediaSyncOutcome::NoChanges => Outcome::NoChanges,
+            MediaSyncOutcome::Synced => Outcome::Synced,
+            MediaSyncOutcome::SanityCheckFailed => Outcome::SanityCheckFailed,
+        };
+
+        Ok(pt::SyncMediaOut {
+            outcome: outcome as i32,
         })
-        .collect()
-}
+    }
 
-fn rendered_node_to_proto(node: RenderedNode) -> pt::rendered_template_node::Value {
-    match node {
-        RenderedNode::Text { text } => pt::rendered_template_node::Value::Text(text),
-        RenderedNode::Replacement {
-            field_name,
-            current_text,
-            filters,
-        } => pt::rendered_template_node::Value::Replacement(RenderedTemplateReplacement {
-            field_name,
-            current_text,
-            filters,
-        }),
+    fn sync_login(&self, input: pt::SyncLoginIn) -> Result<pt::SyncLoginOut> {
+        let mut rt = Builder::new().basic_scheduler().enable_all().build()?;
+        let hkey = rt.block_on(sync_login(
+            &input.username,
+            &input.password,
+            &input.endpoint,
+            &network_settings_from_proto(input.network),
+            &input.client_version,
+        ))?;
+
+        Ok(pt::SyncLoginOut {
+            hkey: hkey.unwrap_or_default(),
+        })
+    }
+
+    fn sync_collection(&self, input: pt::SyncCollectionIn) -> Result<pt::SyncCollectionOut> {
+        self.progress.reset();
+        let storage = SqliteStorage::open(&self.col_path)?;
+        let callback =
+            |progress: &NormalSyncProgress| self.progress.update(Progress::NormalSync(*progress));
+
+        let mut rt = Builder::new().basic_scheduler().enable_all().build()?;
+        let output = rt.block_on(sync_collection(
+            &storage,
+            &input.hkey,
+            &input.endpoint,
+            &network_settings_from_proto(input.network),
+            &input.client_version,
+            callback,
+        ))?;
+        // the merged changes can't be undone
+        self.undo.lock().unwrap().clear();
+
+        Ok(sync_output_to_proto(output))
+    }
+
+    fn full_upload(&self, input: pt::FullUploadIn) -> Result<pt::FullSyncOut> {
+        // a backup may still be reading the file
+        self.await_backup_completion()?;
+        self.progress.reset();
+        let format = if input.package {
+            CollectionFormat::Package
+        } else {
+            CollectionFormat::Legacy
+        };
+
+        let mut rt = Builder::new().basic_scheduler().enable_all().build()?;
+        let outcome = rt.block_on(full_upload(
+            &self.col_path,
+            format,
+            &input.hkey,
+            &input.endpoint,
+            &network_settings_from_proto(input.network),
+            &input.client_version,
+            |progress| self.progress.update(Progress::FullSync(*progress)),
+        ))?;
+        self.undo.lock().unwrap().clear();
+
+        Ok(full_sync_outcome_to_proto(outcome))
+    }
+
+    fn full_download(&self, input: pt::FullDownloadIn) -> Result<pt::FullSyncOut> {
+        self.await_backup_completion()?;
+        self.progress.reset();
+        let backup_folder = if input.backup_folder.is_empty() {
+            None
+        } else {
+            Some(Path::new(&input.backup_folder))
+        };
+
+        let mut rt = Builder::new().basic_scheduler().enable_all().build()?;
+        let outcome = rt.block_on(full_download(
+            &self.col_path,
+            backup_folder,
+            &input.hkey,
+            &input.endpoint,
+            &network_settings_from_proto(input.network),
+            &input.client_version,
+            |progress| self.progress.update(Progress::FullSync(*progress)),
+        ))?;
+        self.undo.lock().unwrap().clear();
+
+        Ok(full_sync_outcome_to_proto(outcome))
+    }
+
+    fn full_sync_preview(&self, input: pt::SyncCollectionIn) -> Result<pt::FullSyncPreviewOut> {
+        let storage = SqliteStorage::open(&self.col_path)?;
+        let mut rt = Builder::new().basic_scheduler().enable_all().build()?;
+        let preview = rt.block_on(full_sync_preview(
+            &storage,
+            &input.hkey,
+            &input.endpoint,
+            &network_settings_from_proto(input.network),
+            &input.client_version,
+        ))?;
+
+        Ok(pt::FullSyncPreviewOut {
+            local: Some(change_counts_to_proto(preview.local)),
+            remote: Some(change_counts_to_proto(preview.remote)),
+            remote_replaced: preview.remote_replaced,
+        })
+    }
+
+    fn export_package(&self, input: pt::ExportPackageIn) -> Result<pt::ExportPackageOut> {
+        self.progress.reset();
+        let storage = SqliteStorage::open(&self.col_path)?;
+        let opts = PackageExportOptions {
+            limit: export_limit_from_proto(input.deck_id, input.search, input.context),
+            include_scheduling: input.include_scheduling,
+            include_media: input.include_media,
+            sharing: input.sharing.map(|sharing| SharingOptions {
+                rename_media: sharing.rename_media,
+                strip_system_tags: sharing.strip_system_tags,
+                clear_fields_matching: Some(sharing.clear_fields_matching)
+                    .filter(|pattern| !pattern.is_empty()),
+            }),
+        };
+        let out = export_package(
+            &storage,
+            &self.media_folder,
+            Path::new(&input.out_path),
+            &opts,
+            |progress| self.progress.update(Progress::Export(progress)),
+        )?;
+
+        Ok(pt::ExportPackageOut {
+            notes: out.notes,
+            cards: out.cards,
+            media_files: out.media_files,
+        })
+    }
+
+    fn import_package(&self, input: pt::ImportPackageIn) -> Result<pt::ImportPackageOut> {
+        use pt::import_package_in::DuplicateMode as DuplicateModeProto;
+        self.progress.reset();
+        let mut storage = SqliteStorage::open(&self.col_path)?;
+        let _guard = self.media_lock.lock().unwrap();
+        let duplicate_mode = match DuplicateModeProto::from_i32(input.duplicate_mode) {
+            Some(DuplicateModeProto::Skip) => DuplicateMode::Skip,
+            Some(DuplicateModeProto::Duplicate) => DuplicateMode::Duplicate,
+            _ => DuplicateMode::UpdateIfNewer,
+        };
+        let log = import_package(
+            &mut storage,
+            &self.media_folder,
+            Path::new(&input.package_path),
+            &PackageImportOptions { duplicate_mode },
+            |progress| self.progress.update(Progress::Import(progress)),
+        )?;
+
+        let notes_to_proto = |notes: Vec<LoggedNote>| -> Vec<pt::ImportedNote> {
+            notes
+                .into_iter()
+                .map(|note| pt::ImportedNote {
+                    id: note.id,
+                    fields: note.fields,
+                })
+                .collect()
+        };
+        Ok(pt::ImportPackageOut {
+            found_notes: log.found_notes,
+            added: notes_to_proto(log.added),
+            updated: notes_to_proto(log.updated),
+            duplicate: notes_to_proto(log.duplicate),
+            conflicting: notes_to_proto(log.conflicting),
+            media_files: log.media_files,
+        })
+    }
+
+    fn import_collection_package(&self, path: &str) -> Result<()> {
+        self.progress.reset();
+        self.await_backup_completion()?;
+        let _guard = self.media_lock.lock().unwrap();
+        import_collection_package(
+            &self.col_path,
+            &self.media_folder,
+            Path::new(path),
+            |progress| self.progress.update(Progress::Import(progress)),
+        )
+    }
+
+    fn preview_text_import(
+        &self,
+        input: pt::TextImportPreviewIn,
+    ) -> Result<pt::TextImportPreviewOut> {
+        let file = read_text_file(
+            Path::new(&input.path),
+            delimiter_from_proto(&input.delimiter)?,
+            Some(input.max_rows as usize),
+        )?;
+        Ok(pt::TextImportPreviewOut {
+            delimiter: file.delimiter.to_string(),
+            encoding: file.encoding.into(),
+            tags: file.tags,
+            column_count: file.column_count as u32,
+            rows: file
+                .rows
+                .into_iter()
+                .map(|fields| pt::TextRow { fields })
+                .collect(),
+        })
+    }
+
+    fn import_text(&self, input: pt::ImportTextIn) -> Result<pt::ImportTextOut> {
+        use pt::import_text_in::DuplicateMode as DuplicateModeProto;
+        use pt::text_column_mapping::Kind;
+        use pt::text_import_problem::Kind as ProblemKind;
+        let columns = input
+            .columns
+            .iter()
+            .map(|column| match Kind::from_i32(column.kind) {
+                Some(Kind::Field) => ColumnMapping::Field(column.field_ord as usize),
+                Some(Kind::Tags) => ColumnMapping::Tags,
+                Some(Kind::Deck) => ColumnMapping::Deck,
+                Some(Kind::Guid) => ColumnMapping::Guid,
+                _ => ColumnMapping::Ignored,
+            })
+            .collect();
+        let opts = TextImportOptions {
+            notetype_id: input.notetype_id,
+            deck_id: input.deck_id,
+            delimiter: delimiter_from_proto(&input.delimiter)?,
+            columns,
+            allow_html: input.allow_html,
+            duplicate_mode: match DuplicateModeProto::from_i32(input.duplicate_mode) {
+                Some(DuplicateModeProto::Skip) => TextDuplicateMode::Skip,
+                Some(DuplicateModeProto::Duplicate) => TextDuplicateMode::Duplicate,
+                _ => TextDuplicateMode::Update,
+            },
+            tags_for_updated: input.tags_for_updated,
+        };
+        let mut storage = SqliteStorage::open(&self.col_path)?;
+        let log = import_text_file(&mut storage, Path::new(&input.path), &opts)?;
+
+        let problems = log
+            .problems
+            .into_iter()
+            .map(|problem| {
+                let (kind, fields) = match problem {
+                    RowProblem::WrongFieldCount(fields) => (ProblemKind::WrongFieldCount, fields),
+                    RowProblem::EmptyFirstField(fields) => (ProblemKind::EmptyFirstField, fields),
+                    RowProblem::Repeated(key) => (ProblemKind::Repeated, vec![key]),
+                    RowProblem::NotetypeMismatch(guid) => {
+                        (ProblemKind::NotetypeMismatch, vec![guid])
+                    }
+                };
+                pt::TextImportProblem {
+                    kind: kind as i32,
+                    fields,
+                }
+            })
+            .collect();
+        Ok(pt::ImportTextOut {
+            column_count: log.column_count as u32,
+            problems,
+            parse_error: log.parse_error.unwrap_or_default(),
+            added: log.added,
+            updated: log.updated,
+            unchanged: log.unchanged,
+            matched: log.matched,
+            without_cards: log.without_cards,
+            note_ids: log.note_ids,
+        })
+    }
+
+    fn import_foreign(&self, input: pt::ImportForeignIn) -> Result<pt::ImportForeignOut> {
+        use pt::import_foreign_in::Kind;
+        let mut storage = SqliteStorage::open(&self.col_path)?;
+        let path = Path::new(&input.path);
+        let log = match Kind::from_i32(input.kind) {
+            Some(Kind::SupermemoXml) => import_supermemo_xml(&mut storage, path, input.deck_id)?,
+            _ => import_mnemosyne(&mut storage, path, input.deck_id)?,
+        };
+        Ok(pt::ImportForeignOut {
+            notes_added: log.notes_added,
+            without_cards: log.without_cards,
+            unknown_version: log.unknown_version,
+        })
+    }
+
+    fn export_notes_as_text(&self, input: pt::ExportNotesAsTextIn) -> Result<u32> {
+        let storage = SqliteStorage::open(&self.col_path)?;
+        let opts = TextExportOptions {
+            limit: export_limit_from_proto(input.deck_id, input.search, input.context),
+            include_html: input.include_html,
+            include_guid: input.include_guid,
+            include_notetype: input.include_notetype,
+            include_deck: input.include_deck,
+            include_tags: input.include_tags,
+        };
+        export_notes_as_text(&storage, Path::new(&input.out_path), &opts)
+    }
+
+    fn ruby(&self, input: pt::RubyIn) -> String {
+        use pt::ruby_in::Mode;
+        let text = &input.text;
+        match Mode::from_i32(input.mode).unwrap_or(Mode::Furigana) {
+            Mode::Furigana => furigana_to_ruby(text),
+            Mode::Kana => kana_only(text),
+            Mode::Kanji => kanji_only(text),
+        }
+        .into()
+    }
+
+    fn html_to_text(&self, input: pt::HtmlToTextIn) -> String {
+        use pt::html_to_text_in::Layout;
+        let layout = match Layout::from_i32(input.layout).unwrap_or(Layout::SingleLine) {
+            Layout::SingleLine => TextLayout::SingleLine,
+            Layout::Tables => TextLayout::Tables,
+        };
+        html_to_text(&input.text, layout)
+    }
+
+    fn extract_latex(&self, input: pt::ExtractLatexIn) -> pt::ExtractLatexOut {
+        let (text, extracted) = extract_latex(&input.text, input.svg);
+
+        pt::ExtractLatexOut {
+            text: text.into(),
+            latex: extracted
+                .into_iter()
+                .map(|e: ExtractedLatex| pt::ExtractedLatex {
+                    filename: e.fname,
+                    latex_body: e.latex,
+                })
+                .collect(),
+        }
     }
+
+    fn render_latex(&self, input: pt::RenderLatexIn) -> pt::RenderLatexOut {
+        use pt::latex_image_error::Kind;
+        let opts = LatexOptions {
+            svg: input.svg,
+            header: &input.header,
+            footer: &input.footer,
+            build: input.build,
+            commands: input.commands.into_iter().map(|c| c.args).collect(),
+        };
+        let (text, errors) = render_latex(&input.text, &self.media_folder, &opts);
+
+        pt::RenderLatexOut {
+            text: text.into(),
+            errors: errors
+                .into_iter()
+                .map(|e| {
+                    let (kind, command, log) = match e.error {
+                        LatexError::ForbiddenCommand(cmd) => {
+                            (Kind::ForbiddenCommand, cmd, "".into())
+                        }
+                        LatexError::CommandNotFound(cmd) => (Kind::CommandNotFound, cmd, "".into()),
+                        LatexError::CommandFailed { command, log } => {
+                            (Kind::CommandFailed, command, log)
+                        }
+                        LatexError::IOError(info) => (Kind::IoError, "".into(), info),
+                    };
+                    pt::LatexImageError {
+                        filename: e.fname,
+                        kind: kind as i32,
+                        command,
+                        log,
+                    }
+                })
+                .collect(),
+        }
+    }
+
+    fn answer_card(&self, input: pt::AnswerCardIn) -> Result<pt::AnswerCardOut> {
+        let mut card = card_state_from_proto(input.card.unwrap_or_default())?;
+        let config = scheduling_config_from_proto(input.config.unwrap_or_default())?;
+        let timing = SchedTimingToday {
+            days_elapsed: input.days_elapsed,
+            next_day_at: input.next_day_at,
+        };
+        let load_balancer = match input.load_balancer {
+            Some(lb) => {
+                let free_weekdays: Vec<u8> = lb.free_weekdays.iter().map(|d| *d as u8).collect();
+                Some(LoadBalancer::from_collection(
+                    &self.col_path,
+                    i64::from(input.days_elapsed),
+                    lb.today_weekday as u8,
+                    &free_weekdays,
+                )?)
+            }
+            None => None,
+        };
+        let outcome = answer_card(
+            &mut card,
+            input.ease as u8,
+            &config,
+            &timing,
+            input.now_secs,
+            load_balancer.as_ref(),
+            &mut rand::thread_rng(),
+        )?;
+
+        let note_tags = &input.note_tags;
+
+        Ok(pt::AnswerCardOut {
+            card: Some(card_state_to_proto(&card)),
+            revlog: outcome.revlog.map(|r| pt::RevlogAnswer {
+                ease: r.ease as u32,
+                interval: r.interval,
+                last_interval: r.last_interval,
+                ease_factor: r.ease_factor as u32,
+                review_kind: r.review_kind as u32,
+            }),
+            leech: outcome.leech.map(|leech| pt::LeechEvent {
+                suspended: leech.suspended,
+                note_tags: add_leech_tag(note_tags),
+            }),
+        })
+    }
+
+    fn empty_filtered_deck(&self, input: pt::EmptyFilteredDeckIn) -> Result<()> {
+        if input.card_ids.is_empty() {
+            empty_filtered_deck(&self.col_path, input.deck_id, input.usn)
+        } else {
+            remove_from_filtered_decks(&self.col_path, &input.card_ids, input.usn)
+        }
+    }
+
+    fn fill_filtered_deck(&self, input: pt::FillFilteredDeckIn) -> Result<u32> {
+        let fill = FilteredDeckFill {
+            deck_id: input.deck_id,
+            terms: input
+                .terms
+                .into_iter()
+                .map(|term| FilteredSearchTerm {
+                    card_ids: term.card_ids,
+                    order: FilteredSearchOrder::from_u32(term.order),
+                    limit: term.limit,
+                })
+                .collect(),
+            reschedule: input.reschedule,
+            today: input.today,
+            usn: input.usn,
+        };
+        fill_filtered_deck(&self.col_path, &fill)
+    }
+
+    fn set_due_date(&self, input: pt::SetDueDateIn) -> Result<pt::CardSchedulesOut> {
+        let spec = DueDateSpec::parse(&input.days)?;
+        let previous = set_due_date(
+            &self.col_path,
+            &input.card_ids,
+            spec,
+            input.today,
+            input.usn,
+            input.mtime_secs,
+            &mut rand::thread_rng(),
+        )?;
+        self.add_undo_op("Set Due Date", &previous);
+
+        Ok(card_schedules_to_proto(previous))
+    }
+
+    fn bury_or_suspend_cards(
+        &self,
+        input: pt::BuryOrSuspendCardsIn,
+    ) -> Result<pt::CardSchedulesOut> {
+        use pt::bury_or_suspend_cards_in::Mode;
+        let mode = match Mode::from_i32(input.mode).unwrap_or(Mode::Suspend) {
+            Mode::Suspend => BuryOrSuspendMode::Suspend,
+            Mode::BuryUser => BuryOrSuspendMode::BuryUser,
+            Mode::BurySched => BuryOrSuspendMode::BurySched,
+        };
+        let previous = bury_or_suspend_cards(
+            &self.col_path,
+            &input.card_ids,
+            mode,
+            input.usn,
+            input.mtime_secs,
+        )?;
+        let op_name = match mode {
+            BuryOrSuspendMode::Suspend => "Suspend",
+            _ => "Bury",
+        };
+        self.add_undo_op(op_name, &previous);
+
+        Ok(card_schedules_to_proto(previous))
+    }
+
+    fn unbury_cards(&self, input: pt::UnburyCardsIn) -> Result<pt::CardSchedulesOut> {
+        use pt::unbury_cards_in::Mode;
+        let mode = match Mode::from_i32(input.mode).unwrap_or(Mode::All) {
+            Mode::All => UnburyMode::All,
+            Mode::User => UnburyMode::User,
+            Mode::Sched => UnburyMode::Sched,
+        };
+        let deck_ids = if input.deck_ids.is_empty() {
+            None
+        } else {
+            Some(input.deck_ids.as_slice())
+        };
+        let previous = unbury_cards(&self.col_path, deck_ids, mode, input.usn, input.mtime_secs)?;
+        if !input.skip_undo {
+            self.add_undo_op("Unbury", &previous);
+        }
+
+        Ok(card_schedules_to_proto(previous))
+    }
+
+    fn bury_siblings(&self, input: pt::BurySiblingsIn) -> Result<pt::BurySiblingsOut> {
+        let config = SiblingBuryConfig {
+            bury_new: input.bury_new,
+            bury_reviews: input.bury_reviews,
+        };
+        let burial = bury_siblings(
+            &self.col_path,
+            input.card_id,
+            input.note_id,
+            input.today,
+            config,
+            input.usn,
+            input.mtime_secs,
+        )?;
+
+        Ok(pt::BurySiblingsOut {
+            siblings: burial.siblings,
+            previous: burial
+                .previous
+                .into_iter()
+                .map(card_snapshot_to_proto)
+                .collect(),
+        })
+    }
+
+    fn deck_due_counts(&self, input: pt::DeckDueCountsIn) -> pt::DeckDueCountsOut {
+        let (decks, tree) =
+            deck_due_counts(&deck_due_inputs_from_proto(input.decks), input.report_limit);
+
+        pt::DeckDueCountsOut {
+            decks: decks
+                .into_iter()
+                .map(|deck| pt::DeckDueCounts {
+                    deck_id: deck.deck_id,
+                    name: deck.name,
+                    review_count: deck.review,
+                    learn_count: deck.learn,
+                    new_count: deck.new,
+                })
+                .collect(),
+            top: Some(pt::DeckTreeNode {
+                children: tree.into_iter().map(deck_tree_node_to_proto).collect(),
+                ..Default::default()
+            }),
+        }
+    }
+
+    /// Record a committed change to cards' scheduling, so it can be undone.
+    /// The name is translated by the frontend.
+    fn add_undo_op(&self, name: &str, previous: &[CardScheduleSnapshot]) {
+        self.add_undo_changes(name, vec![UndoableChange::CardSchedules(previous.to_vec())]);
+    }
+
+    fn add_undo_changes(&self, name: &str, changes: Vec<UndoableChange>) {
+        self.undo.lock().unwrap().add_op(UndoableOp {
+            name: name.into(),
+            changes,
+        });
+    }
+
+    /// Apply an edit to many notes or cards, recording it so it can be
+    /// undone. Returns the number of rows changed.
+    fn bulk_edit<F>(&self, name: &str, edit: F) -> Result<u32>
+    where
+        F: FnOnce(&mut SqliteStorage) -> Result<Vec<UndoableChange>>,
+    {
+        let mut storage = SqliteStorage::open(&self.col_path)?;
+        let changes = edit(&mut storage)?;
+        let count = changes.len() as u32;
+        self.add_undo_changes(name, changes);
+
+        Ok(count)
+    }
+
+    fn undo_status(&self) -> pt::UndoStatusOut {
+        let undo = self.undo.lock().unwrap();
+        pt::UndoStatusOut {
+            undo: undo.undo_name().unwrap_or_default().into(),
+            redo: undo.redo_name().unwrap_or_default().into(),
+        }
+    }
+
+    fn undo_or_redo(&self, redo: bool) -> Result<pt::UndoStatusOut> {
+        let mut storage = SqliteStorage::open(&self.col_path)?;
+        {
+            let mut undo = self.undo.lock().unwrap();
+            if redo {
+                undo.redo(&mut storage)?;
+            } else {
+                undo.undo(&mut storage)?;
+            }
+        }
+        Ok(self.undo_status())
+    }
+
+    /// Start backing up the closed collection in the background, after any
+    /// previous backup has finished.
+    fn backup_collection(&self, input: pt::BackupCollectionIn) -> Result<()> {
+        self.await_backup_completion()?;
+        let limits = input
+            .limits
+            .map(|limits| BackupLimits {
+                recent: limits.recent,
+                daily: limits.daily,
+                weekly: limits.weekly,
+            })
+            .unwrap_or_default();
+        let task = backup_collection(&self.col_path, Path::new(&input.backup_folder), limits)?;
+        *self.backup_task.lock().unwrap() = Some(task);
+        Ok(())
+    }
+
+    /// Wait for a backup in progress, returning any error it encountered.
+    fn await_backup_completion(&self) -> Result<()> {
+        if let Some(task) = self.backup_task.lock().unwrap().take() {
+            task.join().map_err(|_| AnkiError::IOError {
+                info: "backup thread panicked".into(),
+            })??;
+        }
+        Ok(())
+    }
+
+    fn check_database(&self, input: pt::CheckDatabaseIn) -> Result<pt::CheckDatabaseOut> {
+        self.progress.reset();
+        let mut storage = SqliteStorage::open(&self.col_path)?;
+        let out = check_database(
+            &mut storage,
+            input.today,
+            input.usn,
+            input.mtime_secs,
+            |stage| self.progress.update(Progress::DatabaseCheck(stage)),
+        )?;
+        // fixed rows may no longer match the recorded undo state
+        self.undo.lock().unwrap().clear();
+
+        Ok(pt::CheckDatabaseOut {
+            corrupt: out.corrupt,
+            notes_with_missing_notetype: out.notes_with_missing_notetype,
+            templates_with_invalid_deck: out.templates_with_invalid_deck,
+            cards_with_missing_template: out.cards_with_missing_template,
+            notes_with_wrong_field_count: out.notes_with_wrong_field_count,
+            notes_without_cards: out.notes_without_cards,
+            cards_with_missing_note: out.cards_with_missing_note,
+            cards_with_invalid_properties: out.cards_with_invalid_properties,
+            cards_with_missing_deck: out.cards_with_missing_deck,
+            new_cards_with_large_due: out.new_cards_with_large_due,
+            reviews_with_invalid_due: out.reviews_with_invalid_due,
+            cards_with_fractional_values: out.cards_with_fractional_values,
+            revlog_with_fractional_values: out.revlog_with_fractional_values,
+        })
+    }
+
+    fn optimize(&self) -> Result<pt::OptimizeOut> {
+        self.progress.reset();
+        let storage = SqliteStorage::open(&self.col_path)?;
+        let freed_bytes =
+            storage.optimize(|stage| self.progress.update(Progress::Optimize(stage)))?;
+
+        Ok(pt::OptimizeOut { freed_bytes })
+    }
+
+    fn search_cards(&self, input: pt::SearchCardsIn) -> Result<pt::SearchCardsOut> {
+        use pt::search_cards_in::SortMode as S;
+        let storage = SqliteStorage::open(&self.col_path)?;
+        let order = match input.sort_mode {
+            None | Some(S::NoOrder(_)) => SortMode::NoOrder,
+            Some(S::FromConfig(_)) => SortMode::FromConfig,
+            Some(S::Custom(clause)) => SortMode::Custom(clause),
+        };
+        let card_ids = search_cards(
+            &storage,
+            &input.search,
+            &search_context_from_proto(input.context),
+            &order,
+        )?;
+
+        Ok(pt::SearchCardsOut { card_ids })
+    }
+
+    /// Like search_cards(), but unordered.
+    fn find_cards(&self, input: pt::FindCardsIn) -> Result<pt::FindCardsOut> {
+        let storage = SqliteStorage::open(&self.col_path)?;
+        let card_ids = search_cards(
+            &storage,
+            &input.search,
+            &search_context_from_proto(input.context),
+            &SortMode::NoOrder,
+        )?;
+
+        Ok(pt::FindCardsOut { card_ids })
+    }
+
+    fn search_notes(&self, input: pt::SearchNotesIn) -> Result<pt::SearchNotesOut> {
+        let storage = SqliteStorage::open(&self.col_path)?;
+        let note_ids = search_notes(
+            &storage,
+            &input.search,
+            &search_context_from_proto(input.context),
+        )?;
+
+        Ok(pt::SearchNotesOut { note_ids })
+    }
+
+    fn search_page(
+        &self,
+        input: pt::SearchPageIn,
+        search: fn(&SqliteStorage, &str, &SearchContext, &PageSpec) -> Result<SearchPage>,
+    ) -> Result<pt::SearchPageOut> {
+        let storage = SqliteStorage::open(&self.col_path)?;
+        let page = search(
+            &storage,
+            &input.search,
+            &search_context_from_proto(input.context),
+            &PageSpec {
+                column: input.sort_column,
+                reverse: input.reverse,
+                offset: input.offset,
+                limit: input.limit,
+            },
+        )?;
+
+        Ok(pt::SearchPageOut {
+            ids: page.ids,
+            total: page.total,
+        })
+    }
+
+    fn saved_searches(&self) -> Result<pt::SavedSearchesOut> {
+        let storage = SqliteStorage::open(&self.col_path)?;
+        saved_searches_to_proto(&storage)
+    }
+
+    fn save_search(&self, input: pt::SavedSearch) -> Result<pt::SavedSearchesOut> {
+        let storage = SqliteStorage::open(&self.col_path)?;
+        save_search(&storage, &input.name, &input.search)?;
+        saved_searches_to_proto(&storage)
+    }
+
+    fn rename_saved_search(&self, input: pt::RenameSavedSearchIn) -> Result<pt::SavedSearchesOut> {
+        let storage = SqliteStorage::open(&self.col_path)?;
+        rename_saved_search(&storage, &input.old_name, &input.new_name)?;
+        saved_searches_to_proto(&storage)
+    }
+
+    fn remove_saved_search(&self, name: &str) -> Result<pt::SavedSearchesOut> {
+        let storage = SqliteStorage::open(&self.col_path)?;
+        remove_saved_search(&storage, name)?;
+        saved_searches_to_proto(&storage)
+    }
+
+    fn find_duplicates(&self, input: pt::FindDuplicatesIn) -> Result<pt::FindDuplicatesOut> {
+        let storage = SqliteStorage::open(&self.col_path)?;
+        let groups = find_duplicates(
+            &storage,
+            &search_context_from_proto(input.context),
+            non_empty(&input.field_name),
+            &input.search,
+        )?;
+
+        Ok(pt::FindDuplicatesOut {
+            groups: groups
+                .into_iter()
+                .map(|g| pt::DuplicateGroup {
+                    text: g.text,
+                    note_ids: g.note_ids,
+                })
+                .collect(),
+        })
+    }
+
+    fn tag_duplicates(&self, input: pt::TagDuplicatesIn) -> Result<u32> {
+        let query = input.query.unwrap_or_default();
+        let storage = SqliteStorage::open(&self.col_path)?;
+        let changed = tag_duplicates(
+            &storage,
+            &search_context_from_proto(query.context),
+            non_empty(&query.field_name),
+            &query.search,
+            &input.tag,
+            input.usn,
+            input.mtime_secs,
+        )?;
+
+        Ok(changed as u32)
+    }
+
+    fn tag_tree(&self) -> Result<pt::TagTreeOut> {
+        let storage = SqliteStorage::open(&self.col_path)?;
+        let nodes = tag_tree(&storage)?;
+
+        Ok(pt::TagTreeOut {
+            nodes: nodes.into_iter().map(tag_tree_node_to_proto).collect(),
+        })
+    }
+
+    fn tag_usage(&self) -> Result<pt::TagUsageOut> {
+        let storage = SqliteStorage::open(&self.col_path)?;
+        Ok(pt::TagUsageOut {
+            tags: tag_usage(&storage)?
+                .into_iter()
+                .map(|usage| pt::TagUsage {
+                    name: usage.name,
+                    note_count: usage.note_count,
+                })
+                .collect(),
+        })
+    }
+
+    fn clear_unused_tags(&self, usn: i32) -> Result<pt::ClearUnusedTagsOut> {
+        let mut storage = SqliteStorage::open(&self.col_path)?;
+        Ok(pt::ClearUnusedTagsOut {
+            removed_tags: clear_unused_tags(&mut storage, usn)?,
+        })
+    }
+
+    fn browser_rows(&self, input: pt::BrowserRowsIn) -> Result<pt::BrowserRowsOut> {
+        let storage = SqliteStorage::open(&self.col_path)?;
+        let columns: Vec<_> = input.columns.iter().map(|c| Column::from_key(c)).collect();
+        let rows = browser_rows(
+            &storage,
+            &input.card_ids,
+            &columns,
+            input.today,
+            now_millis() / 1000,
+        )?;
+
+        Ok(pt::BrowserRowsOut {
+            rows: rows
+                .into_iter()
+                .map(|row| {
+                    let (font_family, font_size) = match row.font {
+                        Some(font) => (font.family, font.size),
+                        None => ("".into(), 0),
+                    };
+                    pt::BrowserRow {
+                        card_id: row.card_id,
+                        cells: row.cells.into_iter().map(browser_cell_to_proto).collect(),
+                        queue: row.queue as i32,
+                        flag: u32::from(row.flag),
+                        marked: row.marked,
+                        rtl: row.rtl,
+                        font_family,
+                        font_size,
+                    }
+                })
+                .collect(),
+        })
+    }
+
+    /// Take a snapshot of the collection, returning a handle that can be
+    /// queried until it's closed.
+    fn open_snapshot(&self) -> Result<u32> {
+        let snapshot = CollectionSnapshot::open(&self.col_path)?;
+        let handle = self.next_snapshot.fetch_add(1, Ordering::Relaxed);
+        self.snapshots.lock().unwrap().insert(handle, snapshot);
+        Ok(handle)
+    }
+
+    fn snapshot_query(&self, input: pt::SnapshotQueryIn) -> Result<pt::SnapshotQueryOut> {
+        let args: Vec<_> = input.args.into_iter().map(sql_value_from_proto).collect();
+        let snapshots = self.snapshots.lock().unwrap();
+        let snapshot = snapshots
+            .get(&input.snapshot)
+            .ok_or_else(|| AnkiError::invalid_input("snapshot was closed"))?;
+        let rows = snapshot.query(&input.sql, &args)?;
+
+        Ok(pt::SnapshotQueryOut {
+            rows: rows
+                .into_iter()
+                .map(|row| pt::SqlRow {
+                    values: row.into_iter().map(sql_value_to_proto).collect(),
+                })
+                .collect(),
+        })
+    }
+
+    fn find_and_replace(&self, input: pt::FindAndReplaceIn) -> Result<pt::FindAndReplaceOut> {
+        let replacer = FindReplacer::new(
+            &input.search,
+            &input.replacement,
+            input.regex,
+            input.match_case,
+        )?;
+        let include_tags = input.include_tags;
+        let mut replacements = 0;
+        let notes = input
+            .notes
+            .into_iter()
+            .map(|note| {
+                let field_ord = if note.restrict_to_field {
+                    Some(note.field_ord as usize)
+                } else {
+                    None
+                };
+                let mut text = NoteText {
+                    fields: note.fields,
+                    tags: note.tags,
+                };
+                replacements += replacer.replace_in_note(&mut text, field_ord, include_tags);
+                pt::FindAndReplaceNote {
+                    fields: text.fields,
+                    tags: text.tags,
+                    restrict_to_field: note.restrict_to_field,
+                    field_ord: note.field_ord,
+                }
+            })
+            .collect();
+
+        Ok(pt::FindAndReplaceOut {
+            notes,
+            replacements: replacements as u32,
+        })
+    }
+
+    fn get_av_tags(&self, text: &str) -> pt::GetAvTagsOut {
+        let tags = av_tags_with_spans(text)
+            .map(|(span, avtag)| pt::AvTag {
+                value: Some(av_tag_to_proto(avtag)),
+                start: span.start as u32,
+                end: span.end as u32,
+            })
+            .collect();
+
+        pt::GetAvTagsOut { av_tags: tags }
+    }
+
+    fn set_languages(&self, input: pt::SetLanguagesIn) {
+        let folder = Some(Path::new(&input.ftl_folder)).filter(|_| !input.ftl_folder.is_empty());
+        *self.i18n.lock().unwrap() = I18n::new(&input.preferred_langs, folder);
+    }
+
+    fn translate_string(&self, input: pt::TranslateStringIn) -> String {
+        let args: FluentArgs = input
+            .args
+            .iter()
+            .map(|(key, arg)| {
+                let value = match &arg.value {
+                    Some(pt::translate_arg_value::Value::Str(s)) => FluentValue::from(s.as_str()),
+                    Some(pt::translate_arg_value::Value::Number(n)) => FluentValue::from(*n),
+                    None => FluentValue::from(""),
+                };
+                (key.as_str(), value)
+            })
+            .collect();
+        self.i18n.lock().unwrap().trn(&input.key, args)
+    }
+
+    fn add_note(&self, input: pt::AddNoteIn) -> Result<pt::NoteSaveOut> {
+        let mut storage = SqliteStorage::open(&self.col_path)?;
+        let mut note = note_from_proto(input.note.unwrap_or_default());
+        let outcome = add_note(
+            &mut storage,
+            &mut note,
+            input.deck_id,
+            input.usn,
+            input.mtime_secs,
+        )?;
+        Ok(self.note_saved("Add Note", note, outcome))
+    }
+
+    fn update_note(&self, input: pt::UpdateNoteIn) -> Result<pt::NoteSaveOut> {
+        let mut storage = SqliteStorage::open(&self.col_path)?;
+        let mut note = note_from_proto(input.note.unwrap_or_default());
+        let outcome = update_note(&mut storage, &mut note, input.usn, input.mtime_secs)?;
+        Ok(self.note_saved("Update Note", note, outcome))
+    }
+
+    fn update_notetype_schema(&self, input: pt::UpdateNotetypeSchemaIn) -> Result<bool> {
+        let mut notetype: NoteType = serde_json::from_str(&input.notetype_json)
+            .map_err(|e| AnkiError::invalid_input(format!("invalid note type: {}", e)))?;
+        let mut storage = SqliteStorage::open(&self.col_path)?;
+        let full_sync_required = update_notetype_schema(
+            &mut storage,
+            &mut notetype,
+            &optional_ords(&input.field_ords),
+            &optional_ords(&input.template_ords),
+            input.usn,
+            input.mtime_secs,
+        )?;
+        // the recorded changes may refer to the old fields and ordinals
+        self.undo.lock().unwrap().clear();
+
+        Ok(full_sync_required)
+    }
+
+    fn change_notetype(&self, input: pt::ChangeNotetypeIn) -> Result<()> {
+        self.bulk_edit("Change Note Type", |storage| {
+            change_notetype_of_notes(
+                storage,
+                &input.note_ids,
+                input.new_notetype_id,
+                &optional_ords(&input.new_field_ords),
+                &optional_ords(&input.new_template_ords),
+                input.usn,
+                input.mtime_secs,
+            )
+        })?;
+        Ok(())
+    }
+
+    fn card_stats(&self, input: pt::CardStatsIn) -> Result<pt::CardStatsOut> {
+        let storage = SqliteStorage::open(&self.col_path)?;
+        let stats = card_stats(&storage, input.card_id, input.today, input.now_secs)?;
+        Ok(pt::CardStatsOut {
+            card_id: stats.card_id,
+            note_id: stats.note_id,
+            added_secs: stats.added_secs,
+            first_review_secs: stats.first_review_secs.unwrap_or_default(),
+            latest_review_secs: stats.latest_review_secs.unwrap_or_default(),
+            due_secs: stats.due_secs.unwrap_or_default(),
+            interval_days: stats.interval_days.unwrap_or_default(),
+            ease_factor: stats.ease_factor.map(u32::from).unwrap_or_default(),
+            reviews: stats.reviews,
+            lapses: stats.lapses,
+            total_secs: stats.total_secs,
+            average_secs: stats.average_secs,
+            new_position: stats.new_position.unwrap_or(-1),
+            card_type_name: stats.card_type_name,
+            notetype_name: stats.notetype_name,
+            deck_name: stats.deck_name,
+            revlog: stats
+                .revlog
+                .into_iter()
+                .map(|entry| pt::CardStatsRevlogEntry {
+                    time_secs: entry.id / 1000,
+                    ease: entry.ease.into(),
+                    interval: entry.interval,
+                    ease_factor: entry.ease_factor,
+                    taken_secs: entry.taken_millis as f32 / 1000.0,
+                    review_kind: entry.review_kind.into(),
+                })
+                .collect(),
+        })
+    }
+
+    fn graphs(&self, input: pt::GraphsIn) -> Result<pt::GraphsOut> {
+        let storage = SqliteStorage::open(&self.col_path)?;
+        let spec = GraphsSpec {
+            deck_ids: input.deck_ids,
+            today: input.today,
+            next_day_at: input.next_day_at,
+            rollover_hour: input.rollover_hour as u8,
+            bucket_days: input.bucket_days,
+            buckets: if input.buckets == 0 {
+                None
+            } else {
+                Some(input.buckets)
+            },
+            v1_learning_buttons: input.v1_learning_buttons,
+        };
+        let data = graphs(&storage, &spec)?;
+        let count_points = |points: Vec<CountPoint>| -> Vec<pt::CountPoint> {
+            points
+                .into_iter()
+                .map(|p| pt::CountPoint {
+                    bucket: p.bucket,
+                    count: p.count,
+                })
+                .collect()
+        };
+        Ok(pt::GraphsOut {
+            forecast: data
+                .forecast
+                .into_iter()
+                .map(|p| pt::ForecastPoint {
+                    bucket: p.bucket,
+                    young: p.young,
+                    mature: p.mature,
+                })
+                .collect(),
+            due_tomorrow: data.due_tomorrow,
+            reviews: data
+                .reviews
+                .into_iter()
+                .map(|p| pt::ReviewsPoint {
+                    bucket: p.bucket,
+                    counts: Some(pt::ReviewCounts {
+                        learn: p.counts.learn,
+                        young: p.counts.young,
+                        mature: p.counts.mature,
+                        relearn: p.counts.relearn,
+                        filtered: p.counts.filtered,
+                    }),
+                    secs: Some(pt::ReviewTimes {
+                        learn: p.secs.learn,
+                        young: p.secs.young,
+                        mature: p.secs.mature,
+                        relearn: p.secs.relearn,
+                        filtered: p.secs.filtered,
+                    }),
+                })
+                .collect(),
+            added: count_points(data.added),
+            intervals: count_points(data.intervals),
+            interval_summary: Some(pt::IntervalSummary {
+                cards: data.interval_summary.cards,
+                average_days: data.interval_summary.average_days,
+                longest_days: data.interval_summary.longest_days,
+            }),
+            ease_factors: data
+                .ease_factors
+                .into_iter()
+                .map(|e| pt::EaseFactorCount {
+                    ease_factor: e.ease_factor,
+                    count: e.count,
+                })
+                .collect(),
+            buttons: data
+                .buttons
+                .into_iter()
+                .map(|b| pt::ButtonCount {
+                    kind: b.kind as i32,
+                    button: b.button.into(),
+                    count: b.count,
+                })
+                .collect(),
+            hours: data
+                .hours
+                .into_iter()
+                .map(|h| pt::HourPoint {
+                    hour: h.hour.into(),
+                    answers: h.answers,
+                    correct: h.correct,
+                })
+                .collect(),
+        })
+    }
+
+    fn rename_deck(&self, input: pt::RenameDeckIn) -> Result<pt::RenameDeckOut> {
+        let mut storage = SqliteStorage::open(&self.col_path)?;
+        let merged_deck_ids = rename_deck(
+            &mut storage,
+            input.deck_id,
+            &input.new_name,
+            input.usn,
+            input.mtime_secs,
+        )?;
+        if !merged_deck_ids.is_empty() {
+            // the recorded changes may refer to the removed decks
+            self.undo.lock().unwrap().clear();
+        }
+
+        Ok(pt::RenameDeckOut { merged_deck_ids })
+    }
+
+    fn add_or_update_deck_conf(&self, input: pt::AddOrUpdateDeckConfIn) -> Result<String> {
+        let mut conf: DeckConf = serde_json::from_str(&input.conf_json)
+            .map_err(|e| AnkiError::invalid_input(format!("invalid deck options: {}", e)))?;
+        let mut storage = SqliteStorage::open(&self.col_path)?;
+        if conf.id == 0 {
+            add_deck_conf(&mut storage, &mut conf, input.usn, input.mtime_secs)?;
+        } else {
+            update_deck_conf(&mut storage, &mut conf, input.usn, input.mtime_secs)?;
+        }
+        Ok(serde_json::to_string(&conf)?)
+    }
+
+    fn remove_deck_conf(&self, input: pt::RemoveDeckConfIn) -> Result<()> {
+        let mut storage = SqliteStorage::open(&self.col_path)?;
+        remove_deck_conf(&mut storage, input.conf_id, input.usn, input.mtime_secs)
+    }
+
+    fn set_deck_conf(&self, input: pt::SetDeckConfIn) -> Result<u32> {
+        let mut storage = SqliteStorage::open(&self.col_path)?;
+        let changed = set_deck_conf(
+            &mut storage,
+            input.deck_id,
+            input.conf_id,
+            input.include_children,
+            input.usn,
+            input.mtime_secs,
+        )?;
+        Ok(changed as u32)
+    }
+
+    fn deck_conf_for_deck(&self, deck_id: i64) -> Result<String> {
+        let storage = SqliteStorage::open(&self.col_path)?;
+        match deck_conf_for_deck(&storage, deck_id)? {
+            Some(conf) => Ok(serde_json::to_string(&conf)?),
+            None => Ok("".into()),
+        }
+    }
+
+    /// Record the changes so they can be undone, if there were any.
+    fn note_saved(&self, name: &str, note: Note, outcome: NoteSaveOutcome) -> pt::NoteSaveOut {
+        if !outcome.changes.is_empty() {
+            self.add_undo_changes(name, outcome.changes);
+        }
+        pt::NoteSaveOut {
+            note: Some(note_to_proto(note)),
+            added_card_ids: outcome.added_card_ids,
+            removed_card_ids: outcome.removed_card_ids,
+            missing_cloze: outcome.missing_cloze,
+        }
+    }
+}
+
+/// Ordinals with -1 standing for None.
+fn optional_ords(ords: &[i32]) -> Vec<Option<usize>> {
+    ords.iter()
+        .map(|ord| if *ord < 0 { None } else { Some(*ord as usize) })
+        .collect()
+}
+
+fn av_tag_to_proto(tag: std::result::Result<AVTag, TTSError>) -> pt::av_tag::Value {
+    match tag {
+        Ok(AVTag::SoundOrVideo(file)) => pt::av_tag::Value::SoundOrVideo(file.to_string()),
+        Ok(AVTag::TextToSpeech {
+            field_text,
+            lang,
+            voices,
+            speed,
+            pitch,
+            style,
+            other_args,
+        }) => pt::av_tag::Value::Tts(pt::TtsTag {
+            field_text: field_text.to_string(),
+            lang: lang.to_string(),
+            voices: voices.into_iter().map(ToOwned::to_owned).collect(),
+            speed: speed.unwrap_or(0.0),
+            pitch: pitch.unwrap_or(0.0),
+            style: style.unwrap_or("").to_string(),
+            other_args: other_args.into_iter().map(ToOwned::to_owned).collect(),
+        }),
+        Err(err) => pt::av_tag::Value::TtsError(pt::TtsError {
+            kind: match err {
+                TTSError::MissingLanguage => pt::tts_error::Kind::MissingLanguage,
+                TTSError::InvalidArgument { .. } => pt::tts_error::Kind::InvalidArgument,
+            } as i32,
+            message: err.to_string(),
+        }),
+    }
+}
+
+fn card_snapshot_from_proto(card: pt::CardScheduleSnapshot) -> CardScheduleSnapshot {
+    CardScheduleSnapshot {
+        id: card.id,
+        deck_id: card.deck_id,
+        ctype: card.ctype as u8,
+        queue: card.queue as i8,
+        due: card.due,
+        interval: card.interval,
+        ease_factor: card.ease_factor as u16,
+        original_due: card.original_due,
+        original_deck_id: card.original_deck_id,
+        mtime_secs: card.mtime_secs,
+        usn: card.usn,
+    }
+}
+
+fn deck_due_inputs_from_proto(decks: Vec<pt::DeckDueInput>) -> Vec<DeckDueInput> {
+    decks
+        .into_iter()
+        .map(|deck| DeckDueInput {
+            deck_id: deck.deck_id,
+            name: deck.name,
+            filtered: deck.filtered,
+            new_limit: deck.new_limit,
+            review_limit: deck.review_limit,
+            new_cards: deck.new_cards,
+            learn_cards: deck.learn_cards,
+            day_learn_cards: deck.day_learn_cards,
+            review_cards: deck.review_cards,
+            collapsed: deck.collapsed,
+        })
+        .collect()
+}
+
+fn deck_tree_node_to_proto(node: DeckTreeNode) -> pt::DeckTreeNode {
+    pt::DeckTreeNode {
+        names: vec![node.name],
+        deck_id: node.deck_id,
+        review_count: node.review,
+        learn_count: node.learn,
+        new_count: node.new,
+        children: node
+            .children
+            .into_iter()
+            .map(deck_tree_node_to_proto)
+            .collect(),
+        collapsed: node.collapsed,
+    }
+}
+
+fn card_schedules_to_proto(cards: Vec<CardScheduleSnapshot>) -> pt::CardSchedulesOut {
+    pt::CardSchedulesOut {
+        previous: cards.into_iter().map(card_snapshot_to_proto).collect(),
+    }
+}
+
+fn card_snapshot_to_proto(card: CardScheduleSnapshot) -> pt::CardScheduleSnapshot {
+    pt::CardScheduleSnapshot {
+        id: card.id,
+        deck_id: card.deck_id,
+        ctype: u32::from(card.ctype),
+        queue: i32::from(card.queue),
+        due: card.due,
+        interval: card.interval,
+        ease_factor: u32::from(card.ease_factor),
+        original_due: card.original_due,
+        original_deck_id: card.original_deck_id,
+        mtime_secs: card.mtime_secs,
+        usn: card.usn,
+    }
+}
+
+fn tag_tree_node_to_proto(node: TagTreeNode) -> pt::TagTreeNode {
+    pt::TagTreeNode {
+        name: node.name,
+        full_name: node.full_name,
+        note_count: node.note_count,
+        children: node
+            .children
+            .into_iter()
+            .map(tag_tree_node_to_proto)
+            .collect(),
+    }
+}
+
+fn ords_hash_to_set(ords: HashSet<u16>) -> Vec<u32> {
+    ords.iter().map(|ord| *ord as u32).collect()
+}
+
+fn rendered_side_to_proto(
+    side: Result<Vec<RenderedNode>>,
+) -> (Vec<pt::RenderedTemplateNode>, String) {
+    match side {
+        Ok(nodes) => (rendered_nodes_to_proto(nodes), "".into()),
+        Err(AnkiError::TemplateError { info }) => (vec![], info),
+        Err(err) => (vec![], err.to_string()),
+    }
+}
+
+fn rendered_nodes_to_proto(nodes: Vec<RenderedNode>) -> Vec<pt::RenderedTemplateNode> {
+    nodes
+        .into_iter()
+        .map(|n| pt::RenderedTemplateNode {
+            value: Some(rendered_node_to_proto(n)),
+        })
+        .collect()
+}
+
+fn rendered_node_to_proto(node: RenderedNode) -> pt::rendered_template_node::Value {
+    match node {
+        RenderedNode::Text { text } => pt::rendered_template_node::Value::Text(text),
+        RenderedNode::Replacement {
+            field_name,
+            current_text,
+            filters,
+        } => pt::rendered_template_node::Value::Replacement(RenderedTemplateReplacement {
+            field_name,
+            current_text,
+            filters,
+        }),
+    }
+}
+
+fn progress_to_proto_bytes(progress: Progress) -> Vec<u8> {
+    let proto = pt::Progress {
+        value: Some(match progress {
+            Progress::MediaSync(p) => pt::progress::Value::MediaSync(pt::MediaSyncProgress {
+                checked: p.checked as u32,
+                downloaded_files: p.downloaded_files as u32,
+                downloaded_deletions: p.downloaded_deletions as u32,
+                uploaded_files: p.uploaded_files as u32,
+                uploaded_deletions: p.uploaded_deletions as u32,
+            }),
+            Progress::Optimize(stage) => {
+                use pt::optimize_progress::Stage;
+                let stage = match stage {
+                    OptimizeStage::Vacuum => Stage::Vacuum,
+                    OptimizeStage::Analyze => Stage::Analyze,
+                };
+                pt::progress::Value::Optimize(pt::OptimizeProgress {
+                    stage: stage as i32,
+                })
+            }
+            Progress::NormalSync(p) => {
+                use pt::normal_sync_progress::Stage;
+                let stage = match p.stage {
+                    SyncStage::Login => Stage::Login,
+                    SyncStage::Meta => Stage::Meta,
+                    SyncStage::Server => Stage::Server,
+                    SyncStage::Client => Stage::Client,
+                    SyncStage::Sanity => Stage::Sanity,
+                    SyncStage::Finalize => Stage::Finalize,
+                };
+                pt::progress::Value::NormalSync(pt::NormalSyncProgress {
+                    stage: stage as i32,
+                    sent_bytes: p.sent_bytes as u32,
+                    received_bytes: p.received_bytes as u32,
+                })
+            }
+            Progress::FullSync(p) => pt::progress::Value::FullSync(pt::FullSyncProgress {
+                transferred_bytes: p.transferred_bytes as u32,
+                total_bytes: p.total_bytes as u32,
+            }),
+            Progress::MediaCheck(checked) => {
+                pt::progress::Value::MediaCheck(pt::MediaCheckProgress {
+                    checked: checked as u32,
+                })
+            }
+            Progress::DatabaseCheck(stage) => {
+                use pt::database_check_progress::Stage;
+                let stage = match stage {
+                    DatabaseCheckStage::Integrity => Stage::Integrity,
+                    DatabaseCheckStage::Notes => Stage::Notes,
+                    DatabaseCheckStage::Cards => Stage::Cards,
+                    DatabaseCheckStage::Tags => Stage::Tags,
+                    DatabaseCheckStage::FieldCache => Stage::FieldCache,
+                    DatabaseCheckStage::DueNumbers => Stage::DueNumbers,
+                    DatabaseCheckStage::Reindex => Stage::Reindex,
+                };
+                pt::progress::Value::DatabaseCheck(pt::DatabaseCheckProgress {
+                    stage: stage as i32,
+                })
+            }
+            Progress::Export(progress) => pt::progress::Value::Export(match progress {
+                ExportProgress::Collection => pt::ExportProgress::default(),
+                ExportProgress::Media(files) => pt::ExportProgress {
+                    media: true,
+                    media_files: files as u32,
+                },
+            }),
+            Progress::Import(progress) => pt::progress::Value::Importing(match progress {
+                ImportProgress::Collection => pt::ImportProgress::default(),
+                ImportProgress::Media(files) => pt::ImportProgress {
+                    media: true,
+                    media_files: files as u32,
+                },
+            }),
+        }),
+    };
+
+    let mut buf = vec![];
+    proto.encode(&mut buf).expect("encode failed");
+    buf
+}
+
+/// An empty string means the delimiter should be detected.
+fn delimiter_from_proto(delimiter: &str) -> Result<Option<char>> {
+    let mut chars = delimiter.chars();
+    match (chars.next(), chars.next()) {
+        (None, _) => Ok(None),
+        (Some(delimiter), None) => Ok(Some(delimiter)),
+        _ => Err(AnkiError::invalid_input(
+            "the delimiter must be a single character",
+        )),
+    }
+}
+
+fn sync_output_to_proto(output: SyncOutput) -> pt::SyncCollectionOut {
+    use pt::sync_collection_out::Outcome;
+    let outcome = match output.outcome {
+        SyncOutcome::NoChanges => Outcome::NoChanges,
+        SyncOutcome::Success => Outcome::Success,
+        SyncOutcome::FullSyncRequired => Outcome::FullSyncRequired,
+        SyncOutcome::BadAuth => Outcome::BadAuth,
+        SyncOutcome::ServerAbort => Outcome::ServerAbort,
+        SyncOutcome::ClockOff => Outcome::ClockOff,
+        SyncOutcome::BasicCheckFailed => Outcome::BasicCheckFailed,
+        SyncOutcome::SanityCheckFailed => Outcome::SanityCheckFailed,
+    };
+    pt::SyncCollectionOut {
+        outcome: outcome as i32,
+        server_message: output.server_message,
+        host_number: output.host_number.unwrap_or_default(),
+        username: output.username,
+    }
+}
+
+fn full_sync_outcome_to_proto(outcome: FullSyncOutcome) -> pt::FullSyncOut {
+    use pt::full_sync_out::Outcome;
+    let outcome = match outcome {
+        FullSyncOutcome::Success => Outcome::Success,
+        FullSyncOutcome::CheckFailed => Outcome::CheckFailed,
+        FullSyncOutcome::UpgradeRequired => Outcome::UpgradeRequired,
+        FullSyncOutcome::DownloadClobber => Outcome::DownloadClobber,
+    };
+    pt::FullSyncOut {
+        outcome: outcome as i32,
+    }
+}
+
+fn network_settings_from_proto(settings: Option<pt::SyncNetworkSettings>) -> NetworkSettings {
+    let settings = settings.unwrap_or_default();
+    let non_empty = |s: String| if s.is_empty() { None } else { Some(s) };
+    NetworkSettings {
+        proxy: non_empty(settings.proxy),
+        ca_certificates: non_empty(settings.ca_certificates).map(Into::into),
+    }
+}
+
+fn change_counts_to_proto(counts: ChangeCounts) -> pt::SyncChangeCounts {
+    pt::SyncChangeCounts {
+        notes: counts.notes,
+        cards: counts.cards,
+        reviews: counts.reviews,
+        decks: counts.decks,
+        notetypes: counts.notetypes,
+        removals: counts.removals,
+    }
+}
+
+fn saved_searches_to_proto(storage: &SqliteStorage) -> Result<pt::SavedSearchesOut> {
+    Ok(pt::SavedSearchesOut {
+        searches: saved_searches(storage)?
+            .into_iter()
+            .map(|(name, search)| pt::SavedSearch { name, search })
+            .collect(),
+    })
+}
+
+fn search_context_from_proto(ctx: Option<pt::SearchContext>) -> SearchContext {
+    let ctx = ctx.unwrap_or_default();
+    SearchContext {
+        today: ctx.today,
+        day_cutoff: ctx.day_cutoff,
+        current_deck_id: ctx.current_deck_id,
+    }
+}
+
+/// A search takes priority over a deck; with neither, the whole collection
+/// is exported.
+fn note_from_proto(note: pt::Note) -> Note {
+    Note {
+        id: note.id,
+        guid: note.guid,
+        notetype_id: note.notetype_id,
+        mtime_secs: note.mtime_secs,
+        usn: note.usn,
+        tags: note.tags,
+        fields: note.fields,
+        flags: note.flags,
+        data: note.data,
+        // filled in when the note is saved
+        sort_field: "".into(),
+        checksum: 0,
+    }
+}
+
+fn note_to_proto(note: Note) -> pt::Note {
+    pt::Note {
+        id: note.id,
+        guid: note.guid,
+        notetype_id: note.notetype_id,
+        mtime_secs: note.mtime_secs,
+        usn: note.usn,
+        tags: note.tags,
+        fields: note.fields,
+        flags: note.flags,
+        data: note.data,
+    }
+}
+
+fn export_limit_from_proto(
+    deck_id: i64,
+    search: String,
+    context: Option<pt::SearchContext>,
+) -> ExportLimit {
+    if !search.is_empty() {
+        ExportLimit::Search {
+            search,
+            context: search_context_from_proto(context),
+        }
+    } else if deck_id != 0 {
+        ExportLimit::Deck(deck_id)
+    } else {
+        ExportLimit::Collection
+    }
+}
+
+fn browser_cell_to_proto(cell: Cell) -> pt::BrowserCell {
+    use pt::browser_cell::{Label as L, Value as V};
+    let value = match cell {
+        Cell::Text(text) => V::Text(text),
+        Cell::Label(label) => V::Label(match label {
+            Label::New => L::New,
+            Label::Learning => L::Learning,
+            Label::Filtered => L::Filtered,
+            Label::NoDeck => L::NoDeck,
+        } as i32),
+        Cell::Interval(days) => V::IntervalDays(days),
+        Cell::Frontend => V::Frontend(pt::Empty {}),
+    };
+    pt::BrowserCell { value: Some(value) }
+}
+
+/// Proto strings can't be null, so an empty string stands for None.
+fn non_empty(text: &str) -> Option<&str> {
+    if text.is_empty() {
+        None
+    } else {
+        Some(text)
+    }
+}
+
+fn sql_value_from_proto(value: pt::SqlValue) -> SqlValue {
+    use pt::sql_value::Value as V;
+    match value.value {
+        None | Some(V::Null(_)) => SqlValue::Null,
+        Some(V::Integer(n)) => SqlValue::Integer(n),
+        Some(V::Real(n)) => SqlValue::Real(n),
+        Some(V::Text(s)) => SqlValue::Text(s),
+        Some(V::Blob(b)) => SqlValue::Blob(b),
+    }
+}
+
+fn sql_value_to_proto(value: SqlValue) -> pt::SqlValue {
+    use pt::sql_value::Value as V;
+    pt::SqlValue {
+        value: Some(match value {
+            SqlValue::Null => V::Null(pt::Empty {}),
+            SqlValue::Integer(n) => V::Integer(n),
+            SqlValue::Real(n) => V::Real(n),
+            SqlValue::Text(s) => V::Text(s),
+            SqlValue::Blob(b) => V::Blob(b),
+        }),
+    }
+}
+
+fn card_state_from_proto(card: pt::CardSchedulingState) -> Result<CardSchedulingState> {
+    Ok(CardSchedulingState {
+        id: card.id,
+        ctype: CardType::from_u8(card.ctype as u8)
+            .ok_or_else(|| AnkiError::invalid_input("invalid card type"))?,
+        queue: CardQueue::from_i8(card.queue as i8)
+            .ok_or_else(|| AnkiError::invalid_input("invalid card queue"))?,
+        due: card.due,
+        interval: card.interval,
+        ease_factor: card.ease_factor as u16,
+        reps: card.reps,
+        lapses: card.lapses,
+        left: card.left,
+        deck_id: card.deck_id,
+        original_due: card.original_due,
+        original_deck_id: card.original_deck_id,
+    })
+}
+
+fn card_state_to_proto(card: &CardSchedulingState) -> pt::CardSchedulingState {
+    pt::CardSchedulingState {
+        id: card.id,
+        ctype: card.ctype as u32,
+        queue: card.queue as i32,
+        due: card.due,
+        interval: card.interval,
+        ease_factor: card.ease_factor as u32,
+        reps: card.reps,
+        lapses: card.lapses,
+        left: card.left,
+        deck_id: card.deck_id,
+        original_due: card.original_due,
+        original_deck_id: card.original_deck_id,
+    }
+}
+
+fn scheduling_config_from_proto(conf: pt::SchedulingConfig) -> Result<SchedulingConfig> {
+    Ok(SchedulingConfig {
+        new: NewCardConfig {
+            delays: conf.new_delays,
+            graduating_interval: conf.graduating_interval,
+            easy_interval: conf.easy_interval,
+            initial_ease: conf.initial_ease as u16,
+        },
+        lapse: LapseConfig {
+            delays: conf.lapse_delays,
+            multiplier: conf.lapse_multiplier,
+            minimum_interval: conf.minimum_interval,
+            leech_threshold: conf.leech_threshold,
+            leech_suspend: conf.leech_suspend,
+        },
+        review: ReviewConfig {
+            easy_multiplier: conf.easy_multiplier,
+            hard_multiplier: conf.hard_multiplier,
+            interval_multiplier: conf.interval_multiplier,
+            maximum_interval: conf.maximum_interval,
+        },
+        algorithm: AlgorithmKind::from_u32(conf.algorithm)?,
+        preview_delay: if conf.previewing {
+            Some(conf.preview_delay_secs)
+        } else {
+            None
+        },
+    })
 }
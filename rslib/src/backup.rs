@@ -0,0 +1,314 @@
+// Copyright: Ankitects Pty Ltd and contributors
+// License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+//! Automatic backups of the collection. Each backup is a .colpkg zip
+//! containing the collection compressed with zstd, written by a background
+//! thread so closing the collection isn't held up. Older backups are pruned
+//! so that only the most recent ones, and one per day and week before that,
+//! are kept.
+
+use crate::err::{AnkiError, Result};
+use chrono::{Datelike, Local, NaiveDate, NaiveDateTime};
+use std::cmp::Reverse;
+use std::collections::HashSet;
+use std::fs;
+use std::io::{self, Read, Seek, Write};
+use std::path::{Path, PathBuf};
+use std::thread::{self, JoinHandle};
+
+/// The name of the collection in new-style backups.
+pub(crate) const ZSTD_COLLECTION_NAME: &str = "collection.anki21b";
+/// The name of the collection in legacy backups and exported packages.
+const LEGACY_COLLECTION_NAME: &str = "collection.anki2";
+const FILENAME_FORMAT: &str = "backup-%Y-%m-%d-%H.%M.%S.colpkg";
+const ZSTD_LEVEL: i32 = 3;
+
+/// How many backups are kept when pruning.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BackupLimits {
+    /// The most recent backups are always kept.
+    pub recent: u32,
+    /// Of older backups, the latest of each of this many days is kept.
+    pub daily: u32,
+    /// And the latest of each of this many weeks.
+    pub weekly: u32,
+}
+
+impl Default for BackupLimits {
+    fn default() -> Self {
+        BackupLimits {
+            recent: 50,
+            daily: 7,
+            weekly: 4,
+        }
+    }
+}
+
+/// A backup file in the backup folder.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Backup {
+    pub path: PathBuf,
+    /// The local time it was created.
+    pub created: NaiveDateTime,
+}
+
+impl Backup {
+    fn from_path(path: PathBuf) -> Option<Self> {
+        let name = path.file_name()?.to_str()?;
+        let created = NaiveDateTime::parse_from_str(name, FILENAME_FORMAT).ok()?;
+        Some(Backup { path, created })
+    }
+
+    fn day(&self) -> NaiveDate {
+        self.created.date()
+    }
+
+    fn week(&self) -> (i32, u32) {
+        let week = self.created.iso_week();
+        (week.year(), week.week())
+    }
+}
+
+/// Read the collection, and compress it into a new backup on a background
+/// thread, pruning old backups afterwards. The collection must be closed
+/// first. The returned handle can be joined to wait for the backup.
+pub fn backup_collection(
+    col_path: &Path,
+    backup_folder: &Path,
+    limits: BackupLimits,
+) -> Result<JoinHandle<Result<()>>> {
+    // read the file up front, so the collection can be reopened while the
+    // backup is still being written
+    let col_data = fs::read(col_path)?;
+    let backup_folder = backup_folder.to_owned();
+    let filename = Local::now().format(FILENAME_FORMAT).to_string();
+
+    Ok(thread::spawn(move || {
+        write_backup(&col_data, &backup_folder.join(filename))?;
+        prune_backups(&backup_folder, limits)
+    }))
+}
+
+fn write_backup(col_data: &[u8], path: &Path) -> Result<()> {
+    // write to a temporary file first, so an interrupted backup doesn't
+    // leave a truncated file behind
+    let tmp_path = path.with_extension("tmp");
+    write_package(col_data, fs::File::create(&tmp_path)?)?;
+    fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+/// Back up the collection immediately, without pruning. The collection
+/// must be closed.
+pub(crate) fn backup_collection_now(col_path: &Path, backup_folder: &Path) -> Result<()> {
+    let col_data = fs::read(col_path)?;
+    let filename = Local::now().format(FILENAME_FORMAT).to_string();
+    write_backup(&col_data, &backup_folder.join(filename))
+}
+
+/// Write a .colpkg containing the collection compressed with zstd.
+pub(crate) fn write_package<W: Write + Seek>(col_data: &[u8], writer: W) -> Result<()> {
+    let compressed = zstd::encode_all(col_data, ZSTD_LEVEL)?;
+
+    let mut zip = zip::ZipWriter::new(writer);
+    // the collection is already compressed
+    let options =
+        zip::write::FileOptions::default().compression_method(zip::CompressionMethod::Stored);
+    zip.start_file(ZSTD_COLLECTION_NAME, options)?;
+    zip.write_all(&compressed)?;
+    // older clients expect a media map in packages
+    zip.start_file("media", options)?;
+    zip.write_all(b"{}")?;
+    zip.finish()?;
+
+    Ok(())
+}
+
+/// Read the collection from a .colpkg. Legacy packages with an uncompressed
+/// collection are supported as well.
+pub(crate) fn read_package<R: Read + Seek>(reader: R) -> Result<Vec<u8>> {
+    let mut zip = zip::ZipArchive::new(reader)?;
+    let compressed = match zip.by_name(ZSTD_COLLECTION_NAME) {
+        Ok(file) => Some(zstd::decode_all(file)?),
+        Err(zip::result::ZipError::FileNotFound) => None,
+        Err(err) => return Err(err.into()),
+    };
+    match compressed {
+        Some(data) => Ok(data),
+        None => {
+            let mut file = zip
+                .by_name(LEGACY_COLLECTION_NAME)
+                .map_err(|_| AnkiError::invalid_input("package does not contain a collection"))?;
+            let mut data = vec![];
+            file.read_to_end(&mut data)?;
+            Ok(data)
+        }
+    }
+}
+
+/// Existing backups, newest first. Files that don't look like backups are
+/// ignored.
+pub fn list_backups(backup_folder: &Path) -> Result<Vec<Backup>> {
+    let mut backups = vec![];
+    for entry in fs::read_dir(backup_folder)? {
+        if let Some(backup) = Backup::from_path(entry?.path()) {
+            backups.push(backup);
+        }
+    }
+    backups.sort_by_key(|backup| Reverse(backup.created));
+
+    Ok(backups)
+}
+
+/// The backups that fall outside the limits. `backups` must be sorted
+/// newest first.
+fn backups_to_remove(backups: &[Backup], limits: BackupLimits) -> Vec<&Backup> {
+    let mut days = HashSet::new();
+    let mut weeks = HashSet::new();
+
+    backups
+        .iter()
+        .enumerate()
+        .filter(|(idx, backup)| {
+            if *idx < limits.recent as usize {
+                // recent backups also count towards the days and weeks they
+                // were made in
+                days.insert(backup.day());
+                weeks.insert(backup.week());
+                return false;
+            }
+            let mut keep = false;
+            if !days.contains(&backup.day()) && days.len() < limits.daily as usize {
+                days.insert(backup.day());
+                keep = true;
+            }
+            if !weeks.contains(&backup.week()) && weeks.len() < limits.weekly as usize {
+                weeks.insert(backup.week());
+                keep = true;
+            }
+            !keep
+        })
+        .map(|(_, backup)| backup)
+        .collect()
+}
+
+/// Remove backups that fall outside the limits.
+pub fn prune_backups(backup_folder: &Path, limits: BackupLimits) -> Result<()> {
+    let backups = list_backups(backup_folder)?;
+    for backup in backups_to_remove(&backups, limits) {
+        fs::remove_file(&backup.path)?;
+    }
+
+    Ok(())
+}
+
+/// Replace the collection with the one in a backup. Legacy backups with an
+/// uncompressed collection are supported as well. The collection must be
+/// closed.
+pub fn restore_backup(backup_path: &Path, col_path: &Path) -> Result<()> {
+    let col_data = read_package(fs::File::open(backup_path)?)?;
+
+    // like the backup, write to a temporary file first, so a failed restore
+    // doesn't leave a partial collection in place
+    let tmp_path = col_path.with_extension("tmp");
+    fs::write(&tmp_path, &col_data)?;
+    install_collection(&tmp_path, col_path)
+}
+
+/// Move a complete collection file into place, replacing the collection.
+/// The collection must be closed.
+pub(crate) fn install_collection(new_path: &Path, col_path: &Path) -> Result<()> {
+    fs::rename(new_path, col_path)?;
+    // the log of the old collection must not be applied to the new one
+    for suffix in &["-wal", "-shm"] {
+        let mut path = col_path.as_os_str().to_owned();
+        path.push(suffix);
+        match fs::remove_file(PathBuf::from(path)) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err.into()),
+            _ => (),
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::backup::{
+        backup_collection, backups_to_remove, list_backups, restore_backup, Backup, BackupLimits,
+        FILENAME_FORMAT,
+    };
+    use crate::err::Result;
+    use chrono::NaiveDateTime;
+    use std::fs;
+    use tempfile::tempdir;
+
+    fn backup(created: &str) -> Backup {
+        let created = NaiveDateTime::parse_from_str(created, "%Y-%m-%d %H:%M").unwrap();
+        Backup {
+            path: created.format(FILENAME_FORMAT).to_string().into(),
+            created,
+        }
+    }
+
+    #[test]
+    fn test_pruning() {
+        let backups = vec![
+            backup("2020-03-10 18:00"),
+            backup("2020-03-10 12:00"),
+            backup("2020-03-10 09:00"),
+            backup("2020-03-09 20:00"),
+            backup("2020-03-09 10:00"),
+            backup("2020-03-08 10:00"),
+            backup("2020-03-01 10:00"),
+            backup("2020-02-25 10:00"),
+            backup("2020-02-10 10:00"),
+        ];
+        let limits = BackupLimits {
+            recent: 2,
+            daily: 2,
+            weekly: 3,
+        };
+        let removed: Vec<_> = backups_to_remove(&backups, limits)
+            .into_iter()
+            .map(|b| b.created.format("%m-%d %H:%M").to_string())
+            .collect();
+        // the 10th was kept as a recent day and the 9th as the second day,
+        // and the 8th and 1st start the second and third weeks
+        assert_eq!(
+            removed,
+            vec!["03-10 09:00", "03-09 10:00", "02-25 10:00", "02-10 10:00"]
+        );
+
+        let limits = BackupLimits {
+            recent: 0,
+            daily: 0,
+            weekly: 0,
+        };
+        assert_eq!(backups_to_remove(&backups, limits).len(), backups.len());
+    }
+
+    #[test]
+    fn test_backup_and_restore() -> Result<()> {
+        let dir = tempdir()?;
+        let col_path = dir.path().join("collection.anki2");
+        let backup_folder = dir.path().join("backups");
+        fs::create_dir(&backup_folder)?;
+        fs::write(&col_path, b"collection data")?;
+        fs::write(backup_folder.join("unrelated.txt"), b"")?;
+
+        backup_collection(&col_path, &backup_folder, BackupLimits::default())?
+            .join()
+            .unwrap()?;
+        let backups = list_backups(&backup_folder)?;
+        assert_eq!(backups.len(), 1);
+
+        fs::write(&col_path, b"changed")?;
+        fs::write(dir.path().join("collection.anki2-wal"), b"")?;
+        restore_backup(&backups[0].path, &col_path)?;
+        assert_eq!(fs::read(&col_path)?, b"collection data");
+        assert!(!dir.path().join("collection.anki2-wal").exists());
+
+        Ok(())
+    }
+}
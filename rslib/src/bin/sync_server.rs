@@ -0,0 +1,82 @@
+// Copyright: Ankitects Pty Ltd and contributors
+// License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+//! Run a sync server. It's configured with environment variables:
+//!
+//! - SYNC_BASE: the folder user data is kept in (default: ./sync-data)
+//! - SYNC_HOST, SYNC_PORT: the address to listen on (default: 0.0.0.0:8080)
+//! - SYNC_USER1, SYNC_USER2, ...: users, as username:password
+//! - SYNC_MAX_BODY_MB: the largest request accepted, in megabytes
+//!   (default: 300)
+//!
+//! Clients should use http://host:port/sync/ as the sync endpoint, and
+//! http://host:port/msync/ as the media sync endpoint.
+
+use anki::err::{AnkiError, Result};
+use anki::sync::server::{bind, SyncServer, SyncUser};
+use std::env;
+use std::net::SocketAddr;
+use tokio::runtime::Builder;
+
+fn users_from_env() -> Result<Vec<SyncUser>> {
+    let mut users = vec![];
+    for idx in 1.. {
+        let entry = match env::var(format!("SYNC_USER{}", idx)) {
+            Ok(entry) => entry,
+            Err(_) => break,
+        };
+        let mut parts = entry.splitn(2, ':');
+        match (parts.next(), parts.next()) {
+            (Some(username), Some(password)) if !username.is_empty() => users.push(SyncUser {
+                username: username.into(),
+                password: password.into(),
+            }),
+            _ => {
+                return Err(AnkiError::InvalidInput {
+                    info: format!("SYNC_USER{} should be username:password", idx),
+                })
+            }
+        }
+    }
+    Ok(users)
+}
+
+fn run() -> Result<()> {
+    let base = env::var("SYNC_BASE").unwrap_or_else(|_| "sync-data".into());
+    let host = env::var("SYNC_HOST").unwrap_or_else(|_| "0.0.0.0".into());
+    let port = env::var("SYNC_PORT").unwrap_or_else(|_| "8080".into());
+    let addr: SocketAddr =
+        format!("{}:{}", host, port)
+            .parse()
+            .map_err(|_| AnkiError::InvalidInput {
+                info: "invalid SYNC_HOST or SYNC_PORT".into(),
+            })?;
+
+    let users = users_from_env()?;
+    if users.is_empty() {
+        return Err(AnkiError::InvalidInput {
+            info: "no users; set SYNC_USER1".into(),
+        });
+    }
+    let mut server = SyncServer::new(base, users)?;
+    if let Ok(max_mb) = env::var("SYNC_MAX_BODY_MB") {
+        let max_mb: usize = max_mb.parse().map_err(|_| AnkiError::InvalidInput {
+            info: "invalid SYNC_MAX_BODY_MB".into(),
+        })?;
+        server.set_max_body_bytes(max_mb * 1024 * 1024);
+    }
+
+    let mut rt = Builder::new().basic_scheduler().enable_all().build()?;
+    rt.block_on(async {
+        let (addr, serving) = bind(server, &addr)?;
+        println!("listening on {}", addr);
+        serving.await
+    })
+}
+
+fn main() {
+    if let Err(err) = run() {
+        eprintln!("{}", err);
+        std::process::exit(1);
+    }
+}
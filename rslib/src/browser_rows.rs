@@ -0,0 +1,405 @@
+// Copyright: Ankitects Pty Ltd and contributors
+// License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+//! The text shown in the browser's card list. Rows are built in batches,
+//! so scrolling doesn't need a round trip per card. Text that must be
+//! translated, and the question and answer, which need the card to be
+//! rendered, are left for the frontend.
+
+use crate::card::{Card, CardQueue, CardType, USER_FLAG_MASK};
+use crate::decks::Deck;
+use crate::err::Result;
+use crate::notes::Note;
+use crate::notetypes::{CardTemplate, NoteType, NoteTypeKind};
+use crate::storage::SqliteStorage;
+use crate::text::html_to_browser_line;
+use chrono::{Local, TimeZone};
+use std::collections::HashMap;
+
+/// A column of the card list, as stored in the activeCols config key.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Column {
+    Question,
+    Answer,
+    SortField,
+    Template,
+    CardDue,
+    NoteCreation,
+    NoteMod,
+    CardMod,
+    CardReps,
+    CardLapses,
+    NoteTags,
+    Notetype,
+    CardInterval,
+    CardEase,
+    Deck,
+    /// A column added by an add-on.
+    Other,
+}
+
+impl Column {
+    pub fn from_key(key: &str) -> Self {
+        match key {
+            "question" => Column::Question,
+            "answer" => Column::Answer,
+            "noteFld" => Column::SortField,
+            "template" => Column::Template,
+            "cardDue" => Column::CardDue,
+            "noteCrt" => Column::NoteCreation,
+            "noteMod" => Column::NoteMod,
+            "cardMod" => Column::CardMod,
+            "cardReps" => Column::CardReps,
+            "cardLapses" => Column::CardLapses,
+            "noteTags" => Column::NoteTags,
+            "note" => Column::Notetype,
+            "cardIvl" => Column::CardInterval,
+            "cardEase" => Column::CardEase,
+            "deck" => Column::Deck,
+            _ => Column::Other,
+        }
+    }
+}
+
+/// Text the frontend shows in its own language.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Label {
+    New,
+    Learning,
+    Filtered,
+    NoDeck,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Cell {
+    Text(String),
+    Label(Label),
+    /// An interval in days.
+    Interval(u32),
+    /// The question, the answer, and add-on columns.
+    Frontend,
+}
+
+/// The font a template asks the browser to use.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BrowserFont {
+    pub family: String,
+    pub size: u32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct BrowserRow {
+    pub card_id: i64,
+    pub cells: Vec<Cell>,
+    pub queue: CardQueue,
+    pub flag: u8,
+    pub marked: bool,
+    /// True if the sort field is right-to-left.
+    pub rtl: bool,
+    pub font: Option<BrowserFont>,
+}
+
+/// The rows of `card_ids`, in the same order. Cards that no longer exist
+/// are skipped. `today` is the scheduler's day number, and `now_secs` the
+/// current time, which are used to show review due dates.
+pub fn browser_rows(
+    storage: &SqliteStorage,
+    card_ids: &[i64],
+    columns: &[Column],
+    today: u32,
+    now_secs: i64,
+) -> Result<Vec<BrowserRow>> {
+    let notetypes = storage.get_all_notetypes()?;
+    let decks = storage.get_all_decks()?;
+    let mut notes: HashMap<i64, Note> = HashMap::new();
+    let mut rows = Vec::with_capacity(card_ids.len());
+
+    for id in card_ids {
+        let card = match storage.get_card(*id)? {
+            Some(card) => card,
+            None => continue,
+        };
+        if !notes.contains_key(&card.note_id) {
+            match storage.get_note(card.note_id)? {
+                Some(note) => notes.insert(note.id, note),
+                None => continue,
+            };
+        }
+        let note = &notes[&card.note_id];
+        let notetype = match notetypes.get(&note.notetype_id) {
+            Some(nt) => nt,
+            None => continue,
+        };
+        let ctx = RowContext {
+            card: &card,
+            note,
+            notetype,
+            decks: &decks,
+            today,
+            now_secs,
+        };
+        rows.push(ctx.row(columns));
+    }
+
+    Ok(rows)
+}
+
+struct RowContext<'a> {
+    card: &'a Card,
+    note: &'a Note,
+    notetype: &'a NoteType,
+    decks: &'a HashMap<i64, Deck>,
+    today: u32,
+    now_secs: i64,
+}
+
+impl RowContext<'_> {
+    fn row(&self, columns: &[Column]) -> BrowserRow {
+        BrowserRow {
+            card_id: self.card.id,
+            cells: columns.iter().map(|c| self.cell(*c)).collect(),
+            queue: self.card.queue,
+            flag: self.card.flags & USER_FLAG_MASK,
+            marked: self
+                .note
+                .tags
+                .iter()
+                .any(|tag| tag.eq_ignore_ascii_case("marked")),
+            rtl: self.sort_field_is_rtl(),
+            font: self.template().and_then(template_font),
+        }
+    }
+
+    fn cell(&self, column: Column) -> Cell {
+        let card = self.card;
+        match column {
+            Column::Question | Column::Answer | Column::Other => Cell::Frontend,
+            Column::SortField => Cell::Text(self.sort_field()),
+            Column::Template => Cell::Text(self.template_name()),
+            Column::CardDue => self.due(),
+            Column::NoteCreation => Cell::Text(local_date(self.note.id / 1000)),
+            Column::NoteMod => Cell::Text(local_date(self.note.mtime_secs)),
+            Column::CardMod => Cell::Text(local_date(card.mtime_secs)),
+            Column::CardReps => Cell::Text(card.reps.to_string()),
+            Column::CardLapses => Cell::Text(card.lapses.to_string()),
+            Column::NoteTags => Cell::Text(self.note.tags.join(" ")),
+            Column::Notetype => Cell::Text(self.notetype.name.clone()),
+            Column::CardInterval => match card.ctype {
+                CardType::New => Cell::Label(Label::New),
+                CardType::Learn => Cell::Label(Label::Learning),
+                _ => Cell::Interval(card.interval),
+            },
+            Column::CardEase => match card.ctype {
+                CardType::New => Cell::Label(Label::New),
+                _ => Cell::Text(format!("{}%", card.ease_factor / 10)),
+            },
+            Column::Deck => self.deck(),
+        }
+    }
+
+    fn sort_field(&self) -> String {
+        let idx = self.notetype.sort_field_idx as usize;
+        let field = self.note.fields.get(idx).map(String::as_str).unwrap_or("");
+        html_to_browser_line(field)
+    }
+
+    fn sort_field_is_rtl(&self) -> bool {
+        self.notetype
+            .fields
+            .get(self.notetype.sort_field_idx as usize)
+            .and_then(|field| field.other.get("rtl"))
+            .and_then(|rtl| rtl.as_bool())
+            .unwrap_or(false)
+    }
+
+    /// Cloze cards all use the first template.
+    fn template(&self) -> Option<&CardTemplate> {
+        let ord = match self.notetype.kind() {
+            NoteTypeKind::Standard => self.card.ordinal as usize,
+            NoteTypeKind::Cloze => 0,
+        };
+        self.notetype.templates.get(ord)
+    }
+
+    fn template_name(&self) -> String {
+        let name = self.template().map(|t| t.name.as_str()).unwrap_or("");
+        match self.notetype.kind() {
+            NoteTypeKind::Standard => name.into(),
+            NoteTypeKind::Cloze => format!("{} {}", name, self.card.ordinal + 1),
+        }
+    }
+
+    fn due(&self) -> Cell {
+        let card = self.card;
+        if card.original_deck_id != 0 {
+            return Cell::Label(Label::Filtered);
+        }
+        let buried_or_suspended = (card.queue as i8) < 0;
+        let text = match card.queue {
+            CardQueue::Learn => local_date(card.due),
+            CardQueue::New => card.due.to_string(),
+            _ if card.ctype == CardType::New => card.due.to_string(),
+            CardQueue::Review | CardQueue::DayLearn => self.review_date(),
+            _ if card.ctype == CardType::Review && buried_or_suspended => self.review_date(),
+            _ => "".into(),
+        };
+        if buried_or_suspended {
+            Cell::Text(format!("({})", text))
+        } else {
+            Cell::Text(text)
+        }
+    }
+
+    fn review_date(&self) -> String {
+        let days = self.card.due.saturating_sub(i64::from(self.today));
+        local_date(self.now_secs.saturating_add(days.saturating_mul(86_400)))
+    }
+
+    fn deck(&self) -> Cell {
+        let name = |id| self.decks.get(&id).map(|deck| deck.name.as_str());
+        match (name(self.card.deck_id), self.card.original_deck_id) {
+            (Some(current), 0) => Cell::Text(current.into()),
+            (Some(current), home_id) => match name(home_id) {
+                Some(home) => Cell::Text(format!("{} ({})", current, home)),
+                None => Cell::Label(Label::NoDeck),
+            },
+            (None, _) => Cell::Label(Label::NoDeck),
+        }
+    }
+}
+
+fn template_font(template: &CardTemplate) -> Option<BrowserFont> {
+    let family = template.other.get("bfont")?.as_str()?;
+    if family.is_empty() {
+        return None;
+    }
+    let size = template
+        .other
+        .get("bsize")
+        .and_then(|size| size.as_u64())
+        .unwrap_or(12);
+    Some(BrowserFont {
+        family: family.into(),
+        size: size as u32,
+    })
+}
+
+/// Invalid timestamps are shown as an empty string.
+fn local_date(secs: i64) -> String {
+    Local
+        .timestamp_opt(secs, 0)
+        .single()
+        .map(|date| date.format("%Y-%m-%d").to_string())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod test {
+    use crate::browser_rows::{browser_rows, BrowserFont, Cell, Column, Label};
+    use crate::card::{Card, CardQueue, CardType};
+    use crate::err::Result;
+    use crate::notes::Note;
+    use crate::notetypes::NoteType;
+    use crate::storage::testutil::{add_deck, add_filtered_deck, open_test_collection};
+    use chrono::{Local, TimeZone};
+    use serde_json::json;
+
+    #[test]
+    fn test_browser_rows() -> Result<()> {
+        let (_dir, storage) = open_test_collection()?;
+        let notetype: NoteType = serde_json::from_value(json!({
+            "id": 1, "name": "Cloze", "mod": 0, "usn": 0, "type": 1, "sortf": 1,
+            "flds": [{"name": "Text", "ord": 0}, {"name": "Extra", "ord": 1, "rtl": true}],
+            "tmpls": [{"name": "Cloze", "ord": 0, "qfmt": "", "afmt": "",
+                       "bfont": "Arial", "bsize": 20}]
+        }))?;
+        storage.add_or_update_notetype(&notetype)?;
+        add_deck(&storage, 1, "Default")?;
+        add_filtered_deck(&storage, 2, "Filtered")?;
+        let mut note = Note {
+            notetype_id: 1,
+            fields: vec!["text".into(), "a<br><b>b</b> <img src=c.jpg>".into()],
+            tags: vec!["Marked".into(), "other".into()],
+            ..Default::default()
+        };
+        storage.add_note(&mut note)?;
+        let mut new = Card {
+            note_id: note.id,
+            deck_id: 1,
+            ordinal: 1,
+            due: 7,
+            flags: 0b1000_0011,
+            ..Default::default()
+        };
+        storage.add_card(&mut new)?;
+        let mut review = Card {
+            note_id: note.id,
+            deck_id: 2,
+            original_deck_id: 1,
+            ctype: CardType::Review,
+            queue: CardQueue::Review,
+            due: 12,
+            interval: 30,
+            ease_factor: 2505,
+            ..Default::default()
+        };
+        storage.add_card(&mut review)?;
+
+        let columns: Vec<_> = [
+            "noteFld", "template", "cardDue", "cardIvl", "cardEase", "deck", "noteTags", "note",
+            "question", "addon",
+        ]
+        .iter()
+        .map(|key| Column::from_key(key))
+        .collect();
+        let rows = browser_rows(&storage, &[review.id, 123, new.id], &columns, 10, 0)?;
+        assert_eq!(rows.len(), 2);
+
+        let row = &rows[1];
+        assert_eq!(row.card_id, new.id);
+        assert_eq!(
+            row.cells,
+            vec![
+                Cell::Text("a b c.jpg".into()),
+                Cell::Text("Cloze 2".into()),
+                Cell::Text("7".into()),
+                Cell::Label(Label::New),
+                Cell::Label(Label::New),
+                Cell::Text("Default".into()),
+                Cell::Text("Marked other".into()),
+                Cell::Text("Cloze".into()),
+                Cell::Frontend,
+                Cell::Frontend,
+            ]
+        );
+        assert_eq!((row.flag, row.marked, row.rtl), (3, true, true));
+        assert_eq!(
+            row.font,
+            Some(BrowserFont {
+                family: "Arial".into(),
+                size: 20
+            })
+        );
+
+        let row = &rows[0];
+        assert_eq!(row.cells[2], Cell::Label(Label::Filtered));
+        assert_eq!(row.cells[3], Cell::Interval(30));
+        assert_eq!(row.cells[4], Cell::Text("250%".into()));
+        assert_eq!(row.cells[5], Cell::Text("Filtered (Default)".into()));
+
+        // review due dates are relative to today; suspended cards are
+        // shown in parentheses
+        review.original_deck_id = 0;
+        review.deck_id = 1;
+        review.queue = CardQueue::Suspended;
+        storage.update_card(&review)?;
+        let rows = browser_rows(&storage, &[review.id], &[Column::CardDue], 10, 0)?;
+        let expected = Local
+            .timestamp_opt(2 * 86_400, 0)
+            .unwrap()
+            .format("(%Y-%m-%d)");
+        assert_eq!(rows[0].cells, vec![Cell::Text(expected.to_string())]);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,249 @@
+// Copyright: Ankitects Pty Ltd and contributors
+// License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+//! Edits the browser applies to many notes or cards at once. Each runs in a
+//! single transaction, and returns the previous state of the rows it
+//! changed, so the edit can be undone.
+
+use crate::card::{Card, USER_FLAG_MASK};
+use crate::err::{AnkiError, Result};
+use crate::notes::Note;
+use crate::sched::filtered::return_cards_to_home_decks;
+use crate::sched::ids_to_string;
+use crate::storage::SqliteStorage;
+use crate::tags;
+use crate::undo::UndoableChange;
+use rusqlite::params;
+
+fn note_changes(previous: Vec<Note>) -> Vec<UndoableChange> {
+    previous
+        .into_iter()
+        .map(|note| UndoableChange::Note {
+            id: note.id,
+            previous: Some(note),
+        })
+        .collect()
+}
+
+/// The cards in `card_ids` for which `needs_change` returns true, as
+/// changes that would restore them.
+fn card_changes<F>(
+    storage: &SqliteStorage,
+    card_ids: &[i64],
+    needs_change: F,
+) -> Result<Vec<UndoableChange>>
+where
+    F: Fn(&Card) -> bool,
+{
+    let mut changes = vec![];
+    for id in card_ids {
+        if let Some(card) = storage.get_card(*id)? {
+            if needs_change(&card) {
+                changes.push(UndoableChange::Card {
+                    id: *id,
+                    previous: Some(card),
+                });
+            }
+        }
+    }
+    Ok(changes)
+}
+
+fn changed_card_ids(changes: &[UndoableChange]) -> Vec<i64> {
+    changes
+        .iter()
+        .filter_map(|change| match change {
+            UndoableChange::Card { id, .. } => Some(*id),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Add the space-separated `tags` to the notes.
+pub fn add_tags_to_notes(
+    storage: &mut SqliteStorage,
+    note_ids: &[i64],
+    tags: &str,
+    usn: i32,
+    mtime_secs: i64,
+) -> Result<Vec<UndoableChange>> {
+    storage.transact(|storage| {
+        let previous = tags::add_tags(storage, note_ids, tags, usn, mtime_secs)?;
+        Ok(note_changes(previous))
+    })
+}
+
+/// Remove the space-separated `tags` from the notes. * matches any text.
+pub fn remove_tags_from_notes(
+    storage: &mut SqliteStorage,
+    note_ids: &[i64],
+    tags: &str,
+    usn: i32,
+    mtime_secs: i64,
+) -> Result<Vec<UndoableChange>> {
+    storage.transact(|storage| {
+        let previous = tags::remove_tags(storage, note_ids, tags, usn, mtime_secs)?;
+        Ok(note_changes(previous))
+    })
+}
+
+/// Rename a tag and its descendants in every note, merging it with `new`
+/// if that's already in use.
+pub fn rename_tag_in_notes(
+    storage: &mut SqliteStorage,
+    old: &str,
+    new: &str,
+    usn: i32,
+    mtime_secs: i64,
+) -> Result<Vec<UndoableChange>> {
+    storage.transact(|storage| {
+        let previous = tags::rename_tag(storage, old, new, usn, mtime_secs)?;
+        Ok(note_changes(previous))
+    })
+}
+
+/// Move cards into a normal deck. Cards in a filtered deck are returned to
+/// their home deck first.
+pub fn set_deck(
+    storage: &mut SqliteStorage,
+    card_ids: &[i64],
+    deck_id: i64,
+    usn: i32,
+    mtime_secs: i64,
+) -> Result<Vec<UndoableChange>> {
+    match storage.get_deck(deck_id)? {
+        None => return Err(AnkiError::invalid_input("no such deck")),
+        Some(deck) if deck.is_filtered() => {
+            return Err(AnkiError::invalid_input(
+                "cards can't be moved into a filtered deck",
+            ))
+        }
+        _ => (),
+    }
+
+    storage.transact(|storage| {
+        let changes = card_changes(storage, card_ids, |card| {
+            card.deck_id != deck_id || card.original_deck_id != 0
+        })?;
+        let ids = changed_card_ids(&changes);
+        return_cards_to_home_decks(&storage.db, &ids, usn)?;
+        storage.db.execute(
+            &format!(
+                "update cards set did = ?, mod = ?, usn = ? where id in {}",
+                ids_to_string(&ids)
+            ),
+            params![deck_id, mtime_secs, usn],
+        )?;
+        Ok(changes)
+    })
+}
+
+/// Set the user flag of cards, or clear it if `flag` is 0.
+pub fn set_flag(
+    storage: &mut SqliteStorage,
+    card_ids: &[i64],
+    flag: u32,
+    usn: i32,
+    mtime_secs: i64,
+) -> Result<Vec<UndoableChange>> {
+    if flag > u32::from(USER_FLAG_MASK) {
+        return Err(AnkiError::invalid_input("invalid flag"));
+    }
+    let flag = flag as u8;
+
+    storage.transact(|storage| {
+        let changes = card_changes(storage, card_ids, |card| {
+            card.flags & USER_FLAG_MASK != flag
+        })?;
+        storage.db.execute(
+            &format!(
+                "update cards set flags = (flags & ~?) | ?, mod = ?, usn = ? where id in {}",
+                ids_to_string(&changed_card_ids(&changes))
+            ),
+            params![USER_FLAG_MASK, flag, mtime_secs, usn],
+        )?;
+        Ok(changes)
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use crate::bulk::{add_tags_to_notes, remove_tags_from_notes, set_deck, set_flag};
+    use crate::card::Card;
+    use crate::err::Result;
+    use crate::notes::Note;
+    use crate::storage::testutil::{add_deck, add_filtered_deck, open_test_collection};
+    use crate::undo::{UndoManager, UndoableOp};
+
+    #[test]
+    fn test_bulk_edits() -> Result<()> {
+        let (_dir, mut storage) = open_test_collection()?;
+        add_deck(&storage, 1, "Default")?;
+        add_deck(&storage, 2, "Other")?;
+        add_filtered_deck(&storage, 3, "Filtered")?;
+        let mut note = Note::default();
+        storage.add_note(&mut note)?;
+        let mut card = Card {
+            note_id: note.id,
+            deck_id: 1,
+            ..Default::default()
+        };
+        storage.add_card(&mut card)?;
+        let mut filtered = Card {
+            note_id: note.id,
+            deck_id: 3,
+            original_deck_id: 1,
+            original_due: 5,
+            flags: 0b1000_0001,
+            ..Default::default()
+        };
+        storage.add_card(&mut filtered)?;
+        let mut undo = UndoManager::default();
+
+        // tags
+        let changes = add_tags_to_notes(&mut storage, &[note.id], "a b", -1, 10)?;
+        assert_eq!(changes.len(), 1);
+        undo.add_op(UndoableOp {
+            name: "Add Tags".into(),
+            changes,
+        });
+        assert_eq!(storage.get_note(note.id)?.unwrap().tags, vec!["a", "b"]);
+        let changes = remove_tags_from_notes(&mut storage, &[note.id], "A", -1, 10)?;
+        assert_eq!(changes.len(), 1);
+        assert_eq!(storage.get_note(note.id)?.unwrap().tags, vec!["b"]);
+        assert!(remove_tags_from_notes(&mut storage, &[note.id], "a", -1, 10)?.is_empty());
+
+        // decks
+        assert!(set_deck(&mut storage, &[card.id], 3, -1, 10).is_err());
+        assert!(set_deck(&mut storage, &[card.id], 4, -1, 10).is_err());
+        let changes = set_deck(&mut storage, &[card.id, filtered.id], 2, -1, 10)?;
+        assert_eq!(changes.len(), 2);
+        let moved = storage.get_card(filtered.id)?.unwrap();
+        assert_eq!(
+            (moved.deck_id, moved.original_deck_id, moved.due, moved.usn),
+            (2, 0, 5, -1)
+        );
+        // cards already in the deck are left alone
+        assert!(set_deck(&mut storage, &[card.id], 2, -1, 10)?.is_empty());
+
+        // flags keep the other bits
+        assert!(set_flag(&mut storage, &[card.id], 8, -1, 10).is_err());
+        let changes = set_flag(&mut storage, &[card.id, filtered.id], 2, -1, 11)?;
+        assert_eq!(changes.len(), 2);
+        let flagged = storage.get_card(filtered.id)?.unwrap();
+        assert_eq!((flagged.flags, flagged.mtime_secs), (0b1000_0010, 11));
+        undo.add_op(UndoableOp {
+            name: "Set Flag".into(),
+            changes,
+        });
+        assert!(set_flag(&mut storage, &[card.id], 2, -1, 11)?.is_empty());
+
+        // the edits can be undone
+        undo.undo(&mut storage)?;
+        assert_eq!(storage.get_card(filtered.id)?.unwrap().flags, 0b1000_0001);
+        undo.undo(&mut storage)?;
+        assert!(storage.get_note(note.id)?.unwrap().tags.is_empty());
+
+        Ok(())
+    }
+}
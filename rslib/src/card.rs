@@ -0,0 +1,137 @@
+// Copyright: Ankitects Pty Ltd and contributors
+// License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+/// The stage of learning a card is in, as stored in cards.type.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[repr(u8)]
+pub enum CardType {
+    New = 0,
+    Learn = 1,
+    Review = 2,
+    Relearn = 3,
+}
+
+impl CardType {
+    pub fn from_u8(n: u8) -> Option<Self> {
+        Some(match n {
+            0 => CardType::New,
+            1 => CardType::Learn,
+            2 => CardType::Review,
+            3 => CardType::Relearn,
+            _ => return None,
+        })
+    }
+
+    /// The queue a card of this type belongs in when it is not suspended,
+    /// buried or in a filtered deck. Learning and relearning cards may be
+    /// due at a timestamp or on a day, which `due` determines.
+    pub fn home_queue(self, due: i64) -> CardQueue {
+        match self {
+            CardType::New => CardQueue::New,
+            CardType::Learn | CardType::Relearn => {
+                if due > 1_000_000_000 {
+                    CardQueue::Learn
+                } else {
+                    CardQueue::DayLearn
+                }
+            }
+            CardType::Review => CardQueue::Review,
+        }
+    }
+}
+
+/// The queue a card is shown from, as stored in cards.queue.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[repr(i8)]
+pub enum CardQueue {
+    /// Buried by the user.
+    UserBuried = -3,
+    /// Buried automatically, as a sibling of a card that was answered.
+    SchedBuried = -2,
+    Suspended = -1,
+    New = 0,
+    /// Due is a timestamp in seconds.
+    Learn = 1,
+    /// Due is a day number.
+    Review = 2,
+    /// Learning or relearning steps of a day or more; due is a day number.
+    DayLearn = 3,
+    /// Cards in a filtered deck that doesn't reschedule them; due is a
+    /// timestamp in seconds.
+    Preview = 4,
+}
+
+impl CardQueue {
+    pub fn from_i8(n: i8) -> Option<Self> {
+        Some(match n {
+            -3 => CardQueue::UserBuried,
+            -2 => CardQueue::SchedBuried,
+            -1 => CardQueue::Suspended,
+            0 => CardQueue::New,
+            1 => CardQueue::Learn,
+            2 => CardQueue::Review,
+            3 => CardQueue::DayLearn,
+            4 => CardQueue::Preview,
+            _ => return None,
+        })
+    }
+}
+
+/// The flags a user can set are kept in the lower bits of cards.flags.
+pub(crate) const USER_FLAG_MASK: u8 = 0b111;
+
+/// A row in the cards table.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Card {
+    pub id: i64,
+    pub note_id: i64,
+    pub deck_id: i64,
+    /// The template the card was generated from, or the cloze number
+    /// minus one.
+    pub ordinal: u16,
+    pub mtime_secs: i64,
+    pub usn: i32,
+    pub ctype: CardType,
+    pub queue: CardQueue,
+    /// A position for new cards, a day number for reviews, or a timestamp
+    /// for learning cards.
+    pub due: i64,
+    /// In days.
+    pub interval: u32,
+    /// In permille.
+    pub ease_factor: u16,
+    pub reps: u32,
+    pub lapses: u32,
+    /// Learning steps remaining.
+    pub left: u32,
+    /// Set when the card is in a filtered deck.
+    pub original_due: i64,
+    pub original_deck_id: i64,
+    pub flags: u8,
+    pub data: String,
+}
+
+impl Default for Card {
+    fn default() -> Self {
+        Card {
+            id: 0,
+            note_id: 0,
+            deck_id: 0,
+            ordinal: 0,
+            mtime_secs: 0,
+            usn: 0,
+            ctype: CardType::New,
+            queue: CardQueue::New,
+            due: 0,
+            interval: 0,
+            ease_factor: 0,
+            reps: 0,
+            lapses: 0,
+            left: 0,
+            original_due: 0,
+            original_deck_id: 0,
+            flags: 0,
+            data: "".into(),
+        }
+    }
+}
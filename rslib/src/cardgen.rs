@@ -0,0 +1,256 @@
+// Copyright: Ankitects Pty Ltd and contributors
+// License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+use crate::cloze::cloze_numbers_in_string;
+use crate::notetypes::{NoteType, NoteTypeKind};
+use crate::template::{
+    field_is_empty, without_legacy_template_directives, FieldMap, FieldRequirements, ParsedTemplate,
+};
+use serde_json::{json, Value};
+use std::collections::HashSet;
+
+/// The information needed to decide which cards a note of a given note type
+/// should have.
+#[derive(Debug, PartialEq)]
+pub enum CardGenContext {
+    /// One entry per template, in template order.
+    Standard(Vec<FieldRequirements>),
+    /// The ordinals of the fields that the template passes through the
+    /// cloze filter.
+    Cloze(Vec<usize>),
+}
+
+/// The result of comparing a note's existing cards to the cards it should
+/// have. Both lists are sorted.
+#[derive(Debug, Default, PartialEq)]
+pub struct CardChanges {
+    pub to_add: Vec<u16>,
+    pub to_remove: Vec<u16>,
+}
+
+impl CardGenContext {
+    /// Work out the field requirements of each template front. Templates
+    /// that can't be parsed never generate a card.
+    pub fn new_standard(template_fronts: &[&str], field_names: &[&str]) -> Self {
+        let field_map: FieldMap = field_names
+            .iter()
+            .enumerate()
+            .map(|(ord, name)| (*name, ord as u16))
+            .collect();
+        let reqs = template_fronts
+            .iter()
+            .map(|front| {
+                let normalized = without_legacy_template_directives(front);
+                match ParsedTemplate::from_text(normalized.as_ref()) {
+                    Ok(tmpl) => tmpl.requirements(&field_map),
+                    Err(_) => FieldRequirements::None,
+                }
+            })
+            .collect();
+        CardGenContext::Standard(reqs)
+    }
+
+    /// Find the fields a cloze note type's template front uses with the
+    /// cloze filter.
+    pub fn new_cloze(template_front: &str, field_names: &[&str]) -> Self {
+        let normalized = without_legacy_template_directives(template_front);
+        let cloze_fields = ParsedTemplate::from_text(normalized.as_ref())
+            .map(|tmpl| tmpl.cloze_fields())
+            .unwrap_or_default();
+        let ords = field_names
+            .iter()
+            .enumerate()
+            .filter(|(_, name)| cloze_fields.contains(*name))
+            .map(|(ord, _)| ord)
+            .collect();
+        CardGenContext::Cloze(ords)
+    }
+
+    /// The context for a note type's current templates.
+    pub fn for_notetype(notetype: &NoteType) -> Self {
+        let field_names: Vec<_> = notetype.fields.iter().map(|f| f.name.as_str()).collect();
+        let fronts: Vec<_> = notetype
+            .templates
+            .iter()
+            .map(|t| t.question_format.as_str())
+            .collect();
+        match notetype.kind() {
+            NoteTypeKind::Cloze => {
+                Self::new_cloze(fronts.first().copied().unwrap_or_default(), &field_names)
+            }
+            NoteTypeKind::Standard => Self::new_standard(&fronts, &field_names),
+        }
+    }
+
+    /// The requirements in the form the legacy code keeps in the "req" key
+    /// of a standard note type: [ordinal, "any"/"all"/"none", [field ords]]
+    /// for each template. Cloze note types have no requirements.
+    pub fn legacy_requirements(&self) -> Option<Value> {
+        let reqs = match self {
+            CardGenContext::Standard(reqs) => reqs,
+            CardGenContext::Cloze(_) => return None,
+        };
+        let sorted = |ords: &HashSet<u16>| {
+            let mut ords: Vec<_> = ords.iter().copied().collect();
+            ords.sort_unstable();
+            ords
+        };
+        let reqs: Vec<_> = reqs
+            .iter()
+            .enumerate()
+            .map(|(ord, req)| match req {
+                FieldRequirements::Any(ords) => json!([ord, "any", sorted(ords)]),
+                FieldRequirements::All(ords) => json!([ord, "all", sorted(ords)]),
+                FieldRequirements::None => json!([ord, "none", []]),
+            })
+            .collect();
+        Some(reqs.into())
+    }
+
+    /// The ordinals of the cards a note with the provided fields should
+    /// have. A cloze note without any cloze deletions is given the first
+    /// card, so that it can still be added.
+    pub fn ords_to_generate(&self, fields: &[&str]) -> HashSet<u16> {
+        match self {
+            CardGenContext::Standard(reqs) => {
+                let nonempty: HashSet<u16> = fields
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, text)| !field_is_empty(text))
+                    .map(|(ord, _)| ord as u16)
+                    .collect();
+                reqs.iter()
+                    .enumerate()
+                    .filter(|(_, req)| req.satisfied_by(&nonempty))
+                    .map(|(ord, _)| ord as u16)
+                    .collect()
+            }
+            CardGenContext::Cloze(field_ords) => {
+                let mut ords: HashSet<u16> = field_ords
+                    .iter()
+                    .filter_map(|ord| fields.get(*ord))
+                    .flat_map(|text| cloze_numbers_in_string(text))
+                    // cloze numbers start at 1, but {{c0::..}} is not rejected
+                    .filter(|num| *num > 0)
+                    .map(|num| num - 1)
+                    .collect();
+                if ords.is_empty() {
+                    ords.insert(0);
+                }
+                ords
+            }
+        }
+    }
+
+    /// True if a cloze note has no cloze deletions in the fields its
+    /// template uses, so its only card would be blank. Always false for
+    /// standard note types.
+    pub fn missing_cloze(&self, fields: &[&str]) -> bool {
+        match self {
+            CardGenContext::Standard(_) => false,
+            CardGenContext::Cloze(field_ords) => !field_ords
+                .iter()
+                .filter_map(|ord| fields.get(*ord))
+                .flat_map(|text| cloze_numbers_in_string(text))
+                .any(|num| num > 0),
+        }
+    }
+
+    /// Compare the cards a note should have with the ones it already has.
+    #[allow(clippy::implicit_hasher)]
+    pub fn card_changes(&self, fields: &[&str], existing_ords: &HashSet<u16>) -> CardChanges {
+        let wanted = self.ords_to_generate(fields);
+        let mut to_add: Vec<_> = wanted.difference(existing_ords).copied().collect();
+        let mut to_remove: Vec<_> = existing_ords.difference(&wanted).copied().collect();
+        to_add.sort_unstable();
+        to_remove.sort_unstable();
+        CardChanges { to_add, to_remove }
+    }
+}
+
+impl FieldRequirements {
+    /// True if a card should be generated when the provided fields are
+    /// non-empty.
+    #[allow(clippy::implicit_hasher)]
+    pub fn satisfied_by(&self, nonempty_ords: &HashSet<u16>) -> bool {
+        match self {
+            FieldRequirements::Any(ords) => ords.iter().any(|ord| nonempty_ords.contains(ord)),
+            FieldRequirements::All(ords) => ords.iter().all(|ord| nonempty_ords.contains(ord)),
+            FieldRequirements::None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::cardgen::{CardChanges, CardGenContext};
+    use serde_json::json;
+    use std::collections::HashSet;
+
+    #[test]
+    fn test_standard() {
+        let ctx = CardGenContext::new_standard(
+            &[
+                "{{Front}}",
+                "{{#Back}}{{Front}}{{/Back}}",
+                "{{#Extra}}{{/Extra}}",
+                "{{#Front}}",
+            ],
+            &["Front", "Back", "Extra"],
+        );
+        let ords = |fields: &[&str]| {
+            let mut ords: Vec<_> = ctx.ords_to_generate(fields).into_iter().collect();
+            ords.sort_unstable();
+            ords
+        };
+        assert_eq!(ords(&["a", "", "c"]), vec![0]);
+        assert_eq!(ords(&["a", "b", ""]), vec![0, 1]);
+        assert_eq!(ords(&["<br>", "b", " "]), Vec::<u16>::new());
+
+        let existing: HashSet<u16> = vec![0, 2].into_iter().collect();
+        assert_eq!(
+            ctx.card_changes(&["a", "b", ""], &existing),
+            CardChanges {
+                to_add: vec![1],
+                to_remove: vec![2],
+            }
+        );
+
+        assert_eq!(
+            ctx.legacy_requirements(),
+            Some(json!([
+                [0, "any", [0]],
+                [1, "all", [0, 1]],
+                [2, "none", []],
+                [3, "none", []],
+            ]))
+        );
+    }
+
+    #[test]
+    fn test_cloze() {
+        let ctx = CardGenContext::new_cloze(
+            "{{cloze:Text}}{{#Extra}}{{text:cloze:Extra}}{{/Extra}}",
+            &["Text", "Back", "Extra"],
+        );
+        assert_eq!(ctx, CardGenContext::Cloze(vec![0, 2]));
+
+        let ords = |fields: &[&str]| {
+            let mut ords: Vec<_> = ctx.ords_to_generate(fields).into_iter().collect();
+            ords.sort_unstable();
+            ords
+        };
+        assert_eq!(
+            ords(&["{{c1::a}} {{c3::b}}", "{{c5::x}}", "{{c2::c}}"]),
+            vec![0, 1, 2]
+        );
+        // the first card is used if there are no deletions
+        assert_eq!(ords(&["text", "", ""]), vec![0]);
+        assert_eq!(ords(&["{{c0::text}}"]), vec![0]);
+        assert_eq!(ctx.legacy_requirements(), None);
+
+        assert!(ctx.missing_cloze(&["text", "{{c1::back}}", ""]));
+        assert!(ctx.missing_cloze(&["{{c0::text}}", "", ""]));
+        assert!(!ctx.missing_cloze(&["", "", "{{c2::extra}}"]));
+    }
+}
@@ -0,0 +1,325 @@
+// Copyright: Ankitects Pty Ltd and contributors
+// License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+use crate::text::strip_html;
+use lazy_static::lazy_static;
+use regex::{Captures, Regex};
+use std::borrow::Cow;
+use std::collections::HashSet;
+
+lazy_static! {
+    static ref CLOZE_TOKEN: Regex = Regex::new(
+        r#"(?xsi)
+            \{\{c(\d+)::    # 1 = opening tag with cloze number
+            |
+            \}\}            # closing tag
+        "#
+    )
+    .unwrap();
+    static ref MATHJAX: Regex = Regex::new(
+        r#"(?xsi)
+            (\\[(\[])       # 1 = mathjax opening tag
+            (.*?)           # 2 = inner content
+            (\\[])])        # 3 = mathjax closing tag
+           "#
+    )
+    .unwrap();
+}
+
+mod mathjax_caps {
+    pub const OPENING_TAG: usize = 1;
+    pub const INNER_TEXT: usize = 2;
+    pub const CLOSING_TAG: usize = 3;
+}
+
+// Parsing
+//----------------------------------------
+
+#[derive(Debug, PartialEq)]
+enum Token<'a> {
+    OpenCloze(u16, &'a str),
+    Text(&'a str),
+    CloseCloze,
+}
+
+fn tokenize(text: &str) -> Vec<Token> {
+    let mut tokens = vec![];
+    let mut last_end = 0;
+    for caps in CLOZE_TOKEN.captures_iter(text) {
+        let whole = caps.get(0).unwrap();
+        if whole.start() > last_end {
+            tokens.push(Token::Text(&text[last_end..whole.start()]));
+        }
+        tokens.push(match caps.get(1) {
+            Some(ord) => Token::OpenCloze(ord.as_str().parse().unwrap_or(0), whole.as_str()),
+            None => Token::CloseCloze,
+        });
+        last_end = whole.end();
+    }
+    if last_end < text.len() {
+        tokens.push(Token::Text(&text[last_end..]));
+    }
+    tokens
+}
+
+#[derive(Debug, PartialEq)]
+enum TextOrCloze<'a> {
+    Text(&'a str),
+    Cloze(ExtractedCloze<'a>),
+}
+
+#[derive(Debug, PartialEq)]
+struct ExtractedCloze<'a> {
+    ordinal: u16,
+    nodes: Vec<TextOrCloze<'a>>,
+    hint: Option<&'a str>,
+    // the text of the opening tag, needed if the cloze is not closed
+    open_tag: &'a str,
+}
+
+impl<'a> ExtractedCloze<'a> {
+    /// A hint is separated from the cloze text by the first :: in the
+    /// final text node.
+    fn split_hint(&mut self) {
+        if let Some(TextOrCloze::Text(text)) = self.nodes.last_mut() {
+            if let Some(idx) = text.find("::") {
+                self.hint = Some(&text[idx + 2..]);
+                *text = &text[..idx];
+            }
+        }
+    }
+}
+
+/// Parse text into a tree of text and (possibly nested) cloze deletions.
+/// Closing tags without a matching opening tag, and opening tags that are
+/// never closed, are treated as text.
+fn parse_text_with_clozes(text: &str) -> Vec<TextOrCloze> {
+    let mut stack: Vec<ExtractedCloze> = vec![];
+    let mut output = vec![];
+
+    for token in tokenize(text) {
+        match token {
+            Token::OpenCloze(ordinal, open_tag) => stack.push(ExtractedCloze {
+                ordinal,
+                nodes: vec![],
+                hint: None,
+                open_tag,
+            }),
+            Token::Text(text) => {
+                if let Some(cloze) = stack.last_mut() {
+                    cloze.nodes.push(TextOrCloze::Text(text));
+                } else {
+                    output.push(TextOrCloze::Text(text));
+                }
+            }
+            Token::CloseCloze => {
+                if let Some(mut cloze) = stack.pop() {
+                    cloze.split_hint();
+                    if let Some(parent) = stack.last_mut() {
+                        parent.nodes.push(TextOrCloze::Cloze(cloze));
+                    } else {
+                        output.push(TextOrCloze::Cloze(cloze));
+                    }
+                } else {
+                    output.push(TextOrCloze::Text("}}"));
+                }
+            }
+        }
+    }
+
+    // unclosed clozes are flattened back into their parents
+    while let Some(cloze) = stack.pop() {
+        let parent = match stack.last_mut() {
+            Some(parent) => &mut parent.nodes,
+            None => &mut output,
+        };
+        parent.push(TextOrCloze::Text(cloze.open_tag));
+        parent.extend(cloze.nodes);
+    }
+
+    output
+}
+
+fn add_cloze_numbers(nodes: &[TextOrCloze], set: &mut HashSet<u16>) {
+    for node in nodes {
+        if let TextOrCloze::Cloze(cloze) = node {
+            set.insert(cloze.ordinal);
+            add_cloze_numbers(&cloze.nodes, set);
+        }
+    }
+}
+
+// Rendering
+//----------------------------------------
+
+/// Append nodes to buf. If cloze_ord is None, all deletions are revealed
+/// without highlighting.
+fn render_nodes(nodes: &[TextOrCloze], cloze_ord: Option<u16>, question: bool, buf: &mut String) {
+    for node in nodes {
+        match node {
+            TextOrCloze::Text(text) => buf.push_str(text),
+            TextOrCloze::Cloze(cloze) => {
+                if Some(cloze.ordinal) != cloze_ord {
+                    // other cloze deletions are revealed
+                    render_nodes(&cloze.nodes, cloze_ord, question, buf);
+                } else if question {
+                    buf.push_str("<span class=cloze>[");
+                    buf.push_str(cloze.hint.unwrap_or("..."));
+                    buf.push_str("]</span>");
+                } else {
+                    buf.push_str("<span class=cloze>");
+                    render_nodes(&cloze.nodes, cloze_ord, question, buf);
+                    buf.push_str("</span>");
+                }
+            }
+        }
+    }
+}
+
+/// Replace the cloze deletion matching cloze_ord with [...] (or the hint)
+/// on the question side, or a highlighted copy of the text on the answer
+/// side. Other deletions are revealed. An empty string is returned if
+/// cloze_ord does not appear in the text.
+pub fn reveal_cloze_text(text: &str, cloze_ord: u16, question: bool) -> Cow<str> {
+    let nodes = parse_text_with_clozes(text);
+    let mut ords = HashSet::new();
+    add_cloze_numbers(&nodes, &mut ords);
+    if !ords.contains(&cloze_ord) {
+        // Anki treats the string as blank, which add-ons like cloze
+        // overlapper take advantage of
+        return "".into();
+    }
+
+    let mut buf = String::with_capacity(text.len());
+    render_nodes(&nodes, Some(cloze_ord), question, &mut buf);
+    buf.into()
+}
+
+fn strip_html_inside_mathjax(text: &str) -> Cow<str> {
+    MATHJAX.replace_all(text, |caps: &Captures| -> String {
+        format!(
+            "{}{}{}",
+            caps.get(mathjax_caps::OPENING_TAG).unwrap().as_str(),
+            strip_html(caps.get(mathjax_caps::INNER_TEXT).unwrap().as_str()).as_ref(),
+            caps.get(mathjax_caps::CLOSING_TAG).unwrap().as_str()
+        )
+    })
+}
+
+/// Render the provided cloze ordinal (starting at 1) for the question or
+/// answer side, as the cloze filter does.
+pub fn render_cloze(text: &str, cloze_ord: u16, question: bool) -> Cow<str> {
+    match reveal_cloze_text(text, cloze_ord, question) {
+        Cow::Borrowed(b) => strip_html_inside_mathjax(b),
+        Cow::Owned(o) => strip_html_inside_mathjax(&o).into_owned().into(),
+    }
+}
+
+/// Produce a copy of the text for each cloze ordinal with that deletion
+/// shown as on the question side, followed by a copy with all deletions
+/// revealed. This allows latex in any state to be found.
+pub fn expand_clozes_to_reveal_latex(text: &str) -> String {
+    let nodes = parse_text_with_clozes(text);
+    let mut ords = HashSet::new();
+    add_cloze_numbers(&nodes, &mut ords);
+    let mut ords: Vec<_> = ords.into_iter().collect();
+    ords.sort_unstable();
+
+    let mut buf = String::new();
+    for ord in ords {
+        render_nodes(&nodes, Some(ord), true, &mut buf);
+        buf.push(' ');
+    }
+    render_nodes(&nodes, None, false, &mut buf);
+
+    buf
+}
+
+/// The ordinals of all cloze deletions in the text, including nested ones.
+pub fn cloze_numbers_in_string(html: &str) -> HashSet<u16> {
+    let mut set = HashSet::with_capacity(4);
+    add_cloze_numbers(&parse_text_with_clozes(html), &mut set);
+    set
+}
+
+#[cfg(test)]
+mod test {
+    use crate::cloze::{cloze_numbers_in_string, expand_clozes_to_reveal_latex, render_cloze};
+    use crate::text::strip_html;
+    use std::collections::HashSet;
+
+    #[test]
+    fn test_cloze_numbers() {
+        assert_eq!(
+            cloze_numbers_in_string("test"),
+            vec![].into_iter().collect::<HashSet<u16>>()
+        );
+        assert_eq!(
+            cloze_numbers_in_string("{{c2::te}}{{c1::s}}t{{"),
+            vec![1, 2].into_iter().collect::<HashSet<u16>>()
+        );
+        assert_eq!(
+            cloze_numbers_in_string("{{c1::foo {{c2::bar}}}} {{c3::baz}"),
+            vec![1, 2].into_iter().collect::<HashSet<u16>>()
+        );
+    }
+
+    #[test]
+    fn test_nested() {
+        let text = "{{c1::foo {{c2::bar::hint}}::outer}} }}";
+        assert_eq!(
+            render_cloze(text, 1, true),
+            "<span class=cloze>[outer]</span> }}"
+        );
+        assert_eq!(
+            render_cloze(text, 1, false),
+            "<span class=cloze>foo bar</span> }}"
+        );
+        assert_eq!(
+            render_cloze(text, 2, true),
+            "foo <span class=cloze>[hint]</span> }}"
+        );
+        assert_eq!(
+            render_cloze(text, 2, false),
+            "foo <span class=cloze>bar</span> }}"
+        );
+
+        // an unclosed outer deletion is treated as text
+        assert_eq!(
+            render_cloze("{{c1::a {{c2::b}}", 2, false),
+            "{{c1::a <span class=cloze>b</span>"
+        );
+        assert_eq!(render_cloze("{{c1::a {{c2::b}}", 1, false), "");
+    }
+
+    #[test]
+    fn test_render_cloze() {
+        let text = "{{c1::one}} {{c2::two::hint}} {{c1::three}}";
+        assert_eq!(
+            render_cloze(text, 1, true),
+            "<span class=cloze>[...]</span> two <span class=cloze>[...]</span>"
+        );
+        assert_eq!(
+            render_cloze(text, 1, false),
+            "<span class=cloze>one</span> two <span class=cloze>three</span>"
+        );
+        assert_eq!(strip_html(&render_cloze(text, 2, true)), "one [hint] three");
+        assert_eq!(strip_html(&render_cloze(text, 2, false)), "one two three");
+        assert_eq!(render_cloze(text, 3, true), "");
+        assert_eq!(render_cloze("no clozes", 1, false), "");
+
+        // html inside mathjax is stripped
+        assert_eq!(
+            render_cloze(r"{{c1::\(a<br>b\)}}", 1, false),
+            r"<span class=cloze>\(ab\)</span>"
+        );
+    }
+
+    #[test]
+    fn test_expand() {
+        assert_eq!(
+            expand_clozes_to_reveal_latex("{{c1::a}} {{c2::b::h}}"),
+            "<span class=cloze>[...]</span> b a <span class=cloze>[h]</span> a b"
+        );
+    }
+}
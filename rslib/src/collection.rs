@@ -0,0 +1,242 @@
+// Copyright: Ankitects Pty Ltd and contributors
+// License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+//! Opening and closing the collection file. While open, the collection is
+//! in WAL mode, so a write interrupted by a crash is discarded when the
+//! collection is next opened, instead of damaging the file. A lock file
+//! next to the collection is exclusively locked by the process that has
+//! it open, so a second copy of Anki can't open it at the same time. The
+//! OS releases the lock if the process dies, but the file is only removed
+//! when the collection is closed, so a collection that wasn't closed
+//! cleanly can be detected.
+
+use crate::err::{AnkiError, DBErrorKind, Result};
+use crate::storage::SqliteStorage;
+use rusqlite::NO_PARAMS;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// The lock file for the collection at `col_path`.
+fn lock_path(col_path: &Path) -> PathBuf {
+    let mut path = col_path.as_os_str().to_owned();
+    path.push(".lock");
+    path.into()
+}
+
+/// An exclusive lock on the lock file of an open collection, held until
+/// it's dropped.
+pub struct CollectionLock {
+    _file: File,
+}
+
+/// Lock `file`, returning false if another process holds the lock.
+#[cfg(unix)]
+fn try_lock(file: &File) -> io::Result<bool> {
+    use std::os::unix::io::AsRawFd;
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } == 0 {
+        return Ok(true);
+    }
+    let err = io::Error::last_os_error();
+    if err.kind() == io::ErrorKind::WouldBlock {
+        Ok(false)
+    } else {
+        Err(err)
+    }
+}
+
+/// Lock `file`, returning false if another process holds the lock.
+#[cfg(windows)]
+fn try_lock(file: &File) -> io::Result<bool> {
+    use std::os::windows::io::AsRawHandle;
+    use winapi::shared::winerror::ERROR_LOCK_VIOLATION;
+    use winapi::um::fileapi::LockFileEx;
+    use winapi::um::minwinbase::{LOCKFILE_EXCLUSIVE_LOCK, LOCKFILE_FAIL_IMMEDIATELY, OVERLAPPED};
+
+    let mut overlapped: OVERLAPPED = unsafe { std::mem::zeroed() };
+    let locked = unsafe {
+        LockFileEx(
+            file.as_raw_handle() as _,
+            LOCKFILE_EXCLUSIVE_LOCK | LOCKFILE_FAIL_IMMEDIATELY,
+            0,
+            !0,
+            !0,
+            &mut overlapped,
+        )
+    };
+    if locked != 0 {
+        return Ok(true);
+    }
+    let err = io::Error::last_os_error();
+    if err.raw_os_error() == Some(ERROR_LOCK_VIOLATION as i32) {
+        Ok(false)
+    } else {
+        Err(err)
+    }
+}
+
+/// Lock the collection at `col_path`, returning the lock and true if the
+/// lock file was left behind by a session that wasn't closed.
+fn lock_collection(col_path: &Path) -> Result<(CollectionLock, bool)> {
+    let lock_path = lock_path(col_path);
+    loop {
+        let (file, existed) = match OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&lock_path)
+        {
+            Ok(file) => (file, false),
+            Err(err) if err.kind() == io::ErrorKind::AlreadyExists => {
+                match OpenOptions::new().write(true).open(&lock_path) {
+                    Ok(file) => (file, true),
+                    // removed by a close in the meantime
+                    Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+                    Err(err) => return Err(err.into()),
+                }
+            }
+            Err(err) => return Err(err.into()),
+        };
+        if !try_lock(&file)? {
+            return Err(AnkiError::CollectionInUse {
+                lock_path: lock_path.to_string_lossy().into(),
+            });
+        }
+        // if the file was removed by a close before we locked it, another
+        // process may lock a new file, so start over
+        if lock_path.exists() {
+            return Ok((CollectionLock { _file: file }, existed));
+        }
+    }
+}
+
+/// Open the collection at `col_path`, creating it if it doesn't exist, and
+/// switch it to WAL mode. Unless this process already holds it, `lock` is
+/// set to the collection's lock, which should be kept until the
+/// collection is closed. Returns true if the collection wasn't closed
+/// cleanly last time, in which case any incomplete write has been rolled
+/// back. Fails with AnkiError::CollectionInUse if another process has it
+/// open.
+pub fn open_collection(col_path: &Path, lock: &mut Option<CollectionLock>) -> Result<bool> {
+    let (new_lock, recovered) = match lock {
+        // reopened after a close that didn't complete
+        Some(_) => (None, false),
+        None => {
+            let (new_lock, recovered) = lock_collection(col_path)?;
+            (Some(new_lock), recovered)
+        }
+    };
+
+    // SQLite rolls back a torn transaction when the file is first opened
+    let storage = SqliteStorage::open_or_create(col_path)?;
+    if recovered {
+        // and the committed changes in the log are moved into the file
+        storage
+            .db
+            .query_row("pragma wal_checkpoint(truncate)", NO_PARAMS, |_| Ok(()))?;
+    }
+    let mode: String = storage
+        .db
+        .query_row("pragma journal_mode = wal", NO_PARAMS, |row| row.get(0))?;
+    if mode != "wal" {
+        return Err(AnkiError::db_error(
+            format!("unable to enable WAL mode, got {}", mode),
+            DBErrorKind::Other,
+        ));
+    }
+
+    if new_lock.is_some() {
+        *lock = new_lock;
+    }
+    Ok(recovered)
+}
+
+/// Close the collection at `col_path`, moving the log into the main file,
+/// and release `lock`. If `downgrade` is true, the collection is switched
+/// back to the legacy journal mode, so it can be copied as a single file
+/// and opened by older clients. Other connections must be closed first.
+pub fn close_collection(
+    col_path: &Path,
+    lock: &mut Option<CollectionLock>,
+    downgrade: bool,
+) -> Result<()> {
+    let storage = SqliteStorage::open_or_create(col_path)?;
+    storage
+        .db
+        .query_row("pragma wal_checkpoint(truncate)", NO_PARAMS, |_| Ok(()))?;
+    if downgrade {
+        storage
+            .db
+            .query_row("pragma journal_mode = delete", NO_PARAMS, |_| Ok(()))?;
+    }
+    drop(storage);
+
+    // the file is removed before the lock is released, so a process
+    // waiting on it can't take over a file that's about to disappear
+    if let Some(lock) = lock.take() {
+        match fs::remove_file(lock_path(col_path)) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err.into()),
+            _ => (),
+        }
+        drop(lock);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::collection::{close_collection, lock_path, open_collection};
+    use crate::err::{AnkiError, Result};
+    use crate::storage::SqliteStorage;
+    use rusqlite::NO_PARAMS;
+    use std::fs;
+    use std::path::Path;
+    use tempfile::tempdir;
+
+    fn journal_mode(col_path: &Path) -> Result<String> {
+        let storage = SqliteStorage::open_or_create(col_path)?;
+        Ok(storage
+            .db
+            .query_row("pragma journal_mode", NO_PARAMS, |row| row.get(0))?)
+    }
+
+    #[test]
+    fn test_open_and_close() -> Result<()> {
+        let dir = tempdir()?;
+        let col_path = dir.path().join("collection.anki2");
+
+        let mut lock = None;
+        assert!(!open_collection(&col_path, &mut lock)?);
+        assert!(lock.is_some());
+        assert!(lock_path(&col_path).exists());
+        assert_eq!(journal_mode(&col_path)?, "wal");
+        // reopening while holding the lock is fine
+        assert!(!open_collection(&col_path, &mut lock)?);
+
+        // but anyone else is kept out, even within the same process
+        let mut other_lock = None;
+        match open_collection(&col_path, &mut other_lock) {
+            Err(AnkiError::CollectionInUse { .. }) => (),
+            other => panic!("unexpected: {:?}", other),
+        }
+        assert!(other_lock.is_none());
+        // and can't remove the lock file
+        close_collection(&col_path, &mut other_lock, false)?;
+        assert!(lock_path(&col_path).exists());
+
+        close_collection(&col_path, &mut lock, true)?;
+        assert!(lock.is_none());
+        assert!(!lock_path(&col_path).exists());
+        assert_eq!(journal_mode(&col_path)?, "delete");
+
+        // a lock file left behind by a process that died is unlocked, and
+        // shows the collection wasn't closed
+        fs::write(lock_path(&col_path), "")?;
+        assert!(open_collection(&col_path, &mut lock)?);
+        close_collection(&col_path, &mut lock, false)?;
+        assert_eq!(journal_mode(&col_path)?, "wal");
+        assert!(!open_collection(&col_path, &mut lock)?);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,529 @@
+// Copyright: Ankitects Pty Ltd and contributors
+// License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+//! Check Database: find and fix rows that are inconsistent with each other
+//! or with the notetypes and decks, then rebuild the caches that are
+//! derived from the notes. The problems found are counted by kind, so the
+//! frontend can describe them.
+
+use crate::err::{AnkiError, Result};
+use crate::notes::{field_checksum, split_tags};
+use crate::notetypes::{NoteType, NoteTypeKind};
+use crate::sched::ids_to_string;
+use crate::storage::{GraveKind, SqliteStorage};
+use crate::text::sort_field_text;
+use rusqlite::{params, ToSql, NO_PARAMS};
+use serde_json::Value;
+use std::collections::HashMap;
+
+/// What Check Database found and fixed.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CheckDatabaseOutput {
+    /// SQLite's integrity check failed, so nothing else was checked.
+    pub corrupt: bool,
+    /// Removed.
+    pub notes_with_missing_notetype: u32,
+    /// Templates with a deck override of "None", written by AnkiDroid.
+    pub templates_with_invalid_deck: u32,
+    /// Removed.
+    pub cards_with_missing_template: u32,
+    /// Removed.
+    pub notes_with_wrong_field_count: u32,
+    /// Removed.
+    pub notes_without_cards: u32,
+    /// Removed.
+    pub cards_with_missing_note: u32,
+    /// Cards with an original deck or due set outside a filtered deck.
+    pub cards_with_invalid_properties: u32,
+    /// Moved to the default deck.
+    pub cards_with_missing_deck: u32,
+    /// New cards with a position over 2,000,000, which is too large for
+    /// older clients, wrapped back to 1,000,000.
+    pub new_cards_with_large_due: u32,
+    /// Rescheduled for today.
+    pub reviews_with_invalid_due: u32,
+    /// Fractional intervals and due numbers, written by an old scheduler
+    /// bug.
+    pub cards_with_fractional_values: u32,
+    pub revlog_with_fractional_values: u32,
+}
+
+impl CheckDatabaseOutput {
+    pub fn problems_found(&self) -> bool {
+        *self
+            != CheckDatabaseOutput {
+                corrupt: self.corrupt,
+                ..Default::default()
+            }
+    }
+}
+
+/// The stages of a check, in the order they run.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DatabaseCheckStage {
+    Integrity,
+    Notes,
+    Cards,
+    Tags,
+    FieldCache,
+    DueNumbers,
+    Reindex,
+}
+
+fn start_stage<F>(progress_cb: &mut F, stage: DatabaseCheckStage) -> Result<()>
+where
+    F: FnMut(DatabaseCheckStage) -> bool,
+{
+    if progress_cb(stage) {
+        Ok(())
+    } else {
+        Err(AnkiError::Interrupted)
+    }
+}
+
+/// Check and fix the collection, marking the schema modified if anything
+/// was changed, so the fixes are sent in a full sync. `today` is the
+/// scheduler's day number. `progress_cb` is called before each stage, and
+/// can return false to stop, which rolls back the fixes and returns
+/// AnkiError::Interrupted.
+pub fn check_database<F>(
+    storage: &mut SqliteStorage,
+    today: u32,
+    usn: i32,
+    mtime_secs: i64,
+    mut progress_cb: F,
+) -> Result<CheckDatabaseOutput>
+where
+    F: FnMut(DatabaseCheckStage) -> bool,
+{
+    start_stage(&mut progress_cb, DatabaseCheckStage::Integrity)?;
+    let integrity: String = storage
+        .db
+        .query_row("pragma integrity_check", NO_PARAMS, |row| row.get(0))?;
+    if integrity != "ok" {
+        return Ok(CheckDatabaseOutput {
+            corrupt: true,
+            ..Default::default()
+        });
+    }
+
+    let out = storage.transact(|storage| {
+        let mut checker = DatabaseChecker {
+            storage,
+            today,
+            usn,
+            mtime_secs,
+            out: CheckDatabaseOutput::default(),
+        };
+        checker.check(&mut progress_cb)?;
+        if checker.out.problems_found() {
+            storage.mark_schema_modified(mtime_secs * 1000)?;
+        }
+        Ok(checker.out)
+    })?;
+
+    start_stage(&mut progress_cb, DatabaseCheckStage::Reindex)?;
+    storage.db.execute_batch("reindex")?;
+
+    Ok(out)
+}
+
+struct DatabaseChecker<'a> {
+    storage: &'a SqliteStorage,
+    today: u32,
+    usn: i32,
+    mtime_secs: i64,
+    out: CheckDatabaseOutput,
+}
+
+impl DatabaseChecker<'_> {
+    fn check<F>(&mut self, progress_cb: &mut F) -> Result<()>
+    where
+        F: FnMut(DatabaseCheckStage) -> bool,
+    {
+        start_stage(progress_cb, DatabaseCheckStage::Notes)?;
+        let notetypes = self.storage.get_all_notetypes()?;
+        self.check_notes_and_notetypes(&notetypes)?;
+        start_stage(progress_cb, DatabaseCheckStage::Cards)?;
+        self.check_missing_cards_and_notes()?;
+        self.check_card_properties()?;
+        start_stage(progress_cb, DatabaseCheckStage::Tags)?;
+        self.rebuild_tags()?;
+        start_stage(progress_cb, DatabaseCheckStage::FieldCache)?;
+        self.rebuild_field_cache(&notetypes)?;
+        start_stage(progress_cb, DatabaseCheckStage::DueNumbers)?;
+        self.check_due_numbers()?;
+
+        Ok(())
+    }
+
+    fn ids(&self, sql: &str, args: &[&dyn ToSql]) -> Result<Vec<i64>> {
+        let mut stmt = self.storage.db.prepare(sql)?;
+        let ids = stmt
+            .query_map(args, |row| row.get(0))?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(ids)
+    }
+
+    /// Remove cards, adding graves so the removal is synced.
+    fn remove_cards(&self, card_ids: &[i64]) -> Result<u32> {
+        for id in card_ids {
+            self.storage.remove_card(*id)?;
+            self.storage.add_grave(*id, GraveKind::Card, self.usn)?;
+        }
+        Ok(card_ids.len() as u32)
+    }
+
+    /// Remove notes and their cards, adding graves.
+    fn remove_notes(&self, note_ids: &[i64]) -> Result<u32> {
+        for id in note_ids {
+            let card_ids = self.ids("select id from cards where nid = ?", &[id])?;
+            self.remove_cards(&card_ids)?;
+            self.storage.remove_note(*id)?;
+            self.storage.add_grave(*id, GraveKind::Note, self.usn)?;
+        }
+        Ok(note_ids.len() as u32)
+    }
+
+    // Notes and notetypes
+    //----------------------------------------
+
+    fn check_notes_and_notetypes(&mut self, notetypes: &HashMap<i64, NoteType>) -> Result<()> {
+        let mut notetype_ids: Vec<_> = notetypes.keys().cloned().collect();
+        notetype_ids.sort_unstable();
+        let ids = self.ids(
+            &format!(
+                "select id from notes where mid not in {}",
+                ids_to_string(&notetype_ids)
+            ),
+            &[],
+        )?;
+        self.out.notes_with_missing_notetype = self.remove_notes(&ids)?;
+
+        for notetype in notetypes.values() {
+            self.fix_template_decks(notetype)?;
+
+            if notetype.kind() == NoteTypeKind::Standard {
+                let ords: Vec<_> = notetype
+                    .templates
+                    .iter()
+                    .map(|t| i64::from(t.ord))
+                    .collect();
+                let ids = self.ids(
+                    &format!(
+                        "select id from cards where ord not in {} and nid in
+(select id from notes where mid = ?)",
+                        ids_to_string(&ords)
+                    ),
+                    &[&notetype.id],
+                )?;
+                self.out.cards_with_missing_template += self.remove_cards(&ids)?;
+            }
+
+            let mut ids = vec![];
+            let mut stmt = self
+                .storage
+                .db
+                .prepare("select id, flds from notes where mid = ?")?;
+            let mut rows = stmt.query(params![notetype.id])?;
+            while let Some(row) = rows.next()? {
+                let fields: String = row.get(1)?;
+                if fields.split('\x1f').count() != notetype.fields.len() {
+                    ids.push(row.get(0)?);
+                }
+            }
+            self.out.notes_with_wrong_field_count += self.remove_notes(&ids)?;
+        }
+
+        Ok(())
+    }
+
+    /// Older AnkiDroid versions saved a missing deck override as "None".
+    fn fix_template_decks(&mut self, notetype: &NoteType) -> Result<()> {
+        let mut notetype = notetype.clone();
+        let mut fixed = 0;
+        for template in &mut notetype.templates {
+            if template.other.get("did") == Some(&Value::String("None".into())) {
+                template.other.insert("did".into(), Value::Null);
+                fixed += 1;
+            }
+        }
+        if fixed > 0 {
+            self.storage.add_or_update_notetype(&notetype)?;
+            self.out.templates_with_invalid_deck += fixed;
+        }
+
+        Ok(())
+    }
+
+    fn check_missing_cards_and_notes(&mut self) -> Result<()> {
+        let ids = self.ids(
+            "select id from notes where id not in (select distinct nid from cards)",
+            &[],
+        )?;
+        for id in &ids {
+            self.storage.remove_note(*id)?;
+            self.storage.add_grave(*id, GraveKind::Note, self.usn)?;
+        }
+        self.out.notes_without_cards = ids.len() as u32;
+
+        let ids = self.ids(
+            "select id from cards where nid not in (select id from notes)",
+            &[],
+        )?;
+        self.out.cards_with_missing_note = self.remove_cards(&ids)?;
+
+        Ok(())
+    }
+
+    // Cards
+    //----------------------------------------
+
+    fn check_card_properties(&mut self) -> Result<()> {
+        let db = &self.storage.db;
+        let decks = self.storage.get_all_decks()?;
+        let mut deck_ids: Vec<_> = decks.keys().cloned().collect();
+        deck_ids.sort_unstable();
+        let mut normal_deck_ids: Vec<_> = decks
+            .values()
+            .filter(|deck| !deck.is_filtered())
+            .map(|deck| deck.id)
+            .collect();
+        normal_deck_ids.sort_unstable();
+
+        // an original due is only needed in filtered decks
+        let mut fixed = db.execute(
+            "update cards set odue = 0, mod = ?, usn = ?
+where odue > 0 and (type = 1 or queue = 2) and not odid",
+            params![self.mtime_secs, self.usn],
+        )?;
+        fixed += db.execute(
+            &format!(
+                "update cards set odid = 0, odue = 0, mod = ?, usn = ?
+where odid > 0 and did in {}",
+                ids_to_string(&normal_deck_ids)
+            ),
+            params![self.mtime_secs, self.usn],
+        )?;
+        self.out.cards_with_invalid_properties = fixed as u32;
+
+        self.out.cards_with_missing_deck = db.execute(
+            &format!(
+                "update cards set did = 1, odid = 0, odue = 0, mod = ?, usn = ?
+where did not in {}",
+                ids_to_string(&deck_ids)
+            ),
+            params![self.mtime_secs, self.usn],
+        )? as u32;
+
+        Ok(())
+    }
+
+    fn check_due_numbers(&mut self) -> Result<()> {
+        let db = &self.storage.db;
+
+        // new card positions must fit in 32 bits, so positions over 2
+        // million are wrapped back to 1 million
+        self.out.new_cards_with_large_due = db.execute(
+            "update cards set due = 1000000 + due % 1000000, mod = ?, usn = ?
+where due >= 2000000 and type = 0",
+            params![self.mtime_secs, self.usn],
+        )? as u32;
+        let next_position: Option<i64> = db.query_row(
+            "select max(due) + 1 from cards where type = 0",
+            NO_PARAMS,
+            |row| row.get(0),
+        )?;
+        self.storage
+            .set_config_value("nextPos", &next_position.unwrap_or_default())?;
+
+        self.out.reviews_with_invalid_due = db.execute(
+            "update cards set due = ?, ivl = 1, mod = ?, usn = ?
+where queue = 2 and due > 100000",
+            params![self.today, self.mtime_secs, self.usn],
+        )? as u32;
+
+        self.out.cards_with_fractional_values = db.execute(
+            "update cards set ivl = round(ivl), due = round(due)
+where ivl != round(ivl) or due != round(due)",
+            NO_PARAMS,
+        )? as u32;
+        self.out.revlog_with_fractional_values = db.execute(
+            "update revlog set ivl = round(ivl), lastIvl = round(lastIvl)
+where ivl != round(ivl) or lastIvl != round(lastIvl)",
+            NO_PARAMS,
+        )? as u32;
+
+        Ok(())
+    }
+
+    // Caches
+    //----------------------------------------
+
+    /// Replace the tag list with the tags used by notes, keeping the usn of
+    /// tags that were already registered.
+    fn rebuild_tags(&self) -> Result<()> {
+        let old_tags = self.storage.get_all_tags()?;
+        let mut tags = HashMap::new();
+        let mut stmt = self.storage.db.prepare("select distinct tags from notes")?;
+        let mut rows = stmt.query(NO_PARAMS)?;
+        while let Some(row) = rows.next()? {
+            let note_tags: String = row.get(0)?;
+            for tag in split_tags(&note_tags) {
+                if !tags.contains_key(tag) {
+                    let usn = old_tags.get(tag).cloned().unwrap_or(self.usn);
+                    tags.insert(tag.to_string(), usn);
+                }
+            }
+        }
+
+        self.storage.set_all_tags(&tags)
+    }
+
+    /// Recalculate the sort field and first field checksum of every note.
+    fn rebuild_field_cache(&self, notetypes: &HashMap<i64, NoteType>) -> Result<()> {
+        let mut update = self
+            .storage
+            .db
+            .prepare("update notes set sfld = ?, csum = ? where id = ?")?;
+        let mut stmt = self.storage.db.prepare("select id, mid, flds from notes")?;
+        let mut rows = stmt.query(NO_PARAMS)?;
+        while let Some(row) = rows.next()? {
+            let notetype = match notetypes.get(&row.get(1)?) {
+                Some(notetype) => notetype,
+                None => continue,
+            };
+            let fields: String = row.get(2)?;
+            let fields: Vec<_> = fields.split('\x1f').collect();
+            let sort_field = fields
+                .get(notetype.sort_field_idx as usize)
+                .cloned()
+                .unwrap_or_default();
+            let sort_field: String = sort_field_text(sort_field).into();
+            update.execute(params![
+                sort_field,
+                field_checksum(fields[0]),
+                row.get::<_, i64>(0)?,
+            ])?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::card::{Card, CardType};
+    use crate::dbcheck::{check_database, CheckDatabaseOutput, DatabaseCheckStage};
+    use crate::err::{AnkiError, Result};
+    use crate::notes::{field_checksum, Note};
+    use crate::storage::testutil::{add_deck, notetype, open_test_collection};
+
+    #[test]
+    fn test_check_database() -> Result<()> {
+        let (_dir, mut storage) = open_test_collection()?;
+        add_deck(&storage, 1, "Default")?;
+        // sorting on the back, with a template deck the legacy code wrote
+        let mut notetype = notetype(1, "Basic", &["Front", "Back"], &[""]);
+        notetype.sort_field_idx = 1;
+        notetype.templates[0]
+            .other
+            .insert("did".into(), "None".into());
+        storage.add_or_update_notetype(&notetype)?;
+
+        let mut note = Note {
+            notetype_id: 1,
+            tags: vec!["one".into()],
+            fields: vec!["front".into(), "<b>back</b>".into()],
+            ..Default::default()
+        };
+        storage.add_note(&mut note)?;
+        let mut card = Card {
+            note_id: note.id,
+            deck_id: 1,
+            due: 2_000_005,
+            ..Default::default()
+        };
+        storage.add_card(&mut card)?;
+        // a card in a missing deck, and one with a missing template
+        let mut card2 = Card {
+            id: 0,
+            deck_id: 5,
+            ctype: CardType::Review,
+            ..card.clone()
+        };
+        storage.add_card(&mut card2)?;
+        let mut card3 = Card {
+            id: 0,
+            ordinal: 1,
+            ..card.clone()
+        };
+        storage.add_card(&mut card3)?;
+        // a note with too few fields, and one without a notetype
+        let mut note2 = Note {
+            id: 0,
+            fields: vec!["one".into()],
+            ..note.clone()
+        };
+        storage.add_note(&mut note2)?;
+        let mut note3 = Note {
+            id: 0,
+            notetype_id: 2,
+            ..note.clone()
+        };
+        storage.add_note(&mut note3)?;
+
+        // stopping part way through rolls back the fixes
+        let res = check_database(&mut storage, 10, -1, 0, |stage| {
+            stage != DatabaseCheckStage::Tags
+        });
+        match res.err() {
+            Some(AnkiError::Interrupted) => (),
+            other => panic!("unexpected: {:?}", other),
+        }
+        assert_eq!(storage.get_card(card2.id)?.unwrap().deck_id, 5);
+
+        let mut stages = vec![];
+        let out = check_database(&mut storage, 10, -1, 0, |stage| {
+            stages.push(stage);
+            true
+        })?;
+        assert_eq!(stages.len(), 7);
+        assert_eq!(
+            out,
+            CheckDatabaseOutput {
+                notes_with_missing_notetype: 1,
+                templates_with_invalid_deck: 1,
+                cards_with_missing_template: 1,
+                notes_with_wrong_field_count: 1,
+                cards_with_missing_deck: 1,
+                new_cards_with_large_due: 1,
+                ..Default::default()
+            }
+        );
+        assert!(out.problems_found());
+
+        assert_eq!(storage.get_card(card.id)?.unwrap().due, 1_000_005);
+        assert_eq!(storage.get_card(card2.id)?.unwrap().deck_id, 1);
+        assert_eq!(storage.get_card(card3.id)?, None);
+        assert_eq!(storage.get_note(note2.id)?, None);
+        assert_eq!(storage.get_note(note3.id)?, None);
+        let note = storage.get_note(note.id)?.unwrap();
+        assert_eq!(note.sort_field, "back");
+        assert_eq!(note.checksum, field_checksum("front"));
+        assert_eq!(
+            storage.get_all_tags()?.into_iter().collect::<Vec<_>>(),
+            vec![("one".to_string(), -1)]
+        );
+        assert_eq!(
+            storage.get_notetype(1)?.unwrap().templates[0].other["did"],
+            serde_json::Value::Null
+        );
+        assert_eq!(storage.get_config_value::<i64>("nextPos")?, Some(1_000_006));
+
+        // a second check finds nothing
+        assert!(!check_database(&mut storage, 10, -1, 0, |_| true)?.problems_found());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,621 @@
+// Copyright: Ankitects Pty Ltd and contributors
+// License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+//! Decks and deck options are stored as JSON in the col table. Only the
+//! keys the Rust code uses are typed; the rest are kept as they are, so
+//! they survive a round trip.
+
+use crate::err::{AnkiError, DeckConfErrorKind, DeckRenameErrorKind, Result};
+use crate::storage::{now_millis, GraveKind, SqliteStorage};
+use crate::text::normalize_to_nfc;
+use serde_derive::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use std::collections::{HashMap, HashSet};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Deck {
+    pub id: i64,
+    /// The full name, with components separated by ::.
+    pub name: String,
+    #[serde(rename = "mod")]
+    pub mtime_secs: i64,
+    pub usn: i32,
+    /// 1 if the deck is a filtered deck.
+    #[serde(rename = "dyn")]
+    pub filtered: u8,
+    #[serde(flatten)]
+    pub other: Map<String, Value>,
+}
+
+impl Deck {
+    pub fn is_filtered(&self) -> bool {
+        self.filtered != 0
+    }
+
+    /// The id of the options group a normal deck uses.
+    pub fn config_id(&self) -> Option<i64> {
+        if self.is_filtered() {
+            None
+        } else {
+            self.other.get("conf").and_then(Value::as_i64)
+        }
+    }
+
+    /// A new normal deck called `name`, with this deck's options but none of
+    /// its state. The caller assigns the id.
+    pub(crate) fn blank_copy(&self, name: String, mtime_secs: i64, usn: i32) -> Deck {
+        let mut deck = self.clone();
+        for key in &["newToday", "revToday", "lrnToday", "timeToday"] {
+            if deck.other.contains_key(*key) {
+                deck.other.insert((*key).into(), json!([0, 0]));
+            }
+        }
+        deck.other.insert("desc".into(), "".into());
+        deck.other.insert("collapsed".into(), false.into());
+        deck.other.insert("browserCollapsed".into(), false.into());
+        deck.name = name;
+        deck.mtime_secs = mtime_secs;
+        deck.usn = usn;
+        deck
+    }
+}
+
+/// An options group, which may be shared by many decks.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DeckConf {
+    pub id: i64,
+    pub name: String,
+    #[serde(rename = "mod")]
+    pub mtime_secs: i64,
+    pub usn: i32,
+    #[serde(flatten)]
+    pub other: Map<String, Value>,
+}
+
+// Names
+//----------------------------------------
+
+/// The name of a deck's parent, if it has one.
+pub(crate) fn parent_name(name: &str) -> Option<&str> {
+    name.rfind("::").map(|idx| &name[..idx])
+}
+
+/// The last component of a deck's name.
+fn basename(name: &str) -> &str {
+    name.rsplit("::").next().unwrap_or_default()
+}
+
+/// Tidy up a deck name the user entered. Components are trimmed, empty
+/// components are dropped, and double quotes are removed, as they can't be
+/// searched for. An empty result is not a valid name.
+pub fn normalize_deck_name(name: &str) -> String {
+    let name = normalize_to_nfc(name).replace('"', "");
+    let components: Vec<_> = name
+        .split("::")
+        .map(str::trim)
+        .filter(|component| !component.is_empty())
+        .collect();
+    components.join("::")
+}
+
+/// Deck names are unique regardless of case, so they're compared with this.
+fn name_key(name: &str) -> String {
+    normalize_to_nfc(name).to_lowercase()
+}
+
+// Renaming
+//----------------------------------------
+
+/// Rename a deck in a single transaction, taking its children with it.
+/// Missing parents are created, and the name takes the case of the parents
+/// that already exist. It's an error for the new name to be taken by
+/// another deck, or to be the child of a filtered deck. When a child's new
+/// name is taken by a normal deck, the child's cards are moved into that
+/// deck and the child is removed. Returns the ids of the removed children.
+pub fn rename_deck(
+    storage: &mut SqliteStorage,
+    deck_id: i64,
+    new_name: &str,
+    usn: i32,
+    mtime_secs: i64,
+) -> Result<Vec<i64>> {
+    storage.transact(|storage| {
+        let mut decks = storage.get_all_decks()?;
+        let old_name = decks
+            .get(&deck_id)
+            .map(|deck| deck.name.clone())
+            .ok_or_else(|| AnkiError::invalid_input("deck not found"))?;
+        let new_name = normalize_deck_name(new_name);
+        if new_name.is_empty() {
+            return Err(AnkiError::invalid_input("empty deck name"));
+        }
+        if new_name == old_name {
+            return Ok(vec![]);
+        }
+
+        // the deck and its children keep their ids, so only the other decks
+        // can stand in their way
+        let old_prefix = format!("{}::", old_name);
+        let mut renamed: Vec<&Deck> = decks
+            .values()
+            .filter(|deck| deck.id == deck_id || deck.name.starts_with(&old_prefix))
+            .collect();
+        // parents before their children
+        renamed.sort_by(|a, b| a.name.cmp(&b.name));
+        let renamed_ids: HashSet<i64> = renamed.iter().map(|deck| deck.id).collect();
+        let others: HashMap<String, &Deck> = decks
+            .values()
+            .filter(|deck| !renamed_ids.contains(&deck.id))
+            .map(|deck| (name_key(&deck.name), deck))
+            .collect();
+
+        let (new_name, missing_parents) = match_parents(&new_name, &others)?;
+        if others.contains_key(&name_key(&new_name)) {
+            return Err(AnkiError::DeckRenameError {
+                kind: DeckRenameErrorKind::AlreadyExists,
+            });
+        }
+
+        // a child whose new name is taken, which is only possible when the
+        // other deck is missing its parent, is merged into the other deck,
+        // provided neither is filtered
+        let mut final_names: HashMap<&str, String> = HashMap::new();
+        let mut new_names = vec![];
+        let mut merges = vec![];
+        for deck in renamed {
+            let name = if deck.id == deck_id {
+                new_name.clone()
+            } else {
+                match parent_name(&deck.name).and_then(|parent| final_names.get(parent)) {
+                    Some(parent) => format!("{}::{}", parent, basename(&deck.name)),
+                    None => format!("{}::{}", new_name, &deck.name[old_prefix.len()..]),
+                }
+            };
+            let final_name = match others.get(&name_key(&name)) {
+                None => {
+                    new_names.push((deck.id, name.clone()));
+                    name
+                }
+                Some(existing)
+                    if !existing.is_filtered() && !deck.is_filtered() && deck.id != 1 =>
+                {
+                    merges.push((deck.id, existing.id));
+                    existing.name.clone()
+                }
+                Some(_) => {
+                    return Err(AnkiError::DeckRenameError {
+                        kind: DeckRenameErrorKind::AlreadyExists,
+                    })
+                }
+            };
+            final_names.insert(&deck.name, final_name);
+        }
+
+        let template = decks
+            .get(&1)
+            .cloned()
+            .ok_or_else(|| AnkiError::invalid_input("default deck missing"))?;
+        for name in missing_parents {
+            let mut parent = template.blank_copy(name, mtime_secs, usn);
+            parent.id = now_millis();
+            while decks.contains_key(&parent.id) {
+                parent.id += 1;
+            }
+            storage.add_or_update_deck(&parent)?;
+            decks.insert(parent.id, parent);
+        }
+        for (id, name) in new_names {
+            if let Some(deck) = decks.get_mut(&id) {
+                deck.name = name;
+                deck.mtime_secs = mtime_secs;
+                deck.usn = usn;
+                storage.add_or_update_deck(deck)?;
+            }
+        }
+        let mut merged_ids = vec![];
+        for (from_id, into_id) in merges {
+            storage.move_deck_cards(from_id, into_id, usn, mtime_secs)?;
+            storage.remove_deck(from_id)?;
+            storage.add_grave(from_id, GraveKind::Deck, usn)?;
+            merged_ids.push(from_id);
+        }
+        merged_ids.sort_unstable();
+        storage.mark_modified(now_millis())?;
+
+        Ok(merged_ids)
+    })
+}
+
+/// Give the parents in `name` the case of the existing decks, returning the
+/// adjusted name and the names of the parents that need to be created,
+/// starting from the top.
+fn match_parents(name: &str, existing: &HashMap<String, &Deck>) -> Result<(String, Vec<String>)> {
+    let components: Vec<_> = name.split("::").collect();
+    let mut prefix = String::new();
+    let mut missing = vec![];
+    for component in &components[..components.len() - 1] {
+        if !prefix.is_empty() {
+            prefix.push_str("::");
+        }
+        prefix.push_str(component);
+        if let Some(parent) = existing.get(&name_key(&prefix)) {
+            if parent.is_filtered() {
+                return Err(AnkiError::DeckRenameError {
+                    kind: DeckRenameErrorKind::FilteredParent,
+                });
+            }
+            prefix = parent.name.clone();
+        } else {
+            missing.push(prefix.clone());
+        }
+    }
+    let name = match components.last() {
+        Some(last) if !prefix.is_empty() => format!("{}::{}", prefix, last),
+        _ => name.to_string(),
+    };
+    Ok((name, missing))
+}
+
+// Options groups
+//----------------------------------------
+
+/// Check the settings of an options group before it's saved.
+pub fn validate_deck_conf(conf: &DeckConf) -> Result<()> {
+    let fail = |kind| Err(AnkiError::DeckConfError { kind });
+    if conf.name.trim().is_empty() {
+        return fail(DeckConfErrorKind::EmptyName);
+    }
+    match conf_steps(conf, "new") {
+        None => return fail(DeckConfErrorKind::InvalidLearningSteps),
+        Some(0) => return fail(DeckConfErrorKind::NoLearningSteps),
+        Some(_) => (),
+    }
+    if conf_steps(conf, "lapse").is_none() {
+        return fail(DeckConfErrorKind::InvalidRelearningSteps);
+    }
+    Ok(())
+}
+
+/// The number of steps in the "delays" of a section of the settings, or
+/// None if they're not all positive numbers. Missing steps count as none.
+fn conf_steps(conf: &DeckConf, section: &str) -> Option<usize> {
+    let steps = match conf.other.get(section).and_then(|s| s.get("delays")) {
+        Some(steps) => steps.as_array()?,
+        None => return Some(0),
+    };
+    if steps
+        .iter()
+        .all(|step| step.as_f64().map(|step| step > 0.0).unwrap_or(false))
+    {
+        Some(steps.len())
+    } else {
+        None
+    }
+}
+
+/// Validate and save a new options group, assigning its id.
+pub fn add_deck_conf(
+    storage: &mut SqliteStorage,
+    conf: &mut DeckConf,
+    usn: i32,
+    mtime_secs: i64,
+) -> Result<()> {
+    validate_deck_conf(conf)?;
+    storage.transact(|storage| {
+        let confs = storage.get_all_deck_conf()?;
+        conf.id = now_millis();
+        while confs.contains_key(&conf.id) {
+            conf.id += 1;
+        }
+        conf.name = conf.name.trim().to_string();
+        conf.mtime_secs = mtime_secs;
+        conf.usn = usn;
+        storage.add_or_update_deck_conf(conf)?;
+        storage.mark_modified(now_millis())
+    })
+}
+
+/// Validate and save the changed settings or name of an existing options
+/// group.
+pub fn update_deck_conf(
+    storage: &mut SqliteStorage,
+    conf: &mut DeckConf,
+    usn: i32,
+    mtime_secs: i64,
+) -> Result<()> {
+    validate_deck_conf(conf)?;
+    storage.transact(|storage| {
+        if storage.get_deck_conf(conf.id)?.is_none() {
+            return Err(AnkiError::invalid_input("options group not found"));
+        }
+        conf.name = conf.name.trim().to_string();
+        conf.mtime_secs = mtime_secs;
+        conf.usn = usn;
+        storage.add_or_update_deck_conf(conf)?;
+        storage.mark_modified(now_millis())
+    })
+}
+
+/// Remove an options group, switching the decks that use it to the default
+/// group, which can't be removed. As options groups have no graves, the
+/// next sync must be a full one.
+pub fn remove_deck_conf(
+    storage: &mut SqliteStorage,
+    conf_id: i64,
+    usn: i32,
+    mtime_secs: i64,
+) -> Result<()> {
+    if conf_id == 1 {
+        return Err(AnkiError::invalid_input(
+            "the default options can't be removed",
+        ));
+    }
+    storage.transact(|storage| {
+        for (_, mut deck) in storage.get_all_decks()? {
+            if deck.config_id() == Some(conf_id) {
+                deck.other.insert("conf".into(), 1.into());
+                deck.mtime_secs = mtime_secs;
+                deck.usn = usn;
+                storage.add_or_update_deck(&deck)?;
+            }
+        }
+        storage.remove_deck_conf(conf_id)?;
+        storage.mark_schema_modified(now_millis())
+    })
+}
+
+/// Switch a deck, and optionally its children, to an options group.
+/// Filtered decks have their own options, so they're skipped. Returns the
+/// number of decks that changed.
+pub fn set_deck_conf(
+    storage: &mut SqliteStorage,
+    deck_id: i64,
+    conf_id: i64,
+    include_children: bool,
+    usn: i32,
+    mtime_secs: i64,
+) -> Result<usize> {
+    storage.transact(|storage| {
+        if storage.get_deck_conf(conf_id)?.is_none() {
+            return Err(AnkiError::invalid_input("options group not found"));
+        }
+        let decks = storage.get_all_decks()?;
+        let deck = decks
+            .get(&deck_id)
+            .ok_or_else(|| AnkiError::invalid_input("deck not found"))?;
+        let child_prefix = format!("{}::", deck.name);
+        let mut changed = 0;
+        for mut deck in decks.values().cloned() {
+            let wanted =
+                deck.id == deck_id || (include_children && deck.name.starts_with(&child_prefix));
+            if !wanted || deck.is_filtered() || deck.config_id() == Some(conf_id) {
+                continue;
+            }
+            deck.other.insert("conf".into(), conf_id.into());
+            deck.mtime_secs = mtime_secs;
+            deck.usn = usn;
+            storage.add_or_update_deck(&deck)?;
+            changed += 1;
+        }
+        if changed > 0 {
+            storage.mark_modified(now_millis())?;
+        }
+        Ok(changed)
+    })
+}
+
+/// The options group a deck uses, falling back on the default group if
+/// its group is missing. None for a filtered deck, as its options are part
+/// of the deck.
+pub fn deck_conf_for_deck(storage: &SqliteStorage, deck_id: i64) -> Result<Option<DeckConf>> {
+    let deck = storage
+        .get_deck(deck_id)?
+        .ok_or_else(|| AnkiError::invalid_input("deck not found"))?;
+    if deck.is_filtered() {
+        return Ok(None);
+    }
+    let mut confs = storage.get_all_deck_conf()?;
+    let conf = confs
+        .remove(&deck.config_id().unwrap_or(1))
+        .or_else(|| confs.remove(&1))
+        .ok_or_else(|| AnkiError::invalid_input("default options missing"))?;
+    Ok(Some(conf))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::card::Card;
+    use crate::decks::{
+        add_deck_conf, deck_conf_for_deck, normalize_deck_name, remove_deck_conf, rename_deck,
+        set_deck_conf, update_deck_conf, DeckConf,
+    };
+    use crate::err::{AnkiError, DeckConfErrorKind, DeckRenameErrorKind, Result};
+    use crate::storage::testutil::{deck, open_test_collection};
+    use crate::storage::SqliteStorage;
+    use serde_json::json;
+
+    #[test]
+    fn test_normalize_deck_name() {
+        assert_eq!(normalize_deck_name(" a :: \"b\"::::c "), "a::b::c");
+        assert_eq!(normalize_deck_name("::"), "");
+    }
+
+    #[test]
+    fn test_rename_deck() -> Result<()> {
+        let (_dir, mut storage) = open_test_collection()?;
+        let decks = &[
+            (1, "Default", 0),
+            (2, "one", 0),
+            (3, "one::two", 0),
+            (4, "one::two::three", 0),
+            // missing its parent
+            (5, "Other::Two", 0),
+            (6, "Filtered", 1),
+        ];
+        for (id, name, filtered) in decks {
+            let mut deck = deck(*id, name);
+            deck.filtered = *filtered;
+            deck.other.insert("newToday".into(), json!([5, 3]));
+            deck.other.insert("collapsed".into(), json!(true));
+            storage.add_or_update_deck(&deck)?;
+        }
+        let mut card = Card {
+            deck_id: 3,
+            ..Default::default()
+        };
+        storage.add_card(&mut card)?;
+        let mut filtered_card = Card {
+            deck_id: 6,
+            original_deck_id: 3,
+            ..Default::default()
+        };
+        storage.add_card(&mut filtered_card)?;
+
+        let names = |storage: &SqliteStorage| -> Result<Vec<String>> {
+            let mut names: Vec<_> = storage
+                .get_all_decks()?
+                .values()
+                .map(|deck| deck.name.clone())
+                .collect();
+            names.sort();
+            Ok(names)
+        };
+        let error_kind = |result: Result<Vec<i64>>| match result {
+            Err(AnkiError::DeckRenameError { kind }) => Some(kind),
+            _ => None,
+        };
+
+        // names are taken regardless of case, and filtered decks can't be
+        // parents
+        assert_eq!(
+            error_kind(rename_deck(&mut storage, 2, " default ", -1, 10)),
+            Some(DeckRenameErrorKind::AlreadyExists)
+        );
+        assert_eq!(
+            error_kind(rename_deck(&mut storage, 2, "FILTERED::one", -1, 10)),
+            Some(DeckRenameErrorKind::FilteredParent)
+        );
+        assert_eq!(names(&storage)?.last().unwrap(), "one::two::three");
+
+        // the children follow, and missing parents are created with the
+        // options of the default deck but none of its state
+        assert!(rename_deck(&mut storage, 2, "a::b::\"c\"", -1, 10)?.is_empty());
+        assert_eq!(
+            names(&storage)?,
+            vec![
+                "Default",
+                "Filtered",
+                "Other::Two",
+                "a",
+                "a::b",
+                "a::b::c",
+                "a::b::c::two",
+                "a::b::c::two::three"
+            ]
+        );
+        let decks = storage.get_all_decks()?;
+        let parent = decks.values().find(|deck| deck.name == "a").unwrap();
+        assert_eq!(parent.other["newToday"], json!([0, 0]));
+        assert_eq!(parent.other["collapsed"], json!(false));
+        assert_eq!((decks[&4].mtime_secs, decks[&4].usn), (10, -1));
+
+        // a child that takes an orphan's name is merged into it, and the
+        // names take the case of the existing decks
+        assert_eq!(rename_deck(&mut storage, 2, "other", -1, 20)?, vec![3]);
+        assert_eq!(
+            names(&storage)?,
+            vec![
+                "Default",
+                "Filtered",
+                "Other::Two",
+                "Other::Two::three",
+                "a",
+                "a::b",
+                "other"
+            ]
+        );
+        assert_eq!(storage.get_card(card.id)?.unwrap().deck_id, 5);
+        assert_eq!(
+            storage
+                .get_card(filtered_card.id)?
+                .unwrap()
+                .original_deck_id,
+            5
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_deck_conf() -> Result<()> {
+        let (_dir, mut storage) = open_test_collection()?;
+        let decks = &[
+            (1, "Default", 0),
+            (2, "parent", 0),
+            (3, "parent::child", 0),
+            (4, "parent::filtered", 1),
+            (5, "parent2", 0),
+        ];
+        for (id, name, filtered) in decks {
+            let mut deck = deck(*id, name);
+            deck.filtered = *filtered;
+            storage.add_or_update_deck(&deck)?;
+        }
+        let default_conf: DeckConf = serde_json::from_value(json!({
+            "id": 1, "name": "Default", "mod": 0, "usn": 0,
+            "new": {"delays": [1, 10], "perDay": 20},
+            "lapse": {"delays": [10], "mult": 0}
+        }))?;
+        storage.add_or_update_deck_conf(&default_conf)?;
+
+        // steps must be positive numbers, and new cards need at least one
+        let error_kind = |result: Result<()>| match result {
+            Err(AnkiError::DeckConfError { kind }) => Some(kind),
+            _ => None,
+        };
+        let mut conf = default_conf.clone();
+        conf.name = " ".into();
+        assert_eq!(
+            error_kind(add_deck_conf(&mut storage, &mut conf, -1, 10)),
+            Some(DeckConfErrorKind::EmptyName)
+        );
+        conf.name = " Copy ".into();
+        conf.other["new"]["delays"] = json!([]);
+        assert_eq!(
+            error_kind(add_deck_conf(&mut storage, &mut conf, -1, 10)),
+            Some(DeckConfErrorKind::NoLearningSteps)
+        );
+        conf.other["new"]["delays"] = json!([1.5, "x"]);
+        assert_eq!(
+            error_kind(add_deck_conf(&mut storage, &mut conf, -1, 10)),
+            Some(DeckConfErrorKind::InvalidLearningSteps)
+        );
+        conf.other["new"]["delays"] = json!([1.5]);
+        conf.other["lapse"]["delays"] = json!([0]);
+        assert_eq!(
+            error_kind(update_deck_conf(&mut storage, &mut conf, -1, 10)),
+            Some(DeckConfErrorKind::InvalidRelearningSteps)
+        );
+        conf.other["lapse"]["delays"] = json!([]);
+        add_deck_conf(&mut storage, &mut conf, -1, 10)?;
+        assert_ne!(conf.id, 1);
+        assert_eq!(storage.get_deck_conf(conf.id)?.unwrap(), conf);
+        assert_eq!(conf.name, "Copy");
+
+        // filtered decks are skipped
+        assert_eq!(set_deck_conf(&mut storage, 2, conf.id, true, -1, 20)?, 2);
+        assert_eq!(set_deck_conf(&mut storage, 2, conf.id, true, -1, 20)?, 0);
+        assert_eq!(deck_conf_for_deck(&storage, 3)?.unwrap().id, conf.id);
+        assert_eq!(deck_conf_for_deck(&storage, 4)?, None);
+        assert_eq!(deck_conf_for_deck(&storage, 5)?.unwrap().id, 1);
+        assert_eq!(storage.get_deck(3)?.unwrap().mtime_secs, 20);
+
+        assert!(remove_deck_conf(&mut storage, 1, -1, 30).is_err());
+        remove_deck_conf(&mut storage, conf.id, -1, 30)?;
+        assert_eq!(storage.get_deck_conf(conf.id)?, None);
+        assert_eq!(storage.get_deck(2)?.unwrap().config_id(), Some(1));
+        assert_eq!(storage.get_deck(3)?.unwrap().usn, -1);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,200 @@
+// Copyright: Ankitects Pty Ltd and contributors
+// License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+//! Finding notes that share the same text in a field. Field text is
+//! compared the way the first field's checksum is calculated, so
+//! formatting differences are ignored.
+
+use crate::err::Result;
+use crate::notes::checksum_text;
+use crate::search::{names_match, search_notes, SearchContext};
+use crate::storage::SqliteStorage;
+use crate::tags::add_tags;
+use crate::text::normalize_to_nfc;
+use rusqlite::NO_PARAMS;
+use std::collections::{HashMap, HashSet};
+
+/// Notes with the same text in the searched field.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DuplicateGroup {
+    pub text: String,
+    pub note_ids: Vec<i64>,
+}
+
+/// Find notes with the same text in `field_name`, or the first field if
+/// it's None. If `search` is not empty, only notes matching it are
+/// included. Groups are returned in the order their first note was added.
+pub fn find_duplicates(
+    storage: &SqliteStorage,
+    ctx: &SearchContext,
+    field_name: Option<&str>,
+    search: &str,
+) -> Result<Vec<DuplicateGroup>> {
+    let allowed: Option<HashSet<i64>> = if search.trim().is_empty() {
+        None
+    } else {
+        Some(search_notes(storage, search, ctx)?.into_iter().collect())
+    };
+
+    let mut sql = "select id, mid, flds from notes".to_string();
+    let field_ords: HashMap<i64, usize> = match field_name {
+        Some(field_name) => storage
+            .get_all_notetypes()?
+            .values()
+            .filter_map(|nt| {
+                nt.fields
+                    .iter()
+                    .find(|f| names_match(&f.name, field_name))
+                    .map(|f| (nt.id, f.ord as usize))
+            })
+            .collect(),
+        None => {
+            // only the first field has a checksum
+            sql.push_str(
+                " where csum in (select csum from notes group by csum having count() > 1)",
+            );
+            HashMap::new()
+        }
+    };
+    if field_name.is_some() && field_ords.is_empty() {
+        return Ok(vec![]);
+    }
+    sql.push_str(" order by id");
+
+    let mut groups: Vec<DuplicateGroup> = vec![];
+    let mut group_by_text: HashMap<String, usize> = HashMap::new();
+    let mut stmt = storage.db.prepare(&sql)?;
+    let mut rows = stmt.query(NO_PARAMS)?;
+    while let Some(row) = rows.next()? {
+        let id: i64 = row.get(0)?;
+        if let Some(allowed) = &allowed {
+            if !allowed.contains(&id) {
+                continue;
+            }
+        }
+        let ord = if field_name.is_some() {
+            match field_ords.get(&row.get(1)?) {
+                Some(ord) => *ord,
+                None => continue,
+            }
+        } else {
+            0
+        };
+        let fields: String = row.get(2)?;
+        let field = match fields.split('\x1f').nth(ord) {
+            Some(field) => field,
+            None => continue,
+        };
+        let text = normalize_to_nfc(&checksum_text(field)).into_owned();
+        // empty fields are not duplicates
+        if text.trim().is_empty() {
+            continue;
+        }
+        match group_by_text.get(&text) {
+            Some(idx) => groups[*idx].note_ids.push(id),
+            None => {
+                group_by_text.insert(text.clone(), groups.len());
+                groups.push(DuplicateGroup {
+                    text,
+                    note_ids: vec![id],
+                });
+            }
+        }
+    }
+
+    groups.retain(|g| g.note_ids.len() > 1);
+    Ok(groups)
+}
+
+/// Add `tag` to every note in a duplicate group. Returns the number of
+/// notes changed.
+pub fn tag_duplicates(
+    storage: &SqliteStorage,
+    ctx: &SearchContext,
+    field_name: Option<&str>,
+    search: &str,
+    tag: &str,
+    usn: i32,
+    mtime_secs: i64,
+) -> Result<usize> {
+    let note_ids: Vec<_> = find_duplicates(storage, ctx, field_name, search)?
+        .into_iter()
+        .flat_map(|g| g.note_ids)
+        .collect();
+    Ok(add_tags(storage, &note_ids, tag, usn, mtime_secs)?.len())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::card::Card;
+    use crate::dupes::{find_duplicates, tag_duplicates, DuplicateGroup};
+    use crate::err::Result;
+    use crate::notes::{field_checksum, Note};
+    use crate::search::SearchContext;
+    use crate::storage::testutil::{add_basic_notetype, open_test_collection};
+    use crate::storage::SqliteStorage;
+
+    fn add_note(storage: &SqliteStorage, front: &str, back: &str) -> Result<i64> {
+        let mut note = Note {
+            notetype_id: 1,
+            fields: vec![front.into(), back.into()],
+            checksum: field_checksum(front),
+            ..Default::default()
+        };
+        storage.add_note(&mut note)?;
+        let mut card = Card {
+            note_id: note.id,
+            ..Default::default()
+        };
+        storage.add_card(&mut card)?;
+        Ok(note.id)
+    }
+
+    #[test]
+    fn test_find_duplicates() -> Result<()> {
+        let (_dir, storage) = open_test_collection()?;
+        add_basic_notetype(&storage)?;
+        let ctx = SearchContext {
+            today: 0,
+            day_cutoff: 0,
+            current_deck_id: 1,
+        };
+        let n1 = add_note(&storage, "foo", "bar")?;
+        let n2 = add_note(&storage, "<b>foo</b>", "bar")?;
+        let n3 = add_note(&storage, "baz", "Bar")?;
+        let n4 = add_note(&storage, "", "bar")?;
+        add_note(&storage, "", "")?;
+
+        // the first field, ignoring formatting; empty fields don't count
+        assert_eq!(
+            find_duplicates(&storage, &ctx, None, "")?,
+            vec![DuplicateGroup {
+                text: "foo".into(),
+                note_ids: vec![n1, n2]
+            }]
+        );
+        // field names are not case sensitive, but the text is
+        assert_eq!(
+            find_duplicates(&storage, &ctx, Some("back"), "")?,
+            vec![DuplicateGroup {
+                text: "bar".into(),
+                note_ids: vec![n1, n2, n4]
+            }]
+        );
+        assert_eq!(
+            find_duplicates(&storage, &ctx, Some("back"), "-baz")?[0].note_ids,
+            vec![n1, n2, n4]
+        );
+        assert!(find_duplicates(&storage, &ctx, Some("back"), "invalid")?.is_empty());
+        assert!(find_duplicates(&storage, &ctx, Some("missing"), "")?.is_empty());
+
+        assert_eq!(
+            tag_duplicates(&storage, &ctx, None, "", "duplicate", -1, 5)?,
+            2
+        );
+        assert_eq!(storage.get_note(n2)?.unwrap().tags, vec!["duplicate"]);
+        assert!(storage.get_note(n3)?.unwrap().tags.is_empty());
+
+        Ok(())
+    }
+}
@@ -1,7 +1,11 @@
 // Copyright: Ankitects Pty Ltd and contributors
 // License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
 
+use crate::i18n::I18n;
+use crate::tr_args;
 pub use failure::{Error, Fail};
+use rusqlite::ErrorCode;
+use std::io;
 
 pub type Result<T> = std::result::Result<T, AnkiError>;
 
@@ -12,6 +16,54 @@ pub enum AnkiError {
 
     #[fail(display = "invalid card template: {}", info)]
     TemplateError { info: String },
+
+    #[fail(display = "I/O error: {}", info)]
+    IOError { info: String },
+
+    #[fail(display = "DB error: {}", info)]
+    DBError { info: String, kind: DBErrorKind },
+
+    #[fail(display = "Network error: {}", info)]
+    NetworkError {
+        info: String,
+        kind: NetworkErrorKind,
+    },
+
+    #[fail(display = "Sync error: {}", info)]
+    SyncError { info: String },
+
+    /// The server rejected the host key, so the user needs to log in again.
+    #[fail(display = "The sync server rejected the login.")]
+    SyncAuthError,
+
+    #[fail(display = "Operation cancelled.")]
+    Interrupted,
+
+    #[fail(
+        display = "Collection version {} requires a newer version of Anki.",
+        version
+    )]
+    CollectionTooNew { version: u8 },
+
+    #[fail(
+        display = "The collection is open in another copy of Anki. If it isn't, remove {}.",
+        lock_path
+    )]
+    CollectionInUse { lock_path: String },
+
+    /// The span of the problem, in characters.
+    #[fail(display = "invalid search: {}", info)]
+    SearchError {
+        info: String,
+        start: usize,
+        end: usize,
+    },
+
+    #[fail(display = "Couldn't rename deck: {:?}", kind)]
+    DeckRenameError { kind: DeckRenameErrorKind },
+
+    #[fail(display = "invalid deck options: {:?}", kind)]
+    DeckConfError { kind: DeckConfErrorKind },
 }
 
 // error helpers
@@ -19,6 +71,190 @@ impl AnkiError {
     pub(crate) fn invalid_input<S: Into<String>>(s: S) -> AnkiError {
         AnkiError::InvalidInput { info: s.into() }
     }
+
+    pub(crate) fn sync_misc<S: Into<String>>(s: S) -> AnkiError {
+        AnkiError::SyncError { info: s.into() }
+    }
+
+    pub(crate) fn db_error<S: Into<String>>(s: S, kind: DBErrorKind) -> AnkiError {
+        AnkiError::DBError {
+            info: s.into(),
+            kind,
+        }
+    }
+
+    /// True if the operation may succeed if tried again later, as the
+    /// network or the database was temporarily unavailable.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            AnkiError::NetworkError { kind, .. } => *kind != NetworkErrorKind::Other,
+            AnkiError::DBError { kind, .. } => *kind == DBErrorKind::Locked,
+            _ => false,
+        }
+    }
+
+    /// A description of the error for the user, in their language. Errors
+    /// that only developers should see are left in English.
+    pub fn localized_description(&self, i18n: &I18n) -> String {
+        match self {
+            AnkiError::NetworkError { info, kind } => match kind {
+                NetworkErrorKind::Offline => i18n.tr("error-network-offline"),
+                NetworkErrorKind::Timeout => i18n.tr("error-network-timeout"),
+                NetworkErrorKind::Other => {
+                    i18n.trn("error-network-other", tr_args!["info" => info.as_str()])
+                }
+            },
+            AnkiError::SyncError { info } => {
+                i18n.trn("error-sync-other", tr_args!["info" => info.as_str()])
+            }
+            AnkiError::SyncAuthError => i18n.tr("error-sync-auth"),
+            AnkiError::Interrupted => i18n.tr("error-interrupted"),
+            AnkiError::DBError {
+                kind: DBErrorKind::Corrupt,
+                ..
+            } => i18n.tr("error-db-corrupt"),
+            AnkiError::DBError {
+                kind: DBErrorKind::Locked,
+                ..
+            } => i18n.tr("error-db-locked"),
+            AnkiError::CollectionTooNew { .. } => i18n.tr("error-collection-too-new"),
+            AnkiError::CollectionInUse { lock_path } => i18n.trn(
+                "error-collection-in-use",
+                tr_args!["lock_path" => lock_path.as_str()],
+            ),
+            AnkiError::DeckRenameError { kind } => match kind {
+                DeckRenameErrorKind::AlreadyExists => i18n.tr("error-deck-exists"),
+                DeckRenameErrorKind::FilteredParent => i18n.tr("error-deck-filtered-parent"),
+            },
+            AnkiError::DeckConfError { kind } => match kind {
+                DeckConfErrorKind::EmptyName => i18n.tr("error-deck-conf-empty-name"),
+                DeckConfErrorKind::InvalidLearningSteps
+                | DeckConfErrorKind::InvalidRelearningSteps => {
+                    i18n.tr("error-deck-conf-invalid-steps")
+                }
+                DeckConfErrorKind::NoLearningSteps => i18n.tr("error-deck-conf-no-steps"),
+            },
+            _ => self.to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DBErrorKind {
+    /// The file is damaged, or isn't a collection.
+    Corrupt,
+    /// Another process is writing to the file.
+    Locked,
+    /// The collection is too old to be upgraded.
+    FileTooOld,
+    Other,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DeckRenameErrorKind {
+    /// Another deck has the name, ignoring case.
+    AlreadyExists,
+    /// Filtered decks can't have children.
+    FilteredParent,
+}
+
+/// A problem with the settings of an options group, so the options screen
+/// can point to the setting.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DeckConfErrorKind {
+    EmptyName,
+    /// A learning step isn't a positive number.
+    InvalidLearningSteps,
+    /// New cards need at least one learning step.
+    NoLearningSteps,
+    /// A relearning step isn't a positive number.
+    InvalidRelearningSteps,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NetworkErrorKind {
+    /// The server couldn't be reached, usually because the computer is
+    /// offline.
+    Offline,
+    /// The server took too long to respond.
+    Timeout,
+    Other,
+}
+
+impl From<io::Error> for AnkiError {
+    fn from(err: io::Error) -> Self {
+        AnkiError::IOError {
+            info: format!("{:?}", err),
+        }
+    }
+}
+
+impl From<rusqlite::Error> for AnkiError {
+    fn from(err: rusqlite::Error) -> Self {
+        let kind = match &err {
+            rusqlite::Error::SqliteFailure(e, _) => match e.code {
+                ErrorCode::DatabaseCorrupt | ErrorCode::NotADatabase => DBErrorKind::Corrupt,
+                ErrorCode::DatabaseBusy | ErrorCode::DatabaseLocked => DBErrorKind::Locked,
+                _ => DBErrorKind::Other,
+            },
+            _ => DBErrorKind::Other,
+        };
+        AnkiError::db_error(format!("{:?}", err), kind)
+    }
+}
+
+impl From<reqwest::Error> for AnkiError {
+    fn from(err: reqwest::Error) -> Self {
+        let kind = if err.is_timeout() {
+            NetworkErrorKind::Timeout
+        } else if source_is_connect_error(&err) {
+            NetworkErrorKind::Offline
+        } else {
+            NetworkErrorKind::Other
+        };
+        AnkiError::NetworkError {
+            info: format!("{:?}", err),
+            kind,
+        }
+    }
+}
+
+/// True if the request failed because a connection couldn't be made.
+fn source_is_connect_error(err: &(dyn std::error::Error + 'static)) -> bool {
+    let mut source = err.source();
+    while let Some(err) = source {
+        if let Some(err) = err.downcast_ref::<hyper::Error>() {
+            return err.is_connect();
+        }
+        source = err.source();
+    }
+    false
+}
+
+impl From<hyper::Error> for AnkiError {
+    fn from(err: hyper::Error) -> Self {
+        let kind = if err.is_connect() {
+            NetworkErrorKind::Offline
+        } else {
+            NetworkErrorKind::Other
+        };
+        AnkiError::NetworkError {
+            info: format!("{:?}", err),
+            kind,
+        }
+    }
+}
+
+impl From<zip::result::ZipError> for AnkiError {
+    fn from(err: zip::result::ZipError) -> Self {
+        AnkiError::sync_misc(err.to_string())
+    }
+}
+
+impl From<serde_json::Error> for AnkiError {
+    fn from(err: serde_json::Error) -> Self {
+        AnkiError::sync_misc(err.to_string())
+    }
 }
 
 #[derive(Debug, PartialEq)]
@@ -27,6 +263,7 @@ pub enum TemplateError {
     ConditionalNotClosed(String),
     ConditionalNotOpen(String),
     FieldNotFound { filters: String, field: String },
+    EmptyFront,
 }
 
 impl From<TemplateError> for AnkiError {
@@ -44,7 +281,131 @@ impl From<TemplateError> for AnkiError {
                     "found '{{{{{}{}}}}}', but there is no field called '{}'",
                     filters, field, field
                 ),
+                TemplateError::EmptyFront => {
+                    "the front of the card doesn't include any fields".to_string()
+                }
             },
         }
     }
 }
+
+/// Why an image could not be generated from some LaTeX.
+#[derive(Debug, PartialEq)]
+pub enum LatexError {
+    /// The LaTeX used a command that could read or write arbitrary files.
+    ForbiddenCommand(String),
+    /// The program could not be found on the PATH.
+    CommandNotFound(String),
+    /// The program returned an error; the log contains its output.
+    CommandFailed {
+        command: String,
+        log: String,
+    },
+    IOError(String),
+}
+
+impl From<io::Error> for LatexError {
+    fn from(err: io::Error) -> Self {
+        LatexError::IOError(format!("{:?}", err))
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum TTSError {
+    MissingLanguage,
+    InvalidArgument { arg: String },
+}
+
+impl std::fmt::Display for TTSError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            TTSError::MissingLanguage => write!(f, "missing language code in tts tag"),
+            TTSError::InvalidArgument { arg } => write!(f, "invalid tts argument '{}'", arg),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::err::{AnkiError, DBErrorKind, NetworkErrorKind, Result};
+    use rusqlite::{Connection, NO_PARAMS};
+    use std::fs;
+    use std::net::TcpListener;
+    use std::time::Duration;
+    use tempfile::tempdir;
+    use tokio::runtime::Builder;
+
+    fn db_error_kind(err: AnkiError) -> DBErrorKind {
+        match err {
+            AnkiError::DBError { kind, .. } => kind,
+            other => panic!("unexpected: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn db_error_kinds() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("test.db");
+
+        // a database being written by another connection is locked
+        let db1 = Connection::open(&path)?;
+        db1.execute_batch("create table t (x int); begin exclusive")?;
+        let db2 = Connection::open(&path)?;
+        db2.busy_timeout(Duration::from_secs(0))?;
+        let err: AnkiError = db2
+            .execute("insert into t values (1)", NO_PARAMS)
+            .unwrap_err()
+            .into();
+        assert_eq!(db_error_kind(err), DBErrorKind::Locked);
+        drop(db1);
+
+        // a file that isn't a database is corrupt
+        fs::write(&path, vec![b'x'; 4096])?;
+        let db = Connection::open(&path)?;
+        let err: AnkiError = db
+            .execute_batch("select * from sqlite_master")
+            .unwrap_err()
+            .into();
+        assert_eq!(db_error_kind(err), DBErrorKind::Corrupt);
+
+        let err: AnkiError = db.execute_batch("select * from").unwrap_err().into();
+        assert_eq!(db_error_kind(err), DBErrorKind::Other);
+
+        Ok(())
+    }
+
+    #[test]
+    fn refused_connection_is_offline() -> Result<()> {
+        // find a port nothing is listening on
+        let addr = TcpListener::bind("127.0.0.1:0")?.local_addr()?;
+        let mut rt = Builder::new().basic_scheduler().enable_all().build()?;
+        let err: AnkiError = rt
+            .block_on(reqwest::get(&format!("http://{}/", addr)))
+            .unwrap_err()
+            .into();
+        match err {
+            AnkiError::NetworkError { kind, .. } => assert_eq!(kind, NetworkErrorKind::Offline),
+            other => panic!("unexpected: {:?}", other),
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn transient_errors() {
+        let network = |kind| AnkiError::NetworkError {
+            info: String::new(),
+            kind,
+        };
+        assert!(network(NetworkErrorKind::Offline).is_transient());
+        assert!(network(NetworkErrorKind::Timeout).is_transient());
+        assert!(!network(NetworkErrorKind::Other).is_transient());
+
+        assert!(AnkiError::db_error("", DBErrorKind::Locked).is_transient());
+        assert!(!AnkiError::db_error("", DBErrorKind::Corrupt).is_transient());
+        assert!(!AnkiError::db_error("", DBErrorKind::Other).is_transient());
+
+        assert!(!AnkiError::invalid_input("").is_transient());
+        assert!(!AnkiError::Interrupted.is_transient());
+    }
+}
@@ -0,0 +1,157 @@
+// Copyright: Ankitects Pty Ltd and contributors
+// License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+use crate::err::{AnkiError, Result};
+use lazy_static::lazy_static;
+use regex::{Captures, NoExpand, Regex};
+use std::borrow::Cow;
+
+lazy_static! {
+    /// Group references in Python's syntax, as used by older clients.
+    static ref PYTHON_GROUP_REF: Regex = Regex::new(r"\\(?:(\d+)|g<(\w+)>|\\)").unwrap();
+}
+
+/// A compiled search and replacement, which can be applied to the fields
+/// and tags of many notes.
+pub struct FindReplacer {
+    regex: Regex,
+    replacement: String,
+    // if false, $1 etc in the replacement are not expanded
+    expand: bool,
+}
+
+/// Text of a single note that should be searched.
+pub struct NoteText {
+    pub fields: Vec<String>,
+    pub tags: String,
+}
+
+impl FindReplacer {
+    /// If `regex` is false, the search is treated as a literal string, and
+    /// the replacement is inserted as-is. Otherwise the replacement may refer
+    /// to capture groups with $1 or ${name}, or with Python's \1 or \g<name>.
+    pub fn new(search: &str, replacement: &str, regex: bool, match_case: bool) -> Result<Self> {
+        let search: Cow<str> = if regex {
+            search.into()
+        } else {
+            regex::escape(search).into()
+        };
+        let search = if match_case {
+            search
+        } else {
+            format!("(?i){}", search).into()
+        };
+        let compiled = Regex::new(&search)
+            .map_err(|e| AnkiError::invalid_input(format!("invalid regex: {}", e)))?;
+
+        Ok(FindReplacer {
+            regex: compiled,
+            replacement: if regex {
+                expand_python_group_refs(replacement)
+            } else {
+                replacement.to_string()
+            },
+            expand: regex,
+        })
+    }
+
+    /// Returns the updated text and the number of replacements made.
+    pub fn replace<'a>(&self, text: &'a str) -> (Cow<'a, str>, usize) {
+        let count = self.regex.find_iter(text).count();
+        if count == 0 {
+            return (text.into(), 0);
+        }
+        let out = if self.expand {
+            self.regex.replace_all(text, self.replacement.as_str())
+        } else {
+            self.regex
+                .replace_all(text, NoExpand(self.replacement.as_str()))
+        };
+        (out, count)
+    }
+
+    /// Apply the replacement to the given field, or all fields if `field_ord`
+    /// is None. Tags are only changed if `include_tags` is true. Returns the
+    /// number of replacements made.
+    pub fn replace_in_note(
+        &self,
+        note: &mut NoteText,
+        field_ord: Option<usize>,
+        include_tags: bool,
+    ) -> usize {
+        let mut count = 0;
+        for (ord, field) in note.fields.iter_mut().enumerate() {
+            if field_ord.is_some() && field_ord != Some(ord) {
+                continue;
+            }
+            count += replace_in_place(self, field);
+        }
+        if include_tags {
+            count += replace_in_place(self, &mut note.tags);
+        }
+        count
+    }
+}
+
+/// Convert \1 and \g<name> to ${1} and ${name}. An escaped backslash is
+/// kept as a single backslash.
+fn expand_python_group_refs(replacement: &str) -> String {
+    PYTHON_GROUP_REF
+        .replace_all(replacement, |caps: &Captures| {
+            match caps.get(1).or_else(|| caps.get(2)) {
+                Some(group) => format!("${{{}}}", group.as_str()),
+                None => "\\".to_string(),
+            }
+        })
+        .into_owned()
+}
+
+fn replace_in_place(replacer: &FindReplacer, text: &mut String) -> usize {
+    let (out, count) = replacer.replace(text);
+    if let Cow::Owned(o) = out {
+        *text = o;
+    }
+    count
+}
+
+#[cfg(test)]
+mod test {
+    use crate::findreplace::{FindReplacer, NoteText};
+
+    #[test]
+    fn test_find_replace() {
+        let fr = FindReplacer::new("foo", "b$1r", false, false).unwrap();
+        assert_eq!(fr.replace("a Foo foo"), ("a b$1r b$1r".into(), 2));
+        assert_eq!(fr.replace("bar"), ("bar".into(), 0));
+
+        let fr = FindReplacer::new("f.o", "bar", false, true).unwrap();
+        assert_eq!(fr.replace("foo f.o F.o"), ("foo bar F.o".into(), 1));
+
+        let fr = FindReplacer::new(r"(\w+)@(?P<host>\w+)", "${host}:$1", true, true).unwrap();
+        assert_eq!(fr.replace("me@home"), ("home:me".into(), 1));
+
+        // the syntax of the legacy Python implementation is also accepted
+        let fr = FindReplacer::new(r"(\w+)@(?P<host>\w+)", r"\g<host>:\1\\2", true, true).unwrap();
+        assert_eq!(fr.replace("me@home"), (r"home:me\2".into(), 1));
+        let fr = FindReplacer::new(r"(a)", r"\1x", true, true).unwrap();
+        assert_eq!(fr.replace("a"), ("ax".into(), 1));
+
+        assert!(FindReplacer::new("(", "", true, true).is_err());
+    }
+
+    #[test]
+    fn test_note() {
+        let fr = FindReplacer::new("a", "b", false, true).unwrap();
+        let mut note = NoteText {
+            fields: vec!["a".into(), "aa".into()],
+            tags: " a ".into(),
+        };
+        assert_eq!(fr.replace_in_note(&mut note, Some(1), false), 2);
+        assert_eq!(note.fields, vec!["a", "bb"]);
+        assert_eq!(note.tags, " a ");
+
+        assert_eq!(fr.replace_in_note(&mut note, None, true), 2);
+        assert_eq!(note.fields, vec!["b", "bb"]);
+        assert_eq!(note.tags, " b ");
+    }
+}
@@ -0,0 +1,447 @@
+// Copyright: Ankitects Pty Ltd and contributors
+// License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+//! A streaming tokenizer for the HTML in fields. It follows the
+//! tokenization rules of the HTML standard closely enough that attribute
+//! values containing `>`, conditional comments and the contents of scripts
+//! are split up the way a browser would, without building a document tree.
+//!
+//! Field content is often not valid HTML, so a few cases differ from the
+//! standard: a tag left unterminated at the end of the text is returned as
+//! text instead of being dropped, so that something like "x<y" survives.
+
+use std::ops::Range;
+
+/// A piece of HTML. Entities are left encoded.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Token<'a> {
+    Text(&'a str),
+    /// The contents of a script or style element.
+    RawText(&'a str),
+    StartTag(Tag<'a>),
+    /// The name of the closing tag; any attributes are ignored.
+    EndTag(&'a str),
+    /// The text between `<!--` and `-->`. Conditional comments such as
+    /// `<!--[if IE]>...<![endif]-->` are comments too.
+    Comment(&'a str),
+    /// Doctypes, processing instructions and other markup that browsers
+    /// ignore, such as `<![endif]>`.
+    Ignored,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Tag<'a> {
+    /// As written, so it may be in any case.
+    pub name: &'a str,
+    pub attrs: Vec<Attribute<'a>>,
+    pub self_closing: bool,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Attribute<'a> {
+    pub name: &'a str,
+    /// Without any quotes; empty if the attribute has no value.
+    pub value: &'a str,
+    /// The byte range of the value in the tokenized text.
+    pub value_span: Range<usize>,
+    /// The byte range of the whole attribute, including any quotes.
+    pub span: Range<usize>,
+}
+
+impl<'a> Tag<'a> {
+    /// True if the tag has the provided lowercase name.
+    pub fn is(&self, name: &str) -> bool {
+        self.name.eq_ignore_ascii_case(name)
+    }
+
+    /// The first attribute with the provided lowercase name.
+    pub fn attr(&self, name: &str) -> Option<&Attribute<'a>> {
+        self.attrs
+            .iter()
+            .find(|attr| attr.name.eq_ignore_ascii_case(name))
+    }
+}
+
+/// Elements whose contents are text up to their closing tag, even if it
+/// looks like markup.
+static RAW_TEXT_ELEMENTS: &[&str] = &["script", "style"];
+
+/// Splits HTML into tokens, along with the byte range of each.
+pub struct Tokenizer<'a> {
+    html: &'a str,
+    pos: usize,
+    end: usize,
+    /// Set after the start tag of a raw text element.
+    raw_text_element: Option<&'static str>,
+}
+
+impl<'a> Tokenizer<'a> {
+    pub fn new(html: &'a str) -> Self {
+        Self::in_range(html, 0..html.len())
+    }
+
+    /// Tokenize part of `html`, such as the contents of a comment. Spans
+    /// are still relative to the start of `html`.
+    pub fn in_range(html: &'a str, range: Range<usize>) -> Self {
+        Tokenizer {
+            html,
+            pos: range.start,
+            end: range.end,
+            raw_text_element: None,
+        }
+    }
+
+    fn bytes(&self) -> &'a [u8] {
+        &self.html.as_bytes()[..self.end]
+    }
+
+    fn raw_text(&self, element: &str) -> Range<usize> {
+        let bytes = self.bytes();
+        let mut pos = self.pos;
+        while let Some(offset) = self.html[pos..self.end].find("</") {
+            let name_start = pos + offset + 2;
+            let name_end = name_start + element.len();
+            if name_end < bytes.len()
+                && bytes[name_start..name_end].eq_ignore_ascii_case(element.as_bytes())
+                && ends_tag_name(bytes[name_end])
+            {
+                return self.pos..pos + offset;
+            }
+            pos = name_start;
+        }
+        self.pos..self.end
+    }
+
+    /// The text from the current position up to the next markup.
+    fn text(&self) -> Range<usize> {
+        let bytes = self.bytes();
+        let mut pos = self.pos;
+        while let Some(offset) = self.html[pos..self.end].find('<') {
+            let start = pos + offset;
+            if starts_markup(bytes, start) {
+                return self.pos..start;
+            }
+            pos = start + 1;
+        }
+        self.pos..self.end
+    }
+
+    /// The markup at the current position, which starts with `<`. None if
+    /// it is unterminated.
+    fn markup(&self) -> Option<(usize, Token<'a>)> {
+        let bytes = self.bytes();
+        let start = self.pos;
+        let rest = &self.html[start..self.end];
+        if rest.starts_with("<!--") {
+            Some(self.comment())
+        } else if rest.starts_with("<!") || rest.starts_with("<?") {
+            Some(self.bogus_comment(start + 2))
+        } else if rest.starts_with("</") {
+            match bytes.get(start + 2) {
+                Some(b) if b.is_ascii_alphabetic() => {
+                    let (end, tag) = self.tag(start + 2)?;
+                    Some((end, Token::EndTag(tag.name)))
+                }
+                // "</>" is dropped
+                Some(b'>') => Some((start + 3, Token::Ignored)),
+                Some(_) => Some(self.bogus_comment(start + 2)),
+                None => None,
+            }
+        } else {
+            let (end, tag) = self.tag(start + 1)?;
+            Some((end, Token::StartTag(tag)))
+        }
+    }
+
+    fn comment(&self) -> (usize, Token<'a>) {
+        let start = self.pos + 4;
+        // "<!-->" and "<!--->" are empty comments
+        for abrupt_end in &[">", "->"] {
+            if self.html[start..self.end].starts_with(abrupt_end) {
+                return (start + abrupt_end.len(), Token::Comment(""));
+            }
+        }
+        match self.html[start..self.end].find("-->") {
+            Some(len) => (
+                start + len + 3,
+                Token::Comment(&self.html[start..start + len]),
+            ),
+            None => (self.end, Token::Comment(&self.html[start..self.end])),
+        }
+    }
+
+    /// Markup that browsers skip over up to the next `>`.
+    fn bogus_comment(&self, start: usize) -> (usize, Token<'a>) {
+        let end = match self.html[start..self.end].find('>') {
+            Some(len) => start + len + 1,
+            None => self.end,
+        };
+        (end, Token::Ignored)
+    }
+
+    /// Parse the tag whose name starts at `name_start`, returning the
+    /// position after it.
+    fn tag(&self, name_start: usize) -> Option<(usize, Tag<'a>)> {
+        let bytes = self.bytes();
+        let mut pos = name_start;
+        while pos < bytes.len() && !ends_tag_name(bytes[pos]) {
+            pos += 1;
+        }
+        let mut tag = Tag {
+            name: &self.html[name_start..pos],
+            attrs: vec![],
+            self_closing: false,
+        };
+
+        loop {
+            pos = skip_whitespace(bytes, pos);
+            match *bytes.get(pos)? {
+                b'>' => return Some((pos + 1, tag)),
+                b'/' => {
+                    pos += 1;
+                    if bytes.get(pos) == Some(&b'>') {
+                        tag.self_closing = true;
+                        return Some((pos + 1, tag));
+                    }
+                }
+                _ => {
+                    let (end, attr) = self.attribute(pos)?;
+                    tag.attrs.push(attr);
+                    pos = end;
+                }
+            }
+        }
+    }
+
+    fn attribute(&self, name_start: usize) -> Option<(usize, Attribute<'a>)> {
+        let bytes = self.bytes();
+        // a name may start with "=", but can't contain one after that
+        let mut pos = name_start + 1;
+        while pos < bytes.len() && !ends_tag_name(bytes[pos]) && bytes[pos] != b'=' {
+            pos += 1;
+        }
+        let name = &self.html[name_start..pos];
+
+        let after_name = skip_whitespace(bytes, pos);
+        if bytes.get(after_name) != Some(&b'=') {
+            return Some((
+                pos,
+                Attribute {
+                    name,
+                    value: "",
+                    value_span: pos..pos,
+                    span: name_start..pos,
+                },
+            ));
+        }
+        let value_start = skip_whitespace(bytes, after_name + 1);
+        let (value_span, end) = match *bytes.get(value_start)? {
+            quote @ b'"' | quote @ b'\'' => {
+                let len = self.html[value_start + 1..self.end].find(quote as char)?;
+                let span = value_start + 1..value_start + 1 + len;
+                let end = span.end + 1;
+                (span, end)
+            }
+            // "a=>" has an empty value
+            b'>' => (value_start..value_start, value_start),
+            _ => {
+                let mut end = value_start;
+                while end < bytes.len() && !is_whitespace(bytes[end]) && bytes[end] != b'>' {
+                    end += 1;
+                }
+                (value_start..end, end)
+            }
+        };
+
+        Some((
+            end,
+            Attribute {
+                name,
+                value: &self.html[value_span.clone()],
+                value_span,
+                span: name_start..end,
+            },
+        ))
+    }
+}
+
+impl<'a> Iterator for Tokenizer<'a> {
+    type Item = (Range<usize>, Token<'a>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.pos >= self.end {
+            return None;
+        }
+
+        if let Some(element) = self.raw_text_element.take() {
+            let span = self.raw_text(element);
+            if !span.is_empty() {
+                self.pos = span.end;
+                return Some((span.clone(), Token::RawText(&self.html[span])));
+            }
+        }
+
+        let start = self.pos;
+        if starts_markup(self.bytes(), start) {
+            if let Some((end, token)) = self.markup() {
+                if let Token::StartTag(tag) = &token {
+                    self.raw_text_element = RAW_TEXT_ELEMENTS
+                        .iter()
+                        .find(|element| tag.is(element))
+                        .copied();
+                }
+                self.pos = end;
+                return Some((start..end, token));
+            }
+            // an unterminated tag is kept as text
+            self.pos = self.end;
+            return Some((start..self.end, Token::Text(&self.html[start..self.end])));
+        }
+
+        let span = self.text();
+        self.pos = span.end;
+        Some((span.clone(), Token::Text(&self.html[span])))
+    }
+}
+
+/// True if the `<` at `pos` starts a tag, comment or similar, instead of
+/// being part of the text, as in "1 < 2".
+fn starts_markup(bytes: &[u8], pos: usize) -> bool {
+    bytes[pos] == b'<'
+        && match bytes.get(pos + 1) {
+            Some(b) => b.is_ascii_alphabetic() || *b == b'/' || *b == b'!' || *b == b'?',
+            None => false,
+        }
+}
+
+fn is_whitespace(byte: u8) -> bool {
+    b" \t\n\r\x0c".contains(&byte)
+}
+
+fn ends_tag_name(byte: u8) -> bool {
+    is_whitespace(byte) || byte == b'/' || byte == b'>'
+}
+
+fn skip_whitespace(bytes: &[u8], mut pos: usize) -> usize {
+    while pos < bytes.len() && is_whitespace(bytes[pos]) {
+        pos += 1;
+    }
+    pos
+}
+
+#[cfg(test)]
+mod test {
+    use crate::html::{Attribute, Tag, Token, Tokenizer};
+
+    fn tokens(html: &str) -> Vec<Token> {
+        Tokenizer::new(html).map(|(_span, token)| token).collect()
+    }
+
+    #[test]
+    fn test_tokenizer() {
+        assert_eq!(
+            tokens("a<B class=x>b</b >"),
+            vec![
+                Token::Text("a"),
+                Token::StartTag(Tag {
+                    name: "B",
+                    attrs: vec![Attribute {
+                        name: "class",
+                        value: "x",
+                        value_span: 10..11,
+                        span: 4..11,
+                    }],
+                    self_closing: false,
+                }),
+                Token::Text("b"),
+                Token::EndTag("b"),
+            ]
+        );
+
+        // quoted values may contain ">" and the other kind of quote
+        let html = r#"<img alt="1 > 0" title='"x"' hidden src = a.jpg/>"#;
+        let tag = match &tokens(html)[0] {
+            Token::StartTag(tag) => tag.clone(),
+            other => panic!("unexpected token: {:?}", other),
+        };
+        assert_eq!(tag.attr("ALT").unwrap().value, "1 > 0");
+        assert_eq!(tag.attr("title").unwrap().value, r#""x""#);
+        assert_eq!(tag.attr("hidden").unwrap().value, "");
+        // as in a browser, the slash is part of an unquoted value
+        let src = tag.attr("src").unwrap();
+        assert_eq!(src.value, "a.jpg/");
+        assert_eq!(&html[src.value_span.clone()], "a.jpg/");
+        assert_eq!(
+            &html[tag.attr("title").unwrap().span.clone()],
+            r#"title='"x"'"#
+        );
+        assert_eq!(&html[src.span.clone()], "src = a.jpg/");
+        assert!(!tag.self_closing);
+        assert_eq!(tag.attrs.len(), 4);
+
+        // a "<" that can't start a tag is text
+        assert_eq!(tokens("1 < 2 <3"), vec![Token::Text("1 < 2 <3")]);
+        // unterminated tags are kept as text
+        assert_eq!(
+            tokens(r#"a<img src="x>"#),
+            vec![Token::Text("a"), Token::Text(r#"<img src="x>"#)]
+        );
+
+        assert_eq!(
+            tokens("<script>if (a<b) x='</b>';</SCRIPT>c<style>"),
+            vec![
+                Token::StartTag(Tag {
+                    name: "script",
+                    attrs: vec![],
+                    self_closing: false,
+                }),
+                Token::RawText("if (a<b) x='</b>';"),
+                Token::EndTag("SCRIPT"),
+                Token::Text("c"),
+                Token::StartTag(Tag {
+                    name: "style",
+                    attrs: vec![],
+                    self_closing: false,
+                }),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_comments() {
+        assert_eq!(
+            tokens("<!--[if gte mso 9]><xml>x</xml><![endif]-->a<![if !vml]>b<![endif]>"),
+            vec![
+                Token::Comment("[if gte mso 9]><xml>x</xml><![endif]"),
+                Token::Text("a"),
+                Token::Ignored,
+                Token::Text("b"),
+                Token::Ignored,
+            ]
+        );
+        assert_eq!(
+            tokens("<!DOCTYPE html><?xml?></><!--><!---></ b>a<!-- b"),
+            vec![
+                Token::Ignored,
+                Token::Ignored,
+                Token::Ignored,
+                Token::Comment(""),
+                Token::Comment(""),
+                Token::Ignored,
+                Token::Text("a"),
+                Token::Comment(" b"),
+            ]
+        );
+
+        // the contents of a comment can be tokenized in place
+        let html = "x<!--<img src=a.jpg>-->";
+        let inner: Vec<_> = Tokenizer::in_range(html, 5..html.len() - 3).collect();
+        match &inner[0] {
+            (span, Token::StartTag(tag)) => {
+                assert_eq!(span, &(5..20));
+                assert_eq!(tag.attr("src").unwrap().value_span, 14..19);
+            }
+            other => panic!("unexpected token: {:?}", other),
+        }
+    }
+}
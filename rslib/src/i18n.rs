@@ -0,0 +1,206 @@
+// Copyright: Ankitects Pty Ltd and contributors
+// License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+//! Translating the text rslib shows to the user, with Fluent. The English
+//! messages in rslib/ftl are compiled in, and translations are read from
+//! .ftl files in a folder per language, like `<ftl folder>/pt-BR/`. A
+//! message is looked up in each of the preferred languages in turn, then
+//! in their base languages (so "pt-BR" falls back to "pt"), and finally in
+//! English, so a partial translation is still usable.
+
+use fluent::concurrent::FluentBundle;
+use fluent::FluentResource;
+use std::fs;
+use std::path::Path;
+use tracing::warn;
+use unic_langid::LanguageIdentifier;
+
+pub use fluent::{FluentArgs, FluentValue};
+
+/// The messages every language falls back on.
+const ENGLISH_FTL: &[&str] = &[
+    include_str!("../ftl/errors.ftl"),
+    include_str!("../ftl/media-check.ftl"),
+];
+
+/// Build the arguments of a message, eg `tr_args!["count" => 3]`.
+#[macro_export]
+macro_rules! tr_args {
+    ( $($key:expr => $value:expr),* ) => {{
+        let mut args: $crate::i18n::FluentArgs = $crate::i18n::FluentArgs::new();
+        $( args.insert($key, $value.into()); )*
+        args
+    }};
+}
+
+/// Can be shared between threads, as the bundles are the concurrent kind.
+pub struct I18n {
+    /// In the order messages are looked up, ending with English.
+    bundles: Vec<FluentBundle<FluentResource>>,
+}
+
+impl Default for I18n {
+    /// English only.
+    fn default() -> Self {
+        I18n::new::<&str>(&[], None)
+    }
+}
+
+impl I18n {
+    /// `locale_codes` are the user's preferred languages, in order, as
+    /// codes like "pt_BR" or "pt-BR". Languages without a folder in
+    /// `ftl_folder` are skipped.
+    pub fn new<S: AsRef<str>>(locale_codes: &[S], ftl_folder: Option<&Path>) -> I18n {
+        let mut bundles = vec![];
+        if let Some(folder) = ftl_folder {
+            for lang in fallback_languages(locale_codes) {
+                let texts = ftl_files_in(&folder.join(&lang));
+                if let Some(bundle) = bundle_for(&lang, &texts) {
+                    bundles.push(bundle);
+                }
+            }
+        }
+        let english: Vec<_> = ENGLISH_FTL.iter().map(|s| s.to_string()).collect();
+        bundles.push(bundle_for("en-US", &english).expect("invalid English messages"));
+
+        I18n { bundles }
+    }
+
+    /// Translate a message that takes no arguments.
+    pub fn tr(&self, key: &str) -> String {
+        self.translate(key, None)
+    }
+
+    /// Translate a message, filling in its arguments. Numeric arguments
+    /// select the plural forms of the language.
+    pub fn trn(&self, key: &str, args: FluentArgs) -> String {
+        self.translate(key, Some(&args))
+    }
+
+    fn translate(&self, key: &str, args: Option<&FluentArgs>) -> String {
+        for bundle in &self.bundles {
+            if let Some(pattern) = bundle.get_message(key).and_then(|msg| msg.value) {
+                let mut errors = vec![];
+                let text = bundle.format_pattern(pattern, args, &mut errors);
+                if !errors.is_empty() {
+                    warn!(key, ?errors, "translation failed");
+                }
+                return text.into_owned();
+            }
+        }
+        // only possible if the key is misspelt
+        key.to_string()
+    }
+}
+
+/// The preferred languages followed by their base languages, without
+/// duplicates, eg ["pt-BR", "pt", "de-DE", "de"] for ["pt_BR", "de_DE"].
+fn fallback_languages<S: AsRef<str>>(locale_codes: &[S]) -> Vec<String> {
+    let mut langs: Vec<String> = vec![];
+    for code in locale_codes {
+        let code = code.as_ref().replace('_', "-");
+        let base = code.split('-').next().unwrap_or_default().to_string();
+        for lang in &[code.clone(), base] {
+            if !lang.is_empty() && !langs.contains(lang) {
+                langs.push(lang.clone());
+            }
+        }
+    }
+    langs
+}
+
+/// The text of the .ftl files in a folder, which may not exist.
+fn ftl_files_in(folder: &Path) -> Vec<String> {
+    let entries = match fs::read_dir(folder) {
+        Ok(entries) => entries,
+        Err(_) => return vec![],
+    };
+    let mut paths: Vec<_> = entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.extension().and_then(|ext| ext.to_str()) == Some("ftl"))
+        .collect();
+    paths.sort();
+
+    paths
+        .into_iter()
+        .filter_map(|path| match fs::read_to_string(&path) {
+            Ok(text) => Some(text),
+            Err(e) => {
+                warn!(?path, error = %e, "unreadable translation");
+                None
+            }
+        })
+        .collect()
+}
+
+/// A bundle of the messages in `texts`, or None if there are none or the
+/// language code is invalid. Messages that fail to parse are skipped.
+fn bundle_for(lang: &str, texts: &[String]) -> Option<FluentBundle<FluentResource>> {
+    if texts.is_empty() {
+        return None;
+    }
+    let langid: LanguageIdentifier = lang.parse().ok()?;
+    let mut bundle = FluentBundle::new(&[langid]);
+    // the marks that keep right-to-left text apart from its arguments
+    // would only clutter the test output
+    if cfg!(test) {
+        bundle.set_use_isolating(false);
+    }
+    for text in texts {
+        let resource = FluentResource::try_new(text.clone()).unwrap_or_else(|(res, errors)| {
+            warn!(lang, ?errors, "invalid translation");
+            res
+        });
+        if let Err(errors) = bundle.add_resource(resource) {
+            warn!(lang, ?errors, "duplicate translation");
+        }
+    }
+
+    Some(bundle)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::err::Result;
+    use crate::i18n::{fallback_languages, I18n};
+    use std::fs;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_fallback_languages() {
+        assert_eq!(
+            fallback_languages(&["pt_BR", "pt-PT", "de"]),
+            vec!["pt-BR", "pt", "pt-PT", "de"]
+        );
+    }
+
+    #[test]
+    fn test_translate() -> Result<()> {
+        let i18n = I18n::default();
+        assert_eq!(
+            i18n.trn("media-check-updated-refs", tr_args!["count" => 1]),
+            "Updated media references in 1 note."
+        );
+        assert_eq!(
+            i18n.trn("media-check-updated-refs", tr_args!["count" => 3]),
+            "Updated media references in 3 notes."
+        );
+        assert_eq!(i18n.tr("no-such-message"), "no-such-message");
+
+        let dir = tempdir()?;
+        fs::create_dir(dir.path().join("pt"))?;
+        fs::write(
+            dir.path().join("pt").join("media-check.ftl"),
+            "media-check-invalid-name = Nome de arquivo inválido: { $name }\n",
+        )?;
+        let i18n = I18n::new(&["pt_BR"], Some(dir.path()));
+        // the base language is used, then English
+        assert_eq!(
+            i18n.trn("media-check-invalid-name", tr_args!["name" => "a:b"]),
+            "Nome de arquivo inválido: a:b"
+        );
+        assert_eq!(i18n.tr("error-interrupted"), "Operation cancelled.");
+
+        Ok(())
+    }
+}
@@ -0,0 +1,352 @@
+// Copyright: Ankitects Pty Ltd and contributors
+// License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+//! Mnemosyne 2 databases. A fact's data is a set of keys and values, and
+//! its cards are views of it; the view's id names the card type (1 front
+//! only, 2 front and back, 3 vocabulary, 5 cloze) and the card's number.
+
+use super::{
+    canonical_tags, import_foreign_notes, new_notetype, ForeignCard, ForeignImportLog, ForeignNote,
+    ForeignNotes,
+};
+use crate::err::Result;
+use crate::notetypes::{NoteType, NoteTypeKind};
+use crate::storage::SqliteStorage;
+use lazy_static::lazy_static;
+use regex::{Captures, Regex};
+use rusqlite::{Connection, OpenFlags, NO_PARAMS};
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::path::Path;
+
+lazy_static! {
+    static ref NEWLINE: Regex = Regex::new(r"\r?\n").unwrap();
+    static ref LATEX: Regex = Regex::new(r"(?i)<(/?(\$|\$\$|latex))>").unwrap();
+    static ref AUDIO: Regex = Regex::new(r#"<audio src="(.+?)">(</audio>)?"#).unwrap();
+    static ref CLOZE: Regex = Regex::new(r"\[(.+?)\]").unwrap();
+    static ref CARD_NUMBER: Regex = Regex::new(r".(\d+)$").unwrap();
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum FactKind {
+    FrontOnly,
+    FrontBack,
+    Vocabulary,
+    Cloze,
+}
+
+impl FactKind {
+    /// The kind of fact a card of the provided view belongs to. Cloned card
+    /// types have ids like "1::name".
+    fn from_view_id(view_id: &str) -> Option<Self> {
+        let is_type = |id: &str| {
+            view_id.starts_with(&format!("{}.", id)) || view_id.starts_with(&format!("{}::", id))
+        };
+        if is_type("1") {
+            Some(FactKind::FrontOnly)
+        } else if is_type("2") {
+            Some(FactKind::FrontBack)
+        } else if is_type("3") {
+            Some(FactKind::Vocabulary)
+        } else if view_id.starts_with("5.1") {
+            Some(FactKind::Cloze)
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Fact {
+    kind: Option<FactKind>,
+    data: HashMap<String, String>,
+    tags: Vec<String>,
+    cards: HashMap<u16, ForeignCard>,
+}
+
+/// Add the facts of a Mnemosyne database as notes. Cards that have been
+/// studied are scheduled as reviews.
+pub fn import_mnemosyne(
+    storage: &mut SqliteStorage,
+    path: &Path,
+    deck_id: i64,
+) -> Result<ForeignImportLog> {
+    let db = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    let version: String = db.query_row(
+        "select value from global_variables where key='version'",
+        NO_PARAMS,
+        |row| row.get(0),
+    )?;
+    let known_version = version.starts_with("Mnemosyne SQL 1") || version == "2" || version == "3";
+
+    let facts = read_facts(&db)?;
+    let mut log = import_foreign_notes(storage, deck_id, notes_from_facts(facts))?;
+    log.unknown_version = !known_version;
+    Ok(log)
+}
+
+fn read_facts(db: &Connection) -> Result<HashMap<i64, Fact>> {
+    let mut facts: HashMap<i64, Fact> = HashMap::new();
+
+    let mut stmt = db.prepare("select _fact_id, key, value from data_for_fact")?;
+    let mut rows = stmt.query(NO_PARAMS)?;
+    while let Some(row) = rows.next()? {
+        let fact = facts.entry(row.get(0)?).or_default();
+        fact.data.insert(row.get(1)?, row.get(2)?);
+    }
+
+    let mut stmt = db.prepare(
+        "select _fact_id, fact_view_id, tags, next_rep, last_rep, easiness,
+acq_reps + ret_reps, lapses from cards",
+    )?;
+    let mut rows = stmt.query(NO_PARAMS)?;
+    while let Some(row) = rows.next()? {
+        let fact = match facts.get_mut(&row.get(0)?) {
+            Some(fact) => fact,
+            None => continue,
+        };
+        let view_id: String = row.get(1)?;
+        if view_id.ends_with(".1") {
+            fact.kind = fact.kind.or_else(|| FactKind::from_view_id(&view_id));
+        }
+
+        // tags are separated by ", ", and may contain spaces
+        let tags: Option<String> = row.get(2)?;
+        fact.tags.extend(
+            tags.unwrap_or_default()
+                .split(", ")
+                .map(|tag| tag.trim().replace(' ', "_")),
+        );
+
+        // new cards are left unscheduled
+        let next_rep: i64 = row.get(3)?;
+        if next_rep == -1 {
+            continue;
+        }
+        let last_rep: i64 = row.get(4)?;
+        let easiness: f64 = row.get(5)?;
+        let ord = CARD_NUMBER
+            .captures(&view_id)
+            .and_then(|caps| caps[1].parse::<u16>().ok())
+            .unwrap_or(1)
+            .saturating_sub(1);
+        fact.cards.insert(
+            ord,
+            ForeignCard {
+                due_secs: next_rep,
+                // the interval isn't stored, so it's inferred
+                interval: ((next_rep - last_rep) / 86_400).max(1) as u32,
+                ease_factor: (easiness * 1000.0) as u16,
+                reps: row.get(6)?,
+                lapses: row.get(7)?,
+            },
+        );
+    }
+
+    Ok(facts)
+}
+
+/// Convert Mnemosyne's markup to Anki's.
+fn munge_field(text: &str) -> String {
+    let text = NEWLINE.replace_all(text, "<br>");
+    let text = LATEX.replace_all(&text, "[$1]");
+    AUDIO.replace_all(&text, "[sound:$1]").into_owned()
+}
+
+/// Turn each [deletion] into a numbered cloze deletion.
+fn cloze_text(text: &str) -> String {
+    let text = NEWLINE.replace_all(text, "<br>");
+    let mut num = 0;
+    let text: Cow<str> = CLOZE.replace_all(&text, |caps: &Captures| {
+        num += 1;
+        format!("{{{{c{}::{}}}}}", num, &caps[1])
+    });
+    munge_field(&text)
+}
+
+/// Group the facts by kind, with a note type for each kind. Facts are
+/// ordered by id, so notes are added in the order they were created.
+fn notes_from_facts(facts: HashMap<i64, Fact>) -> Vec<ForeignNotes> {
+    let mut facts: Vec<_> = facts.into_iter().collect();
+    facts.sort_unstable_by_key(|(id, _)| *id);
+
+    let kinds = [
+        FactKind::FrontOnly,
+        FactKind::FrontBack,
+        FactKind::Vocabulary,
+        FactKind::Cloze,
+    ];
+    kinds
+        .iter()
+        .map(|kind| {
+            let keys: &[&str] = match kind {
+                FactKind::FrontOnly | FactKind::FrontBack => &["f", "b"],
+                FactKind::Vocabulary => &["f", "p_1", "m_1", "n"],
+                FactKind::Cloze => &["text"],
+            };
+            let notes = facts
+                .iter()
+                .filter(|(_, fact)| fact.kind == Some(*kind))
+                .map(|(_, fact)| {
+                    let field = |key: &str| {
+                        let text = fact.data.get(key).map(String::as_str).unwrap_or_default();
+                        if *kind == FactKind::Cloze {
+                            cloze_text(text)
+                        } else {
+                            munge_field(text)
+                        }
+                    };
+                    ForeignNote {
+                        fields: keys.iter().map(|key| field(key)).collect(),
+                        tags: canonical_tags(fact.tags.iter().cloned()),
+                        cards: fact.cards.clone(),
+                    }
+                })
+                .collect();
+            ForeignNotes {
+                notetype: notetype_for_kind(*kind),
+                notes,
+            }
+        })
+        .collect()
+}
+
+fn notetype_for_kind(kind: FactKind) -> NoteType {
+    let front = (
+        "Card 1",
+        "{{Front}}",
+        "{{FrontSide}}\n\n<hr id=answer>\n\n{{Back}}",
+    );
+    match kind {
+        FactKind::FrontOnly => new_notetype(
+            "Mnemosyne-FrontOnly",
+            NoteTypeKind::Standard,
+            &["Front", "Back"],
+            &[front],
+        ),
+        FactKind::FrontBack => new_notetype(
+            "Mnemosyne-FrontBack",
+            NoteTypeKind::Standard,
+            &["Front", "Back"],
+            &[
+                front,
+                (
+                    "Back",
+                    "{{Back}}",
+                    "{{Back}}\n\n<hr id=answer>\n\n{{Front}}",
+                ),
+            ],
+        ),
+        FactKind::Vocabulary => new_notetype(
+            "Mnemosyne-Vocabulary",
+            NoteTypeKind::Standard,
+            &["Expression", "Pronunciation", "Meaning", "Notes"],
+            &[
+                (
+                    "Recognition",
+                    "{{Expression}}",
+                    "{{Expression}}\n\n<hr id=answer>\n\n\
+                     {{Pronunciation}}<br>\n{{Meaning}}<br>\n{{Notes}}",
+                ),
+                (
+                    "Production",
+                    "{{Meaning}}",
+                    "{{Meaning}}\n\n<hr id=answer>\n\n\
+                     {{Expression}}<br>\n{{Pronunciation}}<br>\n{{Notes}}",
+                ),
+            ],
+        ),
+        FactKind::Cloze => new_notetype(
+            "Mnemosyne-Cloze",
+            NoteTypeKind::Cloze,
+            &["Text"],
+            &[("Cloze", "{{cloze:Text}}", "{{cloze:Text}}")],
+        ),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::card::CardType;
+    use crate::err::Result;
+    use crate::import_export::foreign::import_mnemosyne;
+    use crate::storage::testutil::open_test_collection;
+    use rusqlite::Connection;
+
+    #[test]
+    fn test_import_mnemosyne() -> Result<()> {
+        let (dir, mut storage) = open_test_collection()?;
+        let path = dir.path().join("mnemo.db");
+        let db = Connection::open(&path)?;
+        db.execute_batch(
+            "
+create table global_variables (key text, value text);
+insert into global_variables values ('version', 'Mnemosyne SQL 1.0');
+create table data_for_fact (_fact_id integer, key text, value text);
+insert into data_for_fact values
+    (1, 'f', 'front'), (1, 'b', 'back'),
+    (2, 'f', 'one'), (2, 'b', 'two'),
+    (3, 'f', 'expression'), (3, 'p_1', 'pronunciation'), (3, 'm_1', 'meaning'),
+    (4, 'f', 'new<audio src=\"a.mp3\">'),
+    (5, 'text', 'a [b] c [d]');
+create table cards (_fact_id integer, fact_view_id text, tags text, next_rep integer,
+    last_rep integer, easiness real, acq_reps integer, ret_reps integer, lapses integer);
+insert into cards values
+    (1, '1.1', '', 2000000000, 1999827200, 2.5, 1, 2, 1),
+    (2, '2.1', 'a longer tag, tag 1', 2000000000, 1999913600, 2.5, 1, 0, 0),
+    (2, '2.2', 'a longer tag, tag 1', 2000000000, 1999913600, 2.5, 1, 0, 0),
+    (3, '3.1', null, 2000000000, 1999913600, 2.5, 1, 0, 0),
+    (3, '3.2', null, 2000000000, 1999913600, 2.5, 1, 0, 0),
+    (4, '1::clone.1', 'Tag 1', -1, -1, 2.5, 0, 0, 0),
+    (5, '5.1', '', -1, -1, 2.5, 0, 0, 0);
+",
+        )?;
+        drop(db);
+
+        let log = import_mnemosyne(&mut storage, &path, 1)?;
+        assert_eq!(log.notes_added, 5);
+        assert!(!log.unknown_version);
+
+        let notetypes = storage.get_all_notetypes()?;
+        let mut names: Vec<_> = notetypes.values().map(|nt| nt.name.as_str()).collect();
+        names.sort_unstable();
+        assert_eq!(
+            names,
+            vec![
+                "Mnemosyne-Cloze",
+                "Mnemosyne-FrontBack",
+                "Mnemosyne-FrontOnly",
+                "Mnemosyne-Vocabulary"
+            ]
+        );
+
+        let mut notes: Vec<_> = storage
+            .all_note_ids()?
+            .into_iter()
+            .map(|id| storage.get_note(id).map(Option::unwrap))
+            .collect::<Result<_>>()?;
+        notes.sort_unstable_by_key(|note| note.id);
+        // notes are added by type, then in the order of their facts
+        assert_eq!(notes[1].fields, vec!["new[sound:a.mp3]", ""]);
+        assert_eq!(notes[1].tags, vec!["Tag_1"]);
+        assert_eq!(notes[2].tags, vec!["a_longer_tag", "tag_1"]);
+        assert_eq!(notes[4].fields, vec!["a {{c1::b}} c {{c2::d}}"]);
+
+        let cards: Vec<_> = notes
+            .iter()
+            .map(|note| storage.get_cards_of_note(note.id))
+            .collect::<Result<_>>()?;
+        assert_eq!(
+            cards.iter().map(Vec::len).collect::<Vec<_>>(),
+            vec![1, 1, 2, 2, 2]
+        );
+        let card = &cards[0][0];
+        assert_eq!(card.ctype, CardType::Review);
+        assert_eq!((card.interval, card.ease_factor), (2, 2500));
+        assert_eq!((card.reps, card.lapses), (3, 1));
+        assert_eq!(cards[1][0].ctype, CardType::New);
+        assert_eq!(cards[4][1].ctype, CardType::New);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,241 @@
+// Copyright: Ankitects Pty Ltd and contributors
+// License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+//! Importing from other spaced repetition programs. Each program's data is
+//! read into note types and notes first, which are then added to the
+//! collection as new note types, so existing notes are never changed.
+//! Cards that have been studied keep their scheduling as reviews.
+
+mod mnemosyne;
+mod supermemo;
+
+pub use mnemosyne::import_mnemosyne;
+pub use supermemo::import_supermemo_xml;
+
+use crate::card::{Card, CardQueue, CardType};
+use crate::cardgen::CardGenContext;
+use crate::err::Result;
+use crate::notes::{field_checksum, new_guid, Note};
+use crate::notetypes::{CardTemplate, NoteField, NoteType, NoteTypeKind};
+use crate::sched::current_deck::collection_timing_today;
+use crate::storage::{now_millis, SqliteStorage};
+use crate::tags::{canonical_tags, register_tags};
+use crate::text::sort_field_text;
+use serde_json::{json, Map, Value};
+use std::collections::{HashMap, HashSet};
+
+/// A note read from another program. Fields are HTML, in the order of the
+/// note type's fields.
+#[derive(Debug, Clone, Default, PartialEq)]
+struct ForeignNote {
+    fields: Vec<String>,
+    tags: Vec<String>,
+    /// The scheduling of the cards that have been studied, by ordinal.
+    cards: HashMap<u16, ForeignCard>,
+}
+
+/// The scheduling of a studied card.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct ForeignCard {
+    /// When the card is next due, as a timestamp in seconds.
+    due_secs: i64,
+    /// In days.
+    interval: u32,
+    /// In permille.
+    ease_factor: u16,
+    reps: u32,
+    lapses: u32,
+}
+
+/// A note type to add, and the notes to add with it.
+#[derive(Debug, Clone)]
+struct ForeignNotes {
+    notetype: NoteType,
+    notes: Vec<ForeignNote>,
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct ForeignImportLog {
+    pub notes_added: u32,
+    /// Notes that were not added, as they would have no cards.
+    pub without_cards: u32,
+    /// Set if the file's format version wasn't recognised, but the import
+    /// went ahead anyway.
+    pub unknown_version: bool,
+}
+
+/// A note type with the default settings of one created in the
+/// legacy code. Templates are (name, front, back).
+fn new_notetype(
+    name: &str,
+    kind: NoteTypeKind,
+    fields: &[&str],
+    templates: &[(&str, &str, &str)],
+) -> NoteType {
+    let mut css = ".card {\n font-family: arial;\n font-size: 20px;\n text-align: center;\n \
+                   color: black;\n background-color: white;\n}\n"
+        .to_string();
+    if kind == NoteTypeKind::Cloze {
+        css.push_str(
+            "\n.cloze {\n font-weight: bold;\n color: blue;\n}\n.nightMode .cloze {\n \
+             color: lightblue;\n}",
+        );
+    }
+    let other = json!({
+        "did": 1,
+        "latexPre": "\\documentclass[12pt]{article}\n\\special{papersize=3in,5in}\n\
+                     \\usepackage[utf8]{inputenc}\n\\usepackage{amssymb,amsmath}\n\
+                     \\pagestyle{empty}\n\\setlength{\\parindent}{0in}\n\\begin{document}\n",
+        "latexPost": "\\end{document}",
+        "vers": [],
+        "tags": [],
+        "css": css,
+    });
+
+    NoteType {
+        id: 0,
+        name: name.into(),
+        mtime_secs: 0,
+        usn: 0,
+        kind: kind as u8,
+        sort_field_idx: 0,
+        fields: fields
+            .iter()
+            .enumerate()
+            .map(|(ord, name)| NoteField {
+                name: (*name).into(),
+                ord: ord as u16,
+                other: json_map(json!({
+                    "sticky": false,
+                    "rtl": false,
+                    "font": "Arial",
+                    "size": 20,
+                    "media": [],
+                })),
+            })
+            .collect(),
+        templates: templates
+            .iter()
+            .enumerate()
+            .map(|(ord, (name, front, back))| CardTemplate {
+                name: (*name).into(),
+                ord: ord as u16,
+                question_format: (*front).into(),
+                answer_format: (*back).into(),
+                other: json_map(json!({"did": null, "bqfmt": "", "bafmt": ""})),
+            })
+            .collect(),
+        other: json_map(other),
+    }
+}
+
+fn json_map(value: Value) -> Map<String, Value> {
+    match value {
+        Value::Object(map) => map,
+        _ => Map::new(),
+    }
+}
+
+/// Add the note types and their notes, putting new cards in the provided
+/// deck. Note types whose names are taken are given a suffix.
+fn import_foreign_notes(
+    storage: &mut SqliteStorage,
+    deck_id: i64,
+    groups: Vec<ForeignNotes>,
+) -> Result<ForeignImportLog> {
+    storage.transact(|storage| {
+        let usn = storage.usn()?;
+        let now_millis = now_millis();
+        let now_secs = now_millis / 1000;
+        let today = collection_timing_today(storage, &storage.get_all_config()?, now_secs)?
+            .days_elapsed as i64;
+        let mut notetypes = storage.get_all_notetypes()?;
+        let mut next_position: i64 = storage.get_config_value("nextPos")?.unwrap_or(1);
+        let mut tags = HashSet::new();
+        let mut log = ForeignImportLog::default();
+
+        for group in groups.into_iter().filter(|g| !g.notes.is_empty()) {
+            let mut notetype = group.notetype;
+            notetype.id = now_millis;
+            while notetypes.contains_key(&notetype.id) {
+                notetype.id += 1;
+            }
+            let base_name = notetype.name.clone();
+            let mut suffix = 1;
+            while notetypes.values().any(|nt| nt.name == notetype.name) {
+                suffix += 1;
+                notetype.name = format!("{}-{}", base_name, suffix);
+            }
+            notetype.mtime_secs = now_secs;
+            notetype.usn = usn;
+            notetype.other.insert("did".into(), deck_id.into());
+            let cardgen = CardGenContext::for_notetype(&notetype);
+            if let Some(reqs) = cardgen.legacy_requirements() {
+                notetype.other.insert("req".into(), reqs);
+            }
+            storage.add_or_update_notetype(&notetype)?;
+
+            for foreign in group.notes {
+                let mut fields = foreign.fields;
+                fields.resize(notetype.fields.len(), "".into());
+                let field_refs: Vec<_> = fields.iter().map(String::as_str).collect();
+                let mut ords: Vec<_> = cardgen.ords_to_generate(&field_refs).into_iter().collect();
+                if ords.is_empty() {
+                    log.without_cards += 1;
+                    continue;
+                }
+                ords.sort_unstable();
+
+                let sort_field = fields
+                    .get(notetype.sort_field_idx as usize)
+                    .map(String::as_str)
+                    .unwrap_or_default();
+                let mut note = Note {
+                    guid: new_guid(),
+                    notetype_id: notetype.id,
+                    mtime_secs: now_secs,
+                    usn,
+                    tags: foreign.tags,
+                    sort_field: sort_field_text(sort_field).into_owned(),
+                    checksum: field_checksum(&fields[0]),
+                    fields,
+                    ..Default::default()
+                };
+                storage.add_note(&mut note)?;
+                tags.extend(note.tags.iter().cloned());
+
+                for ord in ords {
+                    let mut card = Card {
+                        note_id: note.id,
+                        deck_id,
+                        ordinal: ord,
+                        mtime_secs: now_secs,
+                        usn,
+                        due: next_position,
+                        ..Default::default()
+                    };
+                    if let Some(foreign) = foreign.cards.get(&ord) {
+                        card.ctype = CardType::Review;
+                        card.queue = CardQueue::Review;
+                        card.due = today + (foreign.due_secs - now_secs) / 86_400;
+                        card.interval = foreign.interval;
+                        card.ease_factor = foreign.ease_factor;
+                        card.reps = foreign.reps;
+                        card.lapses = foreign.lapses;
+                    }
+                    storage.add_card(&mut card)?;
+                }
+                next_position += 1;
+                log.notes_added += 1;
+            }
+            notetypes.insert(notetype.id, notetype);
+        }
+
+        let tags: Vec<_> = tags.iter().collect();
+        register_tags(storage, &tags, usn)?;
+        storage.set_config_value("nextPos", &next_position)?;
+        storage.mark_modified(now_millis)?;
+
+        Ok(log)
+    })
+}
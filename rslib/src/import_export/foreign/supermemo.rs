@@ -0,0 +1,298 @@
+// Copyright: Ankitects Pty Ltd and contributors
+// License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+//! SuperMemo XML exports. Elements are nested: topics have a title, and
+//! items have a question and answer, and the learning data of the item.
+//! The titles of the topics an item is in become its tags.
+
+use super::{
+    canonical_tags, import_foreign_notes, new_notetype, ForeignCard, ForeignImportLog, ForeignNote,
+    ForeignNotes,
+};
+use crate::err::{AnkiError, Result};
+use crate::notetypes::NoteTypeKind;
+use crate::storage::SqliteStorage;
+use chrono::{Local, NaiveDate, TimeZone};
+use lazy_static::lazy_static;
+use regex::Regex;
+use roxmltree::{Document, Node};
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use unicode_normalization::char::is_combining_mark;
+use unicode_normalization::UnicodeNormalization;
+
+lazy_static! {
+    static ref NUMBER_IN_BRACKETS: Regex = Regex::new(r"\[[0-9]+\]").unwrap();
+    static ref NON_WORD: Regex = Regex::new(r"\W").unwrap();
+    static ref ONLY_NUMBERS: Regex = Regex::new(r"^[0-9 ]+$").unwrap();
+}
+
+/// Add the items of a SuperMemo XML export as notes. Items that have been
+/// memorized are scheduled as reviews, and tagged "Memorized".
+pub fn import_supermemo_xml(
+    storage: &mut SqliteStorage,
+    path: &Path,
+    deck_id: i64,
+) -> Result<ForeignImportLog> {
+    let text = fs::read_to_string(path)?;
+    let doc = Document::parse(&text)
+        .map_err(|e| AnkiError::invalid_input(format!("invalid XML: {}", e)))?;
+
+    let mut notes = vec![];
+    let mut titles = vec![];
+    for element in child_elements(doc.root_element(), "SuperMemoElement") {
+        read_element(element, &mut titles, &mut notes);
+    }
+
+    let notetype = new_notetype(
+        "Supermemo",
+        NoteTypeKind::Standard,
+        &["Front", "Back"],
+        &[(
+            "Card 1",
+            "{{Front}}",
+            "{{FrontSide}}\n\n<hr id=answer>\n\n{{Back}}",
+        )],
+    );
+    import_foreign_notes(storage, deck_id, vec![ForeignNotes { notetype, notes }])
+}
+
+fn child_elements<'a, 'input>(
+    node: Node<'a, 'input>,
+    name: &'static str,
+) -> impl Iterator<Item = Node<'a, 'input>> {
+    node.children().filter(move |n| n.has_tag_name(name))
+}
+
+/// The trimmed text of the named child, if it has any.
+fn child_text<'a>(node: Node<'a, '_>, name: &'static str) -> Option<&'a str> {
+    child_elements(node, name)
+        .next()
+        .and_then(|n| n.text())
+        .map(str::trim)
+}
+
+/// Add a note for the element if it's an item, then read the elements
+/// inside it. `titles` holds the titles of the topics the element is in.
+fn read_element(element: Node, titles: &mut Vec<String>, notes: &mut Vec<ForeignNote>) {
+    let title = child_text(element, "Title").map(decode_text);
+    if let Some(title) = &title {
+        titles.push(title.clone());
+    } else if let Some(note) = note_from_item(element, titles) {
+        notes.push(note);
+    }
+
+    for child in child_elements(element, "SuperMemoElement") {
+        read_element(child, titles, notes);
+    }
+
+    if title.is_some() {
+        titles.pop();
+    }
+}
+
+fn note_from_item(element: Node, titles: &[String]) -> Option<ForeignNote> {
+    let content = child_elements(element, "Content").next()?;
+    let question = child_text(content, "Question").filter(|s| !s.is_empty())?;
+    let answer = child_text(content, "Answer").filter(|s| !s.is_empty())?;
+
+    let learning = child_elements(element, "LearningData").next();
+    let learning_value = |name| learning.and_then(|node| child_text(node, name));
+    let number = |name| {
+        learning_value(name)
+            .and_then(|s| s.parse::<u32>().ok())
+            .unwrap_or_default()
+    };
+    let interval = number("Interval");
+
+    let mut tags: Vec<_> = titles.iter().filter_map(|t| tag_from_title(t)).collect();
+    if interval > 0 {
+        tags.push("Memorized".into());
+    }
+
+    let mut cards = HashMap::new();
+    let last_rep = learning_value("LastRepetition").and_then(parse_date);
+    if let (true, Some(last_rep)) = (interval >= 1, last_rep) {
+        let afactor = learning_value("AFactor")
+            .and_then(|s| s.replace(',', ".").parse().ok())
+            .unwrap_or(3.0);
+        let lapses = number("Lapses");
+        cards.insert(
+            0,
+            ForeignCard {
+                due_secs: last_rep + i64::from(interval) * 86_400,
+                interval,
+                ease_factor: (afactor_to_ease_factor(afactor) * 1000.0) as u16,
+                reps: number("Repetitions") + lapses,
+                lapses,
+            },
+        );
+    }
+
+    Some(ForeignNote {
+        fields: vec![field_text(question), field_text(answer)],
+        tags: canonical_tags(tags),
+        cards,
+    })
+}
+
+/// Some versions escape the ampersands of entities a second time.
+fn decode_text(text: &str) -> String {
+    text.replace("&amp;", "&")
+}
+
+fn field_text(text: &str) -> String {
+    decode_text(text)
+        .replace("\n\r", "<br>")
+        .replace('\n', "<br>")
+}
+
+/// Midnight local time on a day.month.year date, as a timestamp.
+fn parse_date(text: &str) -> Option<i64> {
+    let date = NaiveDate::parse_from_str(text, "%d.%m.%Y").ok()?;
+    Local
+        .from_local_datetime(&date.and_hms_opt(0, 0, 0)?)
+        .earliest()
+        .map(|dt| dt.timestamp())
+}
+
+/// Map SuperMemo's A-factor (1.2 to 6.9) onto the range of E-factors
+/// (1.3 to 3.3), as described at
+/// <http://www.supermemo.com/beta/xml/xml-core.htm>
+fn afactor_to_ease_factor(afactor: f64) -> f64 {
+    let (af_min, af_max) = (1.2, 6.9);
+    let (ef_min, ef_max) = (1.3, 3.3);
+    let scaled = (afactor.max(af_min).min(af_max) - af_min) / (af_max - af_min);
+    ef_min + scaled * (ef_max - ef_min)
+}
+
+/// Turn a topic title like "Advanced English [97] / phrasal_verbs" into
+/// a tag like "advancedEnglishPhrasalVerbs". Titles made of only numbers
+/// give no tag.
+fn tag_from_title(title: &str) -> Option<String> {
+    let ascii: String = title.nfkd().filter(|c| !is_combining_mark(*c)).collect();
+    let text = NUMBER_IN_BRACKETS
+        .replace_all(&ascii, " ")
+        .replace('_', " ");
+    let text = NON_WORD.replace_all(&text, " ");
+    if ONLY_NUMBERS.is_match(&text) {
+        return None;
+    }
+
+    let words: String = text.split_whitespace().map(capitalize).collect();
+    let mut chars = words.chars();
+    let first = chars.next()?;
+    Some(first.to_lowercase().chain(chars).collect())
+}
+
+fn capitalize(word: &str) -> String {
+    let mut chars = word.chars();
+    match chars.next() {
+        Some(first) => first
+            .to_uppercase()
+            .chain(chars.flat_map(char::to_lowercase))
+            .collect(),
+        None => String::new(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::card::CardType;
+    use crate::err::Result;
+    use crate::import_export::foreign::import_supermemo_xml;
+    use crate::import_export::foreign::supermemo::tag_from_title;
+    use crate::storage::testutil::open_test_collection;
+    use std::fs;
+
+    #[test]
+    fn test_tag_from_title() {
+        assert_eq!(tag_from_title("aoeu").as_deref(), Some("aoeu"));
+        assert_eq!(tag_from_title("1-400"), None);
+        assert_eq!(
+            tag_from_title("Advanced English [97] phrasal_verbs").as_deref(),
+            Some("advancedEnglishPhrasalVerbs")
+        );
+        assert_eq!(tag_from_title("Čeština").as_deref(), Some("cestina"));
+    }
+
+    #[test]
+    fn test_import_supermemo_xml() -> Result<()> {
+        let (dir, mut storage) = open_test_collection()?;
+        let path = dir.path().join("export.xml");
+        fs::write(
+            &path,
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<SuperMemoCollection>
+  <Count>4</Count>
+  <SuperMemoElement>
+    <ID>1</ID>
+    <Title>Verbs</Title>
+    <Type>Topic</Type>
+    <SuperMemoElement>
+      <ID>2</ID>
+      <Title>1-400</Title>
+      <Type>Topic</Type>
+      <SuperMemoElement>
+        <ID>3</ID>
+        <Type>Item</Type>
+        <Content>
+          <Question>to &lt;b&gt;go&lt;/b&gt;&amp;amp;nbsp;</Question>
+          <Answer>gehen
+laufen</Answer>
+        </Content>
+        <LearningData>
+          <Interval>1844</Interval>
+          <Repetitions>7</Repetitions>
+          <Lapses>1</Lapses>
+          <LastRepetition>19.09.2002</LastRepetition>
+          <AFactor>5,701</AFactor>
+        </LearningData>
+      </SuperMemoElement>
+    </SuperMemoElement>
+    <SuperMemoElement>
+      <ID>4</ID>
+      <Type>Item</Type>
+      <Content>
+        <Question>to be</Question>
+        <Answer>sein</Answer>
+      </Content>
+      <LearningData>
+        <Interval>0</Interval>
+      </LearningData>
+    </SuperMemoElement>
+    <SuperMemoElement>
+      <ID>5</ID>
+      <Type>Item</Type>
+      <Content>
+        <Question>no answer</Question>
+        <Answer />
+      </Content>
+    </SuperMemoElement>
+  </SuperMemoElement>
+</SuperMemoCollection>"#,
+        )?;
+
+        let log = import_supermemo_xml(&mut storage, &path, 1)?;
+        assert_eq!(log.notes_added, 2);
+
+        let mut ids = storage.all_note_ids()?;
+        ids.sort_unstable();
+        let note = storage.get_note(ids[0])?.unwrap();
+        assert_eq!(note.fields, vec!["to <b>go</b>&nbsp;", "gehen<br>laufen"]);
+        assert_eq!(note.tags, vec!["Memorized", "verbs"]);
+        let card = storage.get_cards_of_note(note.id)?.remove(0);
+        assert_eq!(card.ctype, CardType::Review);
+        // the A-factor is converted to an ease factor
+        assert_eq!(card.ease_factor, 2879);
+        assert_eq!((card.interval, card.reps, card.lapses), (1844, 8, 1));
+
+        let note = storage.get_note(ids[1])?.unwrap();
+        assert_eq!(note.tags, vec!["verbs"]);
+        let card = storage.get_cards_of_note(note.id)?.remove(0);
+        assert_eq!(card.ctype, CardType::New);
+
+        Ok(())
+    }
+}
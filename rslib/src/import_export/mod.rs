@@ -0,0 +1,50 @@
+// Copyright: Ankitects Pty Ltd and contributors
+// License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+//! Sharing notes and cards with other collections and programs.
+
+pub mod foreign;
+pub mod package;
+pub mod text;
+
+use crate::err::{AnkiError, Result};
+use crate::search::{search_cards, SearchContext, SortMode};
+use crate::storage::SqliteStorage;
+
+/// Which cards are exported.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ExportLimit {
+    Collection,
+    /// A deck and its children.
+    Deck(i64),
+    Search {
+        search: String,
+        context: SearchContext,
+    },
+}
+
+pub(crate) fn card_ids_for_limit(storage: &SqliteStorage, limit: &ExportLimit) -> Result<Vec<i64>> {
+    match limit {
+        ExportLimit::Collection => storage.card_ids_in_decks(None),
+        ExportLimit::Deck(deck_id) => {
+            let deck_ids = deck_and_children(storage, *deck_id)?;
+            storage.card_ids_in_decks(Some(&deck_ids))
+        }
+        ExportLimit::Search { search, context } => {
+            search_cards(storage, search, context, &SortMode::NoOrder)
+        }
+    }
+}
+
+pub(crate) fn deck_and_children(storage: &SqliteStorage, deck_id: i64) -> Result<Vec<i64>> {
+    let decks = storage.get_all_decks()?;
+    let parent = decks
+        .get(&deck_id)
+        .ok_or_else(|| AnkiError::invalid_input(format!("no such deck: {}", deck_id)))?;
+    let prefix = format!("{}::", parent.name);
+    Ok(decks
+        .values()
+        .filter(|deck| deck.id == deck_id || deck.name.starts_with(&prefix))
+        .map(|deck| deck.id)
+        .collect())
+}
@@ -0,0 +1,824 @@
+// Copyright: Ankitects Pty Ltd and contributors
+// License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+//! Writing the notes and cards of a deck or search to a package. The
+//! chosen cards are copied into a new collection along with their notes,
+//! notetypes and decks, and without scheduling the cards are reset to new,
+//! as if the recipient had just added them.
+
+use crate::card::{Card, CardQueue, CardType};
+use crate::cloze::expand_clozes_to_reveal_latex;
+use crate::decks::Deck;
+use crate::err::{AnkiError, Result};
+use crate::import_export::package::{LEGACY_COLLECTION_NAME, MEDIA_MAP_NAME, V2_COLLECTION_NAME};
+use crate::import_export::{card_ids_for_limit, deck_and_children, ExportLimit};
+use crate::media::files::{sha1_of_file, split_extension};
+use crate::notes::{field_checksum, Note};
+use crate::notetypes::{NoteType, NoteTypeKind};
+use crate::sched::leech::LEECH_TAG;
+use crate::storage::{now_millis, SqliteStorage};
+use crate::text::{extract_media_refs, normalize_to_nfc, sort_field_text};
+use regex::Regex;
+use serde_json::{json, Value};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::Path;
+use tempfile::tempdir;
+use zip::write::FileOptions;
+use zip::{CompressionMethod, ZipWriter};
+
+/// The tag the browser adds to marked notes.
+const MARKED_TAG: &str = "marked";
+/// The ease new cards start with, in permille.
+const STARTING_EASE: u16 = 2500;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PackageExportOptions {
+    pub limit: ExportLimit,
+    /// Keep the cards' scheduling and review history, and the decks'
+    /// options. If not set, cards are reset to new, and decks use the
+    /// default options.
+    pub include_scheduling: bool,
+    pub include_media: bool,
+    /// Changes made to the exported notes before a deck is published.
+    pub sharing: Option<SharingOptions>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SharingOptions {
+    /// Give the media files names made from a checksum of their contents,
+    /// and update the notes to match, so the recipient's files aren't
+    /// clobbered by ones with the same name. Images generated from LaTeX
+    /// keep their names. Only applies if media is included.
+    pub rename_media: bool,
+    /// Remove the marked and leech tags, even if scheduling is included.
+    pub strip_system_tags: bool,
+    /// A regular expression; fields with a matching name are emptied, so
+    /// personal notes aren't published. Their media is not exported.
+    pub clear_fields_matching: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExportProgress {
+    /// Copying the notes and cards.
+    Collection,
+    /// The number of media files added so far.
+    Media(usize),
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct PackageExportOutput {
+    pub notes: u32,
+    pub cards: u32,
+    pub media_files: u32,
+}
+
+/// Write the cards `opts` selects to a package at `out_path`, replacing any
+/// file there. `progress_cb` is called before the collection is copied and
+/// after each media file is added, and can return false to stop, which
+/// returns AnkiError::Interrupted. The package is written to a temporary
+/// file first, so an existing file is left intact if the export fails.
+pub fn export_package<F>(
+    storage: &SqliteStorage,
+    media_folder: &Path,
+    out_path: &Path,
+    opts: &PackageExportOptions,
+    mut progress_cb: F,
+) -> Result<PackageExportOutput>
+where
+    F: FnMut(ExportProgress) -> bool,
+{
+    if !progress_cb(ExportProgress::Collection) {
+        return Err(AnkiError::Interrupted);
+    }
+    let card_ids = card_ids_for_limit(storage, &opts.limit)?;
+    let v2 =
+        opts.include_scheduling && storage.get_config_value::<u8>("schedVer")?.unwrap_or(1) == 2;
+
+    let dir = tempdir()?;
+    let col_path = dir.path().join(LEGACY_COLLECTION_NAME);
+    let mut exported = ExportedCollection::default();
+    {
+        let dst = SqliteStorage::open_or_create(&col_path)?;
+        dst.begin()?;
+        exported.copy(storage, &dst, media_folder, &card_ids, opts)?;
+        dst.commit()?;
+        dst.downgrade_to(11)?;
+    }
+    let dummy_path = if v2 {
+        let path = dir.path().join("dummy.anki2");
+        SqliteStorage::open_or_create(&path)?.downgrade_to(11)?;
+        Some(path)
+    } else {
+        None
+    };
+
+    let media_files = if opts.include_media {
+        exported.media_files(media_folder)?
+    } else {
+        vec![]
+    };
+
+    let tmp_path = out_path.with_extension("tmp");
+    let result = write_package(
+        &tmp_path,
+        &col_path,
+        dummy_path.as_deref(),
+        media_folder,
+        &media_files,
+        &mut progress_cb,
+    );
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp_path);
+    }
+    result?;
+    fs::rename(&tmp_path, out_path)?;
+
+    Ok(PackageExportOutput {
+        notes: exported.notes,
+        cards: exported.cards,
+        media_files: media_files.len() as u32,
+    })
+}
+
+/// Put a card at the end of the new queue, like the legacy exporter. New
+/// cards keep their position; other cards are given `position`.
+fn reset_card(card: &mut Card, position: Option<i64>) {
+    if card.original_deck_id != 0 {
+        card.deck_id = card.original_deck_id;
+    }
+    card.original_deck_id = 0;
+    card.original_due = 0;
+    card.reps = 0;
+    card.lapses = 0;
+    card.left = 0;
+    card.flags = 0;
+    card.queue = CardQueue::New;
+    if let Some(position) = position {
+        card.ctype = CardType::New;
+        card.due = position;
+        card.interval = 0;
+        card.ease_factor = STARTING_EASE;
+    }
+}
+
+fn is_new(card: &Card) -> bool {
+    card.ctype == CardType::New && card.queue == CardQueue::New
+}
+
+fn remove_system_tags(note: &mut Note) {
+    note.tags.retain(|tag| {
+        !tag.eq_ignore_ascii_case(MARKED_TAG) && !tag.eq_ignore_ascii_case(LEECH_TAG)
+    });
+}
+
+/// Empty the fields whose names match.
+fn clear_matching_fields(note: &mut Note, notetype: &NoteType, regex: &Regex) {
+    for field in &notetype.fields {
+        if regex.is_match(&field.name) {
+            if let Some(text) = note.fields.get_mut(field.ord as usize) {
+                text.clear();
+            }
+        }
+    }
+}
+
+fn update_field_cache(note: &mut Note, notetype: &NoteType) {
+    let sort_field = note
+        .fields
+        .get(notetype.sort_field_idx as usize)
+        .map(String::as_str)
+        .unwrap_or_default();
+    note.sort_field = sort_field_text(sort_field).into_owned();
+    note.checksum = field_checksum(note.fields.first().map(String::as_str).unwrap_or_default());
+}
+
+/// A name made of the SHA1 of the file, keeping the extension if it's
+/// safe to put in HTML.
+fn checksum_name(path: &Path, fname: &str) -> Result<String> {
+    let (_, ext) = split_extension(fname);
+    let ext = if ext.chars().skip(1).all(char::is_alphanumeric) {
+        ext
+    } else {
+        ""
+    };
+    Ok(format!("{}{}", hex::encode(sha1_of_file(path)?), ext))
+}
+
+/// What has been copied into the package's collection.
+#[derive(Default)]
+struct ExportedCollection {
+    notes: u32,
+    cards: u32,
+    notetypes: HashMap<i64, NoteType>,
+    /// The media the notes refer to, in NFC form.
+    media: HashSet<String>,
+    /// The names media files are given in the package, if they are
+    /// renamed for sharing.
+    renamed_media: HashMap<String, String>,
+}
+
+impl ExportedCollection {
+    fn copy(
+        &mut self,
+        src: &SqliteStorage,
+        dst: &SqliteStorage,
+        media_folder: &Path,
+        card_ids: &[i64],
+        opts: &PackageExportOptions,
+    ) -> Result<()> {
+        let sharing = opts.sharing.clone().unwrap_or_default();
+        let clear_fields =
+            match &sharing.clear_fields_matching {
+                Some(pattern) => Some(Regex::new(pattern).map_err(|e| {
+                    AnkiError::invalid_input(format!("invalid field pattern: {}", e))
+                })?),
+                None => None,
+            };
+
+        let mut cards = vec![];
+        for &card_id in card_ids {
+            if let Some(card) = src.get_card(card_id)? {
+                cards.push(card);
+            }
+        }
+
+        // positions follow those of the exported new cards
+        let mut next_position = cards
+            .iter()
+            .filter(|card| card.ctype == CardType::New)
+            .map(|card| card.due)
+            .max()
+            .unwrap_or(0)
+            + 1;
+        let mut note_positions = HashMap::new();
+        let mut note_ids = BTreeSet::new();
+        let mut deck_ids = HashSet::new();
+        deck_ids.insert(1);
+        for card in &mut cards {
+            if !opts.include_scheduling {
+                let position = if is_new(card) {
+                    None
+                } else {
+                    Some(*note_positions.entry(card.note_id).or_insert_with(|| {
+                        next_position += 1;
+                        next_position - 1
+                    }))
+                };
+                reset_card(card, position);
+            }
+            note_ids.insert(card.note_id);
+            deck_ids.insert(card.deck_id);
+            if card.original_deck_id != 0 {
+                deck_ids.insert(card.original_deck_id);
+            }
+            dst.add_or_update_card(card)?;
+            if opts.include_scheduling {
+                for entry in src.get_revlog_entries(card.id)? {
+                    dst.add_or_update_revlog_entry(&entry)?;
+                }
+            }
+        }
+        self.cards = cards.len() as u32;
+
+        for note_id in note_ids {
+            let mut note = match src.get_note(note_id)? {
+                Some(note) => note,
+                None => continue,
+            };
+            if !opts.include_scheduling || sharing.strip_system_tags {
+                remove_system_tags(&mut note);
+            }
+            if !self.notetypes.contains_key(&note.notetype_id) {
+                if let Some(notetype) = src.get_notetype(note.notetype_id)? {
+                    dst.add_or_update_notetype(&notetype)?;
+                    self.notetypes.insert(notetype.id, notetype);
+                }
+            }
+            if let (Some(regex), Some(notetype)) =
+                (&clear_fields, self.notetypes.get(&note.notetype_id))
+            {
+                clear_matching_fields(&mut note, notetype, regex);
+            }
+            if opts.include_media {
+                self.add_media_refs(&note);
+                if sharing.rename_media {
+                    self.rename_media_refs(&mut note, media_folder)?;
+                }
+            }
+            if let (Some(_), Some(notetype)) =
+                (&opts.sharing, self.notetypes.get(&note.notetype_id))
+            {
+                update_field_cache(&mut note, notetype);
+            }
+            dst.add_or_update_note(&note)?;
+            self.notes += 1;
+        }
+
+        if let ExportLimit::Deck(deck_id) = opts.limit {
+            // empty children are exported too
+            deck_ids.extend(deck_and_children(src, deck_id)?);
+        }
+        copy_decks(src, dst, &deck_ids, opts)?;
+
+        let sched_ver = if opts.include_scheduling {
+            src.get_config_value::<u8>("schedVer")?.unwrap_or(1)
+        } else {
+            1
+        };
+        let config = default_config(
+            self.notetypes.keys().min().copied(),
+            next_position,
+            sched_ver,
+        );
+        if let Value::Object(config) = config {
+            dst.set_all_config(&config)?;
+        }
+        dst.set_creation_stamp(src.creation_stamp()?)?;
+        dst.mark_modified(now_millis())?;
+
+        Ok(())
+    }
+
+    fn add_media_refs(&mut self, note: &Note) {
+        let notetype = match self.notetypes.get(&note.notetype_id) {
+            Some(notetype) => notetype,
+            None => return,
+        };
+        let svg = notetype
+            .other
+            .get("latexsvg")
+            .and_then(Value::as_bool)
+            .unwrap_or_default();
+        for field in &note.fields {
+            let text = if notetype.kind() == NoteTypeKind::Cloze && field.contains("{{c") {
+                Cow::Owned(expand_clozes_to_reveal_latex(field))
+            } else {
+                Cow::Borrowed(field.as_str())
+            };
+            for media_ref in extract_media_refs(&text, svg) {
+                let fname = if media_ref.is_latex {
+                    media_ref.fname.into_owned()
+                } else {
+                    normalize_to_nfc(&media_ref.fname).into_owned()
+                };
+                self.media.insert(fname);
+            }
+        }
+    }
+
+    /// Refer to the files the note's fields use by their checksum names,
+    /// recording the names for media_files(). References to missing files
+    /// are left alone.
+    fn rename_media_refs(&mut self, note: &mut Note, media_folder: &Path) -> Result<()> {
+        for field in &mut note.fields {
+            let refs: Vec<_> = extract_media_refs(field, false)
+                .into_iter()
+                .filter(|r| !r.is_latex)
+                .collect();
+            if refs.is_empty() {
+                continue;
+            }
+            let mut output = String::with_capacity(field.len());
+            let mut last_end = 0;
+            for media_ref in refs {
+                let fname = normalize_to_nfc(&media_ref.fname).into_owned();
+                let new_name = match self.renamed_media.get(&fname) {
+                    Some(new_name) => new_name.clone(),
+                    None => {
+                        let path = media_folder.join(&fname);
+                        if fname.contains(&['/', '\\'][..]) || !path.is_file() {
+                            continue;
+                        }
+                        let new_name = checksum_name(&path, &fname)?;
+                        self.renamed_media.insert(fname, new_name.clone());
+                        new_name
+                    }
+                };
+                output.push_str(&field[last_end..media_ref.span.start]);
+                output.push_str(&new_name);
+                last_end = media_ref.span.end;
+            }
+            output.push_str(&field[last_end..]);
+            *field = output;
+        }
+
+        Ok(())
+    }
+
+    /// The files in the media folder that the notes refer to, and those
+    /// starting with an underscore that the notetypes refer to, such as
+    /// fonts, as (name in folder, name in package) pairs. Files in
+    /// subfolders are skipped, as Anki doesn't support them. Renamed files
+    /// with the same contents are only included once.
+    fn media_files(&self, media_folder: &Path) -> Result<Vec<(String, String)>> {
+        // keyed on the name in the package
+        let mut files: BTreeMap<String, String> = self
+            .media
+            .iter()
+            .filter(|fname| !fname.contains(&['/', '\\'][..]))
+            .filter(|fname| media_folder.join(fname).is_file())
+            .map(|fname| {
+                let package_name = self.renamed_media.get(fname).unwrap_or(fname);
+                (package_name.clone(), fname.clone())
+            })
+            .collect();
+
+        if media_folder.is_dir() {
+            for entry in fs::read_dir(media_folder)? {
+                let entry = entry?;
+                let fname = match entry.file_name().into_string() {
+                    Ok(fname) => fname,
+                    Err(_) => continue,
+                };
+                if fname.starts_with('_')
+                    && entry.file_type()?.is_file()
+                    && self
+                        .notetypes
+                        .values()
+                        .any(|notetype| notetype_refers_to(notetype, &fname))
+                {
+                    let fname = normalize_to_nfc(&fname).into_owned();
+                    files.insert(fname.clone(), fname);
+                }
+            }
+        }
+
+        Ok(files
+            .into_iter()
+            .map(|(package_name, fname)| (fname, package_name))
+            .collect())
+    }
+}
+
+fn notetype_refers_to(notetype: &NoteType, fname: &str) -> bool {
+    let css = notetype
+        .other
+        .get("css")
+        .and_then(Value::as_str)
+        .unwrap_or_default();
+    css.contains(fname)
+        || notetype
+            .templates
+            .iter()
+            .any(|t| t.question_format.contains(fname) || t.answer_format.contains(fname))
+}
+
+/// Copy the decks, and the options they use if scheduling is included.
+/// Otherwise filtered decks are left out, as their cards have been returned
+/// home, and normal decks use the default options.
+fn copy_decks(
+    src: &SqliteStorage,
+    dst: &SqliteStorage,
+    deck_ids: &HashSet<i64>,
+    opts: &PackageExportOptions,
+) -> Result<()> {
+    let all_decks = src.get_all_decks()?;
+    let mut names: HashSet<&str> = all_decks
+        .values()
+        .filter(|deck| deck_ids.contains(&deck.id))
+        .map(|deck| deck.name.as_str())
+        .collect();
+    // parents must exist for their children to be shown
+    for name in names.clone() {
+        let mut parent = name;
+        while let Some(idx) = parent.rfind("::") {
+            parent = &parent[..idx];
+            names.insert(parent);
+        }
+    }
+
+    let mut conf_ids = BTreeSet::new();
+    conf_ids.insert(1);
+    for deck in all_decks.values() {
+        if !names.contains(deck.name.as_str()) {
+            continue;
+        }
+        if deck.is_filtered() && !opts.include_scheduling {
+            continue;
+        }
+        let mut deck: Deck = deck.clone();
+        if !deck.is_filtered() {
+            if opts.include_scheduling {
+                conf_ids.extend(deck.config_id());
+            } else {
+                deck.other.insert("conf".into(), 1.into());
+            }
+        }
+        dst.add_or_update_deck(&deck)?;
+    }
+
+    for conf_id in conf_ids {
+        if let Some(conf) = src.get_deck_conf(conf_id)? {
+            dst.add_or_update_deck_conf(&conf)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// The config of a new collection in the legacy code.
+fn default_config(current_notetype: Option<i64>, next_position: i64, sched_ver: u8) -> Value {
+    json!({
+        "activeDecks": [1],
+        "curDeck": 1,
+        "newSpread": 0,
+        "collapseTime": 1200,
+        "timeLim": 0,
+        "estTimes": true,
+        "dueCounts": true,
+        "curModel": current_notetype,
+        "nextPos": next_position,
+        "sortType": "noteFld",
+        "sortBackwards": false,
+        "addToCur": true,
+        "dayLearnFirst": false,
+        "schedVer": sched_ver,
+        "normalize_note_text": true,
+    })
+}
+
+/// Zip up the collection and media. Stored media is not compressed again,
+/// except for SVG images, which are text.
+fn write_package<F>(
+    path: &Path,
+    col_path: &Path,
+    dummy_path: Option<&Path>,
+    media_folder: &Path,
+    media_files: &[(String, String)],
+    progress_cb: &mut F,
+) -> Result<()>
+where
+    F: FnMut(ExportProgress) -> bool,
+{
+    let mut zip = ZipWriter::new(File::create(path)?);
+    let deflated = FileOptions::default().compression_method(CompressionMethod::Deflated);
+    let stored = FileOptions::default().compression_method(CompressionMethod::Stored);
+
+    if let Some(dummy_path) = dummy_path {
+        zip.start_file(LEGACY_COLLECTION_NAME, deflated)?;
+        io::copy(&mut File::open(dummy_path)?, &mut zip)?;
+        zip.start_file(V2_COLLECTION_NAME, deflated)?;
+    } else {
+        zip.start_file(LEGACY_COLLECTION_NAME, deflated)?;
+    }
+    io::copy(&mut File::open(col_path)?, &mut zip)?;
+
+    let mut media_map = HashMap::new();
+    for (idx, (fname, package_name)) in media_files.iter().enumerate() {
+        let zip_name = idx.to_string();
+        let options = if fname.to_ascii_lowercase().ends_with(".svg") {
+            deflated
+        } else {
+            stored
+        };
+        zip.start_file(zip_name.as_str(), options)?;
+        io::copy(&mut File::open(media_folder.join(fname))?, &mut zip)?;
+        media_map.insert(zip_name, package_name);
+        if !progress_cb(ExportProgress::Media(idx + 1)) {
+            return Err(AnkiError::Interrupted);
+        }
+    }
+    zip.start_file(MEDIA_MAP_NAME, deflated)?;
+    zip.write_all(&serde_json::to_vec(&media_map)?)?;
+    zip.finish()?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::card::{Card, CardQueue, CardType};
+    use crate::err::{AnkiError, Result};
+    use crate::import_export::package::{
+        export_package, ExportProgress, PackageExportOptions, SharingOptions,
+    };
+    use crate::import_export::ExportLimit;
+    use crate::media::files::sha1_of_data;
+    use crate::notes::Note;
+    use crate::revlog::RevlogEntry;
+    use crate::storage::testutil::{add_deck, deck, notetype, open_test_collection};
+    use crate::storage::SqliteStorage;
+    use std::collections::HashMap;
+    use std::fs;
+    use std::io::Read;
+    use std::path::Path;
+    use tempfile::TempDir;
+
+    fn collection() -> Result<(TempDir, SqliteStorage)> {
+        let (dir, storage) = open_test_collection()?;
+        let mut notetype = notetype(1, "Basic", &["Front", "My Notes"], &["{{Front}}"]);
+        notetype
+            .other
+            .insert("css".into(), "@font-face { src: url(_font.ttf); }".into());
+        storage.add_or_update_notetype(&notetype)?;
+        add_deck(&storage, 1, "Default")?;
+        let mut spanish = deck(2, "Spanish");
+        spanish.other.insert("conf".into(), 2.into());
+        storage.add_or_update_deck(&spanish)?;
+        add_deck(&storage, 3, "Spanish::Verbs")?;
+        storage.db.execute_batch(
+            r#"update col set dconf = '{"1": {"id": 1, "name": "Default", "mod": 0, "usn": 0},
+"2": {"id": 2, "name": "Spanish", "mod": 0, "usn": 0}}'"#,
+        )?;
+
+        for (id, deck_id, field) in &[
+            (1, 1, "one"),
+            (2, 3, r#"dos <img src="dos.jpg">"#),
+            (3, 2, "[sound:tres.mp3] [sound:missing.mp3]"),
+        ] {
+            let note = Note {
+                id: *id,
+                guid: id.to_string(),
+                notetype_id: 1,
+                tags: vec!["marked".into(), "verb".into()],
+                fields: vec![field.to_string(), format!("note {}", id)],
+                ..Default::default()
+            };
+            storage.add_or_update_note(&note)?;
+            let mut card = Card {
+                id: *id,
+                note_id: *id,
+                deck_id: *deck_id,
+                ..Default::default()
+            };
+            if *id == 3 {
+                card.ctype = CardType::Review;
+                card.queue = CardQueue::Review;
+                card.due = 100;
+                card.interval = 10;
+                card.reps = 5;
+                card.flags = 1;
+                storage.add_or_update_revlog_entry(&RevlogEntry {
+                    id: 1,
+                    card_id: 3,
+                    ..Default::default()
+                })?;
+            } else {
+                card.due = *id;
+            }
+            storage.add_or_update_card(&card)?;
+        }
+
+        Ok((dir, storage))
+    }
+
+    fn read_package(path: &Path) -> Result<(SqliteStorage, HashMap<String, Vec<u8>>)> {
+        let mut zip = zip::ZipArchive::new(fs::File::open(path)?)?;
+        let media_map: HashMap<String, String> = serde_json::from_reader(zip.by_name("media")?)?;
+        let mut media = HashMap::new();
+        for (zip_name, fname) in media_map {
+            let mut data = vec![];
+            zip.by_name(&zip_name)?.read_to_end(&mut data)?;
+            media.insert(fname, data);
+        }
+
+        let col_path = path.with_extension("anki2");
+        let mut data = vec![];
+        zip.by_name("collection.anki2")?.read_to_end(&mut data)?;
+        fs::write(&col_path, data)?;
+
+        Ok((SqliteStorage::open_or_create(&col_path)?, media))
+    }
+
+    #[test]
+    fn test_export_deck() -> Result<()> {
+        let (dir, storage) = collection()?;
+        let media_folder = dir.path().join("media");
+        fs::create_dir(&media_folder)?;
+        fs::write(media_folder.join("dos.jpg"), "dos")?;
+        fs::write(media_folder.join("tres.mp3"), "tres")?;
+        fs::write(media_folder.join("_font.ttf"), "font")?;
+        fs::write(media_folder.join("_unused.ttf"), "unused")?;
+
+        let out_path = dir.path().join("Spanish.apkg");
+        let mut opts = PackageExportOptions {
+            limit: ExportLimit::Deck(2),
+            include_scheduling: false,
+            include_media: true,
+            sharing: None,
+        };
+        let mut progress = vec![];
+        let out = export_package(&storage, &media_folder, &out_path, &opts, |p| {
+            progress.push(p);
+            true
+        })?;
+        assert_eq!((out.notes, out.cards, out.media_files), (2, 2, 3));
+        assert_eq!(progress.last(), Some(&ExportProgress::Media(3)));
+
+        let (exported, media) = read_package(&out_path)?;
+        let mut fnames: Vec<_> = media.keys().cloned().collect();
+        fnames.sort();
+        assert_eq!(fnames, vec!["_font.ttf", "dos.jpg", "tres.mp3"]);
+        assert_eq!(media["tres.mp3"], b"tres");
+
+        // the review card was reset, and placed after the new one
+        let card = exported.get_card(3)?.unwrap();
+        assert_eq!(card.ctype, CardType::New);
+        assert_eq!((card.due, card.reps, card.flags), (3, 0, 0));
+        assert!(exported.get_revlog_entries(3)?.is_empty());
+        assert!(exported.get_card(1)?.is_none());
+        assert_eq!(exported.get_note(3)?.unwrap().tags, vec!["verb"]);
+        let decks = exported.get_all_decks()?;
+        let mut names: Vec<_> = decks.values().map(|d| d.name.as_str()).collect();
+        names.sort();
+        assert_eq!(names, vec!["Default", "Spanish", "Spanish::Verbs"]);
+        assert_eq!(decks[&2].config_id(), Some(1));
+        assert!(exported.get_deck_conf(2)?.is_none());
+
+        // with scheduling
+        drop(exported);
+        opts.include_scheduling = true;
+        opts.include_media = false;
+        let out = export_package(&storage, &media_folder, &out_path, &opts, |_| true)?;
+        assert_eq!(out.media_files, 0);
+        let (exported, media) = read_package(&out_path)?;
+        assert!(media.is_empty());
+        let card = exported.get_card(3)?.unwrap();
+        assert_eq!(
+            (card.ctype, card.due, card.reps),
+            (CardType::Review, 100, 5)
+        );
+        assert_eq!(exported.get_revlog_entries(3)?.len(), 1);
+        assert_eq!(exported.get_note(3)?.unwrap().tags, vec!["marked", "verb"]);
+        assert_eq!(exported.get_all_decks()?[&2].config_id(), Some(2));
+        assert!(exported.get_deck_conf(2)?.is_some());
+
+        // aborting leaves the previous package alone
+        drop(exported);
+        opts.include_media = true;
+        let result = export_package(&storage, &media_folder, &out_path, &opts, |p| {
+            p == ExportProgress::Collection
+        });
+        match result.err() {
+            Some(AnkiError::Interrupted) => (),
+            other => panic!("unexpected: {:?}", other),
+        }
+        assert!(read_package(&out_path)?.1.is_empty());
+        assert!(!out_path.with_extension("tmp").exists());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_export_for_sharing() -> Result<()> {
+        let (dir, storage) = collection()?;
+        let media_folder = dir.path().join("media");
+        fs::create_dir(&media_folder)?;
+        fs::write(media_folder.join("dos.jpg"), "dos")?;
+        fs::write(media_folder.join("tres.mp3"), "tres")?;
+        fs::write(media_folder.join("_font.ttf"), "font")?;
+
+        let out_path = dir.path().join("Spanish.apkg");
+        let mut opts = PackageExportOptions {
+            limit: ExportLimit::Deck(2),
+            include_scheduling: true,
+            include_media: true,
+            sharing: Some(SharingOptions {
+                rename_media: true,
+                strip_system_tags: true,
+                clear_fields_matching: Some("(?i)notes".into()),
+            }),
+        };
+        let out = export_package(&storage, &media_folder, &out_path, &opts, |_| true)?;
+        assert_eq!(out.media_files, 3);
+
+        let dos = format!("{}.jpg", hex::encode(sha1_of_data(b"dos")));
+        let tres = format!("{}.mp3", hex::encode(sha1_of_data(b"tres")));
+        let (exported, media) = read_package(&out_path)?;
+        assert_eq!(media[&dos], b"dos");
+        assert_eq!(media[&tres], b"tres");
+        assert!(media.contains_key("_font.ttf"));
+
+        let note = exported.get_note(2)?.unwrap();
+        assert_eq!(
+            note.fields,
+            vec![format!(r#"dos <img src="{}">"#, dos), "".to_string()]
+        );
+        assert_eq!(note.sort_field, format!("dos  {} ", dos));
+        assert_eq!(note.tags, vec!["verb"]);
+        // missing files keep their names
+        let note = exported.get_note(3)?.unwrap();
+        assert_eq!(
+            note.fields[0],
+            format!("[sound:{}] [sound:missing.mp3]", tres)
+        );
+        // scheduling is kept
+        assert_eq!(exported.get_card(3)?.unwrap().ctype, CardType::Review);
+
+        drop(exported);
+        opts.sharing = Some(SharingOptions {
+            clear_fields_matching: Some("(".into()),
+            ..Default::default()
+        });
+        let result = export_package(&storage, &media_folder, &out_path, &opts, |_| true);
+        match result.err() {
+            Some(AnkiError::InvalidInput { .. }) => (),
+            other => panic!("unexpected: {:?}", other),
+        }
+
+        Ok(())
+    }
+}
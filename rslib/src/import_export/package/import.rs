@@ -0,0 +1,1018 @@
+// Copyright: Ankitects Pty Ltd and contributors
+// License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+//! Adding the notes and cards in a package to the collection, or replacing
+//! the collection with the one in a .colpkg.
+//!
+//! Notes are matched with existing ones by their guid. Notetypes are matched
+//! by id, and if a notetype with the same id has different fields or
+//! templates, the following ids are tried until a matching or free one is
+//! found. Decks are matched by name. Media files that clash with a different
+//! local file of the same name are added under a name ending in the
+//! notetype's id, and the notes' references are changed to match.
+
+use crate::backup::{install_collection, ZSTD_COLLECTION_NAME};
+use crate::card::{Card, CardQueue, CardType};
+use crate::decks::Deck;
+use crate::err::{AnkiError, Result};
+use crate::import_export::package::{LEGACY_COLLECTION_NAME, MEDIA_MAP_NAME, V2_COLLECTION_NAME};
+use crate::media::files::{check_media_filename, sha1_of_data};
+use crate::notes::{field_checksum, new_guid, Note};
+use crate::notetypes::NoteType;
+use crate::storage::{now_millis, SqliteStorage};
+use crate::tags::register_tags;
+use crate::text::{extract_media_refs, normalize_to_nfc, sort_field_text};
+use serde_json::Value;
+use std::borrow::Cow;
+use std::collections::{hash_map::Entry, HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use tempfile::{tempdir, TempDir};
+use zip::ZipArchive;
+
+/// What happens to a note that's already in the collection.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DuplicateMode {
+    /// Replace the existing note if the imported one was modified more
+    /// recently, as long as they use the same notetype.
+    UpdateIfNewer,
+    /// Keep the existing note.
+    Skip,
+    /// Add the imported note as a new note.
+    Duplicate,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PackageImportOptions {
+    pub duplicate_mode: DuplicateMode,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ImportProgress {
+    /// Adding the notes and cards.
+    Collection,
+    /// The number of media files added so far.
+    Media(usize),
+}
+
+/// A note mentioned in the import log.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LoggedNote {
+    /// The id in the collection, or in the package if it wasn't imported.
+    pub id: i64,
+    pub fields: Vec<String>,
+}
+
+impl From<&Note> for LoggedNote {
+    fn from(note: &Note) -> Self {
+        LoggedNote {
+            id: note.id,
+            fields: note.fields.clone(),
+        }
+    }
+}
+
+/// What an import did with each note in the package.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ImportLog {
+    pub found_notes: u32,
+    pub added: Vec<LoggedNote>,
+    /// Existing notes replaced by a newer version.
+    pub updated: Vec<LoggedNote>,
+    /// Notes already in the collection that were left alone.
+    pub duplicate: Vec<LoggedNote>,
+    /// Notes that weren't imported, as the existing note uses a different
+    /// notetype.
+    pub conflicting: Vec<LoggedNote>,
+    /// Files added to the media folder.
+    pub media_files: u32,
+}
+
+/// A package's collection, extracted to a temporary folder, and its media.
+struct Package {
+    zip: ZipArchive<File>,
+    /// Maps filenames, in NFC form, to their names in the zip.
+    media: HashMap<String, String>,
+    col_path: PathBuf,
+    /// True if the collection uses the v2 scheduler's card states.
+    v2: bool,
+    _dir: TempDir,
+}
+
+impl Package {
+    /// Open a package, extracting the newest collection it contains.
+    fn open(path: &Path) -> Result<Self> {
+        let mut zip = ZipArchive::new(File::open(path)?)?;
+        let dir = tempdir()?;
+        let col_path = dir.path().join(LEGACY_COLLECTION_NAME);
+        let mut out = File::create(&col_path)?;
+        let has_file = |zip: &mut ZipArchive<File>, name| zip.by_name(name).is_ok();
+        let v2 = if has_file(&mut zip, ZSTD_COLLECTION_NAME) {
+            zstd::stream::copy_decode(zip.by_name(ZSTD_COLLECTION_NAME)?, &mut out)?;
+            true
+        } else if has_file(&mut zip, V2_COLLECTION_NAME) {
+            io::copy(&mut zip.by_name(V2_COLLECTION_NAME)?, &mut out)?;
+            true
+        } else if has_file(&mut zip, LEGACY_COLLECTION_NAME) {
+            io::copy(&mut zip.by_name(LEGACY_COLLECTION_NAME)?, &mut out)?;
+            false
+        } else {
+            return Err(AnkiError::invalid_input(
+                "package does not contain a collection",
+            ));
+        };
+
+        let media = match zip.by_name(MEDIA_MAP_NAME) {
+            Ok(file) => media_map(serde_json::from_reader(file)?)?,
+            Err(_) => HashMap::new(),
+        };
+
+        Ok(Package {
+            zip,
+            media,
+            col_path,
+            v2,
+            _dir: dir,
+        })
+    }
+
+    /// The contents of a media file, if the package contains it.
+    fn media_data(&mut self, fname: &str) -> Result<Option<Vec<u8>>> {
+        let zip_name = match self.media.get(fname) {
+            Some(zip_name) => zip_name,
+            None => return Ok(None),
+        };
+        let mut data = vec![];
+        self.zip.by_name(zip_name)?.read_to_end(&mut data)?;
+        Ok(Some(data))
+    }
+}
+
+/// Invert the package's map of zip names to filenames. Names are converted
+/// to NFC, as packages made on macOS may use NFD, and must otherwise be
+/// valid media filenames, so they can't be written outside the media folder.
+fn media_map(map: HashMap<String, String>) -> Result<HashMap<String, String>> {
+    map.into_iter()
+        .map(|(zip_name, fname)| {
+            let fname = normalize_to_nfc(&fname).into_owned();
+            check_media_filename(&fname)?;
+            Ok((fname, zip_name))
+        })
+        .collect()
+}
+
+/// Add the contents of a package to the collection, and its media to the
+/// media folder. `progress_cb` is called before the notes are added and
+/// after each media file is written, and can return false to stop, which
+/// returns AnkiError::Interrupted. The collection is left unchanged if the
+/// import fails, but media already written is kept.
+pub fn import_package<F>(
+    storage: &mut SqliteStorage,
+    media_folder: &Path,
+    package_path: &Path,
+    opts: &PackageImportOptions,
+    progress_cb: F,
+) -> Result<ImportLog>
+where
+    F: FnMut(ImportProgress) -> bool,
+{
+    let mut package = Package::open(package_path)?;
+    // this also upgrades older collections, and rejects ones that are too
+    // new
+    let src = SqliteStorage::open_or_create(&package.col_path)?;
+    storage.transact(|dst| {
+        let mut importer = Importer::new(&src, dst, &mut package, media_folder, progress_cb)?;
+        importer.import(opts.duplicate_mode)?;
+        Ok(importer.log)
+    })
+}
+
+/// Replace the collection with the one in a .colpkg, and add its media files
+/// to the media folder. Files already in the folder with the same contents
+/// are not written again, and no files are removed. The collection must be
+/// closed. `progress_cb` is called like in import_package().
+pub fn import_collection_package<F>(
+    col_path: &Path,
+    media_folder: &Path,
+    package_path: &Path,
+    mut progress_cb: F,
+) -> Result<()>
+where
+    F: FnMut(ImportProgress) -> bool,
+{
+    if !progress_cb(ImportProgress::Collection) {
+        return Err(AnkiError::Interrupted);
+    }
+    let mut package = Package::open(package_path)?;
+    {
+        let storage = SqliteStorage::open_or_create(&package.col_path)?;
+        if !storage.integrity_check_passes()? {
+            return Err(AnkiError::invalid_input(
+                "the collection in the package is corrupt",
+            ));
+        }
+    }
+
+    let mut fnames: Vec<_> = package.media.keys().cloned().collect();
+    fnames.sort();
+    for (idx, fname) in fnames.iter().enumerate() {
+        if let Some(data) = package.media_data(fname)? {
+            let path = media_folder.join(fname);
+            if !file_matches(&path, &data)? {
+                fs::write(path, data)?;
+            }
+        }
+        if !progress_cb(ImportProgress::Media(idx + 1)) {
+            return Err(AnkiError::Interrupted);
+        }
+    }
+
+    // copied, so a failed install doesn't leave the temporary folder empty
+    let tmp_path = col_path.with_extension("tmp");
+    fs::copy(&package.col_path, &tmp_path)?;
+    install_collection(&tmp_path, col_path)
+}
+
+/// True if the file exists and has the provided contents.
+fn file_matches(path: &Path, data: &[u8]) -> Result<bool> {
+    match fs::read(path) {
+        Ok(existing) => Ok(sha1_of_data(&existing) == sha1_of_data(data)),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// The name a clashing media file is added under, eg foo_1234.jpg.
+fn notetype_local_name(fname: &str, notetype_id: i64) -> String {
+    match fname.rfind('.') {
+        Some(idx) if idx > 0 => format!("{}_{}{}", &fname[..idx], notetype_id, &fname[idx..]),
+        _ => format!("{}_{}", fname, notetype_id),
+    }
+}
+
+/// True if the notetypes have the same fields and templates, so notes of one
+/// can be used with the other.
+fn same_schema(a: &NoteType, b: &NoteType) -> bool {
+    a.fields.len() == b.fields.len()
+        && a.templates.len() == b.templates.len()
+        && a.fields
+            .iter()
+            .zip(&b.fields)
+            .all(|(a, b)| a.name == b.name)
+        && a.templates
+            .iter()
+            .zip(&b.templates)
+            .all(|(a, b)| a.name == b.name)
+}
+
+/// The days since the collection was created.
+fn days_elapsed(creation_stamp: i64, now_secs: i64) -> i64 {
+    (now_secs - creation_stamp) / 86_400
+}
+
+struct Importer<'a, F> {
+    src: &'a SqliteStorage,
+    dst: &'a SqliteStorage,
+    package: &'a mut Package,
+    media_folder: &'a Path,
+    progress_cb: F,
+    usn: i32,
+    now_secs: i64,
+    src_decks: HashMap<i64, Deck>,
+    dst_decks: HashMap<i64, Deck>,
+    notetypes: HashMap<i64, NoteType>,
+    /// Maps the package's ids to the collection's.
+    notetype_map: HashMap<i64, i64>,
+    deck_map: HashMap<i64, i64>,
+    /// Only includes notes whose cards should be imported.
+    note_map: HashMap<i64, i64>,
+    /// The new names of files that clashed with local files.
+    media_renames: HashMap<String, Option<String>>,
+    next_position: i64,
+    log: ImportLog,
+}
+
+impl<'a, F> Importer<'a, F>
+where
+    F: FnMut(ImportProgress) -> bool,
+{
+    fn new(
+        src: &'a SqliteStorage,
+        dst: &'a SqliteStorage,
+        package: &'a mut Package,
+        media_folder: &'a Path,
+        progress_cb: F,
+    ) -> Result<Self> {
+        Ok(Importer {
+            src,
+            dst,
+            package,
+            media_folder,
+            progress_cb,
+            usn: dst.usn()?,
+            now_secs: now_millis() / 1000,
+            src_decks: src.get_all_decks()?,
+            dst_decks: dst.get_all_decks()?,
+            notetypes: HashMap::new(),
+            notetype_map: HashMap::new(),
+            deck_map: HashMap::new(),
+            note_map: HashMap::new(),
+            media_renames: HashMap::new(),
+            next_position: dst.get_config_value("nextPos")?.unwrap_or(1),
+            log: ImportLog::default(),
+        })
+    }
+
+    fn import(&mut self, duplicate_mode: DuplicateMode) -> Result<()> {
+        if !(self.progress_cb)(ImportProgress::Collection) {
+            return Err(AnkiError::Interrupted);
+        }
+        self.import_notes(duplicate_mode)?;
+        self.import_cards()?;
+        self.import_static_media()?;
+
+        self.dst.set_config_value("nextPos", &self.next_position)?;
+        self.dst.mark_modified(now_millis())
+    }
+
+    fn write_media(&mut self, fname: &str, data: &[u8]) -> Result<()> {
+        fs::write(self.media_folder.join(fname), data)?;
+        self.log.media_files += 1;
+        if (self.progress_cb)(ImportProgress::Media(self.log.media_files as usize)) {
+            Ok(())
+        } else {
+            Err(AnkiError::Interrupted)
+        }
+    }
+
+    // Notetypes
+    //----------------------------------------
+
+    /// The id of the collection's notetype for a notetype in the package,
+    /// adding it if needed. A notetype with the same schema is updated if the
+    /// package's copy is newer, so changes to templates and styling carry
+    /// over.
+    fn map_notetype(&mut self, src_id: i64) -> Result<Option<i64>> {
+        if let Some(id) = self.notetype_map.get(&src_id) {
+            return Ok(Some(*id));
+        }
+        let mut notetype = match self.src.get_notetype(src_id)? {
+            Some(notetype) => notetype,
+            None => return Ok(None),
+        };
+
+        let mut id = src_id;
+        loop {
+            match self.dst.get_notetype(id)? {
+                Some(existing) if !same_schema(&existing, &notetype) => id += 1,
+                Some(existing) if existing.mtime_secs >= notetype.mtime_secs => {
+                    notetype = existing;
+                    break;
+                }
+                _ => {
+                    notetype.id = id;
+                    notetype.usn = self.usn;
+                    self.dst.add_or_update_notetype(&notetype)?;
+                    break;
+                }
+            }
+        }
+
+        self.notetype_map.insert(src_id, id);
+        self.notetypes.insert(id, notetype);
+        Ok(Some(id))
+    }
+
+    // Notes
+    //----------------------------------------
+
+    fn import_notes(&mut self, duplicate_mode: DuplicateMode) -> Result<()> {
+        let existing_notes = self.dst.note_ids_by_guid()?;
+        let mut tags = HashSet::new();
+
+        for src_id in self.src.all_note_ids()? {
+            let mut note = match self.src.get_note(src_id)? {
+                Some(note) => note,
+                None => continue,
+            };
+            self.log.found_notes += 1;
+            let notetype_id = match self.map_notetype(note.notetype_id)? {
+                Some(id) => id,
+                None => continue,
+            };
+            note.notetype_id = notetype_id;
+
+            let existing = match existing_notes.get(&note.guid) {
+                Some(id) => self.dst.get_note(*id)?,
+                None => None,
+            };
+            let existing = match existing {
+                Some(_) if duplicate_mode == DuplicateMode::Duplicate => {
+                    note.guid = new_guid();
+                    None
+                }
+                other => other,
+            };
+
+            match existing {
+                None => {
+                    while self.dst.get_note(note.id)?.is_some() {
+                        note.id += 999;
+                    }
+                    self.prepare_note(&mut note)?;
+                    self.dst.add_or_update_note(&note)?;
+                    tags.extend(note.tags.iter().cloned());
+                    self.log.added.push((&note).into());
+                }
+                Some(existing) if existing.notetype_id != note.notetype_id => {
+                    self.log.conflicting.push((&note).into());
+                    continue;
+                }
+                Some(existing)
+                    if duplicate_mode == DuplicateMode::UpdateIfNewer
+                        && note.mtime_secs > existing.mtime_secs =>
+                {
+                    note.id = existing.id;
+                    self.prepare_note(&mut note)?;
+                    self.dst.add_or_update_note(&note)?;
+                    tags.extend(note.tags.iter().cloned());
+                    self.log.updated.push((&note).into());
+                }
+                Some(existing) => {
+                    note.id = existing.id;
+                    self.log.duplicate.push((&existing).into());
+                }
+            }
+            self.note_map.insert(src_id, note.id);
+        }
+
+        let tags: Vec<_> = tags.into_iter().collect();
+        register_tags(self.dst, &tags, self.usn)?;
+
+        Ok(())
+    }
+
+    /// Bring in the note's media, and update its caches, which depend on the
+    /// notetype.
+    fn prepare_note(&mut self, note: &mut Note) -> Result<()> {
+        note.usn = self.usn;
+        for idx in 0..note.fields.len() {
+            if let Cow::Owned(field) =
+                self.import_field_media(&note.fields[idx], note.notetype_id)?
+            {
+                note.fields[idx] = field;
+            }
+        }
+
+        let sort_idx = self
+            .notetypes
+            .get(&note.notetype_id)
+            .map(|nt| nt.sort_field_idx as usize)
+            .unwrap_or_default();
+        let sort_field = note.fields.get(sort_idx).map(String::as_str);
+        note.sort_field = sort_field_text(sort_field.unwrap_or_default()).into_owned();
+        note.checksum = field_checksum(note.fields.first().map(String::as_str).unwrap_or_default());
+
+        Ok(())
+    }
+
+    // Media
+    //----------------------------------------
+
+    /// Copy the media the field refers to, returning the field with any
+    /// renamed files referenced by their new name.
+    fn import_field_media<'t>(&mut self, field: &'t str, notetype_id: i64) -> Result<Cow<'t, str>> {
+        let mut output = String::new();
+        let mut last_end = 0;
+        // latex images are handled by import_static_media()
+        for media_ref in extract_media_refs(field, false)
+            .into_iter()
+            .filter(|r| !r.is_latex)
+        {
+            let fname = normalize_to_nfc(&media_ref.fname).into_owned();
+            if let Some(new_name) = self.import_media_file(&fname, notetype_id)? {
+                let original = &field[media_ref.span.clone()];
+                output.push_str(&field[last_end..media_ref.span.start]);
+                output.push_str(&original.replace(media_ref.fname.as_ref(), &new_name));
+                last_end = media_ref.span.end;
+            }
+        }
+
+        if last_end == 0 {
+            Ok(Cow::Borrowed(field))
+        } else {
+            output.push_str(&field[last_end..]);
+            Ok(Cow::Owned(output))
+        }
+    }
+
+    /// Add a media file from the package, unless the same file is already
+    /// in the media folder. If a different file with the same name exists,
+    /// the file is added under a name specific to the notetype, which is
+    /// returned. Files the package doesn't contain are ignored.
+    fn import_media_file(&mut self, fname: &str, notetype_id: i64) -> Result<Option<String>> {
+        if let Some(new_name) = self.media_renames.get(fname) {
+            return Ok(new_name.clone());
+        }
+        let data = match self.package.media_data(fname)? {
+            Some(data) => data,
+            None => return Ok(None),
+        };
+
+        let path = self.media_folder.join(fname);
+        let new_name = if !path.exists() {
+            self.write_media(fname, &data)?;
+            None
+        } else if file_matches(&path, &data)? {
+            None
+        } else {
+            let local_name = notetype_local_name(fname, notetype_id);
+            if !self.media_folder.join(&local_name).exists() {
+                self.write_media(&local_name, &data)?;
+            }
+            Some(local_name)
+        };
+
+        self.media_renames.insert(fname.into(), new_name.clone());
+        Ok(new_name)
+    }
+
+    /// Add the files that notetypes and LaTeX refer to, which start with _
+    /// and latex- respectively, if they aren't already in the media folder.
+    fn import_static_media(&mut self) -> Result<()> {
+        let mut fnames: Vec<_> = self
+            .package
+            .media
+            .keys()
+            .filter(|fname| fname.starts_with('_') || fname.starts_with("latex-"))
+            .cloned()
+            .collect();
+        fnames.sort();
+        for fname in fnames {
+            if !self.media_folder.join(&fname).exists() {
+                if let Some(data) = self.package.media_data(&fname)? {
+                    self.write_media(&fname, &data)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    // Decks
+    //----------------------------------------
+
+    /// The id of the collection's deck for a deck in the package, adding it
+    /// and its parents if needed. Missing decks are mapped to the default
+    /// deck.
+    fn map_deck(&mut self, src_id: i64) -> Result<i64> {
+        if let Some(id) = self.deck_map.get(&src_id) {
+            return Ok(*id);
+        }
+        let id = match self.src_decks.get(&src_id) {
+            Some(deck) => self.import_deck(deck.clone())?,
+            None => 1,
+        };
+        self.deck_map.insert(src_id, id);
+        Ok(id)
+    }
+
+    fn import_deck(&mut self, deck: Deck) -> Result<i64> {
+        if let Some(idx) = deck.name.rfind("::") {
+            let parent_name = &deck.name[..idx];
+            let src_parent = self
+                .src_decks
+                .values()
+                .find(|d| d.name == parent_name)
+                .map(|d| d.id);
+            match src_parent {
+                Some(parent_id) => {
+                    self.map_deck(parent_id)?;
+                }
+                None => {
+                    let mut parent = deck.clone();
+                    parent.name = parent_name.into();
+                    parent.other.insert("desc".into(), "".into());
+                    self.import_deck(parent)?;
+                }
+            }
+        }
+
+        let existing = self
+            .dst_decks
+            .values()
+            .find(|d| d.name.to_lowercase() == deck.name.to_lowercase())
+            .cloned();
+        let mut target = match existing {
+            Some(existing) if !existing.is_filtered() => existing,
+            existing => {
+                let mut target = deck.clone();
+                if existing.is_some() {
+                    // cards can't be added to a filtered deck
+                    target.name = format!("{} {}", deck.name, self.now_secs);
+                }
+                target.id = now_millis();
+                while self.dst_decks.contains_key(&target.id) {
+                    target.id += 1;
+                }
+                target
+            }
+        };
+
+        if let Some(conf_id) = deck.config_id().filter(|id| *id != 1) {
+            if self.dst.get_deck_conf(conf_id)?.is_none() {
+                if let Some(mut conf) = self.src.get_deck_conf(conf_id)? {
+                    conf.usn = self.usn;
+                    self.dst.add_or_update_deck_conf(&conf)?;
+                }
+            }
+            if self.dst.get_deck_conf(conf_id)?.is_some() {
+                target.other.insert("conf".into(), conf_id.into());
+            }
+        }
+        let desc = deck.other.get("desc").cloned().unwrap_or(Value::Null);
+        if !desc.is_null() {
+            target.other.insert("desc".into(), desc);
+        }
+        target.usn = self.usn;
+        target.mtime_secs = self.now_secs;
+        self.dst.add_or_update_deck(&target)?;
+
+        let id = target.id;
+        self.dst_decks.insert(id, target);
+        Ok(id)
+    }
+
+    // Cards
+    //----------------------------------------
+
+    fn import_cards(&mut self) -> Result<()> {
+        let ahead_by = days_elapsed(self.src.creation_stamp()?, self.now_secs)
+            - days_elapsed(self.dst.creation_stamp()?, self.now_secs);
+        // v1 learning states can't be used by the v2 scheduler
+        let reset_learning =
+            !self.package.v2 && self.dst.get_config_value::<u8>("schedVer")?.unwrap_or(1) == 2;
+
+        let mut existing_ords: HashMap<i64, Vec<u16>> = HashMap::new();
+        for src_id in self.src.card_ids_in_decks(None)? {
+            let mut card = match self.src.get_card(src_id)? {
+                Some(card) => card,
+                None => continue,
+            };
+            let note_id = match self.note_map.get(&card.note_id) {
+                Some(id) => *id,
+                None => continue,
+            };
+            let ords = match existing_ords.entry(note_id) {
+                Entry::Occupied(entry) => entry.into_mut(),
+                Entry::Vacant(entry) => entry.insert(
+                    self.dst
+                        .get_cards_of_note(note_id)?
+                        .iter()
+                        .map(|c| c.ordinal)
+                        .collect(),
+                ),
+            };
+            if ords.contains(&card.ordinal) {
+                continue;
+            }
+
+            while self.dst.get_card(card.id)?.is_some() {
+                card.id += 999;
+            }
+            card.note_id = note_id;
+            card.mtime_secs = self.now_secs;
+            card.usn = self.usn;
+            self.adjust_card_scheduling(&mut card, ahead_by, reset_learning);
+            card.deck_id = self.map_deck(card.deck_id)?;
+            if card.ctype == CardType::New {
+                self.next_position = self.next_position.max(card.due + 1);
+            }
+            self.dst.add_or_update_card(&card)?;
+
+            for mut entry in self.src.get_revlog_entries(src_id)? {
+                entry.card_id = card.id;
+                entry.usn = self.usn;
+                // the v2 scheduler has an extra learning button
+                if reset_learning
+                    && (entry.review_kind == 0 || entry.review_kind == 2)
+                    && (entry.ease == 2 || entry.ease == 3)
+                {
+                    entry.ease += 1;
+                }
+                self.dst.add_revlog_entry_if_missing(&entry)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Make due days relative to the collection's creation, return cards in
+    /// filtered decks to their home deck, and convert v1 learning states if
+    /// needed.
+    fn adjust_card_scheduling(&mut self, card: &mut Card, ahead_by: i64, reset_learning: bool) {
+        let due_is_day = match card.queue {
+            CardQueue::Review | CardQueue::DayLearn => true,
+            _ => card.ctype == CardType::Review,
+        };
+        if due_is_day {
+            card.due -= ahead_by;
+        }
+        if card.original_due != 0 {
+            card.original_due -= ahead_by;
+        }
+
+        if card.original_deck_id != 0 {
+            card.deck_id = card.original_deck_id;
+            card.original_deck_id = 0;
+            card.due = card.original_due;
+            card.original_due = 0;
+            let (ctype, queue) = match card.ctype {
+                CardType::New | CardType::Learn => (CardType::New, CardQueue::New),
+                CardType::Review | CardType::Relearn => (CardType::Review, CardQueue::Review),
+            };
+            card.ctype = ctype;
+            card.queue = queue;
+        }
+
+        let learning = match card.queue {
+            CardQueue::Learn | CardQueue::DayLearn => true,
+            _ => false,
+        };
+        if reset_learning && learning {
+            match card.ctype {
+                CardType::Review | CardType::Relearn => {
+                    // relearning v1 cards keep their review due date here
+                    card.due = card.original_due;
+                    card.ctype = CardType::Review;
+                    card.queue = CardQueue::Review;
+                }
+                CardType::New | CardType::Learn => {
+                    card.due = self.next_position;
+                    card.ctype = CardType::New;
+                    card.queue = CardQueue::New;
+                    card.interval = 0;
+                    card.left = 0;
+                }
+            }
+            card.original_due = 0;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::card::{Card, CardType};
+    use crate::err::{AnkiError, Result};
+    use crate::import_export::package::{
+        export_package, import_collection_package, import_package, DuplicateMode, ImportProgress,
+        PackageExportOptions, PackageImportOptions,
+    };
+    use crate::import_export::ExportLimit;
+    use crate::notes::Note;
+    use crate::storage::testutil::{add_basic_notetype, add_deck, notetype};
+    use crate::storage::SqliteStorage;
+    use serde_json::json;
+    use std::fs;
+    use std::io::{self, Write};
+    use std::path::Path;
+    use tempfile::tempdir;
+    use zip::write::FileOptions;
+    use zip::{ZipArchive, ZipWriter};
+
+    fn add_note(
+        storage: &SqliteStorage,
+        id: i64,
+        guid: &str,
+        field: &str,
+        mtime: i64,
+    ) -> Result<()> {
+        storage.add_or_update_note(&Note {
+            id,
+            guid: guid.into(),
+            notetype_id: 1,
+            mtime_secs: mtime,
+            fields: vec![field.into(), "".into()],
+            ..Default::default()
+        })?;
+        storage.add_or_update_card(&Card {
+            id,
+            note_id: id,
+            deck_id: 2,
+            due: id,
+            ..Default::default()
+        })
+    }
+
+    /// A collection with a Basic notetype and a "Spanish::Verbs" deck.
+    fn collection(path: &Path) -> Result<SqliteStorage> {
+        let storage = SqliteStorage::open_or_create(path)?;
+        add_basic_notetype(&storage)?;
+        add_deck(&storage, 1, "Default")?;
+        add_deck(&storage, 2, "Spanish::Verbs")?;
+        storage.db.execute_batch(
+            r#"update col set dconf = '{"1": {"id": 1, "name": "Default", "mod": 0, "usn": 0}}'"#,
+        )?;
+        Ok(storage)
+    }
+
+    fn export(storage: &SqliteStorage, media_folder: &Path, path: &Path) -> Result<()> {
+        let opts = PackageExportOptions {
+            limit: ExportLimit::Collection,
+            include_scheduling: true,
+            include_media: true,
+            sharing: None,
+        };
+        export_package(storage, media_folder, path, &opts, |_| true)?;
+        Ok(())
+    }
+
+    fn import(
+        storage: &mut SqliteStorage,
+        media_folder: &Path,
+        path: &Path,
+        duplicate_mode: DuplicateMode,
+    ) -> Result<crate::import_export::package::ImportLog> {
+        import_package(
+            storage,
+            media_folder,
+            path,
+            &PackageImportOptions { duplicate_mode },
+            |_| true,
+        )
+    }
+
+    #[test]
+    fn test_import_package() -> Result<()> {
+        let dir = tempdir()?;
+        let src_media = dir.path().join("src.media");
+        let dst_media = dir.path().join("dst.media");
+        fs::create_dir(&src_media)?;
+        fs::create_dir(&dst_media)?;
+        let src = collection(&dir.path().join("src.anki2"))?;
+        add_note(&src, 1, "guid1", r#"uno <img src="foo.jpg">"#, 10)?;
+        add_note(&src, 2, "guid2", "dos", 10)?;
+        fs::write(src_media.join("foo.jpg"), "foo")?;
+        fs::write(src_media.join("_font.ttf"), "font")?;
+        let apkg = dir.path().join("src.apkg");
+        export(&src, &src_media, &apkg)?;
+
+        // into an empty collection
+        let mut dst = SqliteStorage::open_or_create(&dir.path().join("dst.anki2"))?;
+        let log = import(&mut dst, &dst_media, &apkg, DuplicateMode::UpdateIfNewer)?;
+        assert_eq!(log.found_notes, 2);
+        assert_eq!(log.added.len(), 2);
+        assert_eq!(log.added[1].fields[0], "dos");
+        assert_eq!(log.media_files, 1);
+        assert!(dst.get_notetype(1)?.is_some());
+        assert_eq!(dst.get_card(1)?.unwrap().note_id, 1);
+        let decks = dst.get_all_decks()?;
+        let mut names: Vec<_> = decks.values().map(|d| d.name.as_str()).collect();
+        names.sort();
+        assert_eq!(names, vec!["Spanish", "Spanish::Verbs"]);
+        assert_eq!(fs::read(dst_media.join("foo.jpg"))?, b"foo");
+        // the notetype doesn't refer to it
+        assert!(!dst_media.join("_font.ttf").exists());
+
+        // importing again changes nothing
+        let log = import(&mut dst, &dst_media, &apkg, DuplicateMode::UpdateIfNewer)?;
+        assert_eq!((log.added.len(), log.duplicate.len()), (0, 2));
+        assert_eq!(log.media_files, 0);
+        assert_eq!(dst.all_note_ids()?.len(), 2);
+        assert_eq!(dst.card_ids_in_decks(None)?.len(), 2);
+
+        // a newer note is updated, unless skipping
+        add_note(&src, 1, "guid1", r#"one <img src="foo.jpg">"#, 20)?;
+        export(&src, &src_media, &apkg)?;
+        let log = import(&mut dst, &dst_media, &apkg, DuplicateMode::Skip)?;
+        assert_eq!((log.updated.len(), log.duplicate.len()), (0, 2));
+        let log = import(&mut dst, &dst_media, &apkg, DuplicateMode::UpdateIfNewer)?;
+        assert_eq!((log.updated.len(), log.duplicate.len()), (1, 1));
+        assert!(dst.get_note(1)?.unwrap().fields[0].starts_with("one"));
+
+        // a clashing media file is renamed
+        fs::write(dst_media.join("foo.jpg"), "bar")?;
+        let log = import(&mut dst, &dst_media, &apkg, DuplicateMode::Duplicate)?;
+        assert_eq!(log.added.len(), 2);
+        assert_eq!(dst.all_note_ids()?.len(), 4);
+        let note = dst.get_note(log.added[0].id)?.unwrap();
+        assert_eq!(note.fields[0], r#"one <img src="foo_1.jpg">"#);
+        assert_ne!(note.guid, "guid1");
+        assert_eq!(fs::read(dst_media.join("foo_1.jpg"))?, b"foo");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_notetype_conflicts() -> Result<()> {
+        let dir = tempdir()?;
+        let media = dir.path().join("media");
+        fs::create_dir(&media)?;
+        let src = collection(&dir.path().join("src.anki2"))?;
+        add_note(&src, 1, "guid1", "uno", 10)?;
+        add_note(&src, 2, "guid2", "dos", 10)?;
+        let apkg = dir.path().join("src.apkg");
+        export(&src, &media, &apkg)?;
+
+        // the local notetype with the same id has different fields, and one
+        // of the notes is already using it
+        let mut dst = collection(&dir.path().join("dst.anki2"))?;
+        dst.add_or_update_notetype(&notetype(
+            1,
+            "Basic",
+            &["Question", "Answer"],
+            &["{{Question}}"],
+        ))?;
+        add_note(&dst, 1, "guid1", "local", 30)?;
+
+        let log = import(&mut dst, &media, &apkg, DuplicateMode::UpdateIfNewer)?;
+        assert_eq!(log.conflicting.len(), 1);
+        assert_eq!(log.added.len(), 1);
+        let added = dst.get_note(log.added[0].id)?.unwrap();
+        assert_eq!(added.notetype_id, 2);
+        assert_eq!(dst.get_notetype(2)?.unwrap().fields[0].name, "Front");
+        assert_eq!(dst.get_note(1)?.unwrap().fields[0], "local");
+        // the conflicting note's card was skipped
+        assert_eq!(dst.card_ids_in_decks(None)?.len(), 2);
+        let card = dst.get_cards_of_note(added.id)?.remove(0);
+        assert_eq!(card.id, 2);
+        assert_eq!(card.ctype, CardType::New);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_hostile_media_map() -> Result<()> {
+        let dir = tempdir()?;
+        let media = dir.path().join("media");
+        fs::create_dir(&media)?;
+        let src = collection(&dir.path().join("src.anki2"))?;
+        let apkg = dir.path().join("src.apkg");
+        export(&src, &media, &apkg)?;
+        let mut dst = collection(&dir.path().join("dst.anki2"))?;
+
+        for fname in &["../evil.jpg", "..", "a\\b.jpg", "C:evil.jpg", "con.jpg", ""] {
+            // copy the package, replacing its media map
+            let hostile = dir.path().join("hostile.apkg");
+            let mut zip = ZipArchive::new(fs::File::open(&apkg)?)?;
+            let mut out = ZipWriter::new(fs::File::create(&hostile)?);
+            for i in 0..zip.len() {
+                let mut entry = zip.by_index(i)?;
+                let name = entry.name().to_string();
+                out.start_file(name.as_str(), FileOptions::default())?;
+                if name == "media" {
+                    out.write_all(&serde_json::to_vec(&json!({ "0": fname }))?)?;
+                } else {
+                    io::copy(&mut entry, &mut out)?;
+                }
+            }
+            out.start_file("0", FileOptions::default())?;
+            out.write_all(b"evil")?;
+            out.finish()?;
+
+            match import(&mut dst, &media, &hostile, DuplicateMode::UpdateIfNewer).err() {
+                Some(AnkiError::InvalidInput { .. }) => (),
+                other => panic!("unexpected for {:?}: {:?}", fname, other),
+            }
+        }
+        assert!(!dir.path().join("evil.jpg").exists());
+        assert_eq!(fs::read_dir(&media)?.count(), 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_import_collection_package() -> Result<()> {
+        let dir = tempdir()?;
+        let media = dir.path().join("media");
+        fs::create_dir(&media)?;
+        let src = collection(&dir.path().join("src.anki2"))?;
+        add_note(&src, 1, "guid1", r#"<img src="foo.jpg">"#, 10)?;
+        fs::write(media.join("foo.jpg"), "foo")?;
+        let apkg = dir.path().join("src.apkg");
+        export(&src, &media, &apkg)?;
+
+        let col_path = dir.path().join("collection.anki2");
+        fs::write(&col_path, "not a collection")?;
+        let dst_media = dir.path().join("dst.media");
+        fs::create_dir(&dst_media)?;
+        let mut progress = vec![];
+        import_collection_package(&col_path, &dst_media, &apkg, |p| {
+            progress.push(p);
+            true
+        })?;
+        assert_eq!(progress.last(), Some(&ImportProgress::Media(1)));
+        assert_eq!(fs::read(dst_media.join("foo.jpg"))?, b"foo");
+        let storage = SqliteStorage::open_or_create(&col_path)?;
+        assert!(storage.get_note(1)?.is_some());
+
+        let result = import_collection_package(&col_path, &dst_media, &apkg, |_| false);
+        match result.err() {
+            Some(AnkiError::Interrupted) => (),
+            other => panic!("unexpected: {:?}", other),
+        }
+
+        Ok(())
+    }
+}
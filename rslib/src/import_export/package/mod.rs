@@ -0,0 +1,26 @@
+// Copyright: Ankitects Pty Ltd and contributors
+// License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+//! Deck packages (.apkg). A package is a zip holding a collection with the
+//! shared notes and cards, and the media files they use. Media files are
+//! stored under numbers, and the "media" file maps the numbers to the
+//! filenames as JSON.
+
+mod export;
+mod import;
+
+pub use export::{
+    export_package, ExportProgress, PackageExportOptions, PackageExportOutput, SharingOptions,
+};
+pub use import::{
+    import_collection_package, import_package, DuplicateMode, ImportLog, ImportProgress,
+    LoggedNote, PackageImportOptions,
+};
+
+/// The collection, in a schema all clients can read.
+const LEGACY_COLLECTION_NAME: &str = "collection.anki2";
+/// The collection, if it uses the v2 scheduler. Older clients can't
+/// understand its scheduling, so they're given an empty collection under
+/// the legacy name instead.
+const V2_COLLECTION_NAME: &str = "collection.anki21";
+const MEDIA_MAP_NAME: &str = "media";
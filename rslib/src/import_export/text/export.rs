@@ -0,0 +1,229 @@
+// Copyright: Ankitects Pty Ltd and contributors
+// License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+//! Writing notes as tab-separated text, one note per line. Rows are written
+//! as the notes are read, so large collections aren't held in memory.
+
+use crate::err::Result;
+use crate::import_export::{card_ids_for_limit, ExportLimit};
+use crate::notes::Note;
+use crate::storage::SqliteStorage;
+use crate::text::{html_to_text, TextLayout};
+use lazy_static::lazy_static;
+use regex::Regex;
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+lazy_static! {
+    static ref STYLE: Regex = Regex::new(r"(?i)<style>.*?</style>").unwrap();
+    static ref TYPE_ANSWER: Regex = Regex::new(r"\[\[type:[^]]+\]\]").unwrap();
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TextExportOptions {
+    pub limit: ExportLimit,
+    /// If not set, fields are converted to plain text, with tables keeping
+    /// their rows and cells.
+    pub include_html: bool,
+    /// The columns before the fields, in this order.
+    pub include_guid: bool,
+    pub include_notetype: bool,
+    /// The deck of the note's first exported card.
+    pub include_deck: bool,
+    /// A column after the fields.
+    pub include_tags: bool,
+}
+
+/// Write the notes of the cards `opts` selects to `out_path`, replacing any
+/// file there, and return the number of notes written. The file is written
+/// to a temporary file first, so an existing file is left intact if the
+/// export fails.
+pub fn export_notes_as_text(
+    storage: &SqliteStorage,
+    out_path: &Path,
+    opts: &TextExportOptions,
+) -> Result<u32> {
+    // the deck of each note's first card, in note order
+    let mut note_decks = BTreeMap::new();
+    for card_id in card_ids_for_limit(storage, &opts.limit)? {
+        if let Some(card) = storage.get_card(card_id)? {
+            let deck_id = if card.original_deck_id != 0 {
+                card.original_deck_id
+            } else {
+                card.deck_id
+            };
+            let entry = note_decks
+                .entry(card.note_id)
+                .or_insert((card.ordinal, deck_id));
+            if card.ordinal < entry.0 {
+                *entry = (card.ordinal, deck_id);
+            }
+        }
+    }
+
+    let tmp_path = out_path.with_extension("tmp");
+    let result = write_rows(storage, &tmp_path, &note_decks, opts);
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp_path);
+    }
+    let count = result?;
+    fs::rename(&tmp_path, out_path)?;
+
+    Ok(count)
+}
+
+fn write_rows(
+    storage: &SqliteStorage,
+    path: &Path,
+    note_decks: &BTreeMap<i64, (u16, i64)>,
+    opts: &TextExportOptions,
+) -> Result<u32> {
+    let decks = storage.get_all_decks()?;
+    let notetypes = storage.get_all_notetypes()?;
+    let mut out = BufWriter::new(File::create(path)?);
+    let mut count = 0;
+    let mut row = vec![];
+
+    for (note_id, (_, deck_id)) in note_decks {
+        let note = match storage.get_note(*note_id)? {
+            Some(note) => note,
+            None => continue,
+        };
+        row.clear();
+        if opts.include_guid {
+            row.push(escape_text(&note.guid).into_owned());
+        }
+        if opts.include_notetype {
+            let name = notetypes
+                .get(&note.notetype_id)
+                .map(|nt| nt.name.as_str())
+                .unwrap_or_default();
+            row.push(escape_text(name).into_owned());
+        }
+        if opts.include_deck {
+            let name = decks
+                .get(deck_id)
+                .map(|deck| deck.name.as_str())
+                .unwrap_or_default();
+            row.push(escape_text(name).into_owned());
+        }
+        row.extend(fields_as_text(&note, opts.include_html));
+        if opts.include_tags {
+            row.push(escape_text(&note.tags.join(" ")).into_owned());
+        }
+
+        writeln!(out, "{}", row.join("\t"))?;
+        count += 1;
+    }
+    out.flush()?;
+
+    Ok(count)
+}
+
+fn fields_as_text(note: &Note, include_html: bool) -> impl Iterator<Item = String> + '_ {
+    note.fields.iter().map(move |field| {
+        let text = if include_html {
+            // newlines and tabs are not significant in HTML
+            field.replace('\n', " ").replace('\t', "        ")
+        } else {
+            html_to_text(field, TextLayout::Tables)
+        };
+        let text = STYLE.replace_all(&text, "");
+        let text = TYPE_ANSWER.replace_all(&text, "");
+        escape_text(&text).into_owned()
+    })
+}
+
+/// Quote text that has quotes, newlines or tabs in it, doubling its quotes.
+fn escape_text(text: &str) -> Cow<str> {
+    if text.contains(&['"', '\n', '\t'][..]) {
+        format!("\"{}\"", text.replace('"', "\"\"")).into()
+    } else {
+        text.into()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::card::Card;
+    use crate::err::Result;
+    use crate::import_export::text::{export_notes_as_text, TextExportOptions};
+    use crate::import_export::ExportLimit;
+    use crate::notes::Note;
+    use crate::storage::testutil::{add_basic_notetype, add_deck, open_test_collection};
+    use std::fs;
+
+    #[test]
+    fn test_export_notes_as_text() -> Result<()> {
+        let (dir, storage) = open_test_collection()?;
+        add_basic_notetype(&storage)?;
+        for (id, name) in &[(1, "Default"), (2, "Spanish"), (3, "Spanish::Verbs")] {
+            add_deck(&storage, *id, name)?;
+        }
+        for (id, deck_id, fields) in &[
+            (1, 1, ["one", "<b>uno</b>\n[[type:Front]]"]),
+            (
+                2,
+                3,
+                ["dos", "<table><tr><td>a</td><td>b</td></tr></table>"],
+            ),
+            (3, 2, ["\"tres\"", "<style>b {}</style>three&nbsp;"]),
+        ] {
+            let note = Note {
+                id: *id,
+                guid: format!("guid{}", id),
+                notetype_id: 1,
+                tags: vec!["a".into(), "b".into()],
+                fields: fields.iter().map(|f| f.to_string()).collect(),
+                ..Default::default()
+            };
+            storage.add_or_update_note(&note)?;
+            storage.add_or_update_card(&Card {
+                id: *id,
+                note_id: *id,
+                deck_id: *deck_id,
+                ..Default::default()
+            })?;
+        }
+
+        let path = dir.path().join("notes.txt");
+        let mut opts = TextExportOptions {
+            limit: ExportLimit::Collection,
+            include_html: true,
+            include_guid: false,
+            include_notetype: false,
+            include_deck: false,
+            include_tags: true,
+        };
+        assert_eq!(export_notes_as_text(&storage, &path, &opts)?, 3);
+        assert_eq!(
+            fs::read_to_string(&path)?,
+            concat!(
+                "one\t<b>uno</b> \ta b\n",
+                "dos\t<table><tr><td>a</td><td>b</td></tr></table>\ta b\n",
+                "\"\"\"tres\"\"\"\tthree&nbsp;\ta b\n",
+            )
+        );
+
+        // without HTML, tables keep their cells
+        opts.limit = ExportLimit::Deck(2);
+        opts.include_html = false;
+        opts.include_tags = false;
+        opts.include_guid = true;
+        opts.include_notetype = true;
+        opts.include_deck = true;
+        assert_eq!(export_notes_as_text(&storage, &path, &opts)?, 2);
+        assert_eq!(
+            fs::read_to_string(&path)?,
+            concat!(
+                "guid2\tBasic\tSpanish::Verbs\tdos\t\"a\tb\"\n",
+                "guid3\tBasic\tSpanish\t\"\"\"tres\"\"\"\tthree\n",
+            )
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,877 @@
+// Copyright: Ankitects Pty Ltd and contributors
+// License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+//! Reading text files, and adding or updating a note for each row.
+//!
+//! Rows are matched with existing notes of the notetype by their first
+//! field, or by their guid if a column is mapped to it. Like the legacy
+//! importer, a matching row can update the note, be skipped, or be added as
+//! a duplicate.
+
+use crate::card::Card;
+use crate::cardgen::CardGenContext;
+use crate::decks::Deck;
+use crate::err::{AnkiError, Result};
+use crate::notes::{field_checksum, new_guid, split_tags, Note};
+use crate::notetypes::NoteType;
+use crate::storage::{now_millis, SqliteStorage};
+use crate::tags::register_tags;
+use crate::text::{normalize_to_nfc, sort_field_text};
+use encoding_rs::{Encoding, WINDOWS_1252};
+use rand::seq::SliceRandom;
+use serde_json::Value;
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::Path;
+
+/// The delimiters that are detected, in order of preference.
+const DELIMITERS: &[char] = &['\t', '|', ',', ';', ':'];
+/// How many lines delimiter detection looks at.
+const DETECTION_LINES: usize = 10;
+
+/// A text file, split into rows of fields.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TextFile {
+    pub delimiter: char,
+    /// The encoding the file was read with, eg "UTF-8". Files without a
+    /// byte order mark that aren't valid UTF-8 are read as windows-1252.
+    pub encoding: &'static str,
+    /// From the "tags:" line, if any.
+    pub tags: Vec<String>,
+    /// The number of fields in the first row. Rows with a different number
+    /// of fields are not imported.
+    pub column_count: usize,
+    pub rows: Vec<Vec<String>>,
+    /// Set if the rest of the file after `rows` couldn't be parsed.
+    pub error: Option<String>,
+}
+
+fn decode(data: &[u8]) -> (Cow<str>, &'static str) {
+    if let Some((encoding, bom_len)) = Encoding::for_bom(data) {
+        let (text, _) = encoding.decode_without_bom_handling(&data[bom_len..]);
+        (text, encoding.name())
+    } else if let Ok(text) = std::str::from_utf8(data) {
+        (text.into(), "UTF-8")
+    } else {
+        let (text, _) = WINDOWS_1252.decode_without_bom_handling(data);
+        (text, WINDOWS_1252.name())
+    }
+}
+
+/// The delimiter that appears the same number of times on most of the
+/// first lines. If there isn't one, the first of tab, semicolon and comma
+/// that appears on the first line, or space.
+fn guess_delimiter(lines: &[&str]) -> char {
+    let sample: Vec<_> = lines
+        .iter()
+        .filter(|line| !line.trim().is_empty())
+        .take(DETECTION_LINES)
+        .collect();
+    let mut best = None;
+    let mut best_score = sample.len() / 2;
+    for &delimiter in DELIMITERS {
+        let mut lines_with_count: HashMap<usize, usize> = HashMap::new();
+        for line in &sample {
+            let count = line.matches(delimiter).count();
+            if count > 0 {
+                *lines_with_count.entry(count).or_default() += 1;
+            }
+        }
+        let score = lines_with_count.values().max().copied().unwrap_or_default();
+        if score > best_score {
+            best = Some(delimiter);
+            best_score = score;
+        }
+    }
+
+    best.unwrap_or_else(|| {
+        let first = sample.first().copied().copied().unwrap_or_default();
+        ['\t', ';', ',']
+            .iter()
+            .copied()
+            .find(|delimiter| first.contains(*delimiter))
+            .unwrap_or(' ')
+    })
+}
+
+/// Read a text file, detecting its encoding, and its delimiter if one isn't
+/// provided. If `max_rows` is provided, reading stops after that many rows.
+pub fn read_text_file(
+    path: &Path,
+    delimiter: Option<char>,
+    max_rows: Option<usize>,
+) -> Result<TextFile> {
+    let data = fs::read(path)?;
+    let (text, encoding) = decode(&data);
+    let mut lines: Vec<&str> = text
+        .split('\n')
+        .map(|line| line.trim_end_matches('\r'))
+        .filter(|line| !line.starts_with('#'))
+        .collect();
+    let mut tags = vec![];
+    if let Some(line) = lines.first().filter(|line| line.starts_with("tags:")) {
+        tags = split_tags(&line["tags:".len()..]).map(Into::into).collect();
+        lines.remove(0);
+    }
+
+    let delimiter = delimiter.unwrap_or_else(|| guess_delimiter(&lines));
+    if !delimiter.is_ascii() {
+        return Err(AnkiError::invalid_input(
+            "the delimiter must be an ASCII character",
+        ));
+    }
+    let text = lines.join("\n");
+    let mut reader = csv::ReaderBuilder::new()
+        .delimiter(delimiter as u8)
+        .has_headers(false)
+        .flexible(true)
+        .from_reader(text.as_bytes());
+    let mut rows = vec![];
+    let mut error = None;
+    for record in reader.records() {
+        if max_rows.map(|max| rows.len() >= max).unwrap_or_default() {
+            break;
+        }
+        match record {
+            Ok(record) => rows.push(record.iter().map(Into::into).collect::<Vec<String>>()),
+            Err(err) => {
+                error = Some(err.to_string());
+                break;
+            }
+        }
+    }
+
+    Ok(TextFile {
+        delimiter,
+        encoding,
+        tags,
+        column_count: rows.first().map(Vec::len).unwrap_or_default(),
+        rows,
+        error,
+    })
+}
+
+/// What a column of the file is imported as.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ColumnMapping {
+    Ignored,
+    /// The notetype's field with this ordinal.
+    Field(usize),
+    /// Space-separated tags.
+    Tags,
+    /// The name of the deck a new note's cards are added to. Missing decks
+    /// are created.
+    Deck,
+    /// The note's guid, which is used to find existing notes instead of the
+    /// first field.
+    Guid,
+}
+
+/// What happens to a row that matches an existing note.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TextDuplicateMode {
+    /// Replace the note's mapped fields.
+    Update,
+    /// Leave the note alone.
+    Skip,
+    /// Add the row as a new note.
+    Duplicate,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TextImportOptions {
+    pub notetype_id: i64,
+    /// The deck new cards are added to, if the row doesn't name one. Its
+    /// options decide whether new cards are shown in random order.
+    pub deck_id: i64,
+    /// Detected if not provided.
+    pub delimiter: Option<char>,
+    /// One entry per column. The first field must be mapped.
+    pub columns: Vec<ColumnMapping>,
+    /// If false, fields are plain text: HTML special characters are escaped,
+    /// and line breaks are converted to <br>.
+    pub allow_html: bool,
+    pub duplicate_mode: TextDuplicateMode,
+    /// Added to the notes that are updated, if no column is mapped to tags.
+    pub tags_for_updated: Vec<String>,
+}
+
+/// A row that wasn't imported.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RowProblem {
+    /// The row has a different number of fields than the first row.
+    WrongFieldCount(Vec<String>),
+    EmptyFirstField(Vec<String>),
+    /// An earlier row had the same first field, or guid.
+    Repeated(String),
+    /// The row's guid belongs to a note of another notetype.
+    NotetypeMismatch(String),
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TextImportLog {
+    /// The number of fields rows are expected to have.
+    pub column_count: usize,
+    /// In file order.
+    pub problems: Vec<RowProblem>,
+    /// Set if the rest of the file couldn't be parsed.
+    pub parse_error: Option<String>,
+    pub added: u32,
+    /// Existing notes whose fields changed.
+    pub updated: u32,
+    /// Existing notes that were matched but not changed.
+    pub unchanged: u32,
+    /// The first field of each existing note that was updated, or of which
+    /// a duplicate was added.
+    pub matched: Vec<String>,
+    /// Rows that weren't imported, as the note would have no cards.
+    pub without_cards: u32,
+    /// The notes added, and the existing notes matched when updating.
+    pub note_ids: Vec<i64>,
+}
+
+/// Add or update a note for each row in a text file. The collection is left
+/// unchanged if the import fails.
+pub fn import_text_file(
+    storage: &mut SqliteStorage,
+    path: &Path,
+    opts: &TextImportOptions,
+) -> Result<TextImportLog> {
+    let file = read_text_file(path, opts.delimiter, None)?;
+    if file.rows.is_empty() {
+        return Err(AnkiError::invalid_input("the file contains no notes"));
+    }
+
+    storage.transact(|storage| {
+        let mut importer = TextImporter::new(storage, opts, &file)?;
+        for row in &file.rows {
+            importer.import_row(row)?;
+        }
+        importer.add_cards()?;
+
+        let tags: Vec<_> = importer.tags.iter().collect();
+        register_tags(storage, &tags, importer.usn)?;
+        storage.mark_modified(now_millis())?;
+
+        let mut log = importer.log;
+        log.column_count = file.column_count;
+        log.parse_error = file.error.clone();
+        Ok(log)
+    })
+}
+
+struct TextImporter<'a> {
+    storage: &'a SqliteStorage,
+    opts: &'a TextImportOptions,
+    /// The mapping of the columns rows have.
+    columns: Vec<ColumnMapping>,
+    file_tags: &'a [String],
+    column_count: usize,
+    notetype: NoteType,
+    cardgen: CardGenContext,
+    first_field_column: usize,
+    guid_column: Option<usize>,
+    deck_column: Option<usize>,
+    tags_mapped: bool,
+    usn: i32,
+    now_secs: i64,
+    guids: HashMap<String, i64>,
+    checksums: HashMap<u32, Vec<i64>>,
+    decks: HashMap<i64, Deck>,
+    /// Deck ids by lowercase name.
+    decks_by_name: HashMap<String, i64>,
+    /// The first fields or guids of the rows so far.
+    seen: HashSet<String>,
+    /// The first fields that a duplicate has been added of.
+    duplicated: HashSet<String>,
+    tags: HashSet<String>,
+    /// The cards to add, grouped by note. They're given positions once all
+    /// rows have been read, as the order may be random.
+    new_cards: Vec<Vec<Card>>,
+    log: TextImportLog,
+}
+
+impl<'a> TextImporter<'a> {
+    fn new(
+        storage: &'a SqliteStorage,
+        opts: &'a TextImportOptions,
+        file: &'a TextFile,
+    ) -> Result<Self> {
+        let notetype = storage
+            .get_notetype(opts.notetype_id)?
+            .ok_or_else(|| AnkiError::invalid_input("notetype not found"))?;
+        let columns: Vec<_> = opts
+            .columns
+            .iter()
+            .take(file.column_count)
+            .copied()
+            .collect();
+        let column_of = |mapping| columns.iter().position(|m| *m == mapping);
+        let first_field_column = column_of(ColumnMapping::Field(0))
+            .ok_or_else(|| AnkiError::invalid_input("the first field must be mapped"))?;
+
+        let cardgen = CardGenContext::for_notetype(&notetype);
+
+        Ok(TextImporter {
+            storage,
+            opts,
+            file_tags: &file.tags,
+            column_count: file.column_count,
+            first_field_column,
+            guid_column: column_of(ColumnMapping::Guid),
+            deck_column: column_of(ColumnMapping::Deck),
+            tags_mapped: column_of(ColumnMapping::Tags).is_some(),
+            columns,
+            cardgen,
+            usn: storage.usn()?,
+            now_secs: now_millis() / 1000,
+            guids: storage.note_ids_by_guid()?,
+            checksums: storage.note_ids_by_checksum(notetype.id)?,
+            notetype,
+            decks: storage.get_all_decks()?,
+            decks_by_name: HashMap::new(),
+            seen: HashSet::new(),
+            duplicated: HashSet::new(),
+            tags: HashSet::new(),
+            new_cards: vec![],
+            log: TextImportLog::default(),
+        })
+    }
+
+    fn import_row(&mut self, row: &[String]) -> Result<()> {
+        if row.len() != self.column_count {
+            self.log
+                .problems
+                .push(RowProblem::WrongFieldCount(row.to_vec()));
+            return Ok(());
+        }
+        let row: Vec<_> = row.iter().map(|text| self.prepare_text(text)).collect();
+        let first_field = &row[self.first_field_column];
+        if first_field.is_empty() {
+            self.log.problems.push(RowProblem::EmptyFirstField(row));
+            return Ok(());
+        }
+        let guid = self
+            .guid_column
+            .map(|idx| row[idx].as_str())
+            .filter(|guid| !guid.is_empty());
+        let key = guid.unwrap_or(first_field);
+        if !self.seen.insert(key.into()) && self.opts.duplicate_mode != TextDuplicateMode::Duplicate
+        {
+            self.log.problems.push(RowProblem::Repeated(key.into()));
+            return Ok(());
+        }
+
+        let existing = match guid {
+            Some(guid) => match self.guids.get(guid) {
+                Some(id) => self.storage.get_note(*id)?.into_iter().collect(),
+                None => vec![],
+            },
+            None => self.notes_with_first_field(first_field)?,
+        };
+        if existing.iter().any(|n| n.notetype_id != self.notetype.id) {
+            self.log
+                .problems
+                .push(RowProblem::NotetypeMismatch(key.into()));
+            return Ok(());
+        }
+
+        match self.opts.duplicate_mode {
+            _ if existing.is_empty() => self.add_note(&row, guid)?,
+            TextDuplicateMode::Update => {
+                for note in existing {
+                    self.update_note(note, &row)?;
+                }
+            }
+            TextDuplicateMode::Skip => self.log.unchanged += existing.len() as u32,
+            TextDuplicateMode::Duplicate => {
+                if self.duplicated.insert(first_field.clone()) {
+                    self.log.matched.push(first_field.clone());
+                }
+                self.add_note(&row, None)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn prepare_text(&self, text: &str) -> String {
+        let text = if self.opts.allow_html {
+            text.trim().into()
+        } else {
+            text.replace('&', "&amp;")
+                .replace('<', "&lt;")
+                .replace('>', "&gt;")
+                .trim()
+                .replace('\n', "<br>")
+        };
+        normalize_to_nfc(&text).into_owned()
+    }
+
+    /// The checksum may match other text, so the field is compared too.
+    fn notes_with_first_field(&self, text: &str) -> Result<Vec<Note>> {
+        let mut notes = vec![];
+        for id in self
+            .checksums
+            .get(&field_checksum(text))
+            .into_iter()
+            .flatten()
+        {
+            if let Some(note) = self.storage.get_note(*id)? {
+                if note.fields.first().map(String::as_str) == Some(text) {
+                    notes.push(note);
+                }
+            }
+        }
+        Ok(notes)
+    }
+
+    /// The provided fields, with the mapped ones replaced.
+    fn fields_from_row(&self, row: &[String], mut fields: Vec<String>) -> Vec<String> {
+        for (mapping, text) in self.columns.iter().zip(row) {
+            if let ColumnMapping::Field(ord) = mapping {
+                if let Some(field) = fields.get_mut(*ord) {
+                    *field = text.clone();
+                }
+            }
+        }
+        fields
+    }
+
+    fn tags_from_row(&self, row: &[String]) -> Vec<String> {
+        let mut tags = self.file_tags.to_vec();
+        for (mapping, text) in self.columns.iter().zip(row) {
+            if *mapping == ColumnMapping::Tags {
+                tags.extend(split_tags(text).map(Into::into));
+            }
+        }
+        tags
+    }
+
+    fn ords_to_generate(&self, fields: &[String]) -> Vec<u16> {
+        let fields: Vec<_> = fields.iter().map(String::as_str).collect();
+        let mut ords: Vec<_> = self.cardgen.ords_to_generate(&fields).into_iter().collect();
+        ords.sort_unstable();
+        ords
+    }
+
+    fn update_field_cache(&self, note: &mut Note) {
+        let sort_field = note
+            .fields
+            .get(self.notetype.sort_field_idx as usize)
+            .map(String::as_str)
+            .unwrap_or_default();
+        note.sort_field = sort_field_text(sort_field).into_owned();
+        note.checksum = field_checksum(&note.fields[0]);
+    }
+
+    /// Queue a card for each ordinal, to be added by add_cards().
+    fn queue_cards(&mut self, note_id: i64, deck_id: Option<i64>, ords: &[u16]) {
+        let cards = ords
+            .iter()
+            .map(|ord| Card {
+                note_id,
+                deck_id: deck_id
+                    .or_else(|| self.template_deck(*ord))
+                    .unwrap_or(self.opts.deck_id),
+                ordinal: *ord,
+                mtime_secs: self.now_secs,
+                usn: self.usn,
+                ..Default::default()
+            })
+            .collect();
+        self.new_cards.push(cards);
+    }
+
+    fn add_note(&mut self, row: &[String], guid: Option<&str>) -> Result<()> {
+        let fields = self.fields_from_row(row, vec!["".into(); self.notetype.fields.len()]);
+        let ords = self.ords_to_generate(&fields);
+        if ords.is_empty() {
+            self.log.without_cards += 1;
+            return Ok(());
+        }
+
+        let guid = match guid {
+            Some(guid) if !self.guids.contains_key(guid) => guid.into(),
+            _ => new_guid(),
+        };
+        let mut note = Note {
+            guid,
+            notetype_id: self.notetype.id,
+            mtime_secs: self.now_secs,
+            usn: self.usn,
+            tags: self.tags_from_row(row),
+            fields,
+            ..Default::default()
+        };
+        self.update_field_cache(&mut note);
+        self.storage.add_note(&mut note)?;
+        self.guids.insert(note.guid.clone(), note.id);
+        self.tags.extend(note.tags.iter().cloned());
+
+        let deck_id = match self.deck_column {
+            Some(idx) if !row[idx].is_empty() => Some(self.deck_id_for_name(&row[idx])?),
+            _ => None,
+        };
+        self.queue_cards(note.id, deck_id, &ords);
+        self.log.added += 1;
+        self.log.note_ids.push(note.id);
+
+        Ok(())
+    }
+
+    fn update_note(&mut self, mut note: Note, row: &[String]) -> Result<()> {
+        let fields = self.fields_from_row(row, note.fields.clone());
+        let ords = self.ords_to_generate(&fields);
+        if ords.is_empty() {
+            self.log.without_cards += 1;
+            return Ok(());
+        }
+        self.log.matched.push(note.fields[0].clone());
+        self.log.note_ids.push(note.id);
+
+        let tags = if self.tags_mapped {
+            self.tags_from_row(row)
+        } else {
+            let mut tags = note.tags.clone();
+            for tag in &self.opts.tags_for_updated {
+                if !tags.iter().any(|t| t.to_lowercase() == tag.to_lowercase()) {
+                    tags.push(tag.clone());
+                }
+            }
+            tags
+        };
+        // like the legacy importer, tags that aren't mapped are only changed
+        // if the fields are
+        if fields != note.fields || (self.tags_mapped && tags != note.tags) {
+            note.fields = fields;
+            note.tags = tags;
+            note.mtime_secs = self.now_secs;
+            note.usn = self.usn;
+            self.update_field_cache(&mut note);
+            self.storage.update_note(&note)?;
+            self.tags.extend(note.tags.iter().cloned());
+            self.log.updated += 1;
+        } else {
+            self.log.unchanged += 1;
+        }
+
+        // the changed fields may need more cards, which go in the deck the
+        // existing cards are in
+        let existing = self.storage.get_cards_of_note(note.id)?;
+        let existing_ords: HashSet<_> = existing.iter().map(|c| c.ordinal).collect();
+        let missing: Vec<_> = ords
+            .into_iter()
+            .filter(|ord| !existing_ords.contains(ord))
+            .collect();
+        if !missing.is_empty() {
+            let deck_id = existing.first().map(|card| {
+                if card.original_deck_id != 0 {
+                    card.original_deck_id
+                } else {
+                    card.deck_id
+                }
+            });
+            self.queue_cards(note.id, deck_id, &missing);
+        }
+
+        Ok(())
+    }
+
+    /// The deck a template's cards are added to, if it overrides the deck.
+    fn template_deck(&self, ord: u16) -> Option<i64> {
+        let template = self.notetype.templates.get(ord as usize)?;
+        let deck_id = template.other.get("did").and_then(Value::as_i64)?;
+        self.decks
+            .get(&deck_id)
+            .filter(|deck| !deck.is_filtered())
+            .map(|deck| deck.id)
+    }
+
+    /// The id of the deck with the provided name, which is created along with
+    /// its parents if it doesn't exist. Filtered decks can't have cards added
+    /// to them, so the default deck is used instead.
+    fn deck_id_for_name(&mut self, name: &str) -> Result<i64> {
+        let name: Vec<_> = name
+            .split("::")
+            .map(str::trim)
+            .filter(|component| !component.is_empty())
+            .collect();
+        let name = name.join("::");
+        let key = name.to_lowercase();
+        if let Some(id) = self.decks_by_name.get(&key) {
+            return Ok(*id);
+        }
+
+        let existing = self
+            .decks
+            .values()
+            .find(|deck| deck.name.to_lowercase() == key);
+        let id = match existing {
+            Some(deck) if deck.is_filtered() => self.opts.deck_id,
+            Some(deck) => deck.id,
+            None if name.is_empty() => self.opts.deck_id,
+            None => {
+                if let Some(idx) = name.rfind("::") {
+                    self.deck_id_for_name(&name[..idx])?;
+                }
+                self.add_deck(name)?
+            }
+        };
+
+        self.decks_by_name.insert(key, id);
+        Ok(id)
+    }
+
+    /// Add a deck using the default deck's options, but none of its state.
+    fn add_deck(&mut self, name: String) -> Result<i64> {
+        let mut deck = self
+            .decks
+            .get(&self.opts.deck_id)
+            .filter(|deck| !deck.is_filtered())
+            .or_else(|| self.decks.get(&1))
+            .ok_or_else(|| AnkiError::invalid_input("deck not found"))?
+            .blank_copy(name, self.now_secs, self.usn);
+        deck.id = now_millis();
+        while self.decks.contains_key(&deck.id) {
+            deck.id += 1;
+        }
+        self.storage.add_or_update_deck(&deck)?;
+
+        let id = deck.id;
+        self.decks.insert(id, deck);
+        Ok(id)
+    }
+
+    /// True if the default deck's options show new cards in random order.
+    fn random_order(&self) -> Result<bool> {
+        let conf_id = self
+            .decks
+            .get(&self.opts.deck_id)
+            .and_then(Deck::config_id)
+            .unwrap_or(1);
+        let order = self
+            .storage
+            .get_deck_conf(conf_id)?
+            .and_then(|conf| conf.other.get("new")?.get("order")?.as_i64());
+        Ok(order == Some(0))
+    }
+
+    /// Add the queued cards, giving the cards of each note the same new
+    /// card position.
+    fn add_cards(&mut self) -> Result<()> {
+        let start: i64 = self.storage.get_config_value("nextPos")?.unwrap_or(1);
+        let end = start + self.new_cards.len() as i64;
+        let mut positions: Vec<_> = (start..end).collect();
+        if self.random_order()? {
+            positions.shuffle(&mut rand::thread_rng());
+        }
+        for (cards, position) in self.new_cards.iter_mut().zip(positions) {
+            for card in cards {
+                card.due = position;
+                self.storage.add_card(card)?;
+            }
+        }
+        self.storage.set_config_value("nextPos", &end)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::err::Result;
+    use crate::import_export::text::{
+        import_text_file, read_text_file, ColumnMapping, RowProblem, TextDuplicateMode,
+        TextImportOptions,
+    };
+    use crate::storage::testutil::{add_basic_notetype, deck, open_test_collection};
+    use crate::storage::SqliteStorage;
+    use serde_json::json;
+    use std::fs;
+    use tempfile::{tempdir, TempDir};
+
+    #[test]
+    fn test_read_text_file() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("notes.txt");
+
+        fs::write(
+            &path,
+            "# a comment\ntags:one two\nfoo,\"bar, baz\"\n\"multi\nline\",qux\n",
+        )?;
+        let file = read_text_file(&path, None, None)?;
+        assert_eq!(file.delimiter, ',');
+        assert_eq!(file.encoding, "UTF-8");
+        assert_eq!(file.tags, vec!["one", "two"]);
+        assert_eq!(file.column_count, 2);
+        assert_eq!(
+            file.rows,
+            vec![vec!["foo", "bar, baz"], vec!["multi\nline", "qux"]]
+        );
+        assert_eq!(read_text_file(&path, None, Some(1))?.rows.len(), 1);
+        // a delimiter can be forced
+        let file = read_text_file(&path, Some(';'), None)?;
+        assert_eq!(file.rows[0], vec!["foo,\"bar, baz\""]);
+
+        // semicolons appear on every line, commas only on some
+        fs::write(&path, b"caf\xe9;b,c\nd;e\nf;g\n")?;
+        let file = read_text_file(&path, None, None)?;
+        assert_eq!(file.delimiter, ';');
+        assert_eq!(file.encoding, "windows-1252");
+        assert_eq!(file.rows[0], vec!["café", "b,c"]);
+
+        // a byte order mark decides the encoding
+        fs::write(&path, b"\xef\xbb\xbfone\ttwo\n")?;
+        let file = read_text_file(&path, None, None)?;
+        assert_eq!(file.delimiter, '\t');
+        assert_eq!(file.rows, vec![vec!["one", "two"]]);
+
+        // with no delimiter, each line is a single field
+        fs::write(&path, "one\ntwo\n")?;
+        let file = read_text_file(&path, None, None)?;
+        assert_eq!(file.column_count, 1);
+
+        Ok(())
+    }
+
+    /// A collection with a Front/Back notetype and a default deck that adds
+    /// new cards in order.
+    fn collection() -> Result<(TempDir, SqliteStorage)> {
+        let (dir, storage) = open_test_collection()?;
+        add_basic_notetype(&storage)?;
+        let mut deck = deck(1, "Default");
+        deck.other.insert("desc".into(), "".into());
+        deck.other.insert("newToday".into(), json!([3, 5]));
+        storage.add_or_update_deck(&deck)?;
+        storage.db.execute_batch(
+            r#"update col set dconf = '{"1": {"id": 1, "name": "Default", "mod": 0, "usn": 0,
+"new": {"order": 1}}}'"#,
+        )?;
+        Ok((dir, storage))
+    }
+
+    fn options(
+        columns: Vec<ColumnMapping>,
+        duplicate_mode: TextDuplicateMode,
+    ) -> TextImportOptions {
+        TextImportOptions {
+            notetype_id: 1,
+            deck_id: 1,
+            delimiter: None,
+            columns,
+            allow_html: false,
+            duplicate_mode,
+            tags_for_updated: vec![],
+        }
+    }
+
+    #[test]
+    fn test_import_text_file() -> Result<()> {
+        let (dir, mut storage) = collection()?;
+        let path = dir.path().join("notes.txt");
+        fs::write(
+            &path,
+            "one\tuno\ntwo\t<b>dos</b>\none\tagain\nthree\ttres\textra\n\tnada\nfour\t\n",
+        )?;
+        let mut opts = options(
+            vec![ColumnMapping::Field(0), ColumnMapping::Field(1)],
+            TextDuplicateMode::Update,
+        );
+
+        let log = import_text_file(&mut storage, &path, &opts)?;
+        assert_eq!(log.added, 3);
+        assert_eq!(
+            log.problems,
+            vec![
+                RowProblem::Repeated("one".into()),
+                RowProblem::WrongFieldCount(vec!["three".into(), "tres".into(), "extra".into()]),
+                RowProblem::EmptyFirstField(vec!["".into(), "nada".into()]),
+            ]
+        );
+        let note = storage.get_note(log.note_ids[1])?.unwrap();
+        assert_eq!(note.fields, vec!["two", "&lt;b&gt;dos&lt;/b&gt;"]);
+        assert_eq!(note.sort_field, "two");
+        let card = storage.get_cards_of_note(note.id)?.remove(0);
+        assert_eq!((card.deck_id, card.due), (1, 2));
+        assert_eq!(storage.get_config_value::<i64>("nextPos")?, Some(4));
+
+        // importing again matches the notes, and only changes the ones
+        // that differ
+        opts.allow_html = true;
+        let log = import_text_file(&mut storage, &path, &opts)?;
+        assert_eq!((log.added, log.updated, log.unchanged), (0, 1, 2));
+        assert_eq!(log.matched, vec!["one", "two", "four"]);
+        assert_eq!(storage.get_note(note.id)?.unwrap().fields[1], "<b>dos</b>");
+        assert_eq!(storage.card_ids_in_decks(None)?.len(), 3);
+
+        opts.duplicate_mode = TextDuplicateMode::Skip;
+        let log = import_text_file(&mut storage, &path, &opts)?;
+        assert_eq!((log.added, log.unchanged), (0, 3));
+
+        // duplicates include the repeated row
+        opts.duplicate_mode = TextDuplicateMode::Duplicate;
+        let log = import_text_file(&mut storage, &path, &opts)?;
+        assert_eq!(log.added, 4);
+        assert_eq!(log.matched, vec!["one", "two", "four"]);
+        assert_eq!(storage.all_note_ids()?.len(), 7);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_guid_deck_and_tag_columns() -> Result<()> {
+        let (dir, mut storage) = collection()?;
+        let path = dir.path().join("notes.csv");
+        fs::write(
+            &path,
+            "tags:file\nabc,one,Spanish::Verbs,a b\nxyz,two,,\nabc,three,,\n",
+        )?;
+        let mut opts = options(
+            vec![
+                ColumnMapping::Guid,
+                ColumnMapping::Field(0),
+                ColumnMapping::Deck,
+                ColumnMapping::Tags,
+            ],
+            TextDuplicateMode::Update,
+        );
+
+        let log = import_text_file(&mut storage, &path, &opts)?;
+        assert_eq!(log.added, 2);
+        assert_eq!(log.problems, vec![RowProblem::Repeated("abc".into())]);
+        let note = storage.get_note(log.note_ids[0])?.unwrap();
+        assert_eq!(note.guid, "abc");
+        assert_eq!(note.tags, vec!["file", "a", "b"]);
+        let decks = storage.get_all_decks()?;
+        let deck = &decks[&storage.get_cards_of_note(note.id)?[0].deck_id];
+        assert_eq!(deck.name, "Spanish::Verbs");
+        assert_eq!(deck.other["newToday"], json!([0, 0]));
+        assert!(decks.values().any(|d| d.name == "Spanish"));
+        let note = storage.get_note(log.note_ids[1])?.unwrap();
+        assert_eq!(note.guid, "xyz");
+        assert_eq!(storage.get_cards_of_note(note.id)?[0].deck_id, 1);
+
+        // the guid matches even though the first field differs, and the
+        // updated note's tags are replaced
+        fs::write(&path, "abc,uno,,c\n")?;
+        let log = import_text_file(&mut storage, &path, &opts)?;
+        assert_eq!(log.updated, 1);
+        let note = storage.get_note(log.note_ids[0])?.unwrap();
+        assert_eq!(note.fields[0], "uno");
+        assert_eq!(note.tags, vec!["c"]);
+
+        // if tags aren't mapped, the provided tags are added to updated notes
+        fs::write(&path, "abc,eins\n")?;
+        opts.columns = vec![ColumnMapping::Guid, ColumnMapping::Field(0)];
+        opts.tags_for_updated = vec!["new".into()];
+        import_text_file(&mut storage, &path, &opts)?;
+        let note = storage.get_note(note.id)?.unwrap();
+        assert_eq!(note.tags, vec!["c", "new"]);
+
+        // the first field must be mapped
+        opts.columns = vec![ColumnMapping::Guid];
+        assert!(import_text_file(&mut storage, &path, &opts).is_err());
+
+        Ok(())
+    }
+}
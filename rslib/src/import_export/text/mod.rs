@@ -0,0 +1,15 @@
+// Copyright: Ankitects Pty Ltd and contributors
+// License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+//! Notes as delimited text, one note per line, such as the CSV files
+//! spreadsheets export. Lines starting with # are comments, and a first
+//! line starting with "tags:" lists tags to add to every note.
+
+mod export;
+mod import;
+
+pub use export::{export_notes_as_text, TextExportOptions};
+pub use import::{
+    import_text_file, read_text_file, ColumnMapping, RowProblem, TextDuplicateMode, TextFile,
+    TextImportLog, TextImportOptions,
+};
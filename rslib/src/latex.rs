@@ -0,0 +1,381 @@
+// Copyright: Ankitects Pty Ltd and contributors
+// License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+use crate::err::LatexError;
+use crate::text::{decode_entities, strip_html, MediaRef};
+use lazy_static::lazy_static;
+use regex::{Captures, Regex};
+use std::borrow::Cow;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::process::Command;
+
+lazy_static! {
+    static ref LATEX: Regex = Regex::new(
+        r#"(?xsi)
+            \[latex\](.+?)\[/latex\]     # 1 - standard latex
+            |
+            \[\$\](.+?)\[/\$\]           # 2 - inline math
+            |
+            \[\$\$\](.+?)\[/\$\$\]       # 3 - math environment
+        "#
+    )
+    .unwrap();
+    static ref LATEX_NEWLINES: Regex = Regex::new(
+        r#"(?x)
+            <br( /)?>
+            |
+            <div>
+        "#
+    )
+    .unwrap();
+}
+
+#[derive(Debug, PartialEq)]
+pub struct ExtractedLatex {
+    /// The image filename, based on a checksum of the latex.
+    pub fname: String,
+    /// The latex to be rendered, without the header/footer.
+    pub latex: String,
+}
+
+/// Replace latex tags in the provided text with image references,
+/// returning the updated text and the latex that was found.
+pub fn extract_latex(text: &str, svg: bool) -> (Cow<str>, Vec<ExtractedLatex>) {
+    let mut extracted = vec![];
+
+    let new_text = LATEX.replace_all(text, |caps: &Captures| {
+        let latex = latex_from_caps(caps);
+        let fname = fname_for_latex(&latex, svg);
+        let img_link = format!(r#"<img class=latex src="{}">"#, fname);
+        extracted.push(ExtractedLatex { fname, latex });
+
+        img_link
+    });
+
+    (new_text, extracted)
+}
+
+/// The images that latex in the provided text would be rendered into.
+/// Each span covers the latex tag the image was generated from.
+pub(crate) fn latex_media_refs(text: &str, svg: bool) -> Vec<MediaRef<'static>> {
+    LATEX
+        .captures_iter(text)
+        .map(|caps| {
+            let latex = latex_from_caps(&caps);
+            MediaRef {
+                fname: fname_for_latex(&latex, svg).into(),
+                span: caps.get(0).unwrap().range(),
+                is_latex: true,
+            }
+        })
+        .collect()
+}
+
+fn latex_from_caps(caps: &Captures) -> String {
+    if let Some(expr) = caps.get(1) {
+        latex_from_html(expr.as_str())
+    } else if let Some(expr) = caps.get(2) {
+        format!("${}$", latex_from_html(expr.as_str()))
+    } else {
+        format!(
+            r"\begin{{displaymath}}{}\end{{displaymath}}",
+            latex_from_html(caps.get(3).unwrap().as_str())
+        )
+    }
+}
+
+/// Convert line breaks to newlines, and strip other HTML.
+fn latex_from_html(text: &str) -> String {
+    let text = LATEX_NEWLINES.replace_all(text, "\n");
+    let text = strip_html(&text).replace("&nbsp;", " ");
+    decode_entities(&text).into()
+}
+
+fn fname_for_latex(latex: &str, svg: bool) -> String {
+    let ext = if svg { "svg" } else { "png" };
+    let csum = hex::encode(sha1::Sha1::from(latex).digest().bytes());
+
+    format!("latex-{}.{}", csum, ext)
+}
+
+// Building images
+//----------------------------------------
+
+const PNG_COMMANDS: &[&[&str]] = &[
+    &["latex", "-interaction=nonstopmode", "tmp.tex"],
+    &[
+        "dvipng", "-D", "200", "-T", "tight", "tmp.dvi", "-o", "tmp.png",
+    ],
+];
+
+const SVG_COMMANDS: &[&[&str]] = &[
+    &["latex", "-interaction=nonstopmode", "tmp.tex"],
+    &[
+        "dvisvgm",
+        "--no-fonts",
+        "--exact",
+        "-Z",
+        "2",
+        "tmp.dvi",
+        "-o",
+        "tmp.svg",
+    ],
+];
+
+/// Commands that could be used to read or write arbitrary files. Latex is
+/// only really secure when run in a jail, but these are the most common.
+const FORBIDDEN_COMMANDS: &[&str] = &[
+    r"\write18",
+    r"\readline",
+    r"\input",
+    r"\include",
+    r"\catcode",
+    r"\openout",
+    r"\write",
+    r"\loop",
+    r"\def",
+    r"\shipout",
+];
+
+pub struct LatexOptions<'a> {
+    /// Build SVG images with dvisvgm instead of PNG images with dvipng.
+    pub svg: bool,
+    /// Added before and after the latex of each image.
+    pub header: &'a str,
+    pub footer: &'a str,
+    /// If false, existing images are used, but missing ones are not built,
+    /// and their latex is shown instead.
+    pub build: bool,
+    /// The commands to run in the build folder, each a program followed by
+    /// its arguments. If empty, latex and dvipng/dvisvgm are used.
+    pub commands: Vec<Vec<String>>,
+}
+
+/// An image that could not be built. Its link is left in the text.
+#[derive(Debug, PartialEq)]
+pub struct LatexImageError {
+    pub fname: String,
+    pub error: LatexError,
+}
+
+/// Replace latex tags in the provided text with image references, building
+/// any images not already in the media folder. Since images are named after
+/// a checksum of their latex, existing images are reused.
+pub fn render_latex<'a>(
+    text: &'a str,
+    media_folder: &Path,
+    opts: &LatexOptions,
+) -> (Cow<'a, str>, Vec<LatexImageError>) {
+    let (mut text, extracted) = extract_latex(text, opts.svg);
+    let mut errors = vec![];
+
+    for latex in extracted {
+        if media_folder.join(&latex.fname).exists() {
+            continue;
+        }
+
+        if !opts.build {
+            let link = format!(r#"<img class=latex src="{}">"#, latex.fname);
+            let tag = format!("[latex]{}[/latex]", latex.latex);
+            text = text.replace(&link, &tag).into();
+            continue;
+        }
+
+        if let Err(error) = build_image(&latex, media_folder, opts) {
+            errors.push(LatexImageError {
+                fname: latex.fname,
+                error,
+            });
+        }
+    }
+
+    (text, errors)
+}
+
+/// Render the latex into an image in a temporary folder, then copy it into
+/// the media folder.
+fn build_image(
+    latex: &ExtractedLatex,
+    media_folder: &Path,
+    opts: &LatexOptions,
+) -> Result<(), LatexError> {
+    let full_latex = format!("{}\n{}\n{}", opts.header, latex.latex, opts.footer);
+    check_for_forbidden_commands(&full_latex)?;
+
+    let dir = tempfile::tempdir()?;
+    fs::write(dir.path().join("tmp.tex"), &full_latex)?;
+
+    let default_commands = if opts.svg { SVG_COMMANDS } else { PNG_COMMANDS };
+    let commands: Vec<Vec<&str>> = if opts.commands.is_empty() {
+        default_commands.iter().map(|c| c.to_vec()).collect()
+    } else {
+        opts.commands
+            .iter()
+            .map(|c| c.iter().map(String::as_str).collect())
+            .collect()
+    };
+
+    let mut log = String::new();
+    for command in commands {
+        let (program, args) = match command.split_first() {
+            Some(split) => split,
+            None => continue,
+        };
+        let output = match Command::new(program)
+            .args(args)
+            .current_dir(dir.path())
+            .output()
+        {
+            Ok(output) => output,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                return Err(LatexError::CommandNotFound(program.to_string()))
+            }
+            Err(e) => return Err(e.into()),
+        };
+        log.push_str(&String::from_utf8_lossy(&output.stdout));
+        log.push_str(&String::from_utf8_lossy(&output.stderr));
+        if !output.status.success() {
+            return Err(LatexError::CommandFailed {
+                command: program.to_string(),
+                log,
+            });
+        }
+    }
+
+    let ext = if opts.svg { "svg" } else { "png" };
+    fs::copy(
+        dir.path().join(format!("tmp.{}", ext)),
+        media_folder.join(&latex.fname),
+    )?;
+
+    Ok(())
+}
+
+/// Commands are only forbidden when not followed by another letter, so
+/// eg \defeq is allowed.
+fn check_for_forbidden_commands(latex: &str) -> Result<(), LatexError> {
+    let latex = latex.replace(r"\includegraphics", "");
+    for command in FORBIDDEN_COMMANDS {
+        let found = latex.match_indices(command).any(|(idx, _)| {
+            latex[idx + command.len()..]
+                .chars()
+                .next()
+                .map(|c| !c.is_ascii_alphabetic())
+                .unwrap_or(false)
+        });
+        if found {
+            return Err(LatexError::ForbiddenCommand((*command).into()));
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::err::{LatexError, Result};
+    use crate::latex::{
+        check_for_forbidden_commands, extract_latex, latex_media_refs, render_latex,
+        ExtractedLatex, LatexImageError, LatexOptions,
+    };
+    use std::fs;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_latex() {
+        let fname = "latex-ef30b3f4141c33a5bf7044b0d1961d3399c05d50.png";
+        assert_eq!(
+            extract_latex("a[latex]one<br>and<div>two[/latex]b", false),
+            (
+                format!("a<img class=latex src=\"{}\">b", fname).into(),
+                vec![ExtractedLatex {
+                    fname: fname.into(),
+                    latex: "one\nand\ntwo".into()
+                }]
+            )
+        );
+        // like the Python code, only lowercase tags become newlines
+        assert_eq!(
+            extract_latex("[latex]one<BR>two[/latex]", false).1[0].latex,
+            "onetwo"
+        );
+
+        assert_eq!(
+            extract_latex("[$]&lt;&lt;[/$]", true).1,
+            vec![ExtractedLatex {
+                fname: "latex-0027ee7f31929b403a40c9390caa46223961bf45.svg".into(),
+                latex: "$<<$".into()
+            }]
+        );
+
+        assert_eq!(
+            extract_latex("[$$]x[/$$]", false).1[0].latex,
+            r"\begin{displaymath}x\end{displaymath}"
+        );
+
+        let refs = latex_media_refs("a[latex]one<br>and<div>two[/latex]", false);
+        assert_eq!(refs[0].fname, fname);
+        assert_eq!(refs[0].span, 1..34);
+    }
+
+    #[test]
+    fn test_forbidden_commands() {
+        assert_eq!(
+            check_for_forbidden_commands("\\write18{rm}\n"),
+            Err(LatexError::ForbiddenCommand("\\write18".into()))
+        );
+        assert_eq!(
+            check_for_forbidden_commands("\\def\n"),
+            Err(LatexError::ForbiddenCommand("\\def".into()))
+        );
+        assert_eq!(check_for_forbidden_commands("\\defeq\n"), Ok(()));
+        assert_eq!(
+            check_for_forbidden_commands("\\includegraphics{a}\n"),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn test_render() -> Result<()> {
+        let dir = tempdir()?;
+        let media_folder = dir.path();
+        let text = "[latex]one[/latex][latex]two[/latex]";
+        let fnames: Vec<_> = extract_latex(text, false)
+            .1
+            .into_iter()
+            .map(|l| l.fname)
+            .collect();
+        fs::write(media_folder.join(&fnames[0]), "")?;
+
+        let mut opts = LatexOptions {
+            svg: false,
+            header: "",
+            footer: "",
+            build: false,
+            commands: vec![vec!["nonexistent-latex-command".into()]],
+        };
+
+        // existing images are used, and missing ones are left as latex
+        let (html, errors) = render_latex(text, media_folder, &opts);
+        assert_eq!(
+            html,
+            format!(r#"<img class=latex src="{}">[latex]two[/latex]"#, fnames[0])
+        );
+        assert_eq!(errors, vec![]);
+
+        // build failures are reported
+        opts.build = true;
+        let (_, errors) = render_latex(text, media_folder, &opts);
+        assert_eq!(
+            errors,
+            vec![LatexImageError {
+                fname: fnames[1].clone(),
+                error: LatexError::CommandNotFound("nonexistent-latex-command".into()),
+            }]
+        );
+
+        Ok(())
+    }
+}
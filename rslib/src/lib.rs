@@ -4,8 +4,37 @@
 mod backend_proto;
 
 pub mod backend;
+pub mod backup;
+pub mod browser_rows;
+pub mod bulk;
+pub mod card;
+pub mod cardgen;
+pub mod cloze;
+pub mod collection;
+pub mod dbcheck;
+pub mod decks;
+pub mod dupes;
 pub mod err;
+pub mod findreplace;
+pub mod html;
+pub mod i18n;
+pub mod import_export;
+pub mod latex;
+pub mod log;
+pub mod markdown;
+pub mod media;
+pub mod notes;
+pub mod notetypes;
+pub mod revlog;
+pub mod ruby;
 pub mod sched;
+pub mod search;
+pub mod stats;
+pub mod storage;
+pub mod sync;
+pub mod tags;
 pub mod template;
 pub mod template_filters;
 pub mod text;
+pub mod typeanswer;
+pub mod undo;
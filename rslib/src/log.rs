@@ -0,0 +1,266 @@
+// Copyright: Ankitects Pty Ltd and contributors
+// License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+//! Logging to a file in the profile folder, so the steps leading up to a
+//! problem a user reports can be traced. Code logs with the macros of the
+//! `tracing` crate, giving the details of an event as fields, eg
+//! `debug!(method, sent, "sync request")`, which are written after the
+//! message as key=value pairs. The file is rotated when it grows too
+//! large, keeping a few of the previous files.
+
+use crate::err::{AnkiError, Result};
+use chrono::Local;
+use lazy_static::lazy_static;
+use std::env;
+use std::fmt::{self, Write as _};
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::subscriber::{self, Interest, Subscriber};
+use tracing::{Event, Level, Metadata};
+
+/// The environment variable that sets the level if none is provided, eg
+/// "debug". Defaults to "info".
+pub const LOG_LEVEL_VAR: &str = "ANKI_LOG";
+
+/// Files are rotated once they grow past this size.
+const MAX_LOG_SIZE: u64 = 10 * 1024 * 1024;
+/// The number of rotated files kept, named like anki.log.1, where higher
+/// numbers are older.
+const MAX_OLD_LOGS: usize = 4;
+
+lazy_static! {
+    /// Where events are written, if logging is on.
+    static ref OUTPUT: Mutex<Option<LogOutput>> = Mutex::new(None);
+}
+
+struct LogOutput {
+    file: LogFile,
+    /// The least severe level written.
+    level: Level,
+}
+
+/// Start logging to the file at `path`, or stop logging if no path is
+/// provided. `level` is a level name like "info" or "off"; if it's empty,
+/// the level is read from ANKI_LOG instead, ignoring invalid values.
+pub fn set_up_logging(path: Option<&Path>, level: &str) -> Result<()> {
+    let level = if level.is_empty() {
+        env::var(LOG_LEVEL_VAR)
+            .ok()
+            .and_then(|level| parse_level(&level).ok())
+            .unwrap_or(Some(Level::INFO))
+    } else {
+        parse_level(level)?
+    };
+
+    let output = match (path, level) {
+        (Some(path), Some(level)) => Some(LogOutput {
+            file: LogFile::open(path, MAX_LOG_SIZE)?,
+            level,
+        }),
+        _ => None,
+    };
+    *OUTPUT.lock().unwrap() = output;
+    // fails if the subscriber has already been installed, which is harmless
+    let _ = subscriber::set_global_default(FileSubscriber::default());
+
+    Ok(())
+}
+
+/// The level named `name`, or None if it's "off".
+fn parse_level(name: &str) -> Result<Option<Level>> {
+    Ok(Some(match name.to_ascii_lowercase().as_str() {
+        "off" => return Ok(None),
+        "error" => Level::ERROR,
+        "warn" => Level::WARN,
+        "info" => Level::INFO,
+        "debug" => Level::DEBUG,
+        "trace" => Level::TRACE,
+        _ => {
+            return Err(AnkiError::invalid_input(format!(
+                "invalid log level: {}",
+                name
+            )))
+        }
+    }))
+}
+
+/// Writes events to [OUTPUT]. Spans are accepted but not recorded.
+#[derive(Default)]
+struct FileSubscriber {
+    last_span_id: AtomicU64,
+}
+
+impl Subscriber for FileSubscriber {
+    fn register_callsite(&self, _metadata: &'static Metadata<'static>) -> Interest {
+        // the level can change, so the answer can't be cached
+        Interest::sometimes()
+    }
+
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        OUTPUT
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|output| metadata.level() <= &output.level)
+            .unwrap_or_default()
+    }
+
+    fn new_span(&self, _span: &Attributes) -> Id {
+        Id::from_u64(self.last_span_id.fetch_add(1, Ordering::Relaxed) + 1)
+    }
+
+    fn record(&self, _span: &Id, _values: &Record) {}
+
+    fn record_follows_from(&self, _span: &Id, _follows: &Id) {}
+
+    fn event(&self, event: &Event) {
+        let metadata = event.metadata();
+        let mut line = format!(
+            "{} {} {}:",
+            Local::now().format("%Y-%m-%d %H:%M:%S%.3f"),
+            metadata.level(),
+            metadata.target()
+        );
+        event.record(&mut FieldWriter { line: &mut line });
+        line.push('\n');
+
+        if let Some(output) = OUTPUT.lock().unwrap().as_mut() {
+            // there's nowhere to report a failure to log
+            let _ = output.file.write_line(&line);
+        }
+    }
+
+    fn enter(&self, _span: &Id) {}
+
+    fn exit(&self, _span: &Id) {}
+}
+
+/// Appends the message of an event, then its other fields as key=value.
+struct FieldWriter<'a> {
+    line: &'a mut String,
+}
+
+impl Visit for FieldWriter<'_> {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        let _ = if field.name() == "message" {
+            write!(self.line, " {:?}", value)
+        } else {
+            write!(self.line, " {}={:?}", field.name(), value)
+        };
+    }
+}
+
+struct LogFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_size: u64,
+}
+
+impl LogFile {
+    fn open(path: &Path, max_size: u64) -> Result<LogFile> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+        Ok(LogFile {
+            path: path.to_owned(),
+            file,
+            size,
+            max_size,
+        })
+    }
+
+    fn write_line(&mut self, line: &str) -> Result<()> {
+        if self.size > 0 && self.size + line.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+        self.file.write_all(line.as_bytes())?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    /// Move the current file to .1, .1 to .2 and so on, dropping the
+    /// oldest, and start a new file.
+    fn rotate(&mut self) -> Result<()> {
+        for idx in (1..MAX_OLD_LOGS).rev() {
+            let older = old_log_path(&self.path, idx);
+            if older.exists() {
+                fs::rename(&older, old_log_path(&self.path, idx + 1))?;
+            }
+        }
+        fs::rename(&self.path, old_log_path(&self.path, 1))?;
+        *self = LogFile::open(&self.path, self.max_size)?;
+        Ok(())
+    }
+}
+
+fn old_log_path(path: &Path, idx: usize) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_owned();
+    name.push(format!(".{}", idx));
+    path.with_file_name(name)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::err::Result;
+    use crate::log::{old_log_path, set_up_logging, LogFile, MAX_OLD_LOGS};
+    use std::fs;
+    use tempfile::tempdir;
+    use tracing::{debug, info, warn};
+
+    #[test]
+    fn test_rotation() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("anki.log");
+        let mut file = LogFile::open(&path, 10)?;
+        for idx in 0..=MAX_OLD_LOGS + 1 {
+            file.write_line(&format!("line {}\n", idx))?;
+        }
+        assert_eq!(fs::read_to_string(&path)?, "line 5\n");
+        assert_eq!(fs::read_to_string(old_log_path(&path, 1))?, "line 4\n");
+        assert_eq!(fs::read_to_string(old_log_path(&path, 4))?, "line 1\n");
+        assert!(!old_log_path(&path, 5).exists());
+
+        // appends to an existing file
+        drop(file);
+        let mut file = LogFile::open(&path, 20)?;
+        file.write_line("line 6\n")?;
+        assert_eq!(fs::read_to_string(&path)?, "line 5\nline 6\n");
+
+        Ok(())
+    }
+
+    // other tests may log while this runs, so it only checks for the lines
+    // it logs itself
+    #[test]
+    fn test_set_up_logging() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("anki.log");
+
+        set_up_logging(Some(&path), "info")?;
+        info!(count = 2, name = "x", "test info");
+        debug!("test debug");
+        let text = fs::read_to_string(&path)?;
+        assert!(text.contains(" INFO anki::log::test: test info count=2 name=\"x\"\n"));
+        assert!(!text.contains("test debug"));
+
+        set_up_logging(Some(&path), "debug")?;
+        debug!("test debug");
+        assert!(fs::read_to_string(&path)?.contains("test debug"));
+
+        set_up_logging(Some(&path), "off")?;
+        warn!("test warn while off");
+        set_up_logging(None, "debug")?;
+        warn!("test warn without a file");
+        let text = fs::read_to_string(&path)?;
+        assert!(!text.contains("test warn"));
+
+        assert!(set_up_logging(Some(&path), "loud").is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,111 @@
+// Copyright: Ankitects Pty Ltd and contributors
+// License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+use crate::text::decode_entities;
+use lazy_static::lazy_static;
+use pulldown_cmark::{html, CowStr, Event, Options, Parser};
+use regex::{Captures, Regex};
+use std::borrow::Cow;
+
+lazy_static! {
+    // line breaks inserted by the editor
+    static ref EDITOR_LINE_BREAK: Regex = Regex::new(r"(?i)<br\s*/?>|</div>").unwrap();
+    static ref EDITOR_DIV: Regex = Regex::new(r"(?i)<div>").unwrap();
+
+    static ref MATHJAX: Regex = Regex::new(r"(?s)\\\(.*?\\\)|\\\[.*?\\\]").unwrap();
+    static ref PLACEHOLDER: Regex = Regex::new("\u{e000}(\\d+)\u{e001}").unwrap();
+}
+
+/// Render a field written in Markdown into HTML.
+///
+/// Line breaks added by the editor are converted into newlines first, and
+/// the entities the editor escaped the typed text with are decoded, so `<`,
+/// `>` and `&` reach the Markdown parser as they were typed.
+/// MathJax expressions are protected from Markdown processing, so
+/// backslashes and underscores inside them are passed through as-is.
+pub fn render_markdown(text: &str) -> String {
+    let text = EDITOR_LINE_BREAK.replace_all(text, "\n");
+    let text = EDITOR_DIV.replace_all(&text, "");
+    let text = decode_entities(&text).replace('\u{a0}', " ");
+
+    // swap mathjax out for placeholders
+    let mut math = vec![];
+    let text = MATHJAX.replace_all(&text, |caps: &Captures| {
+        math.push(caps[0].to_string());
+        format!("\u{e000}{}\u{e001}", math.len() - 1)
+    });
+
+    let parser = Parser::new_ext(
+        &text,
+        Options::ENABLE_TABLES | Options::ENABLE_STRIKETHROUGH,
+    )
+    .map(|event| match event {
+        Event::Text(text) => Event::Text(restore_math(text, &math)),
+        Event::Code(text) => Event::Code(restore_math(text, &math)),
+        Event::Html(text) => Event::Html(restore_math(text, &math)),
+        other => other,
+    });
+
+    let mut out = String::with_capacity(text.len() * 3 / 2);
+    html::push_html(&mut out, parser);
+
+    out.trim_end().into()
+}
+
+/// Put the original text back in place of any placeholders.
+fn restore_math<'a>(text: CowStr<'a>, math: &[String]) -> CowStr<'a> {
+    match PLACEHOLDER.replace_all(&text, |caps: &Captures| {
+        caps[1]
+            .parse::<usize>()
+            .ok()
+            .and_then(|idx| math.get(idx))
+            .cloned()
+            .unwrap_or_default()
+    }) {
+        Cow::Borrowed(_) => text,
+        Cow::Owned(s) => s.into(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::markdown::render_markdown;
+
+    #[test]
+    fn test_markdown() {
+        assert_eq!(render_markdown("*foo*"), "<p><em>foo</em></p>");
+        assert_eq!(
+            render_markdown("# heading<br>- one<br>- two"),
+            "<h1>heading</h1>\n<ul>\n<li>one</li>\n<li>two</li>\n</ul>"
+        );
+        // editor divs
+        assert_eq!(
+            render_markdown("<div>**a**</div><div>b</div>"),
+            "<p><strong>a</strong>\nb</p>"
+        );
+
+        // mathjax should survive intact
+        assert_eq!(
+            render_markdown(r"\(a_1 * b_2 * c\) and \[x\]"),
+            r"<p>\(a_1 * b_2 * c\) and \[x\]</p>"
+        );
+        assert_eq!(render_markdown(r"\(a&lt;b\)"), r"<p>\(a&lt;b\)</p>");
+
+        // the editor's escaping is undone before parsing; code is escaped
+        // again on output, and mathjax inside it is left alone
+        assert_eq!(
+            render_markdown("`&lt;b&gt;` and `\\(x\\)`"),
+            r"<p><code>&lt;b&gt;</code> and <code>\(x\)</code></p>"
+        );
+        assert_eq!(
+            render_markdown("```<br>if a &lt; b &amp;&amp; c:<br>```"),
+            "<pre><code>if a &lt; b &amp;&amp; c:\n</code></pre>"
+        );
+        assert_eq!(
+            render_markdown("&gt; quote<br><br>-&nbsp;item"),
+            "<blockquote>\n<p>quote</p>\n</blockquote>\n<ul>\n<li>item</li>\n</ul>"
+        );
+        // typed html is passed through, as in any Markdown document
+        assert_eq!(render_markdown("&lt;i&gt;x&lt;/i&gt;"), "<p><i>x</i></p>");
+    }
+}
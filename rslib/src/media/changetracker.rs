@@ -0,0 +1,385 @@
+// Copyright: Ankitects Pty Ltd and contributors
+// License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+use crate::err::Result;
+use crate::media::database::{MediaDatabase, MediaEntry};
+use crate::media::files::{
+    filename_is_valid, mtime_as_i64, sha1_of_file, MEDIA_SYNC_FILESIZE_LIMIT,
+};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use tracing::info;
+
+/// The number of threads files are hashed on when the core count can't be
+/// determined.
+const FALLBACK_HASHING_THREADS: usize = 4;
+
+/// Files that were added, changed or removed since the previous scan.
+/// Both lists are sorted.
+#[derive(Debug, Default, PartialEq)]
+pub struct MediaChanges {
+    /// New or modified files.
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+}
+
+/// A file on disk whose contents may have changed since the last scan.
+struct FilesystemEntry {
+    fname: String,
+    sha1: Option<[u8; 20]>,
+    mtime: i64,
+}
+
+/// Compare the media folder with the DB, and record any changes.
+///
+/// If `force` is false and the folder's modification time matches the time
+/// of the last scan, nothing is scanned. Files are only hashed if their
+/// modification time differs from the one in the DB.
+pub(super) fn register_changes(
+    db: &mut MediaDatabase,
+    media_folder: &Path,
+    force: bool,
+) -> Result<MediaChanges> {
+    let folder_mtime = mtime_as_i64(media_folder)?;
+    let mut meta = db.get_meta()?;
+    if !force && meta.folder_mtime == folder_mtime {
+        return Ok(MediaChanges::default());
+    }
+
+    let mtimes = db.all_mtimes()?;
+    let (changed, removed) = changes_since(media_folder, mtimes)?;
+
+    db.transact(|ctx| {
+        let mut changes = MediaChanges::default();
+
+        for file in changed {
+            if let Some(existing) = ctx.get_entry(&file.fname)? {
+                if existing.sha1.is_some() && existing.sha1 == file.sha1 {
+                    // only the mtime changed; avoid rehashing next time
+                    ctx.set_entry(&MediaEntry {
+                        mtime: file.mtime,
+                        ..existing
+                    })?;
+                    continue;
+                }
+            }
+            ctx.set_entry(&MediaEntry {
+                fname: file.fname.clone(),
+                sha1: file.sha1,
+                mtime: file.mtime,
+                sync_required: true,
+            })?;
+            changes.added.push(file.fname);
+        }
+
+        for fname in removed {
+            ctx.set_entry(&MediaEntry {
+                fname: fname.clone(),
+                sha1: None,
+                mtime: 0,
+                sync_required: true,
+            })?;
+            changes.removed.push(fname);
+        }
+
+        meta.folder_mtime = folder_mtime;
+        ctx.set_meta(&meta)?;
+
+        changes.added.sort_unstable();
+        changes.removed.sort_unstable();
+        info!(
+            added = changes.added.len(),
+            removed = changes.removed.len(),
+            "media scan"
+        );
+        Ok(changes)
+    })
+}
+
+/// Scan the folder, hashing any files not present in `mtimes` or with a
+/// different modification time. Returns the changed files, in no particular
+/// order, and the names of files in `mtimes` that no longer exist.
+fn changes_since(
+    media_folder: &Path,
+    mut mtimes: HashMap<String, i64>,
+) -> Result<(Vec<FilesystemEntry>, Vec<String>)> {
+    let hasher = FileHasher::new(hashing_threads());
+    let walked = walk_folder(media_folder, &mut mtimes, &hasher);
+    // wait for the files queued before any error to be hashed
+    let changed = hasher.finish();
+    walked?;
+
+    // anything left over has been removed
+    let removed = mtimes.into_iter().map(|(fname, _)| fname).collect();
+
+    Ok((changed?, removed))
+}
+
+/// Queue the files that need hashing, removing every file found from
+/// `mtimes`.
+fn walk_folder(
+    media_folder: &Path,
+    mtimes: &mut HashMap<String, i64>,
+    hasher: &FileHasher,
+) -> Result<()> {
+    for dentry in fs::read_dir(media_folder)? {
+        let dentry = dentry?;
+
+        // skip folders
+        if dentry.file_type()?.is_dir() {
+            continue;
+        }
+
+        // skip files with invalid names; the media check will rename them
+        let fname = match dentry.file_name().into_string() {
+            Ok(name) => name,
+            Err(_) => continue,
+        };
+        if !filename_is_valid(&fname) || fname.eq_ignore_ascii_case("thumbs.db") {
+            continue;
+        }
+
+        // skip empty files and files that are too large to sync
+        let metadata = dentry.metadata()?;
+        if metadata.len() == 0 || metadata.len() > MEDIA_SYNC_FILESIZE_LIMIT {
+            continue;
+        }
+
+        // unchanged since the last scan?
+        let mtime = mtime_as_i64(dentry.path())?;
+        if let Some(previous_mtime) = mtimes.remove(&fname) {
+            if previous_mtime == mtime {
+                continue;
+            }
+        }
+
+        hasher.add(HashJob {
+            path: dentry.path(),
+            fname,
+            mtime,
+        });
+    }
+
+    Ok(())
+}
+
+/// The number of threads files are hashed on: one per core. Hashing large
+/// audio and video files is most of the time a scan takes, so it is done in
+/// parallel with walking the folder.
+fn hashing_threads() -> usize {
+    available_cores().unwrap_or(FALLBACK_HASHING_THREADS).max(1)
+}
+
+/// The number of cores online, if the system reports it.
+#[cfg(unix)]
+fn available_cores() -> Option<usize> {
+    let cores = unsafe { libc::sysconf(libc::_SC_NPROCESSORS_ONLN) };
+    if cores > 0 {
+        Some(cores as usize)
+    } else {
+        None
+    }
+}
+
+/// The number of cores online, if the system reports it.
+#[cfg(windows)]
+fn available_cores() -> Option<usize> {
+    use winapi::um::sysinfoapi::{GetSystemInfo, SYSTEM_INFO};
+
+    let mut info: SYSTEM_INFO = unsafe { std::mem::zeroed() };
+    unsafe { GetSystemInfo(&mut info) };
+    if info.dwNumberOfProcessors > 0 {
+        Some(info.dwNumberOfProcessors as usize)
+    } else {
+        None
+    }
+}
+
+#[cfg(not(any(unix, windows)))]
+fn available_cores() -> Option<usize> {
+    None
+}
+
+/// A file waiting to be hashed.
+struct HashJob {
+    path: PathBuf,
+    fname: String,
+    mtime: i64,
+}
+
+/// Hashes files on a pool of threads while the caller continues. The queue
+/// of waiting files is bounded, so a large folder is not held in memory.
+struct FileHasher {
+    jobs: SyncSender<HashJob>,
+    results: Receiver<io::Result<FilesystemEntry>>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl FileHasher {
+    fn new(threads: usize) -> Self {
+        let (jobs, queue) = mpsc::sync_channel::<HashJob>(threads * 4);
+        let queue = Arc::new(Mutex::new(queue));
+        let (results_tx, results) = mpsc::channel();
+
+        let workers = (0..threads)
+            .map(|_| {
+                let queue = Arc::clone(&queue);
+                let results = results_tx.clone();
+                thread::spawn(move || loop {
+                    // the lock is released once a job has been taken
+                    let job = match queue.lock().unwrap().recv() {
+                        Ok(job) => job,
+                        // the queue has been closed
+                        Err(_) => break,
+                    };
+                    let entry = sha1_of_file(&job.path).map(|sha1| FilesystemEntry {
+                        fname: job.fname,
+                        sha1: Some(sha1),
+                        mtime: job.mtime,
+                    });
+                    if results.send(entry).is_err() {
+                        break;
+                    }
+                })
+            })
+            .collect();
+
+        FileHasher {
+            jobs,
+            results,
+            workers,
+        }
+    }
+
+    /// Queue a file, waiting if the queue is full.
+    fn add(&self, job: HashJob) {
+        // the workers only stop early if the hasher is gone
+        let _ = self.jobs.send(job);
+    }
+
+    /// Wait for the queued files to be hashed, returning the first error
+    /// if any could not be read.
+    fn finish(self) -> io::Result<Vec<FilesystemEntry>> {
+        let FileHasher {
+            jobs,
+            results,
+            workers,
+        } = self;
+        drop(jobs);
+        let mut entries = vec![];
+        let mut error = None;
+        for result in results.iter() {
+            match result {
+                Ok(entry) => entries.push(entry),
+                Err(e) => {
+                    error.get_or_insert(e);
+                }
+            }
+        }
+        for worker in workers {
+            worker.join().expect("media hashing thread panicked");
+        }
+
+        match error {
+            Some(e) => Err(e),
+            None => Ok(entries),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::err::Result;
+    use crate::media::changetracker::MediaChanges;
+    use crate::media::MediaManager;
+    use filetime::{set_file_mtime, FileTime};
+    use std::fs;
+    use tempfile::tempdir;
+
+    fn changes(added: &[&str], removed: &[&str]) -> MediaChanges {
+        MediaChanges {
+            added: added.iter().map(ToString::to_string).collect(),
+            removed: removed.iter().map(ToString::to_string).collect(),
+        }
+    }
+
+    #[test]
+    fn test_change_tracking() -> Result<()> {
+        let dir = tempdir()?;
+        let media_dir = dir.path().join("media");
+        fs::create_dir(&media_dir)?;
+        let media_db = dir.path().join("media.db");
+        let mut mgr = MediaManager::new(&media_dir, &media_db)?;
+
+        assert_eq!(mgr.register_changes(true)?, MediaChanges::default());
+
+        // add a file, along with some that should be ignored
+        let path = media_dir.join("file.jpg");
+        fs::write(&path, "hello")?;
+        fs::write(media_dir.join("empty.jpg"), "")?;
+        fs::write(media_dir.join("Thumbs.db"), "ignored")?;
+        fs::create_dir(media_dir.join("folder"))?;
+        assert_eq!(mgr.register_changes(true)?, changes(&["file.jpg"], &[]));
+        assert_eq!(mgr.register_changes(true)?, MediaChanges::default());
+        let entry = mgr.db.get_entry("file.jpg")?.unwrap();
+        assert_eq!(
+            hex::encode(entry.sha1.unwrap()),
+            "aaf4c61ddcc5e8a2dabede0f3b482cd9aea9434d"
+        );
+        assert!(entry.sync_required);
+
+        // touching the file without changing it is not a change
+        let mtime = FileTime::from_unix_time(entry.mtime + 10, 0);
+        set_file_mtime(&path, mtime)?;
+        assert_eq!(mgr.register_changes(true)?, MediaChanges::default());
+        assert_eq!(
+            mgr.db.get_entry("file.jpg")?.unwrap().mtime,
+            entry.mtime + 10
+        );
+
+        // but altering the contents is
+        fs::write(&path, "hello2")?;
+        set_file_mtime(&path, FileTime::from_unix_time(entry.mtime + 20, 0))?;
+        assert_eq!(mgr.register_changes(true)?, changes(&["file.jpg"], &[]));
+
+        // removing it
+        fs::remove_file(&path)?;
+        assert_eq!(mgr.register_changes(true)?, changes(&[], &["file.jpg"]));
+        let entry = mgr.db.get_entry("file.jpg")?.unwrap();
+        assert_eq!(entry.sha1, None);
+        assert_eq!(entry.mtime, 0);
+        assert_eq!(mgr.register_changes(true)?, MediaChanges::default());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_many_files() -> Result<()> {
+        let dir = tempdir()?;
+        let media_dir = dir.path().join("media");
+        fs::create_dir(&media_dir)?;
+        let mut mgr = MediaManager::new(&media_dir, dir.path().join("media.db"))?;
+
+        // more files than the hashing queue holds
+        let mut fnames: Vec<_> = (0..200).map(|n| format!("{:03}.mp3", n)).collect();
+        for fname in &fnames {
+            fs::write(media_dir.join(fname), fname)?;
+        }
+        fnames.sort();
+        let added: Vec<_> = fnames.iter().map(String::as_str).collect();
+        assert_eq!(mgr.register_changes(true)?, changes(&added, &[]));
+
+        let entry = mgr.db.get_entry("123.mp3")?.unwrap();
+        assert_eq!(
+            hex::encode(entry.sha1.unwrap()),
+            "4a27bfbd1b104e6181fc8209140c8da875dfde70"
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,518 @@
+// Copyright: Ankitects Pty Ltd and contributors
+// License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+use crate::cloze::expand_clozes_to_reveal_latex;
+use crate::err::{AnkiError, DBErrorKind, Result};
+use crate::i18n::I18n;
+use crate::media::files::{filename_is_valid, move_file_to_trash};
+use crate::media::MediaManager;
+use crate::notes::field_checksum;
+use crate::text::{extract_media_refs, normalize_to_nfc, sort_field_text};
+use crate::tr_args;
+use rusqlite::{params, Connection, OpenFlags, NO_PARAMS};
+use serde_derive::Deserialize;
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+use unicode_normalization::is_nfc;
+
+/// The result of checking the media folder against the collection's notes.
+/// All lists are sorted.
+#[derive(Debug, Default, PartialEq)]
+pub struct MediaCheckOutput {
+    /// Files in the media folder that no note refers to.
+    pub unused: Vec<String>,
+    /// Files that notes refer to, but which are not in the media folder.
+    pub missing: Vec<String>,
+    /// Files that were skipped because their names contain characters that
+    /// are invalid on some platforms. The user needs to rename them.
+    pub invalid_names: Vec<String>,
+    /// Subfolders of the media folder, which Anki does not support.
+    pub subfolders: Vec<String>,
+    /// Files that were renamed to NFC form, as (old, new) pairs.
+    pub renamed: Vec<(String, String)>,
+    /// Notes that have LaTeX for which no image has been generated yet.
+    pub notes_missing_latex: Vec<i64>,
+    /// Notes with media references not in NFC form. The references are
+    /// rewritten so they match the renamed files.
+    pub notes_with_unnormalized_refs: Vec<i64>,
+}
+
+impl MediaCheckOutput {
+    /// The problems to report besides the unused and missing files, in
+    /// the user's language. `dry_run` should match the check's.
+    pub fn warnings(&self, dry_run: bool, i18n: &I18n) -> Vec<String> {
+        let mut warnings = vec![];
+        let rename_key = if dry_run {
+            "media-check-would-rename"
+        } else {
+            "media-check-renamed"
+        };
+        for (old, new) in &self.renamed {
+            warnings.push(i18n.trn(
+                rename_key,
+                tr_args!["old" => old.as_str(), "new" => new.as_str()],
+            ));
+        }
+        if !self.notes_with_unnormalized_refs.is_empty() {
+            let key = if dry_run {
+                "media-check-would-update-refs"
+            } else {
+                "media-check-updated-refs"
+            };
+            let count = self.notes_with_unnormalized_refs.len();
+            warnings.push(i18n.trn(key, tr_args!["count" => count]));
+        }
+        for name in &self.invalid_names {
+            warnings.push(i18n.trn(
+                "media-check-invalid-name",
+                tr_args!["name" => name.as_str()],
+            ));
+        }
+        if !self.subfolders.is_empty() {
+            warnings.push(i18n.tr("media-check-subfolders"));
+        }
+        warnings
+    }
+}
+
+const MODEL_CLOZE: u8 = 1;
+
+#[derive(Deserialize)]
+struct NoteTypeInfo {
+    #[serde(rename = "type")]
+    kind: u8,
+    #[serde(rename = "latexsvg", default)]
+    latex_svg: bool,
+    #[serde(rename = "sortf", default)]
+    sort_field: usize,
+}
+
+/// Media referenced by the collection's notes, in NFC form.
+#[derive(Default)]
+struct References {
+    files: HashSet<String>,
+    /// Maps images generated from LaTeX to the notes that use them.
+    latex: HashMap<String, Vec<i64>>,
+    /// Notes that refer to media with names not in NFC form.
+    unnormalized: HashSet<i64>,
+}
+
+impl References {
+    fn add_field(&mut self, nid: i64, field: &str, notetype: Option<&NoteTypeInfo>) {
+        let svg = notetype.map(|nt| nt.latex_svg).unwrap_or_default();
+        let is_cloze = notetype
+            .map(|nt| nt.kind == MODEL_CLOZE)
+            .unwrap_or_default();
+        let text = if is_cloze && field.contains("{{c") {
+            // each deletion may hide some of the latex
+            Cow::Owned(expand_clozes_to_reveal_latex(field))
+        } else {
+            Cow::Borrowed(field)
+        };
+
+        for media_ref in extract_media_refs(&text, svg) {
+            if media_ref.is_latex {
+                self.latex
+                    .entry(media_ref.fname.into_owned())
+                    .or_default()
+                    .push(nid);
+            } else {
+                if !is_nfc(&media_ref.fname) {
+                    self.unnormalized.insert(nid);
+                }
+                self.files
+                    .insert(normalize_to_nfc(&media_ref.fname).into_owned());
+            }
+        }
+    }
+}
+
+fn get_notetypes(db: &Connection) -> Result<HashMap<String, NoteTypeInfo>> {
+    let models: String = db.query_row("select models from col", NO_PARAMS, |row| row.get(0))?;
+    serde_json::from_str(&models).map_err(|e| {
+        AnkiError::db_error(format!("invalid note types: {}", e), DBErrorKind::Corrupt)
+    })
+}
+
+/// Read the media references of every note in the collection. The
+/// collection is opened read-only, so the caller must commit any pending
+/// changes first.
+fn gather_references(col_path: &Path) -> Result<References> {
+    let db = Connection::open_with_flags(col_path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    let notetypes = get_notetypes(&db)?;
+
+    let mut refs = References::default();
+    let mut stmt = db.prepare("select id, mid, flds from notes")?;
+    let mut rows = stmt.query(NO_PARAMS)?;
+    while let Some(row) = rows.next()? {
+        let nid: i64 = row.get(0)?;
+        let mid: i64 = row.get(1)?;
+        let fields: String = row.get(2)?;
+        let notetype = notetypes.get(&mid.to_string());
+        for field in fields.split('\x1f') {
+            refs.add_field(nid, field, notetype);
+        }
+    }
+
+    Ok(refs)
+}
+
+/// Convert the media references in the text to NFC form, leaving the rest
+/// of the text untouched.
+fn normalize_media_refs(text: &str) -> Cow<str> {
+    let refs: Vec<_> = extract_media_refs(text, false)
+        .into_iter()
+        .filter(|r| !r.is_latex)
+        .collect();
+    if refs.iter().all(|r| is_nfc(&text[r.span.clone()])) {
+        return Cow::Borrowed(text);
+    }
+
+    let mut output = String::with_capacity(text.len());
+    let mut last_end = 0;
+    for media_ref in refs {
+        output.push_str(&text[last_end..media_ref.span.start]);
+        output.push_str(&normalize_to_nfc(&text[media_ref.span.clone()]));
+        last_end = media_ref.span.end;
+    }
+    output.push_str(&text[last_end..]);
+
+    output.into()
+}
+
+/// Rewrite the media references of the provided notes to NFC form,
+/// updating the sort field and checksum, and marking the notes as
+/// modified so the changes are synced.
+fn normalize_note_references(col_path: &Path, nids: &[i64]) -> Result<()> {
+    let mut db = Connection::open(col_path)?;
+    let notetypes = get_notetypes(&db)?;
+    let mtime = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or_default();
+
+    let tx = db.transaction()?;
+    for nid in nids {
+        let (mid, fields): (i64, String) = tx.query_row(
+            "select mid, flds from notes where id = ?",
+            params![nid],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )?;
+        let fields: Vec<_> = fields.split('\x1f').map(normalize_media_refs).collect();
+        let sort_idx = notetypes
+            .get(&mid.to_string())
+            .map(|nt| nt.sort_field)
+            .unwrap_or_default();
+        let sort_field = fields.get(sort_idx).or_else(|| fields.first()).unwrap();
+
+        tx.execute(
+            "update notes set flds = ?, sfld = ?, csum = ?, mod = ?, usn = -1 where id = ?",
+            params![
+                fields.join("\x1f"),
+                sort_field_text(sort_field).as_ref(),
+                field_checksum(&fields[0]),
+                mtime,
+                nid
+            ],
+        )?;
+    }
+    tx.commit()?;
+
+    Ok(())
+}
+
+impl MediaManager {
+    /// Compare the media folder with the media used by the collection's
+    /// notes.
+    ///
+    /// Files with names not in NFC form are renamed; if a file with the
+    /// normalized name already exists, the other copy is moved to the trash.
+    /// References in notes that are not in NFC form are rewritten to match.
+    /// Files starting with an underscore are never reported as unused or
+    /// missing. The media DB is updated afterwards.
+    ///
+    /// If `dry_run` is true, nothing is changed, and the output reports
+    /// what would have been renamed or rewritten.
+    ///
+    /// `progress_cb` is called with the number of files checked so far
+    /// before each file, and can return false to stop, which returns
+    /// AnkiError::Interrupted. Notes are only changed after all files have
+    /// been checked.
+    pub fn check_media<F>(
+        &mut self,
+        col_path: &Path,
+        dry_run: bool,
+        mut progress_cb: F,
+    ) -> Result<MediaCheckOutput>
+    where
+        F: FnMut(usize) -> bool,
+    {
+        let mut refs = gather_references(col_path)?;
+        let mut output = MediaCheckOutput::default();
+        let mut found_latex = HashSet::new();
+
+        for (checked, dentry) in fs::read_dir(&self.media_folder)?.enumerate() {
+            if !progress_cb(checked) {
+                return Err(AnkiError::Interrupted);
+            }
+            let dentry = dentry?;
+            let fname = match dentry.file_name().into_string() {
+                Ok(fname) => fname,
+                Err(fname) => {
+                    // not valid unicode
+                    output
+                        .invalid_names
+                        .push(fname.to_string_lossy().into_owned());
+                    continue;
+                }
+            };
+
+            if dentry.file_type()?.is_dir() {
+                output.subfolders.push(fname);
+                continue;
+            }
+
+            let fname = match self.normalize_file(fname, &mut output, dry_run)? {
+                Some(fname) => fname,
+                None => continue,
+            };
+
+            if fname.starts_with('_') {
+                continue;
+            }
+            if !filename_is_valid(&fname) {
+                output.invalid_names.push(fname);
+                continue;
+            }
+
+            if refs.latex.contains_key(&fname) {
+                found_latex.insert(fname);
+            } else if !refs.files.remove(&fname) {
+                output.unused.push(fname);
+            }
+        }
+
+        output.missing = refs
+            .files
+            .into_iter()
+            .filter(|fname| !fname.starts_with('_'))
+            .collect();
+        let mut latex_nids: Vec<i64> = refs
+            .latex
+            .into_iter()
+            .filter(|(fname, _)| !found_latex.contains(fname))
+            .flat_map(|(_, nids)| nids)
+            .collect();
+        latex_nids.sort_unstable();
+        latex_nids.dedup();
+        output.notes_missing_latex = latex_nids;
+        output.notes_with_unnormalized_refs = refs.unnormalized.into_iter().collect();
+
+        output.unused.sort_unstable();
+        output.missing.sort_unstable();
+        output.invalid_names.sort_unstable();
+        output.subfolders.sort_unstable();
+        output.renamed.sort_unstable();
+        output.notes_with_unnormalized_refs.sort_unstable();
+
+        if !dry_run {
+            normalize_note_references(col_path, &output.notes_with_unnormalized_refs)?;
+            self.register_changes(true)?;
+        }
+
+        Ok(output)
+    }
+
+    /// If the filename is not in NFC form, rename the file, returning the
+    /// new name. If the normalized name is already taken, the file is
+    /// moved to the trash, and None is returned. In a dry run, the new name
+    /// is returned without changing anything.
+    fn normalize_file(
+        &self,
+        fname: String,
+        output: &mut MediaCheckOutput,
+        dry_run: bool,
+    ) -> Result<Option<String>> {
+        let normalized = match normalize_to_nfc(&fname) {
+            Cow::Borrowed(_) => return Ok(Some(fname)),
+            Cow::Owned(normalized) => normalized,
+        };
+
+        let new_path = self.media_folder.join(&normalized);
+        if new_path.exists() {
+            if !dry_run {
+                move_file_to_trash(&self.media_folder, &fname)?;
+            }
+            Ok(None)
+        } else {
+            if !dry_run {
+                fs::rename(self.media_folder.join(&fname), new_path)?;
+            }
+            output.renamed.push((fname, normalized.clone()));
+            Ok(Some(normalized))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::err::{AnkiError, Result};
+    use crate::i18n::I18n;
+    use crate::media::check::MediaCheckOutput;
+    use crate::media::MediaManager;
+    use rusqlite::{params, Connection, NO_PARAMS};
+    use std::fs;
+    use std::path::Path;
+    use tempfile::tempdir;
+
+    fn create_collection(path: &Path, notes: &[(i64, i64, &str)]) -> Result<()> {
+        let db = Connection::open(path)?;
+        db.execute_batch(
+            r#"
+            create table col (models text not null);
+            insert into col values ('{"1": {"type": 0}, "2": {"type": 1, "latexsvg": true}}');
+            create table notes (id integer primary key, mid integer not null,
+                mod integer not null default 0, usn integer not null default 0,
+                flds text not null, sfld text not null default '',
+                csum integer not null default 0);
+            "#,
+        )?;
+        for (nid, mid, fields) in notes {
+            db.execute(
+                "insert into notes (id, mid, flds) values (?, ?, ?)",
+                params![nid, mid, fields],
+            )?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_check() -> Result<()> {
+        let dir = tempdir()?;
+        let media_dir = dir.path().join("collection.media");
+        fs::create_dir(&media_dir)?;
+        let col_path = dir.path().join("collection.anki2");
+        create_collection(
+            &col_path,
+            &[
+                (1, 1, "<img src=used.jpg>\x1f[sound:missing.mp3]"),
+                (2, 1, "[latex]x[/latex] <img src='_missing.png'>"),
+                (3, 2, "{{c1::[$]y[/$]}}\x1f[sound:e\u{301}.mp3]"),
+            ],
+        )?;
+        let mut mgr = MediaManager::new(&media_dir, dir.path().join("media.db"))?;
+
+        for fname in &[
+            "used.jpg",
+            "unused.jpg",
+            "_ignored.jpg",
+            "a:b.jpg",
+            "e\u{301}.mp3",
+        ] {
+            fs::write(media_dir.join(fname), "data")?;
+        }
+        fs::create_dir(media_dir.join("folder"))?;
+
+        // the check can be stopped part way through
+        let mut progress = vec![];
+        let res = mgr.check_media(&col_path, false, |checked| {
+            progress.push(checked);
+            checked < 2
+        });
+        match res.err() {
+            Some(AnkiError::Interrupted) => (),
+            other => panic!("unexpected: {:?}", other),
+        }
+        assert_eq!(progress, vec![0, 1, 2]);
+
+        let output = mgr.check_media(&col_path, false, |_| true)?;
+        assert_eq!(
+            output,
+            MediaCheckOutput {
+                unused: vec!["unused.jpg".into()],
+                missing: vec!["missing.mp3".into()],
+                invalid_names: vec!["a:b.jpg".into()],
+                subfolders: vec!["folder".into()],
+                renamed: vec![("e\u{301}.mp3".into(), "\u{e9}.mp3".into())],
+                notes_missing_latex: vec![2, 3],
+                notes_with_unnormalized_refs: vec![3],
+            }
+        );
+        assert!(media_dir.join("\u{e9}.mp3").exists());
+        assert_eq!(
+            output.warnings(false, &I18n::default()),
+            vec![
+                "Renamed e\u{301}.mp3 to \u{e9}.mp3",
+                "Updated media references in 1 note.",
+                "Invalid file name, please rename: a:b.jpg",
+                "Anki does not support files in subfolders of the collection.media folder.",
+            ]
+        );
+
+        // unused files can be moved to the trash
+        mgr.trash_files(&["unused.jpg", "nonexistent.jpg"])?;
+        assert!(!media_dir.join("unused.jpg").exists());
+        assert!(dir.path().join("media.trash").join("unused.jpg").exists());
+        assert!(mgr.trash_files(&["../collection.anki2"]).is_err());
+
+        let output = mgr.check_media(&col_path, false, |_| true)?;
+        assert_eq!(output.unused, Vec::<String>::new());
+        assert_eq!(output.renamed, vec![]);
+        assert_eq!(output.notes_with_unnormalized_refs, Vec::<i64>::new());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_normalize_refs() -> Result<()> {
+        let dir = tempdir()?;
+        let media_dir = dir.path().join("collection.media");
+        fs::create_dir(&media_dir)?;
+        let col_path = dir.path().join("collection.anki2");
+        let field = "<img src=\"e\u{301}.jpg\"> e\u{301}";
+        create_collection(&col_path, &[(1, 1, field)])?;
+        fs::write(media_dir.join("e\u{301}.jpg"), "data")?;
+        let mut mgr = MediaManager::new(&media_dir, dir.path().join("media.db"))?;
+
+        let note_fields = || -> Result<(String, String, i64)> {
+            let db = Connection::open(&col_path)?;
+            Ok(db.query_row(
+                "select flds, sfld, usn from notes where id = 1",
+                NO_PARAMS,
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )?)
+        };
+
+        // a dry run reports the changes, but doesn't make them
+        let output = mgr.check_media(&col_path, true, |_| true)?;
+        assert_eq!(
+            output.renamed,
+            vec![("e\u{301}.jpg".into(), "\u{e9}.jpg".into())]
+        );
+        assert_eq!(output.notes_with_unnormalized_refs, vec![1]);
+        assert_eq!(output.missing, Vec::<String>::new());
+        assert!(media_dir.join("e\u{301}.jpg").exists());
+        assert_eq!(note_fields()?.0, field);
+
+        // only the reference is normalized, not the rest of the text
+        let output = mgr.check_media(&col_path, false, |_| true)?;
+        assert_eq!(output.notes_with_unnormalized_refs, vec![1]);
+        assert!(media_dir.join("\u{e9}.jpg").exists());
+        assert_eq!(
+            note_fields()?,
+            (
+                "<img src=\"\u{e9}.jpg\"> e\u{301}".into(),
+                " \u{e9}.jpg  e\u{301}".into(),
+                -1
+            )
+        );
+
+        let output = mgr.check_media(&col_path, false, |_| true)?;
+        assert_eq!(output, MediaCheckOutput::default());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,250 @@
+// Copyright: Ankitects Pty Ltd and contributors
+// License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+use crate::err::Result;
+use rusqlite::{params, Connection, OptionalExtension, Row, NO_PARAMS};
+use std::collections::HashMap;
+use std::path::Path;
+
+/// A file in the media folder, or one that has been deleted but not yet
+/// synced.
+#[derive(Debug, PartialEq)]
+pub struct MediaEntry {
+    pub fname: String,
+    /// If None, the file has been deleted.
+    pub sha1: Option<[u8; 20]>,
+    /// Zero if deleted.
+    pub mtime: i64,
+    pub sync_required: bool,
+}
+
+#[derive(Debug, PartialEq, Default)]
+pub struct MediaDatabaseMetadata {
+    /// The modification time of the media folder when it was last scanned.
+    pub folder_mtime: i64,
+    pub last_sync_usn: i32,
+}
+
+/// The media DB, which caches the checksum of each file in the media
+/// folder. It uses the same schema as the legacy Python code, so either
+/// can open it.
+pub struct MediaDatabase {
+    db: Connection,
+}
+
+fn open_or_create(path: &Path) -> Result<Connection> {
+    let db = Connection::open(path)?;
+
+    let exists: bool = db.query_row(
+        "select count(*) from sqlite_master where type = 'table' and name = 'media'",
+        NO_PARAMS,
+        |row| row.get(0),
+    )?;
+    if !exists {
+        db.execute_batch(include_str!("schema.sql"))?;
+    }
+
+    Ok(db)
+}
+
+fn row_to_entry(row: &Row) -> rusqlite::Result<MediaEntry> {
+    // map the string checksum into bytes
+    let sha1_str: Option<String> = row.get(1)?;
+    let sha1_array = if let Some(s) = sha1_str {
+        let mut arr = [0; 20];
+        match hex::decode_to_slice(s, arr.as_mut()) {
+            Ok(_) => Some(arr),
+            _ => None,
+        }
+    } else {
+        None
+    };
+    Ok(MediaEntry {
+        fname: row.get(0)?,
+        sha1: sha1_array,
+        mtime: row.get(2)?,
+        sync_required: row.get(3)?,
+    })
+}
+
+impl MediaDatabase {
+    pub(super) fn open(path: &Path) -> Result<Self> {
+        Ok(MediaDatabase {
+            db: open_or_create(path)?,
+        })
+    }
+
+    /// Run the provided closure in a transaction, rolling back if it
+    /// returns an error.
+    pub(super) fn transact<F, R>(&mut self, func: F) -> Result<R>
+    where
+        F: FnOnce(&mut MediaDatabase) -> Result<R>,
+    {
+        self.db.execute_batch("begin immediate")?;
+        let result = func(self);
+        if result.is_ok() {
+            self.db.execute_batch("commit")?;
+        } else {
+            self.db.execute_batch("rollback")?;
+        }
+        result
+    }
+
+    pub(super) fn get_entry(&self, fname: &str) -> Result<Option<MediaEntry>> {
+        self.db
+            .prepare_cached("select fname, csum, mtime, dirty from media where fname = ?")?
+            .query_row(params![fname], row_to_entry)
+            .optional()
+            .map_err(Into::into)
+    }
+
+    pub(super) fn set_entry(&self, entry: &MediaEntry) -> Result<()> {
+        let sha1_str = entry.sha1.map(hex::encode);
+        self.db
+            .prepare_cached("insert or replace into media values (?, ?, ?, ?)")?
+            .execute(params![
+                entry.fname,
+                sha1_str,
+                entry.mtime,
+                entry.sync_required
+            ])?;
+        Ok(())
+    }
+
+    pub(super) fn remove_entry(&self, fname: &str) -> Result<()> {
+        self.db
+            .prepare_cached("delete from media where fname = ?")?
+            .execute(params![fname])?;
+        Ok(())
+    }
+
+    pub(super) fn get_meta(&self) -> Result<MediaDatabaseMetadata> {
+        self.db
+            .query_row("select dirMod, lastUsn from meta", NO_PARAMS, |row| {
+                Ok(MediaDatabaseMetadata {
+                    folder_mtime: row.get(0)?,
+                    last_sync_usn: row.get(1)?,
+                })
+            })
+            .map_err(Into::into)
+    }
+
+    pub(super) fn set_meta(&self, meta: &MediaDatabaseMetadata) -> Result<()> {
+        self.db.execute(
+            "update meta set dirMod = ?, lastUsn = ?",
+            params![meta.folder_mtime, meta.last_sync_usn],
+        )?;
+        Ok(())
+    }
+
+    /// Maps each existing (non-deleted) file to its modification time.
+    pub(super) fn all_mtimes(&self) -> Result<HashMap<String, i64>> {
+        let mut stmt = self
+            .db
+            .prepare("select fname, mtime from media where csum is not null")?;
+        let map: std::result::Result<HashMap<String, i64>, rusqlite::Error> = stmt
+            .query_map(NO_PARAMS, |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect();
+        Ok(map?)
+    }
+
+    /// Up to `max_entries` files or deletions that need to be sent to the
+    /// server.
+    pub(super) fn get_pending_uploads(&self, max_entries: u32) -> Result<Vec<MediaEntry>> {
+        let mut stmt = self
+            .db
+            .prepare("select fname, csum, mtime, dirty from media where dirty = 1 limit ?")?;
+        let results: std::result::Result<Vec<_>, rusqlite::Error> = stmt
+            .query_and_then(params![max_entries], row_to_entry)?
+            .collect();
+        Ok(results?)
+    }
+
+    /// Forget all files and the last sync point, so the next sync compares
+    /// every file with the server.
+    pub(super) fn force_resync(&self) -> Result<()> {
+        self.db
+            .execute_batch("delete from media; update meta set lastUsn = 0, dirMod = 0")?;
+        Ok(())
+    }
+
+    pub(super) fn count(&self) -> Result<u32> {
+        self.db
+            .query_row(
+                "select count(*) from media where csum is not null",
+                NO_PARAMS,
+                |row| row.get(0),
+            )
+            .map_err(Into::into)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::err::Result;
+    use crate::media::database::{MediaDatabase, MediaDatabaseMetadata, MediaEntry};
+    use tempfile::NamedTempFile;
+
+    #[test]
+    fn test_database() -> Result<()> {
+        let db_file = NamedTempFile::new()?;
+        let mut db = MediaDatabase::open(db_file.path())?;
+
+        db.transact(|ctx| {
+            // no entry exists yet
+            assert_eq!(ctx.get_entry("test.mp3")?, None);
+
+            // add one
+            let mut entry = MediaEntry {
+                fname: "test.mp3".into(),
+                sha1: None,
+                mtime: 0,
+                sync_required: false,
+            };
+            ctx.set_entry(&entry)?;
+            assert_eq!(ctx.get_entry("test.mp3")?.unwrap(), entry);
+
+            // update it
+            entry.sha1 = Some([1; 20]);
+            entry.mtime = 123;
+            entry.sync_required = true;
+            ctx.set_entry(&entry)?;
+            assert_eq!(ctx.get_entry("test.mp3")?.unwrap(), entry);
+            assert_eq!(ctx.all_mtimes()?.get("test.mp3"), Some(&123));
+            assert_eq!(ctx.count()?, 1);
+            assert_eq!(ctx.get_pending_uploads(25)?, vec![entry]);
+
+            // remove it
+            ctx.remove_entry("test.mp3")?;
+            assert_eq!(ctx.get_entry("test.mp3")?, None);
+
+            // update metadata
+            let mut meta = ctx.get_meta()?;
+            assert_eq!(meta, MediaDatabaseMetadata::default());
+            meta.folder_mtime = 123;
+            meta.last_sync_usn = 321;
+            ctx.set_meta(&meta)?;
+            assert_eq!(ctx.get_meta()?, meta);
+
+            Ok(())
+        })?;
+
+        // an error rolls back the changes
+        let res: Result<()> = db.transact(|ctx| {
+            ctx.set_meta(&MediaDatabaseMetadata {
+                folder_mtime: 1,
+                last_sync_usn: 1,
+            })?;
+            Err(crate::err::AnkiError::invalid_input("abort"))
+        });
+        assert!(res.is_err());
+        assert_eq!(db.get_meta()?.folder_mtime, 123);
+
+        // reopening the database works
+        drop(db);
+        let db = MediaDatabase::open(db_file.path())?;
+        assert_eq!(db.get_meta()?.last_sync_usn, 321);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,318 @@
+// Copyright: Ankitects Pty Ltd and contributors
+// License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+use crate::err::{AnkiError, Result};
+use crate::text::normalize_to_nfc;
+use lazy_static::lazy_static;
+use regex::Regex;
+use sha1::Sha1;
+use std::borrow::Cow;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+use std::{fs, io};
+use unicode_normalization::is_nfc;
+
+/// Files larger than this are not synced, and are skipped when scanning.
+pub(crate) const MEDIA_SYNC_FILESIZE_LIMIT: u64 = 100 * 1024 * 1024;
+
+/// The maximum length of a filename in bytes. This leaves room for a
+/// " (n)" suffix on typical Windows paths and eCryptfs partitions.
+const MAX_FILENAME_LENGTH: usize = 120;
+
+lazy_static! {
+    static ref ILLEGAL_CHARS: Regex = Regex::new(
+        r#"(?x)
+            [
+                \[ \] < > : " / ? * ^ \\ |
+                \x00 \r \n
+            ]
+        "#
+    )
+    .unwrap();
+    static ref WINDOWS_DEVICE_NAME: Regex = Regex::new(
+        r#"(?xi)
+            # starts with a reserved device name
+            ^
+            (
+                CON | PRN | AUX | NUL | COM[1-9] | LPT[1-9]
+            )
+            # followed by an optional extension
+            (\..*)?
+            $
+        "#
+    )
+    .unwrap();
+    static ref DUPLICATE_SUFFIX: Regex = Regex::new(r" \((\d+)\)$").unwrap();
+}
+
+/// True if the filename can be stored in the media folder and synced as-is:
+/// it must be in NFC form, and not contain characters that are invalid on
+/// some platforms.
+pub(super) fn filename_is_valid(fname: &str) -> bool {
+    !fname.is_empty() && is_nfc(fname) && !ILLEGAL_CHARS.is_match(fname)
+}
+
+/// Convert the provided name into one that is valid on all platforms:
+/// it is converted to NFC form, illegal characters are removed, reserved
+/// Windows device names are prefixed, and overly long names are truncated
+/// while preserving the extension.
+pub(crate) fn normalize_filename(fname: &str) -> Cow<str> {
+    let mut output = Cow::Borrowed(fname);
+
+    if !is_nfc(&output) {
+        output = normalize_to_nfc(&output).into_owned().into();
+    }
+
+    if let Cow::Owned(o) = ILLEGAL_CHARS.replace_all(&output, "") {
+        output = o.into();
+    }
+
+    if WINDOWS_DEVICE_NAME.is_match(&output) {
+        output = format!("renamed{}", output).into();
+    }
+
+    if let Cow::Owned(o) = truncate_filename(&output, MAX_FILENAME_LENGTH) {
+        output = o.into();
+    }
+
+    if output.is_empty() {
+        output = "renamed".into();
+    }
+
+    output
+}
+
+/// Fail unless the name refers to a file directly inside the media folder,
+/// and is already in the form normalize_filename() would give it. Names
+/// from a remote source must pass this before they're joined to a path.
+pub(crate) fn check_media_filename(fname: &str) -> Result<()> {
+    if fname == "." || fname == ".." || normalize_filename(fname) != fname {
+        Err(AnkiError::invalid_input(format!(
+            "invalid filename: {}",
+            fname
+        )))
+    } else {
+        Ok(())
+    }
+}
+
+/// Split a filename into its stem and extension, with the extension
+/// including the leading dot.
+pub(crate) fn split_extension(fname: &str) -> (&str, &str) {
+    match fname.rfind('.') {
+        Some(0) | None => (fname, ""),
+        Some(idx) => fname.split_at(idx),
+    }
+}
+
+/// Shorten the filename to at most `max_bytes`, keeping its extension
+/// intact where possible.
+fn truncate_filename(fname: &str, max_bytes: usize) -> Cow<str> {
+    if fname.len() <= max_bytes {
+        return Cow::Borrowed(fname);
+    }
+
+    let (stem, ext) = split_extension(fname);
+    let (stem, ext) = if ext.len() < max_bytes {
+        (stem, ext)
+    } else {
+        (fname, "")
+    };
+
+    let mut stem_len = max_bytes - ext.len();
+    while !stem.is_char_boundary(stem_len) {
+        stem_len -= 1;
+    }
+
+    format!("{}{}", &stem[..stem_len], ext).into()
+}
+
+/// Write the data into the media folder, returning the name it was stored
+/// under. If a file with the same name and contents exists, it is reused.
+/// If a file with the same name but different contents exists, a " (n)"
+/// suffix is added to the name until a free or matching name is found.
+///
+/// The name should have been normalized with normalize_filename().
+pub(super) fn add_data_to_folder_uniquely<'a>(
+    folder: &Path,
+    desired_name: &'a str,
+    data: &[u8],
+    sha1: [u8; 20],
+) -> io::Result<Cow<'a, str>> {
+    let mut target = Cow::Borrowed(desired_name);
+
+    loop {
+        let path = folder.join(target.as_ref());
+        if !path.exists() {
+            fs::write(&path, data)?;
+            return Ok(target);
+        }
+
+        if sha1_of_file(&path)? == sha1 {
+            return Ok(target);
+        }
+
+        target = add_duplicate_suffix(&target).into();
+    }
+}
+
+/// Add a " (1)" suffix to the stem, or increment an existing one.
+fn add_duplicate_suffix(fname: &str) -> String {
+    let (stem, ext) = split_extension(fname);
+
+    let new_stem = if let Some(caps) = DUPLICATE_SUFFIX.captures(stem) {
+        let n: u32 = caps[1].parse().unwrap_or_default();
+        let prefix = &stem[..caps.get(0).unwrap().start()];
+        format!("{} ({})", prefix, n + 1)
+    } else {
+        format!("{} (1)", stem)
+    };
+
+    format!("{}{}", new_stem, ext)
+}
+
+/// The SHA1 of the provided data.
+pub(crate) fn sha1_of_data(data: &[u8]) -> [u8; 20] {
+    let mut hasher = Sha1::new();
+    hasher.update(data);
+    hasher.digest().bytes()
+}
+
+/// The SHA1 of the file's contents.
+pub(crate) fn sha1_of_file(path: &Path) -> io::Result<[u8; 20]> {
+    let mut file = fs::File::open(path)?;
+    let mut hasher = Sha1::new();
+    let mut buf = [0; 64 * 1024];
+    loop {
+        match file.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => hasher.update(&buf[0..n]),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+    }
+    Ok(hasher.digest().bytes())
+}
+
+/// The folder unused media is moved into, next to the media folder. It is
+/// created if it doesn't exist.
+pub(super) fn trash_folder(media_folder: &Path) -> io::Result<PathBuf> {
+    let trash = media_folder.with_file_name("media.trash");
+    fs::create_dir_all(&trash)?;
+    Ok(trash)
+}
+
+/// Move a file from the media folder into the trash folder, replacing any
+/// file of the same name already in the trash.
+pub(super) fn move_file_to_trash(media_folder: &Path, fname: &str) -> io::Result<()> {
+    let target = trash_folder(media_folder)?.join(fname);
+    if target.exists() {
+        fs::remove_file(&target)?;
+    }
+    fs::rename(media_folder.join(fname), target)
+}
+
+/// The modification time in seconds, as the legacy code stored it.
+pub(super) fn mtime_as_i64<P: AsRef<Path>>(path: P) -> io::Result<i64> {
+    Ok(fs::metadata(path)?
+        .modified()?
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or_default())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::err::Result;
+    use crate::media::files::{
+        add_data_to_folder_uniquely, add_duplicate_suffix, filename_is_valid, normalize_filename,
+        sha1_of_data, sha1_of_file, truncate_filename, MAX_FILENAME_LENGTH,
+    };
+    use std::borrow::Cow;
+    use std::fs;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_filenames() {
+        assert!(filename_is_valid("foo.jpg"));
+        assert!(filename_is_valid("日本語.mp3"));
+        assert!(!filename_is_valid(""));
+        assert!(!filename_is_valid("a/b.jpg"));
+        assert!(!filename_is_valid("a:b.jpg"));
+        assert!(!filename_is_valid("a\nb.jpg"));
+        // decomposed form
+        assert!(!filename_is_valid("e\u{301}.jpg"));
+    }
+
+    #[test]
+    fn test_normalize() {
+        assert_eq!(normalize_filename("foo.jpg"), Cow::Borrowed("foo.jpg"));
+        assert_eq!(normalize_filename("e\u{301}.jpg"), "\u{e9}.jpg");
+        assert_eq!(normalize_filename("a:b/c?.jpg"), "abc.jpg");
+        assert_eq!(normalize_filename("con.jpg"), "renamedcon.jpg");
+        assert_eq!(normalize_filename("console.jpg"), "console.jpg");
+        assert_eq!(normalize_filename("::"), "renamed");
+
+        let long_name = format!("{}.jpg", "x".repeat(200));
+        let normalized = normalize_filename(&long_name);
+        assert_eq!(normalized.len(), MAX_FILENAME_LENGTH);
+        assert!(normalized.ends_with("x.jpg"));
+
+        // multibyte characters are not split
+        assert_eq!(truncate_filename("日本語.mp3", 10), "日本.mp3");
+        assert_eq!(truncate_filename("日本語", 7), "日本");
+    }
+
+    #[test]
+    fn test_duplicate_suffix() {
+        assert_eq!(add_duplicate_suffix("foo.jpg"), "foo (1).jpg");
+        assert_eq!(add_duplicate_suffix("foo (1).jpg"), "foo (2).jpg");
+        assert_eq!(add_duplicate_suffix("foo (9)"), "foo (10)");
+        assert_eq!(add_duplicate_suffix(".hidden"), ".hidden (1)");
+    }
+
+    #[test]
+    fn test_add_uniquely() -> Result<()> {
+        let dir = tempdir()?;
+        let folder = dir.path();
+
+        let hello = b"hello";
+        let sha1 = sha1_of_data(hello);
+        assert_eq!(
+            add_data_to_folder_uniquely(folder, "foo.jpg", hello, sha1)?,
+            "foo.jpg"
+        );
+        // identical data is reused
+        assert_eq!(
+            add_data_to_folder_uniquely(folder, "foo.jpg", hello, sha1)?,
+            "foo.jpg"
+        );
+        // different data gets a new name
+        let world = b"world";
+        let sha1 = sha1_of_data(world);
+        assert_eq!(
+            add_data_to_folder_uniquely(folder, "foo.jpg", world, sha1)?,
+            "foo (1).jpg"
+        );
+        assert_eq!(
+            add_data_to_folder_uniquely(folder, "foo.jpg", world, sha1)?,
+            "foo (1).jpg"
+        );
+        assert_eq!(fs::read(folder.join("foo (1).jpg"))?, world);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_sha1() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("file");
+        fs::write(&path, "hello")?;
+        assert_eq!(
+            hex::encode(sha1_of_file(&path)?),
+            "aaf4c61ddcc5e8a2dabede0f3b482cd9aea9434d"
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,166 @@
+// Copyright: Ankitects Pty Ltd and contributors
+// License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+pub mod changetracker;
+pub mod check;
+pub mod database;
+pub mod files;
+pub mod sync;
+pub mod transcode;
+pub mod trash;
+
+use crate::err::Result;
+use crate::media::changetracker::{register_changes, MediaChanges};
+use crate::media::database::{MediaDatabase, MediaEntry};
+use crate::media::files::{
+    add_data_to_folder_uniquely, mtime_as_i64, normalize_filename, sha1_of_data,
+};
+use crate::media::sync::{MediaSyncOutcome, MediaSyncProgress, MediaSyncer};
+use crate::sync::NetworkSettings;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// Provides access to the media folder, and the DB that tracks its
+/// contents.
+pub struct MediaManager {
+    db: MediaDatabase,
+    media_folder: PathBuf,
+}
+
+impl MediaManager {
+    pub fn new<P, P2>(media_folder: P, media_db: P2) -> Result<Self>
+    where
+        P: Into<PathBuf>,
+        P2: AsRef<Path>,
+    {
+        Ok(MediaManager {
+            db: MediaDatabase::open(media_db.as_ref())?,
+            media_folder: media_folder.into(),
+        })
+    }
+
+    /// Add a file to the media folder, returning the name it was stored
+    /// under, which should be used to refer to it.
+    ///
+    /// The desired name is normalized so that it is valid on all platforms.
+    /// If a file with the same name and contents already exists, it is
+    /// reused; if the contents differ, a number is appended to the name.
+    pub fn add_file(&mut self, desired_name: &str, data: &[u8]) -> Result<String> {
+        let pre_add_folder_mtime = mtime_as_i64(&self.media_folder)?;
+
+        let sha1 = sha1_of_data(data);
+        let desired_name = normalize_filename(desired_name);
+        let chosen_fname =
+            add_data_to_folder_uniquely(&self.media_folder, &desired_name, data, sha1)?
+                .into_owned();
+        let file_mtime = mtime_as_i64(self.media_folder.join(&chosen_fname))?;
+        let post_add_folder_mtime = mtime_as_i64(&self.media_folder)?;
+
+        self.db.transact(|ctx| {
+            let update_required = ctx
+                .get_entry(&chosen_fname)?
+                .map(|entry| entry.sha1 != Some(sha1))
+                .unwrap_or(true);
+            if update_required {
+                ctx.set_entry(&MediaEntry {
+                    fname: chosen_fname.clone(),
+                    sha1: Some(sha1),
+                    mtime: file_mtime,
+                    sync_required: true,
+                })?;
+            }
+
+            // if the folder was unchanged before we added the file, we can
+            // skip the next scan
+            let mut meta = ctx.get_meta()?;
+            if meta.folder_mtime == pre_add_folder_mtime {
+                meta.folder_mtime = post_add_folder_mtime;
+                ctx.set_meta(&meta)?;
+            }
+
+            Ok(())
+        })?;
+
+        Ok(chosen_fname)
+    }
+
+    /// Scan the media folder for files that have been added, changed or
+    /// removed, and record them in the DB. Unless `force` is true, the scan
+    /// is skipped when the folder's modification time has not changed.
+    pub fn register_changes(&mut self, force: bool) -> Result<MediaChanges> {
+        register_changes(&mut self.db, &self.media_folder, force)
+    }
+
+    /// Sync the media folder with AnkiWeb. `endpoint` is the base URL of
+    /// the media sync server, and `hkey` the user's host key.
+    ///
+    /// The progress callback is called periodically; if it returns false,
+    /// the sync is aborted with AnkiError::Interrupted. Progress is saved
+    /// after each batch of changes, so an aborted sync can be resumed.
+    ///
+    /// `folder_lock` is held while the folder and DB are being changed, but
+    /// not while waiting on the network, so other changes to the folder
+    /// that take the same lock can be made while the sync runs.
+    pub async fn sync_media<F>(
+        &mut self,
+        progress: F,
+        endpoint: &str,
+        network: &NetworkSettings,
+        hkey: &str,
+        client_version: &str,
+        folder_lock: &Mutex<()>,
+    ) -> Result<MediaSyncOutcome>
+    where
+        F: FnMut(&MediaSyncProgress) -> bool,
+    {
+        let mut syncer = MediaSyncer::new(
+            self,
+            progress,
+            endpoint,
+            network,
+            client_version,
+            folder_lock,
+        )?;
+        syncer.sync(hkey).await
+    }
+
+    /// The number of files in the media folder, as of the last scan.
+    pub fn file_count(&self) -> Result<u32> {
+        self.db.count()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::err::Result;
+    use crate::media::changetracker::MediaChanges;
+    use crate::media::MediaManager;
+    use std::fs;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_add_file() -> Result<()> {
+        let dir = tempdir()?;
+        let media_dir = dir.path().join("media");
+        fs::create_dir(&media_dir)?;
+        let mut mgr = MediaManager::new(&media_dir, dir.path().join("media.db"))?;
+        mgr.register_changes(true)?;
+
+        // adding the same data repeatedly reuses the file
+        for _ in 0..10 {
+            assert_eq!(mgr.add_file("paste.png", b"image")?, "paste.png");
+        }
+        assert_eq!(mgr.add_file("paste.png", b"other")?, "paste (1).png");
+        assert_eq!(mgr.add_file("a:b.png", b"image")?, "ab.png");
+        assert_eq!(mgr.file_count()?, 3);
+
+        let entry = mgr.db.get_entry("paste.png")?.unwrap();
+        assert!(entry.sync_required);
+
+        // the added files have already been recorded
+        let changes = mgr.register_changes(true)?;
+        assert_eq!(changes, MediaChanges::default());
+
+        Ok(())
+    }
+}
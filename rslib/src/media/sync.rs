@@ -0,0 +1,815 @@
+// Copyright: Ankitects Pty Ltd and contributors
+// License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+use crate::err::{AnkiError, Result};
+use crate::media::changetracker::register_changes;
+use crate::media::database::{MediaDatabase, MediaEntry};
+use crate::media::files::{
+    mtime_as_i64, normalize_filename, sha1_of_data, MEDIA_SYNC_FILESIZE_LIMIT,
+};
+use crate::media::MediaManager;
+use crate::sync::NetworkSettings;
+use reqwest::{multipart, Client, Response, StatusCode};
+use serde_derive::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use std::{fs, io};
+use tracing::info;
+
+/// The maximum number of files sent or fetched in a single zip.
+const SYNC_MAX_FILES: usize = 25;
+
+/// Once a zip being uploaded reaches this size, no more files are added.
+const SYNC_MAX_BYTES: usize = (2.5 * 1024.0 * 1024.0) as usize;
+
+/// The minimum time between progress updates.
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Counts of the work done so far, passed to the progress callback.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct MediaSyncProgress {
+    /// Changes received from the server that have been compared with the
+    /// local state.
+    pub checked: usize,
+    pub downloaded_files: usize,
+    pub downloaded_deletions: usize,
+    pub uploaded_files: usize,
+    pub uploaded_deletions: usize,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum MediaSyncOutcome {
+    /// The client and server were already in sync.
+    NoChanges,
+    Synced,
+    /// The client and server disagree on the number of files. The local
+    /// state has been reset, so the next sync will compare every file.
+    SanityCheckFailed,
+}
+
+pub(super) struct MediaSyncer<'a, P>
+where
+    P: FnMut(&MediaSyncProgress) -> bool,
+{
+    db: &'a mut MediaDatabase,
+    media_folder: &'a Path,
+    client: Client,
+    endpoint: &'a str,
+    client_version: &'a str,
+    /// Held while the folder or DB is changed.
+    folder_lock: &'a Mutex<()>,
+    skey: String,
+    progress_cb: P,
+    progress: MediaSyncProgress,
+    progress_updated: Instant,
+}
+
+// Server messages
+//----------------------------------------
+
+/// Every reply is wrapped in an object with either data or an error.
+#[derive(Debug, Deserialize)]
+struct ServerReply<T> {
+    data: Option<T>,
+    #[serde(default)]
+    err: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct SyncBeginResponse {
+    #[serde(rename = "sk")]
+    sync_key: String,
+    usn: i32,
+}
+
+/// A file that was added, changed or deleted on the server. An empty
+/// checksum signals a deletion.
+#[derive(Debug)]
+struct ServerMediaRecord {
+    fname: String,
+    usn: i32,
+    sha1: String,
+}
+
+// Sync logic
+//----------------------------------------
+
+#[derive(Debug, Clone, Copy)]
+enum LocalState {
+    NotInDB,
+    InDBNotPending,
+    InDBAndPending,
+}
+
+#[derive(Debug, PartialEq)]
+enum RequiredChange {
+    // no checks are performed at this stage, so the file may already exist
+    Download,
+    Delete,
+    RemovePending,
+    None,
+}
+
+/// Compare a change from the server with the local state of the file.
+/// Empty checksums indicate a deleted or missing file.
+fn determine_required_change(
+    local_sha1: &str,
+    remote_sha1: &str,
+    local_state: LocalState,
+) -> RequiredChange {
+    use LocalState as L;
+    use RequiredChange as R;
+
+    match (local_sha1, remote_sha1, local_state) {
+        // both deleted, and not in the local DB
+        ("", "", L::NotInDB) => R::None,
+        // both deleted; forget any pending deletion
+        ("", "", _) => R::RemovePending,
+        // added on the server; this overrides a pending local deletion
+        ("", _, _) => R::Download,
+        // deleted on the server, but added locally; it will be uploaded
+        (_, "", L::InDBAndPending) => R::None,
+        // deleted on the server, and unchanged locally
+        (_, "", _) => R::Delete,
+        // identical on both sides; no need to upload
+        (lsum, rsum, L::InDBAndPending) if lsum == rsum => R::RemovePending,
+        (lsum, rsum, _) => {
+            if lsum == rsum {
+                R::None
+            } else {
+                R::Download
+            }
+        }
+    }
+}
+
+/// The changes needed to bring the local state up to date with a batch of
+/// server records.
+#[derive(Debug, Default, PartialEq)]
+struct BatchChanges<'a> {
+    /// Files to fetch, and their expected checksums.
+    to_download: Vec<(&'a str, &'a str)>,
+    to_delete: Vec<&'a str>,
+    to_remove_pending: Vec<&'a str>,
+}
+
+fn determine_required_changes<'a>(
+    db: &MediaDatabase,
+    records: &'a [ServerMediaRecord],
+) -> Result<BatchChanges<'a>> {
+    let mut changes = BatchChanges::default();
+
+    for remote in records {
+        let (local_sha1, local_state) = match db.get_entry(&remote.fname)? {
+            Some(entry) => (
+                entry.sha1.map(hex::encode).unwrap_or_default(),
+                if entry.sync_required {
+                    LocalState::InDBAndPending
+                } else {
+                    LocalState::InDBNotPending
+                },
+            ),
+            None => (String::new(), LocalState::NotInDB),
+        };
+
+        match determine_required_change(&local_sha1, &remote.sha1, local_state) {
+            RequiredChange::Download => changes.to_download.push((&remote.fname, &remote.sha1)),
+            RequiredChange::Delete => changes.to_delete.push(&remote.fname),
+            RequiredChange::RemovePending => changes.to_remove_pending.push(&remote.fname),
+            RequiredChange::None => (),
+        };
+    }
+
+    Ok(changes)
+}
+
+impl<'a, P> MediaSyncer<'a, P>
+where
+    P: FnMut(&MediaSyncProgress) -> bool,
+{
+    pub(super) fn new(
+        mgr: &'a mut MediaManager,
+        progress_cb: P,
+        endpoint: &'a str,
+        network: &NetworkSettings,
+        client_version: &'a str,
+        folder_lock: &'a Mutex<()>,
+    ) -> Result<Self> {
+        let client = network.client()?;
+
+        Ok(MediaSyncer {
+            db: &mut mgr.db,
+            media_folder: &mgr.media_folder,
+            client,
+            endpoint,
+            client_version,
+            folder_lock,
+            skey: String::new(),
+            progress_cb,
+            progress: MediaSyncProgress::default(),
+            progress_updated: Instant::now(),
+        })
+    }
+
+    pub(super) async fn sync(&mut self, hkey: &str) -> Result<MediaSyncOutcome> {
+        // make sure the DB is up to date with the folder first
+        let last_sync_usn = {
+            let _guard = self.folder_lock.lock().unwrap();
+            register_changes(self.db, self.media_folder, false)?;
+            self.db.get_meta()?.last_sync_usn
+        };
+        let server_usn = self.sync_begin(hkey).await?;
+
+        let mut actions_performed = false;
+
+        // fetch changes from the server
+        if last_sync_usn != server_usn {
+            self.fetch_changes(last_sync_usn).await?;
+            actions_performed = true;
+        }
+
+        // send our own changes
+        if !self.db.get_pending_uploads(1)?.is_empty() {
+            self.send_changes().await?;
+            actions_performed = true;
+        }
+
+        self.fire_progress_cb()?;
+
+        info!(
+            downloaded = self.progress.downloaded_files,
+            uploaded = self.progress.uploaded_files,
+            "media sync"
+        );
+        if !actions_performed {
+            return Ok(MediaSyncOutcome::NoChanges);
+        }
+
+        self.finalize_sync().await
+    }
+
+    fn fire_progress_cb(&mut self) -> Result<()> {
+        if (self.progress_cb)(&self.progress) {
+            self.progress_updated = Instant::now();
+            Ok(())
+        } else {
+            Err(AnkiError::Interrupted)
+        }
+    }
+
+    fn maybe_fire_progress_cb(&mut self) -> Result<()> {
+        if self.progress_updated.elapsed() >= PROGRESS_INTERVAL {
+            self.fire_progress_cb()
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Start a session, returning the server's current usn.
+    async fn sync_begin(&mut self, hkey: &str) -> Result<i32> {
+        let vars = &[("k", hkey), ("v", self.client_version)];
+        let resp = self.request("begin", vars, b"{}".to_vec()).await?;
+        let reply: SyncBeginResponse = server_data(resp).await?;
+        self.skey = reply.sync_key;
+        Ok(reply.usn)
+    }
+
+    /// Apply the server's changes in batches, recording the new usn after
+    /// each batch, so an interrupted sync resumes where it left off.
+    async fn fetch_changes(&mut self, mut last_usn: i32) -> Result<()> {
+        loop {
+            let batch = self.fetch_record_batch(last_usn).await?;
+            if batch.is_empty() {
+                break;
+            }
+            last_usn = batch.last().unwrap().usn;
+
+            self.progress.checked += batch.len();
+            self.maybe_fire_progress_cb()?;
+
+            // the folder's mtime after our last change to it; if something
+            // else changes the folder while the lock is released, the next
+            // scan can't be skipped
+            let mut folder_mtime;
+            let mut folder_untouched;
+            let changes = {
+                let _guard = self.folder_lock.lock().unwrap();
+                let changes = determine_required_changes(self.db, &batch)?;
+                folder_untouched =
+                    self.db.get_meta()?.folder_mtime == mtime_as_i64(self.media_folder)?;
+
+                for fname in &changes.to_delete {
+                    remove_file_if_exists(&self.media_folder.join(fname))?;
+                }
+                self.db.transact(|ctx| {
+                    record_clean(ctx, &changes.to_remove_pending)?;
+                    for fname in &changes.to_delete {
+                        ctx.remove_entry(fname)?;
+                    }
+                    Ok(())
+                })?;
+                folder_mtime = mtime_as_i64(self.media_folder)?;
+                changes
+            };
+            self.progress.downloaded_deletions += changes.to_delete.len();
+            self.maybe_fire_progress_cb()?;
+
+            // downloads
+            let mut downloaded = vec![];
+            let mut remaining = changes.to_download.as_slice();
+            while !remaining.is_empty() {
+                let chunk = &remaining[..remaining.len().min(SYNC_MAX_FILES)];
+                let fnames: Vec<_> = chunk.iter().map(|(fname, _)| *fname).collect();
+                let zip_data = self.fetch_zip(&fnames).await?;
+                let files = {
+                    let _guard = self.folder_lock.lock().unwrap();
+                    folder_untouched &= mtime_as_i64(self.media_folder)? == folder_mtime;
+                    let files = extract_into_media_folder(self.media_folder, &zip_data, chunk)?;
+                    folder_mtime = mtime_as_i64(self.media_folder)?;
+                    files
+                };
+                if files.is_empty() {
+                    return Err(AnkiError::sync_misc("server sent no files"));
+                }
+                remaining = &remaining[files.len().min(remaining.len())..];
+                self.progress.downloaded_files += files.len();
+                downloaded.extend(files);
+                self.maybe_fire_progress_cb()?;
+            }
+
+            let _guard = self.folder_lock.lock().unwrap();
+            folder_untouched &= mtime_as_i64(self.media_folder)? == folder_mtime;
+            self.db.transact(|ctx| {
+                record_additions(ctx, downloaded)?;
+
+                let mut meta = ctx.get_meta()?;
+                meta.last_sync_usn = last_usn;
+                // if nothing else changed in the folder, the next scan can
+                // be skipped
+                if folder_untouched {
+                    meta.folder_mtime = folder_mtime;
+                }
+                ctx.set_meta(&meta)
+            })?;
+        }
+
+        Ok(())
+    }
+
+    /// Upload pending additions and deletions in batches.
+    async fn send_changes(&mut self) -> Result<()> {
+        loop {
+            let (pending, data, count) = {
+                let _guard = self.folder_lock.lock().unwrap();
+                let pending = self.db.get_pending_uploads(SYNC_MAX_FILES as u32)?;
+                if pending.is_empty() {
+                    break;
+                }
+
+                match zip_files(self.media_folder, &pending)? {
+                    ZippedFiles::Ready { data, count } => (pending, data, count),
+                    ZippedFiles::Invalid(fnames) => {
+                        // files that have disappeared or can no longer be
+                        // synced; drop them from the DB and try again
+                        self.db.transact(|ctx| {
+                            for fname in &fnames {
+                                ctx.remove_entry(fname)?;
+                            }
+                            Ok(())
+                        })?;
+                        continue;
+                    }
+                }
+            };
+
+            // the reply is [processed count, current server usn]
+            let resp = self.request("uploadChanges", &[], data).await?;
+            let (processed, current_usn): (usize, i32) = server_data(resp).await?;
+            let processed = &pending[..processed.min(count)];
+
+            for entry in processed {
+                if entry.sha1.is_some() {
+                    self.progress.uploaded_files += 1;
+                } else {
+                    self.progress.uploaded_deletions += 1;
+                }
+            }
+            self.maybe_fire_progress_cb()?;
+
+            let _guard = self.folder_lock.lock().unwrap();
+            self.db.transact(|ctx| {
+                record_uploaded(ctx, processed)?;
+                // only advance our usn if no other client has made changes
+                // in the meantime
+                let mut meta = ctx.get_meta()?;
+                if meta.last_sync_usn + processed.len() as i32 == current_usn {
+                    meta.last_sync_usn = current_usn;
+                    ctx.set_meta(&meta)?;
+                }
+                Ok(())
+            })?;
+
+            if processed.is_empty() {
+                return Err(AnkiError::sync_misc("server did not accept any files"));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Compare the file count with the server. If they differ, the local
+    /// state is reset so that the next sync starts from scratch.
+    async fn finalize_sync(&mut self) -> Result<MediaSyncOutcome> {
+        #[derive(Serialize)]
+        struct FinalizeRequest {
+            local: u32,
+        }
+
+        let local = self.db.count()?;
+        let body = serde_json::to_vec(&FinalizeRequest { local })?;
+        let resp = self.request("mediaSanity", &[], body).await?;
+        let reply: String = server_data(resp).await?;
+
+        if reply == "OK" {
+            Ok(MediaSyncOutcome::Synced)
+        } else {
+            self.db.transact(|ctx| ctx.force_resync())?;
+            Ok(MediaSyncOutcome::SanityCheckFailed)
+        }
+    }
+
+    async fn fetch_record_batch(&self, last_usn: i32) -> Result<Vec<ServerMediaRecord>> {
+        #[derive(Serialize)]
+        #[serde(rename_all = "camelCase")]
+        struct RecordBatchRequest {
+            last_usn: i32,
+        }
+
+        let body = serde_json::to_vec(&RecordBatchRequest { last_usn })?;
+        let resp = self.request("mediaChanges", &[], body).await?;
+
+        // each record is sent as [fname, usn, sha1]
+        let records: Vec<(String, i32, String)> = server_data(resp).await?;
+        Ok(records
+            .into_iter()
+            .map(|(fname, usn, sha1)| ServerMediaRecord { fname, usn, sha1 })
+            .collect())
+    }
+
+    async fn fetch_zip(&self, files: &[&str]) -> Result<Vec<u8>> {
+        #[derive(Serialize)]
+        struct ZipRequest<'a> {
+            files: &'a [&'a str],
+        }
+
+        let body = serde_json::to_vec(&ZipRequest { files })?;
+        let resp = self.request("downloadFiles", &[], body).await?;
+        Ok(resp.bytes().await?.to_vec())
+    }
+
+    /// Post a request to the server. The data is sent uncompressed, along
+    /// with the session key and any extra variables.
+    async fn request(
+        &self,
+        method: &str,
+        vars: &[(&str, &str)],
+        data: Vec<u8>,
+    ) -> Result<Response> {
+        let url = format!("{}{}", self.endpoint, method);
+
+        let mut form = multipart::Form::new().text("c", "0");
+        if vars.is_empty() {
+            form = form.text("sk", self.skey.clone());
+        } else {
+            for (key, value) in vars {
+                form = form.text(key.to_string(), value.to_string());
+            }
+        }
+        form = form.part("data", multipart::Part::bytes(data).file_name("data"));
+
+        let resp = self.client.post(&url).multipart(form).send().await?;
+        if resp.status() == StatusCode::FORBIDDEN {
+            return Err(AnkiError::SyncAuthError);
+        }
+        Ok(resp.error_for_status()?)
+    }
+}
+
+/// Extract the data from a server reply, or return the error it contains.
+async fn server_data<T>(resp: Response) -> Result<T>
+where
+    T: serde::de::DeserializeOwned,
+{
+    let bytes = resp.bytes().await?;
+    let reply: ServerReply<T> = serde_json::from_slice(&bytes)?;
+    match reply.data {
+        Some(data) if reply.err.is_empty() => Ok(data),
+        _ => Err(AnkiError::sync_misc(reply.err)),
+    }
+}
+
+fn remove_file_if_exists(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+// Zips
+//----------------------------------------
+
+/// A file received from the server.
+#[derive(Debug)]
+struct DownloadedFile {
+    fname: String,
+    sha1: [u8; 20],
+    mtime: i64,
+    /// If the server's name was not valid locally, the file is stored under
+    /// a normalized name instead, and this holds the original name.
+    renamed_from: Option<String>,
+}
+
+/// Write the files in a zip from the server into the media folder. Each
+/// file's checksum is verified against the one the server advertised.
+fn extract_into_media_folder(
+    media_folder: &Path,
+    zip_data: &[u8],
+    expected: &[(&str, &str)],
+) -> Result<Vec<DownloadedFile>> {
+    let mut zip = zip::ZipArchive::new(io::Cursor::new(zip_data))?;
+
+    // the meta file maps names in the zip to real filenames
+    let fmap: HashMap<String, String> = serde_json::from_reader(zip.by_name("_meta")?)?;
+    let expected: HashMap<&str, &str> = expected.iter().cloned().collect();
+
+    let mut output = Vec::with_capacity(fmap.len());
+    for idx in 0..zip.len() {
+        let mut file = zip.by_index(idx)?;
+        if file.name() == "_meta" {
+            continue;
+        }
+        let real_name = fmap
+            .get(file.name())
+            .ok_or_else(|| AnkiError::sync_misc("malformed zip"))?;
+
+        let mut data = Vec::with_capacity(file.size() as usize);
+        file.read_to_end(&mut data)?;
+
+        let sha1 = sha1_of_data(&data);
+        if expected.get(real_name.as_str()) != Some(&hex::encode(sha1).as_str()) {
+            return Err(AnkiError::sync_misc(format!(
+                "checksum mismatch for {}",
+                real_name
+            )));
+        }
+
+        let (fname, renamed_from) = match normalize_filename(real_name) {
+            Cow::Borrowed(_) => (real_name.clone(), None),
+            Cow::Owned(fname) => (fname, Some(real_name.clone())),
+        };
+        let path = media_folder.join(&fname);
+        fs::write(&path, &data)?;
+
+        output.push(DownloadedFile {
+            fname,
+            sha1,
+            mtime: mtime_as_i64(&path)?,
+            renamed_from,
+        });
+    }
+
+    Ok(output)
+}
+
+enum ZippedFiles {
+    /// The zip data, and the number of entries it covers.
+    Ready { data: Vec<u8>, count: usize },
+    /// Entries that could not be included.
+    Invalid(Vec<String>),
+}
+
+/// Build a zip of pending changes to send to the server, stopping early
+/// once it grows too large. The meta file lists each entry as
+/// [fname, name in zip], where an empty name in the zip is a deletion.
+fn zip_files(media_folder: &Path, entries: &[MediaEntry]) -> Result<ZippedFiles> {
+    let mut zip = zip::ZipWriter::new(io::Cursor::new(vec![]));
+    let options =
+        zip::write::FileOptions::default().compression_method(zip::CompressionMethod::Deflated);
+
+    let mut invalid = vec![];
+    let mut meta: Vec<(&str, String)> = vec![];
+    let mut accumulated_size = 0;
+
+    for (idx, entry) in entries.iter().enumerate() {
+        if accumulated_size >= SYNC_MAX_BYTES {
+            break;
+        }
+
+        if entry.sha1.is_none() {
+            meta.push((&entry.fname, String::new()));
+            continue;
+        }
+
+        let data = match fs::read(media_folder.join(&entry.fname)) {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                invalid.push(entry.fname.clone());
+                continue;
+            }
+            Err(e) => return Err(e.into()),
+        };
+        if data.is_empty()
+            || data.len() as u64 > MEDIA_SYNC_FILESIZE_LIMIT
+            || normalize_filename(&entry.fname) != entry.fname.as_str()
+        {
+            invalid.push(entry.fname.clone());
+            continue;
+        }
+
+        let zip_name = idx.to_string();
+        zip.start_file(zip_name.as_str(), options)?;
+        zip.write_all(&data)?;
+        accumulated_size += data.len();
+        meta.push((&entry.fname, zip_name));
+    }
+
+    if !invalid.is_empty() {
+        return Ok(ZippedFiles::Invalid(invalid));
+    }
+
+    zip.start_file("_meta", options)?;
+    zip.write_all(&serde_json::to_vec(&meta)?)?;
+    let data = zip.finish()?.into_inner();
+
+    Ok(ZippedFiles::Ready {
+        data,
+        count: meta.len(),
+    })
+}
+
+// DB updates
+//----------------------------------------
+
+fn record_clean(db: &MediaDatabase, fnames: &[&str]) -> Result<()> {
+    for fname in fnames {
+        if let Some(mut entry) = db.get_entry(fname)? {
+            if entry.sync_required {
+                entry.sync_required = false;
+                db.set_entry(&entry)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Like record_clean(), but files that have changed since they were
+/// uploaded are left to be sent again.
+fn record_uploaded(db: &MediaDatabase, uploaded: &[MediaEntry]) -> Result<()> {
+    for file in uploaded {
+        if let Some(mut entry) = db.get_entry(&file.fname)? {
+            if entry.sync_required && entry.sha1 == file.sha1 {
+                entry.sync_required = false;
+                db.set_entry(&entry)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn record_additions(db: &MediaDatabase, files: Vec<DownloadedFile>) -> Result<()> {
+    for file in files {
+        let sync_required = if let Some(original) = file.renamed_from {
+            // remove the invalid name from the server, and upload the file
+            // under its new name
+            db.set_entry(&MediaEntry {
+                fname: original,
+                sha1: None,
+                mtime: 0,
+                sync_required: true,
+            })?;
+            true
+        } else {
+            false
+        };
+
+        db.set_entry(&MediaEntry {
+            fname: file.fname,
+            sha1: Some(file.sha1),
+            mtime: file.mtime,
+            sync_required,
+        })?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::err::Result;
+    use crate::media::database::MediaEntry;
+    use crate::media::files::sha1_of_data;
+    use crate::media::sync::{
+        determine_required_change, extract_into_media_folder, zip_files, LocalState,
+        RequiredChange, ZippedFiles,
+    };
+    use std::collections::HashMap;
+    use std::fs;
+    use std::io::{self, Read, Write};
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_required_change() {
+        use determine_required_change as d;
+        use LocalState as L;
+        use RequiredChange as R;
+
+        assert_eq!(d("", "", L::NotInDB), R::None);
+        assert_eq!(d("", "", L::InDBNotPending), R::RemovePending);
+        assert_eq!(d("", "1", L::InDBAndPending), R::Download);
+        assert_eq!(d("1", "", L::InDBAndPending), R::None);
+        assert_eq!(d("1", "", L::InDBNotPending), R::Delete);
+        assert_eq!(d("1", "1", L::InDBAndPending), R::RemovePending);
+        assert_eq!(d("1", "1", L::InDBNotPending), R::None);
+        assert_eq!(d("1", "2", L::InDBAndPending), R::Download);
+    }
+
+    #[test]
+    fn test_zips() -> Result<()> {
+        let dir = tempdir()?;
+        let folder = dir.path();
+        fs::write(folder.join("added.jpg"), "data")?;
+
+        let entries = vec![
+            MediaEntry {
+                fname: "added.jpg".into(),
+                sha1: Some(sha1_of_data(b"data")),
+                mtime: 0,
+                sync_required: true,
+            },
+            MediaEntry {
+                fname: "deleted.jpg".into(),
+                sha1: None,
+                mtime: 0,
+                sync_required: true,
+            },
+        ];
+        let data = match zip_files(folder, &entries)? {
+            ZippedFiles::Ready { data, count } => {
+                assert_eq!(count, 2);
+                data
+            }
+            ZippedFiles::Invalid(_) => panic!("unexpected invalid files"),
+        };
+        let mut zip = zip::ZipArchive::new(io::Cursor::new(data))?;
+        let mut meta = String::new();
+        zip.by_name("_meta")?.read_to_string(&mut meta)?;
+        assert_eq!(meta, r#"[["added.jpg","0"],["deleted.jpg",""]]"#);
+
+        // missing files are reported
+        fs::remove_file(folder.join("added.jpg"))?;
+        match zip_files(folder, &entries)? {
+            ZippedFiles::Invalid(fnames) => assert_eq!(fnames, vec!["added.jpg".to_string()]),
+            ZippedFiles::Ready { .. } => panic!("missing file not reported"),
+        }
+
+        // extracting a zip from the server
+        let mut zip = zip::ZipWriter::new(io::Cursor::new(vec![]));
+        let options = zip::write::FileOptions::default();
+        zip.start_file("0", options)?;
+        zip.write_all(b"data")?;
+        zip.start_file("1", options)?;
+        zip.write_all(b"more")?;
+        let fmap: HashMap<_, _> = vec![("0", "a.jpg"), ("1", "b:c.jpg")].into_iter().collect();
+        zip.start_file("_meta", options)?;
+        zip.write_all(serde_json::to_string(&fmap)?.as_bytes())?;
+        let data = zip.finish()?.into_inner();
+
+        let data_sha1 = hex::encode(sha1_of_data(b"data"));
+        let more_sha1 = hex::encode(sha1_of_data(b"more"));
+        let expected = vec![
+            ("a.jpg", data_sha1.as_str()),
+            ("b:c.jpg", more_sha1.as_str()),
+        ];
+        let files = extract_into_media_folder(folder, &data, &expected)?;
+        assert_eq!(files.len(), 2);
+        assert_eq!(fs::read(folder.join("a.jpg"))?, b"data");
+        // invalid names are normalized
+        assert_eq!(files[1].fname, "bc.jpg");
+        assert_eq!(
+            files[1].renamed_from.as_ref().map(String::as_str),
+            Some("b:c.jpg")
+        );
+
+        // a checksum mismatch is an error
+        let expected = vec![
+            ("a.jpg", more_sha1.as_str()),
+            ("b:c.jpg", more_sha1.as_str()),
+        ];
+        assert!(extract_into_media_folder(folder, &data, &expected).is_err());
+
+        Ok(())
+    }
+}
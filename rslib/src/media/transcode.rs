@@ -0,0 +1,162 @@
+// Copyright: Ankitects Pty Ltd and contributors
+// License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+use crate::err::Result;
+use crate::media::files::split_extension;
+use crate::media::MediaManager;
+use image::{FilterType, GenericImageView, ImageFormat};
+
+/// Settings for converting large images to WebP when they are added.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImageTranscodeConfig {
+    /// The WebP quality, from 0 to 100.
+    pub quality: f32,
+    /// Images wider or taller than this are scaled down to fit. 0 means
+    /// no limit.
+    pub max_dimension: u32,
+    /// Images smaller than this many bytes are stored as-is.
+    pub min_size: usize,
+}
+
+impl Default for ImageTranscodeConfig {
+    fn default() -> Self {
+        ImageTranscodeConfig {
+            quality: 80.0,
+            max_dimension: 1920,
+            min_size: 100 * 1024,
+        }
+    }
+}
+
+/// The format of images that are worth converting, based on their
+/// extension. Other formats are either already compressed, or may be
+/// animated.
+fn transcodable_format(ext: &str) -> Option<ImageFormat> {
+    match ext.to_ascii_lowercase().as_str() {
+        ".png" => Some(ImageFormat::PNG),
+        ".bmp" => Some(ImageFormat::BMP),
+        _ => None,
+    }
+}
+
+/// If the provided file is a large PNG or BMP image, convert it into a
+/// WebP image, returning the new name and data. None is returned if the
+/// image can't be decoded, or if converting it would not save space.
+pub(super) fn transcode_image(
+    fname: &str,
+    data: &[u8],
+    config: &ImageTranscodeConfig,
+) -> Option<(String, Vec<u8>)> {
+    if data.len() < config.min_size {
+        return None;
+    }
+    let (stem, ext) = split_extension(fname);
+    let format = transcodable_format(ext)?;
+    let mut image = image::load_from_memory_with_format(data, format).ok()?;
+
+    let max = config.max_dimension;
+    if max > 0 && (image.width() > max || image.height() > max) {
+        image = image.resize(max, max, FilterType::Triangle);
+    }
+
+    let rgba = image.to_rgba();
+    let webp = webp::Encoder::from_rgba(&rgba, rgba.width(), rgba.height())
+        .encode(config.quality.max(0.0).min(100.0));
+    if webp.len() >= data.len() {
+        return None;
+    }
+
+    Some((format!("{}.webp", stem), webp.to_vec()))
+}
+
+impl MediaManager {
+    /// Like add_file(), but large PNG and BMP images are converted to WebP
+    /// first, and scaled down if they exceed the configured size.
+    pub fn add_file_transcoding(
+        &mut self,
+        desired_name: &str,
+        data: &[u8],
+        config: &ImageTranscodeConfig,
+    ) -> Result<String> {
+        match transcode_image(desired_name, data, config) {
+            Some((fname, data)) => self.add_file(&fname, &data),
+            None => self.add_file(desired_name, data),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::err::Result;
+    use crate::media::transcode::{transcode_image, ImageTranscodeConfig};
+    use crate::media::MediaManager;
+    use image::{DynamicImage, ImageFormat, RgbaImage};
+    use std::fs;
+    use std::io::Cursor;
+    use tempfile::tempdir;
+
+    fn png_data(width: u32, height: u32) -> Vec<u8> {
+        // a noisy gradient, which PNG compresses poorly, like a photo
+        let image = RgbaImage::from_fn(width, height, |x, y| {
+            let noise = (x.wrapping_mul(7919) ^ y.wrapping_mul(104_729)) % 32;
+            image::Rgba([(x % 224 + noise) as u8, (y % 224 + noise) as u8, 128, 255])
+        });
+        let mut data = Cursor::new(vec![]);
+        DynamicImage::ImageRgba8(image)
+            .write_to(&mut data, ImageFormat::PNG)
+            .unwrap();
+        data.into_inner()
+    }
+
+    #[test]
+    fn test_transcode() {
+        let config = ImageTranscodeConfig {
+            quality: 80.0,
+            max_dimension: 100,
+            min_size: 0,
+        };
+        let png = png_data(400, 200);
+
+        let (fname, data) = transcode_image("paste.PNG", &png, &config).unwrap();
+        assert_eq!(fname, "paste.webp");
+        assert!(data.len() < png.len());
+        // scaled down to fit, preserving the aspect ratio
+        let image = webp::Decoder::new(&data).decode().unwrap();
+        assert_eq!((image.width(), image.height()), (100, 50));
+
+        // other formats, small files and invalid data are left alone
+        assert_eq!(transcode_image("paste.jpg", &png, &config), None);
+        assert_eq!(transcode_image("png", &png, &config), None);
+        assert_eq!(
+            transcode_image(
+                "paste.png",
+                &png,
+                &ImageTranscodeConfig {
+                    min_size: png.len() + 1,
+                    ..config.clone()
+                }
+            ),
+            None
+        );
+        assert_eq!(transcode_image("paste.png", b"not a png", &config), None);
+    }
+
+    #[test]
+    fn test_add_transcoded() -> Result<()> {
+        let dir = tempdir()?;
+        let media_dir = dir.path().join("media");
+        fs::create_dir(&media_dir)?;
+        let mut mgr = MediaManager::new(&media_dir, dir.path().join("media.db"))?;
+
+        let config = ImageTranscodeConfig {
+            min_size: 0,
+            ..Default::default()
+        };
+        let fname = mgr.add_file_transcoding("image.png", &png_data(50, 50), &config)?;
+        assert_eq!(fname, "image.webp");
+        assert!(media_dir.join("image.webp").exists());
+        assert!(!media_dir.join("image.png").exists());
+
+        Ok(())
+    }
+}
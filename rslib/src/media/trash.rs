@@ -0,0 +1,152 @@
+// Copyright: Ankitects Pty Ltd and contributors
+// License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+use crate::err::{AnkiError, Result};
+use crate::media::files::{move_file_to_trash, mtime_as_i64, trash_folder};
+use crate::media::MediaManager;
+use std::fs;
+use std::path::Path;
+
+/// A file in the media trash folder.
+#[derive(Debug, PartialEq)]
+pub struct TrashedFile {
+    pub fname: String,
+    /// The size in bytes.
+    pub size: u64,
+    /// The modification time of the file, in seconds.
+    pub mtime: i64,
+}
+
+/// Only plain filenames are accepted, so that callers can't refer to files
+/// outside the media or trash folders.
+fn ensure_plain_filename(fname: &str) -> Result<()> {
+    if Path::new(fname).file_name().and_then(|f| f.to_str()) != Some(fname) {
+        Err(AnkiError::invalid_input(format!(
+            "invalid media filename: {}",
+            fname
+        )))
+    } else {
+        Ok(())
+    }
+}
+
+impl MediaManager {
+    /// Move the provided files from the media folder into the trash folder,
+    /// which is stored next to the media folder. Files that don't exist are
+    /// skipped.
+    pub fn trash_files(&mut self, fnames: &[&str]) -> Result<()> {
+        for fname in fnames {
+            ensure_plain_filename(fname)?;
+            if self.media_folder.join(fname).exists() {
+                move_file_to_trash(&self.media_folder, fname)?;
+            }
+        }
+
+        self.register_changes(true)?;
+
+        Ok(())
+    }
+
+    /// The files in the trash folder, sorted by name.
+    pub fn trash_contents(&self) -> Result<Vec<TrashedFile>> {
+        let mut files = vec![];
+        for dentry in fs::read_dir(trash_folder(&self.media_folder)?)? {
+            let dentry = dentry?;
+            let metadata = dentry.metadata()?;
+            if !metadata.is_file() {
+                continue;
+            }
+            if let Ok(fname) = dentry.file_name().into_string() {
+                files.push(TrashedFile {
+                    fname,
+                    size: metadata.len(),
+                    mtime: mtime_as_i64(dentry.path())?,
+                });
+            }
+        }
+        files.sort_unstable_by(|a, b| a.fname.cmp(&b.fname));
+
+        Ok(files)
+    }
+
+    /// Move the provided files from the trash back into the media folder,
+    /// returning the names they were restored under. If a different file
+    /// with the same name has been added to the media folder since, a
+    /// number is appended to the name. Files not in the trash are skipped.
+    pub fn restore_from_trash(&mut self, fnames: &[&str]) -> Result<Vec<String>> {
+        let trash = trash_folder(&self.media_folder)?;
+        let mut restored = vec![];
+
+        for fname in fnames {
+            ensure_plain_filename(fname)?;
+            let path = trash.join(fname);
+            if !path.exists() {
+                continue;
+            }
+            let data = fs::read(&path)?;
+            restored.push(self.add_file(fname, &data)?);
+            fs::remove_file(&path)?;
+        }
+
+        Ok(restored)
+    }
+
+    /// Permanently delete all files in the trash folder.
+    pub fn empty_trash(&self) -> Result<()> {
+        for dentry in fs::read_dir(trash_folder(&self.media_folder)?)? {
+            let dentry = dentry?;
+            if dentry.file_type()?.is_dir() {
+                fs::remove_dir_all(dentry.path())?;
+            } else {
+                fs::remove_file(dentry.path())?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::err::Result;
+    use crate::media::MediaManager;
+    use std::fs;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_trash() -> Result<()> {
+        let dir = tempdir()?;
+        let media_dir = dir.path().join("collection.media");
+        fs::create_dir(&media_dir)?;
+        let mut mgr = MediaManager::new(&media_dir, dir.path().join("media.db"))?;
+
+        fs::write(media_dir.join("a.jpg"), "a")?;
+        fs::write(media_dir.join("b.jpg"), "bb")?;
+        mgr.trash_files(&["a.jpg", "b.jpg"])?;
+        assert_eq!(mgr.file_count()?, 0);
+
+        let contents = mgr.trash_contents()?;
+        assert_eq!(
+            contents
+                .iter()
+                .map(|f| (f.fname.as_str(), f.size))
+                .collect::<Vec<_>>(),
+            vec![("a.jpg", 1), ("b.jpg", 2)]
+        );
+
+        // a file of the same name was added in the meantime
+        fs::write(media_dir.join("a.jpg"), "new")?;
+        assert_eq!(
+            mgr.restore_from_trash(&["a.jpg", "missing.jpg"])?,
+            vec!["a (1).jpg"]
+        );
+        assert_eq!(fs::read_to_string(media_dir.join("a (1).jpg"))?, "a");
+        assert!(mgr.restore_from_trash(&["../media.db"]).is_err());
+        assert_eq!(mgr.trash_contents()?.len(), 1);
+
+        mgr.empty_trash()?;
+        assert_eq!(mgr.trash_contents()?, vec![]);
+
+        Ok(())
+    }
+}
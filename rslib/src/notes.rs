@@ -0,0 +1,791 @@
+// Copyright: Ankitects Pty Ltd and contributors
+// License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+use crate::card::{Card, CardType};
+use crate::cardgen::CardGenContext;
+use crate::err::{AnkiError, Result};
+use crate::notetypes::{CardTemplate, NoteType, NoteTypeKind};
+use crate::storage::{now_millis, GraveKind, SqliteStorage};
+use crate::tags::{canonical_tags, register_tags};
+use crate::text::{
+    decode_entities, normalize_to_nfc, sort_field_text, strip_html_preserving_image_filenames,
+};
+use crate::undo::UndoableChange;
+use rand::Rng;
+use serde_json::Value;
+use sha1::Sha1;
+use std::borrow::Cow;
+use std::collections::HashSet;
+
+/// A row in the notes table.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Note {
+    pub id: i64,
+    pub guid: String,
+    pub notetype_id: i64,
+    pub mtime_secs: i64,
+    pub usn: i32,
+    pub tags: Vec<String>,
+    pub fields: Vec<String>,
+    /// The field the browser sorts on, with HTML stripped.
+    pub sort_field: String,
+    /// See field_checksum().
+    pub checksum: u32,
+    pub flags: u32,
+    pub data: String,
+}
+
+/// Split a space-separated list of tags. Like the legacy code, ideographic
+/// spaces are also accepted as separators.
+pub(crate) fn split_tags(tags: &str) -> impl Iterator<Item = &str> {
+    tags.split(&[' ', '\u{3000}'][..])
+        .filter(|tag| !tag.is_empty())
+}
+
+/// The characters guids are encoded with: all printable ASCII except quotes,
+/// backslash and the separators used in text exports.
+const GUID_CHARS: &[u8] =
+    b"abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789!#$%&()*+,-./:;<=>?@[]^_`{|}~";
+
+/// A random guid, which identifies a note across collections. Like the
+/// legacy code, it's a 64 bit number in base 91.
+pub(crate) fn new_guid() -> String {
+    let base = GUID_CHARS.len() as u64;
+    let mut num: u64 = rand::random();
+    let mut buf = vec![];
+    while num > 0 {
+        buf.push(GUID_CHARS[(num % base) as usize]);
+        num /= base;
+    }
+    buf.reverse();
+    String::from_utf8(buf).unwrap()
+}
+
+impl Note {
+    /// Fields are stored separated by 0x1f.
+    pub(crate) fn joined_fields(&self) -> String {
+        self.fields.join("\x1f")
+    }
+
+    /// Tags are stored separated by spaces, with a leading and trailing
+    /// space.
+    pub(crate) fn joined_tags(&self) -> String {
+        join_tags(&self.tags)
+    }
+
+    /// Update the sort field and checksum from the fields.
+    pub(crate) fn update_field_cache(&mut self, sort_field_idx: u16) {
+        let sort_field = self
+            .fields
+            .get(sort_field_idx as usize)
+            .map(String::as_str)
+            .unwrap_or_default();
+        self.sort_field = sort_field_text(sort_field).into_owned();
+        self.checksum = self
+            .fields
+            .first()
+            .map(|field| field_checksum(field))
+            .unwrap_or_default();
+    }
+}
+
+/// Join tags for storage, with a leading and trailing space.
+pub(crate) fn join_tags<S: AsRef<str>>(tags: &[S]) -> String {
+    if tags.is_empty() {
+        "".into()
+    } else {
+        let tags: Vec<_> = tags.iter().map(AsRef::as_ref).collect();
+        format!(" {} ", tags.join(" "))
+    }
+}
+
+/// The checksum stored in notes.csum, used to find duplicates of the
+/// first field. Matches the legacy Python implementation: HTML is stripped
+/// (keeping image filenames), entities are decoded, and the first 32 bits
+/// of the SHA1 hash are returned.
+pub fn field_checksum(text: &str) -> u32 {
+    let digest = Sha1::from(checksum_text(text)).digest().bytes();
+    u32::from_be_bytes([digest[0], digest[1], digest[2], digest[3]])
+}
+
+/// The text of a field that field_checksum() hashes.
+pub(crate) fn checksum_text(text: &str) -> String {
+    let stripped = strip_html_preserving_image_filenames(text);
+    decode_entities(&stripped.replace("&nbsp;", " ")).into_owned()
+}
+
+// Adding and updating
+//----------------------------------------
+
+/// What saving a note changed.
+#[derive(Debug, Default, PartialEq)]
+pub struct NoteSaveOutcome {
+    pub added_card_ids: Vec<i64>,
+    /// New cards the note's fields no longer call for.
+    pub removed_card_ids: Vec<i64>,
+    /// Set if a cloze note has no cloze deletions, so its only card will be
+    /// blank. The note is saved regardless.
+    pub missing_cloze: bool,
+    /// The previous state of the changed rows, for undo.
+    pub changes: Vec<UndoableChange>,
+}
+
+/// Add a new note and the cards its fields call for, in a single
+/// transaction. The note's id, guid, tags and field cache are updated.
+/// Cards go in the deck their template overrides the deck with, or in
+/// `deck_id`. It's an error to add a note that would have no cards.
+pub fn add_note(
+    storage: &mut SqliteStorage,
+    note: &mut Note,
+    deck_id: i64,
+    usn: i32,
+    mtime_secs: i64,
+) -> Result<NoteSaveOutcome> {
+    storage.transact(|storage| {
+        let notetype = notetype_of_note(storage, note)?;
+        let cardgen = CardGenContext::for_notetype(&notetype);
+        prepare_note(storage, note, &notetype, usn)?;
+        let fields: Vec<_> = note.fields.iter().map(String::as_str).collect();
+        let mut ords: Vec<_> = cardgen.ords_to_generate(&fields).into_iter().collect();
+        if ords.is_empty() {
+            return Err(AnkiError::invalid_input("the note would have no cards"));
+        }
+        ords.sort_unstable();
+        let missing_cloze = cardgen.missing_cloze(&fields);
+
+        note.mtime_secs = mtime_secs;
+        note.usn = usn;
+        if note.guid.is_empty() {
+            note.guid = new_guid();
+        }
+        storage.add_note(note)?;
+        let mut changes = vec![UndoableChange::Note {
+            id: note.id,
+            previous: None,
+        }];
+
+        let added_card_ids = add_cards(
+            storage,
+            note.id,
+            &notetype,
+            &ords,
+            deck_id,
+            usn,
+            mtime_secs,
+            &mut changes,
+        )?;
+        storage.mark_modified(now_millis())?;
+
+        Ok(NoteSaveOutcome {
+            added_card_ids,
+            removed_card_ids: vec![],
+            missing_cloze,
+            changes,
+        })
+    })
+}
+
+/// Save the changed fields and tags of an existing note in a single
+/// transaction, adding the cards the fields now call for and removing new
+/// cards they no longer do. Cards that have been studied are kept. Nothing
+/// is written if the note is unchanged.
+pub fn update_note(
+    storage: &mut SqliteStorage,
+    note: &mut Note,
+    usn: i32,
+    mtime_secs: i64,
+) -> Result<NoteSaveOutcome> {
+    storage.transact(|storage| {
+        let existing = storage
+            .get_note(note.id)?
+            .ok_or_else(|| AnkiError::invalid_input("no such note"))?;
+        if existing.notetype_id != note.notetype_id {
+            return Err(AnkiError::invalid_input(
+                "the note type of a note can't be changed when it's saved",
+            ));
+        }
+        let notetype = notetype_of_note(storage, note)?;
+        let cardgen = CardGenContext::for_notetype(&notetype);
+        prepare_note(storage, note, &notetype, usn)?;
+        let fields: Vec<_> = note.fields.iter().map(String::as_str).collect();
+        let missing_cloze = cardgen.missing_cloze(&fields);
+        let cards = storage.get_cards_of_note(note.id)?;
+        let existing_ords: HashSet<_> = cards.iter().map(|card| card.ordinal).collect();
+        let card_changes = cardgen.card_changes(&fields, &existing_ords);
+        if note.fields == existing.fields && note.tags == existing.tags {
+            note.mtime_secs = existing.mtime_secs;
+            note.usn = existing.usn;
+            return Ok(NoteSaveOutcome {
+                missing_cloze,
+                ..Default::default()
+            });
+        }
+
+        note.mtime_secs = mtime_secs;
+        note.usn = usn;
+        storage.update_note(note)?;
+        let mut changes = vec![UndoableChange::Note {
+            id: note.id,
+            previous: Some(existing),
+        }];
+
+        // like the legacy code, added cards go in the deck of the note's
+        // other cards
+        let home_deck_id = home_deck_of_cards(&cards).unwrap_or_else(|| default_deck_id(&notetype));
+        let added_card_ids = add_cards(
+            storage,
+            note.id,
+            &notetype,
+            &card_changes.to_add,
+            home_deck_id,
+            usn,
+            mtime_secs,
+            &mut changes,
+        )?;
+
+        let mut removed_card_ids = vec![];
+        for card in cards {
+            if card.ctype == CardType::New && card_changes.to_remove.contains(&card.ordinal) {
+                storage.remove_card(card.id)?;
+                storage.add_grave(card.id, GraveKind::Card, usn)?;
+                removed_card_ids.push(card.id);
+                changes.push(UndoableChange::Card {
+                    id: card.id,
+                    previous: Some(card),
+                });
+            }
+        }
+        storage.mark_modified(now_millis())?;
+
+        Ok(NoteSaveOutcome {
+            added_card_ids,
+            removed_card_ids,
+            missing_cloze,
+            changes,
+        })
+    })
+}
+
+// Changing the note type
+//----------------------------------------
+
+/// Change the notes to `new_notetype_id` in a single transaction.
+/// `new_field_ords` gives the new index of each field of the current note
+/// type, or None to drop the field's content, and `new_template_ords` does
+/// the same for the cards of each template. The cards of a cloze note type
+/// are numbered by their deletions, so they keep their ordinals, and are
+/// removed if the new note type doesn't have a template for them. Missing
+/// cards are then generated, and notes left without any cards are removed.
+/// The notes must share a note type.
+pub fn change_notetype_of_notes(
+    storage: &mut SqliteStorage,
+    note_ids: &[i64],
+    new_notetype_id: i64,
+    new_field_ords: &[Option<usize>],
+    new_template_ords: &[Option<usize>],
+    usn: i32,
+    mtime_secs: i64,
+) -> Result<Vec<UndoableChange>> {
+    storage.transact(|storage| {
+        let mut notes = vec![];
+        for id in note_ids {
+            if let Some(note) = storage.get_note(*id)? {
+                notes.push(note);
+            }
+        }
+        let old_notetype_id = match notes.first() {
+            Some(note) => note.notetype_id,
+            None => return Ok(vec![]),
+        };
+        if notes.iter().any(|note| note.notetype_id != old_notetype_id) {
+            return Err(AnkiError::invalid_input(
+                "the notes must have the same note type",
+            ));
+        }
+        let old_notetype = storage
+            .get_notetype(old_notetype_id)?
+            .ok_or_else(|| AnkiError::invalid_input("no such note type"))?;
+        let new_notetype = storage
+            .get_notetype(new_notetype_id)?
+            .ok_or_else(|| AnkiError::invalid_input("no such note type"))?;
+        let new_is_cloze = new_notetype.kind() == NoteTypeKind::Cloze;
+        check_mapped_ords(
+            new_field_ords,
+            old_notetype.fields.len(),
+            Some(new_notetype.fields.len()),
+        )?;
+        if old_notetype.kind() == NoteTypeKind::Standard {
+            // any card number can be mapped to a cloze deletion
+            let limit = if new_is_cloze {
+                None
+            } else {
+                Some(new_notetype.templates.len())
+            };
+            check_mapped_ords(new_template_ords, old_notetype.templates.len(), limit)?;
+        }
+        let cardgen = CardGenContext::for_notetype(&new_notetype);
+
+        let mut changes = vec![];
+        for mut note in notes {
+            let previous = note.clone();
+            let mut fields = vec![String::new(); new_notetype.fields.len()];
+            for (field, new_ord) in note.fields.drain(..).zip(new_field_ords) {
+                if let Some(new_ord) = new_ord {
+                    fields[*new_ord] = field;
+                }
+            }
+            note.fields = fields;
+            note.notetype_id = new_notetype_id;
+            note.update_field_cache(new_notetype.sort_field_idx);
+            note.mtime_secs = mtime_secs;
+            note.usn = usn;
+            storage.update_note(&note)?;
+            changes.push(UndoableChange::Note {
+                id: note.id,
+                previous: Some(previous),
+            });
+
+            let cards = storage.get_cards_of_note(note.id)?;
+            let home_deck_id =
+                home_deck_of_cards(&cards).unwrap_or_else(|| default_deck_id(&new_notetype));
+            let mut existing_ords = HashSet::new();
+            for card in cards {
+                let new_ord = if old_notetype.kind() == NoteTypeKind::Cloze {
+                    Some(card.ordinal).filter(|ord| {
+                        new_is_cloze || (*ord as usize) < new_notetype.templates.len()
+                    })
+                } else {
+                    new_template_ords
+                        .get(card.ordinal as usize)
+                        .copied()
+                        .flatten()
+                        .map(|ord| ord as u16)
+                };
+                match new_ord {
+                    Some(ord) => {
+                        existing_ords.insert(ord);
+                        if ord != card.ordinal {
+                            let mut updated = card.clone();
+                            updated.ordinal = ord;
+                            updated.mtime_secs = mtime_secs;
+                            updated.usn = usn;
+                            storage.update_card(&updated)?;
+                            changes.push(UndoableChange::Card {
+                                id: card.id,
+                                previous: Some(card),
+                            });
+                        }
+                    }
+                    None => {
+                        storage.remove_card(card.id)?;
+                        storage.add_grave(card.id, GraveKind::Card, usn)?;
+                        changes.push(UndoableChange::Card {
+                            id: card.id,
+                            previous: Some(card),
+                        });
+                    }
+                }
+            }
+
+            let fields: Vec<_> = note.fields.iter().map(String::as_str).collect();
+            let to_add = cardgen.card_changes(&fields, &existing_ords).to_add;
+            add_cards(
+                storage,
+                note.id,
+                &new_notetype,
+                &to_add,
+                home_deck_id,
+                usn,
+                mtime_secs,
+                &mut changes,
+            )?;
+            if existing_ords.is_empty() && to_add.is_empty() {
+                storage.remove_note(note.id)?;
+                storage.add_grave(note.id, GraveKind::Note, usn)?;
+            }
+        }
+        storage.mark_schema_modified(now_millis())?;
+
+        Ok(changes)
+    })
+}
+
+/// There must be an entry for each old field or template, and each new
+/// one may only be used once. Ordinals must be below `new_count` if it's
+/// provided.
+fn check_mapped_ords(
+    new_ords: &[Option<usize>],
+    old_count: usize,
+    new_count: Option<usize>,
+) -> Result<()> {
+    if new_ords.len() != old_count {
+        return Err(AnkiError::invalid_input(
+            "the ordinals don't match the note type",
+        ));
+    }
+    let mut seen = HashSet::new();
+    for ord in new_ords.iter().flatten() {
+        let out_of_range = new_count.iter().any(|count| ord >= count);
+        if out_of_range || !seen.insert(*ord) {
+            return Err(AnkiError::invalid_input(format!(
+                "invalid ordinal: {}",
+                ord
+            )));
+        }
+    }
+    Ok(())
+}
+
+fn notetype_of_note(storage: &SqliteStorage, note: &Note) -> Result<NoteType> {
+    let notetype = storage
+        .get_notetype(note.notetype_id)?
+        .ok_or_else(|| AnkiError::invalid_input("no such note type"))?;
+    if note.fields.len() != notetype.fields.len() {
+        return Err(AnkiError::invalid_input(format!(
+            "the note has {} fields, but its note type has {}",
+            note.fields.len(),
+            notetype.fields.len()
+        )));
+    }
+    Ok(notetype)
+}
+
+/// Normalize the fields unless the user has turned that off, tidy the tags
+/// like the legacy code does, registering any new ones, and update the
+/// field cache.
+fn prepare_note(
+    storage: &SqliteStorage,
+    note: &mut Note,
+    notetype: &NoteType,
+    usn: i32,
+) -> Result<()> {
+    if storage
+        .get_config_value("normalize_note_text")?
+        .unwrap_or(true)
+    {
+        for field in &mut note.fields {
+            if let Cow::Owned(normalized) = normalize_to_nfc(field) {
+                *field = normalized;
+            }
+        }
+    }
+
+    let tags = note
+        .tags
+        .iter()
+        .flat_map(|tags| split_tags(tags))
+        .map(|tag| tag.replace(&['"', '\''][..], ""));
+    note.tags = register_tags(storage, &canonical_tags(tags), usn)?;
+    note.tags.sort();
+    note.update_field_cache(notetype.sort_field_idx);
+
+    Ok(())
+}
+
+/// The deck of the first card, or the deck it was moved into a filtered
+/// deck from.
+fn home_deck_of_cards(cards: &[Card]) -> Option<i64> {
+    cards.first().map(|card| {
+        if card.original_deck_id != 0 {
+            card.original_deck_id
+        } else {
+            card.deck_id
+        }
+    })
+}
+
+/// The deck the note type adds cards to when no deck is provided.
+fn default_deck_id(notetype: &NoteType) -> i64 {
+    notetype
+        .other
+        .get("did")
+        .and_then(Value::as_i64)
+        .unwrap_or(1)
+}
+
+/// Add a new card for each ordinal, sharing the next new card position.
+/// Returns the ids of the added cards.
+#[allow(clippy::too_many_arguments)]
+fn add_cards(
+    storage: &SqliteStorage,
+    note_id: i64,
+    notetype: &NoteType,
+    ords: &[u16],
+    deck_id: i64,
+    usn: i32,
+    mtime_secs: i64,
+    changes: &mut Vec<UndoableChange>,
+) -> Result<Vec<i64>> {
+    if ords.is_empty() {
+        return Ok(vec![]);
+    }
+    let position: i64 = storage.get_config_value("nextPos")?.unwrap_or(1);
+    storage.set_config_value("nextPos", &(position + 1))?;
+    // the cards of a note share a random position too
+    let random_position = rand::thread_rng().gen_range(1, position.max(1000));
+
+    let mut card_ids = vec![];
+    for ord in ords {
+        let deck_id = deck_for_new_card(storage, notetype.templates.get(*ord as usize), deck_id)?;
+        let mut card = Card {
+            note_id,
+            deck_id,
+            ordinal: *ord,
+            mtime_secs,
+            usn,
+            due: if new_cards_in_random_order(storage, deck_id)? {
+                random_position
+            } else {
+                position
+            },
+            ..Default::default()
+        };
+        storage.add_card(&mut card)?;
+        card_ids.push(card.id);
+        changes.push(UndoableChange::Card {
+            id: card.id,
+            previous: None,
+        });
+    }
+
+    Ok(card_ids)
+}
+
+/// The deck the template overrides the deck with, if it exists, or else
+/// `deck_id`. Cards can't be added to a filtered deck, so the default deck
+/// is used instead, as it is if neither deck exists.
+fn deck_for_new_card(
+    storage: &SqliteStorage,
+    template: Option<&CardTemplate>,
+    deck_id: i64,
+) -> Result<i64> {
+    let template_deck_id = template
+        .and_then(|template| template.other.get("did"))
+        .and_then(Value::as_i64)
+        .filter(|id| *id != 0);
+    for id in template_deck_id.into_iter().chain(Some(deck_id)) {
+        if let Some(deck) = storage.get_deck(id)? {
+            return Ok(if deck.is_filtered() { 1 } else { deck.id });
+        }
+    }
+    Ok(1)
+}
+
+/// True if the options of the deck show new cards in random order.
+fn new_cards_in_random_order(storage: &SqliteStorage, deck_id: i64) -> Result<bool> {
+    let conf_id = storage
+        .get_deck(deck_id)?
+        .and_then(|deck| deck.config_id())
+        .unwrap_or(1);
+    let order = storage
+        .get_deck_conf(conf_id)?
+        .and_then(|conf| conf.other.get("new")?.get("order")?.as_i64());
+    Ok(order == Some(0))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::err::Result;
+    use crate::notes::{add_note, change_notetype_of_notes, field_checksum, update_note, Note};
+    use crate::storage::testutil::{add_deck, add_filtered_deck, notetype, open_test_collection};
+    use crate::storage::SqliteStorage;
+    use crate::undo::{UndoManager, UndoableOp};
+
+    #[test]
+    fn test_checksum() {
+        assert_eq!(field_checksum("test"), 2_840_236_005);
+        assert_eq!(field_checksum("a&nbsp;b"), 2_109_598_005);
+        assert_eq!(
+            field_checksum("<b>ab</b>&amp;c<img src=x.jpg>"),
+            771_190_691
+        );
+    }
+
+    #[test]
+    fn test_add_and_update_note() -> Result<()> {
+        let (_dir, mut storage) = open_test_collection()?;
+        let mut notetype = notetype(
+            1,
+            "Basic",
+            &["Front", "Back"],
+            &["{{Front}}", "{{#Back}}{{Back}}{{/Back}}"],
+        );
+        notetype.sort_field_idx = 1;
+        storage.add_or_update_notetype(&notetype)?;
+        add_deck(&storage, 1, "Default")?;
+        add_filtered_deck(&storage, 2, "Filtered")?;
+        storage.set_config_value("nextPos", &5)?;
+
+        // cards can't be added to a filtered deck
+        let mut note = Note {
+            notetype_id: 1,
+            tags: vec!["b".into(), "A".into(), "a".into(), "\"c\"".into()],
+            fields: vec!["<b>cafe\u{301}</b>".into(), "".into()],
+            ..Default::default()
+        };
+        let outcome = add_note(&mut storage, &mut note, 2, -1, 10)?;
+        assert_eq!(outcome.added_card_ids.len(), 1);
+        assert!(!outcome.missing_cloze);
+        let card = storage.get_card(outcome.added_card_ids[0])?.unwrap();
+        assert_eq!((card.deck_id, card.ordinal, card.due), (1, 0, 5));
+        assert_eq!(note.tags, vec!["A", "b", "c"]);
+        assert!(!note.guid.is_empty());
+        assert_eq!(storage.get_note(note.id)?.unwrap(), note);
+        // fields are normalized
+        assert_eq!(note.fields[0], "<b>caf\u{e9}</b>");
+        assert_eq!(note.checksum, field_checksum("caf\u{e9}"));
+        assert_eq!(storage.get_config_value::<i64>("nextPos")?, Some(6));
+
+        // the fields must suit the note type, and call for a card
+        let mut invalid = Note {
+            notetype_id: 1,
+            fields: vec!["".into(), "".into()],
+            tags: vec!["new".into()],
+            ..Default::default()
+        };
+        assert!(add_note(&mut storage, &mut invalid, 1, -1, 10).is_err());
+        invalid.fields.pop();
+        assert!(add_note(&mut storage, &mut invalid, 1, -1, 10).is_err());
+        assert!(!storage.get_all_tags()?.contains_key("new"));
+
+        // filling in the back adds a card, and the sort field is updated
+        let mut undo = UndoManager::default();
+        note.fields[1] = "back".into();
+        note.tags.push("a".into());
+        let outcome = update_note(&mut storage, &mut note, -1, 20)?;
+        assert_eq!(outcome.added_card_ids.len(), 1);
+        assert_eq!(note.tags, vec!["A", "b", "c"]);
+        assert_eq!((note.sort_field.as_str(), note.mtime_secs), ("back", 20));
+        let added = storage.get_card(outcome.added_card_ids[0])?.unwrap();
+        assert_eq!((added.ordinal, added.due), (1, 6));
+        assert!(update_note(&mut storage, &mut note, -1, 30)?
+            .changes
+            .is_empty());
+        assert_eq!(note.mtime_secs, 20);
+
+        // emptying it again removes the card, which can be undone
+        note.fields[1] = "".into();
+        let outcome = update_note(&mut storage, &mut note, -1, 30)?;
+        assert_eq!(outcome.removed_card_ids, vec![added.id]);
+        assert!(storage.get_card(added.id)?.is_none());
+        undo.add_op(UndoableOp {
+            name: "Update Note".into(),
+            changes: outcome.changes,
+        });
+        undo.undo(&mut storage)?;
+        assert_eq!(storage.get_card(added.id)?, Some(added));
+        assert_eq!(storage.get_note(note.id)?.unwrap().fields[1], "back");
+
+        // the note type can't be changed
+        note.notetype_id = 2;
+        assert!(update_note(&mut storage, &mut note, -1, 40).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_change_notetype() -> Result<()> {
+        let (_dir, mut storage) = open_test_collection()?;
+        storage.add_or_update_notetype(&notetype(
+            1,
+            "Basic",
+            &["Front", "Back"],
+            &["{{Front}}", "{{Back}}"],
+        ))?;
+        let mut cloze = notetype(2, "Cloze", &["Text", "Extra"], &["{{cloze:Text}}"]);
+        cloze.kind = 1;
+        storage.add_or_update_notetype(&cloze)?;
+        let mut note = Note {
+            notetype_id: 1,
+            fields: vec!["front".into(), "back".into()],
+            ..Default::default()
+        };
+        let card_ids = add_note(&mut storage, &mut note, 1, -1, 10)?.added_card_ids;
+        let ordinal = |storage: &SqliteStorage, id| -> Result<Option<u16>> {
+            Ok(storage.get_card(id)?.map(|card| card.ordinal))
+        };
+
+        // swap the fields and cards
+        let changes = change_notetype_of_notes(
+            &mut storage,
+            &[note.id],
+            1,
+            &[Some(1), Some(0)],
+            &[Some(1), Some(0)],
+            -1,
+            20,
+        )?;
+        assert_eq!(changes.len(), 3);
+        let changed = storage.get_note(note.id)?.unwrap();
+        assert_eq!(changed.fields, vec!["back", "front"]);
+        assert_eq!(changed.sort_field, "back");
+        assert_eq!(ordinal(&storage, card_ids[0])?, Some(1));
+        assert_eq!(ordinal(&storage, card_ids[1])?, Some(0));
+
+        // a field or template can't be mapped twice
+        assert!(change_notetype_of_notes(
+            &mut storage,
+            &[note.id],
+            1,
+            &[Some(0), Some(0)],
+            &[Some(0), Some(1)],
+            -1,
+            30
+        )
+        .is_err());
+        assert!(change_notetype_of_notes(
+            &mut storage,
+            &[note.id],
+            1,
+            &[Some(0), Some(1)],
+            &[Some(0), Some(2)],
+            -1,
+            30
+        )
+        .is_err());
+
+        // cards mapped to nothing are removed, and missing ones generated
+        let mut undo = UndoManager::default();
+        let changes = change_notetype_of_notes(
+            &mut storage,
+            &[note.id],
+            2,
+            &[Some(0), None],
+            &[None, Some(1)],
+            -1,
+            30,
+        )?;
+        assert_eq!(storage.get_note(note.id)?.unwrap().fields, vec!["back", ""]);
+        assert_eq!(ordinal(&storage, card_ids[0])?, Some(1));
+        assert_eq!(ordinal(&storage, card_ids[1])?, None);
+        // the note has no deletions, so the first card is added
+        let ords: Vec<_> = storage
+            .get_cards_of_note(note.id)?
+            .iter()
+            .map(|card| card.ordinal)
+            .collect();
+        assert_eq!(ords, vec![0, 1]);
+        undo.add_op(UndoableOp {
+            name: "Change Note Type".into(),
+            changes,
+        });
+        undo.undo(&mut storage)?;
+        assert_eq!(storage.get_note(note.id)?, Some(changed));
+        assert_eq!(ordinal(&storage, card_ids[1])?, Some(0));
+        assert_eq!(storage.get_cards_of_note(note.id)?.len(), 2);
+
+        // notes left without cards are removed
+        change_notetype_of_notes(
+            &mut storage,
+            &[note.id],
+            1,
+            &[None, None],
+            &[None, None],
+            -1,
+            40,
+        )?;
+        assert!(storage.get_note(note.id)?.is_none());
+
+        Ok(())
+    }
+}
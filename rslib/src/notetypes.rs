@@ -0,0 +1,400 @@
+// Copyright: Ankitects Pty Ltd and contributors
+// License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+//! Notetypes are stored as JSON in the col table. Only the keys the Rust
+//! code uses are typed; the rest are kept as they are, so they survive a
+//! round trip.
+
+use crate::cardgen::CardGenContext;
+use crate::err::{AnkiError, Result};
+use crate::storage::{now_millis, GraveKind, SqliteStorage};
+use serde_derive::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::collections::{HashMap, HashSet};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[repr(u8)]
+pub enum NoteTypeKind {
+    Standard = 0,
+    Cloze = 1,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct NoteType {
+    pub id: i64,
+    pub name: String,
+    #[serde(rename = "mod")]
+    pub mtime_secs: i64,
+    pub usn: i32,
+    /// See NoteTypeKind.
+    #[serde(rename = "type")]
+    pub kind: u8,
+    /// The index of the field the browser sorts on.
+    #[serde(rename = "sortf")]
+    pub sort_field_idx: u16,
+    #[serde(rename = "flds")]
+    pub fields: Vec<NoteField>,
+    #[serde(rename = "tmpls")]
+    pub templates: Vec<CardTemplate>,
+    #[serde(flatten)]
+    pub other: Map<String, Value>,
+}
+
+impl NoteType {
+    pub fn kind(&self) -> NoteTypeKind {
+        if self.kind == NoteTypeKind::Cloze as u8 {
+            NoteTypeKind::Cloze
+        } else {
+            NoteTypeKind::Standard
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct NoteField {
+    pub name: String,
+    pub ord: u16,
+    #[serde(flatten)]
+    pub other: Map<String, Value>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CardTemplate {
+    pub name: String,
+    pub ord: u16,
+    #[serde(rename = "qfmt")]
+    pub question_format: String,
+    #[serde(rename = "afmt")]
+    pub answer_format: String,
+    #[serde(flatten)]
+    pub other: Map<String, Value>,
+}
+
+// Changing the schema
+//----------------------------------------
+
+/// Save a notetype whose fields or templates have been added, removed,
+/// renamed or reordered, changing its notes and cards to match in a single
+/// transaction. `field_ords` and `template_ords` give the previous index of
+/// each field and template, or None if it's new. Notes have their fields
+/// rearranged, and cards have their ordinals changed, or are removed with
+/// their template. Cloze cards are numbered by their deletions, so they
+/// keep their ordinals. Returns true if the change requires a full sync,
+/// which reordering templates does not.
+pub fn update_notetype_schema(
+    storage: &mut SqliteStorage,
+    notetype: &mut NoteType,
+    field_ords: &[Option<usize>],
+    template_ords: &[Option<usize>],
+    usn: i32,
+    mtime_secs: i64,
+) -> Result<bool> {
+    storage.transact(|storage| {
+        let existing = storage
+            .get_notetype(notetype.id)?
+            .ok_or_else(|| AnkiError::invalid_input("no such note type"))?;
+        check_new_ords(field_ords, notetype.fields.len(), existing.fields.len())?;
+        check_new_ords(
+            template_ords,
+            notetype.templates.len(),
+            existing.templates.len(),
+        )?;
+        check_field_names(notetype)?;
+        if notetype.templates.is_empty() {
+            return Err(AnkiError::invalid_input(
+                "a note type needs at least one template",
+            ));
+        }
+        if notetype.sort_field_idx as usize >= notetype.fields.len() {
+            notetype.sort_field_idx = 0;
+        }
+
+        let fields_unchanged = ords_unchanged(field_ords, existing.fields.len());
+        if !fields_unchanged || notetype.sort_field_idx != existing.sort_field_idx {
+            update_note_fields(storage, notetype, field_ords, usn, mtime_secs)?;
+        }
+        if existing.kind() == NoteTypeKind::Standard
+            && !ords_unchanged(template_ords, existing.templates.len())
+        {
+            update_card_ords(storage, notetype.id, template_ords, usn, mtime_secs)?;
+        }
+
+        for (ord, field) in notetype.fields.iter_mut().enumerate() {
+            field.ord = ord as u16;
+        }
+        for (ord, template) in notetype.templates.iter_mut().enumerate() {
+            template.ord = ord as u16;
+        }
+        if let Some(reqs) = CardGenContext::for_notetype(notetype).legacy_requirements() {
+            notetype.other.insert("req".into(), reqs);
+        }
+        notetype.mtime_secs = mtime_secs;
+        notetype.usn = usn;
+        storage.add_or_update_notetype(notetype)?;
+
+        // reordering templates changes only the ordinals of cards, which a
+        // normal sync can send
+        let templates_added_or_removed = template_ords.len() != existing.templates.len()
+            || template_ords.iter().any(Option::is_none);
+        let names_changed = notetype
+            .fields
+            .iter()
+            .map(|f| &f.name)
+            .ne(existing.fields.iter().map(|f| &f.name));
+        let full_sync_required = !fields_unchanged || names_changed || templates_added_or_removed;
+        if full_sync_required {
+            storage.mark_schema_modified(now_millis())?;
+        } else {
+            storage.mark_modified(now_millis())?;
+        }
+
+        Ok(full_sync_required)
+    })
+}
+
+/// There must be an entry for each new field or template, and each old one
+/// may only be used once.
+fn check_new_ords(ords: &[Option<usize>], new_count: usize, old_count: usize) -> Result<()> {
+    if ords.len() != new_count {
+        return Err(AnkiError::invalid_input(
+            "the ordinals don't match the note type",
+        ));
+    }
+    let mut seen = HashSet::new();
+    for ord in ords.iter().flatten() {
+        if *ord >= old_count || !seen.insert(*ord) {
+            return Err(AnkiError::invalid_input(format!(
+                "invalid ordinal: {}",
+                ord
+            )));
+        }
+    }
+    Ok(())
+}
+
+fn check_field_names(notetype: &NoteType) -> Result<()> {
+    if notetype.fields.is_empty() {
+        return Err(AnkiError::invalid_input(
+            "a note type needs at least one field",
+        ));
+    }
+    let mut seen = HashSet::new();
+    for field in &notetype.fields {
+        if field.name.trim().is_empty() {
+            return Err(AnkiError::invalid_input("fields must have a name"));
+        }
+        if !seen.insert(field.name.as_str()) {
+            return Err(AnkiError::invalid_input(format!(
+                "duplicate field name: {}",
+                field.name
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// True if nothing was added, removed or moved.
+fn ords_unchanged(ords: &[Option<usize>], old_count: usize) -> bool {
+    ords.len() == old_count && ords.iter().enumerate().all(|(new, old)| *old == Some(new))
+}
+
+/// Rearrange the fields of the notetype's notes, and update their sort
+/// field and checksum.
+fn update_note_fields(
+    storage: &SqliteStorage,
+    notetype: &NoteType,
+    field_ords: &[Option<usize>],
+    usn: i32,
+    mtime_secs: i64,
+) -> Result<()> {
+    for note_id in storage.note_ids_of_notetype(notetype.id)? {
+        let mut note = match storage.get_note(note_id)? {
+            Some(note) => note,
+            None => continue,
+        };
+        note.fields = field_ords
+            .iter()
+            .map(|ord| {
+                ord.and_then(|ord| note.fields.get(ord).cloned())
+                    .unwrap_or_default()
+            })
+            .collect();
+        note.update_field_cache(notetype.sort_field_idx);
+        note.mtime_secs = mtime_secs;
+        note.usn = usn;
+        storage.update_note(&note)?;
+    }
+    Ok(())
+}
+
+/// Give the cards of the notetype's notes the new ordinal of their
+/// template, removing the cards of removed templates. It's an error to
+/// remove every card a note has.
+fn update_card_ords(
+    storage: &SqliteStorage,
+    notetype_id: i64,
+    template_ords: &[Option<usize>],
+    usn: i32,
+    mtime_secs: i64,
+) -> Result<()> {
+    let new_ords: HashMap<u16, u16> = template_ords
+        .iter()
+        .enumerate()
+        .filter_map(|(new, old)| old.map(|old| (old as u16, new as u16)))
+        .collect();
+    for note_id in storage.note_ids_of_notetype(notetype_id)? {
+        let cards = storage.get_cards_of_note(note_id)?;
+        if !cards.is_empty() && !cards.iter().any(|c| new_ords.contains_key(&c.ordinal)) {
+            return Err(AnkiError::invalid_input(
+                "removing the template would leave notes without cards",
+            ));
+        }
+        for mut card in cards {
+            match new_ords.get(&card.ordinal) {
+                Some(ord) if *ord == card.ordinal => (),
+                Some(ord) => {
+                    card.ordinal = *ord;
+                    card.mtime_secs = mtime_secs;
+                    card.usn = usn;
+                    storage.update_card(&card)?;
+                }
+                None => {
+                    storage.remove_card(card.id)?;
+                    storage.add_grave(card.id, GraveKind::Card, usn)?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::card::{Card, CardType};
+    use crate::err::Result;
+    use crate::notes::Note;
+    use crate::notetypes::{update_notetype_schema, NoteField};
+    use crate::storage::testutil::{notetype, open_test_collection};
+    use serde_json::json;
+
+    #[test]
+    fn test_update_notetype_schema() -> Result<()> {
+        let (_dir, mut storage) = open_test_collection()?;
+        let mut notetype = notetype(1, "Basic", &["Front", "Back"], &["{{Front}}", "{{Back}}"]);
+        notetype.sort_field_idx = 1;
+        storage.add_or_update_notetype(&notetype)?;
+        let mut note = Note {
+            notetype_id: 1,
+            fields: vec!["front".into(), "back".into()],
+            ..Default::default()
+        };
+        storage.add_note(&mut note)?;
+        let mut cards = vec![];
+        for ord in 0..2 {
+            let mut card = Card {
+                note_id: note.id,
+                ordinal: ord,
+                ctype: CardType::Review,
+                ..Default::default()
+            };
+            storage.add_card(&mut card)?;
+            cards.push(card);
+        }
+        let schema_mtime = storage.schema_modified_millis()?;
+
+        // add a field between the others, and move the sort field with it
+        notetype.fields.insert(
+            1,
+            NoteField {
+                name: "Extra".into(),
+                ord: 0,
+                other: Default::default(),
+            },
+        );
+        notetype.sort_field_idx = 2;
+        let ords = [Some(0), None, Some(1)];
+        assert!(update_notetype_schema(
+            &mut storage,
+            &mut notetype,
+            &ords,
+            &[Some(0), Some(1)],
+            -1,
+            10
+        )?);
+        assert!(storage.schema_modified_millis()? > schema_mtime);
+        let updated = storage.get_note(note.id)?.unwrap();
+        assert_eq!(updated.fields, vec!["front", "", "back"]);
+        assert_eq!(
+            (updated.sort_field.as_str(), updated.mtime_secs),
+            ("back", 10)
+        );
+        let saved = storage.get_notetype(1)?.unwrap();
+        assert_eq!(saved.fields[2].ord, 2);
+        assert_eq!(
+            saved.other["req"],
+            json!([[0, "any", [0]], [1, "any", [2]]])
+        );
+
+        // fields must be named uniquely, and ordinals used once
+        notetype.fields[1].name = "Front".into();
+        assert!(update_notetype_schema(
+            &mut storage,
+            &mut notetype,
+            &[Some(0), Some(1), Some(2)],
+            &[Some(0), Some(1)],
+            -1,
+            20
+        )
+        .is_err());
+        notetype.fields[1].name = "Extra".into();
+        assert!(update_notetype_schema(
+            &mut storage,
+            &mut notetype,
+            &[Some(0), Some(0), Some(2)],
+            &[Some(0), Some(1)],
+            -1,
+            20
+        )
+        .is_err());
+
+        // reordering templates doesn't need a full sync
+        notetype.templates.swap(0, 1);
+        assert!(!update_notetype_schema(
+            &mut storage,
+            &mut notetype,
+            &[Some(0), Some(1), Some(2)],
+            &[Some(1), Some(0)],
+            -1,
+            30
+        )?);
+        assert_eq!(storage.get_card(cards[0].id)?.unwrap().ordinal, 1);
+        assert_eq!(storage.get_card(cards[1].id)?.unwrap().ordinal, 0);
+
+        // removing a template removes its cards, unless a note would be left
+        // without any
+        notetype.templates.remove(0);
+        assert!(update_notetype_schema(
+            &mut storage,
+            &mut notetype,
+            &[Some(0), Some(1), Some(2)],
+            &[Some(1)],
+            -1,
+            40
+        )?);
+        assert!(storage.get_card(cards[1].id)?.is_none());
+        assert_eq!(storage.get_card(cards[0].id)?.unwrap().ordinal, 0);
+        notetype.templates.push(notetype.templates[0].clone());
+        assert!(update_notetype_schema(
+            &mut storage,
+            &mut notetype,
+            &[Some(0), Some(1), Some(2)],
+            &[None, None],
+            -1,
+            50
+        )
+        .is_err());
+        assert_eq!(storage.get_notetype(1)?.unwrap().templates.len(), 1);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,21 @@
+// Copyright: Ankitects Pty Ltd and contributors
+// License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+/// A row in the revlog table, recording a single answer.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RevlogEntry {
+    /// The time of the answer, in milliseconds.
+    pub id: i64,
+    pub card_id: i64,
+    pub usn: i32,
+    /// 1-4, or 0 for manual rescheduling.
+    pub ease: u8,
+    /// Positive values are days, negative values seconds.
+    pub interval: i32,
+    pub last_interval: i32,
+    /// In permille.
+    pub ease_factor: u32,
+    pub taken_millis: u32,
+    /// See RevlogReviewKind; older clients may have logged other values.
+    pub review_kind: u8,
+}
@@ -0,0 +1,94 @@
+// Copyright: Ankitects Pty Ltd and contributors
+// License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+//! Japanese reading support, as used by the furigana, kana and kanji
+//! filters. Readings are written after the base text in square brackets,
+//! eg `漢字[かんじ]`, and a leading space can be used to mark where the
+//! base text starts.
+
+use lazy_static::lazy_static;
+use regex::{Captures, Regex};
+use std::borrow::Cow;
+
+lazy_static! {
+    static ref FURIGANA: Regex = Regex::new(r" ?([^ >]+?)\[(.+?)\]").unwrap();
+}
+
+mod furigana_caps {
+    // the text the reading applies to
+    pub const BASE: usize = 1;
+    // the reading
+    pub const READING: usize = 2;
+}
+
+/// Did furigana regex match a sound tag?
+fn captured_sound(caps: &Captures) -> bool {
+    caps.get(furigana_caps::READING)
+        .unwrap()
+        .as_str()
+        .starts_with("sound:")
+}
+
+/// Replace each base[reading] pair using the provided function, leaving
+/// sound tags untouched.
+fn replace_readings<F>(text: &str, mut replacer: F) -> Cow<str>
+where
+    F: FnMut(&str, &str) -> String,
+{
+    let text = text.replace("&nbsp;", " ");
+    FURIGANA
+        .replace_all(&text, |caps: &Captures| {
+            if captured_sound(caps) {
+                caps.get(0).unwrap().as_str().to_owned()
+            } else {
+                replacer(
+                    caps.get(furigana_caps::BASE).unwrap().as_str(),
+                    caps.get(furigana_caps::READING).unwrap().as_str(),
+                )
+            }
+        })
+        .into_owned()
+        .into()
+}
+
+/// Convert base[reading] pairs into <ruby> markup.
+pub fn furigana_to_ruby(text: &str) -> Cow<str> {
+    replace_readings(text, |base, reading| {
+        format!("<ruby><rb>{}</rb><rt>{}</rt></ruby>", base, reading)
+    })
+}
+
+/// Keep only the readings.
+pub fn kana_only(text: &str) -> Cow<str> {
+    replace_readings(text, |_base, reading| reading.to_string())
+}
+
+/// Keep only the base text.
+pub fn kanji_only(text: &str) -> Cow<str> {
+    replace_readings(text, |base, _reading| base.to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::ruby::{furigana_to_ruby, kana_only, kanji_only};
+
+    #[test]
+    fn test_furigana() {
+        let text = "test first[second] third[fourth]";
+        assert_eq!(kana_only(text).as_ref(), "testsecondfourth");
+        assert_eq!(kanji_only(text).as_ref(), "testfirstthird");
+        assert_eq!(
+            furigana_to_ruby("first[second]").as_ref(),
+            "<ruby><rb>first</rb><rt>second</rt></ruby>"
+        );
+        assert_eq!(
+            furigana_to_ruby("日本[にほん]&nbsp;語[ご]").as_ref(),
+            "<ruby><rb>日本</rb><rt>にほん</rt></ruby><ruby><rb>語</rb><rt>ご</rt></ruby>"
+        );
+
+        // sound tags are not readings
+        let text = "漢字[かんじ][sound:foo.mp3]";
+        assert_eq!(kana_only(text).as_ref(), "かんじ[sound:foo.mp3]");
+        assert_eq!(kanji_only(text).as_ref(), "漢字[sound:foo.mp3]");
+    }
+}
@@ -0,0 +1,283 @@
+// Copyright: Ankitects Pty Ltd and contributors
+// License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+//! The part of the scheduler that decides how long a card can be left
+//! before it is shown again. Queues, learning steps, fuzz, load balancing
+//! and leeches are handled by the answering code; an algorithm only
+//! estimates intervals and ease factors, so a different memory model can
+//! be used by implementing SchedulingAlgorithm and adding it to
+//! AlgorithmKind.
+
+use crate::err::{AnkiError, Result};
+use crate::sched::answering::{CardSchedulingState, SchedulingConfig};
+
+/// The algorithm a deck options group uses.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[repr(u8)]
+pub enum AlgorithmKind {
+    /// The SuperMemo 2 derived algorithm Anki has always used.
+    Sm2 = 0,
+}
+
+impl AlgorithmKind {
+    pub fn from_u32(n: u32) -> Result<Self> {
+        match n {
+            0 => Ok(AlgorithmKind::Sm2),
+            _ => Err(AnkiError::invalid_input(format!(
+                "unknown scheduling algorithm: {}",
+                n
+            ))),
+        }
+    }
+
+    pub fn algorithm(self) -> &'static dyn SchedulingAlgorithm {
+        match self {
+            AlgorithmKind::Sm2 => &Sm2,
+        }
+    }
+}
+
+/// A review card that is being answered.
+pub struct ReviewContext<'a> {
+    pub card: &'a CardSchedulingState,
+    pub config: &'a SchedulingConfig,
+    /// Days since the card became due. 0 if it was answered on time.
+    pub days_late: i64,
+    /// Days since the card was last answered, if it was reviewed ahead
+    /// of time in a filtered deck.
+    pub early_elapsed: Option<i64>,
+}
+
+/// The state a card moves to after an answer.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct NextState {
+    /// In days. The answering code applies the interval multiplier, fuzz
+    /// and maximum interval.
+    pub interval: f64,
+    /// In permille.
+    pub ease_factor: u16,
+}
+
+/// The possible next states of a review card, one for each answer.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ReviewStates {
+    /// The interval is used once the card has been relearnt.
+    pub again: NextState,
+    pub hard: NextState,
+    pub good: NextState,
+    pub easy: NextState,
+}
+
+impl ReviewStates {
+    pub fn for_ease(&self, ease: u8) -> NextState {
+        match ease {
+            1 => self.again,
+            2 => self.hard,
+            3 => self.good,
+            _ => self.easy,
+        }
+    }
+}
+
+pub trait SchedulingAlgorithm: Sync {
+    /// The state of a new card leaving learning. `easy` is set if it was
+    /// answered with 'easy' instead of completing its steps.
+    fn graduating_state(&self, config: &SchedulingConfig, easy: bool) -> NextState;
+
+    /// The states a review card may move to.
+    fn review_states(&self, ctx: &ReviewContext) -> ReviewStates;
+
+    /// The interval a relearning card will have once relearnt, after it
+    /// has been failed again.
+    fn relearning_failed_interval(
+        &self,
+        card: &CardSchedulingState,
+        config: &SchedulingConfig,
+    ) -> f64;
+}
+
+/// The minimum ease factor.
+const MINIMUM_EASE_FACTOR: u16 = 1300;
+
+pub struct Sm2;
+
+impl Sm2 {
+    fn adjusted_ease(card: &CardSchedulingState, adjustment: i32) -> u16 {
+        (i32::from(card.ease_factor) + adjustment).max(i32::from(MINIMUM_EASE_FACTOR)) as u16
+    }
+
+    fn lapse_interval(card: &CardSchedulingState, config: &SchedulingConfig) -> f64 {
+        let conf = &config.lapse;
+        let interval = (f64::from(card.interval) * conf.multiplier).floor();
+        interval.max(f64::from(conf.minimum_interval)).max(1.0)
+    }
+
+    /// Intervals for hard, good and easy.
+    fn review_intervals(ctx: &ReviewContext) -> (f64, f64, f64) {
+        let conf = &ctx.config.review;
+        let interval = f64::from(ctx.card.interval);
+        let factor = f64::from(ctx.card.ease_factor) / 1000.0;
+        let days_late = ctx.days_late;
+
+        (
+            interval * conf.hard_multiplier,
+            (interval + (days_late / 2) as f64) * factor,
+            (interval + days_late as f64) * factor * conf.easy_multiplier,
+        )
+    }
+
+    /// Intervals for hard, good and easy when reviewing early.
+    fn early_review_intervals(ctx: &ReviewContext, elapsed: i64) -> (f64, f64, f64) {
+        let conf = &ctx.config.review;
+        let card_factor = f64::from(ctx.card.ease_factor) / 1000.0;
+        let interval = |factor: f64, min_new_interval: f64, easy_bonus: f64| {
+            let new_interval = (elapsed as f64 * factor).max(1.0);
+            // cap interval decreases
+            (f64::from(ctx.card.interval) * min_new_interval).max(new_interval) * easy_bonus
+        };
+        let easy = conf.easy_multiplier;
+
+        (
+            // hard cards shouldn't have their interval decreased by more
+            // than 50% of the normal factor
+            interval(conf.hard_multiplier, conf.hard_multiplier / 2.0, 1.0),
+            // early good/easy reviews shouldn't decrease the previous
+            // interval
+            interval(card_factor, 1.0, 1.0),
+            // 1.3 -> 1.15
+            interval(card_factor, 1.0, easy - (easy - 1.0) / 2.0),
+        )
+    }
+}
+
+impl SchedulingAlgorithm for Sm2 {
+    fn graduating_state(&self, config: &SchedulingConfig, easy: bool) -> NextState {
+        let interval = if easy {
+            config.new.easy_interval
+        } else {
+            config.new.graduating_interval
+        };
+        NextState {
+            interval: f64::from(interval),
+            ease_factor: config.new.initial_ease,
+        }
+    }
+
+    fn review_states(&self, ctx: &ReviewContext) -> ReviewStates {
+        let (hard, good, easy) = match ctx.early_elapsed {
+            Some(elapsed) => Sm2::early_review_intervals(ctx, elapsed),
+            None => Sm2::review_intervals(ctx),
+        };
+        let state = |interval, adjustment| NextState {
+            interval,
+            ease_factor: Sm2::adjusted_ease(ctx.card, adjustment),
+        };
+
+        ReviewStates {
+            again: state(Sm2::lapse_interval(ctx.card, ctx.config), -200),
+            hard: state(hard, -150),
+            good: state(good, 0),
+            easy: state(easy, 150),
+        }
+    }
+
+    fn relearning_failed_interval(
+        &self,
+        card: &CardSchedulingState,
+        config: &SchedulingConfig,
+    ) -> f64 {
+        Sm2::lapse_interval(card, config)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::card::{CardQueue, CardType};
+    use crate::sched::algorithm::{AlgorithmKind, ReviewContext, ReviewStates};
+    use crate::sched::answering::{
+        CardSchedulingState, LapseConfig, NewCardConfig, ReviewConfig, SchedulingConfig,
+    };
+
+    fn config() -> SchedulingConfig {
+        SchedulingConfig {
+            new: NewCardConfig {
+                delays: vec![1.0, 10.0],
+                graduating_interval: 1,
+                easy_interval: 4,
+                initial_ease: 2500,
+            },
+            lapse: LapseConfig {
+                delays: vec![10.0],
+                multiplier: 0.5,
+                minimum_interval: 1,
+                leech_threshold: 8,
+                leech_suspend: true,
+            },
+            review: ReviewConfig {
+                easy_multiplier: 1.3,
+                hard_multiplier: 1.2,
+                interval_multiplier: 1.0,
+                maximum_interval: 36500,
+            },
+            algorithm: AlgorithmKind::Sm2,
+            preview_delay: None,
+        }
+    }
+
+    #[test]
+    fn test_sm2() {
+        let conf = config();
+        let sm2 = AlgorithmKind::from_u32(0).unwrap().algorithm();
+        assert!(AlgorithmKind::from_u32(1).is_err());
+
+        let state = sm2.graduating_state(&conf, true);
+        assert_eq!((state.interval, state.ease_factor), (4.0, 2500));
+
+        let card = CardSchedulingState {
+            id: 1,
+            ctype: CardType::Review,
+            queue: CardQueue::Review,
+            due: 100,
+            interval: 100,
+            ease_factor: 2500,
+            reps: 5,
+            lapses: 0,
+            left: 0,
+            deck_id: 1,
+            original_due: 0,
+            original_deck_id: 0,
+        };
+        let ctx = ReviewContext {
+            card: &card,
+            config: &conf,
+            days_late: 10,
+            early_elapsed: None,
+        };
+        let states = sm2.review_states(&ctx);
+        let summary = |states: ReviewStates| {
+            (1..=4)
+                .map(|ease| {
+                    let state = states.for_ease(ease);
+                    (state.interval.round() as u32, state.ease_factor)
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            summary(states),
+            vec![(50, 2300), (120, 2350), (263, 2500), (358, 2650)]
+        );
+
+        // reviewed 40 days into a 100 day interval
+        let ctx = ReviewContext {
+            days_late: 0,
+            early_elapsed: Some(40),
+            ..ctx
+        };
+        assert_eq!(
+            summary(sm2.review_states(&ctx)),
+            vec![(50, 2300), (60, 2350), (100, 2500), (115, 2650)]
+        );
+
+        assert_eq!(sm2.relearning_failed_interval(&card, &conf), 50.0);
+    }
+}
@@ -0,0 +1,868 @@
+// Copyright: Ankitects Pty Ltd and contributors
+// License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+//! Answering cards with the v2 scheduler. This is a port of the Python
+//! code, and must produce the same intervals.
+
+use crate::card::{CardQueue, CardType};
+use crate::err::{AnkiError, Result};
+use crate::sched::algorithm::{AlgorithmKind, ReviewContext, ReviewStates, SchedulingAlgorithm};
+use crate::sched::fuzz::{fuzz_range, fuzzed_interval};
+use crate::sched::leech::{is_leech, LeechEvent};
+use crate::sched::load_balance::LoadBalancer;
+use crate::sched::SchedTimingToday;
+use rand::Rng;
+use tracing::debug;
+
+/// The scheduling-related fields of a card.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CardSchedulingState {
+    pub id: i64,
+    pub ctype: CardType,
+    pub queue: CardQueue,
+    /// A position for new cards, a timestamp for cards in the learning and
+    /// preview queues, and a day number otherwise.
+    pub due: i64,
+    /// In days.
+    pub interval: u32,
+    /// In permille, eg 2500 for 250%.
+    pub ease_factor: u16,
+    pub reps: u32,
+    pub lapses: u32,
+    /// For cards in learning, the number of steps left until graduation,
+    /// plus 1000 times the number of steps that can be completed today.
+    pub left: u32,
+    pub deck_id: i64,
+    /// Only set for cards in a filtered deck.
+    pub original_due: i64,
+    pub original_deck_id: i64,
+}
+
+pub struct NewCardConfig {
+    /// Learning steps in minutes.
+    pub delays: Vec<f64>,
+    /// In days.
+    pub graduating_interval: u32,
+    pub easy_interval: u32,
+    pub initial_ease: u16,
+}
+
+pub struct LapseConfig {
+    /// Relearning steps in minutes.
+    pub delays: Vec<f64>,
+    /// Applied to the interval of a lapsed card.
+    pub multiplier: f64,
+    pub minimum_interval: u32,
+    /// 0 disables leech detection.
+    pub leech_threshold: u32,
+    /// If false, leeches are only tagged.
+    pub leech_suspend: bool,
+}
+
+pub struct ReviewConfig {
+    pub easy_multiplier: f64,
+    pub hard_multiplier: f64,
+    pub interval_multiplier: f64,
+    pub maximum_interval: u32,
+}
+
+/// The deck options that apply to a card. For cards in a filtered deck,
+/// these should come from the card's original deck.
+pub struct SchedulingConfig {
+    pub new: NewCardConfig,
+    pub lapse: LapseConfig,
+    pub review: ReviewConfig,
+    pub algorithm: AlgorithmKind,
+    /// Set when the card is in a filtered deck that does not reschedule
+    /// cards. Cards answered with 'again' are shown again after this many
+    /// seconds.
+    pub preview_delay: Option<u32>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[repr(u8)]
+pub enum RevlogReviewKind {
+    Learning = 0,
+    Review = 1,
+    Relearning = 2,
+    EarlyReview = 3,
+}
+
+/// The parts of a revlog entry that depend on the answer. The caller
+/// provides the id, card id, usn and time taken.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RevlogAnswer {
+    pub ease: u8,
+    /// Positive values are days, negative values seconds.
+    pub interval: i32,
+    pub last_interval: i32,
+    pub ease_factor: u16,
+    pub review_kind: RevlogReviewKind,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct AnswerOutcome {
+    /// Not set when a card is previewed.
+    pub revlog: Option<RevlogAnswer>,
+    /// Set if the card became a leech, and its note should be tagged.
+    pub leech: Option<LeechEvent>,
+}
+
+/// Update the card's scheduling state after it has been answered with
+/// `ease` (1-4). `now` is the current time in seconds. The random number
+/// generator is used to fuzz learning steps; intervals are fuzzed
+/// deterministically, or spread over quieter days if a load balancer is
+/// provided.
+pub fn answer_card<R: Rng>(
+    card: &mut CardSchedulingState,
+    ease: u8,
+    config: &SchedulingConfig,
+    timing: &SchedTimingToday,
+    now: i64,
+    load_balancer: Option<&LoadBalancer>,
+    rng: &mut R,
+) -> Result<AnswerOutcome> {
+    if !(1..=4).contains(&ease) {
+        return Err(AnkiError::invalid_input(format!("invalid ease: {}", ease)));
+    }
+
+    let mut answerer = CardAnswerer {
+        card,
+        config,
+        today: i64::from(timing.days_elapsed),
+        day_cutoff: timing.next_day_at,
+        now,
+        load_balancer,
+        rng,
+    };
+
+    if let Some(delay) = config.preview_delay {
+        answerer.answer_preview(ease, delay)?;
+        Ok(AnswerOutcome {
+            revlog: None,
+            leech: None,
+        })
+    } else {
+        answerer.answer(ease)
+    }
+}
+
+/// The delay in seconds of the current learning step.
+fn delay_for_grade(delays: &[f64], left: i64) -> f64 {
+    let left = left.rem_euclid(1000) as usize;
+    let delay = if left == 0 {
+        delays.first()
+    } else {
+        delays
+            .len()
+            .checked_sub(left)
+            .and_then(|idx| delays.get(idx))
+    };
+    // if the user deleted the final step, fall back on the first
+    let minutes = delay.or_else(|| delays.first()).copied().unwrap_or(1.0);
+
+    minutes * 60.0
+}
+
+/// The delay in seconds when 'hard' is chosen: halfway between the current
+/// and next steps.
+fn delay_for_repeating_grade(delays: &[f64], left: i64) -> f64 {
+    let delay1 = delay_for_grade(delays, left);
+    let delay2 = if delays.len() > 1 {
+        delay_for_grade(delays, left - 1)
+    } else {
+        delay1 * 2.0
+    };
+
+    ((delay1 + delay1.max(delay2)) / 2.0).floor()
+}
+
+struct CardAnswerer<'a, R> {
+    card: &'a mut CardSchedulingState,
+    config: &'a SchedulingConfig,
+    today: i64,
+    day_cutoff: i64,
+    now: i64,
+    load_balancer: Option<&'a LoadBalancer>,
+    rng: &'a mut R,
+}
+
+impl<R: Rng> CardAnswerer<'_, R> {
+    fn answer(&mut self, ease: u8) -> Result<AnswerOutcome> {
+        match self.card.queue {
+            CardQueue::New | CardQueue::Learn | CardQueue::DayLearn | CardQueue::Review => (),
+            _ => return Err(AnkiError::invalid_input("card is not in a study queue")),
+        }
+
+        self.card.reps += 1;
+
+        if self.card.queue == CardQueue::New {
+            // came from the new queue, move to learning
+            self.card.queue = CardQueue::Learn;
+            self.card.ctype = CardType::Learn;
+            self.card.left = self.starting_left();
+        }
+
+        let (revlog, leech) = if self.card.queue == CardQueue::Review {
+            self.answer_review(ease)
+        } else {
+            (self.answer_learning(ease), None)
+        };
+
+        // once a card has been answered once, the original due date
+        // no longer applies
+        self.card.original_due = 0;
+        debug!(
+            card_id = self.card.id,
+            ease,
+            queue = ?self.card.queue,
+            due = self.card.due,
+            interval = self.card.interval,
+            factor = self.card.ease_factor,
+            "answered card"
+        );
+
+        Ok(AnswerOutcome {
+            revlog: Some(revlog),
+            leech,
+        })
+    }
+
+    // Previewing
+    //----------------------------------------
+
+    fn answer_preview(&mut self, ease: u8, delay: u32) -> Result<()> {
+        match ease {
+            1 => {
+                // repeat after delay
+                self.card.queue = CardQueue::Preview;
+                self.card.due = self.now + i64::from(delay);
+            }
+            2 => {
+                // restore original card state and remove from filtered deck
+                self.restore_preview_card();
+                self.remove_from_filtered();
+            }
+            _ => {
+                return Err(AnkiError::invalid_input(
+                    "cards being previewed only have two answer buttons",
+                ))
+            }
+        }
+
+        Ok(())
+    }
+
+    fn restore_preview_card(&mut self) {
+        let card = &mut *self.card;
+        card.due = card.original_due;
+        card.queue = card.ctype.home_queue(card.original_due);
+    }
+
+    fn remove_from_filtered(&mut self) {
+        let card = &mut *self.card;
+        if card.original_deck_id != 0 {
+            card.deck_id = card.original_deck_id;
+            card.original_due = 0;
+            card.original_deck_id = 0;
+        }
+    }
+
+    // Learning
+    //----------------------------------------
+
+    fn is_lapsed(&self) -> bool {
+        match self.card.ctype {
+            CardType::Review | CardType::Relearn => true,
+            CardType::New | CardType::Learn => false,
+        }
+    }
+
+    /// Relearning steps for lapsed cards, and learning steps otherwise.
+    fn learning_delays(&self) -> &[f64] {
+        if self.is_lapsed() {
+            &self.config.lapse.delays
+        } else {
+            &self.config.new.delays
+        }
+    }
+
+    fn answer_learning(&mut self, ease: u8) -> RevlogAnswer {
+        let review_kind = if self.is_lapsed() {
+            RevlogReviewKind::Relearning
+        } else {
+            RevlogReviewKind::Learning
+        };
+        let last_left = self.card.left;
+        let mut leaving = false;
+
+        match ease {
+            4 => {
+                // immediate graduate
+                self.reschedule_as_review(true);
+                leaving = true;
+            }
+            3 => {
+                if (last_left % 1000) <= 1 {
+                    // graduation time
+                    self.reschedule_as_review(false);
+                    leaving = true;
+                } else {
+                    self.move_to_next_step();
+                }
+            }
+            2 => self.repeat_step(),
+            _ => {
+                self.move_to_first_step();
+            }
+        }
+
+        // the steps of the original card type are logged, even if the
+        // card graduated
+        let delays = if review_kind == RevlogReviewKind::Relearning {
+            &self.config.lapse.delays
+        } else {
+            &self.config.new.delays
+        };
+        let last_interval = -(delay_for_grade(delays, i64::from(last_left)) as i32);
+        let left = i64::from(self.card.left);
+        let interval = if leaving {
+            self.card.interval as i32
+        } else if ease == 2 {
+            -(delay_for_repeating_grade(delays, left) as i32)
+        } else {
+            -(delay_for_grade(delays, left) as i32)
+        };
+
+        RevlogAnswer {
+            ease,
+            interval,
+            last_interval,
+            ease_factor: self.card.ease_factor,
+            review_kind,
+        }
+    }
+
+    /// The steps left until graduation, and those that can be completed
+    /// today, encoded as in CardSchedulingState::left.
+    fn starting_left(&self) -> u32 {
+        let delays = self.learning_delays();
+        let total = delays.len() as u32;
+        let today = self.steps_left_today(delays, total);
+        total + today * 1000
+    }
+
+    /// The number of steps that can be completed by the day cutoff.
+    fn steps_left_today(&self, delays: &[f64], left: u32) -> u32 {
+        let start = if left == 0 {
+            0
+        } else {
+            delays.len().saturating_sub(left as usize)
+        };
+        let mut now = self.now;
+        let mut completable = 0;
+        for (idx, delay) in delays[start..].iter().enumerate() {
+            now += (*delay * 60.0) as i64;
+            if now > self.day_cutoff {
+                break;
+            }
+            completable = idx as u32;
+        }
+        completable + 1
+    }
+
+    fn move_to_first_step(&mut self) -> f64 {
+        // relearning card?
+        if self.card.ctype == CardType::Relearn {
+            self.card.interval =
+                self.algorithm()
+                    .relearning_failed_interval(self.card, self.config) as u32;
+        }
+
+        self.restart_steps()
+    }
+
+    /// Returns the delay in seconds of the first step.
+    fn restart_steps(&mut self) -> f64 {
+        self.card.left = self.starting_left();
+        self.reschedule_learning_card(None)
+    }
+
+    fn move_to_next_step(&mut self) {
+        // decrement real left count and recalculate left today
+        let left = (self.card.left % 1000) - 1;
+        self.card.left = self.steps_left_today(self.learning_delays(), left) * 1000 + left;
+
+        self.reschedule_learning_card(None);
+    }
+
+    fn repeat_step(&mut self) {
+        let delay = delay_for_repeating_grade(self.learning_delays(), i64::from(self.card.left));
+        self.reschedule_learning_card(Some(delay));
+    }
+
+    /// Schedule the card for its next learning step, returning the delay in
+    /// seconds. If no delay is provided, the current step's delay is used.
+    fn reschedule_learning_card(&mut self, delay: Option<f64>) -> f64 {
+        let delay = delay
+            .unwrap_or_else(|| delay_for_grade(self.learning_delays(), i64::from(self.card.left)));
+
+        let due = (self.now as f64 + delay) as i64;
+        if due < self.day_cutoff {
+            // add some randomness, up to 5 minutes or 25%
+            let max_extra = 300.min((delay * 0.25) as i64);
+            let fuzz = if max_extra > 0 {
+                self.rng.gen_range(0, max_extra)
+            } else {
+                0
+            };
+            self.card.due = (self.day_cutoff - 1).min(due + fuzz);
+            self.card.queue = CardQueue::Learn;
+        } else {
+            // the card is due in one or more days, so we need to use the
+            // day learn queue
+            let ahead = (due - self.day_cutoff).div_euclid(86_400) + 1;
+            self.card.due = self.today + ahead;
+            self.card.queue = CardQueue::DayLearn;
+        }
+
+        delay
+    }
+
+    fn reschedule_as_review(&mut self, early: bool) {
+        if self.is_lapsed() {
+            if early {
+                self.card.interval += 1;
+            }
+        } else {
+            // a new card graduating for the first time
+            let state = self.algorithm().graduating_state(self.config, early);
+            self.card.interval = self.fuzzed_interval(state.interval as u32, 1);
+            self.card.ease_factor = state.ease_factor;
+        }
+        self.card.due = self.today + i64::from(self.card.interval);
+        self.card.ctype = CardType::Review;
+        self.card.queue = CardQueue::Review;
+
+        // if we were in a filtered deck, graduating means moving back to the
+        // original deck
+        self.remove_from_filtered();
+    }
+
+    // Reviewing
+    //----------------------------------------
+
+    /// Returns the revlog entry, and whether the card became a leech.
+    fn answer_review(&mut self, ease: u8) -> (RevlogAnswer, Option<LeechEvent>) {
+        let early = self.card.original_deck_id != 0 && self.card.original_due > self.today;
+        let review_kind = if early {
+            RevlogReviewKind::EarlyReview
+        } else {
+            RevlogReviewKind::Review
+        };
+        let last_interval = self.card.interval as i32;
+
+        let states = self.review_states(early);
+        let (delay, leech) = if ease == 1 {
+            self.reschedule_lapse(&states)
+        } else {
+            self.reschedule_review(&states, ease, early);
+            (0.0, None)
+        };
+
+        let delay = delay as i32;
+        let interval = if delay != 0 {
+            -delay
+        } else {
+            self.card.interval as i32
+        };
+
+        (
+            RevlogAnswer {
+                ease,
+                interval,
+                last_interval,
+                ease_factor: self.card.ease_factor,
+                review_kind,
+            },
+            leech,
+        )
+    }
+
+    /// Returns the relearning delay in seconds (0 if there are no relearning
+    /// steps), and whether the card became a leech.
+    fn reschedule_lapse(&mut self, states: &ReviewStates) -> (f64, Option<LeechEvent>) {
+        self.card.lapses += 1;
+        self.card.ease_factor = states.again.ease_factor;
+        self.card.interval = states.again.interval as u32;
+
+        let leech = self.check_leech();
+        let suspended = leech.as_ref().map(|l| l.suspended).unwrap_or_default();
+
+        if !self.config.lapse.delays.is_empty() && !suspended {
+            self.card.ctype = CardType::Relearn;
+            (self.restart_steps(), leech)
+        } else {
+            // no relearning steps
+            self.reschedule_as_review(false);
+            // need to reset the queue after rescheduling
+            if suspended {
+                self.card.queue = CardQueue::Suspended;
+            }
+            (0.0, leech)
+        }
+    }
+
+    /// If the card has become a leech, suspend it if the deck options ask
+    /// for it.
+    fn check_leech(&mut self) -> Option<LeechEvent> {
+        let conf = &self.config.lapse;
+        if !is_leech(self.card.lapses, conf.leech_threshold) {
+            return None;
+        }
+        if conf.leech_suspend {
+            self.card.queue = CardQueue::Suspended;
+        }
+        Some(LeechEvent {
+            suspended: conf.leech_suspend,
+        })
+    }
+
+    fn reschedule_review(&mut self, states: &ReviewStates, ease: u8, early: bool) {
+        let state = states.for_ease(ease);
+        self.card.interval = if early {
+            self.constrained_interval(state.interval, 0, false)
+        } else {
+            self.next_review_interval(states, ease)
+        };
+        self.card.ease_factor = state.ease_factor;
+        self.card.due = self.today + i64::from(self.card.interval);
+
+        // card leaves filtered deck
+        self.remove_from_filtered();
+    }
+
+    // Intervals
+    //----------------------------------------
+
+    fn days_late(&self) -> i64 {
+        let due = if self.card.original_deck_id != 0 {
+            self.card.original_due
+        } else {
+            self.card.due
+        };
+        (self.today - due).max(0)
+    }
+
+    fn algorithm(&self) -> &'static dyn SchedulingAlgorithm {
+        self.config.algorithm.algorithm()
+    }
+
+    fn review_states(&self, early: bool) -> ReviewStates {
+        let early_elapsed = if early {
+            Some(i64::from(self.card.interval) - (self.card.original_due - self.today))
+        } else {
+            None
+        };
+        self.algorithm().review_states(&ReviewContext {
+            card: self.card,
+            config: self.config,
+            days_late: self.days_late(),
+            early_elapsed,
+        })
+    }
+
+    /// The fuzzed interval for a review answered on time. Each answer
+    /// gets a longer interval than the one before it.
+    fn next_review_interval(&self, states: &ReviewStates, ease: u8) -> u32 {
+        // a hard answer shouldn't shrink the interval, unless the
+        // algorithm wants it to
+        let hard_minimum = if states.hard.interval > f64::from(self.card.interval) {
+            self.card.interval
+        } else {
+            0
+        };
+        let hard_interval = self.constrained_interval(states.hard.interval, hard_minimum, true);
+        if ease == 2 {
+            return hard_interval;
+        }
+
+        let good_interval = self.constrained_interval(states.good.interval, hard_interval, true);
+        if ease == 3 {
+            return good_interval;
+        }
+
+        self.constrained_interval(states.easy.interval, good_interval, true)
+    }
+
+    /// Apply the interval multiplier and fuzz, ensure the interval is
+    /// larger than `previous`, and cap it to the maximum interval.
+    fn constrained_interval(&self, interval: f64, previous: u32, fuzz: bool) -> u32 {
+        let conf = &self.config.review;
+        let mut interval = (interval * conf.interval_multiplier) as u32;
+        if fuzz {
+            interval = self.fuzzed_interval(interval, previous + 1);
+        }
+        interval.max(previous + 1).max(1).min(conf.maximum_interval)
+    }
+
+    /// Fuzz the interval, preferring quieter days if load balancing is
+    /// enabled. Days before `minimum` are not considered by the balancer.
+    fn fuzzed_interval(&self, interval: u32, minimum: u32) -> u32 {
+        if let Some(balancer) = self.load_balancer {
+            let (min, max) = fuzz_range(interval);
+            let min = min.max(minimum);
+            balancer.balanced_interval(self.card.id, min, max.max(min))
+        } else {
+            fuzzed_interval(self.card.id, interval)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::card::{CardQueue, CardType};
+    use crate::err::Result;
+    use crate::sched::algorithm::AlgorithmKind;
+    use crate::sched::answering::{
+        answer_card, CardSchedulingState, LapseConfig, NewCardConfig, ReviewConfig, RevlogAnswer,
+        RevlogReviewKind, SchedulingConfig,
+    };
+    use crate::sched::leech::LeechEvent;
+    use crate::sched::load_balance::LoadBalancer;
+    use crate::sched::SchedTimingToday;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    const NOW: i64 = 1_000_000_000;
+    const TODAY: i64 = 100;
+
+    fn timing() -> SchedTimingToday {
+        SchedTimingToday {
+            days_elapsed: TODAY as u32,
+            next_day_at: NOW + 12 * 3600,
+        }
+    }
+
+    fn config() -> SchedulingConfig {
+        SchedulingConfig {
+            new: NewCardConfig {
+                delays: vec![1.0, 10.0],
+                graduating_interval: 1,
+                easy_interval: 4,
+                initial_ease: 2500,
+            },
+            lapse: LapseConfig {
+                delays: vec![10.0],
+                multiplier: 0.0,
+                minimum_interval: 1,
+                leech_threshold: 8,
+                leech_suspend: true,
+            },
+            review: ReviewConfig {
+                easy_multiplier: 1.3,
+                hard_multiplier: 1.2,
+                interval_multiplier: 1.0,
+                maximum_interval: 36500,
+            },
+            algorithm: AlgorithmKind::Sm2,
+            preview_delay: None,
+        }
+    }
+
+    fn new_card() -> CardSchedulingState {
+        CardSchedulingState {
+            id: 1,
+            ctype: CardType::New,
+            queue: CardQueue::New,
+            due: 1,
+            interval: 0,
+            ease_factor: 0,
+            reps: 0,
+            lapses: 0,
+            left: 0,
+            deck_id: 1,
+            original_due: 0,
+            original_deck_id: 0,
+        }
+    }
+
+    fn review_card(interval: u32) -> CardSchedulingState {
+        CardSchedulingState {
+            ctype: CardType::Review,
+            queue: CardQueue::Review,
+            due: TODAY,
+            interval,
+            ease_factor: 2500,
+            ..new_card()
+        }
+    }
+
+    #[test]
+    fn test_learning() -> Result<()> {
+        let mut rng = StdRng::seed_from_u64(0);
+        let conf = config();
+        let mut card = new_card();
+
+        // the first answer moves the card into learning
+        let out = answer_card(&mut card, 3, &conf, &timing(), NOW, None, &mut rng)?;
+        assert_eq!(card.queue, CardQueue::Learn);
+        assert_eq!(card.ctype, CardType::Learn);
+        assert_eq!(card.left, 1001);
+        assert!(card.due >= NOW + 600 && card.due <= NOW + 900);
+        assert_eq!(
+            out.revlog,
+            Some(RevlogAnswer {
+                ease: 3,
+                interval: -600,
+                last_interval: -60,
+                ease_factor: 0,
+                review_kind: RevlogReviewKind::Learning,
+            })
+        );
+
+        // 'hard' repeats the step
+        answer_card(&mut card, 2, &conf, &timing(), NOW, None, &mut rng)?;
+        assert_eq!(card.left, 1001);
+
+        // 'again' goes back to the first step
+        answer_card(&mut card, 1, &conf, &timing(), NOW, None, &mut rng)?;
+        assert_eq!(card.left, 2002);
+        assert!(card.due >= NOW + 60 && card.due <= NOW + 75);
+
+        // graduation
+        answer_card(&mut card, 3, &conf, &timing(), NOW, None, &mut rng)?;
+        let out = answer_card(&mut card, 3, &conf, &timing(), NOW, None, &mut rng)?;
+        assert_eq!(card.queue, CardQueue::Review);
+        assert_eq!(card.interval, 1);
+        assert_eq!(card.due, TODAY + 1);
+        assert_eq!(card.ease_factor, 2500);
+        assert_eq!(card.reps, 5);
+        assert_eq!(out.revlog.unwrap().interval, 1);
+
+        // steps past the day cutoff use the day learning queue
+        let mut card = new_card();
+        let mut conf = config();
+        conf.new.delays = vec![1440.0, 2880.0];
+        answer_card(&mut card, 1, &conf, &timing(), NOW, None, &mut rng)?;
+        assert_eq!(card.queue, CardQueue::DayLearn);
+        assert_eq!(card.due, TODAY + 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_reviews() -> Result<()> {
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut conf = config();
+
+        // 'good' multiplies the interval by the ease factor
+        let mut card = review_card(100);
+        let out = answer_card(&mut card, 3, &conf, &timing(), NOW, None, &mut rng)?;
+        assert!(card.interval >= 238 && card.interval <= 262);
+        assert_eq!(card.due, TODAY + i64::from(card.interval));
+        assert_eq!(card.ease_factor, 2500);
+        let revlog = out.revlog.unwrap();
+        assert_eq!(revlog.last_interval, 100);
+        assert_eq!(revlog.review_kind, RevlogReviewKind::Review);
+
+        // 'easy' increases the ease factor
+        let mut card = review_card(100);
+        answer_card(&mut card, 4, &conf, &timing(), NOW, None, &mut rng)?;
+        assert_eq!(card.ease_factor, 2650);
+        assert!(card.interval >= 309 && card.interval <= 341);
+
+        // a lapse moves the card into relearning
+        let mut card = review_card(100);
+        let out = answer_card(&mut card, 1, &conf, &timing(), NOW, None, &mut rng)?;
+        assert_eq!(card.ctype, CardType::Relearn);
+        assert_eq!(card.queue, CardQueue::Learn);
+        assert_eq!(card.interval, 1);
+        assert_eq!(card.ease_factor, 2300);
+        assert_eq!(card.lapses, 1);
+        assert_eq!(out.revlog.unwrap().interval, -600);
+
+        // graduating from relearning keeps the reduced interval
+        answer_card(&mut card, 3, &conf, &timing(), NOW, None, &mut rng)?;
+        assert_eq!(card.queue, CardQueue::Review);
+        assert_eq!(card.interval, 1);
+
+        // without relearning steps, leeches are suspended immediately
+        conf.lapse.delays = vec![];
+        let mut card = review_card(100);
+        card.lapses = 7;
+        let out = answer_card(&mut card, 1, &conf, &timing(), NOW, None, &mut rng)?;
+        assert_eq!(out.leech, Some(LeechEvent { suspended: true }));
+        assert_eq!(card.queue, CardQueue::Suspended);
+        assert_eq!(card.due, TODAY + 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_load_balancing() -> Result<()> {
+        let mut rng = StdRng::seed_from_u64(0);
+        let conf = config();
+
+        // 'good' on a 100 day interval can land anywhere from 238 to 262
+        // days away; every day but one is busy
+        let counts = (238..=262)
+            .filter(|ivl| *ivl != 241)
+            .map(|ivl| (TODAY + ivl, 10))
+            .collect();
+        let balancer = LoadBalancer::new(counts, TODAY, 0, &[]);
+        let mut card = review_card(100);
+        answer_card(
+            &mut card,
+            3,
+            &conf,
+            &timing(),
+            NOW,
+            Some(&balancer),
+            &mut rng,
+        )?;
+        assert_eq!(card.interval, 241);
+        assert_eq!(card.due, TODAY + 241);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_filtered() -> Result<()> {
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut conf = config();
+
+        // reviewing early in a filtered deck
+        let mut card = review_card(10);
+        card.deck_id = 2;
+        card.original_deck_id = 1;
+        card.original_due = TODAY + 5;
+        let out = answer_card(&mut card, 3, &conf, &timing(), NOW, None, &mut rng)?;
+        assert_eq!(
+            out.revlog.unwrap().review_kind,
+            RevlogReviewKind::EarlyReview
+        );
+        // 5 days elapsed * 2.5
+        assert_eq!(card.interval, 12);
+        assert_eq!(card.deck_id, 1);
+        assert_eq!(card.original_deck_id, 0);
+
+        // previewing
+        conf.preview_delay = Some(600);
+        let mut card = review_card(10);
+        card.deck_id = 2;
+        card.original_deck_id = 1;
+        card.original_due = TODAY + 5;
+        let out = answer_card(&mut card, 1, &conf, &timing(), NOW, None, &mut rng)?;
+        assert_eq!(out.revlog, None);
+        assert_eq!(card.queue, CardQueue::Preview);
+        assert_eq!(card.due, NOW + 600);
+        answer_card(&mut card, 2, &conf, &timing(), NOW, None, &mut rng)?;
+        assert_eq!(card.queue, CardQueue::Review);
+        assert_eq!(card.due, TODAY + 5);
+        assert_eq!(card.deck_id, 1);
+        assert!(answer_card(&mut card, 3, &conf, &timing(), NOW, None, &mut rng).is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,274 @@
+// Copyright: Ankitects Pty Ltd and contributors
+// License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+//! Burying and suspending cards. Each operation runs in a single
+//! transaction, and returns the previous state of the cards it changed so
+//! it can be undone.
+
+use crate::card::CardQueue;
+use crate::err::Result;
+use crate::sched::ids_to_string;
+use crate::sched::undo::{card_snapshots, update_cards, write_snapshots, CardScheduleSnapshot};
+use rusqlite::{params, Connection};
+use std::path::Path;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BuryOrSuspendMode {
+    Suspend,
+    BuryUser,
+    BurySched,
+}
+
+impl BuryOrSuspendMode {
+    fn queue(self) -> CardQueue {
+        match self {
+            BuryOrSuspendMode::Suspend => CardQueue::Suspended,
+            BuryOrSuspendMode::BuryUser => CardQueue::UserBuried,
+            BuryOrSuspendMode::BurySched => CardQueue::SchedBuried,
+        }
+    }
+}
+
+/// Which buried cards to unbury.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UnburyMode {
+    All,
+    /// Cards buried by the user.
+    User,
+    /// Siblings buried automatically.
+    Sched,
+}
+
+impl UnburyMode {
+    fn condition(self) -> String {
+        match self {
+            UnburyMode::All => format!(
+                "queue in ({}, {})",
+                CardQueue::SchedBuried as i8,
+                CardQueue::UserBuried as i8
+            ),
+            UnburyMode::User => format!("queue = {}", CardQueue::UserBuried as i8),
+            UnburyMode::Sched => format!("queue = {}", CardQueue::SchedBuried as i8),
+        }
+    }
+}
+
+pub fn bury_or_suspend_cards(
+    col_path: &Path,
+    card_ids: &[i64],
+    mode: BuryOrSuspendMode,
+    usn: i32,
+    mtime_secs: i64,
+) -> Result<Vec<CardScheduleSnapshot>> {
+    if card_ids.is_empty() {
+        return Ok(vec![]);
+    }
+    let queue = mode.queue() as i8;
+    update_cards(
+        col_path,
+        &format!("id in {} and queue != ?", ids_to_string(card_ids)),
+        &[&queue],
+        usn,
+        mtime_secs,
+        |card| card.queue = queue,
+    )
+}
+
+/// Unsuspend the provided cards. Cards that are not suspended are left
+/// alone.
+pub fn unsuspend_cards(
+    col_path: &Path,
+    card_ids: &[i64],
+    usn: i32,
+    mtime_secs: i64,
+) -> Result<Vec<CardScheduleSnapshot>> {
+    if card_ids.is_empty() {
+        return Ok(vec![]);
+    }
+    update_cards(
+        col_path,
+        &format!(
+            "id in {} and queue = {}",
+            ids_to_string(card_ids),
+            CardQueue::Suspended as i8
+        ),
+        &[],
+        usn,
+        mtime_secs,
+        CardScheduleSnapshot::restore_queue,
+    )
+}
+
+/// Unbury cards in the provided decks, or in all decks if None.
+pub fn unbury_cards(
+    col_path: &Path,
+    deck_ids: Option<&[i64]>,
+    mode: UnburyMode,
+    usn: i32,
+    mtime_secs: i64,
+) -> Result<Vec<CardScheduleSnapshot>> {
+    let mut condition = mode.condition();
+    if let Some(dids) = deck_ids {
+        condition += &format!(" and did in {}", ids_to_string(dids));
+    }
+    update_cards(
+        col_path,
+        &condition,
+        &[],
+        usn,
+        mtime_secs,
+        CardScheduleSnapshot::restore_queue,
+    )
+}
+
+pub struct SiblingBurial {
+    /// New cards and reviews due today from the same note. They should be
+    /// removed from the study queues even if they weren't buried, so
+    /// siblings aren't shown on the same day.
+    pub siblings: Vec<i64>,
+    /// The state of the siblings that were buried.
+    pub previous: Vec<CardScheduleSnapshot>,
+}
+
+/// The deck options of the card being answered.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SiblingBuryConfig {
+    pub bury_new: bool,
+    pub bury_reviews: bool,
+}
+
+/// Bury the new and/or review siblings of a card that is being answered.
+pub fn bury_siblings(
+    col_path: &Path,
+    card_id: i64,
+    note_id: i64,
+    today: u32,
+    config: SiblingBuryConfig,
+    usn: i32,
+    mtime_secs: i64,
+) -> Result<SiblingBurial> {
+    let new = CardQueue::New as i8;
+    let review = CardQueue::Review as i8;
+    let mut db = Connection::open(col_path)?;
+    let tx = db.transaction()?;
+
+    let candidates = card_snapshots(
+        &tx,
+        "nid = ? and id != ? and (queue = ? or (queue = ? and due <= ?))",
+        params![note_id, card_id, new, review, today],
+    )?;
+    let siblings = candidates.iter().map(|card| card.id).collect();
+    let previous: Vec<_> = candidates
+        .into_iter()
+        .filter(|card| {
+            if card.queue == new {
+                config.bury_new
+            } else {
+                config.bury_reviews
+            }
+        })
+        .collect();
+    let buried: Vec<_> = previous
+        .iter()
+        .cloned()
+        .map(|mut card| {
+            card.queue = CardQueue::SchedBuried as i8;
+            card.mtime_secs = mtime_secs;
+            card.usn = usn;
+            card
+        })
+        .collect();
+    write_snapshots(&tx, &buried)?;
+    tx.commit()?;
+
+    Ok(SiblingBurial { siblings, previous })
+}
+
+#[cfg(test)]
+mod test {
+    use crate::err::Result;
+    use crate::sched::bury_suspend::{
+        bury_or_suspend_cards, bury_siblings, unbury_cards, unsuspend_cards, BuryOrSuspendMode,
+        SiblingBuryConfig, UnburyMode,
+    };
+    use crate::sched::undo::restore_card_schedules;
+    use rusqlite::{Connection, NO_PARAMS};
+    use std::path::Path;
+    use tempfile::tempdir;
+
+    fn queues(col_path: &Path) -> Result<Vec<i8>> {
+        let db = Connection::open(col_path)?;
+        let mut stmt = db.prepare("select queue from cards order by id")?;
+        let queues = stmt
+            .query_map(NO_PARAMS, |row| row.get(0))?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(queues)
+    }
+
+    #[test]
+    fn test_bury_suspend() -> Result<()> {
+        let dir = tempdir()?;
+        let col_path = dir.path().join("collection.anki2");
+        let db = Connection::open(&col_path)?;
+        db.execute_batch(
+            "create table cards (id integer primary key, nid integer, did integer,
+type integer, queue integer, due integer, ivl integer, factor integer, odue integer,
+odid integer, mod integer, usn integer);
+insert into cards values (1, 1, 1, 0, 0, 1, 0, 0, 0, 0, 0, 0);
+insert into cards values (2, 1, 1, 2, 2, 100, 10, 2500, 0, 0, 0, 0);
+insert into cards values (3, 1, 1, 2, 2, 200, 10, 2500, 0, 0, 0, 0);
+insert into cards values (4, 1, 2, 1, 1, 1500000000, 0, 2500, 0, 0, 0, 0);
+insert into cards values (5, 1, 2, 3, 3, 100, 1, 2500, 0, 0, 0, 0);",
+        )?;
+
+        // answering the learning card buries the new card and the review
+        // due today, but not the review due later
+        let config = SiblingBuryConfig {
+            bury_new: true,
+            bury_reviews: true,
+        };
+        let burial = bury_siblings(&col_path, 4, 1, 100, config, -1, 10)?;
+        assert_eq!(burial.siblings, vec![1, 2]);
+        assert_eq!(queues(&col_path)?, vec![-2, -2, 2, 1, 3]);
+        restore_card_schedules(&col_path, &burial.previous)?;
+        assert_eq!(queues(&col_path)?, vec![0, 2, 2, 1, 3]);
+
+        // siblings are still reported when burying is disabled
+        let config = SiblingBuryConfig {
+            bury_new: false,
+            bury_reviews: true,
+        };
+        let burial = bury_siblings(&col_path, 4, 1, 100, config, -1, 10)?;
+        assert_eq!(burial.siblings, vec![1, 2]);
+        assert_eq!(burial.previous.len(), 1);
+        assert_eq!(queues(&col_path)?, vec![0, -2, 2, 1, 3]);
+
+        // manual burying
+        let previous =
+            bury_or_suspend_cards(&col_path, &[1, 2], BuryOrSuspendMode::BuryUser, -1, 10)?;
+        assert_eq!(previous.len(), 2);
+        assert_eq!(queues(&col_path)?, vec![-3, -3, 2, 1, 3]);
+
+        // suspending and unsuspending restores the queue matching the type
+        bury_or_suspend_cards(&col_path, &[3, 4, 5], BuryOrSuspendMode::Suspend, -1, 10)?;
+        assert_eq!(queues(&col_path)?, vec![-3, -3, -1, -1, -1]);
+        let previous = unsuspend_cards(&col_path, &[1, 3, 4, 5], -1, 10)?;
+        assert_eq!(previous.len(), 3);
+        assert_eq!(queues(&col_path)?, vec![-3, -3, 2, 1, 3]);
+
+        // unburying by deck
+        bury_or_suspend_cards(&col_path, &[3], BuryOrSuspendMode::BurySched, -1, 10)?;
+        unbury_cards(&col_path, Some(&[2]), UnburyMode::All, -1, 10)?;
+        assert_eq!(queues(&col_path)?, vec![-3, -3, -2, 1, 3]);
+        unbury_cards(&col_path, Some(&[1]), UnburyMode::Sched, -1, 10)?;
+        assert_eq!(queues(&col_path)?, vec![-3, -3, 2, 1, 3]);
+        let previous = unbury_cards(&col_path, None, UnburyMode::All, -1, 10)?;
+        assert_eq!(queues(&col_path)?, vec![0, 2, 2, 1, 3]);
+
+        // undo
+        restore_card_schedules(&col_path, &previous)?;
+        assert_eq!(queues(&col_path)?, vec![-3, -3, 2, 1, 3]);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,344 @@
+// Copyright: Ankitects Pty Ltd and contributors
+// License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+//! Due counts for the deck list and study screen. The caller counts the
+//! cards due in each deck, and each deck's daily limits are then applied
+//! down the deck tree: a child can't show more new cards or reviews than
+//! its parents allow.
+
+use crate::decks::parent_name;
+use std::collections::HashMap;
+
+/// A deck's own limits and due cards, ignoring its parents and children.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DeckDueInput {
+    pub deck_id: i64,
+    /// The full name, with components separated by ::.
+    pub name: String,
+    pub filtered: bool,
+    /// New cards that can still be shown today.
+    pub new_limit: u32,
+    /// Reviews that can still be shown today.
+    pub review_limit: u32,
+    pub new_cards: u32,
+    /// Learning cards due within the learn ahead limit.
+    pub learn_cards: u32,
+    /// Learning cards with steps of a day or more, due today.
+    pub day_learn_cards: u32,
+    pub review_cards: u32,
+    /// True if the deck's children are hidden in the deck list.
+    pub collapsed: bool,
+}
+
+/// The counts of a single deck. Reviews include the deck's children;
+/// learning and new cards don't.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeckDueCounts {
+    pub deck_id: i64,
+    pub name: String,
+    pub review: u32,
+    pub learn: u32,
+    pub new: u32,
+}
+
+/// A deck and its children, with counts that include the children.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeckTreeNode {
+    /// The last component of the deck's name.
+    pub name: String,
+    pub deck_id: i64,
+    pub review: u32,
+    pub learn: u32,
+    pub new: u32,
+    pub children: Vec<DeckTreeNode>,
+    /// Whether the children are hidden. Always false for the missing parent
+    /// of an orphaned deck.
+    pub collapsed: bool,
+}
+
+fn name_components(name: &str) -> Vec<&str> {
+    name.split("::").collect()
+}
+
+/// Counts for each deck, sorted by name, and the same counts as a tree.
+/// No count for a single deck exceeds `report_limit`.
+pub fn deck_due_counts(
+    decks: &[DeckDueInput],
+    report_limit: u32,
+) -> (Vec<DeckDueCounts>, Vec<DeckTreeNode>) {
+    let mut decks: Vec<_> = decks.iter().collect();
+    decks.sort_by(|a, b| a.name.cmp(&b.name));
+
+    // reviews are counted for each deck and its children
+    let mut reviews_in_tree: HashMap<&str, u32> = HashMap::new();
+    for deck in &decks {
+        let mut name = Some(deck.name.as_str());
+        while let Some(n) = name {
+            *reviews_in_tree.entry(n).or_default() += deck.review_cards;
+            name = parent_name(n);
+        }
+    }
+
+    // parents sort before their children, so their limits are known
+    // by the time the children are reached
+    let mut limits: HashMap<&str, (u32, u32)> = HashMap::new();
+    let mut counts = Vec::with_capacity(decks.len());
+    for deck in &decks {
+        let mut new_limit = deck.new_limit;
+        let mut review_limit = deck.review_limit;
+        if let Some((parent_new, parent_review)) =
+            parent_name(&deck.name).and_then(|p| limits.get(p).cloned())
+        {
+            new_limit = new_limit.min(parent_new);
+            // filtered decks ignore the review limits of their parents
+            if !deck.filtered {
+                review_limit = review_limit.min(parent_review);
+            }
+        }
+        limits.insert(&deck.name, (new_limit, review_limit));
+
+        counts.push(DeckDueCounts {
+            deck_id: deck.deck_id,
+            name: deck.name.clone(),
+            review: reviews_in_tree[deck.name.as_str()]
+                .min(review_limit)
+                .min(report_limit),
+            learn: deck.learn_cards.min(report_limit) + deck.day_learn_cards.min(report_limit),
+            new: deck.new_cards.min(new_limit).min(report_limit),
+        });
+    }
+
+    let mut entries: Vec<_> = counts
+        .iter()
+        .zip(decks)
+        .map(|(counts, deck)| (name_components(&counts.name), counts, deck))
+        .collect();
+    entries.sort_by(|a, b| a.0.cmp(&b.0));
+    let tree = build_tree(&entries, 0);
+
+    (counts, tree)
+}
+
+type TreeEntry<'a> = (Vec<&'a str>, &'a DeckDueCounts, &'a DeckDueInput);
+
+/// Build the nodes at `depth` from entries sorted by name components, all
+/// of which share their first `depth` components.
+fn build_tree(entries: &[TreeEntry], depth: usize) -> Vec<DeckTreeNode> {
+    let mut nodes = vec![];
+    let mut start = 0;
+    while start < entries.len() {
+        let head = entries[start].0[depth];
+        let end = start
+            + entries[start..]
+                .iter()
+                .take_while(|(components, _, _)| components[depth] == head)
+                .count();
+
+        // split the deck itself from its descendants
+        let (own, descendants): (Vec<_>, Vec<_>) = entries[start..end]
+            .iter()
+            .cloned()
+            .partition(|(components, _, _)| components.len() == depth + 1);
+        let children = build_tree(&descendants, depth + 1);
+
+        let mut node = DeckTreeNode {
+            name: head.to_string(),
+            deck_id: 0,
+            review: 0,
+            learn: children.iter().map(|c| c.learn).sum(),
+            new: children.iter().map(|c| c.new).sum(),
+            children,
+            collapsed: false,
+        };
+        if let Some((_, counts, deck)) = own.first() {
+            node.deck_id = counts.deck_id;
+            node.review = counts.review;
+            node.learn += counts.learn;
+            node.new += counts.new;
+            node.collapsed = deck.collapsed;
+            if !deck.filtered {
+                node.new = node.new.min(deck.new_limit);
+            }
+        }
+        nodes.push(node);
+
+        start = end;
+    }
+
+    nodes
+}
+
+/// The number of new cards that can be studied in the active decks. A
+/// deck's limit is shared with its parents, so cards gathered from one
+/// child reduce what can be gathered from its siblings.
+pub fn new_count_for_active_decks(decks: &[DeckDueInput], active_deck_ids: &[i64]) -> u32 {
+    limited_count_for_active_decks(decks, active_deck_ids, |d| d.new_limit, |d| d.new_cards)
+}
+
+/// The v1 scheduler's review count, which shares limits with parents the
+/// same way new cards do.
+pub(crate) fn v1_review_count_for_active_decks(
+    decks: &[DeckDueInput],
+    active_deck_ids: &[i64],
+) -> u32 {
+    limited_count_for_active_decks(
+        decks,
+        active_deck_ids,
+        |d| d.review_limit,
+        |d| d.review_cards,
+    )
+}
+
+fn limited_count_for_active_decks<L, C>(
+    decks: &[DeckDueInput],
+    active_deck_ids: &[i64],
+    limit_of: L,
+    cards_in: C,
+) -> u32
+where
+    L: Fn(&DeckDueInput) -> u32,
+    C: Fn(&DeckDueInput) -> u32,
+{
+    let by_id: HashMap<i64, &DeckDueInput> = decks.iter().map(|d| (d.deck_id, d)).collect();
+    let by_name: HashMap<&str, &DeckDueInput> =
+        decks.iter().map(|d| (d.name.as_str(), d)).collect();
+
+    let mut total = 0;
+    // the limits left in each deck after cards were taken from it or its
+    // children
+    let mut remaining: HashMap<i64, u32> = HashMap::new();
+    for did in active_deck_ids {
+        let deck = match by_id.get(did) {
+            Some(deck) => deck,
+            None => continue,
+        };
+        let mut limit = limit_of(deck);
+        if limit == 0 {
+            continue;
+        }
+
+        let mut parents = vec![];
+        let mut name = parent_name(&deck.name);
+        while let Some(n) = name {
+            if let Some(parent) = by_name.get(n) {
+                parents.push(parent.deck_id);
+                let parent_limit = *remaining
+                    .entry(parent.deck_id)
+                    .or_insert_with(|| limit_of(parent));
+                limit = limit.min(parent_limit);
+            }
+            name = parent_name(n);
+        }
+
+        let count = cards_in(deck).min(limit);
+        for parent in parents {
+            if let Some(parent_limit) = remaining.get_mut(&parent) {
+                *parent_limit -= count;
+            }
+        }
+        remaining.insert(deck.deck_id, limit - count);
+        total += count;
+    }
+
+    total
+}
+
+#[cfg(test)]
+mod test {
+    use crate::sched::counts::{
+        deck_due_counts, new_count_for_active_decks, DeckDueInput, DeckTreeNode,
+    };
+
+    fn deck(deck_id: i64, name: &str, new_limit: u32, review_limit: u32) -> DeckDueInput {
+        DeckDueInput {
+            deck_id,
+            name: name.into(),
+            new_limit,
+            review_limit,
+            new_cards: 10,
+            learn_cards: 1,
+            day_learn_cards: 1,
+            review_cards: 10,
+            ..Default::default()
+        }
+    }
+
+    fn summary(nodes: &[DeckTreeNode]) -> Vec<(String, u32, u32, u32)> {
+        let mut out = vec![];
+        for node in nodes {
+            out.push((node.name.clone(), node.review, node.learn, node.new));
+            out.extend(summary(&node.children));
+        }
+        out
+    }
+
+    #[test]
+    fn test_deck_due_counts() {
+        let mut decks = vec![
+            deck(4, "parent::child2", 20, 100),
+            deck(2, "parent", 15, 25),
+            deck(3, "parent::child1", 20, 100),
+            deck(5, "parent::child1::grandchild", 20, 100),
+            deck(1, "Default", 20, 5000),
+            DeckDueInput {
+                filtered: true,
+                ..deck(6, "parent::filtered", 99999, 99999)
+            },
+        ];
+        decks[4].review_cards = 2000;
+        decks[1].collapsed = true;
+
+        let (counts, tree) = deck_due_counts(&decks, 1000);
+        let flat: Vec<_> = counts
+            .iter()
+            .map(|c| (c.deck_id, c.review, c.learn, c.new))
+            .collect();
+        assert_eq!(
+            flat,
+            vec![
+                // capped by the report limit
+                (1, 1000, 2, 10),
+                // reviews include the children, capped by the deck's limit
+                (2, 25, 2, 10),
+                (3, 20, 2, 10),
+                // the grandchild shares the parent's new limit of 15
+                (5, 10, 2, 10),
+                (4, 10, 2, 10),
+                // filtered decks ignore the parent's review limit
+                (6, 10, 2, 10),
+            ]
+        );
+
+        assert_eq!(
+            summary(&tree),
+            vec![
+                ("Default".into(), 1000, 2, 10),
+                // the children's new cards are capped by the parent
+                ("parent".into(), 25, 10, 15),
+                ("child1".into(), 20, 4, 20),
+                ("grandchild".into(), 10, 2, 10),
+                ("child2".into(), 10, 2, 10),
+                ("filtered".into(), 10, 2, 10),
+            ]
+        );
+        assert_eq!(tree[1].children[0].children[0].deck_id, 5);
+        assert!(tree[1].collapsed);
+        assert!(!tree[1].children[0].collapsed);
+    }
+
+    #[test]
+    fn test_new_count_for_active_decks() {
+        let decks = vec![
+            deck(1, "parent", 15, 100),
+            deck(2, "parent::child1", 8, 100),
+            deck(3, "parent::child2", 20, 100),
+            deck(4, "parent::child3", 0, 100),
+        ];
+
+        // the children share the parent's limit
+        assert_eq!(new_count_for_active_decks(&decks, &[1, 2, 3, 4]), 15);
+        assert_eq!(new_count_for_active_decks(&decks, &[2, 3]), 15);
+        assert_eq!(new_count_for_active_decks(&decks, &[2]), 8);
+        assert_eq!(new_count_for_active_decks(&decks, &[4, 99]), 0);
+    }
+}
@@ -0,0 +1,280 @@
+// Copyright: Ankitects Pty Ltd and contributors
+// License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+//! The counts the study screen shows for the current deck, read straight
+//! from the collection. These follow the rules of the legacy schedulers
+//! exactly, as the sync sanity check compares them with the counts the
+//! server calculates.
+
+use crate::decks::{Deck, DeckConf};
+use crate::err::Result;
+use crate::sched::counts::{
+    new_count_for_active_decks, v1_review_count_for_active_decks, DeckDueInput,
+};
+use crate::sched::{
+    ids_to_string, local_minutes_west_for_stamp, local_sched_timing_today, sched_timing_today,
+    SchedTimingToday,
+};
+use crate::storage::SqliteStorage;
+use rusqlite::types::ToSql;
+use rusqlite::{params, NO_PARAMS};
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+
+/// The v1 scheduler's limit on the counts it reports, which is also the
+/// limit of a filtered deck.
+const V1_REPORT_LIMIT: u32 = 1000;
+
+/// The v2 scheduler's limit for filtered decks.
+const V2_FILTERED_LIMIT: u32 = 99_999;
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct StudyCounts {
+    pub new: u32,
+    pub learn: u32,
+    pub review: u32,
+}
+
+fn config_i64(conf: &Map<String, Value>, key: &str) -> Option<i64> {
+    conf.get(key).and_then(Value::as_i64)
+}
+
+/// The scheduler version the collection uses, 1 or 2.
+pub(crate) fn scheduler_version(conf: &Map<String, Value>) -> u8 {
+    if config_i64(conf, "schedVer") == Some(2) {
+        2
+    } else {
+        1
+    }
+}
+
+/// The day number and the end of the day, as the collection's scheduler
+/// calculates them.
+pub(crate) fn collection_timing_today(
+    storage: &SqliteStorage,
+    conf: &Map<String, Value>,
+    now_secs: i64,
+) -> Result<SchedTimingToday> {
+    let created = storage.creation_stamp()?;
+    if scheduler_version(conf) == 1 {
+        // whole days since creation
+        let days_elapsed = ((now_secs - created) / 86_400).max(0);
+        return Ok(SchedTimingToday {
+            days_elapsed: days_elapsed as u32,
+            next_day_at: created + (days_elapsed + 1) * 86_400,
+        });
+    }
+
+    let rollover = config_i64(conf, "rollover").unwrap_or(4) as i8;
+    Ok(match config_i64(conf, "creationOffset") {
+        Some(created_mins_west) => sched_timing_today(
+            created,
+            created_mins_west as i32,
+            now_secs,
+            local_minutes_west_for_stamp(now_secs),
+            rollover,
+        ),
+        None => local_sched_timing_today(created, now_secs, rollover),
+    })
+}
+
+/// The decks cards are gathered from: the current deck and its children.
+/// Early versions stored the ids as strings.
+fn active_deck_ids(conf: &Map<String, Value>) -> Vec<i64> {
+    conf.get("activeDecks")
+        .and_then(Value::as_array)
+        .map(|ids| {
+            ids.iter()
+                .filter_map(|id| match id {
+                    Value::String(s) => s.parse().ok(),
+                    other => other.as_i64(),
+                })
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// The number of cards left of a daily limit in the options group, eg
+/// ("new", "newToday").
+fn remaining_today(deck: &Deck, conf: Option<&DeckConf>, kind: &str, today: u32) -> u32 {
+    let default_per_day = if kind == "new" { 20 } else { 200 };
+    let per_day = conf
+        .and_then(|c| c.other.get(kind))
+        .and_then(|limits| limits.get("perDay"))
+        .and_then(Value::as_i64)
+        .unwrap_or(default_per_day);
+    // the count is only kept for the day it was last updated
+    let done = deck
+        .other
+        .get(&format!("{}Today", kind))
+        .and_then(Value::as_array)
+        .and_then(|pair| match (pair.first()?.as_i64(), pair.get(1)?.as_i64()) {
+            (Some(day), Some(done)) if day == i64::from(today) => Some(done),
+            _ => None,
+        })
+        .unwrap_or(0);
+    (per_day - done).max(0) as u32
+}
+
+/// Cards matching `cond` in each deck.
+fn cards_by_deck<P>(storage: &SqliteStorage, cond: &str, params: P) -> Result<HashMap<i64, u32>>
+where
+    P: IntoIterator,
+    P::Item: ToSql,
+{
+    let mut stmt = storage.db.prepare(&format!(
+        "select did, count() from cards where {} group by did",
+        cond
+    ))?;
+    let counts = stmt
+        .query_map(params, |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<rusqlite::Result<_>>()?;
+    Ok(counts)
+}
+
+/// Each deck's own limits and due cards, ignoring its parents and
+/// children.
+fn deck_due_inputs(
+    storage: &SqliteStorage,
+    decks: &HashMap<i64, Deck>,
+    filtered_limit: u32,
+    today: u32,
+) -> Result<Vec<DeckDueInput>> {
+    let confs = storage.get_all_deck_conf()?;
+    let new = cards_by_deck(storage, "queue = 0", NO_PARAMS)?;
+    let review = cards_by_deck(storage, "queue = 2 and due <= ?", params![today])?;
+
+    Ok(decks
+        .values()
+        .map(|deck| {
+            let (new_limit, review_limit) = if deck.is_filtered() {
+                (filtered_limit, filtered_limit)
+            } else {
+                let conf = confs
+                    .get(&deck.config_id().unwrap_or(1))
+                    .or_else(|| confs.get(&1));
+                (
+                    remaining_today(deck, conf, "new", today),
+                    remaining_today(deck, conf, "rev", today),
+                )
+            };
+            DeckDueInput {
+                deck_id: deck.id,
+                name: deck.name.clone(),
+                filtered: deck.is_filtered(),
+                new_limit,
+                review_limit,
+                new_cards: new.get(&deck.id).cloned().unwrap_or_default(),
+                review_cards: review.get(&deck.id).cloned().unwrap_or_default(),
+                ..Default::default()
+            }
+        })
+        .collect())
+}
+
+/// The v2 scheduler's review limit: the current deck's own limit, capped
+/// by its parents unless it's a filtered deck.
+fn v2_current_review_limit(decks: &[DeckDueInput], current_deck_id: i64) -> u32 {
+    let current = match decks.iter().find(|d| d.deck_id == current_deck_id) {
+        Some(deck) => deck,
+        None => return 0,
+    };
+    if current.filtered {
+        return current.review_limit;
+    }
+    decks
+        .iter()
+        .filter(|d| current.name.starts_with(&format!("{}::", d.name)))
+        .map(|d| d.review_limit)
+        .fold(current.review_limit, u32::min)
+}
+
+fn scalar<P>(storage: &SqliteStorage, sql: &str, params: P) -> Result<u32>
+where
+    P: IntoIterator,
+    P::Item: ToSql,
+{
+    storage
+        .db
+        .query_row(sql, params, |row| row.get::<_, Option<u32>>(0))
+        .map(Option::unwrap_or_default)
+        .map_err(Into::into)
+}
+
+/// The new, learning and review counts of the current deck and its
+/// children, at `now_secs`.
+pub fn current_deck_counts(storage: &SqliteStorage, now_secs: i64) -> Result<StudyCounts> {
+    let conf = storage.get_all_config()?;
+    let timing = collection_timing_today(storage, &conf, now_secs)?;
+    let today = timing.days_elapsed;
+    let active = active_deck_ids(&conf);
+    let active_sql = ids_to_string(&active);
+    let decks = storage.get_all_decks()?;
+
+    if scheduler_version(&conf) == 1 {
+        let inputs = deck_due_inputs(storage, &decks, V1_REPORT_LIMIT, today)?;
+        // learning cards count once for each step remaining today
+        let learn = scalar(
+            storage,
+            &format!(
+                "select sum(left / 1000) from (select left from cards where did in {}
+and queue = 1 and due < ? limit {})",
+                active_sql, V1_REPORT_LIMIT
+            ),
+            params![timing.next_day_at],
+        )? + scalar(
+            storage,
+            &format!(
+                "select count() from cards where did in {} and queue = 3 and due <= ?",
+                active_sql
+            ),
+            params![today],
+        )?;
+        return Ok(StudyCounts {
+            new: new_count_for_active_decks(&inputs, &active),
+            learn,
+            review: v1_review_count_for_active_decks(&inputs, &active),
+        });
+    }
+
+    let inputs = deck_due_inputs(storage, &decks, V2_FILTERED_LIMIT, today)?;
+    let collapse_secs = config_i64(&conf, "collapseTime").unwrap_or(1200);
+    let learn = scalar(
+        storage,
+        &format!(
+            "select count() from cards where did in {} and queue = 1 and due < ?",
+            active_sql
+        ),
+        params![now_secs + collapse_secs],
+    )? + scalar(
+        storage,
+        &format!(
+            "select count() from cards where did in {} and queue = 3 and due <= ?",
+            active_sql
+        ),
+        params![today],
+    )? + scalar(
+        storage,
+        &format!(
+            "select count() from cards where did in {} and queue = 4",
+            active_sql
+        ),
+        NO_PARAMS,
+    )?;
+    let current_deck_id = config_i64(&conf, "curDeck").unwrap_or(1);
+    let review = scalar(
+        storage,
+        &format!(
+            "select count() from cards where did in {} and queue = 2 and due <= ?",
+            active_sql
+        ),
+        params![today],
+    )?
+    .min(v2_current_review_limit(&inputs, current_deck_id));
+
+    Ok(StudyCounts {
+        new: new_count_for_active_decks(&inputs, &active),
+        learn,
+        review,
+    })
+}
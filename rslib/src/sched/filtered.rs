@@ -0,0 +1,337 @@
+// Copyright: Ankitects Pty Ltd and contributors
+// License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+//! Building and emptying filtered decks. Cards moved into a filtered deck
+//! remember their home deck and due date in odid and odue, which are
+//! restored when the deck is emptied.
+
+use crate::err::Result;
+use crate::sched::ids_to_string;
+use crate::sched::undo::{card_snapshots, write_snapshots};
+use rusqlite::{params, Connection, ToSql, Transaction};
+use std::path::Path;
+
+/// The order cards are gathered in, as stored in the deck's terms.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[repr(u8)]
+pub enum FilteredSearchOrder {
+    OldestReviewedFirst = 0,
+    Random = 1,
+    IntervalsAscending = 2,
+    IntervalsDescending = 3,
+    Lapses = 4,
+    Added = 5,
+    Due = 6,
+    ReverseAdded = 7,
+    DuePriority = 8,
+}
+
+impl FilteredSearchOrder {
+    /// Unknown values are treated as Due.
+    pub fn from_u32(n: u32) -> Self {
+        match n {
+            0 => FilteredSearchOrder::OldestReviewedFirst,
+            1 => FilteredSearchOrder::Random,
+            2 => FilteredSearchOrder::IntervalsAscending,
+            3 => FilteredSearchOrder::IntervalsDescending,
+            4 => FilteredSearchOrder::Lapses,
+            5 => FilteredSearchOrder::Added,
+            7 => FilteredSearchOrder::ReverseAdded,
+            8 => FilteredSearchOrder::DuePriority,
+            _ => FilteredSearchOrder::Due,
+        }
+    }
+
+    /// An SQL order clause, for a query on cards c and notes n.
+    fn sql_order(self, today: u32) -> String {
+        match self {
+            FilteredSearchOrder::OldestReviewedFirst => {
+                "(select max(id) from revlog where cid=c.id)".into()
+            }
+            FilteredSearchOrder::Random => "random()".into(),
+            FilteredSearchOrder::IntervalsAscending => "ivl".into(),
+            FilteredSearchOrder::IntervalsDescending => "ivl desc".into(),
+            FilteredSearchOrder::Lapses => "lapses desc".into(),
+            FilteredSearchOrder::Added => "n.id".into(),
+            FilteredSearchOrder::ReverseAdded => "n.id desc".into(),
+            FilteredSearchOrder::DuePriority => format!(
+                "(case when queue=2 and due <= {today} \
+                 then (ivl / cast({today}-due+0.001 as real)) else 100000+due end)",
+                today = today
+            ),
+            FilteredSearchOrder::Due => "c.due, c.ord".into(),
+        }
+    }
+}
+
+/// One of the searches that make up a filtered deck.
+pub struct FilteredSearchTerm {
+    /// The cards matching the term's search. Searching is done by the
+    /// caller; cards that are already in a filtered deck are skipped.
+    pub card_ids: Vec<i64>,
+    pub order: FilteredSearchOrder,
+    pub limit: u32,
+}
+
+pub struct FilteredDeckFill {
+    pub deck_id: i64,
+    pub terms: Vec<FilteredSearchTerm>,
+    /// If false, cards are shown in the review queue, and their scheduling
+    /// is restored after they're answered.
+    pub reschedule: bool,
+    pub today: u32,
+    pub usn: i32,
+}
+
+/// Cards are positioned starting from this due number, so they are shown
+/// in the order they were gathered.
+const FIRST_POSITION: i64 = -100_000;
+
+/// Move the cards matching each term into the filtered deck, returning the
+/// number of cards moved. Terms are processed in order, so a card matched
+/// by an earlier term is not gathered again.
+pub fn fill_filtered_deck(col_path: &Path, fill: &FilteredDeckFill) -> Result<u32> {
+    let mut db = Connection::open(col_path)?;
+    let tx = db.transaction()?;
+
+    let mut total = 0;
+    for term in &fill.terms {
+        let cids = ordered_card_ids(&tx, term, fill.today)?;
+        move_cards_to_filtered_deck(&tx, fill, &cids, FIRST_POSITION + i64::from(total))?;
+        total += cids.len() as u32;
+    }
+    tx.commit()?;
+
+    Ok(total)
+}
+
+fn ordered_card_ids(tx: &Transaction, term: &FilteredSearchTerm, today: u32) -> Result<Vec<i64>> {
+    if term.card_ids.is_empty() || term.limit == 0 {
+        return Ok(vec![]);
+    }
+    let sql = format!(
+        "select c.id from cards c, notes n where c.nid = n.id and c.odid = 0 and c.id in {}
+order by {} limit {}",
+        ids_to_string(&term.card_ids),
+        term.order.sql_order(today),
+        term.limit
+    );
+    let mut stmt = tx.prepare(&sql)?;
+    let ids = stmt
+        .query_map(params![], |row| row.get(0))?
+        .collect::<rusqlite::Result<_>>()?;
+
+    Ok(ids)
+}
+
+fn move_cards_to_filtered_deck(
+    tx: &Transaction,
+    fill: &FilteredDeckFill,
+    cids: &[i64],
+    start: i64,
+) -> Result<()> {
+    let queue = if fill.reschedule { "" } else { ", queue = 2" };
+    let mut stmt = tx.prepare(&format!(
+        "update cards set odid = did, odue = due, did = ?,
+due = (case when due <= 0 then due else ? end), usn = ?{} where id = ?",
+        queue
+    ))?;
+    for (position, cid) in (start..).zip(cids) {
+        stmt.execute(params![fill.deck_id, position, fill.usn, cid])?;
+    }
+
+    Ok(())
+}
+
+/// Return the cards in the filtered deck to their home decks.
+pub fn empty_filtered_deck(col_path: &Path, deck_id: i64, usn: i32) -> Result<()> {
+    let db = Connection::open(col_path)?;
+    restore_cards(&db, "did = ?", &[&deck_id], usn)
+}
+
+/// Return the provided cards to their home decks. Cards not in a filtered
+/// deck are left alone.
+pub fn remove_from_filtered_decks(col_path: &Path, card_ids: &[i64], usn: i32) -> Result<()> {
+    let db = Connection::open(col_path)?;
+    return_cards_to_home_decks(&db, card_ids, usn)
+}
+
+/// Like remove_from_filtered_decks(), but using an open connection, so it
+/// can be part of a larger transaction.
+pub(crate) fn return_cards_to_home_decks(
+    db: &Connection,
+    card_ids: &[i64],
+    usn: i32,
+) -> Result<()> {
+    if card_ids.is_empty() {
+        return Ok(());
+    }
+    restore_cards(
+        db,
+        &format!("id in {} and odid != 0", ids_to_string(card_ids)),
+        &[],
+        usn,
+    )
+}
+
+/// Move the cards matching the SQL condition back to their home decks,
+/// restoring their original due dates and queues.
+fn restore_cards(db: &Connection, condition: &str, args: &[&dyn ToSql], usn: i32) -> Result<()> {
+    let mut cards = card_snapshots(db, condition, args)?;
+    for card in &mut cards {
+        card.restore_queue();
+        card.deck_id = card.original_deck_id;
+        if card.original_due > 0 {
+            card.due = card.original_due;
+        }
+        card.original_due = 0;
+        card.original_deck_id = 0;
+        card.usn = usn;
+    }
+
+    write_snapshots(db, &cards)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::err::Result;
+    use crate::sched::filtered::{
+        empty_filtered_deck, fill_filtered_deck, remove_from_filtered_decks, FilteredDeckFill,
+        FilteredSearchOrder, FilteredSearchTerm,
+    };
+    use rusqlite::{params, Connection, NO_PARAMS};
+    use std::path::Path;
+    use tempfile::tempdir;
+
+    // (id, did, queue, type, due, ivl)
+    type CardRow = (i64, i64, i8, u8, i64, u32);
+    // (id, did, queue, due, odue, odid)
+    type CardState = (i64, i64, i8, i64, i64, i64);
+
+    fn create_collection(path: &Path, cards: &[CardRow]) -> Result<()> {
+        let db = Connection::open(path)?;
+        db.execute_batch(
+            "create table notes (id integer primary key);
+            create table revlog (id integer primary key, cid integer not null);
+            create table cards (id integer primary key, nid integer not null,
+                did integer not null, ord integer not null default 0,
+                usn integer not null default 0, type integer not null,
+                queue integer not null, due integer not null, ivl integer not null,
+                factor integer not null default 0, lapses integer not null default 0,
+                odue integer not null default 0, odid integer not null default 0,
+                mod integer not null default 0);",
+        )?;
+        for (id, did, queue, ctype, due, ivl) in cards {
+            db.execute("insert into notes (id) values (?)", params![id])?;
+            db.execute(
+                "insert into cards (id, nid, did, type, queue, due, ivl)
+                values (?, ?, ?, ?, ?, ?, ?)",
+                params![id, id, did, ctype, queue, due, ivl],
+            )?;
+        }
+        Ok(())
+    }
+
+    fn cards(path: &Path) -> Result<Vec<CardState>> {
+        let db = Connection::open(path)?;
+        let mut stmt =
+            db.prepare("select id, did, queue, due, odue, odid from cards order by id")?;
+        let rows = stmt
+            .query_map(NO_PARAMS, |row| {
+                Ok((
+                    row.get(0)?,
+                    row.get(1)?,
+                    row.get(2)?,
+                    row.get(3)?,
+                    row.get(4)?,
+                    row.get(5)?,
+                ))
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(rows)
+    }
+
+    #[test]
+    fn test_filtered_deck() -> Result<()> {
+        let dir = tempdir()?;
+        let col_path = dir.path().join("collection.anki2");
+        create_collection(
+            &col_path,
+            &[
+                // new
+                (1, 1, 0, 0, 5, 0),
+                // review cards with various intervals
+                (2, 1, 2, 2, 100, 10),
+                (3, 1, 2, 2, 110, 30),
+                (4, 1, 2, 2, 90, 20),
+                // learning
+                (5, 1, 1, 1, 1_500_000_000, 0),
+            ],
+        )?;
+
+        let fill = FilteredDeckFill {
+            deck_id: 10,
+            terms: vec![
+                FilteredSearchTerm {
+                    card_ids: vec![2, 3, 4],
+                    order: FilteredSearchOrder::IntervalsDescending,
+                    limit: 2,
+                },
+                // cards gathered by the first term are skipped
+                FilteredSearchTerm {
+                    card_ids: vec![1, 2, 3, 4, 5],
+                    order: FilteredSearchOrder::Due,
+                    limit: 100,
+                },
+            ],
+            reschedule: true,
+            today: 100,
+            usn: 5,
+        };
+        assert_eq!(fill_filtered_deck(&col_path, &fill)?, 5);
+        assert_eq!(
+            cards(&col_path)?,
+            vec![
+                (1, 10, 0, -99_998, 5, 1),
+                (2, 10, 2, -99_997, 100, 1),
+                (3, 10, 2, -100_000, 110, 1),
+                (4, 10, 2, -99_999, 90, 1),
+                (5, 10, 1, -99_996, 1_500_000_000, 1),
+            ]
+        );
+
+        // removing individual cards
+        remove_from_filtered_decks(&col_path, &[1], -1)?;
+        assert_eq!(cards(&col_path)?[0], (1, 1, 0, 5, 0, 0));
+
+        // emptying restores the original deck, queue and due date
+        empty_filtered_deck(&col_path, 10, -1)?;
+        assert_eq!(
+            cards(&col_path)?,
+            vec![
+                (1, 1, 0, 5, 0, 0),
+                (2, 1, 2, 100, 0, 0),
+                (3, 1, 2, 110, 0, 0),
+                (4, 1, 2, 90, 0, 0),
+                (5, 1, 1, 1_500_000_000, 0, 0),
+            ]
+        );
+
+        // when not rescheduling, cards are placed in the review queue
+        let fill = FilteredDeckFill {
+            terms: vec![FilteredSearchTerm {
+                card_ids: vec![5],
+                order: FilteredSearchOrder::Random,
+                limit: 1,
+            }],
+            reschedule: false,
+            ..fill
+        };
+        assert_eq!(fill_filtered_deck(&col_path, &fill)?, 1);
+        assert_eq!(cards(&col_path)?[4], (5, 10, 2, -100_000, 1_500_000_000, 1));
+        empty_filtered_deck(&col_path, 10, -1)?;
+        assert_eq!(cards(&col_path)?[4], (5, 1, 1, 1_500_000_000, 0, 0));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,100 @@
+// Copyright: Ankitects Pty Ltd and contributors
+// License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+//! Interval fuzzing. Review intervals are adjusted by a small amount so
+//! that cards added together don't stay together. The adjustment is
+//! derived from the card id and interval, so every client calculates the
+//! same interval for the same answer.
+
+/// The range an interval may be fuzzed into, inclusive.
+///
+/// | interval | range            |
+/// |----------|------------------|
+/// | 0-1      | 1                |
+/// | 2        | 2-3              |
+/// | 3-6      | ± 25%, at least 1 |
+/// | 7-29     | ± 15%, at least 2 |
+/// | 30+      | ± 5%, at least 4  |
+///
+/// Percentages are rounded down.
+pub fn fuzz_range(interval: u32) -> (u32, u32) {
+    let fuzz = if interval < 2 {
+        return (1, 1);
+    } else if interval == 2 {
+        return (2, 3);
+    } else if interval < 7 {
+        (f64::from(interval) * 0.25) as u32
+    } else if interval < 30 {
+        ((f64::from(interval) * 0.15) as u32).max(2)
+    } else {
+        ((f64::from(interval) * 0.05) as u32).max(4)
+    };
+    // fuzz at least a day
+    let fuzz = fuzz.max(1);
+
+    (interval - fuzz, interval + fuzz)
+}
+
+/// Fuzz `interval` (in days) for the card with the provided id. The result
+/// is within fuzz_range(), and is always the same for a given card and
+/// interval.
+///
+/// The value is chosen by taking the SplitMix64 hash of the card id plus
+/// the interval, modulo the size of the range.
+pub fn fuzzed_interval(card_id: i64, interval: u32) -> u32 {
+    let (min, max) = fuzz_range(interval);
+    let seed = (card_id as u64).wrapping_add(u64::from(interval));
+    let offset = splitmix64(seed) % u64::from(max - min + 1);
+
+    min + offset as u32
+}
+
+/// A fast, well-distributed 64 bit hash, which is straightforward to
+/// reproduce in other languages.
+pub(crate) fn splitmix64(seed: u64) -> u64 {
+    let mut z = seed.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::sched::fuzz::{fuzz_range, fuzzed_interval, splitmix64};
+    use std::collections::HashSet;
+
+    #[test]
+    fn test_fuzz_range() {
+        assert_eq!(fuzz_range(0), (1, 1));
+        assert_eq!(fuzz_range(1), (1, 1));
+        assert_eq!(fuzz_range(2), (2, 3));
+        assert_eq!(fuzz_range(4), (3, 5));
+        assert_eq!(fuzz_range(6), (5, 7));
+        assert_eq!(fuzz_range(7), (5, 9));
+        assert_eq!(fuzz_range(20), (17, 23));
+        assert_eq!(fuzz_range(30), (26, 34));
+        assert_eq!(fuzz_range(100), (95, 105));
+    }
+
+    #[test]
+    fn test_fuzzed_interval() {
+        // reference values from the published SplitMix64 algorithm
+        assert_eq!(splitmix64(0), 0xe220_a839_7b1d_cdaf);
+        assert_eq!(splitmix64(1), 0x910a_2dec_8902_5cc1);
+
+        let card_id = 1_581_000_000_000;
+        for interval in 0..1000 {
+            let fuzzed = fuzzed_interval(card_id, interval);
+            let (min, max) = fuzz_range(interval);
+            assert!(fuzzed >= min && fuzzed <= max);
+            // deterministic
+            assert_eq!(fuzzed, fuzzed_interval(card_id, interval));
+        }
+
+        // different cards get different values
+        let seen: HashSet<_> = (0..100)
+            .map(|card_id| fuzzed_interval(card_id, 100))
+            .collect();
+        assert_eq!(seen.len(), 11);
+    }
+}
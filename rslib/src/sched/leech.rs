@@ -0,0 +1,55 @@
+// Copyright: Ankitects Pty Ltd and contributors
+// License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+//! Leeches are cards that keep being forgotten. They are tagged, and
+//! optionally suspended, so the user can rewrite or remove them.
+
+use crate::notes::split_tags;
+
+/// The tag added to the notes of leeches.
+pub const LEECH_TAG: &str = "leech";
+
+/// A card that became a leech when it was answered.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LeechEvent {
+    /// The card was suspended, as the deck options asked.
+    pub suspended: bool,
+}
+
+/// True if a card that has just lapsed has become a leech. Cards become
+/// leeches when they reach the threshold, and again every half threshold
+/// lapses after that, so the user is reminded of cards they have kept.
+/// A threshold of 0 disables leeches.
+pub fn is_leech(lapses: u32, threshold: u32) -> bool {
+    threshold != 0
+        && lapses >= threshold
+        && (lapses - threshold) % (threshold / 2).max(1) == 0
+}
+
+/// Add the leech tag to a note's space-separated tags, if it's not
+/// already present in any case.
+pub fn add_leech_tag(tags: &str) -> String {
+    let mut tags: Vec<_> = split_tags(tags).collect();
+    if !tags.iter().any(|tag| tag.eq_ignore_ascii_case(LEECH_TAG)) {
+        tags.push(LEECH_TAG);
+    }
+
+    format!(" {} ", tags.join(" "))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::sched::leech::{add_leech_tag, is_leech};
+
+    #[test]
+    fn test_leech() {
+        let leeches: Vec<_> = (0..=16).filter(|lapses| is_leech(*lapses, 8)).collect();
+        assert_eq!(leeches, vec![8, 12, 16]);
+        assert_eq!((0..=4).filter(|lapses| is_leech(*lapses, 1)).count(), 4);
+        assert!(!is_leech(0, 0));
+
+        assert_eq!(add_leech_tag(""), " leech ");
+        assert_eq!(add_leech_tag(" one\u{3000}two "), " one two leech ");
+        assert_eq!(add_leech_tag(" Leech "), " Leech ");
+    }
+}
@@ -0,0 +1,160 @@
+// Copyright: Ankitects Pty Ltd and contributors
+// License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+//! Spreading reviews evenly. When a review is rescheduled, any day in its
+//! fuzz range is acceptable; the load balancer picks the one with the
+//! fewest reviews already due, avoiding the user's free days if possible.
+
+use crate::err::Result;
+use crate::sched::fuzz::splitmix64;
+use rusqlite::{params, Connection, OpenFlags};
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+
+pub struct LoadBalancer {
+    /// The number of cards due on each day, keyed by day number.
+    due_counts: HashMap<i64, u32>,
+    today: i64,
+    /// 0 is Monday.
+    today_weekday: u8,
+    free_weekdays: HashSet<u8>,
+}
+
+impl LoadBalancer {
+    /// - today is the current day number
+    /// - today_weekday is the current day of the week, where 0 is Monday
+    /// - free_weekdays are days of the week reviews should not be
+    ///   scheduled on, unless no other day is possible
+    pub fn new(
+        due_counts: HashMap<i64, u32>,
+        today: i64,
+        today_weekday: u8,
+        free_weekdays: &[u8],
+    ) -> Self {
+        LoadBalancer {
+            due_counts,
+            today,
+            today_weekday: today_weekday % 7,
+            free_weekdays: free_weekdays.iter().cloned().collect(),
+        }
+    }
+
+    /// Count the cards in the review and day learning queues that are due
+    /// from today onwards. Changes the caller has not yet committed to the
+    /// collection are not seen.
+    pub fn from_collection(
+        col_path: &Path,
+        today: i64,
+        today_weekday: u8,
+        free_weekdays: &[u8],
+    ) -> Result<Self> {
+        let db = Connection::open_with_flags(col_path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+        let mut stmt = db.prepare(
+            "select (case when odid != 0 then odue else due end) as day, count()
+from cards where queue in (2, 3) and day >= ? group by day",
+        )?;
+        let due_counts = stmt
+            .query_map(params![today], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<rusqlite::Result<_>>()?;
+
+        Ok(LoadBalancer::new(
+            due_counts,
+            today,
+            today_weekday,
+            free_weekdays,
+        ))
+    }
+
+    fn is_free_day(&self, interval: u32) -> bool {
+        let weekday = (u64::from(self.today_weekday) + u64::from(interval)) % 7;
+        self.free_weekdays.contains(&(weekday as u8))
+    }
+
+    fn due_on(&self, interval: u32) -> u32 {
+        self.due_counts
+            .get(&(self.today + i64::from(interval)))
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Choose an interval between min and max, inclusive. Days with the
+    /// fewest reviews due are chosen; if there are several, one is picked
+    /// based on the card id, so cards don't all land on the same day.
+    pub fn balanced_interval(&self, card_id: i64, min: u32, max: u32) -> u32 {
+        let max = max.max(min);
+        let mut candidates: Vec<u32> = (min..=max).filter(|i| !self.is_free_day(*i)).collect();
+        if candidates.is_empty() {
+            candidates = (min..=max).collect();
+        }
+
+        let lowest = candidates
+            .iter()
+            .map(|i| self.due_on(*i))
+            .min()
+            .unwrap_or_default();
+        candidates.retain(|i| self.due_on(*i) == lowest);
+
+        let idx = splitmix64(card_id as u64) % candidates.len() as u64;
+        candidates[idx as usize]
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::err::Result;
+    use crate::sched::load_balance::LoadBalancer;
+    use rusqlite::Connection;
+    use std::collections::HashMap;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_balanced_interval() {
+        let today = 100;
+        let counts: HashMap<i64, u32> = vec![(110, 5), (111, 3), (112, 7), (113, 3)]
+            .into_iter()
+            .collect();
+        let balancer = LoadBalancer::new(counts.clone(), today, 0, &[]);
+
+        // the quietest day is chosen
+        assert_eq!(balancer.balanced_interval(1, 10, 12), 11);
+        // ties are broken by card id, and every quiet day is used
+        let mut chosen: Vec<_> = (0..20)
+            .map(|cid| balancer.balanced_interval(cid, 11, 13))
+            .collect();
+        chosen.sort_unstable();
+        chosen.dedup();
+        assert_eq!(chosen, vec![11, 13]);
+        // days with nothing due beat busy days
+        assert_eq!(balancer.balanced_interval(1, 12, 14), 14);
+
+        // today is a Monday, so an interval of 11 days lands on a Friday
+        let balancer = LoadBalancer::new(counts, today, 0, &[4]);
+        assert_eq!(balancer.balanced_interval(1, 10, 12), 10);
+        // free days are used if there is no alternative
+        assert_eq!(balancer.balanced_interval(1, 11, 11), 11);
+    }
+
+    #[test]
+    fn test_from_collection() -> Result<()> {
+        let dir = tempdir()?;
+        let col_path = dir.path().join("col.anki2");
+        let db = Connection::open(&col_path)?;
+        db.execute_batch(
+            "create table cards (id integer primary key, due integer, queue integer,
+odue integer, odid integer);
+insert into cards values (1, 105, 2, 0, 0);
+insert into cards values (2, 105, 3, 0, 0);
+insert into cards values (3, 106, 2, 0, 0);
+insert into cards values (4, -100, 2, 106, 1);
+insert into cards values (5, 107, 0, 0, 0);
+insert into cards values (6, 50, 2, 0, 0);",
+        )?;
+
+        let balancer = LoadBalancer::from_collection(&col_path, 100, 0, &[])?;
+        let mut counts: Vec<_> = balancer.due_counts.into_iter().collect();
+        counts.sort_unstable();
+        assert_eq!(counts, vec![(105, 2), (106, 2)]);
+
+        Ok(())
+    }
+}
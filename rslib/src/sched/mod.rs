@@ -1,7 +1,19 @@
 // Copyright: Ankitects Pty Ltd and contributors
 // License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
 
-use chrono::{Date, Duration, FixedOffset, Local, TimeZone};
+pub mod algorithm;
+pub mod answering;
+pub mod bury_suspend;
+pub mod counts;
+pub mod current_deck;
+pub mod filtered;
+pub mod fuzz;
+pub mod leech;
+pub mod load_balance;
+pub mod reschedule;
+pub mod undo;
+
+use chrono::{Date, Duration, FixedOffset, Local, NaiveDateTime, TimeZone};
 
 pub struct SchedTimingToday {
     /// The number of days that have passed since the collection was created.
@@ -50,6 +62,62 @@ pub fn sched_timing_today(
     }
 }
 
+/// Timing information for the current day, using the local timezone of
+/// this device. See sched_timing_today_in_timezone().
+pub fn local_sched_timing_today(
+    created_secs: i64,
+    now_secs: i64,
+    rollover_hour: i8,
+) -> SchedTimingToday {
+    sched_timing_today_in_timezone(&Local, created_secs, now_secs, rollover_hour)
+}
+
+/// Timing information for the current day in the provided timezone.
+///
+/// Unlike sched_timing_today(), which works with fixed UTC offsets, this
+/// follows daylight savings changes: the day always rolls over at
+/// rollover_hour local time, so days may be 23 or 25 hours long. If the
+/// rollover hour is skipped by a daylight savings change, the day rolls
+/// over an hour later.
+pub fn sched_timing_today_in_timezone<Tz: TimeZone>(
+    tz: &Tz,
+    created_secs: i64,
+    now_secs: i64,
+    rollover_hour: i8,
+) -> SchedTimingToday {
+    let rollover_hour = normalized_rollover_hour(rollover_hour);
+    let created_date = local_datetime(tz, created_secs).date();
+    let now = local_datetime(tz, now_secs);
+
+    // the day a time belongs to starts at the rollover hour
+    let today = (now - Duration::hours(rollover_hour.into())).date();
+    let days_elapsed = (today - created_date).num_days().max(0) as u32;
+
+    let next_day = (today + Duration::days(1))
+        .and_hms_opt(rollover_hour.into(), 0, 0)
+        .unwrap();
+    let next_day_at = tz
+        .from_local_datetime(&next_day)
+        .earliest()
+        .or_else(|| {
+            tz.from_local_datetime(&(next_day + Duration::hours(1)))
+                .earliest()
+        })
+        .map(|dt| dt.timestamp())
+        .unwrap_or_else(|| now_secs + 86_400);
+
+    SchedTimingToday {
+        days_elapsed,
+        next_day_at,
+    }
+}
+
+/// The wall clock time in the provided timezone. Unlike local times,
+/// timestamps always map to a single result.
+fn local_datetime<Tz: TimeZone>(tz: &Tz, stamp: i64) -> NaiveDateTime {
+    tz.timestamp_opt(stamp, 0).unwrap().naive_local()
+}
+
 /// The number of times the day rolled over between two dates.
 fn days_elapsed(
     start_date: Date<FixedOffset>,
@@ -90,13 +158,20 @@ pub fn local_minutes_west_for_stamp(stamp: i64) -> i32 {
     Local.timestamp(stamp, 0).offset().utc_minus_local() / 60
 }
 
+/// A list of ids for use in an SQL `in` clause, eg "(1,2,3)".
+pub(crate) fn ids_to_string(ids: &[i64]) -> String {
+    let ids: Vec<_> = ids.iter().map(ToString::to_string).collect();
+    format!("({})", ids.join(","))
+}
+
 #[cfg(test)]
 mod test {
     use crate::sched::{
         fixed_offset_from_minutes, local_minutes_west_for_stamp, normalized_rollover_hour,
-        sched_timing_today,
+        sched_timing_today, sched_timing_today_in_timezone,
     };
     use chrono::{FixedOffset, Local, TimeZone, Utc};
+    use chrono_tz::America::Denver;
 
     #[test]
     fn test_rollover() {
@@ -258,4 +333,57 @@ mod test {
         );
         assert_eq!(today.next_day_at, next_day_at.timestamp());
     }
+
+    #[test]
+    fn test_timing_in_timezone() {
+        let crt = Denver.ymd(2019, 3, 1).and_hms(12, 0, 0).timestamp();
+        let timing =
+            |now: i64, rollover: i8| sched_timing_today_in_timezone(&Denver, crt, now, rollover);
+
+        // without a daylight savings change, this matches the fixed offset
+        // calculation
+        let mst = FixedOffset::west(7 * 60 * 60);
+        for hour in &[0, 3, 4, 12, 23] {
+            let now = mst.ymd(2019, 3, 5).and_hms(*hour, 0, 0).timestamp();
+            let today = timing(now, 4);
+            let expected = sched_timing_today(crt, 7 * 60, now, 7 * 60, 4);
+            assert_eq!(today.days_elapsed, expected.days_elapsed);
+            assert_eq!(today.next_day_at, expected.next_day_at);
+        }
+
+        // clocks go forward at 2am on March 10th, so that day is 23 hours
+        // long, and the rollover still happens at 4am local time
+        let now = Denver.ymd(2019, 3, 10).and_hms(1, 0, 0).timestamp();
+        let today = timing(now, 4);
+        assert_eq!(today.days_elapsed, 8);
+        assert_eq!(
+            today.next_day_at,
+            Denver.ymd(2019, 3, 10).and_hms(4, 0, 0).timestamp()
+        );
+        assert_eq!(today.next_day_at - now, 2 * 3600);
+        let now = Denver.ymd(2019, 3, 10).and_hms(12, 0, 0).timestamp();
+        assert_eq!(
+            timing(now, 4).next_day_at,
+            Denver.ymd(2019, 3, 11).and_hms(4, 0, 0).timestamp()
+        );
+
+        // a rollover hour that doesn't exist on that day happens an hour later
+        let now = Denver.ymd(2019, 3, 9).and_hms(12, 0, 0).timestamp();
+        assert_eq!(
+            timing(now, 2).next_day_at,
+            Denver.ymd(2019, 3, 10).and_hms(3, 0, 0).timestamp()
+        );
+
+        // clocks go back at 2am on November 3rd; the day count doesn't drift
+        let now = Denver.ymd(2019, 11, 3).and_hms(3, 59, 59).timestamp();
+        assert_eq!(timing(now, 4).days_elapsed, 246);
+        let now = Denver.ymd(2019, 11, 3).and_hms(4, 0, 0).timestamp();
+        assert_eq!(timing(now, 4).days_elapsed, 247);
+        // an ambiguous rollover hour uses the first occurrence
+        let now = Denver.ymd(2019, 11, 2).and_hms(12, 0, 0).timestamp();
+        assert_eq!(
+            timing(now, 1).next_day_at,
+            Utc.ymd(2019, 11, 3).and_hms(7, 0, 0).timestamp()
+        );
+    }
 }
@@ -0,0 +1,242 @@
+// Copyright: Ankitects Pty Ltd and contributors
+// License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+//! Manually setting the due date of cards.
+
+use crate::card::{CardQueue, CardType};
+use crate::err::{AnkiError, Result};
+use crate::sched::ids_to_string;
+use crate::sched::undo::{update_cards, CardScheduleSnapshot};
+use lazy_static::lazy_static;
+use rand::distributions::Uniform;
+use rand::Rng;
+use regex::Regex;
+use std::path::Path;
+
+/// The ease given to cards that don't have one yet.
+const INITIAL_EASE_FACTOR: u16 = 2500;
+
+/// Due numbers above this are timestamps rather than day numbers.
+const TIMESTAMP_CUTOFF: i64 = 1_000_000_000;
+
+/// The furthest a card can be scheduled into the future, in days.
+const MAX_DUE_DAYS: u32 = 36_500;
+
+/// A range of days from today, and whether intervals should be reset.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DueDateSpec {
+    pub min_days: u32,
+    pub max_days: u32,
+    /// If false, review cards keep their interval, extended or shortened
+    /// by the distance they were moved. New and learning cards always get
+    /// an interval matching their new due date.
+    pub force_reset: bool,
+}
+
+impl DueDateSpec {
+    /// Parse a spec like "0" (today), "1-7" (a random day in the next
+    /// week) or "3!" (in three days, resetting the interval to 3).
+    pub fn parse(text: &str) -> Result<Self> {
+        lazy_static! {
+            static ref SPEC: Regex = Regex::new(r"^\s*(\d+)(?:\s*-\s*(\d+))?\s*(!)?\s*$").unwrap();
+        }
+        let caps = SPEC
+            .captures(text)
+            .ok_or_else(|| AnkiError::invalid_input(format!("invalid due date: {}", text)))?;
+        let parse_days = |s: &str| {
+            s.parse::<u32>()
+                .ok()
+                .filter(|days| *days <= MAX_DUE_DAYS)
+                .ok_or_else(|| AnkiError::invalid_input(format!("invalid due date: {}", text)))
+        };
+        let min_days = parse_days(&caps[1])?;
+        let max_days = match caps.get(2) {
+            Some(max) => parse_days(max.as_str())?,
+            None => min_days,
+        };
+
+        Ok(DueDateSpec {
+            min_days: min_days.min(max_days),
+            max_days: min_days.max(max_days),
+            force_reset: caps.get(3).is_some(),
+        })
+    }
+}
+
+impl CardScheduleSnapshot {
+    /// Schedule the card as a review due in `days` days.
+    fn set_due_date(&mut self, today: u32, days: u32, force_reset: bool) {
+        let new_due = i64::from(today) + i64::from(days);
+        let old_due = if self.original_deck_id != 0 {
+            self.original_due
+        } else {
+            self.due
+        };
+        let is_review = match CardType::from_u8(self.ctype) {
+            Some(CardType::Review) | Some(CardType::Relearn) => true,
+            _ => false,
+        };
+
+        self.interval = if force_reset || !is_review || old_due > TIMESTAMP_CUTOFF {
+            days
+        } else {
+            (i64::from(self.interval) + new_due - old_due).max(0) as u32
+        }
+        .max(1);
+
+        if self.original_deck_id != 0 {
+            self.deck_id = self.original_deck_id;
+            self.original_deck_id = 0;
+        }
+        self.original_due = 0;
+        self.due = new_due;
+        self.ctype = CardType::Review as u8;
+        self.queue = CardQueue::Review as i8;
+        if self.ease_factor == 0 {
+            self.ease_factor = INITIAL_EASE_FACTOR;
+        }
+    }
+}
+
+/// Reschedule the provided cards as reviews due on a day in the spec's
+/// range. Cards in filtered decks are returned to their home decks.
+/// The cards' previous state is returned, for use with
+/// undo::restore_card_schedules().
+pub fn set_due_date<R: Rng>(
+    col_path: &Path,
+    card_ids: &[i64],
+    spec: DueDateSpec,
+    today: u32,
+    usn: i32,
+    mtime_secs: i64,
+    rng: &mut R,
+) -> Result<Vec<CardScheduleSnapshot>> {
+    if card_ids.is_empty() {
+        return Ok(vec![]);
+    }
+    update_cards(
+        col_path,
+        &format!("id in {}", ids_to_string(card_ids)),
+        &[],
+        usn,
+        mtime_secs,
+        |card| {
+            let days = rng.sample(Uniform::new_inclusive(spec.min_days, spec.max_days));
+            card.set_due_date(today, days, spec.force_reset);
+        },
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use crate::err::Result;
+    use crate::sched::ids_to_string;
+    use crate::sched::reschedule::{set_due_date, DueDateSpec};
+    use crate::sched::undo::{card_snapshots, restore_card_schedules, CardScheduleSnapshot};
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+    use rusqlite::Connection;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_parse_spec() {
+        let spec = |min_days, max_days, force_reset| DueDateSpec {
+            min_days,
+            max_days,
+            force_reset,
+        };
+        assert_eq!(DueDateSpec::parse("0").unwrap(), spec(0, 0, false));
+        assert_eq!(DueDateSpec::parse("1-7").unwrap(), spec(1, 7, false));
+        assert_eq!(DueDateSpec::parse(" 7 - 1 ").unwrap(), spec(1, 7, false));
+        assert_eq!(DueDateSpec::parse("3!").unwrap(), spec(3, 3, true));
+        assert_eq!(DueDateSpec::parse("1-3!").unwrap(), spec(1, 3, true));
+        assert!(DueDateSpec::parse("").is_err());
+        assert!(DueDateSpec::parse("-1").is_err());
+        assert!(DueDateSpec::parse("1-").is_err());
+        assert!(DueDateSpec::parse("99999999999").is_err());
+        assert_eq!(
+            DueDateSpec::parse("36500").unwrap(),
+            spec(36500, 36500, false)
+        );
+        assert!(DueDateSpec::parse("0-36501").is_err());
+    }
+
+    #[test]
+    fn test_set_due_date() -> Result<()> {
+        let dir = tempdir()?;
+        let col_path = dir.path().join("collection.anki2");
+        let db = Connection::open(&col_path)?;
+        db.execute_batch(
+            "create table cards (id integer primary key, did integer, type integer,
+queue integer, due integer, ivl integer, factor integer, odue integer, odid integer,
+mod integer, usn integer);
+insert into cards values (1, 1, 0, 0, 5, 0, 0, 0, 0, 10, 0);
+insert into cards values (2, 1, 2, 2, 100, 10, 2000, 0, 0, 10, 0);
+insert into cards values (3, 5, 2, 2, -100000, 10, 2000, 105, 1, 10, 0);
+insert into cards values (4, 1, 3, 1, 1500000000, 1, 2000, 0, 0, 10, 0);
+insert into cards values (5, 1, 2, -1, 100, 10, 2000, 0, 0, 10, 0);",
+        )?;
+        let cards = |ids: &[i64]| -> Result<Vec<CardScheduleSnapshot>> {
+            let mut db = Connection::open(&col_path)?;
+            let tx = db.transaction()?;
+            let mut cards = card_snapshots(&tx, &format!("id in {}", ids_to_string(ids)), &[])?;
+            cards.sort_by_key(|c| c.id);
+            Ok(cards)
+        };
+        let before = cards(&[1, 2, 3, 4, 5])?;
+        let mut rng = StdRng::seed_from_u64(0);
+
+        let spec = DueDateSpec::parse("3")?;
+        let mut original = set_due_date(&col_path, &[1, 2, 3, 4, 5], spec, 100, -1, 20, &mut rng)?;
+        original.sort_by_key(|c| c.id);
+        assert_eq!(original, before);
+
+        let after = cards(&[1, 2, 3, 4, 5])?;
+        let summary: Vec<_> = after
+            .iter()
+            .map(|c| {
+                (
+                    c.deck_id,
+                    c.ctype,
+                    c.queue,
+                    c.due,
+                    c.interval,
+                    c.ease_factor,
+                )
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                // new cards get the default ease
+                (1, 2, 2, 103, 3, 2500),
+                // reviews due today moved 3 days later get a longer interval
+                (1, 2, 2, 103, 13, 2000),
+                // cards in filtered decks are returned home, and their
+                // original due date is used
+                (1, 2, 2, 103, 8, 2000),
+                // relearning cards with a timestamp due are reset
+                (1, 2, 2, 103, 3, 2000),
+                // suspended cards are unsuspended
+                (1, 2, 2, 103, 13, 2000),
+            ]
+        );
+        assert!(after.iter().all(|c| c.original_deck_id == 0
+            && c.original_due == 0
+            && c.mtime_secs == 20
+            && c.usn == -1));
+
+        // resetting the interval
+        let spec = DueDateSpec::parse("1-2!")?;
+        set_due_date(&col_path, &[2], spec, 100, -1, 20, &mut rng)?;
+        let card = &cards(&[2])?[0];
+        assert!(card.interval == 1 || card.interval == 2);
+        assert_eq!(card.due, 100 + i64::from(card.interval));
+
+        // undoing
+        restore_card_schedules(&col_path, &original)?;
+        assert_eq!(cards(&[1, 2, 3, 4, 5])?, before);
+
+        Ok(())
+    }
+}
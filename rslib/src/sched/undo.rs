@@ -0,0 +1,145 @@
+// Copyright: Ankitects Pty Ltd and contributors
+// License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+//! Undoing scheduling operations. Operations that the backend commits
+//! itself can't be undone by rolling back the caller's transaction, so
+//! they return the previous state of the cards they changed instead.
+
+use crate::card::CardType;
+use crate::err::Result;
+use rusqlite::{params, Connection, Row, ToSql};
+use std::path::Path;
+
+/// The scheduling-related columns of a card, so a change can be undone.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CardScheduleSnapshot {
+    pub id: i64,
+    pub deck_id: i64,
+    pub ctype: u8,
+    pub queue: i8,
+    pub due: i64,
+    pub interval: u32,
+    pub ease_factor: u16,
+    pub original_due: i64,
+    pub original_deck_id: i64,
+    pub mtime_secs: i64,
+    pub usn: i32,
+}
+
+impl CardScheduleSnapshot {
+    fn from_row(row: &Row) -> rusqlite::Result<Self> {
+        Ok(CardScheduleSnapshot {
+            id: row.get(0)?,
+            deck_id: row.get(1)?,
+            ctype: row.get(2)?,
+            queue: row.get(3)?,
+            due: row.get(4)?,
+            interval: row.get(5)?,
+            ease_factor: row.get(6)?,
+            original_due: row.get(7)?,
+            original_deck_id: row.get(8)?,
+            mtime_secs: row.get(9)?,
+            usn: row.get(10)?,
+        })
+    }
+
+    /// Move a suspended or buried card back to the queue matching its
+    /// type.
+    pub(crate) fn restore_queue(&mut self) {
+        let due = if self.original_due != 0 {
+            self.original_due
+        } else {
+            self.due
+        };
+        self.queue = match CardType::from_u8(self.ctype) {
+            Some(ctype) => ctype.home_queue(due) as i8,
+            None => self.ctype as i8,
+        };
+    }
+}
+
+/// Snapshots of the cards matching the provided SQL condition.
+pub(crate) fn card_snapshots(
+    db: &Connection,
+    condition: &str,
+    args: &[&dyn ToSql],
+) -> Result<Vec<CardScheduleSnapshot>> {
+    let mut stmt = db.prepare(&format!(
+        "select id, did, type, queue, due, ivl, factor, odue, odid, mod, usn
+from cards where {}",
+        condition
+    ))?;
+    let cards = stmt
+        .query_map(args, CardScheduleSnapshot::from_row)?
+        .collect::<rusqlite::Result<_>>()?;
+
+    Ok(cards)
+}
+
+pub(crate) fn write_snapshots(db: &Connection, cards: &[CardScheduleSnapshot]) -> Result<()> {
+    let mut stmt = db.prepare(
+        "update cards set did = ?, type = ?, queue = ?, due = ?, ivl = ?, factor = ?,
+odue = ?, odid = ?, mod = ?, usn = ? where id = ?",
+    )?;
+    for card in cards {
+        stmt.execute(params![
+            card.deck_id,
+            card.ctype,
+            card.queue,
+            card.due,
+            card.interval,
+            card.ease_factor,
+            card.original_due,
+            card.original_deck_id,
+            card.mtime_secs,
+            card.usn,
+            card.id
+        ])?;
+    }
+
+    Ok(())
+}
+
+/// Apply `change` to the cards matching `condition` in a single
+/// transaction, updating their modification time and usn. The cards'
+/// previous state is returned.
+pub(crate) fn update_cards<F>(
+    col_path: &Path,
+    condition: &str,
+    args: &[&dyn ToSql],
+    usn: i32,
+    mtime_secs: i64,
+    mut change: F,
+) -> Result<Vec<CardScheduleSnapshot>>
+where
+    F: FnMut(&mut CardScheduleSnapshot),
+{
+    let mut db = Connection::open(col_path)?;
+    let tx = db.transaction()?;
+
+    let original = card_snapshots(&tx, condition, args)?;
+    let updated: Vec<_> = original
+        .iter()
+        .cloned()
+        .map(|mut card| {
+            change(&mut card);
+            card.mtime_secs = mtime_secs;
+            card.usn = usn;
+            card
+        })
+        .collect();
+    write_snapshots(&tx, &updated)?;
+    tx.commit()?;
+
+    Ok(original)
+}
+
+/// Write previously saved scheduling state back to the cards.
+pub fn restore_card_schedules(col_path: &Path, cards: &[CardScheduleSnapshot]) -> Result<()> {
+    let mut db = Connection::open(col_path)?;
+    let tx = db.transaction()?;
+    write_snapshots(&tx, cards)?;
+    tx.commit()?;
+
+    Ok(())
+}
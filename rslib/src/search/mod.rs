@@ -0,0 +1,461 @@
+// Copyright: Ankitects Pty Ltd and contributors
+// License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+//! The browser's search language. A search is parsed into a list of nodes,
+//! which is then converted into SQL and run against the collection. If the
+//! full-text index is enabled, it's updated before searching, so searches
+//! may write to the collection.
+
+mod parser;
+mod saved;
+mod sqlwriter;
+
+use crate::err::Result;
+use crate::storage::SqliteStorage;
+pub(crate) use sqlwriter::names_match;
+use sqlwriter::node_to_sql;
+
+pub use parser::{parse, Node, PropertyKind, SearchNode, StateKind, TemplateKind};
+pub use saved::{remove_saved_search, rename_saved_search, save_search, saved_searches};
+
+/// Details of the collection some searches depend on.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SearchContext {
+    /// The scheduler's day number.
+    pub today: u32,
+    /// The end of today, in seconds.
+    pub day_cutoff: i64,
+    /// The deck deck:current refers to.
+    pub current_deck_id: i64,
+}
+
+/// How card search results are ordered.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SortMode {
+    NoOrder,
+    /// The browser's sort column, as stored in the collection config.
+    FromConfig,
+    /// An SQL order by clause, which may also contain a limit.
+    Custom(String),
+}
+
+/// The ids of cards matching `search`.
+pub fn search_cards(
+    storage: &SqliteStorage,
+    search: &str,
+    ctx: &SearchContext,
+    order: &SortMode,
+) -> Result<Vec<i64>> {
+    storage.update_fulltext_index()?;
+    let (where_clause, args) = node_to_sql(storage, ctx, &parse(search)?)?;
+    let mut sql = format!(
+        "select c.id from cards c, notes n where c.nid = n.id and ({})",
+        where_clause
+    );
+    let reverse = match order {
+        SortMode::NoOrder => false,
+        SortMode::FromConfig => {
+            let (clause, reverse) = order_from_config(storage)?;
+            sql.push_str(" order by ");
+            sql.push_str(clause);
+            reverse
+        }
+        SortMode::Custom(clause) => {
+            sql.push_str(" order by ");
+            sql.push_str(clause);
+            false
+        }
+    };
+
+    let mut ids = query_ids(storage, &sql, &args)?;
+    if reverse {
+        ids.reverse();
+    }
+    Ok(ids)
+}
+
+/// The ids of notes with a card matching `search`.
+pub fn search_notes(
+    storage: &SqliteStorage,
+    search: &str,
+    ctx: &SearchContext,
+) -> Result<Vec<i64>> {
+    storage.update_fulltext_index()?;
+    let (where_clause, args) = node_to_sql(storage, ctx, &parse(search)?)?;
+    let sql = format!(
+        "select distinct n.id from cards c, notes n where c.nid = n.id and ({})",
+        where_clause
+    );
+    query_ids(storage, &sql, &args)
+}
+
+/// Which part of the results to return, and how they're sorted.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PageSpec {
+    /// A browser column like "cardDue", as stored in the sortType config
+    /// key. Unknown columns sort by note creation.
+    pub column: String,
+    pub reverse: bool,
+    pub offset: u32,
+    /// 0 for all the results after `offset`.
+    pub limit: u32,
+}
+
+/// One page of search results, and the number of matches on all pages.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SearchPage {
+    pub ids: Vec<i64>,
+    pub total: u32,
+}
+
+/// A page of the ids of cards matching `search`, so large results don't
+/// need to be held in memory at once.
+pub fn search_cards_page(
+    storage: &SqliteStorage,
+    search: &str,
+    ctx: &SearchContext,
+    page: &PageSpec,
+) -> Result<SearchPage> {
+    storage.update_fulltext_index()?;
+    let (where_clause, args) = node_to_sql(storage, ctx, &parse(search)?)?;
+    let from = format!(
+        "from cards c, notes n where c.nid = n.id and ({})",
+        where_clause
+    );
+    let total = query_count(storage, &format!("select count() {}", from), &args)?;
+    let sql = format!(
+        "select c.id {} order by {} {}",
+        from,
+        order_clause(card_order_for_column(&page.column), page.reverse),
+        limit_clause(page)
+    );
+
+    Ok(SearchPage {
+        ids: query_ids(storage, &sql, &args)?,
+        total,
+    })
+}
+
+/// Like search_cards_page(), but for notes with a card matching `search`.
+/// Notes can only be sorted on note columns; other columns sort by
+/// creation.
+pub fn search_notes_page(
+    storage: &SqliteStorage,
+    search: &str,
+    ctx: &SearchContext,
+    page: &PageSpec,
+) -> Result<SearchPage> {
+    storage.update_fulltext_index()?;
+    let (where_clause, args) = node_to_sql(storage, ctx, &parse(search)?)?;
+    let from = format!(
+        "from cards c, notes n where c.nid = n.id and ({})",
+        where_clause
+    );
+    let total = query_count(
+        storage,
+        &format!("select count(distinct n.id) {}", from),
+        &args,
+    )?;
+    let sql = format!(
+        "select n.id {} group by n.id order by {} {}",
+        from,
+        order_clause(note_order_for_column(&page.column), page.reverse),
+        limit_clause(page)
+    );
+
+    Ok(SearchPage {
+        ids: query_ids(storage, &sql, &args)?,
+        total,
+    })
+}
+
+fn query_count(storage: &SqliteStorage, sql: &str, args: &[String]) -> Result<u32> {
+    Ok(storage.db.query_row(sql, args, |row| row.get(0))?)
+}
+
+fn query_ids(storage: &SqliteStorage, sql: &str, args: &[String]) -> Result<Vec<i64>> {
+    let mut stmt = storage.db.prepare(sql)?;
+    let ids = stmt
+        .query_map(args, |row| row.get(0))?
+        .collect::<rusqlite::Result<_>>()?;
+    Ok(ids)
+}
+
+/// The order by clause for the browser's sort column, and whether the
+/// results should be reversed.
+fn order_from_config(storage: &SqliteStorage) -> Result<(&'static str, bool)> {
+    let column: String = storage
+        .get_config_value("sortType")?
+        .unwrap_or_else(|| "noteCrt".into());
+    let reverse = storage.get_config_value("sortBackwards")?.unwrap_or(false);
+
+    Ok((card_order_for_column(&column), reverse))
+}
+
+fn card_order_for_column(column: &str) -> &'static str {
+    match column {
+        "noteMod" => "n.mod, c.ord",
+        "noteFld" => "n.sfld collate nocase, c.ord",
+        "cardMod" => "c.mod",
+        "cardReps" => "c.reps",
+        "cardDue" => "c.type, c.due",
+        "cardEase" => "c.type == 0, c.factor",
+        "cardLapses" => "c.lapses",
+        "cardIvl" => "c.ivl",
+        // noteCrt, and any unknown column
+        _ => "n.id, c.ord",
+    }
+}
+
+fn note_order_for_column(column: &str) -> &'static str {
+    match column {
+        "noteMod" => "n.mod",
+        "noteFld" => "n.sfld collate nocase",
+        _ => "n.id",
+    }
+}
+
+/// The order by clause, with each term descending if `reverse` is set.
+fn order_clause(clause: &str, reverse: bool) -> String {
+    if reverse {
+        clause
+            .split(", ")
+            .map(|term| format!("{} desc", term))
+            .collect::<Vec<_>>()
+            .join(", ")
+    } else {
+        clause.to_string()
+    }
+}
+
+fn limit_clause(page: &PageSpec) -> String {
+    // a negative limit means no limit
+    let limit = if page.limit == 0 {
+        -1
+    } else {
+        i64::from(page.limit)
+    };
+    format!("limit {} offset {}", limit, page.offset)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::card::{Card, CardType};
+    use crate::err::{AnkiError, Result};
+    use crate::notes::Note;
+    use crate::search::{
+        search_cards, search_cards_page, search_notes, search_notes_page, PageSpec, SearchContext,
+        SortMode,
+    };
+    use crate::storage::testutil::{add_deck, open_test_collection};
+
+    #[test]
+    fn test_search() -> Result<()> {
+        let (_dir, storage) = open_test_collection()?;
+        for (id, name) in &[(1, "Default"), (2, "Default::Child"), (3, "Other")] {
+            add_deck(&storage, *id, name)?;
+        }
+        let mut note = Note {
+            fields: vec!["front".into(), "back".into()],
+            sort_field: "front".into(),
+            tags: vec!["one::two".into()],
+            ..Default::default()
+        };
+        storage.add_note(&mut note)?;
+        let mut ids = vec![];
+        for deck_id in 1..=3 {
+            let mut card = Card {
+                note_id: note.id,
+                deck_id,
+                ordinal: deck_id as u16,
+                mtime_secs: deck_id,
+                ..Default::default()
+            };
+            storage.add_card(&mut card)?;
+            ids.push(card.id);
+        }
+
+        let ctx = SearchContext {
+            today: 0,
+            day_cutoff: 0,
+            current_deck_id: 1,
+        };
+        let search = |text: &str| search_cards(&storage, text, &ctx, &SortMode::NoOrder);
+        assert_eq!(search("")?, ids);
+        assert_eq!(search("deck:current")?, &ids[..2]);
+        assert_eq!(search("deck:other or deck:def*::child")?, &ids[1..]);
+        assert_eq!(search("-deck:default fro*")?, &ids[2..]);
+        assert_eq!(search("tag:one::two is:new")?, ids);
+        assert_eq!(search("tag:one::*")?, ids);
+        assert_eq!(search("tag:one::two::*")?, ids);
+        assert!(search("tag:one")?.is_empty());
+        assert!(search("tag:two::*")?.is_empty());
+        assert!(search("is:foo").is_err());
+        // terms that can't be matched are reported with their position
+        match search("front nofield:x") {
+            Err(AnkiError::SearchError { start, end, .. }) => assert_eq!((start, end), (6, 15)),
+            other => panic!("unexpected: {:?}", other),
+        }
+        // regexes are matched against each field, ignoring case
+        assert_eq!(search("re:^BA")?, ids);
+        assert!(search("re:t.b")?.is_empty());
+
+        storage.set_config_value("sortBackwards", &true)?;
+        let mut reversed = ids.clone();
+        reversed.reverse();
+        assert_eq!(
+            search_cards(&storage, "", &ctx, &SortMode::FromConfig)?,
+            reversed
+        );
+        assert_eq!(search_notes(&storage, "back", &ctx)?, vec![note.id]);
+
+        // results can be fetched a page at a time
+        let mut spec = PageSpec {
+            column: "cardMod".into(),
+            reverse: true,
+            offset: 1,
+            limit: 1,
+        };
+        let page = search_cards_page(&storage, "", &ctx, &spec)?;
+        assert_eq!((page.ids, page.total), (vec![ids[1]], 3));
+        spec.limit = 0;
+        let page = search_cards_page(&storage, "", &ctx, &spec)?;
+        assert_eq!(page.ids, &reversed[1..]);
+        spec.offset = 0;
+        let page = search_notes_page(&storage, "front", &ctx, &spec)?;
+        assert_eq!((page.ids, page.total), (vec![note.id], 1));
+
+        // the full-text index doesn't change what plain words match
+        storage.set_fulltext_index_enabled(true)?;
+        assert_eq!(search("fro")?, ids);
+        assert_eq!(search("ront")?, ids);
+        assert_eq!(search("RON")?, ids);
+        assert!(search("fronts")?.is_empty());
+        assert!(search("tb")?.is_empty());
+        assert_eq!(search("ron*")?, ids);
+        assert!(search("-bac is:new")?.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_search_page() -> Result<()> {
+        let (_dir, storage) = open_test_collection()?;
+        // (sort field, [(type, due) of each card])
+        let notes = [
+            ("banana", [(CardType::New, 1), (CardType::Review, 2)]),
+            ("Apple", [(CardType::Review, 5), (CardType::New, 3)]),
+            ("cherry", [(CardType::Learn, 100), (CardType::New, 2)]),
+        ];
+        let mut note_ids = vec![];
+        // card ids by note and ordinal
+        let mut card_ids = vec![];
+        for (sort_field, cards) in &notes {
+            let mut note = Note {
+                fields: vec![(*sort_field).into()],
+                sort_field: (*sort_field).into(),
+                ..Default::default()
+            };
+            storage.add_note(&mut note)?;
+            note_ids.push(note.id);
+            let mut ids = vec![];
+            for (ord, (ctype, due)) in cards.iter().enumerate() {
+                let mut card = Card {
+                    note_id: note.id,
+                    deck_id: 1,
+                    ordinal: ord as u16,
+                    ctype: *ctype,
+                    due: *due,
+                    ..Default::default()
+                };
+                storage.add_card(&mut card)?;
+                ids.push(card.id);
+            }
+            card_ids.push(ids);
+        }
+        let card = |note: usize, ord: usize| card_ids[note][ord];
+
+        let ctx = SearchContext {
+            today: 0,
+            day_cutoff: 0,
+            current_deck_id: 1,
+        };
+        let page = |search: &str, column: &str, reverse: bool, offset: u32, limit: u32| {
+            let spec = PageSpec {
+                column: column.into(),
+                reverse,
+                offset,
+                limit,
+            };
+            let page = search_cards_page(&storage, search, &ctx, &spec).unwrap();
+            (page.ids, page.total)
+        };
+
+        // the sort field is compared ignoring case, then the cards of a
+        // note are in template order
+        let by_field = vec![
+            card(1, 0),
+            card(1, 1),
+            card(0, 0),
+            card(0, 1),
+            card(2, 0),
+            card(2, 1),
+        ];
+        assert_eq!(page("", "noteFld", false, 0, 0), (by_field.clone(), 6));
+        // reversing reverses each part of the order
+        let mut reversed = by_field.clone();
+        reversed.reverse();
+        assert_eq!(page("", "noteFld", true, 0, 0), (reversed, 6));
+
+        // new cards sort before learning cards and reviews, each by due
+        assert_eq!(
+            page("", "cardDue", false, 0, 0).0,
+            vec![
+                card(0, 0),
+                card(2, 1),
+                card(1, 1),
+                card(2, 0),
+                card(0, 1),
+                card(1, 0)
+            ]
+        );
+        // unknown columns sort by note creation
+        assert_eq!(
+            page("", "bogus", false, 0, 0).0,
+            card_ids.iter().flatten().cloned().collect::<Vec<_>>()
+        );
+
+        // pages cover the results in order, each reporting the total
+        assert_eq!(
+            page("", "noteFld", false, 0, 4),
+            (by_field[..4].to_vec(), 6)
+        );
+        assert_eq!(
+            page("", "noteFld", false, 4, 4),
+            (by_field[4..].to_vec(), 6)
+        );
+        assert_eq!(page("", "noteFld", false, 6, 4), (vec![], 6));
+        assert_eq!(
+            page("", "noteFld", false, 2, 0),
+            (by_field[2..].to_vec(), 6)
+        );
+        // the total is of the matches, not the collection
+        assert_eq!(
+            page("a", "noteFld", false, 1, 2),
+            (by_field[1..3].to_vec(), 4)
+        );
+        assert_eq!(page("nothing", "noteFld", false, 0, 2), (vec![], 0));
+
+        // notes are counted once, however many cards match
+        let spec = PageSpec {
+            column: "noteFld".into(),
+            reverse: true,
+            offset: 1,
+            limit: 1,
+        };
+        let page = search_notes_page(&storage, "", &ctx, &spec)?;
+        assert_eq!((page.ids, page.total), (vec![note_ids[0]], 3));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,638 @@
+// Copyright: Ankitects Pty Ltd and contributors
+// License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+//! Parsing of the search language. Terms separated by spaces must all
+//! match, unless "or" is placed between them. A term can be negated with a
+//! leading -, terms can be grouped with parentheses, and quotes allow
+//! spaces and other special characters in a term, eg "deck:my deck".
+//! Positions in errors are counted in characters, not bytes.
+
+use crate::err::{AnkiError, Result};
+use lazy_static::lazy_static;
+use regex::{Regex, RegexBuilder};
+use std::iter::Peekable;
+use std::ops::Range;
+use std::vec::IntoIter;
+
+/// Part of a parsed search. Adjacent terms are joined with an explicit
+/// Node::And.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Node {
+    And,
+    Or,
+    Not(Box<Node>),
+    Group(Vec<Node>),
+    /// A term, and where it appeared in the search, for errors found when
+    /// it's converted to SQL.
+    Search(SearchNode, Range<usize>),
+}
+
+/// A single search term.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SearchNode {
+    /// Text in any field. * matches any sequence of characters.
+    UnqualifiedText(String),
+    /// re:regex, matching part of any field.
+    Regex(String),
+    /// field:text, matching the whole field, or field:re:regex, matching
+    /// part of it.
+    SingleField {
+        field: String,
+        text: String,
+        is_re: bool,
+    },
+    AddedInDays(u32),
+    CardTemplate(TemplateKind),
+    Deck(String),
+    NoteTypeID(i64),
+    NoteType(String),
+    Rated {
+        days: u32,
+        ease: Option<u8>,
+    },
+    Tag(String),
+    /// Notes of a notetype whose first field matches text, which should
+    /// already have had its HTML stripped.
+    Duplicates {
+        note_type_id: i64,
+        text: String,
+    },
+    State(StateKind),
+    Flag(u8),
+    /// A comma-separated list of ids.
+    NoteIDs(String),
+    CardIDs(String),
+    Property {
+        operator: String,
+        kind: PropertyKind,
+    },
+    WholeCollection,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TemplateKind {
+    /// 0-based.
+    Ordinal(u16),
+    Name(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StateKind {
+    New,
+    Review,
+    Learning,
+    Due,
+    Buried,
+    Suspended,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PropertyKind {
+    /// Days from today.
+    Due(i32),
+    Interval(u32),
+    Reps(u32),
+    Lapses(u32),
+    Ease(f32),
+}
+
+#[derive(Debug, PartialEq)]
+enum TokenKind {
+    Open,
+    Close,
+    Negate,
+    Or,
+    Text(String),
+}
+
+#[derive(Debug, PartialEq)]
+struct Token {
+    kind: TokenKind,
+    start: usize,
+    end: usize,
+}
+
+fn error<T, S: Into<String>>(info: S, start: usize, end: usize) -> Result<T> {
+    Err(AnkiError::SearchError {
+        info: info.into(),
+        start,
+        end,
+    })
+}
+
+/// The most memory a compiled re: search may use, so a pathological pattern
+/// fails to compile instead of exhausting memory.
+const REGEX_SIZE_LIMIT: usize = 1 << 20;
+
+/// Compile the pattern of a re: search. Matching ignores case, like the rest
+/// of the search language.
+pub(crate) fn build_regex(pattern: &str) -> std::result::Result<Regex, regex::Error> {
+    RegexBuilder::new(pattern)
+        .case_insensitive(true)
+        .size_limit(REGEX_SIZE_LIMIT)
+        .dfa_size_limit(REGEX_SIZE_LIMIT)
+        .build()
+}
+
+/// Parse a search into a list of nodes. An empty search matches the whole
+/// collection.
+pub fn parse(search: &str) -> Result<Vec<Node>> {
+    let mut tokens = tokenize(search)?.into_iter().peekable();
+    parse_nodes(&mut tokens, None)
+}
+
+// Tokenizing
+//----------------------------------------
+
+/// The text token currently being read.
+struct PendingText {
+    text: String,
+    start: usize,
+    /// Quoted text can't be an operator like "or".
+    quoted: bool,
+}
+
+impl PendingText {
+    fn into_token(self, end: usize) -> Option<Token> {
+        let kind = if !self.quoted && self.text.eq_ignore_ascii_case("or") {
+            TokenKind::Or
+        } else if self.text.is_empty() {
+            // eg ""
+            return None;
+        } else {
+            TokenKind::Text(self.text)
+        };
+        Some(Token {
+            kind,
+            start: self.start,
+            end,
+        })
+    }
+}
+
+fn tokenize(search: &str) -> Result<Vec<Token>> {
+    let mut tokens = vec![];
+    let mut pending: Option<PendingText> = None;
+    // the quote character and where it started
+    let mut quote: Option<(char, usize)> = None;
+    let mut len = 0;
+
+    for (idx, c) in search.chars().enumerate() {
+        len = idx + 1;
+        if let Some((quote_char, _)) = quote {
+            if c == quote_char {
+                quote = None;
+            } else if let Some(pending) = &mut pending {
+                pending.text.push(c);
+            }
+            continue;
+        }
+
+        match c {
+            '"' | '\'' => match &mut pending {
+                None => {
+                    pending = Some(PendingText {
+                        text: String::new(),
+                        start: idx,
+                        quoted: true,
+                    });
+                    quote = Some((c, idx));
+                }
+                // quotes may start after a colon, eg deck:"my deck"
+                Some(text) if text.text.ends_with(':') => {
+                    text.quoted = true;
+                    quote = Some((c, idx));
+                }
+                Some(text) => text.text.push(c),
+            },
+            ' ' | '\u{3000}' | '(' | ')' => {
+                if let Some(token) = pending.take().and_then(|p| p.into_token(idx)) {
+                    tokens.push(token);
+                }
+                let kind = match c {
+                    '(' => TokenKind::Open,
+                    ')' => TokenKind::Close,
+                    _ => continue,
+                };
+                tokens.push(Token {
+                    kind,
+                    start: idx,
+                    end: idx + 1,
+                });
+            }
+            '-' if pending.is_none() => {
+                // repeated negation has no further effect
+                if tokens.last().map(|t| &t.kind) != Some(&TokenKind::Negate) {
+                    tokens.push(Token {
+                        kind: TokenKind::Negate,
+                        start: idx,
+                        end: idx + 1,
+                    });
+                }
+            }
+            _ => match &mut pending {
+                Some(pending) => pending.text.push(c),
+                None => {
+                    pending = Some(PendingText {
+                        text: c.to_string(),
+                        start: idx,
+                        quoted: false,
+                    })
+                }
+            },
+        }
+    }
+
+    if let Some((_, start)) = quote {
+        return error("unterminated quote", start, len);
+    }
+    if let Some(token) = pending.and_then(|p| p.into_token(len)) {
+        tokens.push(token);
+    }
+
+    Ok(tokens)
+}
+
+// Grouping
+//----------------------------------------
+
+/// Parse nodes until the end of the search, or the parenthesis closing
+/// `open`.
+fn parse_nodes(tokens: &mut Peekable<IntoIter<Token>>, open: Option<&Token>) -> Result<Vec<Node>> {
+    let mut nodes = vec![];
+    // the span of a trailing "or", which needs a term after it
+    let mut pending_or: Option<(usize, usize)> = None;
+
+    while let Some(token) = tokens.next() {
+        match token.kind {
+            TokenKind::Close if open.is_none() => {
+                return error("unexpected )", token.start, token.end)
+            }
+            TokenKind::Close => {
+                if let Some((start, end)) = pending_or {
+                    return error("or must be followed by a term", start, end);
+                }
+                if nodes.is_empty() {
+                    let open = open.unwrap();
+                    return error("empty group", open.start, token.end);
+                }
+                return Ok(nodes);
+            }
+            TokenKind::Or => {
+                match nodes.last() {
+                    None | Some(Node::Or) => {
+                        return error("or must be placed between terms", token.start, token.end)
+                    }
+                    _ => (),
+                }
+                nodes.push(Node::Or);
+                pending_or = Some((token.start, token.end));
+            }
+            _ => {
+                let node = parse_term(token, tokens)?;
+                if pending_or.take().is_none() && !nodes.is_empty() {
+                    nodes.push(Node::And);
+                }
+                nodes.push(node);
+            }
+        }
+    }
+
+    if let Some(open) = open {
+        error("missing )", open.start, open.end)
+    } else if let Some((start, end)) = pending_or {
+        error("or must be followed by a term", start, end)
+    } else {
+        Ok(nodes)
+    }
+}
+
+/// A search term, group or negation, starting with `token`.
+fn parse_term(token: Token, tokens: &mut Peekable<IntoIter<Token>>) -> Result<Node> {
+    match token.kind {
+        TokenKind::Open => Ok(Node::Group(parse_nodes(tokens, Some(&token))?)),
+        TokenKind::Negate => match tokens.peek().map(|t| &t.kind) {
+            Some(TokenKind::Open) | Some(TokenKind::Text(_)) => {
+                let next = tokens.next().unwrap();
+                Ok(Node::Not(Box::new(parse_term(next, tokens)?)))
+            }
+            _ => error("nothing to negate", token.start, token.end),
+        },
+        TokenKind::Text(text) => Ok(Node::Search(
+            parse_search_node(&text, token.start, token.end)?,
+            token.start..token.end,
+        )),
+        TokenKind::Close | TokenKind::Or => unreachable!(),
+    }
+}
+
+// Terms
+//----------------------------------------
+
+/// Parse a single term, eg deck:foo. The span is used for errors.
+fn parse_search_node(text: &str, start: usize, end: usize) -> Result<SearchNode> {
+    let (key, val) = match text.find(':') {
+        Some(idx) => (&text[..idx], &text[idx + 1..]),
+        None => return Ok(SearchNode::UnqualifiedText(text.into())),
+    };
+    let invalid = |info: &str| error(info, start, end);
+    let check_regex = |pattern: &str| match build_regex(pattern) {
+        Ok(_) => Ok(()),
+        Err(regex::Error::CompiledTooBig(_)) => error("re: pattern is too large", start, end),
+        Err(_) => error("re: requires a valid regular expression", start, end),
+    };
+
+    Ok(match key.to_ascii_lowercase().as_str() {
+        "added" => match val.parse() {
+            Ok(days) => SearchNode::AddedInDays(days),
+            Err(_) => return invalid("added: requires a number of days"),
+        },
+        "card" => match val.parse::<u16>() {
+            Ok(0) => return invalid("card: numbers start at 1"),
+            Ok(ord) => SearchNode::CardTemplate(TemplateKind::Ordinal(ord - 1)),
+            Err(_) => SearchNode::CardTemplate(TemplateKind::Name(val.into())),
+        },
+        "deck" if val == "*" => SearchNode::WholeCollection,
+        "deck" => SearchNode::Deck(val.into()),
+        "mid" => match val.parse() {
+            Ok(id) => SearchNode::NoteTypeID(id),
+            Err(_) => return invalid("mid: requires a notetype id"),
+        },
+        "nid" if is_id_list(val) => SearchNode::NoteIDs(val.into()),
+        "nid" => return invalid("nid: requires a list of note ids"),
+        "cid" if is_id_list(val) => SearchNode::CardIDs(val.into()),
+        "cid" => return invalid("cid: requires a list of card ids"),
+        "note" => SearchNode::NoteType(val.into()),
+        "prop" => match parse_property(val) {
+            Some((operator, kind)) => SearchNode::Property { operator, kind },
+            None => return invalid("prop: requires eg ivl>10, due=1 or ease!=2.5"),
+        },
+        "rated" => match parse_rated(val) {
+            Some((days, ease)) => SearchNode::Rated { days, ease },
+            None => return invalid("rated: requires days, and optionally an ease of 1-4"),
+        },
+        "re" => {
+            check_regex(val)?;
+            SearchNode::Regex(val.into())
+        }
+        "tag" => SearchNode::Tag(val.into()),
+        "dupe" => match parse_dupes(val) {
+            Some((note_type_id, text)) => SearchNode::Duplicates { note_type_id, text },
+            None => return invalid("dupe: requires a notetype id and text"),
+        },
+        "flag" => match val.parse() {
+            Ok(flag) if flag <= 4 && val.len() == 1 => SearchNode::Flag(flag),
+            _ => return invalid("flag: requires a number from 0 to 4"),
+        },
+        "is" => match val.to_ascii_lowercase().as_str() {
+            "new" => SearchNode::State(StateKind::New),
+            "review" => SearchNode::State(StateKind::Review),
+            "learn" => SearchNode::State(StateKind::Learning),
+            "due" => SearchNode::State(StateKind::Due),
+            "buried" => SearchNode::State(StateKind::Buried),
+            "suspended" => SearchNode::State(StateKind::Suspended),
+            _ => return invalid("is: requires new, review, learn, due, buried or suspended"),
+        },
+        _ if val.starts_with("re:") => {
+            let pattern = &val[3..];
+            check_regex(pattern)?;
+            SearchNode::SingleField {
+                field: key.into(),
+                text: pattern.into(),
+                is_re: true,
+            }
+        }
+        _ => SearchNode::SingleField {
+            field: key.into(),
+            text: val.into(),
+            is_re: false,
+        },
+    })
+}
+
+fn is_id_list(text: &str) -> bool {
+    text.split(',')
+        .all(|id| !id.is_empty() && id.bytes().all(|b| b.is_ascii_digit()))
+}
+
+fn parse_property(text: &str) -> Option<(String, PropertyKind)> {
+    lazy_static! {
+        static ref PROPERTY: Regex = Regex::new("^(.+?)(<=|>=|!=|=|<|>)(.+)$").unwrap();
+    }
+    let caps = PROPERTY.captures(text)?;
+    let val = &caps[3];
+    let kind = match caps[1].to_ascii_lowercase().as_str() {
+        "due" => PropertyKind::Due(val.parse().ok()?),
+        "ivl" => PropertyKind::Interval(val.parse().ok()?),
+        "reps" => PropertyKind::Reps(val.parse().ok()?),
+        "lapses" => PropertyKind::Lapses(val.parse().ok()?),
+        "ease" => PropertyKind::Ease(val.parse().ok()?),
+        _ => return None,
+    };
+
+    Some((caps[2].into(), kind))
+}
+
+fn parse_rated(text: &str) -> Option<(u32, Option<u8>)> {
+    let mut parts = text.splitn(2, ':');
+    let days = parts.next()?.parse().ok()?;
+    let ease = match parts.next() {
+        Some(ease) => match ease.parse() {
+            Ok(ease) if (1..=4).contains(&ease) => Some(ease),
+            _ => return None,
+        },
+        None => None,
+    };
+
+    Some((days, ease))
+}
+
+fn parse_dupes(text: &str) -> Option<(i64, String)> {
+    let mut parts = text.splitn(2, ',');
+    let note_type_id = parts.next()?.parse().ok()?;
+    let text = parts.next()?;
+
+    Some((note_type_id, text.into()))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::err::{AnkiError, Result};
+    use crate::search::parser::{parse, Node, PropertyKind, SearchNode, StateKind, TemplateKind};
+    use std::ops::Range;
+
+    fn text(text: &str, span: Range<usize>) -> Node {
+        Node::Search(SearchNode::UnqualifiedText(text.into()), span)
+    }
+
+    fn error_span(search: &str) -> (usize, usize) {
+        match parse(search) {
+            Err(AnkiError::SearchError { start, end, .. }) => (start, end),
+            other => panic!("unexpected: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_parsing() -> Result<()> {
+        use Node::*;
+        use SearchNode::*;
+
+        assert_eq!(parse("")?, vec![]);
+        assert_eq!(parse("  ")?, vec![]);
+        assert_eq!(parse("foo")?, vec![text("foo", 0..3)]);
+        assert_eq!(
+            parse("foo bar or -baz")?,
+            vec![
+                text("foo", 0..3),
+                And,
+                text("bar", 4..7),
+                Or,
+                Not(Box::new(text("baz", 12..15)))
+            ]
+        );
+
+        // grouping, and quoted phrases
+        assert_eq!(
+            parse(r#"-(a "b c") 'or'"#)?,
+            vec![
+                Not(Box::new(Group(vec![
+                    text("a", 2..3),
+                    And,
+                    text("b c", 4..9)
+                ]))),
+                And,
+                text("or", 11..15)
+            ]
+        );
+        assert_eq!(
+            parse(r#"deck:"my deck" front:'a (b)' it's"#)?,
+            vec![
+                Search(Deck("my deck".into()), 0..14),
+                And,
+                Search(
+                    SingleField {
+                        field: "front".into(),
+                        text: "a (b)".into(),
+                        is_re: false
+                    },
+                    15..28
+                ),
+                And,
+                text("it's", 29..33)
+            ]
+        );
+        // dashes inside a term or quotes are kept, and repeated negation
+        // is ignored
+        assert_eq!(
+            parse(r#"a-b "-c" --d"#)?,
+            vec![
+                text("a-b", 0..3),
+                And,
+                text("-c", 4..8),
+                And,
+                Not(Box::new(text("d", 11..12)))
+            ]
+        );
+
+        // searches
+        assert_eq!(parse("deck:*")?, vec![Search(WholeCollection, 0..6)]);
+        assert_eq!(
+            parse("card:2 card:Reverse")?,
+            vec![
+                Search(CardTemplate(TemplateKind::Ordinal(1)), 0..6),
+                And,
+                Search(CardTemplate(TemplateKind::Name("Reverse".into())), 7..19)
+            ]
+        );
+        assert_eq!(
+            parse("IS:Due flag:3 nid:1,2 tag:foo*")?,
+            vec![
+                Search(State(StateKind::Due), 0..6),
+                And,
+                Search(Flag(3), 7..13),
+                And,
+                Search(NoteIDs("1,2".into()), 14..21),
+                And,
+                Search(Tag("foo*".into()), 22..30)
+            ]
+        );
+        assert_eq!(
+            parse("prop:ease>=2.5 prop:due=-1 rated:7:1 added:3")?,
+            vec![
+                Search(
+                    Property {
+                        operator: ">=".into(),
+                        kind: PropertyKind::Ease(2.5)
+                    },
+                    0..14
+                ),
+                And,
+                Search(
+                    Property {
+                        operator: "=".into(),
+                        kind: PropertyKind::Due(-1)
+                    },
+                    15..26
+                ),
+                And,
+                Search(
+                    Rated {
+                        days: 7,
+                        ease: Some(1)
+                    },
+                    27..36
+                ),
+                And,
+                Search(AddedInDays(3), 37..44)
+            ]
+        );
+        assert_eq!(
+            parse(r"re:a\d Front:re:^\d{4}$")?,
+            vec![
+                Search(Regex(r"a\d".into()), 0..6),
+                And,
+                Search(
+                    SingleField {
+                        field: "Front".into(),
+                        text: r"^\d{4}$".into(),
+                        is_re: true
+                    },
+                    7..23
+                )
+            ]
+        );
+        assert_eq!(
+            parse("dupe:5,a,b")?,
+            vec![Search(
+                Duplicates {
+                    note_type_id: 5,
+                    text: "a,b".into()
+                },
+                0..10
+            )]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_errors() {
+        // positions are in characters
+        assert_eq!(error_span("ä flag:7"), (2, 8));
+        assert_eq!(error_span("a (b"), (2, 3));
+        assert_eq!(error_span("a b)"), (3, 4));
+        assert_eq!(error_span("a ()"), (2, 4));
+        assert_eq!(error_span("or a"), (0, 2));
+        assert_eq!(error_span("a or or b"), (5, 7));
+        assert_eq!(error_span("(a or) b"), (3, 5));
+        assert_eq!(error_span("a -"), (2, 3));
+        assert_eq!(error_span(r#"a "b"#), (2, 4));
+        assert_eq!(error_span("is:foo"), (0, 6));
+        assert_eq!(error_span("prop:ivl>x"), (0, 10));
+        assert_eq!(error_span("rated:1:5"), (0, 9));
+        assert_eq!(error_span("nid:1,,2"), (0, 8));
+        assert_eq!(error_span("flag:01"), (0, 7));
+        assert_eq!(error_span("a re:["), (2, 6));
+        assert_eq!(error_span("front:re:a{1000}{1000}"), (0, 22));
+    }
+}
@@ -0,0 +1,106 @@
+// Copyright: Ankitects Pty Ltd and contributors
+// License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+//! Named searches shown in the browser's sidebar. They're kept in the
+//! collection config, so they sync with the collection.
+
+use crate::err::{AnkiError, Result};
+use crate::storage::{now_millis, SqliteStorage};
+use std::collections::BTreeMap;
+
+/// The config key, shared with the legacy code.
+const SAVED_SEARCHES_KEY: &str = "savedFilters";
+
+/// The saved searches, keyed and sorted by name.
+pub fn saved_searches(storage: &SqliteStorage) -> Result<BTreeMap<String, String>> {
+    Ok(storage
+        .get_config_value(SAVED_SEARCHES_KEY)?
+        .unwrap_or_default())
+}
+
+fn set_saved_searches(storage: &SqliteStorage, searches: &BTreeMap<String, String>) -> Result<()> {
+    storage.set_config_value(SAVED_SEARCHES_KEY, searches)?;
+    storage.mark_modified(now_millis())
+}
+
+fn normalized_name(name: &str) -> Result<&str> {
+    let name = name.trim();
+    if name.is_empty() {
+        Err(AnkiError::invalid_input("saved searches need a name"))
+    } else {
+        Ok(name)
+    }
+}
+
+/// Save `search` as `name`, replacing any search with the same name.
+pub fn save_search(storage: &SqliteStorage, name: &str, search: &str) -> Result<()> {
+    let name = normalized_name(name)?;
+    let mut searches = saved_searches(storage)?;
+    searches.insert(name.into(), search.into());
+    set_saved_searches(storage, &searches)
+}
+
+/// Rename a saved search. Fails if it doesn't exist, or if another search
+/// already has the new name.
+pub fn rename_saved_search(storage: &SqliteStorage, old_name: &str, new_name: &str) -> Result<()> {
+    let new_name = normalized_name(new_name)?;
+    let mut searches = saved_searches(storage)?;
+    if new_name != old_name && searches.contains_key(new_name) {
+        return Err(AnkiError::invalid_input(format!(
+            "a saved search named {} already exists",
+            new_name
+        )));
+    }
+    let search = searches
+        .remove(old_name)
+        .ok_or_else(|| AnkiError::invalid_input(format!("no saved search named {}", old_name)))?;
+    searches.insert(new_name.into(), search);
+    set_saved_searches(storage, &searches)
+}
+
+/// Remove a saved search, if it exists.
+pub fn remove_saved_search(storage: &SqliteStorage, name: &str) -> Result<()> {
+    let mut searches = saved_searches(storage)?;
+    if searches.remove(name).is_some() {
+        set_saved_searches(storage, &searches)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::err::Result;
+    use crate::search::{remove_saved_search, rename_saved_search, save_search, saved_searches};
+    use crate::storage::testutil::open_test_collection;
+
+    #[test]
+    fn test_saved_searches() -> Result<()> {
+        let (_dir, storage) = open_test_collection()?;
+        assert!(saved_searches(&storage)?.is_empty());
+
+        save_search(&storage, " due ", "is:due")?;
+        save_search(&storage, "a", "deck:a")?;
+        save_search(&storage, "a", "deck:b")?;
+        assert!(save_search(&storage, " ", "x").is_err());
+        let names: Vec<_> = saved_searches(&storage)?.into_iter().collect();
+        assert_eq!(
+            names,
+            vec![
+                ("a".to_string(), "deck:b".to_string()),
+                ("due".to_string(), "is:due".to_string())
+            ]
+        );
+
+        // renaming can't clobber another search
+        assert!(rename_saved_search(&storage, "a", "due").is_err());
+        assert!(rename_saved_search(&storage, "missing", "b").is_err());
+        rename_saved_search(&storage, "a", "b")?;
+        assert_eq!(saved_searches(&storage)?["b"], "deck:b");
+
+        remove_saved_search(&storage, "b")?;
+        remove_saved_search(&storage, "missing")?;
+        assert_eq!(saved_searches(&storage)?.len(), 1);
+
+        Ok(())
+    }
+}
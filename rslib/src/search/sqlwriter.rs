@@ -0,0 +1,574 @@
+// Copyright: Ankitects Pty Ltd and contributors
+// License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+//! Conversion of a parsed search into an SQL where clause, for a query on
+//! `cards c, notes n`. Terms that refer to things that don't exist, like an
+//! unknown deck, are an error, unless they are negated, in which case they
+//! have no effect.
+
+use crate::err::{AnkiError, Result};
+use crate::notes::field_checksum;
+use crate::notetypes::NoteTypeKind;
+use crate::sched::ids_to_string;
+use crate::search::parser::{build_regex, Node, PropertyKind, SearchNode, StateKind, TemplateKind};
+use crate::search::SearchContext;
+use crate::storage::SqliteStorage;
+use crate::text::{normalize_for_search, normalize_to_nfc, sort_field_text};
+use regex::Regex;
+use rusqlite::params;
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write;
+use std::ops::Range;
+use std::time::{Duration, Instant};
+
+/// How long scanning the notes for a re: term may take, so a slow pattern
+/// fails instead of hanging the browser.
+const SCAN_TIME_LIMIT: Duration = Duration::from_secs(5);
+
+/// Compile a re: pattern, which the parser has already checked.
+fn compile_search_regex(pattern: &str) -> Result<Regex> {
+    build_regex(pattern).map_err(|err| AnkiError::invalid_input(err.to_string()))
+}
+
+struct SqlWriter<'a> {
+    storage: &'a SqliteStorage,
+    ctx: &'a SearchContext,
+    sql: String,
+    args: Vec<String>,
+    /// True while writing a term negated with -.
+    negated: bool,
+    /// Where the term being written appeared in the search.
+    term_span: Range<usize>,
+    /// Whether plain words can be looked up in the full-text index.
+    fulltext: bool,
+}
+
+/// Build a where clause for `nodes`, returning it and its arguments.
+pub(crate) fn node_to_sql(
+    storage: &SqliteStorage,
+    ctx: &SearchContext,
+    nodes: &[Node],
+) -> Result<(String, Vec<String>)> {
+    let mut writer = SqlWriter {
+        storage,
+        ctx,
+        sql: String::new(),
+        args: vec![],
+        negated: false,
+        term_span: 0..0,
+        fulltext: storage.fulltext_index_enabled()?,
+    };
+    if nodes.is_empty() {
+        writer.sql.push_str("true");
+    } else {
+        writer.write_nodes(nodes)?;
+    }
+
+    Ok((writer.sql, writer.args))
+}
+
+/// True if `text` is a single word without wildcards, which the full-text
+/// index can find.
+fn is_plain_word(text: &str) -> bool {
+    !text.is_empty() && text.chars().all(char::is_alphanumeric)
+}
+
+/// Names of decks, notetypes and so on are matched ignoring case.
+pub(crate) fn names_match(name: &str, search: &str) -> bool {
+    normalize_for_search(name, false) == normalize_for_search(search, false)
+}
+
+impl SqlWriter<'_> {
+    fn write_nodes(&mut self, nodes: &[Node]) -> Result<()> {
+        for node in nodes {
+            self.write_node(node)?;
+        }
+        Ok(())
+    }
+
+    fn write_node(&mut self, node: &Node) -> Result<()> {
+        match node {
+            Node::And => self.sql.push_str(" and "),
+            Node::Or => self.sql.push_str(" or "),
+            Node::Not(node) => {
+                self.sql.push_str("not ");
+                self.negated = match **node {
+                    Node::Search(..) => true,
+                    _ => false,
+                };
+                self.write_node(node)?;
+                self.negated = false;
+            }
+            Node::Group(nodes) => {
+                self.sql.push('(');
+                self.write_nodes(nodes)?;
+                self.sql.push(')');
+            }
+            Node::Search(search, span) => {
+                self.term_span = span.clone();
+                self.sql.push('(');
+                self.write_search_node(search)?;
+                self.sql.push(')');
+            }
+        }
+        Ok(())
+    }
+
+    /// Add an argument, returning its number for use as ?N.
+    fn push_arg(&mut self, arg: String) -> usize {
+        self.args.push(arg);
+        self.args.len()
+    }
+
+    /// Write a term that matched nothing in the collection.
+    fn write_unmatched(&mut self, info: String) -> Result<()> {
+        if self.negated {
+            self.sql.push_str("false");
+            Ok(())
+        } else {
+            Err(self.term_error(info))
+        }
+    }
+
+    /// An error covering the term being written.
+    fn term_error(&self, info: impl Into<String>) -> AnkiError {
+        AnkiError::SearchError {
+            info: info.into(),
+            start: self.term_span.start,
+            end: self.term_span.end,
+        }
+    }
+
+    fn write_search_node(&mut self, node: &SearchNode) -> Result<()> {
+        match node {
+            SearchNode::UnqualifiedText(text) => self.write_unqualified(text),
+            SearchNode::Regex(pattern) => self.write_regex(pattern)?,
+            SearchNode::SingleField { field, text, is_re } => {
+                self.write_single_field(field, text, *is_re)?
+            }
+            SearchNode::AddedInDays(days) => {
+                let cutoff = (self.ctx.day_cutoff - 86_400 * i64::from(*days)) * 1000;
+                write!(self.sql, "c.id > {}", cutoff).unwrap();
+            }
+            SearchNode::CardTemplate(template) => self.write_template(template)?,
+            SearchNode::Deck(deck) => self.write_deck(deck)?,
+            SearchNode::NoteTypeID(id) => write!(self.sql, "n.mid = {}", id).unwrap(),
+            SearchNode::NoteType(name) => self.write_note_type(name)?,
+            SearchNode::Rated { days, ease } => self.write_rated(*days, *ease),
+            SearchNode::Tag(tag) => self.write_tag(tag),
+            SearchNode::Duplicates { note_type_id, text } => {
+                self.write_dupes(*note_type_id, text)?
+            }
+            SearchNode::State(state) => self.write_state(*state),
+            SearchNode::Flag(flag) => write!(self.sql, "(c.flags & 7) == {}", flag).unwrap(),
+            SearchNode::NoteIDs(ids) => write!(self.sql, "n.id in ({})", ids).unwrap(),
+            SearchNode::CardIDs(ids) => write!(self.sql, "c.id in ({})", ids).unwrap(),
+            SearchNode::Property { operator, kind } => self.write_property(operator, *kind),
+            SearchNode::WholeCollection => self.sql.push_str("true"),
+        }
+        Ok(())
+    }
+
+    fn write_unqualified(&mut self, text: &str) {
+        let arg = self.push_arg(format!("%{}%", text.replace('*', "%")));
+        let like = format!(
+            "n.sfld like ?{n} escape '\\' or n.flds like ?{n} escape '\\'",
+            n = arg
+        );
+        if self.fulltext && is_plain_word(text) {
+            // the index finds the notes that may contain the text, so that
+            // only those need to be checked with like
+            let arg = self.push_arg(format!("\"{}\"*", text));
+            write!(
+                self.sql,
+                "n.id in (select rowid from notes_fts where notes_fts match ?{}) and ({})",
+                arg, like
+            )
+            .unwrap();
+        } else {
+            self.sql.push_str(&like);
+        }
+    }
+
+    /// Without re:, the field must match as a whole, so the candidates are
+    /// checked with a regex after a rough filter in SQL.
+    fn write_single_field(&mut self, field: &str, text: &str, is_re: bool) -> Result<()> {
+        let mut field_ords = HashMap::new();
+        for note_type in self.storage.get_all_notetypes()?.values() {
+            if let Some(fld) = note_type
+                .fields
+                .iter()
+                .find(|f| names_match(&f.name, field))
+            {
+                field_ords.insert(note_type.id, fld.ord as usize);
+            }
+        }
+        if field_ords.is_empty() {
+            return self.write_unmatched(format!("no notetype has a field named {}", field));
+        }
+
+        let note_type_ids: Vec<_> = field_ords.keys().cloned().collect();
+        let mut sql = format!(
+            "select id, mid, flds from notes where mid in {}",
+            ids_to_string(&note_type_ids)
+        );
+        let note_ids = if is_re {
+            let regex = compile_search_regex(text)?;
+            self.matching_notes(
+                &sql,
+                &[],
+                Some(&field_ords),
+                Some(SCAN_TIME_LIMIT),
+                |field| regex.is_match(field),
+            )?
+        } else {
+            let like = text.replace('*', "%");
+            let regex = Regex::new(&format!(
+                "(?si)^{}$",
+                regex::escape(&like).replace('_', ".").replace('%', ".*")
+            ))
+            .map_err(|err| AnkiError::invalid_input(err.to_string()))?;
+            sql.push_str(" and flds like ? escape '\\'");
+            self.matching_notes(
+                &sql,
+                &[format!("%{}%", like)],
+                Some(&field_ords),
+                None,
+                |field| regex.is_match(field),
+            )?
+        };
+
+        write!(self.sql, "n.id in {}", ids_to_string(&note_ids)).unwrap();
+        Ok(())
+    }
+
+    fn write_regex(&mut self, pattern: &str) -> Result<()> {
+        let regex = compile_search_regex(pattern)?;
+        let note_ids = self.matching_notes(
+            "select id, mid, flds from notes",
+            &[],
+            None,
+            Some(SCAN_TIME_LIMIT),
+            |field| regex.is_match(field),
+        )?;
+        write!(self.sql, "n.id in {}", ids_to_string(&note_ids)).unwrap();
+        Ok(())
+    }
+
+    /// The ids of notes returned by `sql`, which should select id, mid and
+    /// flds, where `matches` accepts the field given by `field_ords` for
+    /// the note's notetype, or any field if it's None. Fails if this takes
+    /// longer than `time_limit`.
+    fn matching_notes<F>(
+        &self,
+        sql: &str,
+        args: &[String],
+        field_ords: Option<&HashMap<i64, usize>>,
+        time_limit: Option<Duration>,
+        matches: F,
+    ) -> Result<Vec<i64>>
+    where
+        F: Fn(&str) -> bool,
+    {
+        let deadline = time_limit.map(|limit| Instant::now() + limit);
+        let mut stmt = self.storage.db.prepare(sql)?;
+        let mut rows = stmt.query(args)?;
+        let mut note_ids = vec![];
+        while let Some(row) = rows.next()? {
+            if deadline.map(|d| Instant::now() > d).unwrap_or_default() {
+                return Err(self.term_error("the search took too long"));
+            }
+            let fields: String = row.get(2)?;
+            let mut fields = fields.split('\x1f');
+            let matched = match field_ords {
+                Some(ords) => {
+                    let ord = ords[&row.get::<_, i64>(1)?];
+                    fields.nth(ord).map(&matches).unwrap_or(false)
+                }
+                None => fields.any(&matches),
+            };
+            if matched {
+                note_ids.push(row.get(0)?);
+            }
+        }
+
+        Ok(note_ids)
+    }
+
+    fn write_template(&mut self, template: &TemplateKind) -> Result<()> {
+        match template {
+            TemplateKind::Ordinal(ord) => write!(self.sql, "c.ord = {}", ord).unwrap(),
+            TemplateKind::Name(name) => {
+                let mut clauses = vec![];
+                for note_type in self.storage.get_all_notetypes()?.values() {
+                    for template in &note_type.templates {
+                        if !names_match(&template.name, name) {
+                            continue;
+                        }
+                        if note_type.kind() == NoteTypeKind::Cloze {
+                            // cloze cards of every ordinal use the one template
+                            clauses.push(format!("n.mid = {}", note_type.id));
+                        } else {
+                            clauses.push(format!(
+                                "(n.mid = {} and c.ord = {})",
+                                note_type.id, template.ord
+                            ));
+                        }
+                    }
+                }
+                if clauses.is_empty() {
+                    self.write_unmatched(format!("no card type named {}", name))?;
+                } else {
+                    self.sql.push_str(&clauses.join(" or "));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Cards in the deck or its children, including cards that have been
+    /// moved into a filtered deck.
+    fn write_deck(&mut self, deck: &str) -> Result<()> {
+        if deck == "filtered" {
+            self.sql.push_str("c.odid != 0");
+            return Ok(());
+        }
+
+        let decks = self.storage.get_all_decks()?;
+        let parents: Vec<&str> = if deck.eq_ignore_ascii_case("current") {
+            decks
+                .get(&self.ctx.current_deck_id)
+                .map(|deck| deck.name.as_str())
+                .into_iter()
+                .collect()
+        } else if deck.contains('*') {
+            let regex = Regex::new(&format!(
+                "(?i)^{}$",
+                regex::escape(&normalize_to_nfc(deck)).replace(r"\*", ".*")
+            ))
+            .map_err(|err| AnkiError::invalid_input(err.to_string()))?;
+            decks
+                .values()
+                .filter(|d| regex.is_match(&normalize_to_nfc(&d.name)))
+                .map(|d| d.name.as_str())
+                .collect()
+        } else {
+            decks
+                .values()
+                .filter(|d| names_match(&d.name, deck))
+                .map(|d| d.name.as_str())
+                .collect()
+        };
+
+        let parents: HashSet<_> = parents
+            .into_iter()
+            .map(|name| normalize_for_search(name, false))
+            .collect();
+        let mut ids: Vec<_> = decks
+            .values()
+            .filter(|d| {
+                let name = normalize_for_search(&d.name, false);
+                parents.contains(&name)
+                    || parents
+                        .iter()
+                        .any(|parent| name.starts_with(&format!("{}::", parent)))
+            })
+            .map(|d| d.id)
+            .collect();
+        ids.sort_unstable();
+
+        if ids.is_empty() {
+            self.write_unmatched(format!("no deck named {}", deck))?;
+        } else {
+            let ids = ids_to_string(&ids);
+            write!(self.sql, "c.did in {} or c.odid in {}", ids, ids).unwrap();
+        }
+        Ok(())
+    }
+
+    fn write_note_type(&mut self, name: &str) -> Result<()> {
+        let mut ids: Vec<_> = self
+            .storage
+            .get_all_notetypes()?
+            .values()
+            .filter(|nt| names_match(&nt.name, name))
+            .map(|nt| nt.id)
+            .collect();
+        ids.sort_unstable();
+
+        if ids.is_empty() {
+            self.write_unmatched(format!("no notetype named {}", name))
+        } else {
+            write!(self.sql, "n.mid in {}", ids_to_string(&ids)).unwrap();
+            Ok(())
+        }
+    }
+
+    fn write_rated(&mut self, days: u32, ease: Option<u8>) {
+        let days = i64::from(days.min(31));
+        let cutoff = (self.ctx.day_cutoff - 86_400 * days) * 1000;
+        write!(
+            self.sql,
+            "c.id in (select cid from revlog where id > {}",
+            cutoff
+        )
+        .unwrap();
+        if let Some(ease) = ease {
+            write!(self.sql, " and ease = {}", ease).unwrap();
+        }
+        self.sql.push(')');
+    }
+
+    fn write_tag(&mut self, tag: &str) {
+        if tag == "none" {
+            self.sql.push_str("n.tags = ''");
+            return;
+        }
+        if tag.ends_with("::*") {
+            // the parent itself, as well as its descendants
+            let parent = tag[..tag.len() - 3].replace('*', "%");
+            let own = self.push_arg(format!("% {} %", parent));
+            let descendants = self.push_arg(format!("% {}::%", parent));
+            write!(
+                self.sql,
+                "n.tags like ?{} escape '\\' or n.tags like ?{} escape '\\'",
+                own, descendants
+            )
+            .unwrap();
+            return;
+        }
+        // tags are stored with a space on either side
+        let mut pattern = tag.replace('*', "%");
+        if !pattern.starts_with('%') {
+            pattern.insert_str(0, "% ");
+        }
+        if !pattern.ends_with('%') || pattern.ends_with("\\%") {
+            pattern.push_str(" %");
+        }
+        let arg = self.push_arg(pattern);
+        write!(self.sql, "n.tags like ?{} escape '\\'", arg).unwrap();
+    }
+
+    fn write_dupes(&mut self, note_type_id: i64, text: &str) -> Result<()> {
+        let mut stmt = self
+            .storage
+            .db
+            .prepare("select id, flds from notes where mid = ? and csum = ?")?;
+        let mut rows = stmt.query(params![note_type_id, i64::from(field_checksum(text))])?;
+        let mut note_ids = vec![];
+        while let Some(row) = rows.next()? {
+            let fields: String = row.get(1)?;
+            let first = fields.split('\x1f').next().unwrap_or_default();
+            if sort_field_text(first) == text {
+                note_ids.push(row.get(0)?);
+            }
+        }
+
+        write!(self.sql, "n.id in {}", ids_to_string(&note_ids)).unwrap();
+        Ok(())
+    }
+
+    fn write_state(&mut self, state: StateKind) {
+        match state {
+            StateKind::New => self.sql.push_str("c.type = 0"),
+            StateKind::Review => self.sql.push_str("c.type = 2"),
+            StateKind::Learning => self.sql.push_str("c.queue in (1, 3)"),
+            StateKind::Buried => self.sql.push_str("c.queue in (-2, -3)"),
+            StateKind::Suspended => self.sql.push_str("c.queue = -1"),
+            StateKind::Due => write!(
+                self.sql,
+                "(c.queue in (2, 3) and c.due <= {}) or (c.queue = 1 and c.due <= {})",
+                self.ctx.today, self.ctx.day_cutoff
+            )
+            .unwrap(),
+        }
+    }
+
+    fn write_property(&mut self, operator: &str, kind: PropertyKind) {
+        match kind {
+            PropertyKind::Due(days) => write!(
+                self.sql,
+                // only reviews and interday learning cards have a due day
+                "c.queue in (2, 3) and c.due {} {}",
+                operator,
+                i64::from(self.ctx.today) + i64::from(days)
+            ),
+            PropertyKind::Interval(ivl) => write!(self.sql, "c.ivl {} {}", operator, ivl),
+            PropertyKind::Reps(reps) => write!(self.sql, "c.reps {} {}", operator, reps),
+            PropertyKind::Lapses(lapses) => write!(self.sql, "c.lapses {} {}", operator, lapses),
+            PropertyKind::Ease(ease) => {
+                write!(self.sql, "c.factor {} {}", operator, (ease * 1000.0) as u32)
+            }
+        }
+        .unwrap();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::err::Result;
+    use crate::search::parser::parse;
+    use crate::search::sqlwriter::node_to_sql;
+    use crate::search::SearchContext;
+    use crate::storage::testutil::open_test_collection;
+
+    #[test]
+    fn test_sql() -> Result<()> {
+        let (_dir, storage) = open_test_collection()?;
+        let ctx = SearchContext {
+            today: 100,
+            day_cutoff: 1_000_000,
+            current_deck_id: 1,
+        };
+        let sql = |search: &str| -> Result<(String, Vec<String>)> {
+            node_to_sql(&storage, &ctx, &parse(search)?)
+        };
+
+        assert_eq!(sql("")?, ("true".into(), vec![]));
+        assert_eq!(
+            sql("a -b* or tag:c")?,
+            (
+                concat!(
+                    r"(n.sfld like ?1 escape '\' or n.flds like ?1 escape '\') and ",
+                    r"not (n.sfld like ?2 escape '\' or n.flds like ?2 escape '\') or ",
+                    r"(n.tags like ?3 escape '\')"
+                )
+                .into(),
+                vec!["%a%".into(), "%b%%".into(), "% c %".into()]
+            )
+        );
+        // a parent tag and its descendants
+        assert_eq!(
+            sql("tag:a::*")?,
+            (
+                r"(n.tags like ?1 escape '\' or n.tags like ?2 escape '\')".into(),
+                vec!["% a %".into(), "% a::%".into()]
+            )
+        );
+        assert_eq!(
+            sql("(is:due prop:due>1) flag:1")?.0,
+            concat!(
+                "(((c.queue in (2, 3) and c.due <= 100) or (c.queue = 1 and c.due <= 1000000)) ",
+                "and (c.queue in (2, 3) and c.due > 101)) and ((c.flags & 7) == 1)"
+            )
+        );
+        assert_eq!(
+            sql("rated:40:3 added:1")?.0,
+            concat!(
+                "(c.id in (select cid from revlog where id > -1678400000 and ease = 3)) ",
+                "and (c.id > 913600000)"
+            )
+        );
+        assert_eq!(sql("dupe:5,a")?.0, "(n.id in ())");
+        // unknown decks, notetypes and fields are an error, unless negated
+        assert!(sql("deck:missing").is_err());
+        assert!(sql("note:missing").is_err());
+        assert!(sql("-(front:x)").is_err());
+        assert_eq!(
+            sql("-deck:missing -note:missing -front:x")?.0,
+            "not (false) and not (false) and not (false)"
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,201 @@
+// Copyright: Ankitects Pty Ltd and contributors
+// License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+use crate::card::{CardQueue, CardType};
+use crate::err::{AnkiError, Result};
+use crate::notetypes::NoteTypeKind;
+use crate::revlog::RevlogEntry;
+use crate::storage::SqliteStorage;
+
+/// What the card info screen shows about a card.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CardStats {
+    pub card_id: i64,
+    pub note_id: i64,
+    /// Card ids are the time they were added, in milliseconds.
+    pub added_secs: i64,
+    pub first_review_secs: Option<i64>,
+    pub latest_review_secs: Option<i64>,
+    /// When the card is next due. Only set for cards being learnt or
+    /// reviewed that aren't suspended, buried or in a filtered deck.
+    pub due_secs: Option<i64>,
+    /// Only set for cards in the review queue.
+    pub interval_days: Option<u32>,
+    /// In permille; only set once the card has been studied.
+    pub ease_factor: Option<u16>,
+    pub reviews: u32,
+    pub lapses: u32,
+    /// The time taken to answer the card, over all its reviews.
+    pub total_secs: f32,
+    /// 0 if the card has never been answered.
+    pub average_secs: f32,
+    /// Only set for new cards.
+    pub new_position: Option<i64>,
+    pub card_type_name: String,
+    pub notetype_name: String,
+    pub deck_name: String,
+    /// Newest first.
+    pub revlog: Vec<RevlogEntry>,
+}
+
+/// The card's history and scheduling. `today` is the scheduler's day
+/// number, which review due dates are relative to.
+pub fn card_stats(
+    storage: &SqliteStorage,
+    card_id: i64,
+    today: u32,
+    now_secs: i64,
+) -> Result<CardStats> {
+    let card = storage
+        .get_card(card_id)?
+        .ok_or_else(|| AnkiError::invalid_input("no such card"))?;
+    let notetype = match storage.get_note(card.note_id)? {
+        Some(note) => storage.get_notetype(note.notetype_id)?,
+        None => None,
+    };
+    let deck_name = storage
+        .get_deck(card.deck_id)?
+        .map(|deck| deck.name)
+        .unwrap_or_default();
+    let mut revlog = storage.get_revlog_entries(card_id)?;
+    revlog.reverse();
+
+    let studied = card.ctype != CardType::New;
+    let due_secs = if !studied || card.original_deck_id != 0 {
+        None
+    } else {
+        match card.queue {
+            CardQueue::Review | CardQueue::DayLearn => {
+                Some(now_secs + (card.due - i64::from(today)) * 86_400)
+            }
+            CardQueue::Learn => Some(card.due),
+            _ => None,
+        }
+    };
+    let total_millis: u64 = revlog.iter().map(|e| u64::from(e.taken_millis)).sum();
+    let total_secs = total_millis as f32 / 1000.0;
+    let average_secs = if revlog.is_empty() {
+        0.0
+    } else {
+        total_secs / revlog.len() as f32
+    };
+    let (notetype_name, card_type_name) = match &notetype {
+        Some(notetype) => {
+            // cloze cards all share the first template
+            let template = match notetype.kind() {
+                NoteTypeKind::Cloze => notetype.templates.first(),
+                NoteTypeKind::Standard => notetype.templates.get(card.ordinal as usize),
+            };
+            (
+                notetype.name.clone(),
+                template.map(|t| t.name.clone()).unwrap_or_default(),
+            )
+        }
+        None => Default::default(),
+    };
+
+    Ok(CardStats {
+        card_id,
+        note_id: card.note_id,
+        added_secs: card_id / 1000,
+        first_review_secs: revlog.last().map(|e| e.id / 1000),
+        latest_review_secs: revlog.first().map(|e| e.id / 1000),
+        due_secs,
+        interval_days: Some(card.interval).filter(|_| card.queue == CardQueue::Review),
+        ease_factor: Some(card.ease_factor).filter(|_| studied),
+        reviews: card.reps,
+        lapses: card.lapses,
+        total_secs,
+        average_secs,
+        new_position: Some(card.due).filter(|_| card.queue == CardQueue::New),
+        card_type_name,
+        notetype_name,
+        deck_name,
+        revlog,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use crate::card::{Card, CardQueue, CardType};
+    use crate::err::Result;
+    use crate::notes::Note;
+    use crate::revlog::RevlogEntry;
+    use crate::stats::card::card_stats;
+    use crate::storage::testutil::{add_deck, notetype, open_test_collection};
+
+    #[test]
+    fn test_card_stats() -> Result<()> {
+        let (_dir, storage) = open_test_collection()?;
+        let notetype = notetype(1, "Basic", &["Front", "Back"], &["{{Front}}", "{{Back}}"]);
+        storage.add_or_update_notetype(&notetype)?;
+        add_deck(&storage, 1, "Default")?;
+        let mut note = Note {
+            notetype_id: 1,
+            fields: vec!["front".into(), "back".into()],
+            ..Default::default()
+        };
+        storage.add_note(&mut note)?;
+        let mut card = Card {
+            id: 1_500_000_000_000,
+            note_id: note.id,
+            deck_id: 1,
+            ordinal: 1,
+            ctype: CardType::Review,
+            queue: CardQueue::Review,
+            due: 105,
+            interval: 10,
+            ease_factor: 2500,
+            reps: 3,
+            lapses: 1,
+            ..Default::default()
+        };
+        storage.add_card(&mut card)?;
+        for (id, taken_millis) in &[(1_500_000_100_000, 5000), (1_500_000_900_000, 2500)] {
+            storage.add_revlog_entry(&mut RevlogEntry {
+                id: *id,
+                card_id: card.id,
+                ease: 3,
+                taken_millis: *taken_millis,
+                ..Default::default()
+            })?;
+        }
+
+        let stats = card_stats(&storage, card.id, 100, 2_000_000_000)?;
+        assert_eq!(stats.added_secs, 1_500_000_000);
+        assert_eq!(stats.first_review_secs, Some(1_500_000_100));
+        assert_eq!(stats.latest_review_secs, Some(1_500_000_900));
+        assert_eq!(stats.due_secs, Some(2_000_000_000 + 5 * 86_400));
+        assert_eq!(
+            (stats.interval_days, stats.ease_factor),
+            (Some(10), Some(2500))
+        );
+        assert_eq!((stats.total_secs, stats.average_secs), (7.5, 3.75));
+        assert_eq!(stats.new_position, None);
+        assert_eq!(
+            (
+                stats.card_type_name.as_str(),
+                stats.notetype_name.as_str(),
+                stats.deck_name.as_str()
+            ),
+            ("Card 2", "Basic", "Default")
+        );
+        // newest first
+        assert_eq!(stats.revlog[0].id, 1_500_000_900_000);
+
+        // suspended cards have no due date, and new cards have a position
+        card.queue = CardQueue::Suspended;
+        storage.update_card(&card)?;
+        assert_eq!(card_stats(&storage, card.id, 100, 0)?.due_secs, None);
+        card.ctype = CardType::New;
+        card.queue = CardQueue::New;
+        card.due = 7;
+        storage.update_card(&card)?;
+        let stats = card_stats(&storage, card.id, 100, 0)?;
+        assert_eq!((stats.new_position, stats.ease_factor), (Some(7), None));
+
+        assert!(card_stats(&storage, 1, 100, 0).is_err());
+
+        Ok(())
+    }
+}
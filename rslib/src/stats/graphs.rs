@@ -0,0 +1,595 @@
+// Copyright: Ankitects Pty Ltd and contributors
+// License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+//! The data behind the statistics screen's graphs. Reviews and added cards
+//! are grouped by the day they happened on, with days starting at the
+//! user's rollover hour, like the scheduler's. Days are numbered relative
+//! to today, so 0 is today and -1 yesterday, and due cards relative to
+//! today too, so 1 is tomorrow. Days are then grouped into buckets of
+//! `bucket_days`, so a bucket is a week or a month.
+
+use crate::err::{AnkiError, Result};
+use crate::sched::ids_to_string;
+use crate::storage::SqliteStorage;
+use rusqlite::{params, Row, NO_PARAMS};
+
+/// Which cards and reviews the graphs include, and how they're grouped.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GraphsSpec {
+    /// The decks whose cards and their reviews are included, or empty for
+    /// the whole collection.
+    pub deck_ids: Vec<i64>,
+    /// The scheduler's day number.
+    pub today: u32,
+    /// When the next day starts, in seconds.
+    pub next_day_at: i64,
+    /// The hour days start at, used to find the local hour of a review.
+    pub rollover_hour: u8,
+    pub bucket_days: u32,
+    /// How many buckets of history and of upcoming reviews to include, or
+    /// None for all of them.
+    pub buckets: Option<u32>,
+    /// The v1 scheduler shows 3 buttons for learning cards, and logged the
+    /// third as 4 in some versions.
+    pub v1_learning_buttons: bool,
+}
+
+/// The number of review cards due in a bucket. Cards with an interval of
+/// 21 days or more are mature.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ForecastPoint {
+    pub bucket: i32,
+    pub young: u32,
+    pub mature: u32,
+}
+
+/// A total for each kind of answer. Young and mature are reviews of cards
+/// whose previous interval was less than 21 days, and at least 21 days;
+/// filtered are reviews ahead of time in a filtered deck.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ReviewKinds<T> {
+    pub learn: T,
+    pub young: T,
+    pub mature: T,
+    pub relearn: T,
+    pub filtered: T,
+}
+
+/// The answers given in a bucket, and the time taken to give them.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReviewsPoint {
+    pub bucket: i32,
+    pub counts: ReviewKinds<u32>,
+    pub secs: ReviewKinds<f32>,
+}
+
+/// The number of cards in a bucket, of days the cards were added on, or
+/// days of interval.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CountPoint {
+    pub bucket: i32,
+    pub count: u32,
+}
+
+/// The intervals of all review cards, regardless of `buckets`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct IntervalSummary {
+    pub cards: u32,
+    pub average_days: f32,
+    pub longest_days: u32,
+}
+
+/// The number of review cards with an ease factor.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EaseFactorCount {
+    /// In permille.
+    pub ease_factor: u32,
+    pub count: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[repr(u8)]
+pub enum ButtonCardKind {
+    /// Learning and relearning cards.
+    Learning = 0,
+    Young = 1,
+    Mature = 2,
+}
+
+/// How often a button was pressed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ButtonCount {
+    pub kind: ButtonCardKind,
+    /// 1-4.
+    pub button: u8,
+    pub count: u32,
+}
+
+/// The answers given in an hour of the day, in local time.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HourPoint {
+    pub hour: u8,
+    pub answers: u32,
+    /// Answers other than "again".
+    pub correct: u32,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Graphs {
+    pub forecast: Vec<ForecastPoint>,
+    pub due_tomorrow: u32,
+    pub reviews: Vec<ReviewsPoint>,
+    pub added: Vec<CountPoint>,
+    pub intervals: Vec<CountPoint>,
+    pub interval_summary: IntervalSummary,
+    pub ease_factors: Vec<EaseFactorCount>,
+    pub buttons: Vec<ButtonCount>,
+    pub hours: Vec<HourPoint>,
+}
+
+impl GraphsSpec {
+    /// A condition limiting cards to the decks, starting with "and".
+    fn card_limit(&self) -> String {
+        if self.deck_ids.is_empty() {
+            "".into()
+        } else {
+            format!(" and did in {}", ids_to_string(&self.deck_ids))
+        }
+    }
+
+    /// A condition limiting revlog entries to the cards of the decks, and
+    /// to the included history, starting with "and".
+    fn revlog_limit(&self) -> String {
+        let mut sql = format!(" and id > {}", self.history_start_secs() * 1000);
+        if !self.deck_ids.is_empty() {
+            sql.push_str(&format!(
+                " and cid in (select id from cards where did in {})",
+                ids_to_string(&self.deck_ids)
+            ));
+        }
+        sql
+    }
+
+    /// The start of the earliest day included.
+    fn history_start_secs(&self) -> i64 {
+        match self.buckets {
+            Some(buckets) => {
+                self.next_day_at - i64::from(buckets) * i64::from(self.bucket_days) * 86_400
+            }
+            None => 0,
+        }
+    }
+}
+
+/// Gather the data of every graph.
+pub fn graphs(storage: &SqliteStorage, spec: &GraphsSpec) -> Result<Graphs> {
+    if spec.bucket_days == 0 {
+        return Err(AnkiError::invalid_input("buckets must cover a day or more"));
+    }
+    Ok(Graphs {
+        forecast: forecast(storage, spec)?,
+        due_tomorrow: due_tomorrow(storage, spec)?,
+        reviews: reviews(storage, spec)?,
+        added: added(storage, spec)?,
+        intervals: intervals(storage, spec)?,
+        interval_summary: interval_summary(storage, spec)?,
+        ease_factors: ease_factors(storage, spec)?,
+        buttons: buttons(storage, spec)?,
+        hours: hours(storage, spec)?,
+    })
+}
+
+/// Reviews due from today onwards. Overdue cards are not included.
+fn forecast(storage: &SqliteStorage, spec: &GraphsSpec) -> Result<Vec<ForecastPoint>> {
+    let sql = format!(
+        "select (due - ?1) / ?2 as bucket,
+sum(case when ivl < 21 then 1 else 0 end),
+sum(case when ivl >= 21 then 1 else 0 end)
+from cards where queue in (2, 3) and due >= ?1{}
+group by bucket order by bucket",
+        spec.card_limit()
+    );
+    let mut points: Vec<ForecastPoint> = storage
+        .db
+        .prepare(&sql)?
+        .query_map(params![spec.today, spec.bucket_days], |row| {
+            Ok(ForecastPoint {
+                bucket: row.get(0)?,
+                young: row.get(1)?,
+                mature: row.get(2)?,
+            })
+        })?
+        .collect::<rusqlite::Result<_>>()?;
+    if let Some(buckets) = spec.buckets {
+        points.retain(|point| point.bucket < buckets as i32);
+    }
+    Ok(points)
+}
+
+fn due_tomorrow(storage: &SqliteStorage, spec: &GraphsSpec) -> Result<u32> {
+    let sql = format!(
+        "select count() from cards where queue in (2, 3) and due = ?{}",
+        spec.card_limit()
+    );
+    storage
+        .db
+        .query_row(&sql, params![spec.today + 1], |row| row.get(0))
+        .map_err(Into::into)
+}
+
+/// The bucket of the day a card or revlog id was added on.
+const DAY_BUCKET: &str = "cast((id / 1000.0 - ?1) / 86400.0 as int) / ?2";
+
+fn reviews(storage: &SqliteStorage, spec: &GraphsSpec) -> Result<Vec<ReviewsPoint>> {
+    let sql = format!(
+        "select {} as bucket,
+sum(case when type = 0 then 1 else 0 end),
+sum(case when type = 1 and lastIvl < 21 then 1 else 0 end),
+sum(case when type = 1 and lastIvl >= 21 then 1 else 0 end),
+sum(case when type = 2 then 1 else 0 end),
+sum(case when type = 3 then 1 else 0 end),
+sum(case when type = 0 then time else 0 end),
+sum(case when type = 1 and lastIvl < 21 then time else 0 end),
+sum(case when type = 1 and lastIvl >= 21 then time else 0 end),
+sum(case when type = 2 then time else 0 end),
+sum(case when type = 3 then time else 0 end)
+from revlog where 1{}
+group by bucket order by bucket",
+        DAY_BUCKET,
+        spec.revlog_limit()
+    );
+    let secs = |row: &Row, idx: usize| -> rusqlite::Result<f32> {
+        Ok(row.get::<_, i64>(idx)? as f32 / 1000.0)
+    };
+    storage
+        .db
+        .prepare(&sql)?
+        .query_map(params![spec.next_day_at, spec.bucket_days], |row| {
+            Ok(ReviewsPoint {
+                bucket: row.get(0)?,
+                counts: ReviewKinds {
+                    learn: row.get(1)?,
+                    young: row.get(2)?,
+                    mature: row.get(3)?,
+                    relearn: row.get(4)?,
+                    filtered: row.get(5)?,
+                },
+                secs: ReviewKinds {
+                    learn: secs(row, 6)?,
+                    young: secs(row, 7)?,
+                    mature: secs(row, 8)?,
+                    relearn: secs(row, 9)?,
+                    filtered: secs(row, 10)?,
+                },
+            })
+        })?
+        .collect::<rusqlite::Result<_>>()
+        .map_err(Into::into)
+}
+
+fn added(storage: &SqliteStorage, spec: &GraphsSpec) -> Result<Vec<CountPoint>> {
+    let sql = format!(
+        "select {} as bucket, count() from cards
+where id > {}{}
+group by bucket order by bucket",
+        DAY_BUCKET,
+        spec.history_start_secs() * 1000,
+        spec.card_limit()
+    );
+    storage
+        .db
+        .prepare(&sql)?
+        .query_map(params![spec.next_day_at, spec.bucket_days], |row| {
+            Ok(CountPoint {
+                bucket: row.get(0)?,
+                count: row.get(1)?,
+            })
+        })?
+        .collect::<rusqlite::Result<_>>()
+        .map_err(Into::into)
+}
+
+fn intervals(storage: &SqliteStorage, spec: &GraphsSpec) -> Result<Vec<CountPoint>> {
+    let sql = format!(
+        "select ivl / ? as bucket, count() from cards where queue = 2{}
+group by bucket order by bucket",
+        spec.card_limit()
+    );
+    let mut points: Vec<CountPoint> = storage
+        .db
+        .prepare(&sql)?
+        .query_map(params![spec.bucket_days], |row| {
+            Ok(CountPoint {
+                bucket: row.get(0)?,
+                count: row.get(1)?,
+            })
+        })?
+        .collect::<rusqlite::Result<_>>()?;
+    // the last bucket is included, like the legacy code
+    if let Some(buckets) = spec.buckets {
+        points.retain(|point| point.bucket <= buckets as i32);
+    }
+    Ok(points)
+}
+
+fn interval_summary(storage: &SqliteStorage, spec: &GraphsSpec) -> Result<IntervalSummary> {
+    let sql = format!(
+        "select count(), coalesce(avg(ivl), 0), coalesce(max(ivl), 0)
+from cards where queue = 2{}",
+        spec.card_limit()
+    );
+    storage
+        .db
+        .query_row(&sql, NO_PARAMS, |row| {
+            Ok(IntervalSummary {
+                cards: row.get(0)?,
+                average_days: row.get::<_, f64>(1)? as f32,
+                longest_days: row.get(2)?,
+            })
+        })
+        .map_err(Into::into)
+}
+
+fn ease_factors(storage: &SqliteStorage, spec: &GraphsSpec) -> Result<Vec<EaseFactorCount>> {
+    let sql = format!(
+        "select factor, count() from cards where queue = 2{}
+group by factor order by factor",
+        spec.card_limit()
+    );
+    storage
+        .db
+        .prepare(&sql)?
+        .query_map(NO_PARAMS, |row| {
+            Ok(EaseFactorCount {
+                ease_factor: row.get(0)?,
+                count: row.get(1)?,
+            })
+        })?
+        .collect::<rusqlite::Result<_>>()
+        .map_err(Into::into)
+}
+
+fn buttons(storage: &SqliteStorage, spec: &GraphsSpec) -> Result<Vec<ButtonCount>> {
+    let sql = format!(
+        "select (case when type in (0, 2) then 0 when lastIvl < 21 then 1 else 2 end) as kind,
+(case when type in (0, 2) and ease = 4 and ? then 3 else ease end) as button,
+count()
+from revlog where ease between 1 and 4{}
+group by kind, button order by kind, button",
+        spec.revlog_limit()
+    );
+    storage
+        .db
+        .prepare(&sql)?
+        .query_map(params![spec.v1_learning_buttons], |row| {
+            Ok(ButtonCount {
+                kind: match row.get::<_, u8>(0)? {
+                    0 => ButtonCardKind::Learning,
+                    1 => ButtonCardKind::Young,
+                    _ => ButtonCardKind::Mature,
+                },
+                button: row.get(1)?,
+                count: row.get(2)?,
+            })
+        })?
+        .collect::<rusqlite::Result<_>>()
+        .map_err(Into::into)
+}
+
+/// Hours without answers are left out.
+fn hours(storage: &SqliteStorage, spec: &GraphsSpec) -> Result<Vec<HourPoint>> {
+    let sql = format!(
+        "select ((id / 1000 - ?) % 86400 + 86400) % 86400 / 3600 as hour,
+count(), sum(case when ease = 1 then 0 else 1 end)
+from revlog where type in (0, 1, 2){}
+group by hour order by hour",
+        spec.revlog_limit()
+    );
+    // a local midnight, rather than the start of a day at the rollover
+    let midnight = spec.next_day_at - i64::from(spec.rollover_hour) * 3600;
+    storage
+        .db
+        .prepare(&sql)?
+        .query_map(params![midnight], |row| {
+            Ok(HourPoint {
+                hour: row.get(0)?,
+                answers: row.get(1)?,
+                correct: row.get(2)?,
+            })
+        })?
+        .collect::<rusqlite::Result<_>>()
+        .map_err(Into::into)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::card::{Card, CardQueue, CardType};
+    use crate::err::Result;
+    use crate::revlog::RevlogEntry;
+    use crate::stats::graphs::GraphsSpec;
+    use crate::stats::graphs::{
+        graphs, ButtonCardKind, ButtonCount, CountPoint, EaseFactorCount, ForecastPoint, HourPoint,
+        IntervalSummary, ReviewKinds, ReviewsPoint,
+    };
+    use crate::storage::testutil::open_test_collection;
+
+    #[test]
+    fn test_graphs() -> Result<()> {
+        let (_dir, storage) = open_test_collection()?;
+        let next_day_at = 1_600_000_000;
+        let day = 86_400;
+        let card = |id_secs: i64, deck_id, queue, due, interval, ease_factor| -> Result<i64> {
+            let mut card = Card {
+                id: id_secs * 1000,
+                note_id: 1,
+                deck_id,
+                ctype: if queue == CardQueue::New {
+                    CardType::New
+                } else {
+                    CardType::Review
+                },
+                queue,
+                due,
+                interval,
+                ease_factor,
+                ..Default::default()
+            };
+            storage.add_card(&mut card)?;
+            Ok(card.id)
+        };
+        let c1 = card(
+            next_day_at - 2 * day - 100,
+            1,
+            CardQueue::Review,
+            100,
+            5,
+            2500,
+        )?;
+        let c2 = card(next_day_at - 10, 1, CardQueue::Review, 101, 30, 2300)?;
+        let c3 = card(next_day_at - 20, 2, CardQueue::Review, 100, 5, 2500)?;
+        card(next_day_at - 20 * day, 1, CardQueue::New, 1, 0, 0)?;
+        for (card_id, id_secs, review_kind, last_interval, ease, taken_millis) in &[
+            (c1, next_day_at - 3600, 1, 5, 3, 4000),
+            (c2, next_day_at - day - 3600, 1, 30, 1, 2000),
+            (c1, next_day_at - 2 * day - 60, 0, 0, 4, 1000),
+            (c3, next_day_at - 3599, 1, 5, 3, 1000),
+            (c1, next_day_at - 30 * day, 1, 5, 3, 1000),
+        ] {
+            storage.add_revlog_entry(&mut RevlogEntry {
+                id: id_secs * 1000,
+                card_id: *card_id,
+                ease: *ease,
+                last_interval: *last_interval,
+                taken_millis: *taken_millis,
+                review_kind: *review_kind,
+                ..Default::default()
+            })?;
+        }
+
+        let mut spec = GraphsSpec {
+            deck_ids: vec![1],
+            today: 100,
+            next_day_at,
+            rollover_hour: 4,
+            bucket_days: 1,
+            buckets: Some(7),
+            v1_learning_buttons: true,
+        };
+        let data = graphs(&storage, &spec)?;
+        assert_eq!(
+            data.forecast,
+            vec![
+                ForecastPoint {
+                    bucket: 0,
+                    young: 1,
+                    mature: 0
+                },
+                ForecastPoint {
+                    bucket: 1,
+                    young: 0,
+                    mature: 1
+                }
+            ]
+        );
+        assert_eq!(data.due_tomorrow, 1);
+        let buckets: Vec<_> = data.reviews.iter().map(|p| p.bucket).collect();
+        assert_eq!(buckets, vec![-2, -1, 0]);
+        assert_eq!(
+            data.reviews[0],
+            ReviewsPoint {
+                bucket: -2,
+                counts: ReviewKinds {
+                    learn: 1,
+                    ..Default::default()
+                },
+                secs: ReviewKinds {
+                    learn: 1.0,
+                    ..Default::default()
+                },
+            }
+        );
+        assert_eq!(
+            (data.reviews[1].counts.mature, data.reviews[2].secs.young),
+            (1, 4.0)
+        );
+        assert_eq!(
+            data.added,
+            vec![
+                CountPoint {
+                    bucket: -2,
+                    count: 1
+                },
+                CountPoint {
+                    bucket: 0,
+                    count: 1
+                }
+            ]
+        );
+        // longer intervals are only in the summary
+        assert_eq!(
+            data.intervals,
+            vec![CountPoint {
+                bucket: 5,
+                count: 1
+            }]
+        );
+        assert_eq!(
+            data.interval_summary,
+            IntervalSummary {
+                cards: 2,
+                average_days: 17.5,
+                longest_days: 30
+            }
+        );
+        assert_eq!(
+            data.ease_factors,
+            vec![
+                EaseFactorCount {
+                    ease_factor: 2300,
+                    count: 1
+                },
+                EaseFactorCount {
+                    ease_factor: 2500,
+                    count: 1
+                }
+            ]
+        );
+        let button = |kind, button| ButtonCount {
+            kind,
+            button,
+            count: 1,
+        };
+        assert_eq!(
+            data.buttons,
+            vec![
+                button(ButtonCardKind::Learning, 3),
+                button(ButtonCardKind::Young, 3),
+                button(ButtonCardKind::Mature, 1)
+            ]
+        );
+        // the reviews were an hour before the 4am rollover
+        assert_eq!(
+            data.hours,
+            vec![HourPoint {
+                hour: 3,
+                answers: 3,
+                correct: 2
+            }]
+        );
+
+        // the whole collection, and all history, by week
+        spec.deck_ids.clear();
+        spec.buckets = None;
+        spec.bucket_days = 7;
+        let data = graphs(&storage, &spec)?;
+        assert_eq!((data.forecast[0].young, data.forecast[0].mature), (2, 1));
+        let reviews: u32 = data.reviews.iter().map(|p| p.counts.young).sum();
+        assert_eq!(reviews, 3);
+        assert_eq!(data.reviews[0].bucket, -4);
+
+        spec.bucket_days = 0;
+        assert!(graphs(&storage, &spec).is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,175 @@
+// Copyright: Ankitects Pty Ltd and contributors
+// License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+use crate::card::{Card, CardQueue, CardType};
+use crate::err::Result;
+use crate::sched::ids_to_string;
+use crate::storage::SqliteStorage;
+use rusqlite::{params, OptionalExtension, Row, NO_PARAMS};
+
+const CARD_COLUMNS: &str = "id, nid, did, ord, mod, usn, type, queue, due, ivl, factor,
+reps, lapses, left, odue, odid, flags, data";
+
+fn row_to_card(row: &Row) -> rusqlite::Result<Card> {
+    let ctype: u8 = row.get(6)?;
+    let queue: i8 = row.get(7)?;
+    Ok(Card {
+        id: row.get(0)?,
+        note_id: row.get(1)?,
+        deck_id: row.get(2)?,
+        ordinal: row.get(3)?,
+        mtime_secs: row.get(4)?,
+        usn: row.get(5)?,
+        ctype: CardType::from_u8(ctype)
+            .ok_or_else(|| rusqlite::Error::IntegralValueOutOfRange(6, ctype.into()))?,
+        queue: CardQueue::from_i8(queue)
+            .ok_or_else(|| rusqlite::Error::IntegralValueOutOfRange(7, queue.into()))?,
+        due: row.get(8)?,
+        interval: row.get(9)?,
+        ease_factor: row.get(10)?,
+        reps: row.get(11)?,
+        lapses: row.get(12)?,
+        left: row.get(13)?,
+        original_due: row.get(14)?,
+        original_deck_id: row.get(15)?,
+        flags: row.get(16)?,
+        data: row.get(17)?,
+    })
+}
+
+impl SqliteStorage {
+    pub fn get_card(&self, id: i64) -> Result<Option<Card>> {
+        self.db
+            .prepare_cached(&format!("select {} from cards where id = ?", CARD_COLUMNS))?
+            .query_row(params![id], row_to_card)
+            .optional()
+            .map_err(Into::into)
+    }
+
+    pub fn get_cards_of_note(&self, note_id: i64) -> Result<Vec<Card>> {
+        self.db
+            .prepare_cached(&format!(
+                "select {} from cards where nid = ? order by ord",
+                CARD_COLUMNS
+            ))?
+            .query_map(params![note_id], row_to_card)?
+            .collect::<rusqlite::Result<_>>()
+            .map_err(Into::into)
+    }
+
+    /// Add a new card. If its id is 0, a new id is assigned.
+    pub fn add_card(&self, card: &mut Card) -> Result<()> {
+        if card.id == 0 {
+            card.id = self.next_id("cards")?;
+        }
+        self.insert_card("insert", card)
+    }
+
+    /// Write a card with its existing id, replacing any card with the
+    /// same id. Used to restore removed cards.
+    pub(crate) fn add_or_update_card(&self, card: &Card) -> Result<()> {
+        self.insert_card("insert or replace", card)
+    }
+
+    fn insert_card(&self, verb: &str, card: &Card) -> Result<()> {
+        self.db
+            .prepare_cached(&format!(
+                "{} into cards values (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+                verb
+            ))?
+            .execute(params![
+                card.id,
+                card.note_id,
+                card.deck_id,
+                card.ordinal,
+                card.mtime_secs,
+                card.usn,
+                card.ctype as u8,
+                card.queue as i8,
+                card.due,
+                card.interval,
+                card.ease_factor,
+                card.reps,
+                card.lapses,
+                card.left,
+                card.original_due,
+                card.original_deck_id,
+                card.flags,
+                card.data,
+            ])?;
+        Ok(())
+    }
+
+    pub fn update_card(&self, card: &Card) -> Result<()> {
+        self.db
+            .prepare_cached(
+                "update cards set nid = ?, did = ?, ord = ?, mod = ?, usn = ?, type = ?,
+queue = ?, due = ?, ivl = ?, factor = ?, reps = ?, lapses = ?, left = ?, odue = ?,
+odid = ?, flags = ?, data = ? where id = ?",
+            )?
+            .execute(params![
+                card.note_id,
+                card.deck_id,
+                card.ordinal,
+                card.mtime_secs,
+                card.usn,
+                card.ctype as u8,
+                card.queue as i8,
+                card.due,
+                card.interval,
+                card.ease_factor,
+                card.reps,
+                card.lapses,
+                card.left,
+                card.original_due,
+                card.original_deck_id,
+                card.flags,
+                card.data,
+                card.id,
+            ])?;
+        Ok(())
+    }
+
+    /// The ids of the cards in `deck_ids`, or of every card if None, in id
+    /// order.
+    pub(crate) fn card_ids_in_decks(&self, deck_ids: Option<&[i64]>) -> Result<Vec<i64>> {
+        let sql = match deck_ids {
+            Some(ids) => format!(
+                "select id from cards where did in {} order by id",
+                ids_to_string(ids)
+            ),
+            None => "select id from cards order by id".into(),
+        };
+        self.db
+            .prepare(&sql)?
+            .query_map(NO_PARAMS, |row| row.get(0))?
+            .collect::<rusqlite::Result<_>>()
+            .map_err(Into::into)
+    }
+
+    /// Move the cards in a deck to another deck, including the cards that
+    /// are in a filtered deck and will return to it.
+    pub(crate) fn move_deck_cards(
+        &self,
+        from_deck_id: i64,
+        to_deck_id: i64,
+        usn: i32,
+        mtime_secs: i64,
+    ) -> Result<()> {
+        self.db
+            .prepare_cached("update cards set did = ?, mod = ?, usn = ? where did = ?")?
+            .execute(params![to_deck_id, mtime_secs, usn, from_deck_id])?;
+        self.db
+            .prepare_cached("update cards set odid = ?, mod = ?, usn = ? where odid = ?")?
+            .execute(params![to_deck_id, mtime_secs, usn, from_deck_id])?;
+        Ok(())
+    }
+
+    /// Remove a card. The caller is responsible for adding a grave.
+    pub fn remove_card(&self, id: i64) -> Result<()> {
+        self.db
+            .prepare_cached("delete from cards where id = ?")?
+            .execute(params![id])?;
+        Ok(())
+    }
+}
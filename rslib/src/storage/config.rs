@@ -0,0 +1,38 @@
+// Copyright: Ankitects Pty Ltd and contributors
+// License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+use crate::err::Result;
+use crate::storage::SqliteStorage;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::{Map, Value};
+
+// The collection config is a single JSON object in the col table, shared
+// with the legacy code.
+
+impl SqliteStorage {
+    pub fn get_all_config(&self) -> Result<Map<String, Value>> {
+        self.get_json_column("conf")
+    }
+
+    /// The value of a config key, or None if it's missing or can't be
+    /// decoded as T.
+    pub fn get_config_value<T: DeserializeOwned>(&self, key: &str) -> Result<Option<T>> {
+        Ok(self
+            .get_all_config()?
+            .remove(key)
+            .and_then(|value| serde_json::from_value(value).ok()))
+    }
+
+    pub fn set_config_value<T: Serialize>(&self, key: &str, value: &T) -> Result<()> {
+        let mut conf = self.get_all_config()?;
+        conf.insert(key.to_string(), serde_json::to_value(value)?);
+        self.set_json_column("conf", &conf)
+    }
+
+    pub fn remove_config_value(&self, key: &str) -> Result<()> {
+        let mut conf = self.get_all_config()?;
+        conf.remove(key);
+        self.set_json_column("conf", &conf)
+    }
+}
@@ -0,0 +1,76 @@
+// Copyright: Ankitects Pty Ltd and contributors
+// License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+use crate::decks::{Deck, DeckConf};
+use crate::err::Result;
+use crate::storage::SqliteStorage;
+use std::collections::HashMap;
+
+// The col table stores decks, options groups and notetypes as JSON objects
+// keyed by their ids.
+
+pub(super) fn by_id<T>(items: HashMap<String, T>) -> HashMap<i64, T> {
+    items
+        .into_iter()
+        .filter_map(|(id, item)| id.parse().ok().map(|id| (id, item)))
+        .collect()
+}
+
+pub(super) fn by_string_id<T>(items: HashMap<i64, T>) -> HashMap<String, T> {
+    items
+        .into_iter()
+        .map(|(id, item)| (id.to_string(), item))
+        .collect()
+}
+
+impl SqliteStorage {
+    // Decks
+    //----------------------------------------
+
+    pub fn get_all_decks(&self) -> Result<HashMap<i64, Deck>> {
+        self.get_json_column("decks").map(by_id)
+    }
+
+    pub fn get_deck(&self, id: i64) -> Result<Option<Deck>> {
+        Ok(self.get_all_decks()?.remove(&id))
+    }
+
+    /// Add a deck, or replace the existing deck with the same id.
+    pub fn add_or_update_deck(&self, deck: &Deck) -> Result<()> {
+        let mut decks = self.get_all_decks()?;
+        decks.insert(deck.id, deck.clone());
+        self.set_json_column("decks", &by_string_id(decks))
+    }
+
+    /// Remove a deck. Its cards are not moved, and the caller is
+    /// responsible for adding a grave.
+    pub fn remove_deck(&self, id: i64) -> Result<()> {
+        let mut decks = self.get_all_decks()?;
+        decks.remove(&id);
+        self.set_json_column("decks", &by_string_id(decks))
+    }
+
+    // Deck options
+    //----------------------------------------
+
+    pub fn get_all_deck_conf(&self) -> Result<HashMap<i64, DeckConf>> {
+        self.get_json_column("dconf").map(by_id)
+    }
+
+    pub fn get_deck_conf(&self, id: i64) -> Result<Option<DeckConf>> {
+        Ok(self.get_all_deck_conf()?.remove(&id))
+    }
+
+    /// Add an options group, or replace the existing one with the same id.
+    pub fn add_or_update_deck_conf(&self, conf: &DeckConf) -> Result<()> {
+        let mut confs = self.get_all_deck_conf()?;
+        confs.insert(conf.id, conf.clone());
+        self.set_json_column("dconf", &by_string_id(confs))
+    }
+
+    pub fn remove_deck_conf(&self, id: i64) -> Result<()> {
+        let mut confs = self.get_all_deck_conf()?;
+        confs.remove(&id);
+        self.set_json_column("dconf", &by_string_id(confs))
+    }
+}
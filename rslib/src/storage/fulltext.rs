@@ -0,0 +1,204 @@
+// Copyright: Ankitects Pty Ltd and contributors
+// License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+//! An optional full-text index over the text of each note. Changed notes
+//! are queued by triggers, and the index is brought up to date before a
+//! search uses it. The index lives in the collection file, so it's enabled
+//! for as long as its tables exist.
+//!
+//! Searches match text anywhere in a field, so every suffix of each word is
+//! indexed, allowing a prefix query to find the notes that may contain a
+//! word. The index is only used to narrow down the notes to check.
+
+use crate::err::Result;
+use crate::storage::SqliteStorage;
+use rusqlite::{params, OptionalExtension, NO_PARAMS};
+
+/// The text of a note as it is indexed: the suffixes of each run of
+/// letters and digits in the fields, including any in HTML markup, as a
+/// search of the fields would find those too.
+fn note_text(fields: &str) -> String {
+    let mut out = String::new();
+    for word in fields.split(|c: char| !c.is_alphanumeric()) {
+        for (idx, _) in word.char_indices() {
+            out.push_str(&word[idx..]);
+            out.push(' ');
+        }
+    }
+    out
+}
+
+impl SqliteStorage {
+    pub fn fulltext_index_enabled(&self) -> Result<bool> {
+        Ok(self.db.query_row(
+            "select count(*) from sqlite_master where type = 'table' and name = 'notes_fts'",
+            NO_PARAMS,
+            |row| row.get(0),
+        )?)
+    }
+
+    /// Build or remove the index. Building reads every note in the
+    /// collection, so may take a while on large collections.
+    pub fn set_fulltext_index_enabled(&self, enabled: bool) -> Result<()> {
+        if enabled == self.fulltext_index_enabled()? {
+            return Ok(());
+        }
+        self.db.execute_batch("savepoint fulltext")?;
+        let result = if enabled {
+            self.db
+                .execute_batch(include_str!("fulltext.sql"))
+                .map_err(Into::into)
+                .and_then(|_| self.apply_pending_fulltext_changes())
+        } else {
+            self.db
+                .execute_batch(
+                    "drop trigger notes_fts_added;
+                     drop trigger notes_fts_updated;
+                     drop trigger notes_fts_removed;
+                     drop table notes_fts_pending;
+                     drop table notes_fts;",
+                )
+                .map_err(Into::into)
+        };
+        self.release_savepoint(result)
+    }
+
+    /// Reindex notes that have changed since the last update. Does nothing
+    /// if the index is not enabled.
+    pub fn update_fulltext_index(&self) -> Result<()> {
+        if !self.fulltext_index_enabled()? {
+            return Ok(());
+        }
+        let pending: Option<i64> = self
+            .db
+            .query_row(
+                "select id from notes_fts_pending limit 1",
+                NO_PARAMS,
+                |row| row.get(0),
+            )
+            .optional()?;
+        if pending.is_none() {
+            // avoid taking a write lock
+            return Ok(());
+        }
+
+        self.db.execute_batch("savepoint fulltext")?;
+        let result = self.apply_pending_fulltext_changes();
+        self.release_savepoint(result)
+    }
+
+    fn apply_pending_fulltext_changes(&self) -> Result<()> {
+        let mut remove = self
+            .db
+            .prepare_cached("delete from notes_fts where rowid = ?")?;
+        let mut add = self
+            .db
+            .prepare_cached("insert into notes_fts (rowid, text) values (?, ?)")?;
+        let mut stmt = self.db.prepare(
+            "select p.id, n.flds from notes_fts_pending p
+left join notes n on n.id = p.id",
+        )?;
+        let mut rows = stmt.query(NO_PARAMS)?;
+        while let Some(row) = rows.next()? {
+            let id: i64 = row.get(0)?;
+            remove.execute(params![id])?;
+            // removed notes have no fields
+            if let Some(fields) = row.get::<_, Option<String>>(1)? {
+                add.execute(params![id, note_text(&fields)])?;
+            }
+        }
+        self.db.execute_batch("delete from notes_fts_pending")?;
+
+        Ok(())
+    }
+
+    /// Commit or roll back the changes made since `savepoint fulltext`,
+    /// depending on `result`.
+    fn release_savepoint(&self, result: Result<()>) -> Result<()> {
+        if result.is_err() {
+            self.db.execute_batch("rollback to fulltext")?;
+        }
+        self.db.execute_batch("release fulltext")?;
+        result
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::err::Result;
+    use crate::notes::Note;
+    use crate::storage::testutil::open_test_collection;
+    use crate::storage::SqliteStorage;
+    use rusqlite::NO_PARAMS;
+
+    fn indexed(storage: &SqliteStorage, word: &str) -> Result<Vec<i64>> {
+        let mut stmt = storage
+            .db
+            .prepare("select rowid from notes_fts where notes_fts match ? order by rowid")?;
+        let ids = stmt
+            .query_map(&[word], |row| row.get(0))?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(ids)
+    }
+
+    #[test]
+    fn test_fulltext_index() -> Result<()> {
+        let (_dir, storage) = open_test_collection()?;
+        let mut note = Note {
+            fields: vec!["<b>hello</b>".into(), "world".into()],
+            ..Default::default()
+        };
+        storage.add_note(&mut note)?;
+        assert!(!storage.fulltext_index_enabled()?);
+        // updating a disabled index does nothing
+        storage.update_fulltext_index()?;
+
+        // existing notes are indexed when it's enabled, including the
+        // suffixes of each word
+        storage.set_fulltext_index_enabled(true)?;
+        assert!(storage.fulltext_index_enabled()?);
+        assert_eq!(indexed(&storage, "hello")?, vec![note.id]);
+        assert_eq!(indexed(&storage, "llo")?, vec![note.id]);
+        assert!(indexed(&storage, "hell")?.is_empty());
+
+        // changes made outside the storage layer are picked up
+        let mut note2 = note.clone();
+        note2.id = 0;
+        storage.add_note(&mut note2)?;
+        storage
+            .db
+            .execute("update notes set flds = 'other' where id = ?", [note.id])?;
+        storage.update_fulltext_index()?;
+        assert_eq!(indexed(&storage, "hello")?, vec![note2.id]);
+        assert_eq!(indexed(&storage, "other")?, vec![note.id]);
+        storage.remove_note(note2.id)?;
+        storage.update_fulltext_index()?;
+        assert!(indexed(&storage, "hello")?.is_empty());
+        let pending: i64 =
+            storage
+                .db
+                .query_row("select count(*) from notes_fts_pending", NO_PARAMS, |row| {
+                    row.get(0)
+                })?;
+        assert_eq!(pending, 0);
+
+        storage.set_fulltext_index_enabled(false)?;
+        assert!(!storage.fulltext_index_enabled()?);
+        // the triggers are gone too
+        storage.add_note(&mut note2)?;
+
+        // the index is dropped when the collection is prepared for older
+        // clients, as when uploading or exporting it
+        storage.set_fulltext_index_enabled(true)?;
+        storage.downgrade_to(11)?;
+        assert!(!storage.fulltext_index_enabled()?);
+        let fts_objects: i64 = storage.db.query_row(
+            "select count(*) from sqlite_master where name like 'notes_fts%'",
+            NO_PARAMS,
+            |row| row.get(0),
+        )?;
+        assert_eq!(fts_objects, 0);
+
+        Ok(())
+    }
+}
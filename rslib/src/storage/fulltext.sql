@@ -0,0 +1,18 @@
+create virtual table notes_fts using fts5(
+    text,
+    tokenize = 'unicode61 remove_diacritics 0'
+);
+-- notes that need to be reindexed, recorded with triggers so that notes
+-- written by the legacy code are picked up too
+create table notes_fts_pending (id integer primary key not null);
+create trigger notes_fts_added after insert on notes begin
+    insert or ignore into notes_fts_pending values (new.id);
+end;
+create trigger notes_fts_updated after update of id, flds on notes begin
+    insert or ignore into notes_fts_pending values (old.id);
+    insert or ignore into notes_fts_pending values (new.id);
+end;
+create trigger notes_fts_removed after delete on notes begin
+    insert or ignore into notes_fts_pending values (old.id);
+end;
+insert into notes_fts_pending select id from notes;
@@ -0,0 +1,173 @@
+// Copyright: Ankitects Pty Ltd and contributors
+// License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+//! Typed access to the collection database. The schema is shared with the
+//! legacy Python code, which may have the same file open, so callers need
+//! to make sure it has committed before writing.
+
+mod card;
+mod config;
+mod deck;
+mod fulltext;
+mod note;
+mod notetype;
+mod revlog;
+mod snapshot;
+mod sqlite;
+mod sync;
+mod tag;
+#[cfg(test)]
+pub(crate) mod testutil;
+mod upgrades;
+
+pub use snapshot::CollectionSnapshot;
+pub(crate) use sqlite::now_millis;
+pub use sqlite::{GraveKind, OptimizeStage, SqliteStorage};
+
+#[cfg(test)]
+mod test {
+    use crate::card::{Card, CardQueue, CardType};
+    use crate::decks::Deck;
+    use crate::err::{AnkiError, DBErrorKind, Result};
+    use crate::notes::Note;
+    use crate::revlog::RevlogEntry;
+    use crate::storage::testutil::open_test_collection;
+    use crate::storage::{OptimizeStage, SqliteStorage};
+    use std::fs;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_storage() -> Result<()> {
+        let dir = tempdir()?;
+        let col_path = dir.path().join("collection.anki2");
+        let mut storage = SqliteStorage::open_or_create(&col_path)?;
+        assert!(storage.creation_stamp()? > 0);
+
+        // notes
+        let mut note = Note {
+            guid: "abc".into(),
+            notetype_id: 1,
+            tags: vec!["one".into(), "two".into()],
+            fields: vec!["front".into(), "back".into()],
+            sort_field: "123".into(),
+            ..Default::default()
+        };
+        storage.add_note(&mut note)?;
+        assert_ne!(note.id, 0);
+        assert_eq!(storage.get_note(note.id)?, Some(note.clone()));
+        note.fields[1] = "updated".into();
+        storage.update_note(&note)?;
+        assert_eq!(storage.get_note(note.id)?.unwrap().fields[1], "updated");
+
+        // cards, with ids that don't clash
+        let mut card = Card {
+            note_id: note.id,
+            deck_id: 1,
+            due: 5,
+            ..Default::default()
+        };
+        storage.add_card(&mut card)?;
+        let mut card2 = Card {
+            ordinal: 1,
+            ..card.clone()
+        };
+        card2.id = 0;
+        storage.add_card(&mut card2)?;
+        assert!(card2.id > card.id);
+        card.ctype = CardType::Review;
+        card.queue = CardQueue::Review;
+        storage.update_card(&card)?;
+        assert_eq!(
+            storage.get_cards_of_note(note.id)?,
+            vec![card, card2.clone()]
+        );
+
+        // changes are rolled back if an error is returned
+        let result: Result<()> = storage.transact(|storage| {
+            storage.remove_card(card2.id)?;
+            storage.remove_note(note.id)?;
+            Err(crate::err::AnkiError::Interrupted)
+        });
+        assert!(result.is_err());
+        assert!(storage.get_note(note.id)?.is_some());
+        storage.transact(|storage| storage.remove_card(card2.id))?;
+        assert_eq!(storage.get_card(card2.id)?, None);
+
+        // revlog
+        let mut entry = RevlogEntry {
+            card_id: card2.id,
+            ease: 3,
+            interval: -600,
+            ..Default::default()
+        };
+        storage.add_revlog_entry(&mut entry)?;
+        assert_eq!(storage.get_revlog_entries(card2.id)?, vec![entry]);
+
+        // decks keep keys they don't know about
+        let deck: Deck = serde_json::from_str(
+            r#"{"id": 5, "name": "foo::bar", "mod": 1, "usn": -1, "dyn": 0, "conf": 2}"#,
+        )?;
+        storage.add_or_update_deck(&deck)?;
+        let deck = storage.get_deck(5)?.unwrap();
+        assert_eq!(deck.config_id(), Some(2));
+        storage.remove_deck(5)?;
+        assert!(storage.get_all_decks()?.is_empty());
+
+        // config
+        storage.set_config_value("curDeck", &5)?;
+        assert_eq!(storage.get_config_value::<i64>("curDeck")?, Some(5));
+        assert_eq!(storage.get_config_value::<String>("curDeck")?, None);
+        storage.remove_config_value("curDeck")?;
+        assert_eq!(storage.get_config_value::<i64>("curDeck")?, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_open() -> Result<()> {
+        let dir = tempdir()?;
+        let col_path = dir.path().join("collection.anki2");
+        match SqliteStorage::open(&col_path).err() {
+            Some(AnkiError::IOError { .. }) => (),
+            other => panic!("unexpected: {:?}", other),
+        }
+        assert!(!col_path.exists());
+
+        // an empty file isn't turned into a collection either
+        fs::write(&col_path, "")?;
+        match SqliteStorage::open(&col_path).err() {
+            Some(AnkiError::DBError { kind, .. }) => assert_eq!(kind, DBErrorKind::Corrupt),
+            other => panic!("unexpected: {:?}", other),
+        }
+
+        fs::remove_file(&col_path)?;
+        SqliteStorage::open_or_create(&col_path)?;
+        assert!(SqliteStorage::open(&col_path)?.creation_stamp()? > 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_optimize() -> Result<()> {
+        let (_dir, storage) = open_test_collection()?;
+        // leave some free pages behind
+        storage.db.execute_batch(
+            "create table filler (data text);
+             insert into filler values (hex(randomblob(100000)));
+             drop table filler;",
+        )?;
+
+        let mut stages = vec![];
+        let freed = storage.optimize(|stage| {
+            stages.push(stage);
+            true
+        })?;
+        assert_eq!(stages, vec![OptimizeStage::Vacuum, OptimizeStage::Analyze]);
+        assert!(freed >= 100_000);
+
+        // nothing runs after the callback aborts
+        assert!(storage.optimize(|_| false).is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,177 @@
+// Copyright: Ankitects Pty Ltd and contributors
+// License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+use crate::err::Result;
+use crate::notes::{split_tags, Note};
+use crate::sched::ids_to_string;
+use crate::storage::SqliteStorage;
+use rusqlite::types::Value;
+use rusqlite::{params, OptionalExtension, Row, NO_PARAMS};
+use std::collections::HashMap;
+
+fn row_to_note(row: &Row) -> rusqlite::Result<Note> {
+    let tags: String = row.get(5)?;
+    let fields: String = row.get(6)?;
+    // sfld has integer affinity, so numeric text is returned as a number
+    let sort_field = match row.get(7)? {
+        Value::Text(text) => text,
+        Value::Integer(n) => n.to_string(),
+        Value::Real(n) => n.to_string(),
+        _ => "".into(),
+    };
+    Ok(Note {
+        id: row.get(0)?,
+        guid: row.get(1)?,
+        notetype_id: row.get(2)?,
+        mtime_secs: row.get(3)?,
+        usn: row.get(4)?,
+        tags: split_tags(&tags).map(Into::into).collect(),
+        fields: fields.split('\x1f').map(Into::into).collect(),
+        sort_field,
+        checksum: row.get(8)?,
+        flags: row.get(9)?,
+        data: row.get(10)?,
+    })
+}
+
+impl SqliteStorage {
+    pub fn get_note(&self, id: i64) -> Result<Option<Note>> {
+        self.db
+            .prepare_cached(
+                "select id, guid, mid, mod, usn, tags, flds, sfld, csum, flags, data
+from notes where id = ?",
+            )?
+            .query_row(params![id], row_to_note)
+            .optional()
+            .map_err(Into::into)
+    }
+
+    /// Add a new note. If its id is 0, a new id is assigned. The sort field
+    /// and checksum are stored as provided.
+    pub fn add_note(&self, note: &mut Note) -> Result<()> {
+        if note.id == 0 {
+            note.id = self.next_id("notes")?;
+        }
+        self.insert_note("insert", note)
+    }
+
+    /// Write a note with its existing id, replacing any note with the
+    /// same id. Used to restore removed notes.
+    pub(crate) fn add_or_update_note(&self, note: &Note) -> Result<()> {
+        self.insert_note("insert or replace", note)
+    }
+
+    fn insert_note(&self, verb: &str, note: &Note) -> Result<()> {
+        self.db
+            .prepare_cached(&format!(
+                "{} into notes values (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+                verb
+            ))?
+            .execute(params![
+                note.id,
+                note.guid,
+                note.notetype_id,
+                note.mtime_secs,
+                note.usn,
+                note.joined_tags(),
+                note.joined_fields(),
+                note.sort_field,
+                note.checksum,
+                note.flags,
+                note.data,
+            ])?;
+        Ok(())
+    }
+
+    pub fn update_note(&self, note: &Note) -> Result<()> {
+        self.db
+            .prepare_cached(
+                "update notes set guid = ?, mid = ?, mod = ?, usn = ?, tags = ?, flds = ?,
+sfld = ?, csum = ?, flags = ?, data = ? where id = ?",
+            )?
+            .execute(params![
+                note.guid,
+                note.notetype_id,
+                note.mtime_secs,
+                note.usn,
+                note.joined_tags(),
+                note.joined_fields(),
+                note.sort_field,
+                note.checksum,
+                note.flags,
+                note.data,
+                note.id,
+            ])?;
+        Ok(())
+    }
+
+    /// The id and tags of each note in `ids` that exists, in id order. The
+    /// tags are returned as stored.
+    pub fn get_note_tags(&self, ids: &[i64]) -> Result<Vec<(i64, String)>> {
+        let mut stmt = self.db.prepare(&format!(
+            "select id, tags from notes where id in {} order by id",
+            ids_to_string(ids)
+        ))?;
+        let rows = stmt
+            .query_map(NO_PARAMS, |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(rows)
+    }
+
+    pub fn set_note_tags(&self, id: i64, tags: &str, mtime_secs: i64, usn: i32) -> Result<()> {
+        self.db
+            .prepare_cached("update notes set tags = ?, mod = ?, usn = ? where id = ?")?
+            .execute(params![tags, mtime_secs, usn, id])?;
+        Ok(())
+    }
+
+    /// The id of every note, in id order.
+    pub(crate) fn all_note_ids(&self) -> Result<Vec<i64>> {
+        self.db
+            .prepare("select id from notes order by id")?
+            .query_map(NO_PARAMS, |row| row.get(0))?
+            .collect::<rusqlite::Result<_>>()
+            .map_err(Into::into)
+    }
+
+    /// The ids of a notetype's notes, in id order.
+    pub(crate) fn note_ids_of_notetype(&self, notetype_id: i64) -> Result<Vec<i64>> {
+        self.db
+            .prepare("select id from notes where mid = ? order by id")?
+            .query_map(params![notetype_id], |row| row.get(0))?
+            .collect::<rusqlite::Result<_>>()
+            .map_err(Into::into)
+    }
+
+    /// Maps each note's guid to its id.
+    pub(crate) fn note_ids_by_guid(&self) -> Result<HashMap<String, i64>> {
+        self.db
+            .prepare("select guid, id from notes")?
+            .query_map(NO_PARAMS, |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<rusqlite::Result<_>>()
+            .map_err(Into::into)
+    }
+
+    /// Maps the first field checksums of a notetype's notes to the ids of
+    /// the notes with that checksum.
+    pub(crate) fn note_ids_by_checksum(&self, notetype_id: i64) -> Result<HashMap<u32, Vec<i64>>> {
+        let mut stmt = self
+            .db
+            .prepare("select csum, id from notes where mid = ?")?;
+        let mut rows = stmt.query(params![notetype_id])?;
+        let mut ids: HashMap<u32, Vec<i64>> = HashMap::new();
+        while let Some(row) = rows.next()? {
+            ids.entry(row.get(0)?).or_default().push(row.get(1)?);
+        }
+        Ok(ids)
+    }
+
+    /// Remove a note. Its cards are not removed, and the caller is
+    /// responsible for adding a grave.
+    pub fn remove_note(&self, id: i64) -> Result<()> {
+        self.db
+            .prepare_cached("delete from notes where id = ?")?
+            .execute(params![id])?;
+        Ok(())
+    }
+}
@@ -0,0 +1,32 @@
+// Copyright: Ankitects Pty Ltd and contributors
+// License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+use crate::err::Result;
+use crate::notetypes::NoteType;
+use crate::storage::deck::{by_id, by_string_id};
+use crate::storage::SqliteStorage;
+use std::collections::HashMap;
+
+impl SqliteStorage {
+    pub fn get_all_notetypes(&self) -> Result<HashMap<i64, NoteType>> {
+        self.get_json_column("models").map(by_id)
+    }
+
+    pub fn get_notetype(&self, id: i64) -> Result<Option<NoteType>> {
+        Ok(self.get_all_notetypes()?.remove(&id))
+    }
+
+    /// Add a notetype, or replace the existing notetype with the same id.
+    pub fn add_or_update_notetype(&self, notetype: &NoteType) -> Result<()> {
+        let mut notetypes = self.get_all_notetypes()?;
+        notetypes.insert(notetype.id, notetype.clone());
+        self.set_json_column("models", &by_string_id(notetypes))
+    }
+
+    /// Remove a notetype. Its notes are not removed.
+    pub fn remove_notetype(&self, id: i64) -> Result<()> {
+        let mut notetypes = self.get_all_notetypes()?;
+        notetypes.remove(&id);
+        self.set_json_column("models", &by_string_id(notetypes))
+    }
+}
@@ -0,0 +1,95 @@
+// Copyright: Ankitects Pty Ltd and contributors
+// License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+use crate::err::Result;
+use crate::revlog::RevlogEntry;
+use crate::storage::SqliteStorage;
+use rusqlite::{params, OptionalExtension, Row};
+
+fn row_to_revlog_entry(row: &Row) -> rusqlite::Result<RevlogEntry> {
+    Ok(RevlogEntry {
+        id: row.get(0)?,
+        card_id: row.get(1)?,
+        usn: row.get(2)?,
+        ease: row.get(3)?,
+        interval: row.get(4)?,
+        last_interval: row.get(5)?,
+        ease_factor: row.get(6)?,
+        taken_millis: row.get(7)?,
+        review_kind: row.get(8)?,
+    })
+}
+
+impl SqliteStorage {
+    /// Add an entry to the review log. If its id is 0, the current time is
+    /// used, adjusted so it doesn't clash with an existing entry.
+    pub fn add_revlog_entry(&self, entry: &mut RevlogEntry) -> Result<()> {
+        if entry.id == 0 {
+            entry.id = self.next_id("revlog")?;
+        }
+        self.insert_revlog_entry("insert", entry)
+    }
+
+    /// Write an entry with its existing id, replacing any entry with the
+    /// same id. Used to restore removed entries.
+    pub(crate) fn add_or_update_revlog_entry(&self, entry: &RevlogEntry) -> Result<()> {
+        self.insert_revlog_entry("insert or replace", entry)
+    }
+
+    /// Write an entry with its existing id, unless an entry with that id
+    /// already exists. Entries are never changed once logged, so this is
+    /// used when merging entries from another device.
+    pub(crate) fn add_revlog_entry_if_missing(&self, entry: &RevlogEntry) -> Result<()> {
+        self.insert_revlog_entry("insert or ignore", entry)
+    }
+
+    fn insert_revlog_entry(&self, verb: &str, entry: &RevlogEntry) -> Result<()> {
+        self.db
+            .prepare_cached(&format!(
+                "{} into revlog values (?, ?, ?, ?, ?, ?, ?, ?, ?)",
+                verb
+            ))?
+            .execute(params![
+                entry.id,
+                entry.card_id,
+                entry.usn,
+                entry.ease,
+                entry.interval,
+                entry.last_interval,
+                entry.ease_factor,
+                entry.taken_millis,
+                entry.review_kind,
+            ])?;
+        Ok(())
+    }
+
+    pub fn get_revlog_entry(&self, id: i64) -> Result<Option<RevlogEntry>> {
+        self.db
+            .prepare_cached(
+                "select id, cid, usn, ease, ivl, lastIvl, factor, time, type
+from revlog where id = ?",
+            )?
+            .query_row(params![id], row_to_revlog_entry)
+            .optional()
+            .map_err(Into::into)
+    }
+
+    pub fn remove_revlog_entry(&self, id: i64) -> Result<()> {
+        self.db
+            .prepare_cached("delete from revlog where id = ?")?
+            .execute(params![id])?;
+        Ok(())
+    }
+
+    /// The entries of a card, oldest first.
+    pub fn get_revlog_entries(&self, card_id: i64) -> Result<Vec<RevlogEntry>> {
+        self.db
+            .prepare_cached(
+                "select id, cid, usn, ease, ivl, lastIvl, factor, time, type
+from revlog where cid = ? order by id",
+            )?
+            .query_map(params![card_id], row_to_revlog_entry)?
+            .collect::<rusqlite::Result<_>>()
+            .map_err(Into::into)
+    }
+}
@@ -0,0 +1,105 @@
+// Copyright: Ankitects Pty Ltd and contributors
+// License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+//! Read-only snapshots of the collection. A snapshot is a second connection
+//! holding a read transaction open, so it keeps seeing the collection as it
+//! was when the snapshot was taken. In WAL mode readers don't block the
+//! writer, so slow queries like the statistics can run on another thread
+//! while cards are being answered.
+
+use crate::err::{AnkiError, Result};
+use rusqlite::types::Value;
+use rusqlite::{Connection, OpenFlags, NO_PARAMS};
+use std::path::Path;
+
+pub struct CollectionSnapshot {
+    db: Connection,
+}
+
+impl CollectionSnapshot {
+    /// Take a snapshot of the collection at `path`. The collection must be
+    /// in WAL mode, as otherwise the snapshot would prevent other
+    /// connections from committing.
+    pub fn open(path: &Path) -> Result<Self> {
+        let db = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+        let mode: String = db.query_row("pragma journal_mode", NO_PARAMS, |row| row.get(0))?;
+        if mode != "wal" {
+            return Err(AnkiError::invalid_input(
+                "snapshots require the collection to be in WAL mode",
+            ));
+        }
+        // the snapshot is fixed by the first read in the transaction
+        db.execute_batch("begin")?;
+        db.query_row("select ver from col", NO_PARAMS, |row| row.get::<_, i64>(0))?;
+
+        Ok(CollectionSnapshot { db })
+    }
+
+    /// Run a query, returning the rows it produced. Statements that modify
+    /// the collection fail.
+    pub fn query(&self, sql: &str, args: &[Value]) -> Result<Vec<Vec<Value>>> {
+        let mut stmt = self.db.prepare_cached(sql)?;
+        let columns = stmt.column_count();
+        let mut rows = stmt.query(args)?;
+
+        let mut out = vec![];
+        while let Some(row) = rows.next()? {
+            out.push(
+                (0..columns)
+                    .map(|idx| row.get(idx))
+                    .collect::<rusqlite::Result<_>>()?,
+            );
+        }
+
+        Ok(out)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::err::Result;
+    use crate::notes::Note;
+    use crate::storage::{CollectionSnapshot, SqliteStorage};
+    use rusqlite::types::Value;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_snapshot() -> Result<()> {
+        let dir = tempdir()?;
+        let col_path = dir.path().join("collection.anki2");
+        let storage = SqliteStorage::open_or_create(&col_path)?;
+
+        // the collection must be in WAL mode
+        assert!(CollectionSnapshot::open(&col_path).is_err());
+        storage.db.execute_batch("pragma journal_mode = wal")?;
+
+        let mut note = Note {
+            fields: vec!["front".into(), "back".into()],
+            ..Default::default()
+        };
+        storage.add_note(&mut note)?;
+        let snapshot = CollectionSnapshot::open(&col_path)?;
+        let count_sql = "select count() from notes where id >= ?";
+        assert_eq!(
+            snapshot.query(count_sql, &[Value::Integer(0)])?,
+            vec![vec![Value::Integer(1)]]
+        );
+
+        // later changes aren't seen, and the writer isn't blocked
+        note.id = 0;
+        storage.add_note(&mut note)?;
+        assert_eq!(
+            snapshot.query(count_sql, &[Value::Integer(0)])?,
+            vec![vec![Value::Integer(1)]]
+        );
+        assert_eq!(
+            CollectionSnapshot::open(&col_path)?.query(count_sql, &[Value::Integer(0)])?,
+            vec![vec![Value::Integer(2)]]
+        );
+
+        // and the collection can't be modified through it
+        assert!(snapshot.query("delete from notes", &[]).is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,275 @@
+// Copyright: Ankitects Pty Ltd and contributors
+// License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+use crate::err::{AnkiError, DBErrorKind, Result};
+use crate::sched::local_sched_timing_today;
+use crate::storage::upgrades::{schema_version, SCHEMA};
+use rusqlite::{params, Connection, OpenFlags, NO_PARAMS};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// What a grave records the removal of, as stored in graves.type.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[repr(u8)]
+pub enum GraveKind {
+    Card = 0,
+    Note = 1,
+    Deck = 2,
+}
+
+/// The steps of an optimize, reported before each one starts.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OptimizeStage {
+    Vacuum,
+    Analyze,
+}
+
+/// The collection database. All access to the cards, notes, revlog and
+/// col tables should go through this, so the SQL lives in one place.
+pub struct SqliteStorage {
+    pub(crate) db: Connection,
+}
+
+pub(crate) fn now_millis() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as i64)
+        .unwrap_or_default()
+}
+
+fn open_connection(path: &Path, create: bool) -> Result<Connection> {
+    let flags = if create {
+        OpenFlags::default()
+    } else {
+        OpenFlags::default() & !OpenFlags::SQLITE_OPEN_CREATE
+    };
+    let db = Connection::open_with_flags(path, flags).map_err(|e| {
+        if !create && !path.exists() {
+            AnkiError::IOError {
+                info: format!("collection not found: {}", path.display()),
+            }
+        } else {
+            e.into()
+        }
+    })?;
+    db.execute_batch("pragma temp_store = memory; pragma cache_size = 10000;")?;
+
+    let exists: bool = db.query_row(
+        "select count(*) from sqlite_master where type = 'table' and name = 'col'",
+        NO_PARAMS,
+        |row| row.get(0),
+    )?;
+    if !exists {
+        if !create {
+            return Err(AnkiError::db_error(
+                "not a collection",
+                DBErrorKind::Corrupt,
+            ));
+        }
+        db.execute_batch("begin exclusive")?;
+        db.execute_batch(include_str!("schema11.sql"))?;
+        // like the legacy code, days start at 4am on the day of creation
+        let now = now_millis();
+        let created = local_sched_timing_today(now / 1000, now / 1000, 4).next_day_at - 86_400;
+        db.execute(
+            "update col set crt = ?, mod = ?, scm = ?",
+            params![created, now, now],
+        )?;
+        db.execute_batch("commit")?;
+    }
+
+    SCHEMA.upgrade(&db)?;
+
+    Ok(db)
+}
+
+impl SqliteStorage {
+    /// Open the existing collection at `path`.
+    pub fn open(path: &Path) -> Result<Self> {
+        Ok(SqliteStorage {
+            db: open_connection(path, false)?,
+        })
+    }
+
+    /// Open the collection at `path`, creating an empty one if it doesn't
+    /// exist.
+    pub fn open_or_create(path: &Path) -> Result<Self> {
+        Ok(SqliteStorage {
+            db: open_connection(path, true)?,
+        })
+    }
+
+    // Transactions
+    //----------------------------------------
+
+    pub fn begin(&self) -> Result<()> {
+        self.db.execute_batch("begin immediate")?;
+        Ok(())
+    }
+
+    pub fn commit(&self) -> Result<()> {
+        if !self.db.is_autocommit() {
+            self.db.execute_batch("commit")?;
+        }
+        Ok(())
+    }
+
+    pub fn rollback(&self) -> Result<()> {
+        if !self.db.is_autocommit() {
+            self.db.execute_batch("rollback")?;
+        }
+        Ok(())
+    }
+
+    /// Run the provided closure in a transaction, rolling back if it
+    /// returns an error.
+    pub fn transact<F, R>(&mut self, func: F) -> Result<R>
+    where
+        F: FnOnce(&mut SqliteStorage) -> Result<R>,
+    {
+        self.begin()?;
+        let result = func(self);
+        if result.is_ok() {
+            self.commit()?;
+        } else {
+            self.rollback()?;
+        }
+        result
+    }
+
+    // Schema
+    //----------------------------------------
+
+    pub fn schema_version(&self) -> Result<u8> {
+        schema_version(&self.db)
+    }
+
+    /// Downgrade the schema to `version`, so the collection can be opened
+    /// by an older client. The full-text index is removed, as older clients
+    /// can't update it. The collection should be closed afterwards.
+    pub fn downgrade_to(&self, version: u8) -> Result<()> {
+        self.set_fulltext_index_enabled(false)?;
+        SCHEMA.downgrade(&self.db, version)
+    }
+
+    // Maintenance
+    //----------------------------------------
+
+    /// The size of the database file, excluding the log.
+    fn database_size(&self) -> Result<u64> {
+        let pages: i64 = self
+            .db
+            .query_row("pragma page_count", NO_PARAMS, |row| row.get(0))?;
+        let page_size: i64 = self
+            .db
+            .query_row("pragma page_size", NO_PARAMS, |row| row.get(0))?;
+        Ok((pages * page_size) as u64)
+    }
+
+    /// Rebuild the database to reclaim unused space, and update the
+    /// statistics the query planner uses. `progress_cb` is called before
+    /// each stage, and can return false to stop before it starts, which
+    /// returns AnkiError::Interrupted. Returns the number of bytes freed.
+    /// Must be called outside of a transaction.
+    pub fn optimize<F>(&self, mut progress_cb: F) -> Result<u64>
+    where
+        F: FnMut(OptimizeStage) -> bool,
+    {
+        let size_before = self.database_size()?;
+        for (stage, sql) in &[
+            (OptimizeStage::Vacuum, "vacuum"),
+            (OptimizeStage::Analyze, "analyze"),
+        ] {
+            if !progress_cb(*stage) {
+                return Err(AnkiError::Interrupted);
+            }
+            self.db.execute_batch(sql)?;
+        }
+        let size_after = self.database_size()?;
+
+        Ok(size_before.saturating_sub(size_after))
+    }
+
+    // Collection metadata
+    //----------------------------------------
+
+    /// The start of the day the collection was created on.
+    pub fn creation_stamp(&self) -> Result<i64> {
+        self.db
+            .query_row("select crt from col", NO_PARAMS, |row| row.get(0))
+            .map_err(Into::into)
+    }
+
+    /// The usn of changes that haven't been synced yet.
+    pub fn usn(&self) -> Result<i32> {
+        self.db
+            .query_row("select usn from col", NO_PARAMS, |row| row.get(0))
+            .map_err(Into::into)
+    }
+
+    /// Record a change to the collection, so it will be synced.
+    pub fn mark_modified(&self, mtime_millis: i64) -> Result<()> {
+        self.db
+            .prepare_cached("update col set mod = ?")?
+            .execute(params![mtime_millis])?;
+        Ok(())
+    }
+
+    /// Record a change that requires a full sync.
+    pub fn mark_schema_modified(&self, mtime_millis: i64) -> Result<()> {
+        self.db
+            .prepare_cached("update col set mod = ?, scm = ?")?
+            .execute(params![mtime_millis, mtime_millis])?;
+        Ok(())
+    }
+
+    /// Record the removal of a card, note or deck, so it can be synced.
+    pub fn add_grave(&self, id: i64, kind: GraveKind, usn: i32) -> Result<()> {
+        self.db
+            .prepare_cached("insert into graves (usn, oid, type) values (?, ?, ?)")?
+            .execute(params![usn, id, kind as u8])?;
+        Ok(())
+    }
+
+    // JSON columns
+    //----------------------------------------
+
+    /// Decode one of the JSON columns of the col table. `column` must not
+    /// come from user input.
+    pub(super) fn get_json_column<T: DeserializeOwned>(&self, column: &str) -> Result<T> {
+        let text: String =
+            self.db
+                .query_row(&format!("select {} from col", column), NO_PARAMS, |row| {
+                    row.get(0)
+                })?;
+        // older collections may have an empty string instead of an empty
+        // object
+        let text = if text.is_empty() { "{}" } else { &text };
+        serde_json::from_str(text).map_err(|e| {
+            AnkiError::db_error(
+                format!("invalid JSON in col.{}: {}", column, e),
+                DBErrorKind::Corrupt,
+            )
+        })
+    }
+
+    pub(super) fn set_json_column<T: Serialize>(&self, column: &str, value: &T) -> Result<()> {
+        self.db
+            .prepare_cached(&format!("update col set {} = ?", column))?
+            .execute(params![serde_json::to_string(value)?])?;
+        Ok(())
+    }
+
+    /// An id for a new row in `table`: the current time in milliseconds, or
+    /// one more than the largest existing id, whichever is larger.
+    pub(super) fn next_id(&self, table: &str) -> Result<i64> {
+        let max_id: Option<i64> = self.db.query_row(
+            &format!("select max(id) from {}", table),
+            NO_PARAMS,
+            |row| row.get(0),
+        )?;
+        Ok(now_millis().max(max_id.unwrap_or_default() + 1))
+    }
+}
@@ -0,0 +1,165 @@
+// Copyright: Ankitects Pty Ltd and contributors
+// License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+use crate::err::Result;
+use crate::storage::SqliteStorage;
+use crate::sync::{Graves, Pending};
+use rusqlite::{params, OptionalExtension, NO_PARAMS};
+use serde_json::{Map, Value};
+
+// Changes that haven't been synced yet have a usn of -1. When they're
+// sent, they're given the server's usn. The server sends the changes with
+// a usn at least as high as the client's.
+
+impl SqliteStorage {
+    // Collection state
+    //----------------------------------------
+
+    /// The last time the collection was modified, in milliseconds.
+    pub fn modified_millis(&self) -> Result<i64> {
+        self.db
+            .query_row("select mod from col", NO_PARAMS, |row| row.get(0))
+            .map_err(Into::into)
+    }
+
+    /// The last time a change that requires a full sync was made, in
+    /// milliseconds.
+    pub fn schema_modified_millis(&self) -> Result<i64> {
+        self.db
+            .query_row("select scm from col", NO_PARAMS, |row| row.get(0))
+            .map_err(Into::into)
+    }
+
+    /// The server's modification time at the last sync, in milliseconds.
+    pub(crate) fn last_sync_millis(&self) -> Result<i64> {
+        self.db
+            .query_row("select ls from col", NO_PARAMS, |row| row.get(0))
+            .map_err(Into::into)
+    }
+
+    pub(crate) fn set_creation_stamp(&self, stamp: i64) -> Result<()> {
+        self.db
+            .prepare_cached("update col set crt = ?")?
+            .execute(params![stamp])?;
+        Ok(())
+    }
+
+    pub(crate) fn set_all_config(&self, conf: &Map<String, Value>) -> Result<()> {
+        self.set_json_column("conf", conf)
+    }
+
+    /// Record a completed sync. The modification time is set to the
+    /// server's, so the next sync can tell if anything changed since.
+    pub(crate) fn finish_sync(&self, server_mtime_millis: i64, next_usn: i32) -> Result<()> {
+        self.db
+            .prepare_cached("update col set ls = ?, mod = ?, usn = ?")?
+            .execute(params![server_mtime_millis, server_mtime_millis, next_usn])?;
+        Ok(())
+    }
+
+    /// Mark everything as synced before the collection replaces the
+    /// server's, and record a schema change, so other devices must do a full
+    /// sync. The graves are no longer needed.
+    pub(crate) fn prepare_for_full_upload(&self, mtime_millis: i64) -> Result<()> {
+        for table in &["cards", "notes", "revlog"] {
+            self.mark_rows_synced(table, 0)?;
+        }
+        self.db.execute_batch("delete from graves")?;
+        self.db
+            .prepare_cached("update col set usn = usn + 1, mod = ?, scm = ?, ls = ?")?
+            .execute(params![mtime_millis, mtime_millis, mtime_millis])?;
+        Ok(())
+    }
+
+    /// True if `pragma integrity_check` found no problems.
+    pub(crate) fn integrity_check_passes(&self) -> Result<bool> {
+        let result: String = self
+            .db
+            .query_row("pragma integrity_check", NO_PARAMS, |row| row.get(0))?;
+        Ok(result == "ok")
+    }
+
+    // Rows
+    //----------------------------------------
+
+    /// The ids of the rows in `table` the other side hasn't seen. `table`
+    /// must be cards, notes or revlog.
+    pub(crate) fn pending_sync_ids(&self, table: &str, pending: Pending) -> Result<Vec<i64>> {
+        self.db
+            .prepare(&format!("select id from {} where {}", table, pending.sql()))?
+            .query_map(NO_PARAMS, |row| row.get(0))?
+            .collect::<rusqlite::Result<_>>()
+            .map_err(Into::into)
+    }
+
+    /// The modification time of a row in `table`, if it exists and the
+    /// other side hasn't seen it.
+    pub(crate) fn pending_mtime(
+        &self,
+        table: &str,
+        id: i64,
+        pending: Pending,
+    ) -> Result<Option<i64>> {
+        self.db
+            .prepare_cached(&format!(
+                "select mod from {} where id = ? and {}",
+                table,
+                pending.sql()
+            ))?
+            .query_row(params![id], |row| row.get(0))
+            .optional()
+            .map_err(Into::into)
+    }
+
+    /// Mark the unsynced rows in `table` as sent.
+    pub(crate) fn mark_rows_synced(&self, table: &str, usn: i32) -> Result<()> {
+        self.db
+            .prepare(&format!("update {} set usn = ? where usn = -1", table))?
+            .execute(params![usn])?;
+        Ok(())
+    }
+
+    /// The number of rows in `table`, and the number that haven't been
+    /// synced.
+    pub(crate) fn row_counts(&self, table: &str) -> Result<(u32, u32)> {
+        self.db
+            .query_row(
+                &format!("select count(), coalesce(sum(usn = -1), 0) from {}", table),
+                NO_PARAMS,
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .map_err(Into::into)
+    }
+
+    // Graves
+    //----------------------------------------
+
+    /// Graves that haven't been synced. They're marked with `usn`, as
+    /// they're about to be sent.
+    pub(crate) fn take_pending_graves(&self, usn: i32) -> Result<Graves> {
+        let graves = self.pending_graves(Pending::Unsent)?;
+        self.db
+            .prepare_cached("update graves set usn = ? where usn = -1")?
+            .execute(params![usn])?;
+        Ok(graves)
+    }
+
+    /// Graves the other side hasn't seen.
+    pub(crate) fn pending_graves(&self, pending: Pending) -> Result<Graves> {
+        let mut graves = Graves::default();
+        let mut stmt = self.db.prepare(&format!(
+            "select oid, type from graves where {}",
+            pending.sql()
+        ))?;
+        let mut rows = stmt.query(NO_PARAMS)?;
+        while let Some(row) = rows.next()? {
+            let id: i64 = row.get(0)?;
+            match row.get::<_, u8>(1)? {
+                0 => graves.cards.push(id),
+                1 => graves.notes.push(id),
+                _ => graves.decks.push(id),
+            }
+        }
+        Ok(graves)
+    }
+}
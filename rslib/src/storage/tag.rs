@@ -0,0 +1,19 @@
+// Copyright: Ankitects Pty Ltd and contributors
+// License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+use crate::err::Result;
+use crate::storage::SqliteStorage;
+use std::collections::HashMap;
+
+// The tag list is a JSON object in the col table, mapping each tag to the
+// usn it was added with.
+
+impl SqliteStorage {
+    pub fn get_all_tags(&self) -> Result<HashMap<String, i32>> {
+        self.get_json_column("tags")
+    }
+
+    pub fn set_all_tags(&self, tags: &HashMap<String, i32>) -> Result<()> {
+        self.set_json_column("tags", tags)
+    }
+}
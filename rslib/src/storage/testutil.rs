@@ -0,0 +1,75 @@
+// Copyright: Ankitects Pty Ltd and contributors
+// License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+//! Collections for tests to work with.
+
+use crate::decks::Deck;
+use crate::err::Result;
+use crate::notetypes::NoteType;
+use crate::storage::SqliteStorage;
+use serde_json::json;
+use tempfile::{tempdir, TempDir};
+
+/// An empty collection in a new temporary folder. The folder is removed
+/// when the returned TempDir is dropped, so it needs to be kept around.
+pub(crate) fn open_test_collection() -> Result<(TempDir, SqliteStorage)> {
+    let dir = tempdir()?;
+    let storage = SqliteStorage::open_or_create(&dir.path().join("collection.anki2"))?;
+    Ok((dir, storage))
+}
+
+/// A notetype with the provided fields, and a template for each of the
+/// question formats, named "Card 1", "Card 2" and so on. It is not added to
+/// the collection, so it can be changed first.
+pub(crate) fn notetype(id: i64, name: &str, fields: &[&str], qfmts: &[&str]) -> NoteType {
+    let fields: Vec<_> = fields
+        .iter()
+        .enumerate()
+        .map(|(ord, name)| json!({"name": name, "ord": ord}))
+        .collect();
+    let templates: Vec<_> = qfmts
+        .iter()
+        .enumerate()
+        .map(|(ord, qfmt)| {
+            json!({"name": format!("Card {}", ord + 1), "ord": ord, "qfmt": qfmt, "afmt": ""})
+        })
+        .collect();
+    serde_json::from_value(json!({
+        "id": id, "name": name, "mod": 0, "usn": 0, "type": 0, "sortf": 0,
+        "flds": fields,
+        "tmpls": templates,
+    }))
+    .unwrap()
+}
+
+/// Add a notetype with id 1, called "Basic", with Front and Back fields and
+/// a single card showing the front.
+pub(crate) fn add_basic_notetype(storage: &SqliteStorage) -> Result<NoteType> {
+    let notetype = notetype(1, "Basic", &["Front", "Back"], &["{{Front}}"]);
+    storage.add_or_update_notetype(&notetype)?;
+    Ok(notetype)
+}
+
+/// A normal deck using options group 1. It is not added to the collection.
+pub(crate) fn deck(id: i64, name: &str) -> Deck {
+    serde_json::from_value(json!({
+        "id": id, "name": name, "mod": 0, "usn": 0, "dyn": 0, "conf": 1
+    }))
+    .unwrap()
+}
+
+/// Add a normal deck using options group 1.
+pub(crate) fn add_deck(storage: &SqliteStorage, id: i64, name: &str) -> Result<Deck> {
+    let deck = deck(id, name);
+    storage.add_or_update_deck(&deck)?;
+    Ok(deck)
+}
+
+/// Add a filtered deck.
+pub(crate) fn add_filtered_deck(storage: &SqliteStorage, id: i64, name: &str) -> Result<Deck> {
+    let mut deck = deck(id, name);
+    deck.filtered = 1;
+    deck.other.remove("conf");
+    storage.add_or_update_deck(&deck)?;
+    Ok(deck)
+}
@@ -0,0 +1,202 @@
+// Copyright: Ankitects Pty Ltd and contributors
+// License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+//! Schema versions. The version is stored in col.ver, and each upgrade
+//! moves the schema up by one version in its own transaction. Upgrades
+//! that can be reversed without losing data also provide a downgrade, so
+//! the collection can be handed back to older clients.
+
+use crate::err::{AnkiError, DBErrorKind, Result};
+use rusqlite::{params, Connection, NO_PARAMS};
+use tracing::info;
+
+/// One step up from the previous schema version.
+pub(super) struct SchemaUpgrade {
+    pub upgrade: &'static str,
+    /// Reverses the upgrade, if that's possible.
+    pub downgrade: Option<&'static str>,
+}
+
+/// The oldest schema this code can open. Older collections are upgraded
+/// by the legacy code first.
+const BASE_VERSION: u8 = 11;
+
+/// Upgrades from the base version, oldest first. The schema version is the
+/// base version plus the number of upgrades.
+const UPGRADES: &[SchemaUpgrade] = &[];
+
+pub(super) const SCHEMA: Schema<'static> = Schema {
+    base_version: BASE_VERSION,
+    upgrades: UPGRADES,
+};
+
+/// The upgrades between a base version and the latest version.
+pub(super) struct Schema<'a> {
+    pub base_version: u8,
+    pub upgrades: &'a [SchemaUpgrade],
+}
+
+pub(super) fn schema_version(db: &Connection) -> Result<u8> {
+    db.query_row("select ver from col", NO_PARAMS, |row| row.get(0))
+        .map_err(Into::into)
+}
+
+impl Schema<'_> {
+    pub fn latest_version(&self) -> u8 {
+        self.base_version + self.upgrades.len() as u8
+    }
+
+    /// Upgrade the collection to the latest version. Collections created
+    /// by newer clients are rejected, so they aren't damaged.
+    pub fn upgrade(&self, db: &Connection) -> Result<()> {
+        let version = schema_version(db)?;
+        if version > self.latest_version() {
+            return Err(AnkiError::CollectionTooNew { version });
+        } else if version < self.base_version {
+            return Err(AnkiError::db_error(
+                format!("collection version {} is too old to upgrade", version),
+                DBErrorKind::FileTooOld,
+            ));
+        }
+
+        for (idx, step) in self.upgrades.iter().enumerate() {
+            let to_version = self.base_version + idx as u8 + 1;
+            if to_version > version {
+                info!(to_version, "upgrading collection");
+                apply_step(db, step.upgrade, to_version)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Downgrade the collection to `target`, so it can be opened by an
+    /// older client. Fails without changing the collection if a step can't
+    /// be reversed.
+    pub fn downgrade(&self, db: &Connection, target: u8) -> Result<()> {
+        let version = schema_version(db)?;
+        if target < self.base_version || version > self.latest_version() {
+            return Err(AnkiError::invalid_input(format!(
+                "can't downgrade from version {} to {}",
+                version, target
+            )));
+        }
+
+        let steps: Vec<_> = (target..version)
+            .rev()
+            .map(|from_version| {
+                let step = &self.upgrades[(from_version - self.base_version) as usize];
+                step.downgrade.ok_or_else(|| {
+                    AnkiError::invalid_input(format!(
+                        "version {} can't be downgraded",
+                        from_version + 1
+                    ))
+                })
+            })
+            .collect::<Result<_>>()?;
+        for (sql, to_version) in steps.into_iter().zip((target..version).rev()) {
+            apply_step(db, sql, to_version)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Run `sql` and record the new version in a single transaction.
+fn apply_step(db: &Connection, sql: &str, to_version: u8) -> Result<()> {
+    db.execute_batch("begin exclusive")?;
+    let result = db
+        .execute_batch(sql)
+        .and_then(|_| db.execute("update col set ver = ?", params![to_version]));
+    match result {
+        Ok(_) => {
+            db.execute_batch("commit")?;
+            Ok(())
+        }
+        Err(err) => {
+            db.execute_batch("rollback")?;
+            Err(err.into())
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::err::{AnkiError, Result};
+    use crate::storage::upgrades::{schema_version, Schema, SchemaUpgrade};
+    use rusqlite::Connection;
+
+    const UPGRADES: &[SchemaUpgrade] = &[
+        SchemaUpgrade {
+            upgrade: "create table extra (id integer primary key)",
+            downgrade: Some("drop table extra"),
+        },
+        SchemaUpgrade {
+            upgrade: "alter table extra add column name text",
+            downgrade: None,
+        },
+    ];
+
+    fn table_exists(db: &Connection, name: &str) -> Result<bool> {
+        Ok(db.query_row(
+            "select count(*) from sqlite_master where name = ?",
+            &[name],
+            |row| row.get(0),
+        )?)
+    }
+
+    #[test]
+    fn test_upgrades() -> Result<()> {
+        let db = Connection::open_in_memory()?;
+        db.execute_batch("create table col (ver integer); insert into col values (11)")?;
+        let schema = Schema {
+            base_version: 11,
+            upgrades: &UPGRADES[..1],
+        };
+        assert_eq!(schema.latest_version(), 12);
+
+        schema.upgrade(&db)?;
+        assert_eq!(schema_version(&db)?, 12);
+        assert!(table_exists(&db, "extra")?);
+        // already up to date
+        schema.upgrade(&db)?;
+
+        schema.downgrade(&db, 11)?;
+        assert_eq!(schema_version(&db)?, 11);
+        assert!(!table_exists(&db, "extra")?);
+
+        // the second upgrade can't be reversed
+        let schema = Schema {
+            base_version: 11,
+            upgrades: UPGRADES,
+        };
+        schema.upgrade(&db)?;
+        assert_eq!(schema_version(&db)?, 13);
+        assert!(schema.downgrade(&db, 11).is_err());
+        assert_eq!(schema_version(&db)?, 13);
+
+        // a collection from a newer client is left alone
+        let schema = Schema {
+            base_version: 11,
+            upgrades: &[],
+        };
+        match schema.upgrade(&db) {
+            Err(AnkiError::CollectionTooNew { version: 13 }) => (),
+            other => panic!("unexpected: {:?}", other),
+        }
+
+        // failed steps are rolled back
+        let schema = Schema {
+            base_version: 13,
+            upgrades: &[SchemaUpgrade {
+                upgrade: "create table other (id); invalid sql",
+                downgrade: None,
+            }],
+        };
+        assert!(schema.upgrade(&db).is_err());
+        assert_eq!(schema_version(&db)?, 13);
+        assert!(!table_exists(&db, "other")?);
+
+        Ok(())
+    }
+}
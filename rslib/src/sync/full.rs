@@ -0,0 +1,296 @@
+// Copyright: Ankitects Pty Ltd and contributors
+// License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+//! Full syncs, which replace the collection on one side with the one on the
+//! other. They're needed when the schemas differ, such as after a notetype's
+//! fields were changed, or when a normal sync failed its sanity check.
+//!
+//! AnkiWeb currently sends and receives the SQLite file, gzipped.
+//! Collections can also be sent as a .colpkg containing the collection
+//! compressed with zstd, which the server will accept in future.
+
+use crate::backup::{backup_collection_now, install_collection, read_package, write_package};
+use crate::err::{AnkiError, Result};
+use crate::storage::{now_millis, SqliteStorage};
+use crate::sync::http_client::{gzipped, maybe_gunzipped, HTTPSyncClient};
+use crate::sync::{basic_check, NetworkSettings};
+use std::fs;
+use std::io::Cursor;
+use std::path::Path;
+
+/// The schema version AnkiWeb expects.
+pub(super) const SYNC_SCHEMA_VERSION: u8 = 11;
+
+/// AnkiWeb rejects collections larger than this, after compression.
+const MAX_UPLOAD_COMPRESSED_BYTES: usize = 100 * 1024 * 1024;
+/// And larger than this before compression.
+const MAX_UPLOAD_UNCOMPRESSED_BYTES: usize = 250 * 1024 * 1024;
+
+/// The server's reply to a download when the collection needs to be
+/// upgraded on AnkiWeb first.
+const UPGRADE_REQUIRED_REPLY: &[u8] = b"upgradeRequired";
+
+const SQLITE_MAGIC: &[u8] = b"SQLite format 3\0";
+const ZIP_MAGIC: &[u8] = b"PK\x03\x04";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FullSyncOutcome {
+    Success,
+    /// The collection to upload failed its checks, or was rejected by the
+    /// server. Check Database should fix it.
+    CheckFailed,
+    /// The collection on AnkiWeb needs to be upgraded before it can be
+    /// downloaded.
+    UpgradeRequired,
+    /// The download was refused, as the server's collection has no cards
+    /// and the local one does. The user probably meant to upload.
+    DownloadClobber,
+}
+
+/// How much of the collection has been sent or received.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct FullSyncProgress {
+    pub transferred_bytes: usize,
+    /// 0 if the server didn't say how large a download is.
+    pub total_bytes: usize,
+}
+
+/// How the collection is packaged for transfer.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CollectionFormat {
+    /// The SQLite file, gzipped.
+    Legacy,
+    /// A .colpkg containing the collection compressed with zstd.
+    Package,
+}
+
+/// Package the collection file's contents for upload.
+fn encode_collection(col_data: Vec<u8>, format: CollectionFormat) -> Result<Vec<u8>> {
+    if col_data.len() >= MAX_UPLOAD_UNCOMPRESSED_BYTES {
+        return Err(AnkiError::sync_misc(
+            "collection too large to upload to AnkiWeb",
+        ));
+    }
+    let data = match format {
+        CollectionFormat::Legacy => gzipped(&col_data)?,
+        CollectionFormat::Package => {
+            let mut buf = Cursor::new(vec![]);
+            write_package(&col_data, &mut buf)?;
+            buf.into_inner()
+        }
+    };
+    if data.len() >= MAX_UPLOAD_COMPRESSED_BYTES {
+        return Err(AnkiError::sync_misc(
+            "collection too large to upload to AnkiWeb",
+        ));
+    }
+    Ok(data)
+}
+
+/// Extract the collection from a download in either format.
+pub(super) fn decode_collection(data: Vec<u8>) -> Result<Vec<u8>> {
+    let data = maybe_gunzipped(&data)?;
+    if data.starts_with(ZIP_MAGIC) {
+        read_package(Cursor::new(data))
+    } else if data.starts_with(SQLITE_MAGIC) {
+        Ok(data)
+    } else {
+        Err(AnkiError::sync_misc("downloaded file is not a collection"))
+    }
+}
+
+/// Check the collection and get it ready to replace the server's. Returns
+/// false if it fails its checks. The collection must be closed.
+fn prepare_for_upload(col_path: &Path) -> Result<bool> {
+    let mut storage = SqliteStorage::open_or_create(col_path)?;
+    if !storage.integrity_check_passes()? || !basic_check(&storage)? {
+        return Ok(false);
+    }
+
+    storage.transact(|storage| {
+        storage.prepare_for_full_upload(now_millis())?;
+        for notetype in storage.get_all_notetypes()?.values_mut() {
+            notetype.usn = 0;
+            storage.add_or_update_notetype(notetype)?;
+        }
+        for deck in storage.get_all_decks()?.values_mut() {
+            deck.usn = 0;
+            storage.add_or_update_deck(deck)?;
+        }
+        for conf in storage.get_all_deck_conf()?.values_mut() {
+            conf.usn = 0;
+            storage.add_or_update_deck_conf(conf)?;
+        }
+        let mut tags = storage.get_all_tags()?;
+        tags.values_mut().for_each(|usn| *usn = 0);
+        storage.set_all_tags(&tags)
+    })?;
+    storage.downgrade_to(SYNC_SCHEMA_VERSION)?;
+    storage.optimize(|_| true)?;
+
+    Ok(true)
+}
+
+/// Replace the server's collection with the local one. The collection must
+/// be closed. If the upload fails, the collection is left marked as
+/// needing a full sync. `progress_cb` is called before the upload starts,
+/// and can return false to abort; once started, it can't be stopped.
+pub async fn full_upload<F>(
+    col_path: &Path,
+    format: CollectionFormat,
+    hkey: &str,
+    endpoint: &str,
+    network: &NetworkSettings,
+    client_version: &str,
+    mut progress_cb: F,
+) -> Result<FullSyncOutcome>
+where
+    F: FnMut(&FullSyncProgress) -> bool,
+{
+    if !prepare_for_upload(col_path)? {
+        return Ok(FullSyncOutcome::CheckFailed);
+    }
+
+    let data = encode_collection(fs::read(col_path)?, format)?;
+    let progress = FullSyncProgress {
+        transferred_bytes: 0,
+        total_bytes: data.len(),
+    };
+    if !progress_cb(&progress) {
+        return Err(AnkiError::Interrupted);
+    }
+    let mut remote = HTTPSyncClient::new(Some(hkey.into()), endpoint, network, client_version)?;
+    let accepted = remote
+        .upload(data, format == CollectionFormat::Legacy)
+        .await?;
+
+    Ok(if accepted {
+        FullSyncOutcome::Success
+    } else {
+        FullSyncOutcome::CheckFailed
+    })
+}
+
+fn has_cards(storage: &SqliteStorage) -> Result<bool> {
+    Ok(storage.row_counts("cards")?.0 > 0)
+}
+
+/// Replace the local collection with the server's. If `backup_folder` is
+/// provided, the old collection is backed up there first. The collection
+/// must be closed. It's only replaced once the download has been checked,
+/// so a failed or aborted download leaves it untouched. `progress_cb` is
+/// called as the download is received, and can return false to abort.
+pub async fn full_download<F>(
+    col_path: &Path,
+    backup_folder: Option<&Path>,
+    hkey: &str,
+    endpoint: &str,
+    network: &NetworkSettings,
+    client_version: &str,
+    mut progress_cb: F,
+) -> Result<FullSyncOutcome>
+where
+    F: FnMut(&FullSyncProgress) -> bool,
+{
+    let local_has_cards = has_cards(&SqliteStorage::open_or_create(col_path)?)?;
+
+    let mut remote = HTTPSyncClient::new(Some(hkey.into()), endpoint, network, client_version)?;
+    let data = remote
+        .download(|transferred_bytes, total_bytes| {
+            progress_cb(&FullSyncProgress {
+                transferred_bytes,
+                total_bytes,
+            })
+        })
+        .await?;
+    if data == UPGRADE_REQUIRED_REPLY {
+        return Ok(FullSyncOutcome::UpgradeRequired);
+    }
+
+    let tmp_path = col_path.with_extension("tmp");
+    fs::write(&tmp_path, decode_collection(data)?)?;
+    let check = check_download(&tmp_path, local_has_cards);
+    match check {
+        Ok(None) => (),
+        Ok(Some(outcome)) => {
+            fs::remove_file(&tmp_path)?;
+            return Ok(outcome);
+        }
+        Err(err) => {
+            fs::remove_file(&tmp_path)?;
+            return Err(err);
+        }
+    }
+
+    if let Some(folder) = backup_folder {
+        backup_collection_now(col_path, folder)?;
+    }
+    install_collection(&tmp_path, col_path)?;
+
+    Ok(FullSyncOutcome::Success)
+}
+
+/// Make sure a downloaded collection can be opened and is intact. Returns
+/// an outcome if it shouldn't replace the local collection.
+fn check_download(path: &Path, local_has_cards: bool) -> Result<Option<FullSyncOutcome>> {
+    // this also upgrades it if needed, and rejects collections that are
+    // too new
+    let storage = SqliteStorage::open_or_create(path)?;
+    if !storage.integrity_check_passes()? {
+        return Err(AnkiError::sync_misc("downloaded collection is corrupt"));
+    }
+    if local_has_cards && !has_cards(&storage)? {
+        return Ok(Some(FullSyncOutcome::DownloadClobber));
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::err::Result;
+    use crate::storage::SqliteStorage;
+    use crate::sync::full::{
+        check_download, decode_collection, encode_collection, prepare_for_upload, CollectionFormat,
+        FullSyncOutcome,
+    };
+    use crate::sync::Graves;
+    use std::fs;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_full_sync_files() -> Result<()> {
+        let dir = tempdir()?;
+        let col_path = dir.path().join("collection.anki2");
+        {
+            let storage = SqliteStorage::open_or_create(&col_path)?;
+            storage.add_grave(1, crate::storage::GraveKind::Card, -1)?;
+            storage
+                .db
+                .execute_batch("insert into revlog values (1, 1, -1, 3, 1, 0, 2500, 6, 0)")?;
+        }
+
+        assert!(prepare_for_upload(&col_path)?);
+        let storage = SqliteStorage::open_or_create(&col_path)?;
+        assert_eq!(storage.row_counts("revlog")?, (1, 0));
+        assert_eq!(storage.take_pending_graves(1)?, Graves::default());
+        assert_eq!(storage.usn()?, 1);
+        drop(storage);
+
+        // both formats can be read back
+        let col_data = fs::read(&col_path)?;
+        for format in &[CollectionFormat::Legacy, CollectionFormat::Package] {
+            let encoded = encode_collection(col_data.clone(), *format)?;
+            assert_eq!(decode_collection(encoded)?, col_data);
+        }
+        assert!(decode_collection(b"upgradeRequired".to_vec()).is_err());
+
+        // an empty download won't replace a collection with cards
+        assert_eq!(check_download(&col_path, false)?, None);
+        assert_eq!(
+            check_download(&col_path, true)?,
+            Some(FullSyncOutcome::DownloadClobber)
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,317 @@
+// Copyright: Ankitects Pty Ltd and contributors
+// License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+//! The requests the client sends to AnkiWeb. A normal sync posts a gzipped
+//! JSON object as a file, along with the user's host key and a session key.
+//! A full sync posts or receives the whole collection instead.
+
+use crate::err::{AnkiError, Result};
+use crate::sync::{
+    Chunk, Graves, NetworkSettings, SanityCheckCounts, SanityCheckResponse, SyncMeta,
+    UnchunkedChanges, SYNC_VERSION,
+};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use rand::Rng;
+use reqwest::{multipart, Client, Response, StatusCode};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::json;
+use std::io::{Read, Write};
+use tracing::{debug, warn};
+
+pub(super) struct HTTPSyncClient<'a> {
+    client: Client,
+    endpoint: &'a str,
+    client_version: &'a str,
+    hkey: Option<String>,
+    /// Identifies the session to the server, so it can reject requests
+    /// from an older session that's still running.
+    skey: String,
+    pub(super) sent_bytes: usize,
+    pub(super) received_bytes: usize,
+}
+
+impl<'a> HTTPSyncClient<'a> {
+    /// `endpoint` is the base URL of the sync server, ending in a slash.
+    pub(super) fn new(
+        hkey: Option<String>,
+        endpoint: &'a str,
+        network: &NetworkSettings,
+        client_version: &'a str,
+    ) -> Result<Self> {
+        let client = network.client()?;
+        let skey = format!("{:08x}", rand::thread_rng().gen::<u32>());
+
+        Ok(HTTPSyncClient {
+            client,
+            endpoint,
+            client_version,
+            hkey,
+            skey,
+            sent_bytes: 0,
+            received_bytes: 0,
+        })
+    }
+
+    /// Exchange a username and password for a host key. Returns None if
+    /// the server rejects them.
+    pub(super) async fn login(&mut self, username: &str, password: &str) -> Result<Option<String>> {
+        #[derive(serde_derive::Deserialize)]
+        struct HostKeyReply {
+            key: String,
+        }
+
+        let reply: Option<HostKeyReply> = self
+            .request_unless_unauthorized("hostKey", &json!({"u": username, "p": password}))
+            .await?;
+        self.hkey = reply.map(|r| r.key);
+        Ok(self.hkey.clone())
+    }
+
+    /// The server's state. Returns None if the host key is not valid.
+    pub(super) async fn meta(&mut self) -> Result<Option<SyncMeta>> {
+        let input = json!({"v": SYNC_VERSION, "cv": self.client_version});
+        self.request_unless_unauthorized("meta", &input).await
+    }
+
+    /// Start the session, and fetch the server's deletions. `minutes_west`
+    /// is only provided for the v2 scheduler.
+    pub(super) async fn start(
+        &mut self,
+        local_usn: i32,
+        local_is_newer: bool,
+        minutes_west: Option<i32>,
+    ) -> Result<Graves> {
+        let input = json!({
+            "minUsn": local_usn,
+            "lnewer": local_is_newer,
+            "offset": minutes_west,
+        });
+        self.json_request("start", &input).await
+    }
+
+    pub(super) async fn apply_graves(&mut self, chunk: &Graves) -> Result<()> {
+        self.json_request::<_, serde_json::Value>("applyGraves", &json!({ "chunk": chunk }))
+            .await?;
+        Ok(())
+    }
+
+    /// Send the local notetypes, decks, tags and config, and receive the
+    /// server's.
+    pub(super) async fn apply_changes(
+        &mut self,
+        changes: &UnchunkedChanges,
+    ) -> Result<UnchunkedChanges> {
+        self.json_request("applyChanges", &json!({ "changes": changes }))
+            .await
+    }
+
+    pub(super) async fn chunk(&mut self) -> Result<Chunk> {
+        self.json_request("chunk", &json!({})).await
+    }
+
+    pub(super) async fn apply_chunk(&mut self, chunk: &Chunk) -> Result<()> {
+        self.json_request::<_, serde_json::Value>("applyChunk", &json!({ "chunk": chunk }))
+            .await?;
+        Ok(())
+    }
+
+    pub(super) async fn sanity_check(
+        &mut self,
+        counts: &SanityCheckCounts,
+    ) -> Result<SanityCheckResponse> {
+        self.json_request("sanityCheck2", &json!({ "client": counts }))
+            .await
+    }
+
+    /// Commit the server's side of the sync, returning its new
+    /// modification time.
+    pub(super) async fn finish(&mut self) -> Result<i64> {
+        self.json_request("finish", &json!({})).await
+    }
+
+    /// Roll back the server's side of the sync.
+    pub(super) async fn abort(&mut self) -> Result<()> {
+        self.json_request::<_, serde_json::Value>("abort", &json!({}))
+            .await?;
+        Ok(())
+    }
+
+    async fn json_request<T, R>(&mut self, method: &str, input: &T) -> Result<R>
+    where
+        T: Serialize,
+        R: DeserializeOwned,
+    {
+        match self.request_unless_unauthorized(method, input).await? {
+            Some(reply) => Ok(reply),
+            None => Err(AnkiError::SyncAuthError),
+        }
+    }
+
+    /// Post a request, decoding the JSON reply. Returns None if the server
+    /// rejects the credentials.
+    async fn request_unless_unauthorized<T, R>(
+        &mut self,
+        method: &str,
+        input: &T,
+    ) -> Result<Option<R>>
+    where
+        T: Serialize,
+        R: DeserializeOwned,
+    {
+        let data = gzipped(&serde_json::to_vec(input)?)?;
+
+        let mut form = multipart::Form::new().text("c", "1");
+        // the host key is being requested by the login
+        if let Some(hkey) = &self.hkey {
+            form = form.text("k", hkey.clone()).text("s", self.skey.clone());
+        }
+
+        match self.post(method, form, Some(data)).await? {
+            Some(body) => Ok(Some(serde_json::from_slice(&body)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Replace the server's collection with `data`. `gzipped` tells the
+    /// server whether to decompress it. Returns false if the server
+    /// rejected it.
+    pub(super) async fn upload(&mut self, data: Vec<u8>, gzipped: bool) -> Result<bool> {
+        let form = self
+            .full_sync_form()
+            .text("c", if gzipped { "1" } else { "0" });
+        let body = self.full_sync_post("upload", form, Some(data)).await?;
+        Ok(body == b"OK")
+    }
+
+    /// The server's collection. `progress_cb` is called as it's received,
+    /// with the bytes received so far and the expected size, or 0 if the
+    /// server didn't provide it. It can return false to abort.
+    pub(super) async fn download<F>(&mut self, mut progress_cb: F) -> Result<Vec<u8>>
+    where
+        F: FnMut(usize, usize) -> bool,
+    {
+        let form = self.full_sync_form().text("c", "1");
+        let mut resp = match self.send("download", form, None).await? {
+            Some(resp) => resp,
+            None => return Err(AnkiError::SyncAuthError),
+        };
+        let total = resp.content_length().unwrap_or_default() as usize;
+        let mut body = vec![];
+        while let Some(chunk) = resp.chunk().await? {
+            body.extend_from_slice(&chunk);
+            self.received_bytes += chunk.len();
+            if !progress_cb(body.len(), total) {
+                return Err(AnkiError::Interrupted);
+            }
+        }
+
+        maybe_gunzipped(&body)
+    }
+
+    /// Full syncs identify the client in each request, as there's no
+    /// session.
+    fn full_sync_form(&self) -> multipart::Form {
+        multipart::Form::new()
+            .text("k", self.hkey.clone().unwrap_or_default())
+            .text("v", self.client_version.to_string())
+    }
+
+    async fn full_sync_post(
+        &mut self,
+        method: &str,
+        form: multipart::Form,
+        data: Option<Vec<u8>>,
+    ) -> Result<Vec<u8>> {
+        match self.post(method, form, data).await? {
+            Some(body) => Ok(body),
+            None => Err(AnkiError::SyncAuthError),
+        }
+    }
+
+    /// Post `form`, with `data` attached as a file if provided, and read
+    /// the reply. Returns None if the server rejects the credentials.
+    async fn post(
+        &mut self,
+        method: &str,
+        form: multipart::Form,
+        data: Option<Vec<u8>>,
+    ) -> Result<Option<Vec<u8>>> {
+        let resp = match self.send(method, form, data).await? {
+            Some(resp) => resp,
+            None => return Ok(None),
+        };
+        let body = resp.bytes().await?;
+        self.received_bytes += body.len();
+
+        Ok(Some(maybe_gunzipped(&body)?))
+    }
+
+    /// Post `form`, with `data` attached as a file if provided, returning
+    /// the response once its headers have arrived. Returns None if the
+    /// server rejects the credentials.
+    async fn send(
+        &mut self,
+        method: &str,
+        mut form: multipart::Form,
+        data: Option<Vec<u8>>,
+    ) -> Result<Option<Response>> {
+        if let Some(data) = data {
+            self.sent_bytes += data.len();
+            form = form.part("data", multipart::Part::bytes(data).file_name("data"));
+        }
+
+        let url = format!("{}{}", self.endpoint, method);
+        debug!(method, sent = self.sent_bytes, "sync request");
+        let resp = match self.client.post(&url).multipart(form).send().await {
+            Ok(resp) => resp,
+            Err(err) => {
+                warn!(method, error = ?err, "sync request failed");
+                return Err(err.into());
+            }
+        };
+        debug!(method, status = %resp.status(), "sync response");
+        if resp.status() == StatusCode::FORBIDDEN {
+            return Ok(None);
+        }
+
+        Ok(Some(resp.error_for_status()?))
+    }
+}
+
+pub(super) fn gzipped(data: &[u8]) -> Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(vec![], Compression::new(6));
+    encoder.write_all(data)?;
+    Ok(encoder.finish()?)
+}
+
+/// Replies are normally plain JSON, but may be gzipped. JSON never starts
+/// with the gzip magic number, so the two can be told apart.
+pub(super) fn maybe_gunzipped(data: &[u8]) -> Result<Vec<u8>> {
+    if data.starts_with(&[0x1f, 0x8b]) {
+        let mut out = vec![];
+        GzDecoder::new(data).read_to_end(&mut out)?;
+        Ok(out)
+    } else {
+        Ok(data.to_vec())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::err::Result;
+    use crate::sync::http_client::{gzipped, maybe_gunzipped};
+
+    #[test]
+    fn test_gzip() -> Result<()> {
+        let data = br#"{"done": true}"#;
+        let compressed = gzipped(data)?;
+        assert_ne!(&compressed[..], &data[..]);
+        assert_eq!(maybe_gunzipped(&compressed)?, data.to_vec());
+        // uncompressed replies are passed through
+        assert_eq!(maybe_gunzipped(data)?, data.to_vec());
+        Ok(())
+    }
+}
@@ -0,0 +1,1405 @@
+// Copyright: Ankitects Pty Ltd and contributors
+// License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+//! Syncing the collection with AnkiWeb.
+//!
+//! Every change is stamped with an update sequence number (usn). Local
+//! changes that haven't been sent have a usn of -1; when they're sent,
+//! they're given the server's usn, and the collection's usn is set one
+//! higher than that when the sync finishes. A normal sync exchanges
+//! deletions, then the small objects stored in the col table, then the
+//! cards, notes and review log in chunks. Both sides then compare counts
+//! of what they hold, and if they disagree, a full sync is required.
+
+mod full;
+mod http_client;
+mod network;
+mod preview;
+pub mod server;
+
+pub use full::{full_download, full_upload, CollectionFormat, FullSyncOutcome, FullSyncProgress};
+pub use network::NetworkSettings;
+pub use preview::{full_sync_preview, ChangeCounts, FullSyncPreview};
+
+use crate::card::{Card, CardQueue, CardType};
+use crate::decks::{Deck, DeckConf};
+use crate::err::{AnkiError, Result};
+use crate::notes::{field_checksum, split_tags, Note};
+use crate::notetypes::{NoteType, NoteTypeKind};
+use crate::revlog::RevlogEntry;
+use crate::sched::current_deck::{current_deck_counts, scheduler_version, StudyCounts};
+use crate::sched::filtered::return_cards_to_home_decks;
+use crate::sched::{ids_to_string, local_minutes_west_for_stamp};
+use crate::search::names_match;
+use crate::storage::{now_millis, GraveKind, SqliteStorage};
+use crate::text::sort_field_text;
+use http_client::HTTPSyncClient;
+use rusqlite::types::ToSql;
+use rusqlite::{params, OptionalExtension};
+use serde::{Serialize, Serializer};
+use serde_derive::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::collections::{HashMap, VecDeque};
+use std::convert::TryFrom;
+use tracing::{info, warn};
+
+/// The version of the sync protocol this client speaks.
+pub(crate) const SYNC_VERSION: u8 = 9;
+
+/// The maximum number of graves or rows sent in a single request.
+const CHUNK_SIZE: usize = 250;
+
+/// Syncing is refused if the clocks of the client and server differ by
+/// more than this many seconds.
+const MAX_CLOCK_DRIFT_SECS: i64 = 300;
+
+// Protocol
+//----------------------------------------
+
+/// The state of one side of the sync, exchanged at the start.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct SyncMeta {
+    /// In milliseconds.
+    #[serde(rename = "mod")]
+    pub modified: i64,
+    /// The last time a change requiring a full sync was made, in
+    /// milliseconds.
+    #[serde(rename = "scm")]
+    pub schema: i64,
+    pub usn: i32,
+    /// In seconds.
+    #[serde(rename = "ts")]
+    pub current_time: i64,
+    /// A message for the user. If `should_continue` is false, it explains
+    /// why the sync was refused.
+    #[serde(rename = "msg", default)]
+    pub server_message: String,
+    #[serde(rename = "cont")]
+    pub should_continue: bool,
+    /// The number of the server the user's data lives on.
+    #[serde(rename = "hostNum", default)]
+    pub host_number: Option<u32>,
+    #[serde(rename = "uname", default)]
+    pub username: String,
+    #[serde(rename = "musn", default)]
+    pub media_usn: i32,
+}
+
+/// Ids of removed objects.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct Graves {
+    pub cards: Vec<i64>,
+    pub notes: Vec<i64>,
+    pub decks: Vec<i64>,
+}
+
+impl Graves {
+    fn is_empty(&self) -> bool {
+        self.cards.is_empty() && self.notes.is_empty() && self.decks.is_empty()
+    }
+
+    fn len(&self) -> usize {
+        self.cards.len() + self.notes.len() + self.decks.len()
+    }
+
+    /// Remove up to `limit` graves, notes first.
+    fn take_chunk(&mut self, limit: usize) -> Graves {
+        let mut limit = limit;
+        let mut take = |ids: &mut Vec<i64>| {
+            let count = ids.len().min(limit);
+            limit -= count;
+            ids.drain(..count).collect()
+        };
+        let notes = take(&mut self.notes);
+        let cards = take(&mut self.cards);
+        let decks = take(&mut self.decks);
+        Graves {
+            cards,
+            notes,
+            decks,
+        }
+    }
+}
+
+/// The objects stored in the col table that have changed, which are sent
+/// in a single request. The config and creation time are only sent by
+/// the side that was modified last.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct UnchunkedChanges {
+    #[serde(rename = "models")]
+    pub notetypes: Vec<NoteType>,
+    #[serde(rename = "decks")]
+    pub decks_and_config: (Vec<Deck>, Vec<DeckConf>),
+    pub tags: Vec<String>,
+    #[serde(rename = "conf", skip_serializing_if = "Option::is_none")]
+    pub config: Option<Map<String, Value>>,
+    #[serde(rename = "crt", skip_serializing_if = "Option::is_none")]
+    pub creation_stamp: Option<i64>,
+}
+
+/// A card as sent over the wire: the columns of the cards table, in order.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CardEntry(
+    i64,
+    i64,
+    i64,
+    u16,
+    i64,
+    i32,
+    u8,
+    i8,
+    i64,
+    u32,
+    u16,
+    u32,
+    u32,
+    u32,
+    i64,
+    i64,
+    u8,
+    String,
+);
+
+/// A note as sent over the wire: the columns of the notes table, in
+/// order. The sort field and checksum are not sent, as the receiving side
+/// calculates them.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct NoteEntry(
+    i64,
+    String,
+    i64,
+    i64,
+    i32,
+    String,
+    String,
+    Value,
+    Value,
+    u32,
+    String,
+);
+
+/// A review log entry as sent over the wire: the columns of the revlog
+/// table, in order.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ReviewLogEntry(i64, i64, i32, u8, i32, i32, u32, u32, u8);
+
+/// Rows of the large tables. The last chunk has `done` set.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct Chunk {
+    pub done: bool,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub revlog: Vec<ReviewLogEntry>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub cards: Vec<CardEntry>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub notes: Vec<NoteEntry>,
+}
+
+/// What the client holds once it has applied the server's changes. It's
+/// sent as a list, in the order of the fields.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SanityCheckCounts {
+    pub due: StudyCounts,
+    pub cards: u32,
+    pub notes: u32,
+    pub revlog: u32,
+    pub graves: u32,
+    pub notetypes: u32,
+    pub decks: u32,
+    pub deck_config: u32,
+}
+
+impl Serialize for SanityCheckCounts {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        (
+            [self.due.new, self.due.learn, self.due.review],
+            self.cards,
+            self.notes,
+            self.revlog,
+            self.graves,
+            self.notetypes,
+            self.decks,
+            self.deck_config,
+        )
+            .serialize(serializer)
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum SanityCheckStatus {
+    Ok,
+    Bad,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct SanityCheckResponse {
+    pub status: SanityCheckStatus,
+}
+
+impl From<Card> for CardEntry {
+    fn from(c: Card) -> Self {
+        CardEntry(
+            c.id,
+            c.note_id,
+            c.deck_id,
+            c.ordinal,
+            c.mtime_secs,
+            c.usn,
+            c.ctype as u8,
+            c.queue as i8,
+            c.due,
+            c.interval,
+            c.ease_factor,
+            c.reps,
+            c.lapses,
+            c.left,
+            c.original_due,
+            c.original_deck_id,
+            c.flags,
+            c.data,
+        )
+    }
+}
+
+impl TryFrom<CardEntry> for Card {
+    type Error = AnkiError;
+
+    fn try_from(e: CardEntry) -> Result<Self> {
+        Ok(Card {
+            id: e.0,
+            note_id: e.1,
+            deck_id: e.2,
+            ordinal: e.3,
+            mtime_secs: e.4,
+            usn: e.5,
+            ctype: CardType::from_u8(e.6)
+                .ok_or_else(|| AnkiError::sync_misc(format!("invalid card type: {}", e.6)))?,
+            queue: CardQueue::from_i8(e.7)
+                .ok_or_else(|| AnkiError::sync_misc(format!("invalid card queue: {}", e.7)))?,
+            due: e.8,
+            interval: e.9,
+            ease_factor: e.10,
+            reps: e.11,
+            lapses: e.12,
+            left: e.13,
+            original_due: e.14,
+            original_deck_id: e.15,
+            flags: e.16,
+            data: e.17,
+        })
+    }
+}
+
+impl From<Note> for NoteEntry {
+    fn from(n: Note) -> Self {
+        let tags = n.joined_tags();
+        let fields = n.joined_fields();
+        NoteEntry(
+            n.id,
+            n.guid,
+            n.notetype_id,
+            n.mtime_secs,
+            n.usn,
+            tags,
+            fields,
+            "".into(),
+            "".into(),
+            n.flags,
+            n.data,
+        )
+    }
+}
+
+impl From<NoteEntry> for Note {
+    fn from(e: NoteEntry) -> Self {
+        Note {
+            id: e.0,
+            guid: e.1,
+            notetype_id: e.2,
+            mtime_secs: e.3,
+            usn: e.4,
+            tags: split_tags(&e.5).map(Into::into).collect(),
+            fields: e.6.split('\x1f').map(Into::into).collect(),
+            sort_field: "".into(),
+            checksum: 0,
+            flags: e.9,
+            data: e.10,
+        }
+    }
+}
+
+impl From<RevlogEntry> for ReviewLogEntry {
+    fn from(e: RevlogEntry) -> Self {
+        ReviewLogEntry(
+            e.id,
+            e.card_id,
+            e.usn,
+            e.ease,
+            e.interval,
+            e.last_interval,
+            e.ease_factor,
+            e.taken_millis,
+            e.review_kind,
+        )
+    }
+}
+
+impl From<ReviewLogEntry> for RevlogEntry {
+    fn from(e: ReviewLogEntry) -> Self {
+        RevlogEntry {
+            id: e.0,
+            card_id: e.1,
+            usn: e.2,
+            ease: e.3,
+            interval: e.4,
+            last_interval: e.5,
+            ease_factor: e.6,
+            taken_millis: e.7,
+            review_kind: e.8,
+        }
+    }
+}
+
+// Outcomes and progress
+//----------------------------------------
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SyncOutcome {
+    NoChanges,
+    Success,
+    /// The schemas differ, so the whole collection needs to be uploaded or
+    /// downloaded.
+    FullSyncRequired,
+    /// The host key was rejected; the user needs to log in again.
+    BadAuth,
+    /// The server refused the sync; see the server message.
+    ServerAbort,
+    /// The clocks of the client and server differ too much.
+    ClockOff,
+    /// The local collection has problems a database check should fix.
+    BasicCheckFailed,
+    /// The two sides disagreed after the sync. The changes were rolled
+    /// back, and the next sync will be a full one.
+    SanityCheckFailed,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SyncOutput {
+    pub outcome: SyncOutcome,
+    pub server_message: String,
+    pub host_number: Option<u32>,
+    pub username: String,
+}
+
+/// The stages of a normal sync, in order.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SyncStage {
+    Login,
+    Meta,
+    /// Receiving the server's cards, notes and review log.
+    Server,
+    /// Sending the local cards, notes and review log.
+    Client,
+    Sanity,
+    Finalize,
+}
+
+/// Passed to the progress callback at the start of each stage, and after
+/// each request.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct NormalSyncProgress {
+    pub stage: SyncStage,
+    pub sent_bytes: usize,
+    pub received_bytes: usize,
+}
+
+// Entry points
+//----------------------------------------
+
+/// Exchange a username and password for a host key. Returns None if they
+/// are incorrect. `endpoint` is the base URL of the sync server, ending in
+/// a slash.
+pub async fn sync_login(
+    username: &str,
+    password: &str,
+    endpoint: &str,
+    network: &NetworkSettings,
+    client_version: &str,
+) -> Result<Option<String>> {
+    let mut remote = HTTPSyncClient::new(None, endpoint, network, client_version)?;
+    remote.login(username, password).await
+}
+
+/// Sync the collection with the server. The collection must not be in a
+/// transaction, and any other connection must have committed its changes.
+///
+/// The progress callback is called at each stage, and after each request;
+/// if it returns false, the sync is aborted with AnkiError::Interrupted,
+/// and the changes made so far are rolled back on both sides.
+pub async fn sync_collection<F>(
+    storage: &SqliteStorage,
+    hkey: &str,
+    endpoint: &str,
+    network: &NetworkSettings,
+    client_version: &str,
+    progress_cb: F,
+) -> Result<SyncOutput>
+where
+    F: FnMut(&NormalSyncProgress) -> bool,
+{
+    let mut syncer = NormalSyncer {
+        storage,
+        remote: HTTPSyncClient::new(Some(hkey.into()), endpoint, network, client_version)?,
+        progress_cb,
+        stage: SyncStage::Login,
+        server_usn: 0,
+    };
+    syncer.sync().await
+}
+
+// Sync logic
+//----------------------------------------
+
+struct NormalSyncer<'a, F>
+where
+    F: FnMut(&NormalSyncProgress) -> bool,
+{
+    storage: &'a SqliteStorage,
+    remote: HTTPSyncClient<'a>,
+    progress_cb: F,
+    stage: SyncStage,
+    /// Local changes are given this usn as they're sent.
+    server_usn: i32,
+}
+
+fn now_secs() -> i64 {
+    now_millis() / 1000
+}
+
+/// Decide if the sync can't proceed or doesn't need to, from the state of
+/// the two sides.
+fn outcome_from_meta(local: &SyncMeta, remote: &SyncMeta) -> Option<SyncOutcome> {
+    if (remote.current_time - local.current_time).abs() > MAX_CLOCK_DRIFT_SECS {
+        Some(SyncOutcome::ClockOff)
+    } else if local.modified == remote.modified {
+        Some(SyncOutcome::NoChanges)
+    } else if local.schema != remote.schema {
+        Some(SyncOutcome::FullSyncRequired)
+    } else {
+        None
+    }
+}
+
+fn local_meta(storage: &SqliteStorage) -> Result<SyncMeta> {
+    Ok(SyncMeta {
+        modified: storage.modified_millis()?,
+        schema: storage.schema_modified_millis()?,
+        usn: storage.usn()?,
+        current_time: now_secs(),
+        should_continue: true,
+        ..Default::default()
+    })
+}
+
+impl<F> NormalSyncer<'_, F>
+where
+    F: FnMut(&NormalSyncProgress) -> bool,
+{
+    fn fire_progress_cb(&mut self) -> Result<()> {
+        let progress = NormalSyncProgress {
+            stage: self.stage,
+            sent_bytes: self.remote.sent_bytes,
+            received_bytes: self.remote.received_bytes,
+        };
+        if (self.progress_cb)(&progress) {
+            Ok(())
+        } else {
+            Err(AnkiError::Interrupted)
+        }
+    }
+
+    fn set_stage(&mut self, stage: SyncStage) -> Result<()> {
+        self.stage = stage;
+        self.fire_progress_cb()
+    }
+
+    async fn sync(&mut self) -> Result<SyncOutput> {
+        self.set_stage(SyncStage::Login)?;
+        let remote = match self.remote.meta().await? {
+            Some(meta) => meta,
+            None => {
+                return Ok(SyncOutput {
+                    outcome: SyncOutcome::BadAuth,
+                    server_message: "".into(),
+                    host_number: None,
+                    username: "".into(),
+                })
+            }
+        };
+        let mut output = SyncOutput {
+            outcome: SyncOutcome::ServerAbort,
+            server_message: remote.server_message.clone(),
+            host_number: remote.host_number,
+            username: remote.username.clone(),
+        };
+        if !remote.should_continue {
+            return Ok(output);
+        }
+
+        let local = local_meta(self.storage)?;
+        if let Some(outcome) = outcome_from_meta(&local, &remote) {
+            output.outcome = outcome;
+            return Ok(output);
+        }
+        if !basic_check(self.storage)? {
+            output.outcome = SyncOutcome::BasicCheckFailed;
+            return Ok(output);
+        }
+        self.server_usn = remote.usn;
+
+        info!(
+            local_usn = local.usn,
+            server_usn = remote.usn,
+            local_mod = local.modified,
+            server_mod = remote.modified,
+            "sync started"
+        );
+        self.storage.begin()?;
+        let result = self.sync_changes(&local, &remote).await;
+        match result {
+            Ok(outcome) => {
+                self.storage.commit()?;
+                info!(?outcome, "sync finished");
+                output.outcome = outcome;
+                Ok(output)
+            }
+            Err(err) => {
+                warn!(error = %err, "sync failed");
+                self.storage.rollback()?;
+                if let AnkiError::Interrupted = err {
+                    // free the server's side; it's rolled back regardless
+                    let _ = self.remote.abort().await;
+                }
+                Err(err)
+            }
+        }
+    }
+
+    /// Exchange changes with the server. Must be called in a transaction.
+    async fn sync_changes(&mut self, local: &SyncMeta, remote: &SyncMeta) -> Result<SyncOutcome> {
+        let local_is_newer = local.modified > remote.modified;
+        let now = now_secs();
+
+        // deletions
+        self.set_stage(SyncStage::Meta)?;
+        let minutes_west = if scheduler_version(&self.storage.get_all_config()?) == 2 {
+            Some(local_minutes_west_for_stamp(now))
+        } else {
+            None
+        };
+        let remote_graves = self
+            .remote
+            .start(local.usn, local_is_newer, minutes_west)
+            .await?;
+        let mut local_graves = self.storage.take_pending_graves(self.server_usn)?;
+        loop {
+            let chunk = local_graves.take_chunk(CHUNK_SIZE);
+            self.remote.apply_graves(&chunk).await?;
+            self.fire_progress_cb()?;
+            if local_graves.is_empty() {
+                break;
+            }
+        }
+        apply_graves(self.storage, &remote_graves, local.usn)?;
+
+        // notetypes, decks, tags and config
+        let local_changes = local_unchunked_changes(
+            self.storage,
+            Pending::Unsent,
+            self.server_usn,
+            local_is_newer,
+        )?;
+        let remote_changes = self.remote.apply_changes(&local_changes).await?;
+        if !merge_unchunked_changes(self.storage, remote_changes, self.server_usn)? {
+            self.remote.abort().await?;
+            return self.force_full_sync();
+        }
+
+        // cards, notes and revlog
+        self.set_stage(SyncStage::Server)?;
+        loop {
+            let chunk = self.remote.chunk().await?;
+            apply_chunk(
+                self.storage,
+                Pending::Unsent,
+                chunk.revlog,
+                chunk.cards,
+                chunk.notes,
+            )?;
+            self.fire_progress_cb()?;
+            if chunk.done {
+                break;
+            }
+        }
+        self.set_stage(SyncStage::Client)?;
+        let mut pending = PendingRows::new(self.storage, Pending::Unsent)?;
+        loop {
+            let chunk = pending.next_chunk(self.storage, self.server_usn)?;
+            self.remote.apply_chunk(&chunk).await?;
+            self.fire_progress_cb()?;
+            if chunk.done {
+                break;
+            }
+        }
+
+        // compare the results
+        self.set_stage(SyncStage::Sanity)?;
+        let counts = match sanity_check_counts(self.storage, now)? {
+            Some(counts) => counts,
+            None => {
+                self.remote.abort().await?;
+                return self.force_full_sync();
+            }
+        };
+        if let SanityCheckStatus::Bad = self.remote.sanity_check(&counts).await?.status {
+            return self.force_full_sync();
+        }
+
+        self.set_stage(SyncStage::Finalize)?;
+        let server_mtime = self.remote.finish().await?;
+        self.storage
+            .finish_sync(server_mtime, self.server_usn + 1)?;
+
+        Ok(SyncOutcome::Success)
+    }
+
+    /// Roll back the changes made so far, and mark the schema as modified,
+    /// so the next sync is a full one.
+    fn force_full_sync(&mut self) -> Result<SyncOutcome> {
+        self.storage.rollback()?;
+        self.storage.mark_schema_modified(now_millis())?;
+        Ok(SyncOutcome::SanityCheckFailed)
+    }
+}
+
+// Local changes
+//----------------------------------------
+
+/// Which of one side's changes the other side hasn't seen.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Pending {
+    /// The client's unsent changes, which have a usn of -1. They're given
+    /// the server's usn as they're sent.
+    Unsent,
+    /// The server's changes since the client's last sync, which have a
+    /// usn at least as high as the client's. They keep their usn.
+    Since(i32),
+}
+
+impl Pending {
+    fn includes(self, usn: i32) -> bool {
+        match self {
+            Pending::Unsent => usn == -1,
+            Pending::Since(min_usn) => usn >= min_usn,
+        }
+    }
+
+    /// A condition matching the pending rows of a table.
+    pub(crate) fn sql(self) -> String {
+        match self {
+            Pending::Unsent => "usn = -1".into(),
+            Pending::Since(min_usn) => format!("usn >= {}", min_usn),
+        }
+    }
+}
+
+fn exists<P>(storage: &SqliteStorage, sql: &str, params: P) -> Result<bool>
+where
+    P: IntoIterator,
+    P::Item: ToSql,
+{
+    Ok(storage
+        .db
+        .query_row(sql, params, |_| Ok(()))
+        .optional()?
+        .is_some())
+}
+
+/// Check for problems that would cause the two sides to disagree: cards
+/// without notes, notes without cards or notetypes, and cards of standard
+/// notetypes with no matching template.
+pub(crate) fn basic_check(storage: &SqliteStorage) -> Result<bool> {
+    if exists(
+        storage,
+        "select 1 from cards where nid not in (select id from notes) limit 1",
+        params![],
+    )? {
+        return Ok(false);
+    }
+    let notetypes = storage.get_all_notetypes()?;
+    let notetype_ids: Vec<_> = notetypes.keys().cloned().collect();
+    if exists(
+        storage,
+        &format!(
+            "select 1 from notes where id not in (select distinct nid from cards)
+or mid not in {} limit 1",
+            ids_to_string(&notetype_ids)
+        ),
+        params![],
+    )? {
+        return Ok(false);
+    }
+    for notetype in notetypes.values() {
+        if notetype.kind() == NoteTypeKind::Cloze {
+            continue;
+        }
+        let ords: Vec<_> = notetype
+            .templates
+            .iter()
+            .map(|t| i64::from(t.ord))
+            .collect();
+        if exists(
+            storage,
+            &format!(
+                "select 1 from cards where ord not in {} and nid in
+(select id from notes where mid = ?) limit 1",
+                ids_to_string(&ords)
+            ),
+            params![notetype.id],
+        )? {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// The pending notetypes, decks, options groups and tags. Unsent ones are
+/// given the server's usn. The config and creation time are included if
+/// the local collection was modified last.
+fn local_unchunked_changes(
+    storage: &SqliteStorage,
+    pending: Pending,
+    usn: i32,
+    include_config: bool,
+) -> Result<UnchunkedChanges> {
+    let mut changes = UnchunkedChanges::default();
+    let mark_sent = pending == Pending::Unsent;
+
+    for notetype in storage.get_all_notetypes()?.values_mut() {
+        if pending.includes(notetype.usn) {
+            if mark_sent {
+                notetype.usn = usn;
+                storage.add_or_update_notetype(notetype)?;
+            }
+            changes.notetypes.push(notetype.clone());
+        }
+    }
+    for deck in storage.get_all_decks()?.values_mut() {
+        if pending.includes(deck.usn) {
+            if mark_sent {
+                deck.usn = usn;
+                storage.add_or_update_deck(deck)?;
+            }
+            changes.decks_and_config.0.push(deck.clone());
+        }
+    }
+    for conf in storage.get_all_deck_conf()?.values_mut() {
+        if pending.includes(conf.usn) {
+            if mark_sent {
+                conf.usn = usn;
+                storage.add_or_update_deck_conf(conf)?;
+            }
+            changes.decks_and_config.1.push(conf.clone());
+        }
+    }
+
+    let mut tags = storage.get_all_tags()?;
+    for (tag, tag_usn) in tags.iter_mut() {
+        if pending.includes(*tag_usn) {
+            if mark_sent {
+                *tag_usn = usn;
+            }
+            changes.tags.push(tag.clone());
+        }
+    }
+    if mark_sent {
+        storage.set_all_tags(&tags)?;
+    }
+
+    if include_config {
+        changes.config = Some(storage.get_all_config()?);
+        changes.creation_stamp = Some(storage.creation_stamp()?);
+    }
+
+    Ok(changes)
+}
+
+/// Select a deck, making it and its children the active decks.
+fn select_deck(storage: &SqliteStorage, decks: &HashMap<i64, Deck>, deck_id: i64) -> Result<()> {
+    let name = match decks.get(&deck_id) {
+        Some(deck) => format!("{}::", deck.name),
+        None => return Ok(()),
+    };
+    let mut children: Vec<_> = decks
+        .values()
+        .filter(|d| d.name.starts_with(&name))
+        .collect();
+    children.sort_by(|a, b| a.name.cmp(&b.name));
+    let active: Vec<_> = std::iter::once(deck_id)
+        .chain(children.into_iter().map(|d| d.id))
+        .collect();
+    storage.set_config_value("curDeck", &deck_id)?;
+    storage.set_config_value("activeDecks", &active)
+}
+
+/// Reselect the current deck after decks were added or removed, falling
+/// back on the default deck if it no longer exists.
+fn reselect_current_deck(storage: &SqliteStorage) -> Result<()> {
+    let decks = storage.get_all_decks()?;
+    let current = storage
+        .get_config_value::<i64>("curDeck")?
+        .filter(|did| decks.contains_key(did))
+        .or_else(|| decks.keys().min().cloned())
+        .unwrap_or(1);
+    select_deck(storage, &decks, current)
+}
+
+/// Remove a deck the server removed. Its cards are left alone, unless
+/// it's a filtered deck, in which case they're returned home. The default
+/// deck can't be removed; if it's been nested in another deck, it's moved
+/// to the top level instead.
+fn remove_deck(storage: &SqliteStorage, deck_id: i64, usn: i32) -> Result<()> {
+    if deck_id == 1 {
+        let mut deck = match storage.get_deck(1)? {
+            Some(deck) if deck.name.contains("::") => deck,
+            _ => return Ok(()),
+        };
+        let decks = storage.get_all_decks()?;
+        let base = deck
+            .name
+            .rsplit("::")
+            .next()
+            .unwrap_or_default()
+            .to_string();
+        let mut name = base;
+        while decks.values().any(|d| names_match(&d.name, &name)) {
+            name.push('1');
+        }
+        deck.name = name;
+        deck.mtime_secs = now_secs();
+        deck.usn = usn;
+        return storage.add_or_update_deck(&deck);
+    }
+
+    storage.add_grave(deck_id, GraveKind::Deck, usn)?;
+    let deck = match storage.get_deck(deck_id)? {
+        Some(deck) => deck,
+        None => return Ok(()),
+    };
+    if deck.is_filtered() {
+        let card_ids: Vec<i64> = storage
+            .db
+            .prepare("select id from cards where did = ?")?
+            .query_map(params![deck_id], |row| row.get(0))?
+            .collect::<rusqlite::Result<_>>()?;
+        return_cards_to_home_decks(&storage.db, &card_ids, usn)?;
+    }
+    storage.remove_deck(deck_id)?;
+
+    let active: Vec<i64> = storage.get_config_value("activeDecks")?.unwrap_or_default();
+    if active.contains(&deck_id) {
+        reselect_current_deck(storage)?;
+    }
+    Ok(())
+}
+
+/// Remove the objects the other side removed. Graves are recorded with
+/// `usn`, so they're not sent back.
+fn apply_graves(storage: &SqliteStorage, graves: &Graves, usn: i32) -> Result<()> {
+    // the other side sends graves for a note's cards as well
+    for note_id in &graves.notes {
+        storage.remove_note(*note_id)?;
+        storage.add_grave(*note_id, GraveKind::Note, usn)?;
+    }
+    for card_id in &graves.cards {
+        storage.remove_card(*card_id)?;
+        storage.add_grave(*card_id, GraveKind::Card, usn)?;
+    }
+    for deck_id in &graves.decks {
+        remove_deck(storage, *deck_id, usn)?;
+    }
+    Ok(())
+}
+
+/// Merge the other side's notetypes, decks, options groups, tags and
+/// config. Objects are replaced if they're missing or older locally.
+/// Returns false if a notetype's fields or templates were added or
+/// removed, which requires a full sync.
+fn merge_unchunked_changes(
+    storage: &SqliteStorage,
+    changes: UnchunkedChanges,
+    usn: i32,
+) -> Result<bool> {
+    for notetype in changes.notetypes {
+        match storage.get_notetype(notetype.id)? {
+            Some(local) if local.mtime_secs >= notetype.mtime_secs => continue,
+            Some(local)
+                if local.fields.len() != notetype.fields.len()
+                    || local.templates.len() != notetype.templates.len() =>
+            {
+                return Ok(false);
+            }
+            _ => storage.add_or_update_notetype(&notetype)?,
+        }
+    }
+
+    let (decks, deck_config) = changes.decks_and_config;
+    let mut decks_changed = false;
+    for deck in decks {
+        match storage.get_deck(deck.id)? {
+            Some(local) if local.mtime_secs >= deck.mtime_secs => (),
+            _ => {
+                storage.add_or_update_deck(&deck)?;
+                decks_changed = true;
+            }
+        }
+    }
+    if decks_changed {
+        reselect_current_deck(storage)?;
+    }
+    for conf in deck_config {
+        match storage.get_deck_conf(conf.id)? {
+            Some(local) if local.mtime_secs >= conf.mtime_secs => (),
+            _ => storage.add_or_update_deck_conf(&conf)?,
+        }
+    }
+
+    let mut tags = storage.get_all_tags()?;
+    for tag in changes.tags {
+        tags.entry(tag).or_insert(usn);
+    }
+    storage.set_all_tags(&tags)?;
+
+    if let Some(config) = changes.config {
+        storage.set_all_config(&config)?;
+    }
+    if let Some(stamp) = changes.creation_stamp {
+        storage.set_creation_stamp(stamp)?;
+    }
+
+    Ok(true)
+}
+
+/// True if a row from the other side should replace the local one: it's
+/// missing locally, or it hasn't been changed since the last sync, or the
+/// local change is older.
+fn remote_row_wins(
+    storage: &SqliteStorage,
+    pending: Pending,
+    table: &str,
+    id: i64,
+    remote_mtime: i64,
+) -> Result<bool> {
+    Ok(match storage.pending_mtime(table, id, pending)? {
+        Some(local_mtime) => local_mtime < remote_mtime,
+        None => true,
+    })
+}
+
+/// Merge rows from the other side. Local rows win if they're `pending`
+/// and newer.
+fn apply_chunk(
+    storage: &SqliteStorage,
+    pending: Pending,
+    revlog: Vec<ReviewLogEntry>,
+    cards: Vec<CardEntry>,
+    notes: Vec<NoteEntry>,
+) -> Result<()> {
+    for entry in revlog {
+        storage.add_revlog_entry_if_missing(&entry.into())?;
+    }
+
+    for entry in cards {
+        let card = Card::try_from(entry)?;
+        if remote_row_wins(storage, pending, "cards", card.id, card.mtime_secs)? {
+            storage.add_or_update_card(&card)?;
+        }
+    }
+
+    if notes.is_empty() {
+        return Ok(());
+    }
+    let notetypes = storage.get_all_notetypes()?;
+    for entry in notes {
+        let mut note = Note::from(entry);
+        if !remote_row_wins(storage, pending, "notes", note.id, note.mtime_secs)? {
+            continue;
+        }
+        // the sort field and checksum are not sent
+        if let Some(notetype) = notetypes.get(&note.notetype_id) {
+            let sort_field = note
+                .fields
+                .get(notetype.sort_field_idx as usize)
+                .map(String::as_str)
+                .unwrap_or_default();
+            note.sort_field = sort_field_text(sort_field).into();
+            note.checksum = field_checksum(&note.fields[0]);
+        }
+        storage.add_or_update_note(&note)?;
+    }
+
+    Ok(())
+}
+
+/// The local rows that still need to be sent, in the order they're sent.
+struct PendingRows {
+    pending: Pending,
+    tables: VecDeque<(&'static str, Vec<i64>)>,
+}
+
+impl PendingRows {
+    fn new(storage: &SqliteStorage, pending: Pending) -> Result<Self> {
+        let mut tables = VecDeque::new();
+        for table in &["revlog", "cards", "notes"] {
+            tables.push_back((*table, storage.pending_sync_ids(table, pending)?));
+        }
+        Ok(PendingRows { pending, tables })
+    }
+
+    /// The next rows to send. Unsent rows are given the server's usn, and
+    /// once all of a table's rows have been taken, they're marked as sent.
+    fn next_chunk(&mut self, storage: &SqliteStorage, usn: i32) -> Result<Chunk> {
+        let mark_sent = self.pending == Pending::Unsent;
+        let mut chunk = Chunk::default();
+        let mut limit = CHUNK_SIZE;
+        while limit > 0 {
+            let (table, ids) = match self.tables.front_mut() {
+                Some(front) => front,
+                None => break,
+            };
+            let count = ids.len().min(limit);
+            for id in ids.drain(..count) {
+                match *table {
+                    "revlog" => {
+                        if let Some(mut entry) = storage.get_revlog_entry(id)? {
+                            if mark_sent {
+                                entry.usn = usn;
+                            }
+                            chunk.revlog.push(entry.into());
+                        }
+                    }
+                    "cards" => {
+                        if let Some(mut card) = storage.get_card(id)? {
+                            if mark_sent {
+                                card.usn = usn;
+                            }
+                            chunk.cards.push(card.into());
+                        }
+                    }
+                    _ => {
+                        if let Some(mut note) = storage.get_note(id)? {
+                            if mark_sent {
+                                note.usn = usn;
+                            }
+                            chunk.notes.push(note.into());
+                        }
+                    }
+                }
+            }
+            limit -= count;
+            if ids.is_empty() {
+                if mark_sent {
+                    storage.mark_rows_synced(table, usn)?;
+                }
+                self.tables.pop_front();
+            }
+        }
+        chunk.done = self.tables.is_empty();
+        Ok(chunk)
+    }
+}
+
+/// What the local collection holds, for comparison with the server. Returns
+/// None if the collection fails the basic check, or if anything is still
+/// waiting to be sent.
+fn sanity_check_counts(
+    storage: &SqliteStorage,
+    now_secs: i64,
+) -> Result<Option<SanityCheckCounts>> {
+    if !basic_check(storage)? {
+        return Ok(None);
+    }
+
+    let mut table_counts = vec![];
+    for table in &["cards", "notes", "revlog", "graves"] {
+        let (total, pending) = storage.row_counts(table)?;
+        if pending > 0 {
+            return Ok(None);
+        }
+        table_counts.push(total);
+    }
+
+    let notetypes = storage.get_all_notetypes()?;
+    let decks = storage.get_all_decks()?;
+    if notetypes.values().any(|nt| nt.usn == -1)
+        || decks.values().any(|d| d.usn == -1)
+        || storage.get_all_tags()?.values().any(|usn| *usn == -1)
+    {
+        return Ok(None);
+    }
+
+    Ok(Some(SanityCheckCounts {
+        due: current_deck_counts(storage, now_secs)?,
+        cards: table_counts[0],
+        notes: table_counts[1],
+        revlog: table_counts[2],
+        graves: table_counts[3],
+        notetypes: notetypes.len() as u32,
+        decks: decks.len() as u32,
+        deck_config: storage.get_all_deck_conf()?.len() as u32,
+    }))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::card::Card;
+    use crate::decks::Deck;
+    use crate::err::Result;
+    use crate::notes::Note;
+    use crate::revlog::RevlogEntry;
+    use crate::storage::testutil::{
+        add_basic_notetype, add_deck, add_filtered_deck, notetype, open_test_collection,
+    };
+    use crate::storage::SqliteStorage;
+    use crate::sync::{
+        apply_chunk, apply_graves, basic_check, local_unchunked_changes, merge_unchunked_changes,
+        outcome_from_meta, sanity_check_counts, CardEntry, Graves, NoteEntry, Pending, PendingRows,
+        ReviewLogEntry, SyncMeta, SyncOutcome, UnchunkedChanges, CHUNK_SIZE,
+    };
+    use serde_json::json;
+
+    fn add_note_and_card(storage: &SqliteStorage, usn: i32) -> Result<(Note, Card)> {
+        let mut note = Note {
+            notetype_id: 1,
+            fields: vec!["front".into(), "back".into()],
+            usn,
+            ..Default::default()
+        };
+        storage.add_note(&mut note)?;
+        let mut card = Card {
+            note_id: note.id,
+            deck_id: 1,
+            usn,
+            ..Default::default()
+        };
+        storage.add_card(&mut card)?;
+        Ok((note, card))
+    }
+
+    #[test]
+    fn test_outcome_from_meta() {
+        let local = SyncMeta {
+            modified: 10,
+            schema: 5,
+            current_time: 1000,
+            ..Default::default()
+        };
+        let mut remote = SyncMeta {
+            modified: 20,
+            ..local.clone()
+        };
+        assert_eq!(outcome_from_meta(&local, &remote), None);
+        remote.current_time = 1301;
+        assert_eq!(
+            outcome_from_meta(&local, &remote),
+            Some(SyncOutcome::ClockOff)
+        );
+        remote.current_time = 1000;
+        remote.schema = 6;
+        assert_eq!(
+            outcome_from_meta(&local, &remote),
+            Some(SyncOutcome::FullSyncRequired)
+        );
+        remote.modified = 10;
+        assert_eq!(
+            outcome_from_meta(&local, &remote),
+            Some(SyncOutcome::NoChanges)
+        );
+    }
+
+    #[test]
+    fn test_graves() -> Result<()> {
+        let mut graves = Graves {
+            cards: vec![1, 2],
+            notes: vec![3],
+            decks: vec![4],
+        };
+        let chunk = graves.take_chunk(2);
+        assert_eq!((chunk.notes, chunk.cards), (vec![3], vec![1]));
+        assert_eq!(graves.take_chunk(CHUNK_SIZE).decks, vec![4]);
+        assert!(graves.is_empty());
+
+        let (_dir, storage) = open_test_collection()?;
+        add_basic_notetype(&storage)?;
+        add_deck(&storage, 1, "Parent::Default")?;
+        add_deck(&storage, 2, "Default")?;
+        let filtered = add_filtered_deck(&storage, 3, "Filtered")?;
+        storage.set_config_value("curDeck", &3)?;
+        storage.set_config_value("activeDecks", &[3])?;
+        let (note, mut card) = add_note_and_card(&storage, 0)?;
+        card.deck_id = filtered.id;
+        card.original_deck_id = 2;
+        storage.update_card(&card)?;
+
+        apply_graves(
+            &storage,
+            &Graves {
+                cards: vec![],
+                notes: vec![note.id],
+                decks: vec![1, 3],
+            },
+            5,
+        )?;
+        assert!(storage.get_note(note.id)?.is_none());
+        // the filtered deck's cards are returned home
+        assert_eq!(storage.get_card(card.id)?.unwrap().deck_id, 2);
+        assert!(storage.get_deck(3)?.is_none());
+        assert_eq!(storage.get_config_value::<i64>("curDeck")?, Some(1));
+        // the default deck is moved to the top level instead
+        assert_eq!(storage.get_deck(1)?.unwrap().name, "Default1");
+        // graves from the server aren't sent back
+        assert_eq!(storage.take_pending_graves(6)?, Graves::default());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_unchunked_changes() -> Result<()> {
+        let (_dir, storage) = open_test_collection()?;
+        let mut notetype = notetype(1, "Basic", &["Front", "Back"], &["{{Front}}"]);
+        notetype.mtime_secs = 10;
+        storage.add_or_update_notetype(&notetype)?;
+        let mut deck = add_deck(&storage, 2, "Local")?;
+        deck.usn = -1;
+        storage.add_or_update_deck(&deck)?;
+
+        let changes = local_unchunked_changes(&storage, Pending::Unsent, 7, false)?;
+        assert_eq!(changes.decks_and_config.0.len(), 1);
+        assert!(changes.notetypes.is_empty() && changes.config.is_none());
+        assert_eq!(storage.get_deck(2)?.unwrap().usn, 7);
+        // the server sends its changes since the client's last sync as-is
+        let changes = local_unchunked_changes(&storage, Pending::Since(7), 9, false)?;
+        assert_eq!(changes.decks_and_config.0.len(), 1);
+        assert_eq!(storage.get_deck(2)?.unwrap().usn, 7);
+
+        // newer notetypes and decks replace local ones
+        let remote_deck: Deck = serde_json::from_value(json!({
+            "id": 3, "name": "Remote", "mod": 5, "usn": 7, "dyn": 0
+        }))?;
+        notetype.mtime_secs = 5;
+        notetype.name = "Older".into();
+        let remote = UnchunkedChanges {
+            notetypes: vec![notetype.clone()],
+            decks_and_config: (vec![remote_deck], vec![]),
+            tags: vec!["remote".into()],
+            config: None,
+            creation_stamp: Some(1234),
+        };
+        assert!(merge_unchunked_changes(&storage, remote, 7)?);
+        assert_eq!(storage.get_notetype(1)?.unwrap().name, "Basic");
+        assert_eq!(storage.get_deck(3)?.unwrap().name, "Remote");
+        assert_eq!(storage.get_all_tags()?.get("remote"), Some(&7));
+        assert_eq!(storage.creation_stamp()?, 1234);
+
+        // a newer notetype with different fields requires a full sync
+        notetype.mtime_secs = 20;
+        notetype.fields.pop();
+        let remote = UnchunkedChanges {
+            notetypes: vec![notetype],
+            ..Default::default()
+        };
+        assert!(!merge_unchunked_changes(&storage, remote, 7)?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_chunks() -> Result<()> {
+        let (_dir, storage) = open_test_collection()?;
+        // sorting on the field the remote note changes
+        let mut notetype = notetype(1, "Basic", &["Front", "Back"], &["{{Front}}"]);
+        notetype.sort_field_idx = 1;
+        storage.add_or_update_notetype(&notetype)?;
+        add_deck(&storage, 1, "Default")?;
+        let (mut note, mut card) = add_note_and_card(&storage, -1)?;
+        let mut entry = RevlogEntry {
+            card_id: card.id,
+            usn: -1,
+            ..Default::default()
+        };
+        storage.add_revlog_entry(&mut entry)?;
+
+        // newer remote rows replace pending ones; older ones are ignored
+        note.mtime_secs = 10;
+        note.fields[1] = "<b>remote</b>".into();
+        card.mtime_secs = -10;
+        card.due = 99;
+        apply_chunk(
+            &storage,
+            Pending::Unsent,
+            vec![ReviewLogEntry::from(RevlogEntry {
+                ease: 4,
+                ..entry.clone()
+            })],
+            vec![CardEntry::from(card.clone())],
+            vec![NoteEntry::from(note.clone())],
+        )?;
+        assert_eq!(storage.get_revlog_entry(entry.id)?.unwrap().ease, 0);
+        assert_eq!(storage.get_card(card.id)?.unwrap().due, 0);
+        let merged = storage.get_note(note.id)?.unwrap();
+        assert_eq!(merged.fields[1], "<b>remote</b>");
+        assert_eq!(merged.sort_field, "remote");
+
+        // pending rows are sent with the server's usn, and marked as sent
+        let mut pending = PendingRows::new(&storage, Pending::Unsent)?;
+        let chunk = pending.next_chunk(&storage, 7)?;
+        assert!(chunk.done);
+        assert_eq!(
+            (chunk.revlog.len(), chunk.cards.len(), chunk.notes.len()),
+            (1, 1, 1)
+        );
+        assert_eq!(chunk.cards[0].5, 7);
+        assert_eq!(storage.row_counts("cards")?, (1, 0));
+        assert_eq!(storage.row_counts("revlog")?, (1, 0));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_sanity_check_counts() -> Result<()> {
+        let (_dir, storage) = open_test_collection()?;
+        add_basic_notetype(&storage)?;
+        add_deck(&storage, 1, "Default")?;
+        storage.set_config_value("activeDecks", &[1])?;
+        add_note_and_card(&storage, 0)?;
+        assert!(basic_check(&storage)?);
+
+        let counts = sanity_check_counts(&storage, storage.creation_stamp()?)?.unwrap();
+        assert_eq!(counts.due.new, 1);
+        assert_eq!((counts.cards, counts.notes, counts.decks), (1, 1, 1));
+        assert_eq!(
+            serde_json::to_value(&counts)?,
+            json!([[1, 0, 0], 1, 1, 0, 0, 1, 1, counts.deck_config])
+        );
+
+        // unsent changes fail the check
+        add_note_and_card(&storage, -1)?;
+        assert!(sanity_check_counts(&storage, 0)?.is_none());
+        // as do cards with no template
+        storage.db.execute_batch("update cards set ord = 1")?;
+        assert!(!basic_check(&storage)?);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,160 @@
+// Copyright: Ankitects Pty Ltd and contributors
+// License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+//! What a full sync would discard, so the user can choose between uploading
+//! and downloading knowing what each loses. Downloading loses the local
+//! changes since the last sync; uploading loses the server's.
+//!
+//! The server's changes are counted by starting a normal sync without
+//! sending anything, and aborting it once the server's side has been
+//! received.
+
+use crate::err::{AnkiError, Result};
+use crate::storage::SqliteStorage;
+use crate::sync::http_client::HTTPSyncClient;
+use crate::sync::{NetworkSettings, Pending, UnchunkedChanges};
+use std::collections::HashSet;
+
+/// Changes one side has made since the last sync.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ChangeCounts {
+    pub notes: u32,
+    pub cards: u32,
+    pub reviews: u32,
+    pub decks: u32,
+    pub notetypes: u32,
+    /// Notes, cards and decks that were removed.
+    pub removals: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FullSyncPreview {
+    /// Discarded by a download.
+    pub local: ChangeCounts,
+    /// Discarded by an upload.
+    pub remote: ChangeCounts,
+    /// The server's collection has been replaced, or its schema changed,
+    /// since the last sync, so its changes can't be counted. An upload
+    /// discards all of it.
+    pub remote_replaced: bool,
+}
+
+fn local_change_counts(storage: &SqliteStorage) -> Result<ChangeCounts> {
+    let graves = storage.pending_graves(Pending::Unsent)?;
+    let count_ids = |table| -> Result<u32> {
+        Ok(storage.pending_sync_ids(table, Pending::Unsent)?.len() as u32)
+    };
+    Ok(ChangeCounts {
+        notes: count_ids("notes")?,
+        cards: count_ids("cards")?,
+        reviews: count_ids("revlog")?,
+        decks: storage
+            .get_all_decks()?
+            .values()
+            .filter(|d| d.usn == -1)
+            .count() as u32,
+        notetypes: storage
+            .get_all_notetypes()?
+            .values()
+            .filter(|nt| nt.usn == -1)
+            .count() as u32,
+        removals: graves.len() as u32,
+    })
+}
+
+/// Fetch the server's changes since `local_usn`, without sending any. The
+/// session must be aborted afterwards.
+async fn remote_change_counts(
+    remote: &mut HTTPSyncClient<'_>,
+    local_usn: i32,
+) -> Result<ChangeCounts> {
+    let graves = remote.start(local_usn, false, None).await?;
+    let changes = remote.apply_changes(&UnchunkedChanges::default()).await?;
+    let mut counts = ChangeCounts {
+        decks: changes.decks_and_config.0.len() as u32,
+        notetypes: changes.notetypes.len() as u32,
+        removals: graves.len() as u32,
+        ..Default::default()
+    };
+
+    // a row may be sent more than once
+    let mut note_ids = HashSet::new();
+    let mut card_ids = HashSet::new();
+    loop {
+        let chunk = remote.chunk().await?;
+        counts.reviews += chunk.revlog.len() as u32;
+        card_ids.extend(chunk.cards.iter().map(|c| c.0));
+        note_ids.extend(chunk.notes.iter().map(|n| n.0));
+        if chunk.done {
+            break;
+        }
+    }
+    counts.notes = note_ids.len() as u32;
+    counts.cards = card_ids.len() as u32;
+
+    Ok(counts)
+}
+
+/// Compare the changes made on each side since the last sync. The
+/// collection is not modified.
+pub async fn full_sync_preview(
+    storage: &SqliteStorage,
+    hkey: &str,
+    endpoint: &str,
+    network: &NetworkSettings,
+    client_version: &str,
+) -> Result<FullSyncPreview> {
+    let mut preview = FullSyncPreview {
+        local: local_change_counts(storage)?,
+        remote: ChangeCounts::default(),
+        remote_replaced: false,
+    };
+
+    let mut remote = HTTPSyncClient::new(Some(hkey.into()), endpoint, network, client_version)?;
+    let meta = match remote.meta().await? {
+        Some(meta) => meta,
+        None => return Err(AnkiError::SyncAuthError),
+    };
+    let last_sync = storage.last_sync_millis()?;
+    if meta.schema > last_sync {
+        preview.remote_replaced = true;
+    } else if meta.modified > last_sync {
+        let counts = remote_change_counts(&mut remote, storage.usn()?).await;
+        // nothing was sent, so there's nothing on the server to roll back
+        // if this fails
+        let _ = remote.abort().await;
+        preview.remote = counts?;
+    }
+
+    Ok(preview)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::err::Result;
+    use crate::storage::testutil::open_test_collection;
+    use crate::storage::GraveKind;
+    use crate::sync::preview::{local_change_counts, ChangeCounts};
+
+    #[test]
+    fn test_local_change_counts() -> Result<()> {
+        let (_dir, storage) = open_test_collection()?;
+        storage.db.execute_batch(
+            "insert into revlog values (1, 1, -1, 3, 1, 0, 2500, 6, 0);
+insert into revlog values (2, 1, 5, 3, 1, 0, 2500, 6, 0);",
+        )?;
+        storage.add_grave(1, GraveKind::Note, -1)?;
+        storage.add_grave(2, GraveKind::Card, 5)?;
+
+        assert_eq!(
+            local_change_counts(&storage)?,
+            ChangeCounts {
+                reviews: 1,
+                removals: 1,
+                ..Default::default()
+            }
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,219 @@
+// Copyright: Ankitects Pty Ltd and contributors
+// License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+//! The server's side of a collection sync. A normal sync's requests are
+//! applied in a single transaction, which is committed when the client
+//! finishes, and rolled back if it aborts, fails, or starts again.
+
+use crate::backup::install_collection;
+use crate::err::{AnkiError, Result};
+use crate::storage::{now_millis, SqliteStorage};
+use crate::sync::full::{decode_collection, SYNC_SCHEMA_VERSION};
+use crate::sync::server::{json_response, SyncRequest, SyncResponse};
+use crate::sync::{
+    apply_chunk, apply_graves, local_unchunked_changes, merge_unchunked_changes, now_secs,
+    sanity_check_counts, Chunk, Graves, Pending, PendingRows, SyncMeta, UnchunkedChanges,
+    SYNC_VERSION,
+};
+use serde_derive::Deserialize;
+use serde_json::{json, Value};
+use std::fs;
+use std::path::Path;
+
+/// The server's state, sent at the start of a sync. Clients older than
+/// this protocol are turned away.
+pub(super) fn server_meta(
+    col_path: &Path,
+    client_sync_version: u8,
+    username: &str,
+    media_usn: i32,
+) -> Result<SyncMeta> {
+    let storage = SqliteStorage::open_or_create(col_path)?;
+    let mut meta = SyncMeta {
+        modified: storage.modified_millis()?,
+        schema: storage.schema_modified_millis()?,
+        usn: storage.usn()?,
+        current_time: now_secs(),
+        should_continue: true,
+        username: username.into(),
+        media_usn,
+        ..Default::default()
+    };
+    if client_sync_version < SYNC_VERSION {
+        meta.should_continue = false;
+        meta.server_message = "Please update Anki to sync with this server.".into();
+    }
+    Ok(meta)
+}
+
+/// Replace the collection with an uploaded one. Returns false if the
+/// upload is not an intact collection.
+pub(super) fn receive_upload(col_path: &Path, data: Vec<u8>) -> Result<bool> {
+    let col_data = match decode_collection(data) {
+        Ok(col_data) => col_data,
+        Err(_) => return Ok(false),
+    };
+    let tmp_path = col_path.with_extension("tmp");
+    fs::write(&tmp_path, col_data)?;
+    let intact = SqliteStorage::open_or_create(&tmp_path)
+        .and_then(|storage| storage.integrity_check_passes())
+        .unwrap_or(false);
+    if !intact {
+        fs::remove_file(&tmp_path)?;
+        return Ok(false);
+    }
+    install_collection(&tmp_path, col_path)?;
+    Ok(true)
+}
+
+/// The collection file, in the schema clients expect.
+pub(super) fn collection_for_download(col_path: &Path) -> Result<Vec<u8>> {
+    SqliteStorage::open_or_create(col_path)?.downgrade_to(SYNC_SCHEMA_VERSION)?;
+    Ok(fs::read(col_path)?)
+}
+
+#[derive(Deserialize)]
+pub(super) struct StartIn {
+    #[serde(rename = "minUsn")]
+    client_usn: i32,
+    #[serde(rename = "lnewer")]
+    client_is_newer: bool,
+}
+
+#[derive(Deserialize)]
+struct ChunkIn<T> {
+    chunk: T,
+}
+
+#[derive(Deserialize)]
+struct ApplyChangesIn {
+    changes: UnchunkedChanges,
+}
+
+#[derive(Deserialize)]
+struct SanityCheckIn {
+    client: Value,
+}
+
+pub(super) enum SessionOutcome {
+    Continue(SyncResponse),
+    /// The session was committed or rolled back.
+    Ended(SyncResponse),
+}
+
+/// A normal sync in progress.
+pub(super) struct CollectionSession {
+    /// The client's session key. Requests from other sessions are refused.
+    pub(super) skey: String,
+    storage: SqliteStorage,
+    /// Changes the client hasn't seen have at least this usn.
+    client_usn: i32,
+    /// Changes made in this sync are given this usn.
+    server_usn: i32,
+    client_is_newer: bool,
+    /// Created once the client asks for the first chunk.
+    rows: Option<PendingRows>,
+}
+
+impl CollectionSession {
+    /// Open the collection in a transaction, returning the deletions the
+    /// client hasn't seen.
+    pub(super) fn start(col_path: &Path, skey: String, input: StartIn) -> Result<(Self, Graves)> {
+        let storage = SqliteStorage::open_or_create(col_path)?;
+        storage.begin()?;
+        let server_usn = storage.usn()?;
+        let graves = storage.pending_graves(Pending::Since(input.client_usn))?;
+        let session = CollectionSession {
+            skey,
+            storage,
+            client_usn: input.client_usn,
+            server_usn,
+            client_is_newer: input.client_is_newer,
+            rows: None,
+        };
+        Ok((session, graves))
+    }
+
+    fn pending(&self) -> Pending {
+        Pending::Since(self.client_usn)
+    }
+
+    pub(super) fn handle(&mut self, method: &str, req: &SyncRequest) -> Result<SessionOutcome> {
+        let resp = match method {
+            "applyGraves" => {
+                let input: ChunkIn<Graves> = req.json()?;
+                apply_graves(&self.storage, &input.chunk, self.server_usn)?;
+                json_response(&Value::Null)?
+            }
+            "applyChanges" => {
+                let input: ApplyChangesIn = req.json()?;
+                let changes = local_unchunked_changes(
+                    &self.storage,
+                    self.pending(),
+                    self.server_usn,
+                    !self.client_is_newer,
+                )?;
+                if !merge_unchunked_changes(&self.storage, input.changes, self.server_usn)? {
+                    self.force_full_sync()?;
+                    return Err(AnkiError::sync_misc(
+                        "notetypes differ; a full sync is required",
+                    ));
+                }
+                json_response(&changes)?
+            }
+            "chunk" => {
+                if self.rows.is_none() {
+                    self.rows = Some(PendingRows::new(&self.storage, self.pending())?);
+                }
+                let rows = self.rows.as_mut().unwrap();
+                json_response(&rows.next_chunk(&self.storage, self.server_usn)?)?
+            }
+            "applyChunk" => {
+                let input: ChunkIn<Chunk> = req.json()?;
+                let chunk = input.chunk;
+                apply_chunk(
+                    &self.storage,
+                    self.pending(),
+                    chunk.revlog,
+                    chunk.cards,
+                    chunk.notes,
+                )?;
+                json_response(&Value::Null)?
+            }
+            "sanityCheck2" => {
+                let input: SanityCheckIn = req.json()?;
+                let server = match sanity_check_counts(&self.storage, now_secs())? {
+                    Some(counts) => serde_json::to_value(counts)?,
+                    None => Value::Null,
+                };
+                if server == input.client {
+                    json_response(&json!({"status": "ok"}))?
+                } else {
+                    // the client will force a full sync
+                    self.storage.rollback()?;
+                    let resp = json!({"status": "bad", "c": input.client, "s": server});
+                    return Ok(SessionOutcome::Ended(json_response(&resp)?));
+                }
+            }
+            "finish" => {
+                let mtime = now_millis();
+                self.storage.finish_sync(mtime, self.server_usn + 1)?;
+                self.storage.commit()?;
+                return Ok(SessionOutcome::Ended(json_response(&mtime)?));
+            }
+            "abort" => {
+                self.storage.rollback()?;
+                return Ok(SessionOutcome::Ended(json_response(&Value::Null)?));
+            }
+            _ => SyncResponse::NotFound,
+        };
+        Ok(SessionOutcome::Continue(resp))
+    }
+
+    /// Roll back, and mark the schema as modified, so the client's next
+    /// sync is a full one.
+    fn force_full_sync(&mut self) -> Result<()> {
+        self.storage.rollback()?;
+        self.storage.mark_schema_modified(now_millis())
+    }
+}
@@ -0,0 +1,246 @@
+// Copyright: Ankitects Pty Ltd and contributors
+// License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+//! Serving the sync protocols over HTTP. Clients post multipart forms to
+//! /sync/<method> for collection syncs and /msync/<method> for media
+//! syncs.
+
+use crate::err::{AnkiError, Result};
+use crate::sync::server::{SyncRequest, SyncResponse, SyncServer};
+use hyper::body::HttpBody;
+use hyper::header::{CONTENT_LENGTH, CONTENT_TYPE};
+use hyper::server::conn::AddrIncoming;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response, Server, StatusCode};
+use std::convert::Infallible;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+/// Listen on `addr`, returning the address listened on, which tells the
+/// port chosen if `addr`'s port was 0, and a future that serves requests
+/// until it's dropped. Must be called within a Tokio runtime.
+pub fn bind(
+    server: SyncServer,
+    addr: &SocketAddr,
+) -> Result<(SocketAddr, impl Future<Output = Result<()>>)> {
+    let incoming = AddrIncoming::bind(addr)?;
+    let local_addr = incoming.local_addr();
+
+    let server = Arc::new(server);
+    let make_service = make_service_fn(move |_conn| {
+        let server = server.clone();
+        let service = service_fn(move |req| handle_request(server.clone(), req));
+        async move { Ok::<_, Infallible>(service) }
+    });
+    let serving = Server::builder(incoming).serve(make_service);
+
+    Ok((local_addr, async move { serving.await.map_err(Into::into) }))
+}
+
+async fn handle_request(
+    server: Arc<SyncServer>,
+    req: Request<Body>,
+) -> std::result::Result<Response<Body>, Infallible> {
+    let path = req.uri().path().to_string();
+    let content_type = req
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default()
+        .to_string();
+
+    let result = match read_body(req, server.max_body_bytes).await {
+        Ok(Some(body)) => {
+            // handling a request does blocking file and database I/O, so it
+            // mustn't run on the runtime's own threads
+            tokio::task::spawn_blocking(move || {
+                parse_form(&content_type, &body).and_then(|form| route(&server, &path, form))
+            })
+            .await
+            .unwrap_or_else(|err| {
+                Err(AnkiError::SyncError {
+                    info: err.to_string(),
+                })
+            })
+        }
+        Ok(None) => return Ok(response(StatusCode::PAYLOAD_TOO_LARGE, vec![])),
+        Err(err) => Err(err),
+    };
+
+    let (status, body) = match result {
+        Ok(SyncResponse::Data(data)) => (StatusCode::OK, data),
+        Ok(SyncResponse::Forbidden) => (StatusCode::FORBIDDEN, vec![]),
+        Ok(SyncResponse::Conflict) => (StatusCode::CONFLICT, vec![]),
+        Ok(SyncResponse::NotFound) => (StatusCode::NOT_FOUND, vec![]),
+        Err(err) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            err.to_string().into_bytes(),
+        ),
+    };
+    Ok(response(status, body))
+}
+
+fn response(status: StatusCode, body: Vec<u8>) -> Response<Body> {
+    let mut resp = Response::new(Body::from(body));
+    *resp.status_mut() = status;
+    resp
+}
+
+/// Read the body of `req`, or return None if it's longer than `limit`.
+/// The declared length is checked first, so an oversized upload can be
+/// refused before any of it is read.
+async fn read_body(req: Request<Body>, limit: usize) -> Result<Option<Vec<u8>>> {
+    let declared_len = req
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok());
+    if declared_len
+        .map(|len| len > limit as u64)
+        .unwrap_or_default()
+    {
+        return Ok(None);
+    }
+
+    let mut body = req.into_body();
+    let mut data = vec![];
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk?;
+        if data.len() + chunk.len() > limit {
+            return Ok(None);
+        }
+        data.extend_from_slice(&chunk);
+    }
+    Ok(Some(data))
+}
+
+fn route(server: &SyncServer, path: &str, form: SyncRequest) -> Result<SyncResponse> {
+    if path.starts_with("/sync/") {
+        server.collection_request(&path["/sync/".len()..], form)
+    } else if path.starts_with("/msync/") {
+        server.media_request(&path["/msync/".len()..], form)
+    } else {
+        Ok(SyncResponse::NotFound)
+    }
+}
+
+// Forms
+//----------------------------------------
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+/// A parameter of a header, eg the name in
+/// `Content-Disposition: form-data; name="data"`.
+fn header_param<'a>(header: &'a str, param: &str) -> Option<&'a str> {
+    header.split(';').skip(1).find_map(|part| {
+        let mut pair = part.trim().splitn(2, '=');
+        if pair.next()? == param {
+            Some(pair.next()?.trim_matches('"'))
+        } else {
+            None
+        }
+    })
+}
+
+/// Decode a multipart/form-data body. Parts with a filename are taken as
+/// the attached data; the rest are text fields.
+fn parse_form(content_type: &str, body: &[u8]) -> Result<SyncRequest> {
+    let malformed = || AnkiError::invalid_input("malformed form");
+    if !content_type.starts_with("multipart/form-data") {
+        return Err(malformed());
+    }
+    let boundary = header_param(content_type, "boundary").ok_or_else(malformed)?;
+    let delimiter = format!("--{}", boundary).into_bytes();
+    let part_end = format!("\r\n--{}", boundary).into_bytes();
+
+    let mut form = SyncRequest::default();
+    let start = find(body, &delimiter).ok_or_else(malformed)?;
+    let mut rest = &body[start + delimiter.len()..];
+    // the final delimiter is followed by --
+    while !rest.starts_with(b"--") {
+        if !rest.starts_with(b"\r\n") {
+            return Err(malformed());
+        }
+        rest = &rest[2..];
+        let headers_end = find(rest, b"\r\n\r\n").ok_or_else(malformed)?;
+        let headers = std::str::from_utf8(&rest[..headers_end]).map_err(|_| malformed())?;
+        rest = &rest[headers_end + 4..];
+        let content_end = find(rest, &part_end).ok_or_else(malformed)?;
+        let content = &rest[..content_end];
+        rest = &rest[content_end + part_end.len()..];
+
+        let disposition = headers
+            .split("\r\n")
+            .find(|line| {
+                line.to_ascii_lowercase()
+                    .starts_with("content-disposition:")
+            })
+            .ok_or_else(malformed)?;
+        let name = header_param(disposition, "name").ok_or_else(malformed)?;
+        if header_param(disposition, "filename").is_some() {
+            form.data = content.to_vec();
+        } else {
+            let text = String::from_utf8(content.to_vec()).map_err(|_| malformed())?;
+            form.fields.insert(name.to_string(), text);
+        }
+    }
+
+    Ok(form)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::err::Result;
+    use crate::sync::server::http::parse_form;
+    use crate::sync::server::{bind, SyncServer};
+    use reqwest::StatusCode;
+    use tempfile::tempdir;
+    use tokio::runtime::Builder;
+
+    #[test]
+    fn test_parse_form() -> Result<()> {
+        let body = b"--XyZ\r\nContent-Disposition: form-data; name=\"c\"\r\n\r\n1\r\n\
+--XyZ\r\nContent-Disposition: form-data; name=\"data\"; filename=\"data\"\r\n\
+Content-Type: application/octet-stream\r\n\r\n\x1f\x8b\r\n--X\r\n--XyZ--\r\n";
+        let form = parse_form("multipart/form-data; boundary=XyZ", body)?;
+        assert_eq!(form.field("c"), Some("1"));
+        assert_eq!(form.field("data"), None);
+        // the data may contain anything but the delimiter
+        assert_eq!(form.data, b"\x1f\x8b\r\n--X");
+
+        assert!(parse_form("multipart/form-data; boundary=XyZ", b"--XyZ\r\nbad").is_err());
+        assert!(parse_form("application/json", b"{}").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_body_limit() -> Result<()> {
+        let dir = tempdir()?;
+        let mut server = SyncServer::new(dir.path(), vec![])?;
+        server.set_max_body_bytes(100);
+        let mut rt = Builder::new().basic_scheduler().enable_all().build()?;
+
+        rt.block_on(async {
+            let (addr, serving) = bind(server, &"127.0.0.1:0".parse().unwrap())?;
+            tokio::spawn(serving);
+            let url = format!("http://{}/sync/hostKey", addr);
+            let client = reqwest::Client::new();
+
+            let resp = client.post(&url).body(vec![0; 101]).send().await?;
+            assert_eq!(resp.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+            // a body within the limit is passed on, and rejected as it's
+            // not a form
+            let resp = client.post(&url).body(vec![0; 100]).send().await?;
+            assert_eq!(resp.status(), StatusCode::INTERNAL_SERVER_ERROR);
+
+            Ok(())
+        })
+    }
+}
@@ -0,0 +1,293 @@
+// Copyright: Ankitects Pty Ltd and contributors
+// License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+//! The server's side of a media sync. Every change to a user's media
+//! folder is recorded with the next usn, so clients can fetch the changes
+//! made since their last sync.
+
+use crate::err::{AnkiError, Result};
+use crate::media::files::{check_media_filename, sha1_of_data, MEDIA_SYNC_FILESIZE_LIMIT};
+use rusqlite::{params, Connection, OptionalExtension, NO_PARAMS};
+use serde_derive::Deserialize;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use tempfile::NamedTempFile;
+
+/// The maximum number of changes sent in reply to a single request.
+const CHANGES_LIMIT: u32 = 250;
+
+/// Each file's checksum, and the usn of its last change. Deleted files
+/// keep a record with an empty checksum, so the deletion can be synced.
+pub(super) struct ServerMediaDatabase {
+    db: Connection,
+}
+
+impl ServerMediaDatabase {
+    pub(super) fn open(path: &Path) -> Result<Self> {
+        let db = Connection::open(path)?;
+        db.execute_batch(
+            "create table if not exists media (
+  fname text not null primary key,
+  csum text not null,
+  usn integer not null
+);
+create index if not exists ix_media_usn on media (usn);",
+        )?;
+        Ok(ServerMediaDatabase { db })
+    }
+
+    /// The usn of the latest change. A file's usn only ever increases, so
+    /// this is never reused.
+    pub(super) fn last_usn(&self) -> Result<i32> {
+        self.db
+            .query_row(
+                "select coalesce(max(usn), 0) from media",
+                NO_PARAMS,
+                |row| row.get(0),
+            )
+            .map_err(Into::into)
+    }
+
+    /// Changes after `usn`, oldest first, as [fname, usn, checksum].
+    fn changes_since(&self, usn: i32) -> Result<Vec<(String, i32, String)>> {
+        self.db
+            .prepare("select fname, usn, csum from media where usn > ? order by usn limit ?")?
+            .query_map(params![usn, CHANGES_LIMIT], |row| {
+                Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+            })?
+            .collect::<rusqlite::Result<_>>()
+            .map_err(Into::into)
+    }
+
+    fn checksum(&self, fname: &str) -> Result<Option<String>> {
+        self.db
+            .prepare_cached("select csum from media where fname = ?")?
+            .query_row(params![fname], |row| row.get(0))
+            .optional()
+            .map_err(Into::into)
+    }
+
+    /// Record an addition or change, or a deletion if `csum` is empty,
+    /// returning its usn.
+    fn record_change(&self, fname: &str, csum: &str) -> Result<i32> {
+        let usn = self.last_usn()? + 1;
+        self.db
+            .prepare_cached("insert or replace into media (fname, csum, usn) values (?, ?, ?)")?
+            .execute(params![fname, csum, usn])?;
+        Ok(usn)
+    }
+
+    /// The number of files that haven't been deleted.
+    fn file_count(&self) -> Result<u32> {
+        self.db
+            .query_row(
+                "select count() from media where csum != ''",
+                NO_PARAMS,
+                |row| row.get(0),
+            )
+            .map_err(Into::into)
+    }
+
+    fn transact<F, R>(&mut self, func: F) -> Result<R>
+    where
+        F: FnOnce(&ServerMediaDatabase) -> Result<R>,
+    {
+        self.db.execute_batch("begin")?;
+        let result = func(self);
+        if result.is_ok() {
+            self.db.execute_batch("commit")?;
+        } else {
+            self.db.execute_batch("rollback")?;
+        }
+        result
+    }
+}
+
+fn remove_file_if_exists(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// The changes after the client's last sync.
+pub(super) fn media_changes(db: &ServerMediaDatabase, data: &[u8]) -> Result<Value> {
+    #[derive(Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    struct MediaChangesIn {
+        last_usn: i32,
+    }
+
+    let input: MediaChangesIn = serde_json::from_slice(data)?;
+    Ok(serde_json::to_value(db.changes_since(input.last_usn)?)?)
+}
+
+/// A zip of the requested files, in the order requested. The meta file
+/// maps names in the zip to real filenames.
+pub(super) fn download_files(media_folder: &Path, data: &[u8]) -> Result<Vec<u8>> {
+    #[derive(Deserialize)]
+    struct DownloadFilesIn {
+        files: Vec<String>,
+    }
+
+    let input: DownloadFilesIn = serde_json::from_slice(data)?;
+    let mut zip = zip::ZipWriter::new(io::Cursor::new(vec![]));
+    let options =
+        zip::write::FileOptions::default().compression_method(zip::CompressionMethod::Deflated);
+
+    let mut meta = HashMap::new();
+    for (idx, fname) in input.files.iter().enumerate() {
+        check_media_filename(fname)?;
+        let file_data = fs::read(media_folder.join(fname))?;
+        let zip_name = idx.to_string();
+        zip.start_file(zip_name.as_str(), options)?;
+        zip.write_all(&file_data)?;
+        meta.insert(zip_name, fname);
+    }
+    zip.start_file("_meta", options)?;
+    zip.write_all(&serde_json::to_vec(&meta)?)?;
+
+    Ok(zip.finish()?.into_inner())
+}
+
+/// Apply the additions and deletions in a zip from the client. The meta
+/// file lists each change as [fname, name in zip], where an empty name in
+/// the zip is a deletion. Returns the number of changes applied and the
+/// new usn.
+///
+/// The changes are recorded in a transaction, and the media folder is only
+/// altered once it has been committed. New files are written to temporary
+/// files in the meantime, which are removed if the upload is rejected.
+pub(super) fn upload_changes(
+    db: &mut ServerMediaDatabase,
+    media_folder: &Path,
+    data: &[u8],
+) -> Result<Value> {
+    let mut zip = zip::ZipArchive::new(io::Cursor::new(data))?;
+    let meta: Vec<(String, String)> = serde_json::from_reader(zip.by_name("_meta")?)?;
+
+    let mut pending = vec![];
+    let result = db.transact(|db| {
+        for (fname, zip_name) in &meta {
+            check_media_filename(fname)?;
+            let path = media_folder.join(fname);
+            if zip_name.is_empty() {
+                db.record_change(fname, "")?;
+                pending.push(PendingChange::Delete(path));
+            } else {
+                let file_data = read_zip_entry(&mut zip, zip_name, fname)?;
+                let csum = hex::encode(sha1_of_data(&file_data));
+                if db.checksum(fname)?.as_deref() != Some(csum.as_str()) {
+                    let mut temp = NamedTempFile::new_in(media_folder)?;
+                    temp.write_all(&file_data)?;
+                    pending.push(PendingChange::Write(temp, path));
+                }
+                db.record_change(fname, &csum)?;
+            }
+        }
+        Ok(serde_json::to_value((meta.len(), db.last_usn()?))?)
+    })?;
+
+    for change in pending {
+        match change {
+            PendingChange::Write(temp, path) => {
+                temp.persist(&path).map_err(|e| e.error)?;
+            }
+            PendingChange::Delete(path) => remove_file_if_exists(&path)?,
+        }
+    }
+
+    Ok(result)
+}
+
+/// A change to the media folder, applied after the database is updated.
+enum PendingChange {
+    Write(NamedTempFile, PathBuf),
+    Delete(PathBuf),
+}
+
+/// The contents of an uploaded file, which may not be larger than the
+/// files the client is allowed to send.
+fn read_zip_entry(
+    zip: &mut zip::ZipArchive<io::Cursor<&[u8]>>,
+    zip_name: &str,
+    fname: &str,
+) -> Result<Vec<u8>> {
+    let mut file_data = vec![];
+    zip.by_name(zip_name)?
+        .take(MEDIA_SYNC_FILESIZE_LIMIT + 1)
+        .read_to_end(&mut file_data)?;
+    if file_data.len() as u64 > MEDIA_SYNC_FILESIZE_LIMIT {
+        Err(AnkiError::invalid_input(format!(
+            "file too large: {}",
+            fname
+        )))
+    } else {
+        Ok(file_data)
+    }
+}
+
+/// "OK" if the client holds as many files as the server.
+pub(super) fn media_sanity(db: &ServerMediaDatabase, data: &[u8]) -> Result<Value> {
+    #[derive(Deserialize)]
+    struct MediaSanityIn {
+        local: u32,
+    }
+
+    let input: MediaSanityIn = serde_json::from_slice(data)?;
+    Ok(Value::from(if input.local == db.file_count()? {
+        "OK"
+    } else {
+        "FAILED"
+    }))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::err::Result;
+    use crate::sync::server::media::{upload_changes, ServerMediaDatabase};
+    use std::fs;
+    use std::io::{self, Write};
+    use tempfile::tempdir;
+    use zip::write::FileOptions;
+    use zip::ZipWriter;
+
+    fn upload_zip(files: &[(&str, &str)]) -> Result<Vec<u8>> {
+        let mut zip = ZipWriter::new(io::Cursor::new(vec![]));
+        let mut meta = vec![];
+        for (idx, (fname, data)) in files.iter().enumerate() {
+            zip.start_file(idx.to_string().as_str(), FileOptions::default())?;
+            zip.write_all(data.as_bytes())?;
+            meta.push((fname, idx.to_string()));
+        }
+        zip.start_file("_meta", FileOptions::default())?;
+        zip.write_all(&serde_json::to_vec(&meta)?)?;
+        Ok(zip.finish()?.into_inner())
+    }
+
+    #[test]
+    fn test_upload_changes() -> Result<()> {
+        let dir = tempdir()?;
+        let media = dir.path().join("media");
+        fs::create_dir(&media)?;
+        let mut db = ServerMediaDatabase::open(&dir.path().join("media.db"))?;
+
+        // a rejected upload leaves the folder and database untouched
+        let data = upload_zip(&[("foo.jpg", "foo"), ("../bar.jpg", "bar")])?;
+        assert!(upload_changes(&mut db, &media, &data).is_err());
+        assert_eq!(fs::read_dir(&media)?.count(), 0);
+        assert!(!dir.path().join("bar.jpg").exists());
+        assert_eq!(db.last_usn()?, 0);
+
+        let data = upload_zip(&[("foo.jpg", "foo")])?;
+        let result = upload_changes(&mut db, &media, &data)?;
+        assert_eq!(result, serde_json::json!([1, 1]));
+        assert_eq!(fs::read_dir(&media)?.count(), 1);
+        assert_eq!(fs::read(media.join("foo.jpg"))?, b"foo");
+
+        Ok(())
+    }
+}
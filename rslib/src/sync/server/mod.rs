@@ -0,0 +1,446 @@
+// Copyright: Ankitects Pty Ltd and contributors
+// License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+//! A sync server that collections and media can be synced with in place of
+//! AnkiWeb, speaking the same protocols as the client in this crate.
+//!
+//! Each user's data is kept in a folder of their own under the base
+//! folder: the collection, the media folder, and a database recording the
+//! changes made to the media folder.
+
+mod collection;
+mod http;
+mod media;
+
+pub use http::bind;
+
+use crate::err::Result;
+use crate::media::files::sha1_of_data;
+use crate::sync::http_client::{gzipped, maybe_gunzipped};
+use collection::{
+    collection_for_download, receive_upload, server_meta, CollectionSession, SessionOutcome,
+};
+use media::ServerMediaDatabase;
+use rand::Rng;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_derive::Deserialize;
+use serde_json::json;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// The largest request body accepted by default, which leaves room for
+/// full uploads of the largest collections AnkiWeb accepts.
+pub const DEFAULT_MAX_BODY_BYTES: usize = 300 * 1024 * 1024;
+
+/// A user the server accepts.
+#[derive(Debug, Clone)]
+pub struct SyncUser {
+    pub username: String,
+    pub password: String,
+}
+
+pub struct SyncServer {
+    /// Keyed by username.
+    users: Mutex<HashMap<String, UserState>>,
+    /// Larger request bodies are refused.
+    max_body_bytes: usize,
+}
+
+struct UserState {
+    password: String,
+    /// Given to the client in place of the password.
+    hkey: String,
+    folder: PathBuf,
+    /// The normal sync in progress, if any.
+    collection: Option<CollectionSession>,
+    /// The key of the latest media sync.
+    media_skey: Option<String>,
+}
+
+impl UserState {
+    fn col_path(&self) -> PathBuf {
+        self.folder.join("collection.anki2")
+    }
+
+    fn media_folder(&self) -> PathBuf {
+        self.folder.join("collection.media")
+    }
+
+    fn media_db(&self) -> Result<ServerMediaDatabase> {
+        ServerMediaDatabase::open(&self.folder.join("media.server.db"))
+    }
+
+    /// Roll back the normal sync in progress, if any.
+    fn end_session(&mut self) {
+        self.collection = None;
+    }
+}
+
+/// A request, decoded from the form the client posted.
+#[derive(Debug, Default)]
+pub(super) struct SyncRequest {
+    fields: HashMap<String, String>,
+    /// The attached file.
+    data: Vec<u8>,
+}
+
+impl SyncRequest {
+    fn field(&self, name: &str) -> Option<&str> {
+        self.fields.get(name).map(String::as_str)
+    }
+
+    /// Decode the attached JSON, which the collection protocol gzips.
+    fn json<T: DeserializeOwned>(&self) -> Result<T> {
+        serde_json::from_slice(&maybe_gunzipped(&self.data)?).map_err(Into::into)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub(super) enum SyncResponse {
+    Data(Vec<u8>),
+    /// The credentials or key were not valid.
+    Forbidden,
+    /// The request was from a session that has since been replaced.
+    Conflict,
+    NotFound,
+}
+
+fn json_response<T: Serialize>(value: &T) -> Result<SyncResponse> {
+    Ok(SyncResponse::Data(serde_json::to_vec(value)?))
+}
+
+/// The secret host keys are derived from, which is created on first use,
+/// so clients stay logged in when the server is restarted.
+fn load_or_create_secret(base_folder: &Path) -> Result<String> {
+    let path = base_folder.join("secret");
+    if path.exists() {
+        return Ok(fs::read_to_string(path)?.trim().to_string());
+    }
+    let secret = format!("{:016x}", rand::thread_rng().gen::<u64>());
+    fs::write(path, &secret)?;
+    Ok(secret)
+}
+
+impl SyncServer {
+    /// Serve `users`, keeping their data in `base_folder`, which is created
+    /// if needed.
+    pub fn new<P: Into<PathBuf>>(base_folder: P, users: Vec<SyncUser>) -> Result<Self> {
+        let base_folder = base_folder.into();
+        fs::create_dir_all(&base_folder)?;
+        let secret = load_or_create_secret(&base_folder)?;
+
+        let mut states = HashMap::new();
+        for user in users {
+            let folder = base_folder.join(&user.username);
+            fs::create_dir_all(folder.join("collection.media"))?;
+            let hkey = hex::encode(sha1_of_data(
+                format!("{}:{}:{}", secret, user.username, user.password).as_bytes(),
+            ));
+            states.insert(
+                user.username,
+                UserState {
+                    password: user.password,
+                    hkey,
+                    folder,
+                    collection: None,
+                    media_skey: None,
+                },
+            );
+        }
+
+        Ok(SyncServer {
+            users: Mutex::new(states),
+            max_body_bytes: DEFAULT_MAX_BODY_BYTES,
+        })
+    }
+
+    /// Refuse requests whose body is larger than `bytes`, instead of
+    /// [DEFAULT_MAX_BODY_BYTES].
+    pub fn set_max_body_bytes(&mut self, bytes: usize) {
+        self.max_body_bytes = bytes;
+    }
+
+    /// Handle a request to /sync/`method`.
+    pub(super) fn collection_request(
+        &self,
+        method: &str,
+        req: SyncRequest,
+    ) -> Result<SyncResponse> {
+        let mut users = self.users.lock().unwrap();
+
+        if method == "hostKey" {
+            #[derive(Deserialize)]
+            struct HostKeyIn {
+                #[serde(rename = "u")]
+                username: String,
+                #[serde(rename = "p")]
+                password: String,
+            }
+            let input: HostKeyIn = req.json()?;
+            return match users.get(&input.username) {
+                Some(user) if user.password == input.password => {
+                    json_response(&json!({ "key": user.hkey }))
+                }
+                _ => Ok(SyncResponse::Forbidden),
+            };
+        }
+
+        let hkey = req.field("k").unwrap_or_default();
+        let (username, user) = match users.iter_mut().find(|(_, u)| u.hkey == hkey) {
+            Some(found) => found,
+            None => return Ok(SyncResponse::Forbidden),
+        };
+
+        match method {
+            "meta" => {
+                #[derive(Deserialize)]
+                struct MetaIn {
+                    v: u8,
+                }
+                let input: MetaIn = req.json()?;
+                let media_usn = user.media_db()?.last_usn()?;
+                json_response(&server_meta(
+                    &user.col_path(),
+                    input.v,
+                    username,
+                    media_usn,
+                )?)
+            }
+            "upload" => {
+                user.end_session();
+                let accepted = receive_upload(&user.col_path(), req.data)?;
+                Ok(SyncResponse::Data(if accepted {
+                    b"OK".to_vec()
+                } else {
+                    b"collection failed its checks".to_vec()
+                }))
+            }
+            "download" => {
+                user.end_session();
+                let data = collection_for_download(&user.col_path())?;
+                Ok(SyncResponse::Data(gzipped(&data)?))
+            }
+            "start" => {
+                // a client that starts again has given up on its old session
+                user.end_session();
+                let skey = req.field("s").unwrap_or_default().to_string();
+                let (session, graves) =
+                    CollectionSession::start(&user.col_path(), skey, req.json()?)?;
+                user.collection = Some(session);
+                json_response(&graves)
+            }
+            _ => {
+                let session = match &mut user.collection {
+                    Some(session) if Some(session.skey.as_str()) == req.field("s") => session,
+                    _ => return Ok(SyncResponse::Conflict),
+                };
+                match session.handle(method, &req) {
+                    Ok(SessionOutcome::Continue(resp)) => Ok(resp),
+                    Ok(SessionOutcome::Ended(resp)) => {
+                        user.end_session();
+                        Ok(resp)
+                    }
+                    Err(err) => {
+                        user.end_session();
+                        Err(err)
+                    }
+                }
+            }
+        }
+    }
+
+    /// Handle a request to /msync/`method`. Errors are sent to the client
+    /// in the reply.
+    pub(super) fn media_request(&self, method: &str, req: SyncRequest) -> Result<SyncResponse> {
+        let mut users = self.users.lock().unwrap();
+
+        let user = if method == "begin" {
+            let hkey = req.field("k").unwrap_or_default();
+            users.values_mut().find(|u| u.hkey == hkey)
+        } else {
+            let skey = req.field("sk");
+            users
+                .values_mut()
+                .find(|u| u.media_skey.is_some() && u.media_skey.as_deref() == skey)
+        };
+        let user = match user {
+            Some(user) => user,
+            None => return Ok(SyncResponse::Forbidden),
+        };
+
+        let data = match method {
+            "begin" => {
+                let skey = format!("{:08x}", rand::thread_rng().gen::<u32>());
+                user.media_skey = Some(skey.clone());
+                user.media_db()?
+                    .last_usn()
+                    .map(|usn| json!({ "sk": skey, "usn": usn }))
+            }
+            "mediaChanges" => media::media_changes(&user.media_db()?, &req.data),
+            "downloadFiles" => {
+                return match media::download_files(&user.media_folder(), &req.data) {
+                    Ok(zip) => Ok(SyncResponse::Data(zip)),
+                    Err(err) => json_response(&json!({"data": null, "err": err.to_string()})),
+                }
+            }
+            "uploadChanges" => {
+                media::upload_changes(&mut user.media_db()?, &user.media_folder(), &req.data)
+            }
+            "mediaSanity" => media::media_sanity(&user.media_db()?, &req.data),
+            _ => return Ok(SyncResponse::NotFound),
+        };
+
+        json_response(&match data {
+            Ok(data) => json!({"data": data, "err": ""}),
+            Err(err) => json!({"data": null, "err": err.to_string()}),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::err::{AnkiError, Result};
+    use crate::media::sync::{MediaSyncOutcome, MediaSyncProgress};
+    use crate::media::MediaManager;
+    use crate::storage::SqliteStorage;
+    use crate::sync::server::{bind, SyncServer, SyncUser};
+    use crate::sync::{
+        full_download, full_sync_preview, full_upload, sync_collection, sync_login,
+        CollectionFormat, FullSyncOutcome, NetworkSettings, SyncOutcome,
+    };
+    use std::fs;
+    use std::sync::Mutex;
+    use tempfile::tempdir;
+    use tokio::runtime::Builder;
+
+    #[test]
+    fn test_sync_with_server() -> Result<()> {
+        let dir = tempdir()?;
+        let user = SyncUser {
+            username: "user".into(),
+            password: "pass".into(),
+        };
+        let server = SyncServer::new(dir.path().join("server"), vec![user])?;
+        let mut rt = Builder::new().basic_scheduler().enable_all().build()?;
+
+        rt.block_on(async {
+            let (addr, serving) = bind(server, &"127.0.0.1:0".parse().unwrap())?;
+            tokio::spawn(serving);
+            let endpoint = format!("http://{}/sync/", addr);
+            let media_endpoint = format!("http://{}/msync/", addr);
+            let net = NetworkSettings::default();
+
+            assert_eq!(
+                sync_login("user", "wrong", &endpoint, &net, "test").await?,
+                None
+            );
+            let hkey = sync_login("user", "pass", &endpoint, &net, "test")
+                .await?
+                .unwrap();
+
+            // the server's collection starts out empty, so the first sync
+            // is a full one
+            let col1 = dir.path().join("col1.anki2");
+            let storage = SqliteStorage::open_or_create(&col1)?;
+            let output =
+                sync_collection(&storage, &hkey, &endpoint, &net, "test", |_| true).await?;
+            assert_eq!(output.outcome, SyncOutcome::FullSyncRequired);
+            assert_eq!(output.username, "user");
+            drop(storage);
+            let outcome = full_upload(
+                &col1,
+                CollectionFormat::Legacy,
+                &hkey,
+                &endpoint,
+                &net,
+                "test",
+                |_| true,
+            )
+            .await?;
+            assert_eq!(outcome, FullSyncOutcome::Success);
+            let col2 = dir.path().join("col2.anki2");
+            let outcome =
+                full_download(&col2, None, &hkey, &endpoint, &net, "test", |_| true).await?;
+            assert_eq!(outcome, FullSyncOutcome::Success);
+
+            // a rejected host key is reported as such, not as a network
+            // failure
+            let res = full_download(&col2, None, "bad", &endpoint, &net, "test", |_| true).await;
+            match res.err() {
+                Some(AnkiError::SyncAuthError) => (),
+                other => panic!("unexpected: {:?}", other),
+            }
+
+            // after that, changes are exchanged in normal syncs
+            let storage = SqliteStorage::open_or_create(&col1)?;
+            storage
+                .db
+                .execute_batch("insert into revlog values (1, 1, -1, 3, 1, 0, 2500, 6, 0)")?;
+            storage.mark_modified(storage.modified_millis()? + 1)?;
+            let output =
+                sync_collection(&storage, &hkey, &endpoint, &net, "test", |_| true).await?;
+            assert_eq!(output.outcome, SyncOutcome::Success);
+            let storage = SqliteStorage::open_or_create(&col2)?;
+            let output =
+                sync_collection(&storage, &hkey, &endpoint, &net, "test", |_| true).await?;
+            assert_eq!(output.outcome, SyncOutcome::Success);
+            assert_eq!(storage.row_counts("revlog")?, (1, 0));
+            let output =
+                sync_collection(&storage, &hkey, &endpoint, &net, "test", |_| true).await?;
+            assert_eq!(output.outcome, SyncOutcome::NoChanges);
+
+            // when a full sync is required, the changes each side would lose
+            // can be previewed
+            storage
+                .db
+                .execute_batch("insert into revlog values (2, 1, -1, 3, 1, 0, 2500, 6, 0)")?;
+            storage.mark_modified(storage.modified_millis()? + 1)?;
+            sync_collection(&storage, &hkey, &endpoint, &net, "test", |_| true).await?;
+            let storage = SqliteStorage::open_or_create(&col1)?;
+            storage.db.execute_batch(
+                "insert into revlog values (3, 1, -1, 3, 1, 0, 2500, 6, 0);
+insert into revlog values (4, 1, -1, 3, 1, 0, 2500, 6, 0);",
+            )?;
+            storage.mark_schema_modified(storage.modified_millis()? + 1)?;
+            let preview = full_sync_preview(&storage, &hkey, &endpoint, &net, "test").await?;
+            assert_eq!((preview.local.reviews, preview.remote.reviews), (2, 1));
+            assert!(!preview.remote_replaced);
+
+            // media is synced between the devices too
+            let mut media = vec![];
+            for name in &["media1", "media2"] {
+                let folder = dir.path().join(name);
+                fs::create_dir(&folder)?;
+                let mgr = MediaManager::new(&folder, dir.path().join(format!("{}.db", name)))?;
+                media.push((folder, mgr));
+            }
+            media[0].1.add_file("a.jpg", b"hello")?;
+            // the folder isn't locked between the steps of a sync
+            let media_lock = Mutex::new(());
+            let progress = |_: &MediaSyncProgress| media_lock.try_lock().is_ok();
+            for (_, mgr) in &mut media {
+                let outcome = mgr
+                    .sync_media(progress, &media_endpoint, &net, &hkey, "test", &media_lock)
+                    .await?;
+                assert_eq!(outcome, MediaSyncOutcome::Synced);
+            }
+            assert_eq!(fs::read(media[1].0.join("a.jpg"))?, b"hello");
+
+            // and so are deletions
+            fs::remove_file(media[1].0.join("a.jpg"))?;
+            media[1].1.register_changes(true)?;
+            for (_, mgr) in media.iter_mut().rev() {
+                let outcome = mgr
+                    .sync_media(progress, &media_endpoint, &net, &hkey, "test", &media_lock)
+                    .await?;
+                assert_eq!(outcome, MediaSyncOutcome::Synced);
+            }
+            assert!(!media[0].0.join("a.jpg").exists());
+
+            Ok(())
+        })
+    }
+}
@@ -0,0 +1,564 @@
+// Copyright: Ankitects Pty Ltd and contributors
+// License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+//! Adding, removing and renaming tags. Tags are compared ignoring case, and each tag
+//! used is registered in the collection's tag list, so it shows up in the
+//! browser. Tags containing :: form a hierarchy, like deck names.
+
+use crate::err::{AnkiError, Result};
+use crate::notes::{join_tags, split_tags, Note};
+use crate::search::names_match;
+use crate::storage::SqliteStorage;
+use crate::text::normalize_for_search;
+use regex::Regex;
+use rusqlite::{params, NO_PARAMS};
+use std::collections::{HashMap, HashSet};
+
+/// Separates the components of a hierarchical tag.
+const TAG_SEPARATOR: &str = "::";
+
+/// Add the space-separated `tags` to the notes in `note_ids` that don't
+/// already have them. Like the legacy code, quotes are removed, the case
+/// of tags already in the collection is used, and the tags of changed
+/// notes are sorted. Returns the changed notes as they were before.
+pub fn add_tags(
+    storage: &SqliteStorage,
+    note_ids: &[i64],
+    tags: &str,
+    usn: i32,
+    mtime_secs: i64,
+) -> Result<Vec<Note>> {
+    let new_tags: Vec<_> = split_tags(tags)
+        .map(|tag| tag.replace(&['"', '\''][..], ""))
+        .filter(|tag| !tag.is_empty())
+        .collect();
+    if new_tags.is_empty() {
+        return Ok(vec![]);
+    }
+    let new_tags = register_tags(storage, &new_tags, usn)?;
+
+    update_note_tags(storage, note_ids, usn, mtime_secs, |note_tags| {
+        let before = note_tags.len();
+        for tag in &new_tags {
+            if !note_tags.iter().any(|t| names_match(t, tag)) {
+                note_tags.push(tag.clone());
+            }
+        }
+        if note_tags.len() == before {
+            return false;
+        }
+        note_tags.sort();
+        true
+    })
+}
+
+/// Remove the space-separated `tags` from the notes in `note_ids`. Case is
+/// ignored, and * matches any text. Returns the changed notes as they were
+/// before.
+pub fn remove_tags(
+    storage: &SqliteStorage,
+    note_ids: &[i64],
+    tags: &str,
+    usn: i32,
+    mtime_secs: i64,
+) -> Result<Vec<Note>> {
+    let matchers = split_tags(tags)
+        .map(|tag| {
+            let pattern = regex::escape(tag).replace(r"\*", ".*");
+            Regex::new(&format!("(?i)^{}$", pattern))
+                .map_err(|err| AnkiError::invalid_input(err.to_string()))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    if matchers.is_empty() {
+        return Ok(vec![]);
+    }
+
+    update_note_tags(storage, note_ids, usn, mtime_secs, |note_tags| {
+        let before = note_tags.len();
+        note_tags.retain(|tag| !matchers.iter().any(|m| m.is_match(tag)));
+        note_tags.len() != before
+    })
+}
+
+/// Call `update` with the tags of each note, saving the notes for which it
+/// returns true. Returns those notes as they were before.
+fn update_note_tags<F>(
+    storage: &SqliteStorage,
+    note_ids: &[i64],
+    usn: i32,
+    mtime_secs: i64,
+    mut update: F,
+) -> Result<Vec<Note>>
+where
+    F: FnMut(&mut Vec<String>) -> bool,
+{
+    let mut previous = vec![];
+    for (id, existing) in storage.get_note_tags(note_ids)? {
+        let mut note_tags: Vec<String> = split_tags(&existing).map(Into::into).collect();
+        if update(&mut note_tags) {
+            previous.extend(storage.get_note(id)?);
+            storage.set_note_tags(id, &join_tags(&note_tags), mtime_secs, usn)?;
+        }
+    }
+    Ok(previous)
+}
+
+/// Add any tags not already in the collection's tag list. Returns the tags
+/// as they are spelt in the list.
+pub(crate) fn register_tags<S: AsRef<str>>(
+    storage: &SqliteStorage,
+    tags: &[S],
+    usn: i32,
+) -> Result<Vec<String>> {
+    let mut registered = storage.get_all_tags()?;
+    let mut changed = false;
+    let mut canonical = Vec::with_capacity(tags.len());
+    for tag in tags {
+        let tag = tag.as_ref();
+        match registered.keys().find(|t| names_match(t, tag)) {
+            Some(existing) => canonical.push(existing.clone()),
+            None => {
+                registered.insert(tag.to_string(), usn);
+                canonical.push(tag.to_string());
+                changed = true;
+            }
+        }
+    }
+    if changed {
+        storage.set_all_tags(&registered)?;
+    }
+    Ok(canonical)
+}
+
+/// Tags as the legacy code stores them: without duplicates, ignoring case,
+/// and sorted.
+pub(crate) fn canonical_tags(tags: impl IntoIterator<Item = String>) -> Vec<String> {
+    let mut seen = HashSet::new();
+    let mut tags: Vec<_> = tags
+        .into_iter()
+        .map(|tag| tag.trim().to_string())
+        .filter(|tag| !tag.is_empty() && seen.insert(tag.to_lowercase()))
+        .collect();
+    tags.sort_unstable_by_key(|tag| tag.to_lowercase());
+    tags
+}
+
+// Tag tree
+//----------------------------------------
+
+/// A tag and its children, sorted by name.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TagTreeNode {
+    /// The last component of the tag.
+    pub name: String,
+    /// The full tag, for searching.
+    pub full_name: String,
+    /// The number of notes with this tag or one of its descendants.
+    pub note_count: u32,
+    pub children: Vec<TagTreeNode>,
+}
+
+/// Tags are grouped ignoring case.
+fn tag_key(tag: &str) -> String {
+    normalize_for_search(tag, false).into_owned()
+}
+
+/// The tag and each of its parents.
+fn tag_and_parents(tag: &str) -> impl Iterator<Item = &str> {
+    tag.match_indices(TAG_SEPARATOR)
+        .map(move |(idx, _)| &tag[..idx])
+        .chain(std::iter::once(tag))
+}
+
+/// The registered tags as a tree. Parents that are not registered
+/// themselves are included, so every tag can be reached.
+pub fn tag_tree(storage: &SqliteStorage) -> Result<Vec<TagTreeNode>> {
+    let mut top = vec![];
+    for tag in storage.get_all_tags()?.keys() {
+        let components: Vec<_> = tag.split(TAG_SEPARATOR).collect();
+        add_to_tree(&mut top, &components, 0);
+    }
+    let counts = note_counts(storage, true)?;
+    finish_tree(&mut top, &counts);
+    Ok(top)
+}
+
+fn add_to_tree(nodes: &mut Vec<TagTreeNode>, components: &[&str], depth: usize) {
+    let name = components[depth];
+    let idx = match nodes.iter().position(|n| names_match(&n.name, name)) {
+        Some(idx) => idx,
+        None => {
+            nodes.push(TagTreeNode {
+                name: name.into(),
+                full_name: components[..=depth].join(TAG_SEPARATOR),
+                note_count: 0,
+                children: vec![],
+            });
+            nodes.len() - 1
+        }
+    };
+    if depth + 1 < components.len() {
+        add_to_tree(&mut nodes[idx].children, components, depth + 1);
+    }
+}
+
+fn finish_tree(nodes: &mut Vec<TagTreeNode>, counts: &HashMap<String, u32>) {
+    nodes.sort_by_cached_key(|n| tag_key(&n.name));
+    for node in nodes {
+        node.note_count = counts
+            .get(&tag_key(&node.full_name))
+            .cloned()
+            .unwrap_or_default();
+        finish_tree(&mut node.children, counts);
+    }
+}
+
+/// The number of notes using each tag, keyed by tag_key(). If
+/// `include_children` is true, notes using the tag's descendants are
+/// counted too.
+fn note_counts(storage: &SqliteStorage, include_children: bool) -> Result<HashMap<String, u32>> {
+    let mut counts: HashMap<String, u32> = HashMap::new();
+    let mut stmt = storage
+        .db
+        .prepare("select tags from notes where tags != ''")?;
+    let mut rows = stmt.query(NO_PARAMS)?;
+    while let Some(row) = rows.next()? {
+        let tags: String = row.get(0)?;
+        // a note with both a parent and its child is only counted once
+        let mut seen = HashSet::new();
+        for tag in split_tags(&tags) {
+            let key = tag_key(tag);
+            if include_children {
+                for parent in tag_and_parents(&key) {
+                    seen.insert(parent.to_string());
+                }
+            } else {
+                seen.insert(key);
+            }
+        }
+        for key in seen {
+            *counts.entry(key).or_default() += 1;
+        }
+    }
+    Ok(counts)
+}
+
+// Renaming
+//----------------------------------------
+
+/// If `tag` is `old` or one of its descendants, the tag it should be
+/// renamed to.
+fn renamed_tag(tag: &str, old: &str, new: &str) -> Option<String> {
+    let prefix = tag.get(..old.len())?;
+    if !names_match(prefix, old) {
+        return None;
+    }
+    let rest = &tag[old.len()..];
+    if rest.is_empty() || rest.starts_with(TAG_SEPARATOR) {
+        Some(format!("{}{}", new, rest))
+    } else {
+        None
+    }
+}
+
+/// Rename `old` and its descendants to `new` in every note and in the tag
+/// list. If `new` is already in use, the tags are merged. Returns the
+/// changed notes as they were before.
+pub fn rename_tag(
+    storage: &SqliteStorage,
+    old: &str,
+    new: &str,
+    usn: i32,
+    mtime_secs: i64,
+) -> Result<Vec<Note>> {
+    let new = new.trim();
+    if old.is_empty() || new.is_empty() {
+        return Err(AnkiError::invalid_input("tags can't be empty"));
+    }
+    if split_tags(new).count() != 1 {
+        return Err(AnkiError::invalid_input("tags can't contain spaces"));
+    }
+
+    // like ignores case for ASCII only, so can't narrow down other tags
+    let pattern = if old.is_ascii() {
+        let escaped = old
+            .replace('\\', "\\\\")
+            .replace('%', "\\%")
+            .replace('_', "\\_");
+        format!("% {}%", escaped)
+    } else {
+        "%".into()
+    };
+    let mut stmt = storage
+        .db
+        .prepare("select id, tags from notes where tags like ? escape '\\'")?;
+    let notes: Vec<(i64, String)> = stmt
+        .query_map(params![pattern], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<rusqlite::Result<_>>()?;
+
+    let mut previous = vec![];
+    for (id, tags) in notes {
+        let mut updated: Vec<String> = vec![];
+        let mut renamed_any = false;
+        for tag in split_tags(&tags) {
+            let tag = match renamed_tag(tag, old, new) {
+                Some(tag) => {
+                    renamed_any = true;
+                    tag
+                }
+                None => tag.to_string(),
+            };
+            // merging may leave the note with the same tag twice
+            if !updated.iter().any(|t| names_match(t, &tag)) {
+                updated.push(tag);
+            }
+        }
+        if renamed_any {
+            previous.extend(storage.get_note(id)?);
+            storage.set_note_tags(id, &join_tags(&updated), mtime_secs, usn)?;
+        }
+    }
+
+    let mut registered = storage.get_all_tags()?;
+    let renamed: Vec<_> = registered
+        .keys()
+        .filter_map(|tag| renamed_tag(tag, old, new).map(|new_tag| (tag.clone(), new_tag)))
+        .collect();
+    for (old_tag, new_tag) in renamed {
+        registered.remove(&old_tag);
+        if !registered.keys().any(|t| names_match(t, &new_tag)) {
+            registered.insert(new_tag, usn);
+        }
+    }
+    storage.set_all_tags(&registered)?;
+
+    Ok(previous)
+}
+
+// Maintenance
+//----------------------------------------
+
+/// A registered tag and the number of notes using it, ignoring its
+/// descendants.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TagUsage {
+    pub name: String,
+    pub note_count: u32,
+}
+
+/// The registered tags and how many notes use each, sorted by name. Tags
+/// no note uses have a count of 0.
+pub fn tag_usage(storage: &SqliteStorage) -> Result<Vec<TagUsage>> {
+    let counts = note_counts(storage, false)?;
+    let mut usage: Vec<_> = storage
+        .get_all_tags()?
+        .into_iter()
+        .map(|(name, _)| {
+            let note_count = counts.get(&tag_key(&name)).cloned().unwrap_or_default();
+            TagUsage { name, note_count }
+        })
+        .collect();
+    usage.sort_by_cached_key(|u| tag_key(&u.name));
+    Ok(usage)
+}
+
+/// Remove the registered tags no note uses, and register the tags notes
+/// use that are missing from the list, in a single transaction. Returns
+/// the removed tags, sorted.
+pub fn clear_unused_tags(storage: &mut SqliteStorage, usn: i32) -> Result<Vec<String>> {
+    storage.transact(|storage| {
+        let counts = note_counts(storage, false)?;
+        let mut registered = storage.get_all_tags()?;
+        let mut removed: Vec<_> = registered
+            .keys()
+            .filter(|tag| !counts.contains_key(&tag_key(tag)))
+            .cloned()
+            .collect();
+        for tag in &removed {
+            registered.remove(tag);
+        }
+        storage.set_all_tags(&registered)?;
+
+        let mut stmt = storage
+            .db
+            .prepare("select distinct tags from notes where tags != ''")?;
+        let used: Vec<String> = stmt
+            .query_map(NO_PARAMS, |row| row.get(0))?
+            .collect::<rusqlite::Result<_>>()?;
+        let used: Vec<_> = used.iter().flat_map(|tags| split_tags(tags)).collect();
+        register_tags(storage, &used, usn)?;
+
+        removed.sort_unstable();
+        Ok(removed)
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use crate::err::Result;
+    use crate::notes::Note;
+    use crate::storage::testutil::open_test_collection;
+    use crate::storage::SqliteStorage;
+    use crate::tags::{
+        add_tags, clear_unused_tags, remove_tags, rename_tag, tag_tree, tag_usage, TagTreeNode,
+        TagUsage,
+    };
+
+    #[test]
+    fn test_add_tags() -> Result<()> {
+        let (_dir, storage) = open_test_collection()?;
+        let mut note = Note {
+            tags: vec!["One".into()],
+            ..Default::default()
+        };
+        storage.add_note(&mut note)?;
+        let mut note2 = Note::default();
+        storage.add_note(&mut note2)?;
+
+        let previous = add_tags(&storage, &[note.id, note2.id], "one two", -1, 5)?;
+        assert_eq!(previous[0].tags, vec!["One"]);
+        assert_eq!(previous[1].id, note2.id);
+        let note = storage.get_note(note.id)?.unwrap();
+        assert_eq!(note.tags, vec!["One", "two"]);
+        assert_eq!((note.mtime_secs, note.usn), (5, -1));
+        assert_eq!(
+            storage.get_note(note2.id)?.unwrap().tags,
+            vec!["one", "two"]
+        );
+        // the tags were registered
+        let mut registered: Vec<_> = storage.get_all_tags()?.into_iter().collect();
+        registered.sort();
+        assert_eq!(registered, vec![("one".into(), -1), ("two".into(), -1)]);
+
+        // notes that already have the tags are left alone
+        assert!(add_tags(&storage, &[note.id], "TWO", -1, 6)?.is_empty());
+        assert_eq!(storage.get_note(note.id)?.unwrap().mtime_secs, 5);
+
+        // the registered case is used, and quotes are removed
+        add_tags(&storage, &[note2.id], "ONE \"three\"", -1, 6)?;
+        assert_eq!(
+            storage.get_note(note2.id)?.unwrap().tags,
+            vec!["one", "three", "two"]
+        );
+
+        // removal ignores case, and supports wildcards
+        let previous = remove_tags(&storage, &[note.id, note2.id], "ONE t*", 1, 7)?;
+        assert_eq!(previous.len(), 2);
+        assert_eq!(previous[0].tags, vec!["One", "two"]);
+        let note = storage.get_note(note.id)?.unwrap();
+        assert!(note.tags.is_empty());
+        assert_eq!((note.mtime_secs, note.usn), (7, 1));
+        assert!(remove_tags(&storage, &[note.id], "one", 1, 8)?.is_empty());
+
+        Ok(())
+    }
+
+    fn tagged_note(storage: &SqliteStorage, tags: &[&str]) -> Result<i64> {
+        let mut note = Note {
+            tags: tags.iter().map(|t| t.to_string()).collect(),
+            ..Default::default()
+        };
+        storage.add_note(&mut note)?;
+        Ok(note.id)
+    }
+
+    #[test]
+    fn test_tag_tree() -> Result<()> {
+        let (_dir, storage) = open_test_collection()?;
+        tagged_note(&storage, &["a::b", "A::c"])?;
+        tagged_note(&storage, &["a::b::d"])?;
+        tagged_note(&storage, &["e"])?;
+        storage.set_all_tags(
+            &["a::b", "A::c", "a::b::d", "e"]
+                .iter()
+                .map(|t| (t.to_string(), 0))
+                .collect(),
+        )?;
+
+        let node = |name: &str, full_name: &str, note_count, children| TagTreeNode {
+            name: name.into(),
+            full_name: full_name.into(),
+            note_count,
+            children,
+        };
+        let tree = tag_tree(&storage)?;
+        // a wasn't registered, and its children differ in case
+        assert_eq!(tree.len(), 2);
+        assert_eq!(tree[0].note_count, 2);
+        assert_eq!(
+            tree[0].children,
+            vec![
+                node("b", "a::b", 2, vec![node("d", "a::b::d", 1, vec![])]),
+                node("c", "A::c", 1, vec![])
+            ]
+        );
+        assert_eq!(tree[1], node("e", "e", 1, vec![]));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_rename_tag() -> Result<()> {
+        let (_dir, storage) = open_test_collection()?;
+        let n1 = tagged_note(&storage, &["a::b", "ab", "x"])?;
+        let n2 = tagged_note(&storage, &["A", "c"])?;
+        let n3 = tagged_note(&storage, &["c::b", "x"])?;
+        add_tags(&storage, &[], "a::b ab x A c c::b", 0, 0)?;
+
+        assert!(rename_tag(&storage, "a", "c d", -1, 5).is_err());
+        // a and its children are merged into c
+        let previous = rename_tag(&storage, "a", "c", -1, 5)?;
+        assert_eq!(previous.len(), 2);
+        assert_eq!(previous[0].tags, vec!["a::b", "ab", "x"]);
+        let tags = |id| -> Result<Vec<String>> { Ok(storage.get_note(id)?.unwrap().tags) };
+        assert_eq!(tags(n1)?, vec!["c::b", "ab", "x"]);
+        assert_eq!(tags(n2)?, vec!["c"]);
+        assert_eq!(tags(n3)?, vec!["c::b", "x"]);
+        assert_eq!(storage.get_note(n1)?.unwrap().mtime_secs, 5);
+        assert_eq!(storage.get_note(n3)?.unwrap().mtime_secs, 0);
+        let mut registered: Vec<_> = storage.get_all_tags()?.into_iter().collect();
+        registered.sort();
+        assert_eq!(
+            registered,
+            vec![
+                ("ab".into(), 0),
+                ("c".into(), 0),
+                ("c::b".into(), 0),
+                ("x".into(), 0)
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_tag_usage() -> Result<()> {
+        let (_dir, mut storage) = open_test_collection()?;
+        tagged_note(&storage, &["a::b", "c"])?;
+        tagged_note(&storage, &["A::B"])?;
+        storage.set_all_tags(
+            &[("a", 0), ("a::b", 0), ("unused", 0)]
+                .iter()
+                .map(|(t, usn)| (t.to_string(), *usn))
+                .collect(),
+        )?;
+
+        let usage = |name: &str, note_count| TagUsage {
+            name: name.into(),
+            note_count,
+        };
+        // children aren't counted
+        assert_eq!(
+            tag_usage(&storage)?,
+            vec![usage("a", 0), usage("a::b", 2), usage("unused", 0)]
+        );
+
+        // unregistered tags are added
+        assert_eq!(clear_unused_tags(&mut storage, -1)?, vec!["a", "unused"]);
+        let mut registered: Vec<_> = storage.get_all_tags()?.into_iter().collect();
+        registered.sort();
+        assert_eq!(registered, vec![("a::b".into(), 0), ("c".into(), -1)]);
+
+        Ok(())
+    }
+}
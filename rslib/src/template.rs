@@ -2,6 +2,7 @@
 // License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
 
 use crate::err::{Result, TemplateError};
+use crate::markdown::render_markdown;
 use crate::template_filters::apply_filters;
 use crate::text::strip_av_tags;
 use lazy_static::lazy_static;
@@ -246,6 +247,35 @@ fn template_is_empty<'a>(nonempty_fields: &HashSet<&str>, nodes: &[ParsedNode<'a
     true
 }
 
+// Finding cloze fields
+//----------------------------------------
+
+impl<'a> ParsedTemplate<'a> {
+    /// The names of fields that are passed through the cloze filter.
+    pub fn cloze_fields(&self) -> HashSet<&'a str> {
+        let mut fields = HashSet::new();
+        add_cloze_fields(&self.0, &mut fields);
+        fields
+    }
+}
+
+fn add_cloze_fields<'a>(nodes: &[ParsedNode<'a>], fields: &mut HashSet<&'a str>) {
+    use ParsedNode::*;
+    for node in nodes {
+        match node {
+            Text(_) => (),
+            Replacement { key, filters } => {
+                if filters.contains(&"cloze") {
+                    fields.insert(key);
+                }
+            }
+            Conditional { children, .. } | NegatedConditional { children, .. } => {
+                add_cloze_fields(children, fields)
+            }
+        }
+    }
+}
+
 // Rendering
 //----------------------------------------
 
@@ -388,7 +418,7 @@ fn append_str_to_nodes(nodes: &mut Vec<RenderedNode>, text: &str) {
 }
 
 /// True if provided text contains only whitespace and/or empty BR/DIV tags.
-fn field_is_empty(text: &str) -> bool {
+pub(crate) fn field_is_empty(text: &str) -> bool {
     lazy_static! {
         static ref RE: Regex = Regex::new(
             r#"(?xsi)
@@ -420,38 +450,155 @@ fn nonempty_fields<'a>(fields: &'a HashMap<&str, &str>) -> HashSet<&'a str> {
 // Rendering both sides
 //----------------------------------------
 
+/// The output of rendering both sides of a card. A problem in one side's
+/// template does not prevent the other side from being rendered.
+#[derive(Debug)]
+pub struct RenderedCard {
+    pub question: Result<Vec<RenderedNode>>,
+    pub answer: Result<Vec<RenderedNode>>,
+}
+
+/// Details of the card being rendered that don't come from the note's
+/// fields. They are used to fill in special fields like {{Tags}} and
+/// {{Deck}}, so the caller does not need to add them to the field map.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct CardContext<'a> {
+    /// Starting at 0.
+    pub card_ord: u16,
+    /// Space-separated.
+    pub tags: &'a str,
+    pub notetype_name: &'a str,
+    /// The full name, including any parent decks.
+    pub deck_name: &'a str,
+    pub template_name: &'a str,
+    /// The card's flags column; only the lower 3 bits are used.
+    pub card_flags: u32,
+    /// The note fields that have the notetype's Markdown option turned on.
+    /// They are converted to HTML before any filters are applied.
+    pub markdown_fields: &'a [String],
+}
+
+/// The name of the card's flag, such as "flag1", or an empty string if
+/// the card is not flagged.
+fn flag_name(card_flags: u32) -> String {
+    match card_flags & 0b111 {
+        0 => String::new(),
+        flag => format!("flag{}", flag),
+    }
+}
+
 #[allow(clippy::implicit_hasher)]
-pub fn render_card(
+pub fn render_card_sides(
     qfmt: &str,
     afmt: &str,
     field_map: &HashMap<&str, &str>,
-    card_ord: u16,
-) -> Result<(Vec<RenderedNode>, Vec<RenderedNode>)> {
+    card: &CardContext,
+) -> RenderedCard {
+    // add special fields; like the legacy code, they take precedence over
+    // note fields of the same name
+    let flag = flag_name(card.card_flags);
+    let cloze_field = format!("c{}", card.card_ord + 1);
+    let markdown: Vec<(&str, String)> = card
+        .markdown_fields
+        .iter()
+        .filter_map(|name| field_map.get_key_value(name.as_str()))
+        .filter(|(_, text)| !field_is_empty(text))
+        .map(|(name, text)| (*name, render_markdown(text)))
+        .collect();
+    let mut fields = field_map.clone();
+    for (name, html) in &markdown {
+        fields.insert(name, html);
+    }
+    fields.insert("Tags", card.tags.trim());
+    fields.insert("Type", card.notetype_name);
+    fields.insert("Deck", card.deck_name);
+    fields.insert(
+        "Subdeck",
+        card.deck_name.rsplit("::").next().unwrap_or_default(),
+    );
+    fields.insert("Card", card.template_name);
+    fields.insert("CardFlag", &flag);
+    fields.insert(&cloze_field, "1");
+
     // prepare context
     let mut context = RenderContext {
-        fields: field_map,
-        nonempty_fields: &nonempty_fields(field_map),
+        fields: &fields,
+        nonempty_fields: &nonempty_fields(&fields),
         question_side: true,
-        card_ord,
+        card_ord: card.card_ord,
         front_text: None,
     };
 
     // question side
     let qnorm = without_legacy_template_directives(qfmt);
-    let qnodes = ParsedTemplate::from_text(qnorm.as_ref())?.render(&context)?;
+    let question = ParsedTemplate::from_text(qnorm.as_ref())
+        .and_then(|tmpl| tmpl.render(&context))
+        .map_err(Into::into);
 
     // if the question side didn't have any unknown filters, we can pass
     // FrontSide in now
-    if let [RenderedNode::Text { ref text }] = *qnodes.as_slice() {
-        context.front_text = Some(strip_av_tags(text));
+    if let Ok(qnodes) = &question {
+        if let [RenderedNode::Text { ref text }] = *qnodes.as_slice() {
+            context.front_text = Some(strip_av_tags(text));
+        }
     }
 
     // answer side
     context.question_side = false;
     let anorm = without_legacy_template_directives(afmt);
-    let anodes = ParsedTemplate::from_text(anorm.as_ref())?.render(&context)?;
+    let answer = ParsedTemplate::from_text(anorm.as_ref())
+        .and_then(|tmpl| tmpl.render(&context))
+        .map_err(Into::into);
 
-    Ok((qnodes, anodes))
+    RenderedCard { question, answer }
+}
+
+/// Render a card for previewing in the card layout screen. Empty note
+/// fields are replaced with their name in brackets, like "(Front)", so
+/// that every field reference shows up and conditionals on note fields
+/// are always true, even before any content has been entered.
+#[allow(clippy::implicit_hasher)]
+pub fn render_card_preview(
+    qfmt: &str,
+    afmt: &str,
+    field_map: &HashMap<&str, &str>,
+    card: &CardContext,
+) -> RenderedCard {
+    let placeholders: HashMap<&str, String> = field_map
+        .iter()
+        .filter(|(_, text)| field_is_empty(text))
+        .map(|(name, _)| (*name, format!("({})", name)))
+        .collect();
+    let mut fields = field_map.clone();
+    for (name, placeholder) in &placeholders {
+        fields.insert(name, placeholder);
+    }
+    // placeholders are shown as-is
+    let markdown_fields: Vec<String> = card
+        .markdown_fields
+        .iter()
+        .filter(|name| !placeholders.contains_key(name.as_str()))
+        .cloned()
+        .collect();
+    let card = CardContext {
+        markdown_fields: &markdown_fields,
+        ..*card
+    };
+
+    render_card_sides(qfmt, afmt, &fields, &card)
+}
+
+/// Like render_card_sides(), but fails if either side could not be
+/// rendered.
+#[allow(clippy::implicit_hasher)]
+pub fn render_card(
+    qfmt: &str,
+    afmt: &str,
+    field_map: &HashMap<&str, &str>,
+    card: &CardContext,
+) -> Result<(Vec<RenderedNode>, Vec<RenderedNode>)> {
+    let card = render_card_sides(qfmt, afmt, field_map, card);
+    Ok((card.question?, card.answer?))
 }
 
 // Field requirements
@@ -507,6 +654,222 @@ impl ParsedTemplate<'_> {
     }
 }
 
+// Renaming & removing fields
+//----------------------------------------
+
+/// Update references to field `old_name` in the template. If `new_name` is
+/// None, the references are removed instead; conditionals on the field are
+/// removed but their content is kept.
+///
+/// The template is returned unchanged if it does not refer to the field,
+/// or it could not be parsed. Otherwise whitespace inside handlebars is
+/// normalized, and legacy alternate syntax is converted.
+pub fn rename_field<'a>(template: &'a str, old_name: &str, new_name: Option<&str>) -> Cow<'a, str> {
+    let normalized = without_legacy_template_directives(template);
+    if let Ok(parsed) = ParsedTemplate::from_text(normalized.as_ref()) {
+        let mut buf = String::with_capacity(template.len());
+        if write_renamed(&mut buf, &parsed.0, old_name, new_name) {
+            return buf.into();
+        }
+    }
+    template.into()
+}
+
+/// Returns true if any nodes were changed.
+fn write_renamed(
+    buf: &mut String,
+    nodes: &[ParsedNode],
+    old_name: &str,
+    new_name: Option<&str>,
+) -> bool {
+    use ParsedNode::*;
+    let mut changed = false;
+    for node in nodes {
+        match node {
+            Text(text) => buf.push_str(text),
+            Replacement { key, filters } => {
+                let key = if *key == old_name {
+                    changed = true;
+                    match new_name {
+                        Some(name) => name,
+                        None => continue,
+                    }
+                } else {
+                    key
+                };
+                buf.push_str("{{");
+                for filter in filters.iter().rev() {
+                    buf.push_str(filter);
+                    buf.push(':');
+                }
+                buf.push_str(key);
+                buf.push_str("}}");
+            }
+            Conditional { key, children } | NegatedConditional { key, children } => {
+                let key = if *key == old_name {
+                    changed = true;
+                    new_name
+                } else {
+                    Some(*key)
+                };
+                let prefix = if let Conditional { .. } = node {
+                    '#'
+                } else {
+                    '^'
+                };
+                if let Some(key) = key {
+                    buf.push_str("{{");
+                    buf.push(prefix);
+                    buf.push_str(key);
+                    buf.push_str("}}");
+                }
+                changed |= write_renamed(buf, children, old_name, new_name);
+                if let Some(key) = key {
+                    buf.push_str("{{/");
+                    buf.push_str(key);
+                    buf.push_str("}}");
+                }
+            }
+        }
+    }
+    changed
+}
+
+// Checking for problems
+//----------------------------------------
+
+/// Fields that are provided by Anki rather than the note.
+static SPECIAL_FIELDS: &[&str] = &[
+    "FrontSide",
+    "Tags",
+    "Type",
+    "Deck",
+    "Subdeck",
+    "Card",
+    "CardFlag",
+];
+
+#[derive(Debug, PartialEq)]
+pub struct TemplateDiagnostic {
+    pub error: TemplateError,
+    /// 1-based line and column (in characters) of the problem.
+    pub line: usize,
+    pub column: usize,
+}
+
+fn is_known_field(name: &str, field_names: &HashSet<&str>) -> bool {
+    field_names.contains(name)
+        || SPECIAL_FIELDS.contains(&name)
+        || (name.starts_with('c')
+            && name.len() > 1
+            && name[1..].chars().all(|c| c.is_ascii_digit()))
+}
+
+/// Check a template for problems, continuing after the first one so that
+/// all of them can be reported. If `question_side` is true, a template
+/// that would never show any field content is also reported.
+#[allow(clippy::implicit_hasher)]
+pub fn check_template(
+    template: &str,
+    field_names: &HashSet<&str>,
+    question_side: bool,
+) -> Vec<TemplateDiagnostic> {
+    let template = without_legacy_template_directives(template);
+    let template = template.as_ref();
+    let mut problems = vec![];
+    let mut open_tags: Vec<&str> = vec![];
+
+    for token in tokens(template) {
+        match token {
+            Ok(Token::Text(_)) => (),
+            Ok(Token::Replacement(text)) => {
+                let mut it = text.rsplit(':');
+                let key = it.next().unwrap();
+                if !key.is_empty() && !is_known_field(key, field_names) {
+                    let filters = it.rev().chain(iter::once("")).collect::<Vec<_>>().join(":");
+                    problems.push(diagnostic(
+                        template,
+                        TemplateError::FieldNotFound {
+                            field: key.to_string(),
+                            filters,
+                        },
+                        text,
+                    ));
+                }
+            }
+            Ok(Token::OpenConditional(key)) | Ok(Token::OpenNegated(key)) => open_tags.push(key),
+            Ok(Token::CloseConditional(key)) => {
+                if let Some(pos) = open_tags.iter().rposition(|open| *open == key) {
+                    // any tags opened after the matching one were not closed
+                    for unclosed in open_tags.drain(pos..).skip(1) {
+                        problems.push(diagnostic(
+                            template,
+                            TemplateError::ConditionalNotClosed(unclosed.into()),
+                            unclosed,
+                        ));
+                    }
+                } else {
+                    problems.push(diagnostic(
+                        template,
+                        TemplateError::ConditionalNotOpen(key.into()),
+                        key,
+                    ));
+                }
+            }
+            Err(err) => {
+                let at = match &err {
+                    TemplateError::NoClosingBrackets(rest) => {
+                        &template[template.len() - rest.len()..]
+                    }
+                    _ => template,
+                };
+                problems.push(diagnostic(template, err, at));
+                // the remaining text can't be tokenized
+                break;
+            }
+        }
+    }
+
+    for unclosed in open_tags {
+        problems.push(diagnostic(
+            template,
+            TemplateError::ConditionalNotClosed(unclosed.into()),
+            unclosed,
+        ));
+    }
+
+    if question_side && problems.is_empty() {
+        if let Ok(parsed) = ParsedTemplate::from_text(template) {
+            if !parsed.renders_with_fields(field_names) {
+                problems.push(diagnostic(template, TemplateError::EmptyFront, template));
+            }
+        }
+    }
+
+    problems
+}
+
+fn diagnostic(template: &str, error: TemplateError, at: &str) -> TemplateDiagnostic {
+    let (line, column) = line_and_column(template, at);
+    TemplateDiagnostic {
+        error,
+        line,
+        column,
+    }
+}
+
+/// The line and column of `part`, which must be a slice of `text`.
+fn line_and_column(text: &str, part: &str) -> (usize, usize) {
+    let offset = (part.as_ptr() as usize)
+        .saturating_sub(text.as_ptr() as usize)
+        .min(text.len());
+    let before = &text[..offset];
+    let line = before.matches('\n').count() + 1;
+    let line_start = before.rfind('\n').map(|idx| idx + 1).unwrap_or(0);
+    let column = before[line_start..].chars().count() + 1;
+    (line, column)
+}
+
 // Tests
 //---------------------------------------
 
@@ -515,8 +878,9 @@ mod test {
     use super::{FieldMap, ParsedNode::*, ParsedTemplate as PT};
     use crate::err::TemplateError;
     use crate::template::{
-        field_is_empty, nonempty_fields, render_card, without_legacy_template_directives,
-        FieldRequirements, RenderContext, RenderedNode,
+        check_template, field_is_empty, nonempty_fields, rename_field, render_card,
+        render_card_preview, render_card_sides, without_legacy_template_directives, CardContext,
+        FieldRequirements, RenderContext, RenderedNode, TemplateDiagnostic,
     };
     use crate::text::strip_html;
     use std::collections::{HashMap, HashSet};
@@ -594,6 +958,32 @@ mod test {
         assert_eq!(tmpl.renders_with_fields(&fields), false);
     }
 
+    #[test]
+    fn test_rename_field() {
+        let tmpl =
+            "{{Front}} {{ text:Front }} {{#Front}}{{^Front}}x{{/Front}}{{/Front}} {{Front2}}";
+        assert_eq!(
+            rename_field(tmpl, "Front", Some("New")),
+            "{{New}} {{text:New}} {{#New}}{{^New}}x{{/New}}{{/New}} {{Front2}}"
+        );
+        assert_eq!(rename_field(tmpl, "Front", None), "  x {{Front2}}");
+
+        // templates that don't refer to the field are not altered
+        let tmpl = "{{ Back }} {{tts en_US:}}";
+        assert_eq!(rename_field(tmpl, "Front", Some("New")), tmpl);
+        assert_eq!(
+            rename_field(tmpl, "Back", Some("New")),
+            "{{New}} {{tts en_US:}}"
+        );
+        assert_eq!(rename_field("{{#Front}}", "Front", None), "{{#Front}}");
+
+        // legacy syntax
+        assert_eq!(
+            rename_field("{{=<% %>=}}<%Front%>", "Front", Some("New")),
+            "{{New}}"
+        );
+    }
+
     #[test]
     fn test_requirements() {
         let field_map: FieldMap = vec!["a", "b"]
@@ -772,7 +1162,7 @@ mod test {
         let clozed_text = "{{c1::one}} {{c2::two::hint}}";
         let map: HashMap<_, _> = vec![("Text", clozed_text)].into_iter().collect();
 
-        let (qnodes, anodes) = render_card(fmt, fmt, &map, 0).unwrap();
+        let (qnodes, anodes) = render_card(fmt, fmt, &map, &CardContext::default()).unwrap();
         assert_eq!(
             strip_html(get_complete_template(&qnodes).unwrap()),
             "[...] two"
@@ -783,12 +1173,170 @@ mod test {
         );
 
         // FrontSide should render if only standard modifiers were used
+        let second_card = CardContext {
+            card_ord: 1,
+            ..Default::default()
+        };
         let (_qnodes, anodes) =
-            render_card("{{kana:text:Text}}", "{{FrontSide}}", &map, 1).unwrap();
+            render_card("{{kana:text:Text}}", "{{FrontSide}}", &map, &second_card).unwrap();
         assert_eq!(get_complete_template(&anodes).unwrap(), clozed_text);
 
         // But if a custom modifier was used, it's deferred to the Python code
-        let (_qnodes, anodes) = render_card("{{custom:Text}}", "{{FrontSide}}", &map, 1).unwrap();
-        assert_eq!(get_complete_template(&anodes).is_none(), true)
+        let (_qnodes, anodes) =
+            render_card("{{custom:Text}}", "{{FrontSide}}", &map, &second_card).unwrap();
+        assert_eq!(get_complete_template(&anodes).is_none(), true);
+
+        // a problem on one side doesn't prevent the other from rendering
+        let card = render_card_sides("{{#Text}}", "{{Text}}", &map, &CardContext::default());
+        assert!(card.question.is_err());
+        assert_eq!(
+            get_complete_template(card.answer.as_ref().unwrap()),
+            Some(clozed_text)
+        );
+        let card = render_card_sides("{{Text}}", "{{Missing}}", &map, &CardContext::default());
+        assert!(card.question.is_ok());
+        assert!(card.answer.is_err());
+        assert!(render_card("{{Text}}", "{{Missing}}", &map, &CardContext::default()).is_err());
+    }
+
+    #[test]
+    fn test_preview() {
+        let map: HashMap<_, _> = vec![("Front", "front"), ("Back", "<br>")]
+            .into_iter()
+            .collect();
+        let card = render_card_preview(
+            "{{Front}}",
+            "{{FrontSide}} {{#Back}}{{Back}}{{/Back}}{{^Back}}no back{{/Back}}",
+            &map,
+            &CardContext::default(),
+        );
+        assert_eq!(
+            get_complete_template(card.answer.as_ref().unwrap()),
+            Some("front (Back)")
+        );
+
+        // special fields are not replaced
+        let card = render_card_preview("{{Tags}}", "", &map, &CardContext::default());
+        assert_eq!(
+            get_complete_template(card.question.as_ref().unwrap()),
+            Some("")
+        );
+    }
+
+    #[test]
+    fn test_markdown_fields() {
+        let map: HashMap<_, _> = vec![("Front", "*a*"), ("Back", "*b*"), ("Extra", "")]
+            .into_iter()
+            .collect();
+        let markdown_fields = vec!["Front".to_string(), "Extra".to_string()];
+        let card = CardContext {
+            markdown_fields: &markdown_fields,
+            ..Default::default()
+        };
+        let (qnodes, _anodes) =
+            render_card("{{Front}}|{{text:Front}}|{{Back}}", "", &map, &card).unwrap();
+        assert_eq!(
+            get_complete_template(&qnodes).unwrap(),
+            "<p><em>a</em></p>|a|*b*"
+        );
+
+        // an empty field is still empty, and placeholders aren't converted
+        let card = render_card_preview("{{Front}}|{{Extra}}", "", &map, &card);
+        assert_eq!(
+            get_complete_template(card.question.as_ref().unwrap()),
+            Some("<p><em>a</em></p>|(Extra)")
+        );
+    }
+
+    #[test]
+    fn test_special_fields() {
+        let map: HashMap<_, _> = vec![("Text", "text"), ("Tags", "note field")]
+            .into_iter()
+            .collect();
+        let card = CardContext {
+            card_ord: 1,
+            tags: " one two ",
+            notetype_name: "Basic",
+            deck_name: "Parent::Child",
+            template_name: "Card 2",
+            card_flags: 0b1010,
+            ..Default::default()
+        };
+        let (qnodes, _anodes) = render_card(
+            "{{Tags}}|{{Type}}|{{Deck}}|{{Subdeck}}|{{Card}}|{{CardFlag}}",
+            "",
+            &map,
+            &card,
+        )
+        .unwrap();
+        assert_eq!(
+            get_complete_template(&qnodes).unwrap(),
+            "one two|Basic|Parent::Child|Child|Card 2|flag2"
+        );
+
+        // the card's cloze number can be used in a conditional
+        let (qnodes, _anodes) =
+            render_card("{{#c2}}two{{/c2}}{{^c1}}!{{/c1}}", "", &map, &card).unwrap();
+        assert_eq!(get_complete_template(&qnodes).unwrap(), "two!");
+
+        // unflagged cards and empty tags don't satisfy a conditional
+        let (qnodes, _anodes) = render_card(
+            "{{^CardFlag}}unflagged{{/CardFlag}}{{#Tags}}tagged{{/Tags}}",
+            "",
+            &map,
+            &CardContext::default(),
+        )
+        .unwrap();
+        assert_eq!(get_complete_template(&qnodes).unwrap(), "unflagged");
+    }
+
+    #[test]
+    fn test_check() {
+        let fields: HashSet<_> = vec!["Front", "Back"].into_iter().collect();
+        let check = |tmpl, question| {
+            check_template(tmpl, &fields, question)
+                .into_iter()
+                .map(
+                    |TemplateDiagnostic {
+                         error,
+                         line,
+                         column,
+                     }| (error, line, column),
+                )
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(check("{{Front}}{{Tags}}{{c2}}", true), vec![]);
+        assert_eq!(
+            check("{{Front}}\n  {{text:Bad}}", true),
+            vec![(
+                TemplateError::FieldNotFound {
+                    field: "Bad".into(),
+                    filters: "text:".into()
+                },
+                2,
+                5
+            )]
+        );
+        assert_eq!(
+            check("{{#Front}}{{^Back}}\n{{/Front}}{{/Back}}", false),
+            vec![
+                (TemplateError::ConditionalNotClosed("Back".into()), 1, 14),
+                (TemplateError::ConditionalNotOpen("Back".into()), 2, 14),
+            ]
+        );
+        assert_eq!(
+            check("{{#Front}}ü{{Front", false),
+            vec![
+                (TemplateError::NoClosingBrackets("{{Front".into()), 1, 12),
+                (TemplateError::ConditionalNotClosed("Front".into()), 1, 4),
+            ]
+        );
+        assert_eq!(
+            check("{{^Front}}text{{/Front}}", true),
+            vec![(TemplateError::EmptyFront, 1, 1)]
+        );
+        // the back may be empty
+        assert_eq!(check("text", false), vec![]);
     }
 }
@@ -1,11 +1,11 @@
 // Copyright: Ankitects Pty Ltd and contributors
 // License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
 
+use crate::cloze::render_cloze;
+use crate::ruby::{furigana_to_ruby, kana_only, kanji_only};
 use crate::template::RenderContext;
-use crate::text::strip_html;
+use crate::text::{decode_entities, strip_html};
 use blake3::Hasher;
-use lazy_static::lazy_static;
-use regex::{Captures, Regex};
 use std::borrow::Cow;
 
 // Filtering
@@ -64,10 +64,10 @@ fn apply_filter<'a>(
     context: &RenderContext,
 ) -> (bool, Option<String>) {
     let output_text = match filter_name {
-        "text" => strip_html(text),
-        "furigana" => furigana_filter(text),
-        "kanji" => kanji_filter(text),
-        "kana" => kana_filter(text),
+        "text" => text_filter(text),
+        "furigana" => furigana_to_ruby(text),
+        "kanji" => kanji_only(text),
+        "kana" => kana_only(text),
         "type" => type_filter(field_name),
         "type-cloze" => type_cloze_filter(field_name),
         "hint" => hint_filter(text, field_name),
@@ -96,158 +96,8 @@ fn apply_filter<'a>(
 // Cloze filter
 //----------------------------------------
 
-lazy_static! {
-    static ref CLOZE: Regex = Regex::new(
-        r#"(?xsi)
-            \{\{
-            c(\d+)::    # 1 = cloze number
-            (.*?)       # 2 = clozed text
-            (?:
-              ::(.*?)   # 3 = optional hint
-            )?
-            \}\}
-        "#
-    )
-    .unwrap();
-    static ref MATHJAX: Regex = Regex::new(
-        r#"(?xsi)
-            (\\[(\[])       # 1 = mathjax opening tag
-            (.*?)           # 2 = inner content
-            (\\[])])        # 3 = mathjax closing tag 
-           "#
-    )
-    .unwrap();
-}
-
-mod cloze_caps {
-    // cloze ordinal
-    pub const ORD: usize = 1;
-    // the occluded text
-    pub const TEXT: usize = 2;
-    // optional hint
-    pub const HINT: usize = 3;
-}
-
-mod mathjax_caps {
-    pub const OPENING_TAG: usize = 1;
-    pub const INNER_TEXT: usize = 2;
-    pub const CLOSING_TAG: usize = 3;
-}
-
-fn reveal_cloze_text(text: &str, cloze_ord: u16, question: bool) -> Cow<str> {
-    let mut cloze_ord_was_in_text = false;
-
-    let output = CLOZE.replace_all(text, |caps: &Captures| {
-        let captured_ord = caps
-            .get(cloze_caps::ORD)
-            .unwrap()
-            .as_str()
-            .parse()
-            .unwrap_or(0);
-
-        if captured_ord != cloze_ord {
-            // other cloze deletions are unchanged
-            return caps.get(cloze_caps::TEXT).unwrap().as_str().to_owned();
-        } else {
-            cloze_ord_was_in_text = true;
-        }
-
-        let replacement;
-        if question {
-            // hint provided?
-            if let Some(hint) = caps.get(cloze_caps::HINT) {
-                replacement = format!("[{}]", hint.as_str());
-            } else {
-                replacement = "[...]".to_string()
-            }
-        } else {
-            replacement = caps.get(cloze_caps::TEXT).unwrap().as_str().to_owned();
-        }
-
-        format!("<span class=cloze>{}</span>", replacement)
-    });
-
-    if !cloze_ord_was_in_text {
-        return "".into();
-    }
-
-    // if no cloze deletions are found, Anki returns an empty string
-    match output {
-        Cow::Borrowed(_) => "".into(),
-        other => other,
-    }
-}
-
-fn strip_html_inside_mathjax(text: &str) -> Cow<str> {
-    MATHJAX.replace_all(text, |caps: &Captures| -> String {
-        format!(
-            "{}{}{}",
-            caps.get(mathjax_caps::OPENING_TAG).unwrap().as_str(),
-            strip_html(caps.get(mathjax_caps::INNER_TEXT).unwrap().as_str()).as_ref(),
-            caps.get(mathjax_caps::CLOSING_TAG).unwrap().as_str()
-        )
-    })
-}
-
 fn cloze_filter<'a>(text: &'a str, context: &RenderContext) -> Cow<'a, str> {
-    strip_html_inside_mathjax(
-        reveal_cloze_text(text, context.card_ord + 1, context.question_side).as_ref(),
-    )
-    .into_owned()
-    .into()
-}
-
-// Ruby filters
-//----------------------------------------
-
-lazy_static! {
-    static ref FURIGANA: Regex = Regex::new(r" ?([^ >]+?)\[(.+?)\]").unwrap();
-}
-
-/// Did furigana regex match a sound tag?
-fn captured_sound(caps: &Captures) -> bool {
-    caps.get(2).unwrap().as_str().starts_with("sound:")
-}
-
-fn kana_filter(text: &str) -> Cow<str> {
-    FURIGANA
-        .replace_all(&text.replace("&nbsp;", " "), |caps: &Captures| {
-            if captured_sound(caps) {
-                caps.get(0).unwrap().as_str().to_owned()
-            } else {
-                caps.get(2).unwrap().as_str().to_owned()
-            }
-        })
-        .into_owned()
-        .into()
-}
-
-fn kanji_filter(text: &str) -> Cow<str> {
-    FURIGANA
-        .replace_all(&text.replace("&nbsp;", " "), |caps: &Captures| {
-            if captured_sound(caps) {
-                caps.get(0).unwrap().as_str().to_owned()
-            } else {
-                caps.get(1).unwrap().as_str().to_owned()
-            }
-        })
-        .into_owned()
-        .into()
-}
-
-fn furigana_filter(text: &str) -> Cow<str> {
-    FURIGANA
-        .replace_all(&text.replace("&nbsp;", " "), |caps: &Captures| {
-            if captured_sound(caps) {
-                caps.get(0).unwrap().as_str().to_owned()
-            } else {
-                format!(
-                    "<ruby><rb>{}</rb><rt>{}</rt></ruby>",
-                    caps.get(1).unwrap().as_str(),
-                    caps.get(2).unwrap().as_str()
-                )
-            }
-        })
+    render_cloze(text, context.card_ord + 1, context.question_side)
         .into_owned()
         .into()
 }
@@ -264,9 +114,21 @@ fn type_cloze_filter<'a>(field_name: &str) -> Cow<'a, str> {
     format!("[[type:cloze:{}]]", field_name).into()
 }
 
+/// Strip HTML and decode entities, as the legacy Python filter did.
+/// &nbsp; becomes a normal space.
+fn text_filter(text: &str) -> Cow<str> {
+    let stripped = strip_html(text);
+    if !stripped.contains('&') {
+        return stripped;
+    }
+    decode_entities(&stripped.replace("&nbsp;", " "))
+        .into_owned()
+        .into()
+}
+
 fn hint_filter<'a>(text: &'a str, field_name: &str) -> Cow<'a, str> {
     if text.trim().is_empty() {
-        return text.into();
+        return "".into();
     }
 
     // generate a unique DOM id
@@ -301,22 +163,11 @@ fn tts_filter(filter_name: &str, text: &str) -> Cow<'static, str> {
 mod test {
     use crate::template::RenderContext;
     use crate::template_filters::{
-        apply_filters, cloze_filter, furigana_filter, hint_filter, kana_filter, kanji_filter,
-        tts_filter, type_cloze_filter, type_filter,
+        apply_filters, cloze_filter, hint_filter, text_filter, tts_filter, type_cloze_filter,
+        type_filter,
     };
     use crate::text::strip_html;
 
-    #[test]
-    fn test_furigana() {
-        let text = "test first[second] third[fourth]";
-        assert_eq!(kana_filter(text).as_ref(), "testsecondfourth");
-        assert_eq!(kanji_filter(text).as_ref(), "testfirstthird");
-        assert_eq!(
-            furigana_filter("first[second]").as_ref(),
-            "<ruby><rb>first</rb><rt>second</rt></ruby>"
-        );
-    }
-
     #[test]
     fn test_hint() {
         assert_eq!(
@@ -378,6 +229,41 @@ field</a>
         assert_eq!(cloze_filter(text, &ctx).as_ref(), "");
     }
 
+    #[test]
+    fn test_text() {
+        assert_eq!(text_filter("plain"), "plain");
+        assert_eq!(text_filter("<b>a</b> &amp; b&nbsp;"), "a & b ");
+        assert_eq!(hint_filter(" ", "field"), "");
+    }
+
+    #[test]
+    fn test_chain() {
+        let ctx = RenderContext {
+            fields: &Default::default(),
+            nonempty_fields: &Default::default(),
+            question_side: true,
+            card_ord: 0,
+            front_text: None,
+        };
+        // filters are provided right-to-left, and applied in that order
+        assert_eq!(
+            apply_filters("<b>漢字[かんじ]</b>", &["text", "kana"], "F", &ctx),
+            ("かんじ".into(), vec![])
+        );
+        assert_eq!(
+            apply_filters(
+                "<b>漢字[かんじ]</b>",
+                &["furigana", "custom", "text"],
+                "F",
+                &ctx
+            ),
+            (
+                "<b><ruby><rb>漢字</rb><rt>かんじ</rt></ruby></b>".into(),
+                vec!["custom".to_string(), "text".to_string()]
+            )
+        );
+    }
+
     #[test]
     fn test_tts() {
         assert_eq!(
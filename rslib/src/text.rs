@@ -1,12 +1,18 @@
 // Copyright: Ankitects Pty Ltd and contributors
 // License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
 
+use crate::err::TTSError;
+use crate::html::{Attribute, Tag, Token, Tokenizer};
+use crate::latex::latex_media_refs;
+use caseless::default_case_fold_str;
 use htmlescape;
 use lazy_static::lazy_static;
 use regex::{Captures, Regex};
 use std::borrow::Cow;
 use std::collections::HashSet;
-use std::ptr;
+use std::ops::Range;
+use unicode_normalization::char::is_combining_mark;
+use unicode_normalization::{is_nfc, UnicodeNormalization};
 
 #[derive(Debug, PartialEq)]
 pub enum AVTag<'a> {
@@ -15,25 +21,14 @@ pub enum AVTag<'a> {
         field_text: Cow<'a, str>,
         lang: &'a str,
         voices: Vec<&'a str>,
+        speed: Option<f32>,
+        pitch: Option<f32>,
+        style: Option<&'a str>,
         other_args: Vec<&'a str>,
     },
 }
 
 lazy_static! {
-    static ref HTML: Regex = Regex::new(concat!(
-        "(?si)",
-        // wrapped text
-        r"(<!--.*?-->)|(<style.*?>.*?</style>)|(<script.*?>.*?</script>)",
-        // html tags
-        r"|(<.*?>)",
-    ))
-    .unwrap();
-
-    static ref IMG_TAG: Regex = Regex::new(
-        // group 1 is filename
-        r#"(?i)<img[^>]+src=["']?([^"'>]+)["']?[^>]*>"#
-    ).unwrap();
-
     // videos are also in sound tags
     static ref AV_TAGS: Regex = Regex::new(
         r#"(?xs)
@@ -45,32 +40,354 @@ lazy_static! {
             \[/anki:tts\]
             "#).unwrap();
 
-    static ref CLOZED_TEXT: Regex = Regex::new(
-        r"(?s)\{\{c(\d+)::.+?\}\}"
+    static ref SOUND_TAG: Regex = Regex::new(r"\[sound:([^\]]+)\]").unwrap();
+
+    // block-level tags that end a line of text
+    static ref LINE_BREAKS: Regex = Regex::new(
+        r"(?i)<br\s*/?>|</(?:p|div|li|h[1-6]|blockquote|pre)\s*>"
+    ).unwrap();
+
+    // the boundary between two table cells
+    static ref CELL_BOUNDARY: Regex = Regex::new(
+        r"(?i)</t[dh]\s*>\s*<t[dh]\b[^>]*>"
+    ).unwrap();
+
+    // tags that separate the rows of a table, or a table from its surroundings
+    static ref TABLE_ROW_BOUNDARY: Regex = Regex::new(
+        r"(?i)</?table\b[^>]*>|</tr\s*>"
     ).unwrap();
+
+    // tags that become a space when converting to a single line
+    static ref INLINE_BREAKS: Regex = Regex::new(r"(?i)<(?:br ?/?|div|p)>").unwrap();
+
+    static ref WHITESPACE_RUN: Regex = Regex::new(r"[ \n\t]+").unwrap();
 }
 
+/// Remove tags and comments, along with the contents of scripts and style
+/// sheets. Entities are left encoded.
 pub fn strip_html(html: &str) -> Cow<str> {
-    HTML.replace_all(html, "")
+    map_tokens(html, |token| match token {
+        Token::Text(text) => text.into(),
+        _ => "".into(),
+    })
 }
 
-pub fn decode_entities(html: &str) -> Cow<str> {
-    if html.contains('&') {
-        match htmlescape::decode_html(html) {
-            Ok(text) => text,
-            Err(e) => format!("{:?}", e),
+/// Rebuild `html` from the text `f` returns for each of its tokens,
+/// borrowing if that leaves it unchanged.
+fn map_tokens<'a, F>(html: &'a str, mut f: F) -> Cow<'a, str>
+where
+    F: FnMut(Token<'a>) -> Cow<'a, str>,
+{
+    let mut out = String::new();
+    let mut changed = false;
+    for (span, token) in Tokenizer::new(html) {
+        let text = f(token);
+        if !changed && text != html[span.clone()] {
+            changed = true;
+            out.push_str(&html[..span.start]);
         }
-        .into()
+        if changed {
+            out.push_str(&text);
+        }
+    }
+
+    if changed {
+        out.into()
     } else {
+        html.into()
+    }
+}
+
+/// Strip HTML, converting line breaks and block-level tags into newlines,
+/// and table cell boundaries into tabs. Entities are decoded, trailing
+/// whitespace on each line is removed, and runs of empty lines are
+/// collapsed into a single empty line.
+pub fn html_to_text_lines(html: &str) -> String {
+    let text = flatten_tables(html, |cell| {
+        let text = LINE_BREAKS.replace_all(cell, "\n");
+        let text = strip_html(&text);
+        decode_entities(&text).replace('\u{a0}', " ")
+    });
+
+    let mut out = String::with_capacity(text.len());
+    let mut last_was_empty = true;
+    for line in text.lines() {
+        let line = line.trim_end();
+        if line.is_empty() {
+            if last_was_empty {
+                continue;
+            }
+            last_was_empty = true;
+        } else {
+            last_was_empty = false;
+        }
+        out.push_str(line);
+        out.push('\n');
+    }
+
+    out.trim_end().into()
+}
+
+/// How html_to_text() lays out the text it produces.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TextLayout {
+    /// All text is placed on a single line, separated by spaces.
+    SingleLine,
+    /// As with SingleLine, but each table row is placed on a separate line,
+    /// and table cells are separated by tabs.
+    Tables,
+}
+
+/// Convert HTML into plain text for exporting. Sound tags are removed,
+/// entities are decoded and runs of whitespace are collapsed.
+pub fn html_to_text(html: &str, layout: TextLayout) -> String {
+    match layout {
+        TextLayout::SingleLine => html_to_single_line(html),
+        TextLayout::Tables => flatten_tables(html, html_to_single_line),
+    }
+}
+
+/// Convert each table cell in `html` into text with `cell_text`, separating
+/// the cells with tabs and the rows with newlines. Text before, between and
+/// after tables is converted as a row with a single cell. Rows without any
+/// text are skipped.
+fn flatten_tables<F>(html: &str, cell_text: F) -> String
+where
+    F: Fn(&str) -> String,
+{
+    TABLE_ROW_BOUNDARY
+        .split(html)
+        .map(|row| {
+            CELL_BOUNDARY
+                .split(row)
+                .map(&cell_text)
+                .collect::<Vec<_>>()
+                .join("\t")
+        })
+        .filter(|row| !row.trim().is_empty())
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn html_to_single_line(html: &str) -> String {
+    let text = INLINE_BREAKS.replace_all(html, " ");
+    let text = SOUND_TAG.replace_all(&text, "");
+    let text = strip_html(&text).replace("&nbsp;", " ");
+    let text = decode_entities(&text);
+    WHITESPACE_RUN.replace_all(&text, " ").trim().into()
+}
+
+/// Convert a field to a single line for the browser. Unlike
+/// html_to_text(), image filenames are kept.
+pub fn html_to_browser_line(html: &str) -> String {
+    let text = INLINE_BREAKS.replace_all(html, " ");
+    let text = SOUND_TAG.replace_all(&text, "");
+    let text = strip_html_preserving_image_filenames(&text).replace("&nbsp;", " ");
+    let text = decode_entities(&text);
+    WHITESPACE_RUN.replace_all(&text, " ").trim().into()
+}
+
+/// Attributes that may contain a URL.
+static URL_ATTRS: &[&str] = &["action", "background", "data", "formaction", "href", "src"];
+
+lazy_static! {
+    /// Tags that survive sanitizing. Anything else is removed, but its
+    /// contents are kept.
+    static ref ALLOWED_TAGS: HashSet<&'static str> = concat!(
+        "a audio b big blockquote br center code del div em font h1 h2 h3 h4 h5 h6 hr i img ",
+        "ins li object ol p pre rb rp rt ruby s small source span strike strong sub sup table ",
+        "tbody td tfoot th thead tr u ul video"
+    )
+    .split(' ')
+    .collect();
+}
+
+/// Elements that are removed along with their contents when sanitizing.
+/// Objects are kept like images, as their data is a media reference, and
+/// a javascript: URL in it is removed like any other.
+static UNSAFE_WRAPPED_TAGS: &[&str] = &["iframe", "script", "style"];
+
+/// Remove scripts, frames, event handlers and javascript: URLs from the
+/// provided HTML, keeping formatting and media references intact.
+pub fn sanitize_html(html: &str) -> Cow<str> {
+    // set inside an element that is removed with its contents
+    let mut unsafe_element: Option<&str> = None;
+    map_tokens(html, |token| {
+        if let Some(element) = unsafe_element {
+            if let Token::EndTag(name) = token {
+                if name.eq_ignore_ascii_case(element) {
+                    unsafe_element = None;
+                }
+            }
+            return "".into();
+        }
+        match token {
+            // a '<' in text doesn't start a complete tag, such as an
+            // unterminated one at the end of the field, but a browser could
+            // still treat it as the start of one
+            Token::Text(text) => {
+                if text.contains('<') {
+                    text.replace('<', "&lt;").into()
+                } else {
+                    text.into()
+                }
+            }
+            Token::StartTag(tag) => {
+                unsafe_element = UNSAFE_WRAPPED_TAGS
+                    .iter()
+                    .find(|element| tag.is(element))
+                    .copied()
+                    .filter(|_| !tag.self_closing);
+                sanitize_start_tag(html, &tag).into()
+            }
+            Token::EndTag(name) => {
+                let name = name.to_ascii_lowercase();
+                if ALLOWED_TAGS.contains(name.as_str()) {
+                    format!("</{}>", name).into()
+                } else {
+                    "".into()
+                }
+            }
+            Token::RawText(_) | Token::Comment(_) | Token::Ignored => "".into(),
+        }
+    })
+}
+
+/// The tag with its unsafe attributes removed, or nothing if the tag isn't
+/// allowed. Attributes are kept as written.
+fn sanitize_start_tag(html: &str, tag: &Tag) -> String {
+    let name = tag.name.to_ascii_lowercase();
+    if !ALLOWED_TAGS.contains(name.as_str()) {
+        return "".to_string();
+    }
+    let mut out = format!("<{}", name);
+    for attr in tag.attrs.iter().filter(|attr| attr_is_safe(attr)) {
+        out.push(' ');
+        out.push_str(&html[attr.span.clone()]);
+    }
+    if tag.self_closing {
+        out.push_str(" /");
+    }
+    out.push('>');
+    out
+}
+
+fn attr_is_safe(attr: &Attribute) -> bool {
+    let name = attr.name.to_ascii_lowercase();
+    if name.starts_with("on") {
+        return false;
+    }
+    if URL_ATTRS.contains(&name.as_str()) || name == "style" {
+        let value: String = decode_entities(attr.value)
+            .chars()
+            .filter(|c| !c.is_whitespace() && !c.is_control())
+            .collect::<String>()
+            .to_ascii_lowercase();
+        if value.contains("javascript:") || value.contains("vbscript:") {
+            return false;
+        }
+        if name == "style" && value.contains("expression(") {
+            return false;
+        }
+    }
+    true
+}
+
+/// Decode named, decimal and hex entities. Unknown or malformed entities
+/// are passed through unchanged.
+pub fn decode_entities(html: &str) -> Cow<str> {
+    if !html.contains('&') {
         // nothing to do
+        return html.into();
+    }
+
+    let mut out = String::with_capacity(html.len());
+    let mut changed = false;
+    let mut rest = html;
+    while let Some(amp) = rest.find('&') {
+        out.push_str(&rest[..amp]);
+        rest = &rest[amp..];
+        match entity_at_start(rest) {
+            Some((len, c)) => {
+                out.push(c);
+                rest = &rest[len..];
+                changed = true;
+            }
+            None => {
+                out.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    out.push_str(rest);
+
+    if changed {
+        out.into()
+    } else {
         html.into()
     }
 }
 
+/// The length and decoded character of the entity `text` starts with, such
+/// as "&amp;", "&#66;" or "&#x1F600;". The semicolon is required.
+fn entity_at_start(text: &str) -> Option<(usize, char)> {
+    let bytes = text.as_bytes();
+    if bytes.get(1) == Some(&b'#') {
+        let (start, radix, max_digits) = match bytes.get(2) {
+            Some(b'x') | Some(b'X') => (3, 16, 8),
+            _ => (2, 10, 10),
+        };
+        let digits = bytes
+            .iter()
+            .skip(start)
+            .take_while(|b| (**b as char).is_digit(radix))
+            .count();
+        if digits == 0 || digits > max_digits || bytes.get(start + digits) != Some(&b';') {
+            return None;
+        }
+        let c = u32::from_str_radix(&text[start..start + digits], radix)
+            .ok()
+            .and_then(decode_char_reference)?;
+        Some((start + digits + 1, c))
+    } else {
+        let name_len = bytes
+            .iter()
+            .skip(1)
+            .take_while(|b| b.is_ascii_alphanumeric())
+            .count();
+        if !(2..=32).contains(&name_len)
+            || !bytes[1].is_ascii_alphabetic()
+            || bytes.get(1 + name_len) != Some(&b';')
+        {
+            return None;
+        }
+        let len = name_len + 2;
+        let c = htmlescape::decode_html(&text[..len])
+            .ok()
+            .and_then(|s| s.chars().next())?;
+        Some((len, c))
+    }
+}
+
+fn decode_char_reference(codepoint: u32) -> Option<char> {
+    if codepoint == 0 {
+        None
+    } else {
+        std::char::from_u32(codepoint)
+    }
+}
+
+/// Like strip_html(), but tags and comments are replaced with a space so
+/// that the words on either side are kept apart, and entities are decoded.
 pub fn strip_html_for_tts(html: &str) -> Cow<str> {
-    match HTML.replace_all(html, " ") {
+    let stripped = map_tokens(html, |token| match token {
+        Token::Text(text) => text.into(),
+        Token::RawText(_) => "".into(),
+        _ => " ".into(),
+    });
+    match stripped {
         Cow::Borrowed(_) => decode_entities(html),
-        Cow::Owned(s) => decode_entities(&s).to_string().into(),
+        Cow::Owned(s) => decode_entities(&s).into_owned().into(),
     }
 }
 
@@ -86,73 +403,277 @@ pub fn flag_av_tags(text: &str) -> Cow<str> {
         text
     })
 }
-pub fn av_tags_in_string(text: &str) -> impl Iterator<Item = AVTag> {
+
+/// Returns the sound and TTS tags in the text, in the order they appear.
+/// A TTS tag with invalid arguments produces an error in its position, so
+/// the indices produced by flag_av_tags() remain valid.
+pub fn av_tags_in_string(text: &str) -> impl Iterator<Item = Result<AVTag, TTSError>> {
+    av_tags_with_spans(text).map(|(_span, tag)| tag)
+}
+
+/// Like av_tags_in_string(), but also returns the byte range of each tag
+/// in the provided text.
+pub fn av_tags_with_spans(
+    text: &str,
+) -> impl Iterator<Item = (Range<usize>, Result<AVTag, TTSError>)> {
     AV_TAGS.captures_iter(text).map(|caps| {
-        if let Some(av_file) = caps.get(1) {
-            AVTag::SoundOrVideo(decode_entities(av_file.as_str()))
+        let span = caps.get(0).unwrap().range();
+        let tag = if let Some(av_file) = caps.get(1) {
+            Ok(AVTag::SoundOrVideo(decode_entities(av_file.as_str())))
         } else {
             let args = caps.get(2).unwrap();
             let field_text = caps.get(3).unwrap();
             tts_tag_from_string(field_text.as_str(), args.as_str())
-        }
+        };
+        (span, tag)
     })
 }
 
-fn tts_tag_from_string<'a>(field_text: &'a str, args: &'a str) -> AVTag<'a> {
+fn tts_tag_from_string<'a>(field_text: &'a str, args: &'a str) -> Result<AVTag<'a>, TTSError> {
+    let mut split_args = args.split(' ').filter(|arg| !arg.is_empty());
+    let lang = match split_args.next() {
+        Some(lang) if !lang.contains('=') => lang,
+        _ => return Err(TTSError::MissingLanguage),
+    };
+    let mut voices = vec![];
+    let mut speed = None;
+    let mut pitch = None;
+    let mut style = None;
     let mut other_args = vec![];
-    let mut split_args = args.split(' ');
-    let lang = split_args.next().unwrap_or("");
-    let mut voices = None;
-
-    for remaining_arg in split_args {
-        if remaining_arg.starts_with("voices=") {
-            voices = remaining_arg
-                .split('=')
-                .nth(1)
-                .map(|voices| voices.split(',').collect());
-        } else {
-            other_args.push(remaining_arg);
+
+    for arg in split_args {
+        let invalid = || TTSError::InvalidArgument {
+            arg: arg.to_string(),
+        };
+        let mut key_and_val = arg.splitn(2, '=');
+        let key = key_and_val.next().unwrap();
+        let val = key_and_val.next().ok_or_else(invalid)?;
+        match key {
+            "voices" => voices = val.split(',').filter(|v| !v.is_empty()).collect(),
+            "speed" => speed = Some(positive_float(val).ok_or_else(invalid)?),
+            "pitch" => pitch = Some(positive_float(val).ok_or_else(invalid)?),
+            "style" if !val.is_empty() => style = Some(val),
+            "style" => return Err(invalid()),
+            _ => other_args.push(arg),
         }
     }
 
-    AVTag::TextToSpeech {
+    Ok(AVTag::TextToSpeech {
         field_text: strip_html_for_tts(field_text),
         lang,
-        voices: voices.unwrap_or_else(Vec::new),
+        voices,
+        speed,
+        pitch,
+        style,
         other_args,
-    }
+    })
 }
 
+fn positive_float(text: &str) -> Option<f32> {
+    text.parse()
+        .ok()
+        .filter(|val: &f32| val.is_finite() && *val > 0.0)
+}
+
+/// Strip HTML, replacing image tags with their filename surrounded by
+/// spaces.
 pub fn strip_html_preserving_image_filenames(html: &str) -> Cow<str> {
-    let without_fnames = IMG_TAG.replace_all(html, r" $1 ");
-    let without_html = HTML.replace_all(&without_fnames, "");
-    // no changes?
-    if let Cow::Borrowed(b) = without_html {
-        if ptr::eq(b, html) {
-            return Cow::Borrowed(html);
+    map_tokens(html, |token| match token {
+        Token::Text(text) => text.into(),
+        Token::StartTag(tag) if tag.is("img") => filename_in_tag(&tag, "src"),
+        _ => "".into(),
+    })
+}
+
+/// Like strip_html_preserving_image_filenames(), but also keeps the
+/// filenames of audio, video and object tags, and of [sound:...] tags.
+pub fn strip_html_preserving_media_filenames(html: &str) -> Cow<str> {
+    map_tokens(html, |token| match token {
+        Token::Text(text) => SOUND_TAG.replace_all(text, " $1 "),
+        Token::StartTag(tag) => match media_attr_of_tag(&tag) {
+            Some(attr) => filename_in_tag(&tag, attr),
+            None => "".into(),
+        },
+        _ => "".into(),
+    })
+}
+
+/// The text stored in a note's sort field. HTML is stripped with media
+/// filenames kept, and entities are decoded, with non-breaking spaces
+/// becoming regular spaces.
+pub fn sort_field_text(html: &str) -> Cow<str> {
+    let text = strip_html_preserving_media_filenames(html);
+    if !text.contains('&') {
+        return text;
+    }
+    decode_entities(&text.replace("&nbsp;", " "))
+        .into_owned()
+        .into()
+}
+
+/// The attribute that holds the file a tag refers to, if it is one that
+/// may reference media.
+fn media_attr_of_tag(tag: &Tag) -> Option<&'static str> {
+    if tag.is("img") || tag.is("audio") || tag.is("video") || tag.is("source") {
+        Some("src")
+    } else if tag.is("object") {
+        Some("data")
+    } else {
+        None
+    }
+}
+
+/// The value of the attribute surrounded by spaces, or nothing if it is
+/// missing or empty.
+fn filename_in_tag<'a>(tag: &Tag<'a>, attr: &str) -> Cow<'a, str> {
+    match tag.attr(attr) {
+        Some(attr) if !attr.value.is_empty() => format!(" {} ", attr.value).into(),
+        _ => "".into(),
+    }
+}
+
+/// A reference to a file in the media folder.
+#[derive(Debug, PartialEq, Clone)]
+pub struct MediaRef<'a> {
+    /// The filename, with any HTML entities decoded.
+    pub fname: Cow<'a, str>,
+    /// The byte range of the filename as it appears in the source text.
+    /// For images generated from LaTeX, this covers the LaTeX tag instead.
+    pub span: Range<usize>,
+    /// True if the file is an image that LaTeX in the text is rendered into.
+    pub is_latex: bool,
+}
+
+/// Find references to local media files in the provided text, in the
+/// order they appear.
+///
+/// This covers [sound:...] tags, and the src/data/srcset attributes
+/// of img, audio, video, source and object tags. Remote URLs and
+/// inline data are skipped. Images that LaTeX in the text would be
+/// rendered into are included as well, with svg determining their
+/// extension.
+pub fn extract_media_refs(text: &str, svg: bool) -> Vec<MediaRef> {
+    let mut out = latex_media_refs(text, svg);
+
+    for caps in SOUND_TAG.captures_iter(text) {
+        let fname = caps.get(1).unwrap();
+        push_media_ref(&mut out, text, fname.start(), fname.end());
+    }
+
+    push_tag_media_refs(&mut out, text, Tokenizer::new(text));
+
+    out.sort_by_key(|r| r.span.start);
+    out
+}
+
+fn push_tag_media_refs<'a>(out: &mut Vec<MediaRef<'a>>, text: &'a str, tokens: Tokenizer<'a>) {
+    for (span, token) in tokens {
+        match token {
+            Token::StartTag(tag) if media_attr_of_tag(&tag).is_some() => {
+                push_attr_media_refs(out, text, &tag)
+            }
+            // media used by commented-out HTML is kept, so that it can be
+            // uncommented later
+            Token::Comment(comment) => {
+                let start = span.start + "<!--".len();
+                let inner = Tokenizer::in_range(text, start..start + comment.len());
+                push_tag_media_refs(out, text, inner);
+            }
+            _ => (),
         }
     }
-    // make borrow checker happy
-    without_html.into_owned().into()
 }
 
-pub fn cloze_numbers_in_string(html: &str) -> HashSet<u16> {
-    let mut hash = HashSet::with_capacity(4);
-    for cap in CLOZED_TEXT.captures_iter(html) {
-        if let Ok(n) = cap[1].parse() {
-            hash.insert(n);
+fn push_attr_media_refs<'a>(out: &mut Vec<MediaRef<'a>>, text: &'a str, tag: &Tag<'a>) {
+    for attr in &tag.attrs {
+        let (start, end) = (attr.value_span.start, attr.value_span.end);
+        match attr.name.to_ascii_lowercase().as_str() {
+            "src" | "data" => push_media_ref(out, text, start, end),
+            "srcset" => {
+                // comma-separated list of 'url [descriptor]'
+                let mut entry_start = start;
+                for entry in text[start..end].split(',') {
+                    let url_start = entry_start + (entry.len() - entry.trim_start().len());
+                    let url_len = entry.split_whitespace().next().unwrap_or("").len();
+                    push_media_ref(out, text, url_start, url_start + url_len);
+                    entry_start += entry.len() + 1;
+                }
+            }
+            _ => (),
         }
     }
-    hash
+}
+
+fn push_media_ref<'a>(out: &mut Vec<MediaRef<'a>>, text: &'a str, start: usize, end: usize) {
+    let fname = &text[start..end];
+    if fname.trim().is_empty() || is_remote_reference(fname) {
+        return;
+    }
+    out.push(MediaRef {
+        fname: decode_entities(fname),
+        span: start..end,
+        is_latex: false,
+    });
+}
+
+fn is_remote_reference(fname: &str) -> bool {
+    let lower = fname.trim_start().to_ascii_lowercase();
+    lower.starts_with("data:") || lower.contains("://")
+}
+
+/// Convert provided string to NFC form, borrowing if it is already
+/// normalized.
+pub fn normalize_to_nfc(s: &str) -> Cow<str> {
+    if is_nfc(s) {
+        s.into()
+    } else {
+        s.chars().nfc().collect::<String>().into()
+    }
+}
+
+/// Like normalize_to_nfc(), but takes ownership of the string, and avoids
+/// reallocating if it is already normalized.
+pub fn ensure_nfc(s: String) -> String {
+    if is_nfc(&s) {
+        s
+    } else {
+        s.chars().nfc().collect()
+    }
+}
+
+/// Fold case, and optionally remove combining marks, so that text can be
+/// compared in a case and accent insensitive way. The output is in NFC form.
+/// Used by both searching and duplicate checking, so they agree on which
+/// strings match.
+pub fn normalize_for_search(text: &str, strip_marks: bool) -> Cow<str> {
+    if text.is_ascii() && !text.bytes().any(|b| b.is_ascii_uppercase()) {
+        return text.into();
+    }
+
+    let folded = default_case_fold_str(text);
+    if strip_marks {
+        folded
+            .nfd()
+            .filter(|c| !is_combining_mark(*c))
+            .nfc()
+            .collect::<String>()
+            .into()
+    } else {
+        ensure_nfc(folded).into()
+    }
 }
 
 #[cfg(test)]
 mod test {
+    use crate::err::TTSError;
     use crate::text::{
-        av_tags_in_string, cloze_numbers_in_string, flag_av_tags, strip_av_tags, strip_html,
-        strip_html_preserving_image_filenames, AVTag,
+        av_tags_in_string, av_tags_with_spans, decode_entities, ensure_nfc, extract_media_refs,
+        flag_av_tags, html_to_browser_line, html_to_text, html_to_text_lines, normalize_for_search,
+        normalize_to_nfc, sanitize_html, sort_field_text, strip_av_tags, strip_html,
+        strip_html_for_tts, strip_html_preserving_image_filenames,
+        strip_html_preserving_media_filenames, AVTag, MediaRef, TextLayout,
     };
-    use std::collections::HashSet;
+    use std::borrow::Cow;
 
     #[test]
     fn test_stripping() {
@@ -169,17 +690,200 @@ mod test {
             " foo.jpg "
         );
         assert_eq!(strip_html_preserving_image_filenames("<html>"), "");
+
+        assert_eq!(
+            strip_html_preserving_media_filenames(
+                "<b>a</b><audio src=\"a.mp3\" controls></audio><VIDEO><source src='b.webm'></video>"
+            ),
+            "a a.mp3  b.webm "
+        );
+        assert_eq!(
+            strip_html_preserving_media_filenames(
+                "<object type=x data=c.swf></object>[sound:d.ogg]<img src=e.jpg>"
+            ),
+            " c.swf  d.ogg  e.jpg "
+        );
+        assert_eq!(strip_html_preserving_media_filenames("plain"), "plain");
+
+        assert_eq!(sort_field_text("<b>a&amp;b&nbsp;c</b>"), "a&b c");
+        assert_eq!(sort_field_text("[sound:x&amp;y.mp3]"), " x&y.mp3 ");
+    }
+
+    #[test]
+    fn test_malformed_html() {
+        // field content pasted from word processors and web pages, or
+        // typed by hand
+        let corpus: &[(&str, &str, &[&str])] = &[
+            (r#"<img src="a.jpg" alt="1 > 0">x"#, "x", &["a.jpg"]),
+            (r#"<img alt='say "hi"' src='b c.png'>"#, "", &["b c.png"]),
+            (
+                "<!--[if gte mso 9]><xml><w:WordDocument></w:WordDocument></xml><![endif]-->text",
+                "text",
+                &[],
+            ),
+            (
+                "<![if !supportLists]><span>1.</span><![endif]>item",
+                "1.item",
+                &[],
+            ),
+            ("<!--<img src=old.jpg>-->new", "new", &["old.jpg"]),
+            ("a<!-- unterminated <b>b</b>", "a", &[]),
+            ("3<5 and 6>4, x<y", "3<5 and 6>4, x<y", &[]),
+            ("<<b>>", "<>", &[]),
+            (r#"x<img src="c.jpg"#, r#"x<img src="c.jpg"#, &[]),
+            ("a<script>if (x<y) {}", "a", &[]),
+            ("<style>p>b{color:red}</style>c", "c", &[]),
+            ("<b>日本</b>語</ b></>", "日本語", &[]),
+            ("<IMG\nSRC=D.PNG\n>", "", &["D.PNG"]),
+            ("[sound:e.mp3]<audio src=>", "[sound:e.mp3]", &["e.mp3"]),
+        ];
+
+        for (html, stripped, fnames) in corpus {
+            assert_eq!(strip_html(html), *stripped, "stripping {}", html);
+            let found: Vec<_> = extract_media_refs(html, false)
+                .into_iter()
+                .map(|r| r.fname.into_owned())
+                .collect();
+            assert_eq!(&found, fnames, "media in {}", html);
+        }
+
+        assert_eq!(
+            strip_html_preserving_image_filenames(r#"<img alt=">" src=f.jpg>g"#),
+            " f.jpg g"
+        );
+        assert_eq!(
+            strip_html_for_tts("one<!-- x -->two<br>three&amp;"),
+            "one two three&"
+        );
     }
 
     #[test]
-    fn test_cloze() {
+    fn test_text_lines() {
+        assert_eq!(html_to_text_lines("one"), "one");
+        assert_eq!(
+            html_to_text_lines("one<br>two<BR />three"),
+            "one\ntwo\nthree"
+        );
+        assert_eq!(
+            html_to_text_lines("<div>one</div><div>two&nbsp;</div><p>x &amp; y</p>"),
+            "one\ntwo\nx & y"
+        );
+        assert_eq!(html_to_text_lines("<ul><li>a</li><li>b</li></ul>"), "a\nb");
+        assert_eq!(
+            html_to_text_lines("<div>a</div><br><br><br><div>b</div>"),
+            "a\n\nb"
+        );
         assert_eq!(
-            cloze_numbers_in_string("test"),
-            vec![].into_iter().collect::<HashSet<u16>>()
+            html_to_text_lines("<table><tr><td>a</td> <td>b</td></tr></table>"),
+            "a\tb"
         );
+        // tables start on a new line, as with the Tables layout
         assert_eq!(
-            cloze_numbers_in_string("{{c2::te}}{{c1::s}}t{{"),
-            vec![1, 2].into_iter().collect::<HashSet<u16>>()
+            html_to_text_lines(
+                "x<table><tr><td>a<br>b</td><td>c</td></tr><tr><td>d</td></tr></table>y"
+            ),
+            "x\na\nb\tc\nd\ny"
+        );
+    }
+
+    #[test]
+    fn test_text_layout() {
+        let text = "x<br>y [sound:a.mp3]&nbsp;<table border=1><tr><th>a</th><th>b\n c</th></tr>\
+                    <tr><td>1 &amp; 2</td><td></td></tr></table><div>z</div>";
+        assert_eq!(
+            html_to_text(text, TextLayout::SingleLine),
+            "x y ab c1 & 2 z"
+        );
+        assert_eq!(
+            html_to_text(text, TextLayout::Tables),
+            "x y\na\tb c\n1 & 2\t\nz"
+        );
+        assert_eq!(html_to_text("<b>plain</b>", TextLayout::Tables), "plain");
+        assert_eq!(
+            html_to_browser_line("a<br><b>b</b>&amp; <img src=c.jpg>[sound:d.mp3]"),
+            "a b& c.jpg"
+        );
+    }
+
+    #[test]
+    fn test_entities() {
+        assert_eq!(decode_entities("plain"), "plain");
+        assert_eq!(decode_entities("a&nbsp;b"), "a\u{a0}b");
+        assert_eq!(decode_entities("&lt;&AMP;&gt;"), "<&AMP;>");
+        assert_eq!(decode_entities("&#x1F600;&#X41;&#66;"), "\u{1F600}AB");
+        // stray and malformed sequences are left alone
+        assert_eq!(decode_entities("salt & pepper"), "salt & pepper");
+        assert_eq!(decode_entities("a&b;c"), "a&b;c");
+        assert_eq!(decode_entities("&#12"), "&#12");
+        assert_eq!(
+            decode_entities("&#xD800;&#0;&#x110000;"),
+            "&#xD800;&#0;&#x110000;"
+        );
+        assert_eq!(decode_entities("&unknown; &amp"), "&unknown; &amp");
+    }
+
+    #[test]
+    fn test_sanitize() {
+        // unchanged input is borrowed
+        let html = r#"<b>bold</b> <img src="foo.jpg"> [sound:foo.mp3]<br>"#;
+        if let Cow::Owned(_) = sanitize_html(html) {
+            panic!("unchanged text should be borrowed");
+        }
+
+        assert_eq!(
+            sanitize_html("a<script>alert(1)</script>b<SCRIPT src=x>c"),
+            "ab"
+        );
+        assert_eq!(sanitize_html("<iframe src=foo><b>x</b></iframe>z"), "z");
+        // objects keep their media, but not scripts
+        let html = r#"<object data="a.pdf" onload="x()">b</object>"#;
+        let sanitized = sanitize_html(html);
+        assert_eq!(sanitized, r#"<object data="a.pdf">b</object>"#);
+        let refs = extract_media_refs(&sanitized, false);
+        assert_eq!(refs.len(), 1);
+        assert_eq!(refs[0].fname, "a.pdf");
+        assert_eq!(
+            sanitize_html("<OBJECT data=' javascript:alert(1)'>b</OBJECT>"),
+            "<object>b</object>"
+        );
+        assert_eq!(sanitize_html("<!-- comment -->text"), "text");
+        assert_eq!(
+            sanitize_html(r#"<img src="foo.jpg" onerror="alert(1)">"#),
+            r#"<img src="foo.jpg">"#
+        );
+        assert_eq!(
+            sanitize_html(r#"<a href="java&#x09;script:alert(1)">x</a>"#),
+            "<a>x</a>"
+        );
+        assert_eq!(
+            sanitize_html(r#"<a HREF=' JavaScript:foo()' title=t>x</a>"#),
+            "<a title=t>x</a>"
+        );
+        // unknown tags are dropped, but their content is kept
+        assert_eq!(sanitize_html("<blink>hi</blink><br/>"), "hi<br />");
+        assert_eq!(
+            sanitize_html(r#"<audio src="a.mp3"><source src="b.ogg"></audio>"#),
+            r#"<audio src="a.mp3"><source src="b.ogg"></audio>"#
+        );
+        // a '<' that doesn't start a complete tag is escaped
+        assert_eq!(
+            sanitize_html("x<img src=x onerror=alert(1)"),
+            "x&lt;img src=x onerror=alert(1)"
+        );
+        assert_eq!(sanitize_html("1 < 2<b>!</b>"), "1 &lt; 2<b>!</b>");
+
+        // tags are split up the way a browser would split them
+        assert_eq!(
+            sanitize_html(r#"<img alt="a>b" src=x.jpg onerror='c'>"#),
+            r#"<img alt="a>b" src=x.jpg>"#
+        );
+        assert_eq!(
+            sanitize_html("<style>p{}</style><iframe><b>x</b></iframe><!--[if IE]>y<![endif]-->z"),
+            "z"
+        );
+        assert_eq!(
+            sanitize_html("<script>x = '</b>'</script><B>a</B >"),
+            "<b>a</b>"
         );
     }
 
@@ -191,13 +895,16 @@ mod test {
         assert_eq!(
             av_tags_in_string(s).collect::<Vec<_>>(),
             vec![
-                AVTag::SoundOrVideo("fo&o.mp3".into()),
-                AVTag::TextToSpeech {
+                Ok(AVTag::SoundOrVideo("fo&o.mp3".into())),
+                Ok(AVTag::TextToSpeech {
                     field_text: "foo 1>2".into(),
                     lang: "en_US",
                     voices: vec!["Bob", "Jane"],
+                    speed: None,
+                    pitch: None,
+                    style: None,
                     other_args: vec![]
-                },
+                }),
             ]
         );
 
@@ -205,5 +912,129 @@ mod test {
             flag_av_tags(s),
             "abc[anki:play]0[/anki:play]def[anki:play]1[/anki:play]gh"
         );
+
+        let spans: Vec<_> = av_tags_with_spans(s).map(|(span, _)| span).collect();
+        assert_eq!(spans, vec![3..23, 26..s.len() - 2]);
+        assert_eq!(&s[spans[0].clone()], "[sound:fo&amp;o.mp3]");
+    }
+
+    #[test]
+    fn test_tts_args() {
+        let s = "[anki:tts][ja_JP  speed=1.5 pitch=0.8 style=cheerful engine=foo]text[/anki:tts]";
+        assert_eq!(
+            av_tags_in_string(s).collect::<Vec<_>>(),
+            vec![Ok(AVTag::TextToSpeech {
+                field_text: "text".into(),
+                lang: "ja_JP",
+                voices: vec![],
+                speed: Some(1.5),
+                pitch: Some(0.8),
+                style: Some("cheerful"),
+                other_args: vec!["engine=foo"]
+            })]
+        );
+
+        let parse = |args: &str| {
+            av_tags_in_string(&format!("[anki:tts][{}]text[/anki:tts]", args))
+                .next()
+                .unwrap()
+                .map(|_| ())
+        };
+        assert_eq!(parse(""), Err(TTSError::MissingLanguage));
+        assert_eq!(parse("voices=Bob"), Err(TTSError::MissingLanguage));
+        for arg in &["speed=fast", "speed=-1", "pitch=", "style=", "novalue"] {
+            assert_eq!(
+                parse(&format!("en_US {}", arg)),
+                Err(TTSError::InvalidArgument {
+                    arg: arg.to_string()
+                })
+            );
+        }
+    }
+
+    #[test]
+    fn test_nfc() {
+        let nfd = "e\u{301}t\u{e9}";
+        let nfc = "\u{e9}t\u{e9}";
+        assert_eq!(normalize_to_nfc(nfd), nfc);
+        assert_eq!(ensure_nfc(nfd.to_string()), nfc);
+        if let Cow::Owned(_) = normalize_to_nfc(nfc) {
+            panic!("normalized text should be borrowed");
+        }
+        assert_eq!(ensure_nfc(nfc.to_string()), nfc);
+    }
+
+    #[test]
+    fn test_search_normalization() {
+        assert_eq!(normalize_for_search("über", true), "uber");
+        assert_eq!(normalize_for_search("Ångström", true), "angstrom");
+        assert_eq!(
+            normalize_for_search("Ångström", false),
+            "\u{e5}ngstr\u{f6}m"
+        );
+        assert_eq!(normalize_for_search("STRASSE", true), "strasse");
+        assert_eq!(normalize_for_search("Straße", true), "strasse");
+        // decomposed input is recomposed when marks are kept
+        assert_eq!(normalize_for_search("E\u{301}", false), "\u{e9}");
+        if let Cow::Owned(_) = normalize_for_search("plain text", true) {
+            panic!("lowercase ascii should be borrowed");
+        }
+    }
+
+    #[test]
+    fn test_media_refs() {
+        let fnames = |text| {
+            extract_media_refs(text, false)
+                .into_iter()
+                .map(|r| r.fname.into_owned())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            fnames(r#"<img src="a.jpg">[sound:b.mp3]<IMG SRC=c&amp;d.png alt=x>"#),
+            vec!["a.jpg", "b.mp3", "c&d.png"]
+        );
+        assert_eq!(
+            fnames(concat!(
+                r#"<audio src='e.ogg'></audio><video><source src="f.webm"></video>"#,
+                r#"<object data="g.svg"></object><img srcset="h.png 1x, i.png 2x">"#
+            )),
+            vec!["e.ogg", "f.webm", "g.svg", "h.png", "i.png"]
+        );
+        // remote files are skipped
+        assert_eq!(
+            fnames(r#"<img src="http://example.com/a.jpg"><img src="data:image/png;base64,xx">"#),
+            Vec::<String>::new()
+        );
+
+        // spans point at the filename
+        let text = r#"x<img src="foo.jpg">y[sound:bar.mp3]"#;
+        let refs = extract_media_refs(text, false);
+        assert_eq!(
+            refs,
+            vec![
+                MediaRef {
+                    fname: "foo.jpg".into(),
+                    span: 11..18,
+                    is_latex: false
+                },
+                MediaRef {
+                    fname: "bar.mp3".into(),
+                    span: 28..35,
+                    is_latex: false
+                }
+            ]
+        );
+
+        assert_eq!(&text[refs[1].span.clone()], "bar.mp3");
+
+        // latex images are included, in the order they appear
+        let refs = extract_media_refs("[$]x[/$]<img src=a.png>", true);
+        assert_eq!(refs.len(), 2);
+        assert!(refs[0].is_latex);
+        assert_eq!(refs[0].span, 0..8);
+        assert!(refs[0].fname.starts_with("latex-") && refs[0].fname.ends_with(".svg"));
+        assert_eq!(refs[1].fname, "a.png");
+        assert!(!refs[1].is_latex);
     }
 }
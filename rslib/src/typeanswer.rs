@@ -0,0 +1,284 @@
+// Copyright: Ankitects Pty Ltd and contributors
+// License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+//! Comparison of a typed-in answer with the expected answer.
+
+use crate::text::normalize_to_nfc;
+use std::collections::HashMap;
+use unicode_segmentation::UnicodeSegmentation;
+
+#[derive(Debug, PartialEq)]
+enum DiffToken<'a> {
+    Good(&'a str),
+    Bad(&'a str),
+    Missing(String),
+}
+
+/// Compare the provided answer against the expected one, and return the
+/// HTML used to display the result on the answer side.
+///
+/// The comparison is done on grapheme clusters in NFC form, so accented
+/// characters and combining marks are never split from their base
+/// characters.
+pub fn compare_answer(expected: &str, provided: &str) -> String {
+    let expected = normalize_to_nfc(expected);
+    let provided = normalize_to_nfc(provided);
+
+    let mut out = String::new();
+    if expected == provided {
+        out.push_str(&span("typeGood", &provided));
+    } else {
+        let (provided_tokens, expected_tokens) =
+            tokenize_comparison(&Graphemes::new(&provided), &Graphemes::new(&expected));
+
+        for token in provided_tokens {
+            match token {
+                DiffToken::Good(text) => out.push_str(&span("typeGood", text)),
+                DiffToken::Bad(text) => out.push_str(&span("typeBad", text)),
+                DiffToken::Missing(text) => out.push_str(&span("typeBad", &text)),
+            }
+        }
+        out.push_str("<br>&darr;<br>");
+        for token in expected_tokens {
+            match token {
+                DiffToken::Good(text) => out.push_str(&span("typeGood", text)),
+                DiffToken::Bad(text) => out.push_str(&span("typeMissed", text)),
+                DiffToken::Missing(text) => out.push_str(&span("typeMissed", &text)),
+            }
+        }
+    }
+
+    format!("<div><code id=typeans>{}</code></div>", out)
+}
+
+fn span(class: &str, text: &str) -> String {
+    format!("<span class={}>{}</span>", class, escape_html(text))
+}
+
+fn escape_html(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#x27;"),
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+/// Split the provided and expected text into matching and non-matching
+/// runs. Where the provided text is missing characters, it is padded with
+/// dashes so the two lines stay roughly aligned.
+fn tokenize_comparison<'a>(
+    provided: &Graphemes<'a>,
+    expected: &Graphemes<'a>,
+) -> (Vec<DiffToken<'a>>, Vec<DiffToken<'a>>) {
+    let mut provided_tokens = vec![];
+    let mut expected_tokens = vec![];
+    let mut provided_point = 0;
+    let mut expected_point = 0;
+    let mut offby = 0;
+
+    for (x, y, count) in matching_blocks(&provided.graphemes, &expected.graphemes) {
+        // if anything was missed in expected, pad provided
+        if count > 0 && y - offby > x {
+            provided_tokens.push(DiffToken::Missing("-".repeat(y - x - offby)));
+            offby = y - x;
+        }
+        // any preceding mismatches
+        if provided_point < x {
+            provided_tokens.push(DiffToken::Bad(provided.slice(provided_point, x)));
+        }
+        if expected_point < y {
+            expected_tokens.push(DiffToken::Bad(expected.slice(expected_point, y)));
+        }
+        provided_point = x + count;
+        expected_point = y + count;
+        // and the match
+        if count > 0 {
+            provided_tokens.push(DiffToken::Good(provided.slice(x, x + count)));
+            expected_tokens.push(DiffToken::Good(expected.slice(y, y + count)));
+        }
+    }
+
+    (provided_tokens, expected_tokens)
+}
+
+/// A string split into grapheme clusters.
+struct Graphemes<'a> {
+    text: &'a str,
+    /// byte offset of each grapheme, followed by the string length
+    offsets: Vec<usize>,
+    graphemes: Vec<&'a str>,
+}
+
+impl<'a> Graphemes<'a> {
+    fn new(text: &'a str) -> Self {
+        let (mut offsets, graphemes): (Vec<_>, Vec<_>) = text.grapheme_indices(true).unzip();
+        offsets.push(text.len());
+        Graphemes {
+            text,
+            offsets,
+            graphemes,
+        }
+    }
+
+    /// The text covering graphemes start..end.
+    fn slice(&self, start: usize, end: usize) -> &'a str {
+        &self.text[self.offsets[start]..self.offsets[end]]
+    }
+}
+
+/// Return (a_start, b_start, len) triples describing matching runs,
+/// terminated by a zero-length match at the end of both sequences.
+/// This mirrors Python's difflib.SequenceMatcher with autojunk disabled.
+fn matching_blocks(a: &[&str], b: &[&str]) -> Vec<(usize, usize, usize)> {
+    let mut b2j: HashMap<&str, Vec<usize>> = HashMap::new();
+    for (idx, elem) in b.iter().enumerate() {
+        b2j.entry(elem).or_insert_with(Vec::new).push(idx);
+    }
+
+    let mut queue = vec![(0, a.len(), 0, b.len())];
+    let mut blocks = vec![];
+    while let Some((alo, ahi, blo, bhi)) = queue.pop() {
+        let (i, j, k) = find_longest_match(a, &b2j, alo, ahi, blo, bhi);
+        if k > 0 {
+            blocks.push((i, j, k));
+            if alo < i && blo < j {
+                queue.push((alo, i, blo, j));
+            }
+            if i + k < ahi && j + k < bhi {
+                queue.push((i + k, ahi, j + k, bhi));
+            }
+        }
+    }
+    blocks.sort();
+
+    // collapse adjacent blocks
+    let mut collapsed: Vec<(usize, usize, usize)> = vec![];
+    for (i, j, k) in blocks {
+        if let Some(last) = collapsed.last_mut() {
+            if last.0 + last.2 == i && last.1 + last.2 == j {
+                last.2 += k;
+                continue;
+            }
+        }
+        collapsed.push((i, j, k));
+    }
+    collapsed.push((a.len(), b.len(), 0));
+
+    collapsed
+}
+
+fn find_longest_match(
+    a: &[&str],
+    b2j: &HashMap<&str, Vec<usize>>,
+    alo: usize,
+    ahi: usize,
+    blo: usize,
+    bhi: usize,
+) -> (usize, usize, usize) {
+    let (mut besti, mut bestj, mut bestsize) = (alo, blo, 0);
+    let mut j2len: HashMap<usize, usize> = HashMap::new();
+    for (i, elem) in a.iter().enumerate().take(ahi).skip(alo) {
+        let mut new_j2len = HashMap::new();
+        if let Some(indices) = b2j.get(elem) {
+            for &j in indices {
+                if j < blo {
+                    continue;
+                }
+                if j >= bhi {
+                    break;
+                }
+                let k = if j > 0 {
+                    j2len.get(&(j - 1)).cloned().unwrap_or(0)
+                } else {
+                    0
+                } + 1;
+                new_j2len.insert(j, k);
+                if k > bestsize {
+                    besti = i + 1 - k;
+                    bestj = j + 1 - k;
+                    bestsize = k;
+                }
+            }
+        }
+        j2len = new_j2len;
+    }
+
+    (besti, bestj, bestsize)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::typeanswer::compare_answer;
+
+    fn good(s: &str) -> String {
+        format!("<span class=typeGood>{}</span>", s)
+    }
+    fn bad(s: &str) -> String {
+        format!("<span class=typeBad>{}</span>", s)
+    }
+    fn missed(s: &str) -> String {
+        format!("<span class=typeMissed>{}</span>", s)
+    }
+    fn wrap(s: String) -> String {
+        format!("<div><code id=typeans>{}</code></div>", s)
+    }
+
+    #[test]
+    fn test_compare() {
+        assert_eq!(compare_answer("abc", "abc"), wrap(good("abc")));
+        assert_eq!(compare_answer("a<b", "a<b"), wrap(good("a&lt;b")));
+
+        assert_eq!(
+            compare_answer("abc", "abd"),
+            wrap(good("ab") + &bad("d") + "<br>&darr;<br>" + &good("ab") + &missed("c"))
+        );
+
+        // missing text in provided answer is padded
+        assert_eq!(
+            compare_answer("abcd", "ad"),
+            wrap(
+                good("a")
+                    + &bad("--")
+                    + &good("d")
+                    + "<br>&darr;<br>"
+                    + &good("a")
+                    + &missed("bc")
+                    + &good("d")
+            )
+        );
+
+        // decomposed and precomposed forms are equal
+        assert_eq!(
+            compare_answer("caf\u{e9}", "cafe\u{301}"),
+            wrap(good("caf\u{e9}"))
+        );
+
+        // combining marks stay attached to their base character
+        assert_eq!(
+            compare_answer("q\u{303}o", "qo"),
+            wrap(bad("q") + &good("o") + "<br>&darr;<br>" + &missed("q\u{303}") + &good("o"))
+        );
+    }
+
+    #[test]
+    fn test_rtl() {
+        // hebrew
+        assert_eq!(compare_answer("שלום", "שלום"), wrap(good("שלום")));
+        assert_eq!(
+            compare_answer("שלום", "שלוס"),
+            wrap(good("שלו") + &bad("ס") + "<br>&darr;<br>" + &good("שלו") + &missed("ם"))
+        );
+        // arabic with diacritics
+        assert_eq!(
+            compare_answer("كَتَبَ", "كتب"),
+            wrap(bad("كتب") + "<br>&darr;<br>" + &missed("كَتَبَ"))
+        );
+    }
+}
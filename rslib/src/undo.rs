@@ -0,0 +1,294 @@
+// Copyright: Ankitects Pty Ltd and contributors
+// License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+//! Multi-level undo and redo. Each operation records the previous state of
+//! the rows it changed under a name like "Suspend", and undoing it writes
+//! that state back in a single transaction. The state the undo replaced is
+//! recorded in turn, so the operation can be redone.
+
+use crate::card::Card;
+use crate::err::{AnkiError, Result};
+use crate::notes::Note;
+use crate::revlog::RevlogEntry;
+use crate::sched::undo::{card_snapshots, write_snapshots, CardScheduleSnapshot};
+use crate::storage::SqliteStorage;
+use std::collections::VecDeque;
+
+/// How many operations are kept by default.
+pub const DEFAULT_UNDO_STEPS: usize = 30;
+
+/// The state of some rows before a change. `None` means the row didn't
+/// exist, so undoing the change removes it.
+#[derive(Debug, Clone, PartialEq)]
+pub enum UndoableChange {
+    CardSchedules(Vec<CardScheduleSnapshot>),
+    Card {
+        id: i64,
+        previous: Option<Card>,
+    },
+    Note {
+        id: i64,
+        previous: Option<Note>,
+    },
+    RevlogEntry {
+        id: i64,
+        previous: Option<RevlogEntry>,
+    },
+}
+
+impl UndoableChange {
+    /// Write the recorded state back, returning a change that reverses
+    /// this one.
+    fn apply(self, storage: &SqliteStorage) -> Result<UndoableChange> {
+        Ok(match self {
+            UndoableChange::CardSchedules(cards) => {
+                let ids: Vec<_> = cards.iter().map(|c| c.id.to_string()).collect();
+                let current =
+                    card_snapshots(&storage.db, &format!("id in ({})", ids.join(",")), &[])?;
+                write_snapshots(&storage.db, &cards)?;
+                UndoableChange::CardSchedules(current)
+            }
+            UndoableChange::Card { id, previous } => {
+                let current = storage.get_card(id)?;
+                match &previous {
+                    Some(card) => storage.add_or_update_card(card)?,
+                    None => storage.remove_card(id)?,
+                }
+                UndoableChange::Card {
+                    id,
+                    previous: current,
+                }
+            }
+            UndoableChange::Note { id, previous } => {
+                let current = storage.get_note(id)?;
+                match &previous {
+                    Some(note) => storage.add_or_update_note(note)?,
+                    None => storage.remove_note(id)?,
+                }
+                UndoableChange::Note {
+                    id,
+                    previous: current,
+                }
+            }
+            UndoableChange::RevlogEntry { id, previous } => {
+                let current = storage.get_revlog_entry(id)?;
+                match &previous {
+                    Some(entry) => storage.add_or_update_revlog_entry(entry)?,
+                    None => storage.remove_revlog_entry(id)?,
+                }
+                UndoableChange::RevlogEntry {
+                    id,
+                    previous: current,
+                }
+            }
+        })
+    }
+}
+
+/// A named operation, and the changes needed to reverse it, in the order
+/// they were made.
+#[derive(Debug, Clone, PartialEq)]
+pub struct UndoableOp {
+    pub name: String,
+    pub changes: Vec<UndoableChange>,
+}
+
+impl UndoableOp {
+    /// Reverse the changes in a single transaction, returning the op that
+    /// reverses this one.
+    fn apply(self, storage: &mut SqliteStorage) -> Result<UndoableOp> {
+        let name = self.name;
+        let changes = self.changes;
+        storage.transact(|storage| {
+            let mut inverse = Vec::with_capacity(changes.len());
+            for change in changes.into_iter().rev() {
+                inverse.push(change.apply(storage)?);
+            }
+            Ok(UndoableOp {
+                name,
+                changes: inverse,
+            })
+        })
+    }
+}
+
+/// The operations that can be undone, most recent last, and those that
+/// can be redone after an undo.
+#[derive(Debug)]
+pub struct UndoManager {
+    undo_steps: VecDeque<UndoableOp>,
+    redo_steps: Vec<UndoableOp>,
+    max_steps: usize,
+}
+
+impl Default for UndoManager {
+    fn default() -> Self {
+        UndoManager::new(DEFAULT_UNDO_STEPS)
+    }
+}
+
+impl UndoManager {
+    pub fn new(max_steps: usize) -> Self {
+        UndoManager {
+            undo_steps: VecDeque::new(),
+            redo_steps: vec![],
+            max_steps,
+        }
+    }
+
+    /// Change how many operations are kept, discarding the oldest if
+    /// there are too many.
+    pub fn set_max_steps(&mut self, max_steps: usize) {
+        self.max_steps = max_steps;
+        self.trim();
+    }
+
+    fn trim(&mut self) {
+        while self.undo_steps.len() > self.max_steps {
+            self.undo_steps.pop_front();
+        }
+    }
+
+    /// Record an operation that has been committed. Redo is no longer
+    /// possible afterwards. Operations that changed nothing are ignored.
+    pub fn add_op(&mut self, op: UndoableOp) {
+        if op.changes.is_empty() {
+            return;
+        }
+        self.redo_steps.clear();
+        self.undo_steps.push_back(op);
+        self.trim();
+    }
+
+    /// The name of the operation undo would reverse.
+    pub fn undo_name(&self) -> Option<&str> {
+        self.undo_steps.back().map(|op| op.name.as_str())
+    }
+
+    /// The name of the operation redo would repeat.
+    pub fn redo_name(&self) -> Option<&str> {
+        self.redo_steps.last().map(|op| op.name.as_str())
+    }
+
+    /// Reverse the most recent operation, returning its name.
+    pub fn undo(&mut self, storage: &mut SqliteStorage) -> Result<String> {
+        let op = self
+            .undo_steps
+            .pop_back()
+            .ok_or_else(|| AnkiError::invalid_input("nothing to undo"))?;
+        let redo = op.apply(storage)?;
+        let name = redo.name.clone();
+        self.redo_steps.push(redo);
+        Ok(name)
+    }
+
+    /// Repeat the most recently undone operation, returning its name.
+    pub fn redo(&mut self, storage: &mut SqliteStorage) -> Result<String> {
+        let op = self
+            .redo_steps
+            .pop()
+            .ok_or_else(|| AnkiError::invalid_input("nothing to redo"))?;
+        let undo = op.apply(storage)?;
+        let name = undo.name.clone();
+        self.undo_steps.push_back(undo);
+        self.trim();
+        Ok(name)
+    }
+
+    /// Forget all operations, eg when the collection was changed in a way
+    /// the recorded state doesn't account for.
+    pub fn clear(&mut self) {
+        self.undo_steps.clear();
+        self.redo_steps.clear();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::card::Card;
+    use crate::err::Result;
+    use crate::notes::Note;
+    use crate::sched::undo::card_snapshots;
+    use crate::storage::testutil::open_test_collection;
+    use crate::undo::{UndoManager, UndoableChange, UndoableOp};
+
+    #[test]
+    fn test_undo() -> Result<()> {
+        let (_dir, mut storage) = open_test_collection()?;
+        let mut note = Note {
+            fields: vec!["front".into(), "back".into()],
+            ..Default::default()
+        };
+        storage.add_note(&mut note)?;
+        let mut card = Card {
+            note_id: note.id,
+            ..Default::default()
+        };
+        storage.add_card(&mut card)?;
+
+        let mut undo = UndoManager::new(2);
+        assert_eq!(undo.undo_name(), None);
+
+        // suspend the card
+        let previous = card_snapshots(&storage.db, "id = ?", &[&card.id])?;
+        storage
+            .db
+            .execute("update cards set queue = -1 where id = ?", &[&card.id])?;
+        undo.add_op(UndoableOp {
+            name: "Suspend".into(),
+            changes: vec![UndoableChange::CardSchedules(previous)],
+        });
+
+        // remove the note and card
+        let suspended = storage.get_card(card.id)?.unwrap();
+        storage.remove_card(card.id)?;
+        storage.remove_note(note.id)?;
+        undo.add_op(UndoableOp {
+            name: "Delete Note".into(),
+            changes: vec![
+                UndoableChange::Card {
+                    id: card.id,
+                    previous: Some(suspended.clone()),
+                },
+                UndoableChange::Note {
+                    id: note.id,
+                    previous: Some(note.clone()),
+                },
+            ],
+        });
+        assert_eq!(undo.undo_name(), Some("Delete Note"));
+
+        assert_eq!(undo.undo(&mut storage)?, "Delete Note");
+        assert_eq!(storage.get_note(note.id)?, Some(note.clone()));
+        assert_eq!(storage.get_card(card.id)?, Some(suspended));
+        assert_eq!(undo.redo_name(), Some("Delete Note"));
+
+        assert_eq!(undo.undo(&mut storage)?, "Suspend");
+        assert_eq!(storage.get_card(card.id)?, Some(card.clone()));
+        assert_eq!(undo.undo_name(), None);
+        assert!(undo.undo(&mut storage).is_err());
+
+        // redoing repeats the changes in their original order
+        assert_eq!(undo.redo(&mut storage)?, "Suspend");
+        assert_eq!(undo.redo(&mut storage)?, "Delete Note");
+        assert_eq!(storage.get_card(card.id)?, None);
+        assert_eq!(storage.get_note(note.id)?, None);
+
+        // a new op discards redo, and old steps are dropped past the limit
+        assert_eq!(undo.undo(&mut storage)?, "Delete Note");
+        undo.add_op(UndoableOp {
+            name: "Update Tag".into(),
+            changes: vec![UndoableChange::Note {
+                id: note.id,
+                previous: Some(note.clone()),
+            }],
+        });
+        assert_eq!(undo.redo_name(), None);
+        undo.set_max_steps(1);
+        assert_eq!(undo.undo_name(), Some("Update Tag"));
+        undo.undo(&mut storage)?;
+        assert_eq!(undo.undo_name(), None);
+
+        Ok(())
+    }
+}
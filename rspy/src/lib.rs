@@ -1,4 +1,4 @@
-use anki::backend::Backend as RustBackend;
+use anki::backend::{Backend as RustBackend, ProgressHandle as RustProgressHandle};
 use pyo3::prelude::*;
 use pyo3::types::PyBytes;
 use pyo3::wrap_pyfunction;
@@ -8,6 +8,11 @@ struct Backend {
     backend: RustBackend,
 }
 
+#[pyclass]
+struct ProgressHandle {
+    handle: RustProgressHandle,
+}
+
 #[pyfunction]
 fn buildhash() -> &'static str {
     include_str!("../../meta/buildhash").trim()
@@ -16,24 +21,64 @@ fn buildhash() -> &'static str {
 #[pymethods]
 impl Backend {
     #[new]
-    fn init(obj: &PyRawObject, path: String) {
+    fn init(obj: &PyRawObject, col_path: String, media_folder: String, media_db: String) {
         obj.init({
             Backend {
-                backend: RustBackend::new(path),
+                backend: RustBackend::new(col_path, media_folder, media_db),
             }
         });
     }
 
-    fn command(&mut self, py: Python, input: &PyBytes) -> PyResult<PyObject> {
-        let out_bytes = self.backend.run_command_bytes(input.as_bytes());
+    /// Run a command. Commands can be run from several threads at once, such
+    /// as a media sync alongside collection operations.
+    fn command(&self, py: Python, input: &PyBytes) -> PyResult<PyObject> {
+        let in_bytes = input.as_bytes();
+        let backend = &self.backend;
+        // release the GIL, so other threads can run while we block
+        let out_bytes = py.allow_threads(move || backend.run_command_bytes(in_bytes));
         let out_obj = PyBytes::new(py, &out_bytes);
         Ok(out_obj.into())
     }
+
+    /// A handle for following the progress of long-running commands, and
+    /// aborting them. It can be used while a command runs on another thread.
+    fn progress_handle(&self, py: Python) -> PyResult<Py<ProgressHandle>> {
+        Py::new(
+            py,
+            ProgressHandle {
+                handle: self.backend.progress_handle(),
+            },
+        )
+    }
+
+    /// Like progress_handle(), but for media syncing.
+    fn media_progress_handle(&self, py: Python) -> PyResult<Py<ProgressHandle>> {
+        Py::new(
+            py,
+            ProgressHandle {
+                handle: self.backend.media_progress_handle(),
+            },
+        )
+    }
+}
+
+#[pymethods]
+impl ProgressHandle {
+    /// The latest Progress message, encoded, or empty bytes if the running
+    /// command hasn't reported any.
+    fn latest_progress(&self, py: Python) -> PyObject {
+        PyBytes::new(py, &self.handle.latest_progress_bytes()).into()
+    }
+
+    fn set_wants_abort(&self) {
+        self.handle.set_wants_abort();
+    }
 }
 
 #[pymodule]
 fn ankirspy(_py: Python, m: &PyModule) -> PyResult<()> {
     m.add_class::<Backend>()?;
+    m.add_class::<ProgressHandle>()?;
     m.add_wrapped(wrap_pyfunction!(buildhash)).unwrap();
 
     Ok(())
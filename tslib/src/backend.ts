@@ -56,7 +56,7 @@ async function webRequest(input: pb.IBackendInput): Promise<pb.BackendOutput> {
 
 export async function deckTree(): Promise<pb.IDeckTreeNode[]> {
   const resp = await webRequest({
-    deckTree: new pb.Empty()
+    deckTree: new pb.DeckDueCountsIn()
   });
   return expectNotNull(resp?.deckTree?.top?.children);
 }